@@ -0,0 +1,136 @@
+//! Throughput of the interpreter and the JIT, in emulated machine cycles per
+//! second of host time. Run with `cargo bench`; criterion compares each run to
+//! the previous one, so a change to the emitter that slows down compiled code
+//! shows up as a drop in cycles per second.
+//!
+//! Each instruction stream is a small loop that stays in ROM, so both engines
+//! run the same code along with the usual peripheral updates. A real game can
+//! be included by setting GB_BENCH_ROM to a ROM file; it is timed a frame at a
+//! time, once it has run long enough to reach its main loop.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use gb_dynarec::emulator::Core;
+use gb_dynarec::timing::FRAME_CYCLES;
+
+/// Machine cycles run by each iteration of an instruction stream
+const STREAM_CYCLES: u64 = 100_000;
+/// Frames a game runs before it is timed, to get past any intro
+const ROM_SETTLE_FRAMES: usize = 600;
+
+/// Arithmetic on registers, in a tight counted loop
+const ALU_LOOP: &[u8] = &[
+  0x06, 0x00, // LD B, 0x00
+  0x3e, 0x00, // LD A, 0x00
+  0x80, // ADD A, B
+  0x3c, // INC A
+  0x05, // DEC B
+  0x20, 0xfb, // JR NZ, -5
+  0xc3, 0x00, 0x00, // JP 0x0000
+];
+
+/// Copies 256 bytes of work RAM at a time
+const COPY_LOOP: &[u8] = &[
+  0x31, 0xfe, 0xdf, // LD SP, 0xdffe
+  0x21, 0x00, 0xc0, // LD HL, 0xc000
+  0x11, 0x00, 0xc8, // LD DE, 0xc800
+  0x0e, 0x00, // LD C, 0x00
+  0x2a, // LD A, (HL+)
+  0x12, // LD (DE), A
+  0x13, // INC DE
+  0x0d, // DEC C
+  0x20, 0xfa, // JR NZ, -6
+  0xc3, 0x03, 0x00, // JP 0x0003
+];
+
+/// Ops that read and write every flag, along with calls and stack traffic
+const FLAGS_LOOP: &[u8] = &[
+  0x31, 0xfe, 0xdf, // LD SP, 0xdffe
+  0x3e, 0x15, // LD A, 0x15
+  0x06, 0x27, // LD B, 0x27
+  0x88, // ADC A, B
+  0x27, // DAA
+  0x17, // RLA
+  0x98, // SBC A, B
+  0xcb, 0x11, // RL C
+  0xc5, // PUSH BC
+  0xf1, // POP AF
+  0xcd, 0x14, 0x00, // CALL 0x0014
+  0x18, 0xf3, // JR -13
+  0xc9, // RET
+];
+
+/// Waits on bits of STAT, the way games poll for the PPU mode. Consecutive
+/// BIT n,(HL) ops share a single read in compiled code.
+const POLL_LOOP: &[u8] = &[
+  0x21, 0x41, 0xff, // LD HL, 0xff41
+  0xcb, 0x4e, // BIT 1,(HL)
+  0xcb, 0x46, // BIT 0,(HL)
+  0x20, 0xfa, // JR NZ, -6
+  0xc3, 0x00, 0x00, // JP 0x0000
+];
+
+const STREAMS: [(&str, &[u8]); 4] = [
+  ("alu", ALU_LOOP),
+  ("copy", COPY_LOOP),
+  ("flags", FLAGS_LOOP),
+  ("poll", POLL_LOOP),
+];
+
+/// The engines available in this build, and whether each one is the JIT
+fn engines() -> Vec<(&'static str, bool)> {
+  let mut engines = vec![("interpreter", false)];
+  if Core::jit_available() {
+    engines.push(("jit", true));
+  }
+  engines
+}
+
+fn run_cycles(core: &mut Core, cycles: u64) {
+  let end = core.cycles_elapsed() + cycles;
+  while core.cycles_elapsed() < end {
+    core.run_code_block();
+  }
+}
+
+fn instruction_streams(c: &mut Criterion) {
+  let mut group = c.benchmark_group("streams");
+  group.throughput(Throughput::Elements(STREAM_CYCLES));
+  for (name, code) in STREAMS.iter() {
+    for (engine, jit) in engines() {
+      let mut core = Core::with_code_block(code.to_vec().into_boxed_slice());
+      // the loops never wait on anything, so they would all be skipped
+      core.skip_idle_loops = false;
+      core.set_jit_enabled(jit).unwrap();
+      // compile everything before it is timed
+      run_cycles(&mut core, STREAM_CYCLES);
+      group.bench_function(BenchmarkId::new(*name, engine), |b| {
+        b.iter(|| run_cycles(&mut core, STREAM_CYCLES))
+      });
+    }
+  }
+  group.finish();
+}
+
+fn rom_frames(c: &mut Criterion) {
+  let name = match std::env::var("GB_BENCH_ROM") {
+    Ok(name) => name,
+    Err(_) => return,
+  };
+  let data = std::fs::read(&name).unwrap_or_else(|_| panic!("Unable to read {}", name));
+  let mut group = c.benchmark_group("rom");
+  group.throughput(Throughput::Elements(FRAME_CYCLES));
+  for (engine, jit) in engines() {
+    let mut core = Core::from_rom_bytes(data.clone()).unwrap();
+    core.set_jit_enabled(jit).unwrap();
+    for _ in 0..ROM_SETTLE_FRAMES {
+      core.run_frame();
+    }
+    group.bench_function(BenchmarkId::new("frame", engine), |b| {
+      b.iter(|| core.run_frame())
+    });
+  }
+  group.finish();
+}
+
+criterion_group!(benches, instruction_streams, rom_frames);
+criterion_main!(benches);
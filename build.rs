@@ -1,8 +1,16 @@
 fn main() {
+  // The dynarec can only emit code for these architectures. Everywhere else,
+  // the emulator is built as a pure interpreter.
+  println!("cargo:rustc-check-cfg=cfg(jit_backend)");
+  let target_arch = std::env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+  if target_arch == "x86_64" || target_arch == "aarch64" {
+    println!("cargo:rustc-cfg=jit_backend");
+  }
+
   #[cfg(windows)]
   {
     windows::build! {
-      Windows::Win32::Foundation::HWND,
+      Windows::Win32::Foundation::{CloseHandle, HWND},
       Windows::Win32::Graphics::Gdi::{
         BitBlt,
         CreateCompatibleBitmap,
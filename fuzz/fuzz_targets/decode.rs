@@ -0,0 +1,8 @@
+//! Decodes arbitrary bytes, which should never panic
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+  gb_dynarec::debug::fuzz::check_decode(data);
+});
@@ -0,0 +1,9 @@
+//! Runs arbitrary code through the interpreter and the JIT, which should
+//! always leave the machine in the same state
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+  gb_dynarec::debug::fuzz::check_lockstep(data);
+});
@@ -0,0 +1,152 @@
+//! A small, stable surface for embedding the emulator in other front ends.
+//! It wraps a `Core`, which is still reachable through `core` and `core_mut`
+//! for anything the facade doesn't cover.
+
+use crate::devices::joypad::Button;
+use crate::devices::video::lcd::{LCD_HEIGHT, LCD_WIDTH};
+use crate::emulator::{BreakEvent, Core};
+use crate::error::{Error, RomError};
+use crate::system::{self, RomSizePolicy};
+use crate::zip;
+
+pub struct Emulator {
+  core: Core,
+}
+
+impl Emulator {
+  /// Create an emulator with nothing loaded. The CPU spins in an empty
+  /// program until a ROM is loaded.
+  pub fn new() -> Self {
+    // JR -2
+    let idle_loop = vec![0x18, 0xfe].into_boxed_slice();
+    Self {
+      core: Core::with_code_block(idle_loop),
+    }
+  }
+
+  /// Replace whatever is running with a cartridge ROM, or a zip archive
+  /// containing one, starting from the state the boot ROM would leave the
+  /// CPU in
+  pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), Error> {
+    let rom = zip::unpack_rom(rom.to_vec()).map_err(RomError::Invalid)?;
+    let header = system::read_header_from_bytes(&rom)?;
+    if !header.valid_checksum() {
+      return Err(Error::Rom(RomError::InvalidChecksum));
+    }
+    let mut core = Core::from_rom_data(rom, header, RomSizePolicy::Header, None)?;
+    core.set_jit_enabled(self.core.is_jit_enabled())?;
+    self.core.shutdown();
+    // the old game's shutdown reports are left for the caller to take
+    core.messages = self.core.take_messages();
+    self.core = core;
+    Ok(())
+  }
+
+  /// Run until the end of the next frame. A breakpoint or watchpoint set
+  /// through `core_mut` stops the frame early, and is returned.
+  pub fn step_frame(&mut self) -> Option<BreakEvent> {
+    self.core.run_frame()
+  }
+
+  /// The last completed frame, as 160x144 shades from white (255) to black
+  /// (0), one row after another
+  pub fn framebuffer(&self) -> &[u8] {
+    self.core.get_screen_buffer()
+  }
+
+  /// The last completed frame as RGBA8 pixels, colored by the palette
+  pub fn framebuffer_rgba(&self) -> Vec<u8> {
+    self.core.screenshot()
+  }
+
+  pub fn framebuffer_size(&self) -> (usize, usize) {
+    (LCD_WIDTH, LCD_HEIGHT)
+  }
+
+  pub fn set_button(&mut self, button: Button, pressed: bool) {
+    if pressed {
+      self.core.input.press(button);
+    } else {
+      self.core.input.release(button);
+    }
+  }
+
+  /// Interleaved stereo samples produced since the last call. There is no
+  /// APU yet, so this is always empty, but front ends can already drain it
+  /// once per frame.
+  pub fn audio_samples(&mut self) -> Vec<i16> {
+    Vec::new()
+  }
+
+  /// Warnings and reports raised by the core since the last call, like an
+  /// error that stopped a recording. Front ends should take them once per
+  /// frame and show or log them however suits them.
+  pub fn take_messages(&mut self) -> Vec<String> {
+    self.core.take_messages()
+  }
+
+  pub fn save_state(&self) -> Vec<u8> {
+    self.core.save_state()
+  }
+
+  pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+    self.core.load_state(data)
+  }
+
+  pub fn core(&self) -> &Core {
+    &self.core
+  }
+
+  pub fn core_mut(&mut self) -> &mut Core {
+    &mut self.core
+  }
+}
+
+impl Default for Emulator {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Emulator;
+  use crate::devices::joypad::Button;
+  use crate::error::{Error, RomError};
+
+  /// A minimal ROM with a valid header, which loops forever at 0x150
+  fn test_rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    // JP 0x150, then JR -2
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]);
+    rom[0x150..0x152].copy_from_slice(&[0x18, 0xfe]);
+    rom[0x134..0x138].copy_from_slice(b"TEST");
+    let mut checksum: u8 = 0;
+    for byte in rom[0x134..0x14d].iter() {
+      checksum = checksum.wrapping_sub(*byte).wrapping_sub(1);
+    }
+    rom[0x14d] = checksum;
+    rom
+  }
+
+  #[test]
+  fn running_a_rom() {
+    let mut emulator = Emulator::new();
+    assert_eq!(emulator.load_rom(&[0; 0x100]), Err(Error::Rom(RomError::TooShort)));
+    let mut rom = test_rom();
+    rom[0x14d] ^= 1;
+    assert_eq!(emulator.load_rom(&rom), Err(Error::Rom(RomError::InvalidChecksum)));
+
+    emulator.load_rom(&test_rom()).unwrap();
+    emulator.set_button(Button::A, true);
+    for _ in 0..3 {
+      assert!(emulator.step_frame().is_none());
+    }
+    let ip = emulator.core().registers.ip;
+    assert_eq!(ip, 0x150);
+    let (width, height) = emulator.framebuffer_size();
+    assert_eq!(emulator.framebuffer().len(), width * height);
+    assert_eq!(emulator.framebuffer_rgba().len(), width * height * 4);
+    assert!(emulator.audio_samples().is_empty());
+  }
+}
@@ -72,22 +72,118 @@ impl CacheRegion {
     self.cache.remove(&key)
   }
 
-  pub fn invalidate_containing(&mut self, address: u16) -> Option<CodeBlock> {
+  /// Remove the block in the current bank containing an address, returning
+  /// the block along with the location it started at
+  pub fn invalidate_containing(&mut self, address: u16) -> Option<(MemoryLocation, CodeBlock)> {
     let mut found = None;
     for (key, block) in self.cache.iter() {
-      let ip = MemoryLocation::from_u32(*key).address;
+      let location = MemoryLocation::from_u32(*key);
+      let ip = location.address;
       let length = block.bytes_translated as u16;
-      if address >= ip && address < ip + length {
+      if location.bank == self.current_bank && address >= ip && address < ip + length {
         found = Some(*key);
         break;
       }
     }
-    found.and_then(|key| self.cache.remove(&key))
+    found.and_then(|key| {
+      self.cache.remove(&key).map(|block| (MemoryLocation::from_u32(key), block))
+    })
+  }
+
+  /// Remove a block containing an address from whichever bank it was
+  /// compiled in, returning the block along with the location it started at
+  pub fn invalidate_containing_in_any_bank(&mut self, address: u16) -> Option<(MemoryLocation, CodeBlock)> {
+    let key = self.cache.iter().find_map(|(key, block)| {
+      let ip = MemoryLocation::from_u32(*key).address;
+      let contains = address >= ip && (address as usize) < ip as usize + block.bytes_translated;
+      if contains {
+        Some(*key)
+      } else {
+        None
+      }
+    })?;
+    self.cache.remove(&key).map(|block| (MemoryLocation::from_u32(key), block))
+  }
+
+  pub fn len(&self) -> usize {
+    self.cache.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.cache.is_empty()
+  }
+
+  /// Remove every block, in all banks
+  pub fn clear(&mut self) {
+    self.cache.clear();
   }
 
   pub fn set_bank(&mut self, bank: u16) {
     self.current_bank = bank;
   }
+
+  pub fn get_bank(&self) -> u16 {
+    self.current_bank
+  }
+}
+
+/// Bitmap of the addresses in a region of RAM that were translated into at
+/// least one cached block, with one bit per byte, laid out the same way as
+/// the dirty bitmaps kept for that region. Bits are set as blocks are cached,
+/// and only cleared by a rebuild, so the map may cover more than is actually
+/// cached but never less.
+pub struct Coverage {
+  base: u16,
+  bits: Vec<u64>,
+}
+
+impl Coverage {
+  pub fn new(base: u16, length: usize) -> Self {
+    Self {
+      base,
+      bits: vec![0; length.div_ceil(64)],
+    }
+  }
+
+  /// Mark the bytes translated into a block. Anything past the end of the
+  /// region is ignored.
+  pub fn mark(&mut self, start: u16, length: usize) {
+    let first = start.wrapping_sub(self.base) as usize;
+    let end = (first + length).min(self.bits.len() * 64);
+    for index in first..end {
+      self.bits[index / 64] |= 1 << (index % 64);
+    }
+  }
+
+  pub fn clear(&mut self) {
+    self.bits.iter_mut().for_each(|word| *word = 0);
+  }
+
+  /// Reset the map to cover exactly the blocks in a set of regions
+  pub fn rebuild(&mut self, regions: &[&CacheRegion]) {
+    self.clear();
+    for region in regions.iter() {
+      for (key, block) in region.cache.iter() {
+        self.mark(MemoryLocation::from_u32(*key).address, block.bytes_translated);
+      }
+    }
+  }
+
+  /// Addresses that are both covered and marked in a dirty bitmap. Whole
+  /// words are compared at once, so clean or uncovered stretches are skipped
+  /// 64 bytes at a time.
+  pub fn overlapping(&self, dirty: &[u64]) -> Vec<u16> {
+    let mut addresses = Vec::new();
+    for (index, (covered, dirty)) in self.bits.iter().zip(dirty.iter()).enumerate() {
+      let mut overlap = covered & dirty;
+      while overlap != 0 {
+        let bit = overlap.trailing_zeros() as usize;
+        addresses.push(self.base + (index * 64 + bit) as u16);
+        overlap &= overlap - 1;
+      }
+    }
+    addresses
+  }
 }
 
 /// CachedBlocks stores individual lookup caches for each region of memory that
@@ -113,6 +209,46 @@ impl CachedBlocks {
     }
   }
 
+  /// Select which ROM bank is mapped to the switchable region. Lookups and
+  /// insertions in 0x4000-0x7fff only see blocks compiled from that bank.
+  pub fn set_rom_bank(&mut self, bank: u16) {
+    self.rom_high.set_bank(bank);
+  }
+
+  /// Select which ROM bank is mapped to 0x0000-0x3fff
+  pub fn set_low_rom_bank(&mut self, bank: u16) {
+    self.rom_low.set_bank(bank);
+  }
+
+  /// Select which cartridge RAM bank is mapped to 0xa000-0xbfff
+  pub fn set_cart_ram_bank(&mut self, bank: u16) {
+    self.cart_ram.set_bank(bank);
+  }
+
+  /// Select which work RAM bank is mapped to 0xd000-0xdfff
+  pub fn set_wram_bank(&mut self, bank: u16) {
+    self.wram_high.set_bank(bank);
+  }
+
+  /// Total number of cached blocks across all regions
+  pub fn count(&self) -> usize {
+    self.rom_low.len()
+      + self.rom_high.len()
+      + self.cart_ram.len()
+      + self.wram_low.len()
+      + self.wram_high.len()
+      + self.high_ram.len()
+  }
+
+  pub fn clear(&mut self) {
+    self.rom_low.clear();
+    self.rom_high.clear();
+    self.cart_ram.clear();
+    self.wram_low.clear();
+    self.wram_high.clear();
+    self.high_ram.clear();
+  }
+
   pub fn get_region(&self, addr: u16) -> Option<&CacheRegion> {
     if addr < 0x4000 {
       return Some(&self.rom_low);
@@ -169,3 +305,29 @@ impl CachedBlocks {
     None
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::{CacheRegion, CodeBlock, Coverage};
+
+  #[test]
+  fn coverage_overlap() {
+    let mut coverage = Coverage::new(0xc000, 0x2000);
+    coverage.mark(0xc03e, 4);
+    coverage.mark(0xdffe, 8);
+    let mut dirty = [0u64; 128];
+    assert!(coverage.overlapping(&dirty).is_empty());
+    dirty[0] = 1 << 63;
+    dirty[1] = 0b11;
+    dirty[2] = 1;
+    dirty[127] = 1 << 63;
+    assert_eq!(coverage.overlapping(&dirty), vec![0xc03f, 0xc040, 0xc041, 0xdfff]);
+
+    let mut region = CacheRegion::new(0);
+    region.insert(0xc100, CodeBlock { offset: 0, length: 0, bytes_translated: 2 });
+    coverage.rebuild(&[&region]);
+    assert!(coverage.overlapping(&dirty).is_empty());
+    dirty[4] = 1 << 1;
+    assert_eq!(coverage.overlapping(&dirty), vec![0xc101]);
+  }
+}
@@ -0,0 +1,164 @@
+use crate::decoder::ops::Op;
+
+/// Compiled blocks that end in an unconditional jump to a fixed address can be
+/// linked directly to the compiled block at that address, skipping the trip
+/// back through the dispatcher. Each link is a rel32 jump operand in the
+/// source block's epilogue. While unlinked, the operand is zero and the jump
+/// falls through to the normal exit.
+pub struct BlockLink {
+  /// Offset in executable memory of the rel32 operand to patch
+  pub patch_offset: usize,
+  /// GB address of the block containing the jump
+  pub source_ip: u16,
+  /// Bank the block containing the jump was compiled from
+  pub source_bank: u16,
+  /// GB address the block jumps to
+  pub target_ip: u16,
+  /// Bank mapped at the target address when the link was created
+  pub target_bank: u16,
+  pub linked: bool,
+}
+
+pub struct LinkTable {
+  links: Vec<BlockLink>,
+}
+
+impl LinkTable {
+  pub fn new() -> Self {
+    Self {
+      links: Vec::new(),
+    }
+  }
+
+  pub fn add(&mut self, patch_offset: usize, source_ip: u16, source_bank: u16, target_ip: u16, target_bank: u16) {
+    self.links.push(
+      BlockLink {
+        patch_offset,
+        source_ip,
+        source_bank,
+        target_ip,
+        target_bank,
+        linked: false,
+      }
+    );
+  }
+
+  /// Unlinked jumps into a specific address and bank
+  pub fn pending_for_target(&mut self, target_ip: u16, target_bank: u16) -> impl Iterator<Item = &mut BlockLink> {
+    self.links
+      .iter_mut()
+      .filter(move |link| !link.linked && link.target_ip == target_ip && link.target_bank == target_bank)
+  }
+
+  /// Linked jumps into a specific address and bank
+  pub fn linked_to_target(&mut self, target_ip: u16, target_bank: u16) -> impl Iterator<Item = &mut BlockLink> {
+    self.links
+      .iter_mut()
+      .filter(move |link| link.linked && link.target_ip == target_ip && link.target_bank == target_bank)
+  }
+
+  /// Forget all links originating in a block that no longer exists. Blocks
+  /// at the same address in other banks keep theirs.
+  pub fn remove_source(&mut self, source_ip: u16, source_bank: u16) {
+    self.links.retain(|link| link.source_ip != source_ip || link.source_bank != source_bank);
+  }
+}
+
+impl Default for LinkTable {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Linking is only safe if the target is guaranteed to contain the same code
+/// when the jump executes as when the link was made. The fixed ROM bank always
+/// does. Within the switchable bank, a block that jumps to another address in
+/// the same region would be switching out its own code if it changed banks
+/// partway through, so the target can be assumed to come from the same bank.
+/// That doesn't hold for a block that switches banks just before its jump,
+/// like the identical stubs many games place at the same address in several
+/// banks, so blocks that may write to the MBC never link into banked code. On
+/// the few carts that can switch out the fixed bank too, it gets the same
+/// treatment.
+pub fn can_link(source_ip: u16, target_ip: u16, low_rom_banked: bool, may_switch_banks: bool) -> bool {
+  match target_ip {
+    0x0000..=0x3fff => !low_rom_banked || (source_ip < 0x4000 && !may_switch_banks),
+    0x4000..=0x7fff => (0x4000..=0x7fff).contains(&source_ip) && !may_switch_banks,
+    _ => false,
+  }
+}
+
+/// Whether any op in a block may write to the MBC registers mapped over ROM.
+/// Writes through BC, DE, or HL could land anywhere, so they all count.
+/// Pushes are left out, since no game keeps its stack in ROM.
+pub fn may_write_rom(ops: &[(Op, usize)]) -> bool {
+  ops.iter().any(|(op, _)| match op {
+    Op::LoadAToMemory(address, _) | Op::LoadStackPointerToMemory(address) => *address < 0x8000,
+    Op::LoadToIndirect(_, _)
+    | Op::LoadImmediateToHLIndirect(_)
+    | Op::IncrementHLIndirect
+    | Op::DecrementHLIndirect
+    | Op::RotateLeftCarryIndirect
+    | Op::RotateLeftIndirect
+    | Op::RotateRightCarryIndirect
+    | Op::RotateRightIndirect
+    | Op::ShiftLeftIndirect
+    | Op::ShiftRightIndirect
+    | Op::ShiftRightLogicalIndirect
+    | Op::SwapIndirect
+    | Op::BitClearIndirect(_)
+    | Op::BitSetIndirect(_) => true,
+    _ => false,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{can_link, may_write_rom, LinkTable};
+  use crate::decoder::ops::{IndirectLocation, Op, Register8};
+
+  #[test]
+  fn linkable_targets() {
+    assert!(can_link(0x4100, 0x0200, false, false));
+    assert!(can_link(0x4100, 0x4800, false, false));
+    assert!(!can_link(0x0100, 0x4800, false, false));
+    assert!(!can_link(0x0100, 0xc000, false, false));
+    assert!(!can_link(0x4100, 0x0200, true, false));
+    assert!(can_link(0x0100, 0x0200, true, false));
+    // a block that may have switched banks can only link into the fixed bank
+    assert!(!can_link(0x4100, 0x4800, false, true));
+    assert!(can_link(0x4100, 0x0200, false, true));
+    assert!(!can_link(0x0100, 0x0200, true, true));
+  }
+
+  #[test]
+  fn rom_writes() {
+    assert!(may_write_rom(&[(Op::LoadAToMemory(0x2000, true), 3)]));
+    assert!(!may_write_rom(&[(Op::LoadAToMemory(0xc000, true), 3), (Op::LoadToHighMem, 1)]));
+    assert!(may_write_rom(&[(Op::LoadToIndirect(IndirectLocation::HL, Register8::A), 1)]));
+    assert!(!may_write_rom(&[(Op::LoadFromIndirect(Register8::A, IndirectLocation::HL), 1)]));
+  }
+
+  #[test]
+  fn link_lifecycle() {
+    let mut table = LinkTable::new();
+    table.add(0x40, 0x100, 0, 0x200, 0);
+    table.add(0x80, 0x300, 0, 0x200, 0);
+    table.add(0xc0, 0x4100, 2, 0x4200, 2);
+    table.add(0x100, 0x4100, 3, 0x4200, 3);
+    assert_eq!(table.pending_for_target(0x200, 0).count(), 2);
+    for link in table.pending_for_target(0x200, 0) {
+      link.linked = true;
+    }
+    assert_eq!(table.pending_for_target(0x200, 0).count(), 0);
+    table.remove_source(0x100, 0);
+    assert_eq!(table.linked_to_target(0x200, 0).count(), 1);
+    // links into the switchable bank only match the bank they were made in
+    assert_eq!(table.pending_for_target(0x4200, 4).count(), 0);
+    assert_eq!(table.pending_for_target(0x4200, 2).count(), 1);
+    // removing a block leaves the one at the same address in another bank
+    table.remove_source(0x4100, 2);
+    assert_eq!(table.pending_for_target(0x4200, 2).count(), 0);
+    assert_eq!(table.pending_for_target(0x4200, 3).count(), 1);
+  }
+}
@@ -1,9 +1,11 @@
+use crate::error::JitError;
+
 pub struct ExecutableMemory {
   memory: Option<Box<[u8]>>,
 }
 
 impl ExecutableMemory {
-  pub fn new() -> Self {
+  pub fn new() -> Result<Self, JitError> {
     let size: usize = super::INITIAL_MEMORY_SIZE;
     let memory_area = unsafe {
       let pointer: *mut std::ffi::c_void = libc::mmap(
@@ -15,13 +17,13 @@ impl ExecutableMemory {
         0,
       );
       if pointer == libc::MAP_FAILED {
-        panic!("Unable to mmap executable memory");
+        return Err(JitError::NoExecutableMemory);
       }
       Vec::from_raw_parts(pointer as *mut u8, size, size).into_boxed_slice()
     };
-    Self {
+    Ok(Self {
       memory: Some(memory_area),
-    }
+    })
   }
 
   pub fn extend(&self) {
@@ -36,16 +38,16 @@ impl ExecutableMemory {
     self.memory.as_mut().unwrap()
   }
 
-  pub fn make_writable(&self) {
+  pub fn make_writable(&self) -> Result<(), JitError> {
     let size = self.memory.as_ref().unwrap().len();
     let address = self.memory.as_ref().unwrap().as_ptr() as *mut ();
-    apply_protection(address, size, libc::PROT_READ | libc::PROT_WRITE);
+    apply_protection(address, size, libc::PROT_READ | libc::PROT_WRITE)
   }
 
-  pub fn make_executable(&self) {
+  pub fn make_executable(&self) -> Result<(), JitError> {
     let size = self.memory.as_ref().unwrap().len();
     let address = self.memory.as_ref().unwrap().as_ptr() as *mut ();
-    apply_protection(address, size, libc::PROT_READ | libc::PROT_EXEC);
+    apply_protection(address, size, libc::PROT_READ | libc::PROT_EXEC)
   }
 }
 
@@ -62,15 +64,16 @@ impl Drop for ExecutableMemory {
   }
 }
 
-fn apply_protection(address: *mut (), size: usize, protection: i32) {
-  unsafe {
-    let result = libc::mprotect(
+fn apply_protection(address: *mut (), size: usize, protection: i32) -> Result<(), JitError> {
+  let result = unsafe {
+    libc::mprotect(
       address as *mut std::ffi::c_void,
       size,
       protection,
-    );
-    if result != 0 {
-      panic!("Failed to modify memory protection");
-    }
+    )
+  };
+  if result != 0 {
+    return Err(JitError::NoExecutableMemory);
   }
+  Ok(())
 }
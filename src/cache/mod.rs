@@ -1,28 +1,110 @@
 pub mod blocks;
+pub mod links;
+pub mod profile;
+pub mod worker;
 #[cfg(unix)]
 pub mod linux;
 #[cfg(windows)]
 pub mod windows;
 
-use blocks::{CachedBlocks, CodeBlock, MemoryLocation};
+use blocks::{CachedBlocks, CodeBlock, Coverage, MemoryLocation};
+use links::LinkTable;
+use profile::BlockProfile;
+use worker::{Banks, CompileJob, CompiledCode, CompileWorker};
 use crate::cpu::Registers;
 use crate::decoder::decode;
-use crate::emitter::Emitter;
-use crate::mem::MemoryAreas;
+use crate::decoder::ops::{JumpCondition, Op};
+use crate::emitter::{flush_instruction_cache, write_link_displacement, Emitter, LINK_PATCH_OFFSET};
+use crate::error::{JitError, MemError};
+use crate::mem::{get_cart_ram_code, read_straddling_instruction, MemoryAreas};
+use crate::timing::MachineCycles;
+use std::collections::{BTreeMap, BTreeSet};
 
 #[cfg(unix)]
 use linux::ExecutableMemory;
 #[cfg(windows)]
 use self::windows::ExecutableMemory;
 
+/// The prelude is called with (registers, block address, epilogue address,
+/// cycle budget, memory). On x86_64 it always uses the System V ABI, even on
+/// Windows.
+#[cfg(target_arch = "x86_64")]
+type EntryFunction = extern "sysv64" fn(*const Registers, usize, usize, usize, *mut MemoryAreas) -> u8;
+#[cfg(not(target_arch = "x86_64"))]
+type EntryFunction = extern "C" fn(*const Registers, usize, usize, usize, *mut MemoryAreas) -> u8;
+
 pub const INITIAL_MEMORY_SIZE: usize = 0x800000;
 pub const MEMORY_MINIMUM_SIZE: usize = 0x1000;
 pub const MEMORY_SIZE_INCREASE: usize = 0x1000;
+/// Times a block is interpreted before it is compiled, unless configured
+/// otherwise
+pub const DEFAULT_COMPILE_THRESHOLD: u32 = 2;
+
+/// Counters describing how the code cache is being used
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+  /// Number of compiled blocks currently cached
+  pub blocks: usize,
+  /// Bytes of executable memory occupied by compiled blocks
+  pub bytes_used: usize,
+  /// Bytes of executable memory available to compiled blocks
+  pub capacity: usize,
+  /// Total number of blocks compiled since startup
+  pub translations: usize,
+  /// Number of times the cache has filled up and been emptied
+  pub flushes: usize,
+  /// Blocks looked up before running them, and how many of those had
+  /// already been compiled
+  pub lookups: usize,
+  pub hits: usize,
+}
 
 pub struct CodeCache {
-  exec_memory: ExecutableMemory,
+  /// Memory holding compiled code. If it couldn't be allocated, or its
+  /// protection later couldn't be changed, nothing is compiled and every
+  /// block is interpreted.
+  exec_memory: Result<ExecutableMemory, JitError>,
   code_blocks: CachedBlocks,
+  links: LinkTable,
+  /// RAM translated into cached blocks, checked against the bytes written
+  /// since code last ran
+  cart_ram_coverage: Coverage,
+  wram_coverage: Coverage,
+  hram_coverage: Coverage,
   write_cursor: usize,
+  /// Compiled blocks are written after the shared prologue and epilogue,
+  /// starting at this offset
+  code_start: usize,
+  /// Maximum number of bytes used by compiled blocks
+  capacity: usize,
+  translations: usize,
+  flushes: usize,
+  lookups: usize,
+  hits: usize,
+  /// When set, blocks ending in an unconditional jump are patched to continue
+  /// directly into the compiled block at the destination
+  pub link_blocks: bool,
+  /// When set, flags are only computed if a later op in the block reads them
+  /// before they are overwritten
+  pub lazy_flags: bool,
+  /// When set, every run of a compiled block is counted
+  profile: Option<BlockProfile>,
+  /// A block is interpreted this many times before it is compiled, so that
+  /// code which only runs once or twice, like startup and error handling,
+  /// never takes up space in the cache. Zero compiles every block the first
+  /// time it runs.
+  pub compile_threshold: u32,
+  /// Runs of each block that hasn't been compiled yet, by bank and address.
+  /// These are kept across flushes, so that hot code is compiled again as
+  /// soon as it is reached.
+  visits: BTreeMap<u32, u32>,
+  /// Compiles blocks in ROM off the emulation thread, when set
+  worker: Option<CompileWorker>,
+  /// Blocks sent to the worker that haven't been published yet
+  pending: BTreeSet<u32>,
+  /// Counts flushes and changes to ROM. Blocks decoded before the latest one
+  /// are out of date by the time the worker finishes them.
+  generation: usize,
 
   prologue_location: usize,
   epilogue_location: usize,
@@ -33,37 +115,217 @@ impl CodeCache {
     let mut cache = Self {
       exec_memory: ExecutableMemory::new(),
       code_blocks: CachedBlocks::new(),
+      links: LinkTable::new(),
+      cart_ram_coverage: Coverage::new(0xa000, 0x2000),
+      wram_coverage: Coverage::new(0xc000, 0x2000),
+      hram_coverage: Coverage::new(0xff80, 0x80),
       write_cursor: 0,
+      code_start: 0,
+      capacity: 0,
+      translations: 0,
+      flushes: 0,
+      lookups: 0,
+      hits: 0,
+      link_blocks: true,
+      lazy_flags: true,
+      profile: None,
+      compile_threshold: DEFAULT_COMPILE_THRESHOLD,
+      visits: BTreeMap::new(),
+      worker: None,
+      pending: BTreeSet::new(),
+      generation: 0,
 
       prologue_location: 0,
       epilogue_location: 0,
     };
-    cache.write_prelude_block();
-    cache.write_epilogue_block();
+    if cache.write_prelude_block().and_then(|_| cache.write_epilogue_block()).is_ok() {
+      cache.code_start = cache.write_cursor;
+      cache.capacity = cache.memory_area().len() - cache.code_start;
+    }
 
     cache
   }
 
-  pub fn write_prelude_block(&mut self) {
+  /// Discard every compiled block. Blocks are stored back-to-back, so rather
+  /// than evicting individual blocks and compacting the remainder, the whole
+  /// cache is emptied once it fills up. Frequently run code is recompiled the
+  /// next time it is reached.
+  pub fn flush(&mut self) {
+    self.code_blocks.clear();
+    self.links = LinkTable::new();
+    self.cart_ram_coverage.clear();
+    self.wram_coverage.clear();
+    self.hram_coverage.clear();
+    self.write_cursor = self.code_start;
+    self.flushes += 1;
+    self.generation += 1;
+    self.pending.clear();
+  }
+
+  pub fn get_stats(&self) -> CacheStats {
+    CacheStats {
+      blocks: self.code_blocks.count(),
+      bytes_used: self.write_cursor - self.code_start,
+      capacity: self.capacity,
+      translations: self.translations,
+      flushes: self.flushes,
+      lookups: self.lookups,
+      hits: self.hits,
+    }
+  }
+
+  /// Start counting how often each compiled block runs, and the cycles it
+  /// takes, or stop and discard the counts
+  pub fn set_profiling(&mut self, enabled: bool) {
+    if enabled != self.profile.is_some() {
+      self.profile = enabled.then(BlockProfile::new);
+    }
+  }
+
+  pub fn is_profiling(&self) -> bool {
+    self.profile.is_some()
+  }
+
+  pub fn get_profile(&self) -> Option<&BlockProfile> {
+    self.profile.as_ref()
+  }
+
+  pub fn get_profile_mut(&mut self) -> Option<&mut BlockProfile> {
+    self.profile.as_mut()
+  }
+
+  /// Count a run of the block at `ip`, which has just finished. Linked blocks
+  /// continue into each other without returning, so a profiled block must be
+  /// called with a budget of zero for its cycles to be its own.
+  pub fn record_block_run(&mut self, ip: usize, cycles: usize) {
+    let bank = self.get_bank_for_address(ip as u16);
+    let length = self.get_block(ip).map(|block| block.bytes_translated).unwrap_or(0);
+    if let Some(profile) = self.profile.as_mut() {
+      profile.record(MemoryLocation::new(bank, ip as u16), length, cycles);
+    }
+  }
+
+  /// Count a run of the uncompiled block at `ip`, returning whether it has
+  /// been interpreted enough times to compile it now
+  pub fn count_visit(&mut self, ip: usize) -> bool {
+    if self.compile_threshold == 0 {
+      return true;
+    }
+    let bank = self.get_bank_for_address(ip as u16);
+    let visits = self.visits.entry(MemoryLocation::new(bank, ip as u16).as_u32()).or_insert(0);
+    if *visits >= self.compile_threshold {
+      return true;
+    }
+    *visits += 1;
+    false
+  }
+
+  /// Forget how often blocks have run, such as when a different game starts
+  pub fn clear_visits(&mut self) {
+    self.visits.clear();
+  }
+
+  #[cfg(test)]
+  pub fn set_capacity(&mut self, capacity: usize) {
+    self.capacity = capacity;
+  }
+
+  pub fn write_prelude_block(&mut self) -> Result<(), JitError> {
     self.prologue_location = self.write_cursor;
-    self.exec_memory.make_writable();
-    let write_area = self.exec_memory.get_memory_area_mut();
-    let length = Emitter::write_prelude_function(&mut write_area[self.write_cursor..]);
+    self.set_writable(true)?;
+    let write_cursor = self.write_cursor;
+    let write_area = self.memory_area_mut();
+    let length = Emitter::write_prelude_function(&mut write_area[write_cursor..]);
+    flush_instruction_cache(&write_area[write_cursor..(write_cursor + length)]);
     self.write_cursor += length;
-    self.exec_memory.make_executable();
+    self.set_writable(false)
   }
 
-  pub fn write_epilogue_block(&mut self) {
+  pub fn write_epilogue_block(&mut self) -> Result<(), JitError> {
     self.epilogue_location = self.write_cursor;
-    self.exec_memory.make_writable();
-    let write_area = self.exec_memory.get_memory_area_mut();
-    let length = Emitter::write_epilogue_function(&mut write_area[self.write_cursor..]);
+    self.set_writable(true)?;
+    let write_cursor = self.write_cursor;
+    let write_area = self.memory_area_mut();
+    let length = Emitter::write_epilogue_function(&mut write_area[write_cursor..]);
+    flush_instruction_cache(&write_area[write_cursor..(write_cursor + length)]);
     self.write_cursor += length;
-    self.exec_memory.make_executable();
+    self.set_writable(false)
+  }
+
+  /// Fails if there is no executable memory to compile blocks into
+  pub fn check_available(&self) -> Result<(), JitError> {
+    match &self.exec_memory {
+      Ok(_) => Ok(()),
+      Err(error) => Err(error.clone()),
+    }
+  }
+
+  /// Give up the executable memory, as if it couldn't be allocated
+  #[cfg(test)]
+  pub fn discard_memory(&mut self) {
+    self.flush();
+    self.exec_memory = Err(JitError::NoExecutableMemory);
+  }
+
+  /// Switch executable memory between writable and executable. If its
+  /// protection can't be changed, the memory is released along with every
+  /// compiled block, and nothing more is compiled.
+  fn set_writable(&mut self, writable: bool) -> Result<(), JitError> {
+    let result = match &self.exec_memory {
+      Ok(memory) if writable => memory.make_writable(),
+      Ok(memory) => memory.make_executable(),
+      Err(error) => return Err(error.clone()),
+    };
+    if let Err(error) = result {
+      self.flush();
+      self.exec_memory = Err(error.clone());
+      return Err(error);
+    }
+    Ok(())
+  }
+
+  fn memory_area(&self) -> &[u8] {
+    self.exec_memory.as_ref().expect("Executable memory is unavailable").get_memory_area()
+  }
+
+  fn memory_area_mut(&mut self) -> &mut [u8] {
+    self.exec_memory.as_mut().expect("Executable memory is unavailable").get_memory_area_mut()
   }
 
   pub fn get_memory_start_address(&self) -> usize {
-    self.exec_memory.get_memory_area().as_ptr() as *const () as usize
+    self.memory_area().as_ptr() as *const () as usize
+  }
+
+  /// Must be called whenever the mapped ROM bank may have changed, before
+  /// looking up blocks in the switchable region
+  pub fn set_rom_bank(&mut self, bank: usize) {
+    self.code_blocks.set_rom_bank(bank as u16);
+  }
+
+  /// Must be called whenever the ROM bank mapped to 0x0000-0x3fff may have
+  /// changed, before looking up blocks there
+  pub fn set_low_rom_bank(&mut self, bank: usize) {
+    self.code_blocks.set_low_rom_bank(bank as u16);
+  }
+
+  /// Must be called whenever the mapped cartridge RAM bank may have changed,
+  /// before looking up blocks in 0xa000-0xbfff
+  pub fn set_cart_ram_bank(&mut self, bank: usize) {
+    self.code_blocks.set_cart_ram_bank(bank as u16);
+  }
+
+  /// Must be called whenever the mapped work RAM bank may have changed,
+  /// before looking up blocks in 0xd000-0xdfff
+  pub fn set_wram_bank(&mut self, bank: usize) {
+    self.code_blocks.set_wram_bank(bank as u16);
+  }
+
+  /// Bank currently selected for the cache region containing an address
+  fn get_bank_for_address(&self, ip: u16) -> u16 {
+    self.code_blocks
+      .get_region(ip)
+      .map(|region| region.get_bank())
+      .unwrap_or(0)
   }
 
   pub fn get_address_for_ip(&self, ip: usize) -> Option<usize> {
@@ -74,89 +336,296 @@ impl CodeCache {
       .and_then(|block| Some(block.offset))
   }
 
-  pub fn get_executable_memory_segment(&self, ip: usize, mem_ptr: *const MemoryAreas) -> &[u8] {
+  /// Find the compiled block to run at `ip`, counting how often one is found
+  pub fn lookup_block(&mut self, ip: usize) -> Option<usize> {
+    let address = self.get_address_for_ip(ip);
+    self.lookups += 1;
+    if address.is_some() {
+      self.hits += 1;
+    }
+    address
+  }
+
+  /// Code that a block starting at `ip` can be compiled from, up to the end
+  /// of the bank or region containing it
+  pub fn get_executable_memory_segment(&self, ip: usize, mem_ptr: *const MemoryAreas) -> Result<&[u8], MemError> {
     let mem = unsafe { &*mem_ptr };
-    match ip {
-      0x0000..=0x3fff => &mem.rom[ip..0x4000],
+    let segment = match ip {
+      0x0000..=0x3fff => {
+        let bank_start = mem.get_low_rom_bank() * 0x4000;
+        &mem.rom[(bank_start + ip)..(bank_start + 0x4000)]
+      },
       0x4000..=0x7fff => {
         let bank_start = mem.get_rom_bank() * 0x4000;
         let bank_end = bank_start + 0x4000;
         let offset = (ip & 0x3fff) + bank_start;
         &mem.rom[offset..bank_end]
       },
-      //0xc000..=0xcfff => &mem.work_ram[(ip & 0xfff)..0x1000],
-      //0xd000..=0xdfff => {
-      //  let bank_start = mem.wram_bank * 0x1000;
-      //  let bank_end = bank_start + 0x1000;
-      //  let offset = (ip & 0xfff) + bank_start;
-      //  &mem.work_ram[offset..bank_end]
-      //},
-      //0xff80..=0xfffe => &mem.high_ram[(ip & 0x7f)..],
-      _ => panic!("TRIED TO EXECUTE {:X}", ip),
-    }
-  }
-
-  pub fn translate_code_block(&mut self, code: &Box<[u8]>, ip: usize, mem: *const MemoryAreas) -> usize {
-    let mut write_cursor = self.write_cursor;
-    let starting_offset = write_cursor;
-
-    let emitter = Emitter::new(mem);
-    let available_length = {
-      self.exec_memory.make_writable();
-      let translated = self.exec_memory.get_memory_area_mut();
-      //write_cursor += emitter.encode_prelude(&mut translated[write_cursor..]);
-      translated.len()
+      0xa000..=0xbfff => get_cart_ram_code(ip, mem),
+      0xc000..=0xcfff => &mem.work_ram[(ip & 0xfff)..0x1000],
+      0xd000..=0xdfff => {
+        let bank_start = mem.wram_bank * 0x1000;
+        let bank_end = bank_start + 0x1000;
+        let offset = (ip & 0xfff) + bank_start;
+        &mem.work_ram[offset..bank_end]
+      },
+      0xff80..=0xfffe => &mem.high_ram[(ip & 0x7f)..],
+      _ => return Err(MemError::NotExecutable(ip as u16)),
+    };
+    Ok(segment)
+  }
+
+  /// Compile the block starting at `ip`, and return the offset of its code.
+  /// Fails if there is no code to compile at `ip`, or no executable memory to
+  /// compile it into; a block that runs off the end of its region just ends
+  /// there.
+  pub fn translate_code_block(&mut self, code: &Box<[u8]>, ip: usize, mem: *const MemoryAreas) -> Result<usize, JitError> {
+    let job = self.decode_block(ip, mem)?;
+    self.publish(worker::compile(job))
+  }
+
+  /// Compile the block starting at `ip`, returning the offset of its code.
+  /// With background compilation, a block in ROM is queued for the worker
+  /// instead, and nothing is returned until it has been published. Code in
+  /// RAM can change before the worker gets to it, so it is still compiled
+  /// right away.
+  pub fn request_block(&mut self, ip: usize, mem: *const MemoryAreas) -> Option<usize> {
+    if self.worker.is_none() || ip >= 0x8000 {
+      return self.decode_block(ip, mem).ok().and_then(|job| self.publish(worker::compile(job)).ok());
+    }
+    let location = MemoryLocation::new(self.get_bank_for_address(ip as u16), ip as u16).as_u32();
+    if !self.pending.contains(&location) {
+      if let (Ok(job), Some(worker)) = (self.decode_block(ip, mem), self.worker.as_ref()) {
+        worker.submit(job);
+        self.pending.insert(location);
+      }
+    }
+    None
+  }
+
+  /// Decode the block starting at `ip`, to be compiled here or on the worker
+  fn decode_block(&mut self, ip: usize, mem: *const MemoryAreas) -> Result<CompileJob, JitError> {
+    self.check_available()?;
+    if self.get_executable_memory_segment(ip, mem).is_err() {
+      return Err(JitError::Untranslatable(ip as u16));
+    }
+    let memory = unsafe { &*mem };
+    // blocks are always cached under the bank they were compiled from
+    let banks = Banks {
+      rom: memory.get_rom_bank(),
+      low_rom: memory.get_low_rom_bank(),
+      cart_ram: memory.cart_state.get_ram_bank(),
+      wram: memory.wram_bank,
     };
+    self.set_banks(banks);
 
+    // Decode the entire block first, so that it can be analyzed and optimized
+    // before anything is emitted
+    let mut ops = Vec::new();
     let mut block_ended = false;
+    let mut link_target = None;
     let mut index = ip;
+    #[cfg(feature = "dump_disassembly")]
+    let mut source = Vec::new();
     while !block_ended {
-      let code_slice = self.get_executable_memory_segment(index, mem);
-      if code_slice.len() < 1 {
+      // a block in RAM can run off the end of high RAM, into echo RAM, or
+      // out of cartridge RAM
+      if index >= 0xffff || (0xe000..0xff80).contains(&index) || ((0xa000..0xc000).contains(&ip) && index >= 0xc000) {
         break;
       }
+      let code_slice = match self.get_executable_memory_segment(index, mem) {
+        Ok(code_slice) if !code_slice.is_empty() => code_slice,
+        _ => break,
+      };
+      let straddling;
+      let code_slice = if code_slice.len() < 3 {
+        straddling = read_straddling_instruction(index, mem);
+        &straddling[..]
+      } else {
+        code_slice
+      };
       let (next_op, length, _cycles) = decode(code_slice);
+      #[cfg(feature = "dump_disassembly")]
+      source.extend_from_slice(&code_slice[..length]);
       index += length;
       block_ended = next_op.is_block_end();
-      let translated = self.exec_memory.get_memory_area_mut();
-      let written = emitter.encode_op(next_op, length, &mut translated[write_cursor..]);
-      write_cursor += written;
+      if block_ended {
+        link_target = get_link_target(&next_op, index);
+      }
+      ops.push((next_op, length));
+    }
+    let may_switch_banks = links::may_write_rom(&ops);
+    let link_target = link_target.filter(|target| {
+      self.link_blocks && links::can_link(ip as u16, *target, memory.is_low_rom_banked(), may_switch_banks)
+    });
+
+    Ok(CompileJob {
+      ip,
+      ops,
+      bytes_translated: index - ip,
+      link_target,
+      lazy_flags: self.lazy_flags,
+      banks,
+      generation: self.generation,
+      #[cfg(feature = "dump_disassembly")]
+      source,
+      #[cfg(feature = "dump_disassembly")]
+      source_bank: crate::debug::breakpoint::get_bank_for_address(ip as u16, memory),
+    })
+  }
+
+  fn set_banks(&mut self, banks: Banks) {
+    self.set_rom_bank(banks.rom);
+    self.set_low_rom_bank(banks.low_rom);
+    self.set_cart_ram_bank(banks.cart_ram);
+    self.set_wram_bank(banks.wram);
+  }
+
+  /// Copy a compiled block into executable memory at the write cursor, and
+  /// link it to the blocks around it. Returns the offset of its code. This
+  /// leaves the banks it was compiled under selected.
+  fn publish(&mut self, compiled: CompiledCode) -> Result<usize, JitError> {
+    let job = &compiled.job;
+    let ip = job.ip;
+    self.set_banks(job.banks);
+    let space_remaining = self.code_start + self.capacity - self.write_cursor;
+    if space_remaining < MEMORY_MINIMUM_SIZE.max(compiled.code.len()) {
+      self.flush();
+    }
+    self.translations += 1;
+
+    let starting_offset = self.write_cursor;
+    let write_cursor = starting_offset + compiled.code.len();
+    self.set_writable(true)?;
+    {
+      let translated = self.memory_area_mut();
+      translated[starting_offset..write_cursor].copy_from_slice(&compiled.code);
+      flush_instruction_cache(&translated[starting_offset..write_cursor]);
+    }
+    if let Some(target) = job.link_target {
+      let target_bank = self.get_bank_for_address(target);
+      let patch_offset = starting_offset + compiled.epilogue_offset + LINK_PATCH_OFFSET;
+      self.links.add(patch_offset, ip as u16, self.get_bank_for_address(ip as u16), target, target_bank);
     }
+    self.write_cursor = write_cursor;
 
-    
     #[cfg(feature = "dump_disassembly")]
     {
-      let code_slice = match ip {
-        0..=0x7fff => &code[ip..index],
-        0xc000..=0xdfff => unsafe {
-          &(*mem).work_ram[(ip - 0xc000)..(index - 0xc000)]
-        },
-        _ => panic!(),
+      let host_code = &self.memory_area()[starting_offset..write_cursor];
+      let dump = crate::debug::dump::BlockDump {
+        bank: job.source_bank,
+        address: ip as u16,
+        source: &job.source,
+        op_lengths: &compiled.op_lengths,
+        host_code,
+        host_address: host_code.as_ptr() as u64,
+        op_offsets: &compiled.op_offsets,
+        epilogue_offset: compiled.epilogue_offset,
       };
-      let disassembly = crate::debug::disassembly::disassemble(ip as u16, code_slice);
-      for instr in disassembly.iter() {
-        println!("{}", instr);
+      if let Err(error) = dump.write_to(&crate::debug::dump::dump_directory()) {
+        eprintln!("Unable to write the listing for {:04X}: {}", ip, error);
       }
-      println!("  ==  ");
     }
-    
-    {
-      let translated = self.exec_memory.get_memory_area_mut();
-      write_cursor += emitter.encode_epilogue(&mut translated[write_cursor..]);
+
+    self.insert_code_block(ip, starting_offset, write_cursor - starting_offset, job.bytes_translated);
+    // Link any blocks waiting on this one, and this block to its successor
+    self.resolve_links(ip as u16, self.get_bank_for_address(ip as u16), starting_offset);
+    if let Some(target) = job.link_target {
+      if let Some(target_offset) = self.get_address_for_ip(target as usize) {
+        self.resolve_links(target, self.get_bank_for_address(target), target_offset);
+      }
     }
 
-    self.exec_memory.make_executable();
-    self.write_cursor = write_cursor;
+    self.set_writable(false)?;
+
+    Ok(starting_offset)
+  }
+
+  /// Compile blocks in ROM on a worker thread, interpreting each one until it
+  /// has been compiled, or go back to compiling every block right away
+  pub fn set_background_compilation(&mut self, enabled: bool) {
+    if enabled != self.worker.is_some() {
+      self.worker = enabled.then(CompileWorker::spawn);
+      self.pending.clear();
+    }
+  }
+
+  pub fn is_compiling_in_background(&self) -> bool {
+    self.worker.is_some()
+  }
+
+  /// Place every block the worker has finished since the last check into
+  /// the cache. Blocks whose code may have changed since they were decoded
+  /// are thrown away. Banks must be selected again afterwards.
+  pub fn publish_finished(&mut self) {
+    while let Some(compiled) = self.worker.as_ref().and_then(|worker| worker.try_finished()) {
+      self.publish_from_worker(compiled);
+    }
+  }
 
-    let bytes_translated = index - ip;
-    self.insert_code_block(ip, starting_offset, write_cursor - starting_offset, bytes_translated);
+  /// Wait for the worker to finish the oldest block it was sent, and publish
+  /// it
+  #[cfg(test)]
+  pub fn wait_for_worker(&mut self) {
+    if let Some(compiled) = self.worker.as_ref().and_then(|worker| worker.wait_finished()) {
+      self.publish_from_worker(compiled);
+    }
+  }
+
+  fn publish_from_worker(&mut self, compiled: CompiledCode) {
+    // the code may have changed since it was decoded, and a newer job for
+    // the same block may already be pending
+    if compiled.job.generation != self.generation {
+      return;
+    }
+    let job = &compiled.job;
+    self.set_banks(job.banks);
+    self.pending.remove(&MemoryLocation::new(self.get_bank_for_address(job.ip as u16), job.ip as u16).as_u32());
+    if self.get_block(job.ip).is_none() {
+      // if this fails, the cache is left empty and every block is interpreted
+      let _ = self.publish(compiled);
+    }
+  }
+
+  /// Patch every unlinked jump into `target_ip` to go directly to the
+  /// compiled code at `target_offset`. Executable memory must be writable.
+  fn resolve_links(&mut self, target_ip: u16, target_bank: u16, target_offset: usize) {
+    let write_area = self.exec_memory.as_mut().expect("Executable memory is unavailable").get_memory_area_mut();
+    for link in self.links.pending_for_target(target_ip, target_bank) {
+      write_link_displacement(write_area, link.patch_offset, Some(target_offset));
+      link.linked = true;
+    }
+  }
 
-    let space_remaining = available_length - write_cursor;
-    if space_remaining < MEMORY_MINIMUM_SIZE {
-      println!("Running out of space, only {} bytes left", space_remaining);
+  /// Called when a compiled block is discarded. Jumps into the block revert to
+  /// exiting through the dispatcher, and jumps out of it are forgotten.
+  fn unlink_block(&mut self, location: MemoryLocation) {
+    let MemoryLocation { bank, address: ip } = location;
+    // if the memory can't be made writable, every block has already been
+    // discarded along with it
+    if self.set_writable(true).is_err() {
+      return;
     }
+    let write_area = self.exec_memory.as_mut().expect("Executable memory is unavailable").get_memory_area_mut();
+    for link in self.links.linked_to_target(ip, bank) {
+      write_link_displacement(write_area, link.patch_offset, None);
+      link.linked = false;
+    }
+    if self.set_writable(false).is_ok() {
+      self.links.remove_source(ip, bank);
+    }
+  }
+
+  pub fn get_block(&self, ip: usize) -> Option<&CodeBlock> {
+    let gb_ip = ip as u16;
+    self.code_blocks
+      .get_region(gb_ip)
+      .and_then(|region| region.get(gb_ip))
+  }
 
-    starting_offset
+  /// Returns the host machine code emitted for a cached block
+  pub fn get_emitted_bytes(&self, block: &CodeBlock) -> &[u8] {
+    &self.memory_area()[block.offset..(block.offset + block.length)]
   }
 
   fn insert_code_block(&mut self, ip: usize, offset: usize, length: usize, bytes_translated: usize) {
@@ -171,55 +640,180 @@ impl CodeCache {
         bytes_translated,
       },
     );
+    match ip {
+      0xa000..=0xbfff => self.cart_ram_coverage.mark(ip as u16, bytes_translated),
+      0xc000..=0xdfff => self.wram_coverage.mark(ip as u16, bytes_translated),
+      0xff80..=0xfffe => self.hram_coverage.mark(ip as u16, bytes_translated),
+      _ => (),
+    }
   }
 
-  pub fn call(&self, offset: usize, registers: &mut Registers) -> u8 {
+  /// Run the compiled code at `offset`, against the memory at `mem`. Linked
+  /// blocks continue running until at least `cycle_budget` machine cycles
+  /// have been used, at which point the chain exits at the next link so
+  /// peripherals can catch up.
+  pub fn call(&self, offset: usize, registers: &mut Registers, mem: *mut MemoryAreas, cycle_budget: MachineCycles) -> u8 {
     let memory_start = self.get_memory_start_address();
     let func_pointer = (memory_start + self.prologue_location) as *const ();
-    let func: extern "sysv64" fn(*const Registers, usize, usize) -> u8 = unsafe {
+    let func: EntryFunction = unsafe {
       std::mem::transmute(func_pointer)
     };
     let block_addr = memory_start + offset;
     let epilogue_addr = memory_start + self.epilogue_location;
-    func(registers as *const Registers, block_addr, epilogue_addr)
+    // cycles are counted in 16 bits
+    let budget = cycle_budget.as_usize().min(0xffff);
+    // anything outside of compiled code may have changed what it can access
+    // directly, from bank switches to new watchpoints
+    unsafe { (*mem).update_page_table() };
+    func(registers as *const Registers, block_addr, epilogue_addr, budget, mem)
+  }
+
+  /// Discard every block compiled from a byte of ROM that has changed, like
+  /// one patched by a cheat code. `address` is where the byte appears on the
+  /// bus when `bank` is mapped.
+  pub fn invalidate_rom(&mut self, bank: usize, address: u16) {
+    if address >= 0x8000 {
+      return;
+    }
+    // blocks still with the worker may include the old byte
+    self.generation += 1;
+    self.pending.clear();
+    let mapped_bank = self.get_bank_for_address(0x4000);
+    let mapped_low_bank = self.get_bank_for_address(0);
+    if address >= 0x4000 {
+      self.code_blocks.set_rom_bank(bank as u16);
+    } else {
+      self.code_blocks.set_low_rom_bank(bank as u16);
+    }
+    loop {
+      let invalidated = self.code_blocks
+        .get_region_mut(address)
+        .and_then(|region| region.invalidate_containing(address));
+      match invalidated {
+        Some((location, _)) => self.unlink_block(location),
+        None => break,
+      }
+    }
+    self.code_blocks.set_rom_bank(mapped_bank);
+    self.code_blocks.set_low_rom_bank(mapped_low_bank);
+  }
+
+  /// Discard every block compiled from cartridge RAM that has since been
+  /// written. Each bit of `dirty_flags` marks a byte from 0xa000 to 0xbfff,
+  /// in whichever bank was mapped, so matching blocks are discarded from
+  /// every bank.
+  pub fn invalidate_dirty_cart_ram(&mut self, dirty_flags: &[u64; 128]) {
+    let addresses = self.cart_ram_coverage.overlapping(dirty_flags);
+    if addresses.is_empty() {
+      return;
+    }
+    for address in addresses {
+      while let Some((location, _)) = self.code_blocks.cart_ram.invalidate_containing_in_any_bank(address) {
+        self.unlink_block(location);
+      }
+    }
+    self.cart_ram_coverage.rebuild(&[&self.code_blocks.cart_ram]);
   }
 
+  /// Discard every block compiled from work RAM that has since been written.
+  /// Each bit of `dirty_flags` marks a byte from 0xc000 to 0xdfff. Since the
+  /// flags don't say which bank was mapped, blocks in 0xd000-0xdfff are
+  /// discarded from every bank.
   pub fn invalidate_dirty_wram(&mut self, dirty_flags: &[u64; 128]) {
-    // this is incredibly, stupidly inefficient
-    // a faster method would be to iterate over all cached ranges,
-    // turn them into a series of bitmaps, and AND them with the dirty bitmaps
-    // to see if there's any overlap
-    for i in 0..128 {
-      let mut entry = dirty_flags[i];
-      if entry != 0 {
-        let mut addr = 0xc000 + (i as u16) * 64;
-        for _ in 0..64 {
-          if entry & 1 != 0 {
-            if i < 64 {
-              self.code_blocks.wram_low.invalidate_containing(addr);
-            } else {
-              self.code_blocks.wram_high.invalidate_containing(addr);
-            }
-          }
-          addr += 1;
-          entry >>= 1;
+    let addresses = self.wram_coverage.overlapping(dirty_flags);
+    if addresses.is_empty() {
+      return;
+    }
+    for address in addresses {
+      // blocks starting in the lower half can run past 0xd000
+      loop {
+        let invalidated = self.code_blocks.wram_low.invalidate_containing(address)
+          .or_else(|| self.code_blocks.wram_high.invalidate_containing_in_any_bank(address));
+        match invalidated {
+          Some((location, _)) => self.unlink_block(location),
+          None => break,
         }
       }
     }
+    self.wram_coverage.rebuild(&[&self.code_blocks.wram_low, &self.code_blocks.wram_high]);
   }
 
+  /// Discard every block compiled from high RAM that has since been written.
+  /// Each bit of `dirty_flags` marks a byte from 0xff80 to 0xffff.
   pub fn invalidate_dirty_hram(&mut self, dirty_flags: &[u64; 2]) {
-    for (key, block) in self.code_blocks.high_ram.cache.iter() {
-      let ip = MemoryLocation::from_u32(*key).address;
-      let length = block.bytes_translated as u16;
-      let first_index = ((ip & 0x7f) / 64) as usize;
-      let last_index = (((ip + length) & 0x7f) / 64) as usize;
-      for index in first_index..=last_index {
-        let mut entry = dirty_flags[index];
-        if entry != 0 {
-          // TODO: invalidate the entry corresponding with every marked bit
-        }
+    let addresses = self.hram_coverage.overlapping(dirty_flags);
+    if addresses.is_empty() {
+      return;
+    }
+    for address in addresses {
+      while let Some((location, _)) = self.code_blocks.high_ram.invalidate_containing(address) {
+        self.unlink_block(location);
       }
     }
+    self.hram_coverage.rebuild(&[&self.code_blocks.high_ram]);
+  }
+}
+
+/// If a block ends by unconditionally transferring control to a fixed
+/// address, return that address. `next_ip` is the address following the op.
+fn get_link_target(op: &Op, next_ip: usize) -> Option<u16> {
+  match op {
+    Op::Jump(JumpCondition::Always, address) => Some(*address),
+    Op::Call(JumpCondition::Always, address) => Some(*address),
+    Op::JumpRelative(JumpCondition::Always, offset) => Some((next_ip as u16).wrapping_add(*offset as u16)),
+    Op::ResetVector(vector) => Some(*vector),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::CodeCache;
+  use crate::mem::MemoryAreas;
+
+  #[test]
+  fn invalidate_written_ram() {
+    let mut cache = CodeCache::new();
+    cache.insert_code_block(0xc000, 0x100, 0x10, 6);
+    cache.insert_code_block(0xcffe, 0x110, 0x10, 4);
+    cache.insert_code_block(0xd010, 0x120, 0x10, 2);
+    cache.insert_code_block(0xff90, 0x130, 0x10, 3);
+    let mut dirty = [0u64; 128];
+    // a write outside of any block
+    dirty[0] = 1 << 8;
+    cache.invalidate_dirty_wram(&dirty);
+    assert_eq!(cache.get_stats().blocks, 4);
+    // a write into the part of a block that runs past 0xd000
+    dirty[64] = 1 << 1;
+    cache.invalidate_dirty_wram(&dirty);
+    assert!(cache.get_block(0xc000).is_some());
+    assert!(cache.get_block(0xcffe).is_none());
+    assert!(cache.get_block(0xd010).is_some());
+    dirty = [0; 128];
+    dirty[0] = 1 << 5;
+    dirty[64] = 1 << 0x11;
+    cache.invalidate_dirty_wram(&dirty);
+    assert!(cache.get_block(0xc000).is_none());
+    assert!(cache.get_block(0xd010).is_none());
+
+    cache.invalidate_dirty_hram(&[1 << 0x13, 0]);
+    assert!(cache.get_block(0xff90).is_some());
+    cache.invalidate_dirty_hram(&[1 << 0x12, 0]);
+    assert_eq!(cache.get_stats().blocks, 0);
+  }
+
+  #[test]
+  fn blocks_do_not_embed_memory_address() {
+    // LD A, (HL) / LD (DE), A / PUSH BC / POP DE / LDH (0x80), A / JP 0x0000
+    let rom = vec![0x7e, 0x12, 0xc5, 0xd1, 0xe0, 0x80, 0xc3, 0x00, 0x00].into_boxed_slice();
+    let first = MemoryAreas::with_rom(rom.clone());
+    let second = MemoryAreas::with_rom(rom);
+    let compile = |mem: &MemoryAreas| {
+      let mut cache = CodeCache::new();
+      cache.translate_code_block(&mem.rom, 0, mem.as_ptr()).unwrap();
+      let block = cache.get_block(0).unwrap();
+      cache.memory_area()[block.offset..(block.offset + block.length)].to_vec()
+    };
+    assert_eq!(compile(&first), compile(&second));
   }
 }
@@ -0,0 +1,82 @@
+use super::blocks::MemoryLocation;
+use std::collections::BTreeMap;
+
+/// How often a compiled block has run, and how long it took
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BlockCounts {
+  pub runs: u64,
+  /// Machine cycles spent running the block
+  pub cycles: u64,
+  /// Bytes of GB code in the block, as of the last time it ran
+  pub length: usize,
+}
+
+/// Per-block counts for compiled code, kept by GB address rather than by
+/// host code, so that they add up across flushes and recompilation
+#[derive(Default)]
+pub struct BlockProfile {
+  blocks: BTreeMap<u32, BlockCounts>,
+}
+
+impl BlockProfile {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn record(&mut self, location: MemoryLocation, length: usize, cycles: usize) {
+    let counts = self.blocks.entry(location.as_u32()).or_default();
+    counts.runs += 1;
+    counts.cycles += cycles as u64;
+    counts.length = length;
+  }
+
+  /// Every block that has run, with the most cycles first
+  pub fn hottest(&self) -> Vec<(MemoryLocation, BlockCounts)> {
+    let mut blocks: Vec<(MemoryLocation, BlockCounts)> = self.blocks
+      .iter()
+      .map(|(location, counts)| (MemoryLocation::from_u32(*location), *counts))
+      .collect();
+    // ties keep address order, since the map is already sorted by it
+    blocks.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(b.1.runs.cmp(&a.1.runs)));
+    blocks
+  }
+
+  pub fn total_cycles(&self) -> u64 {
+    self.blocks.values().map(|counts| counts.cycles).sum()
+  }
+
+  pub fn total_runs(&self) -> u64 {
+    self.blocks.values().map(|counts| counts.runs).sum()
+  }
+
+  pub fn clear(&mut self) {
+    self.blocks.clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{BlockCounts, BlockProfile};
+  use crate::cache::blocks::MemoryLocation;
+
+  #[test]
+  fn hottest_first() {
+    let mut profile = BlockProfile::new();
+    profile.record(MemoryLocation::new(1, 0x4000), 6, 10);
+    profile.record(MemoryLocation::new(0, 0x0150), 3, 4);
+    profile.record(MemoryLocation::new(0, 0x0150), 3, 4);
+    profile.record(MemoryLocation::new(2, 0x4000), 2, 8);
+    profile.record(MemoryLocation::new(0, 0x0200), 2, 8);
+
+    let hottest: Vec<(u16, u16, u64)> = profile
+      .hottest()
+      .iter()
+      .map(|(location, counts)| (location.bank, location.address, counts.cycles))
+      .collect();
+    assert_eq!(hottest, vec![(1, 0x4000, 10), (0, 0x0150, 8), (0, 0x0200, 8), (2, 0x4000, 8)]);
+    assert_eq!(profile.hottest()[1].1, BlockCounts { runs: 2, cycles: 8, length: 3 });
+    assert_eq!((profile.total_runs(), profile.total_cycles()), (5, 34));
+    profile.clear();
+    assert!(profile.hottest().is_empty());
+  }
+}
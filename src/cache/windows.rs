@@ -1,5 +1,7 @@
 use std::ffi::c_void;
 
+use crate::error::JitError;
+
 use crate::bindings::{
   Windows::Win32::System::Memory::VirtualAlloc,
   Windows::Win32::System::Memory::VirtualFree,
@@ -16,7 +18,7 @@ pub struct ExecutableMemory {
 }
 
 impl ExecutableMemory {
-  pub fn new() -> Self {
+  pub fn new() -> Result<Self, JitError> {
     let size: usize = super::INITIAL_MEMORY_SIZE;
     let memory_area = unsafe {
       let pointer: *mut c_void = VirtualAlloc(
@@ -27,13 +29,13 @@ impl ExecutableMemory {
       );
       
       if pointer == std::ptr::null_mut() {
-        panic!("Unable to VirtualAlloc executable memory");
+        return Err(JitError::NoExecutableMemory);
       }
       Vec::from_raw_parts(pointer as *mut u8, size, size).into_boxed_slice()
     };
-    Self {
+    Ok(Self {
       memory: Some(memory_area),
-    }
+    })
   }
 
   pub fn extend(&self) {
@@ -48,16 +50,16 @@ impl ExecutableMemory {
     self.memory.as_mut().unwrap()
   }
 
-  pub fn make_writable(&self) {
+  pub fn make_writable(&self) -> Result<(), JitError> {
     let size = self.memory.as_ref().unwrap().len();
     let address = self.memory.as_ref().unwrap().as_ptr() as *mut ();
-    apply_protection(address, size, PAGE_READWRITE);
+    apply_protection(address, size, PAGE_READWRITE)
   }
 
-  pub fn make_executable(&self) {
+  pub fn make_executable(&self) -> Result<(), JitError> {
     let size = self.memory.as_ref().unwrap().len();
     let address = self.memory.as_ref().unwrap().as_ptr() as *mut ();
-    apply_protection(address, size, PAGE_EXECUTE_READ);
+    apply_protection(address, size, PAGE_EXECUTE_READ)
   }
 }
 
@@ -76,14 +78,18 @@ impl Drop for ExecutableMemory {
   }
 }
 
-fn apply_protection(address: *mut (), size: usize, protection: PAGE_TYPE) {
+fn apply_protection(address: *mut (), size: usize, protection: PAGE_TYPE) -> Result<(), JitError> {
   let mut old_protect: PAGE_TYPE = PAGE_READWRITE;
-  unsafe {
+  let succeeded = unsafe {
     VirtualProtect(
       address as *mut c_void,
       size,
       protection,
       &mut old_protect as *mut PAGE_TYPE,
-    );
+    )
+  };
+  if !succeeded.as_bool() {
+    return Err(JitError::NoExecutableMemory);
   }
+  Ok(())
 }
@@ -0,0 +1,154 @@
+//! Compiles blocks on a thread of their own, so that the emulator never
+//! stalls on a large block. The emulation thread decodes each block, since
+//! that has to read GB memory, and hands the ops to the worker. The worker
+//! builds, optimizes, and encodes them into a buffer of its own, which the
+//! emulation thread copies into executable memory at the write cursor the
+//! next time it checks for finished blocks. Until then, the block is
+//! interpreted.
+//!
+//! Compiled code is independent of where it ends up, apart from the link
+//! jump in its epilogue, which is only patched once the block is in place.
+
+use crate::decoder::ops::Op;
+use crate::emitter::{Emitter, MAX_EPILOGUE_LENGTH, MAX_OP_LENGTH};
+use crate::ir::Block;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+/// Banks mapped when a block was decoded, which it is cached under
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Banks {
+  pub rom: usize,
+  pub low_rom: usize,
+  pub cart_ram: usize,
+  pub wram: usize,
+}
+
+/// A decoded block, ready to be compiled on any thread
+pub struct CompileJob {
+  pub ip: usize,
+  pub ops: Vec<(Op, usize)>,
+  /// Bytes of GB code in the block
+  pub bytes_translated: usize,
+  /// Where the block jumps when it ends, if it can be linked there
+  pub link_target: Option<u16>,
+  pub lazy_flags: bool,
+  pub banks: Banks,
+  /// The cache generation when the block was decoded. A result from an
+  /// earlier generation may have been compiled from code that has since
+  /// changed, and is thrown away.
+  pub generation: usize,
+  #[cfg(feature = "dump_disassembly")]
+  pub source: Vec<u8>,
+  #[cfg(feature = "dump_disassembly")]
+  pub source_bank: Option<usize>,
+}
+
+/// Host code for a block, including its epilogue, before it has been placed
+/// in executable memory
+pub struct CompiledCode {
+  pub job: CompileJob,
+  pub code: Vec<u8>,
+  /// Where the epilogue starts, after the code for each op
+  pub epilogue_offset: usize,
+  /// Bytes of GB code covered by each op, and where the code for each op
+  /// starts
+  #[cfg(feature = "dump_disassembly")]
+  pub op_lengths: Vec<usize>,
+  #[cfg(feature = "dump_disassembly")]
+  pub op_offsets: Vec<usize>,
+}
+
+/// Encode a block into a buffer of its own, sized for the longest code its
+/// ops could need. Straight-line code can run for most of a bank, so blocks
+/// have no fixed size.
+pub fn compile(mut job: CompileJob) -> CompiledCode {
+  let emitter = Emitter::new();
+  let ops = core::mem::take(&mut job.ops);
+  let mut code = vec![0; ops.len() * MAX_OP_LENGTH + MAX_EPILOGUE_LENGTH];
+  let mut block = Block::from_ops(ops, job.ip);
+  #[cfg(feature = "optimizer")]
+  crate::ir::optimize::optimize(&mut block);
+  if job.lazy_flags {
+    block.compute_live_flags();
+  }
+  #[cfg(feature = "dump_disassembly")]
+  let op_lengths: Vec<usize> = block.ops.iter().map(|op| op.length).collect();
+  #[cfg(not(feature = "dump_disassembly"))]
+  let epilogue_offset = emitter.encode_block(block, &mut code);
+  #[cfg(feature = "dump_disassembly")]
+  let mut op_offsets = Vec::new();
+  #[cfg(feature = "dump_disassembly")]
+  let epilogue_offset = emitter.encode_block_with_offsets(block, &mut code, &mut op_offsets);
+  let length = epilogue_offset + if job.link_target.is_some() {
+    emitter.encode_linkable_epilogue(&mut code[epilogue_offset..])
+  } else {
+    emitter.encode_epilogue(&mut code[epilogue_offset..])
+  };
+  code.truncate(length);
+  CompiledCode {
+    job,
+    code,
+    epilogue_offset,
+    #[cfg(feature = "dump_disassembly")]
+    op_lengths,
+    #[cfg(feature = "dump_disassembly")]
+    op_offsets,
+  }
+}
+
+/// A thread that compiles each job it is sent, in order
+pub struct CompileWorker {
+  jobs: Option<Sender<CompileJob>>,
+  results: Receiver<CompiledCode>,
+  thread: Option<JoinHandle<()>>,
+}
+
+impl CompileWorker {
+  pub fn spawn() -> Self {
+    let (jobs, job_receiver) = channel::<CompileJob>();
+    let (result_sender, results) = channel();
+    let thread = thread::Builder::new()
+      .name(String::from("jit compiler"))
+      .spawn(move || {
+        // ends once the cache drops its end of the channel
+        for job in job_receiver {
+          if result_sender.send(compile(job)).is_err() {
+            break;
+          }
+        }
+      })
+      .expect("Unable to start the compiler thread");
+    Self {
+      jobs: Some(jobs),
+      results,
+      thread: Some(thread),
+    }
+  }
+
+  pub fn submit(&self, job: CompileJob) {
+    if let Some(jobs) = self.jobs.as_ref() {
+      // the thread only stops once this end is dropped
+      let _ = jobs.send(job);
+    }
+  }
+
+  /// A block the worker has finished, if there is one
+  pub fn try_finished(&self) -> Option<CompiledCode> {
+    self.results.try_recv().ok()
+  }
+
+  /// Wait for the worker to finish a block
+  pub fn wait_finished(&self) -> Option<CompiledCode> {
+    self.results.recv().ok()
+  }
+}
+
+impl Drop for CompileWorker {
+  fn drop(&mut self) {
+    self.jobs = None;
+    if let Some(thread) = self.thread.take() {
+      let _ = thread.join();
+    }
+  }
+}
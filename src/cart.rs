@@ -1,4 +1,7 @@
-use std::string::String;
+use alloc::boxed::Box;
+use alloc::string::String;
+use crate::error::RomError;
+use crate::mem::OPEN_BUS;
 
 #[repr(C, packed)]
 pub struct Header {
@@ -22,17 +25,17 @@ pub struct Header {
 impl Header {
   fn as_buffer(&self) -> &[u8] {
     unsafe {
-      std::slice::from_raw_parts(
+      core::slice::from_raw_parts(
         self as *const Self as *const u8,
-        std::mem::size_of::<Self>(),
+        core::mem::size_of::<Self>(),
       )
     }
   }
 
   pub fn get_title(&self) -> String {
     unsafe {
-      let title = std::str::from_utf8_unchecked(&self.title);
-      String::from(title.trim_end_matches(std::char::from_u32_unchecked(0)))
+      let title = core::str::from_utf8_unchecked(&self.title);
+      String::from(title.trim_end_matches(core::char::from_u32_unchecked(0)))
     }
   }
 
@@ -60,13 +63,156 @@ impl Header {
       0x02 => "MBC1 (RAM)",
       0x03 => "MBC1 (RAM, Battery)",
       0x05 => "MBC2",
+      0x06 => "MBC2 (Battery)",
+      0x08 => "No MBC (RAM)",
+      0x09 => "No MBC (RAM, Battery)",
+      0x0b => "MMM01",
+      0x0c => "MMM01 (RAM)",
+      0x0d => "MMM01 (RAM, Battery)",
+      0x0f => "MBC3 (Timer, Battery)",
+      0x10 => "MBC3 (Timer, RAM, Battery)",
       0x11 => "MBC3",
+      0x12 => "MBC3 (RAM)",
+      0x13 => "MBC3 (RAM, Battery)",
       0x19 => "MBC5",
+      0x1a => "MBC5 (RAM)",
+      0x1b => "MBC5 (RAM, Battery)",
+      0x1c => "MBC5 (Rumble)",
+      0x1d => "MBC5 (Rumble, RAM)",
+      0x1e => "MBC5 (Rumble, RAM, Battery)",
+      0x20 => "MBC6",
+      0x22 => "MBC7 (Sensor, Rumble, RAM, Battery)",
+      0xfc => "Pocket Camera",
+      0xfd => "Bandai TAMA5",
+      0xfe => "HuC3",
+      0xff => "HuC1 (RAM, Battery)",
       _ => "Unknown",
     };
     String::from(inner)
   }
 
+  /// The raw cartridge type code, at 0x147
+  pub fn get_cart_type_code(&self) -> u8 {
+    self.cart_type
+  }
+
+  /// The raw ROM size code, at 0x148
+  pub fn get_rom_size_code(&self) -> u8 {
+    self.rom_size
+  }
+
+  /// The raw RAM size code, at 0x149
+  pub fn get_ram_size_code(&self) -> u8 {
+    self.ram_size
+  }
+
+  /// Whether the ROM size code is one that real cartridges use. Unknown codes
+  /// are treated as a 32KiB ROM.
+  pub fn is_rom_size_known(&self) -> bool {
+    matches!(self.rom_size, 0x00..=0x08 | 0x52..=0x54)
+  }
+
+  pub fn is_ram_size_known(&self) -> bool {
+    self.ram_size <= 0x05
+  }
+
+  pub fn get_cgb_support(&self) -> CgbSupport {
+    match self.cgb_support {
+      0xc0 => CgbSupport::Required,
+      0x80 => CgbSupport::Enhanced,
+      _ => CgbSupport::None,
+    }
+  }
+
+  /// Games that use Super Game Boy features set the SGB flag to 0x03, along
+  /// with the old licensee code 0x33. The SGB BIOS ignores the flag without
+  /// the licensee code.
+  pub fn supports_sgb(&self) -> bool {
+    self.sgb_support == 0x03 && self.licensee_deprecated == 0x33
+  }
+
+  /// The publisher, as a two-character code. Newer games store it at
+  /// 0x144-0x145 and set the old one-byte code to 0x33; older games only
+  /// have the one-byte code, which is shown in hex.
+  pub fn get_licensee_code(&self) -> String {
+    if self.licensee_deprecated == 0x33 {
+      self.licensee.iter().map(|c| *c as char).collect()
+    } else {
+      alloc::format!("{:02X}", self.licensee_deprecated)
+    }
+  }
+
+  /// Whether the game was sold in Japan, rather than overseas
+  pub fn is_japanese(&self) -> bool {
+    self.dest_code == 0x00
+  }
+
+  pub fn get_rom_version(&self) -> u8 {
+    self.rom_version
+  }
+
+  pub fn get_header_checksum(&self) -> u8 {
+    self.header_checksum
+  }
+
+  /// The 16-bit sum of every byte of the ROM, as stored at 0x14e-0x14f. Real
+  /// hardware never checks it, so plenty of homebrew leaves it empty.
+  pub fn get_global_checksum(&self) -> u16 {
+    u16::from_be_bytes(self.global_checksum)
+  }
+
+  pub fn valid_global_checksum(&self, rom: &[u8]) -> bool {
+    compute_global_checksum(rom) == self.get_global_checksum()
+  }
+
+  /// Describe every field of the header, and whether it checks out against
+  /// the ROM it came from, one field per line
+  pub fn report(&self, rom: &[u8]) -> String {
+    use core::fmt::Write;
+
+    let mut report = String::new();
+    let _ = writeln!(report, "Title:            {}", self.get_title());
+    let _ = writeln!(report, "Cartridge type:   {} ({:02X})", self.get_cart_type_string(), self.cart_type);
+    let rom_size = if self.is_rom_size_known() {
+      alloc::format!("{} KiB, {} banks", self.get_rom_size_bytes() / 1024, self.get_rom_bank_count())
+    } else {
+      String::from("Unknown")
+    };
+    let _ = writeln!(report, "ROM size:         {} ({:02X})", rom_size, self.rom_size);
+    let ram_size = if self.is_ram_size_known() {
+      alloc::format!("{} KiB", self.get_ram_size_bytes() / 1024)
+    } else {
+      String::from("Unknown")
+    };
+    let _ = writeln!(report, "RAM size:         {} ({:02X})", ram_size, self.ram_size);
+    let cgb = match self.get_cgb_support() {
+      CgbSupport::None => "No",
+      CgbSupport::Enhanced => "Enhanced",
+      CgbSupport::Required => "Required",
+    };
+    let _ = writeln!(report, "Game Boy Color:   {}", cgb);
+    let _ = writeln!(report, "Super Game Boy:   {}", if self.supports_sgb() { "Yes" } else { "No" });
+    let _ = writeln!(report, "Licensee:         {}", self.get_licensee_code());
+    let _ = writeln!(report, "Destination:      {}", if self.is_japanese() { "Japan" } else { "Overseas" });
+    let _ = writeln!(report, "Version:          {}", self.rom_version);
+    let header_status = if self.valid_checksum() { "OK" } else { "INVALID" };
+    let _ = writeln!(report, "Header checksum:  {:02X} {}", self.header_checksum, header_status);
+    let global_status = if self.valid_global_checksum(rom) {
+      String::from("OK")
+    } else {
+      alloc::format!("INVALID, ROM sums to {:04X}", compute_global_checksum(rom))
+    };
+    let _ = writeln!(report, "Global checksum:  {:04X} {}", self.get_global_checksum(), global_status);
+    if matches!(self.cart_type, 0x01..=0x03) && is_mbc1_multicart(rom) {
+      let _ = writeln!(report, "Multicart:        Yes (MBC1M)");
+    }
+    let declared_size = self.get_rom_size_bytes();
+    if rom.len() != declared_size {
+      let _ = writeln!(report, "File size:        {} bytes, but the header declares {}", rom.len(), declared_size);
+    }
+    report
+  }
+
   pub fn get_rom_size_bytes(&self) -> usize {
     self.get_rom_bank_count() * 16 * 1024
   }
@@ -111,20 +257,25 @@ impl Header {
     check == self.header_checksum
   }
 
-  pub fn create_cart_state(&self) -> Box<dyn CartState> {
-    match self.cart_type {
+  /// Create the memory bank controller for this cartridge. Some controllers
+  /// are wired differently depending on the board, which can only be told
+  /// from the ROM itself.
+  pub fn create_cart_state(&self, rom: &[u8]) -> Result<Box<dyn CartState>, RomError> {
+    let cart_state: Box<dyn CartState> = match self.cart_type {
       0x00 => Box::new(NullCartState::new()),
+      0x01 | 0x02 | 0x03 if is_mbc1_multicart(rom) => Box::new(MBC1CartState::multicart()),
       0x01 | 0x02 | 0x03 => Box::new(MBC1CartState::new()),
-      
+
       0x11 | 0x12 | 0x13 => Box::new(MBC3CartState::new()),
 
-      _ => panic!("Unsupported cart type"),
-    }
+      cart_type => return Err(RomError::UnsupportedCartType(cart_type)),
+    };
+    Ok(cart_state)
   }
 }
 
-impl std::fmt::Debug for Header {
-  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Debug for Header {
+  fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
     f.debug_struct("ROM Header")
       .field("title", &self.get_title())
       .field("type", &self.get_cart_type_string())
@@ -133,6 +284,35 @@ impl std::fmt::Debug for Header {
   }
 }
 
+/// How a game uses the Game Boy Color, from the flag at 0x143
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CgbSupport {
+  /// A game for the original Game Boy
+  None,
+  /// A game that runs on either, with color on a Game Boy Color
+  Enhanced,
+  /// A game that only runs on a Game Boy Color
+  Required,
+}
+
+/// Sum every byte of a ROM except the global checksum itself, to compare
+/// with the checksum in its header
+pub fn compute_global_checksum(rom: &[u8]) -> u16 {
+  rom
+    .iter()
+    .enumerate()
+    .filter(|(i, _)| *i != 0x14e && *i != 0x14f)
+    .fold(0u16, |sum, (_, byte)| sum.wrapping_add(*byte as u16))
+}
+
+/// MBC1 multicarts (MBC1M) pack several 256KiB games into a 1MiB ROM, and
+/// wire the upper bank bits one bit lower so that each game sees a normal
+/// 16-bank cartridge. The board can't be told from the header, but each game
+/// starts with its own copy of the Nintendo logo.
+pub fn is_mbc1_multicart(rom: &[u8]) -> bool {
+  rom.len() == 0x100000 && rom[0x104..0x134] == rom[0x40104..0x40134]
+}
+
 #[derive(Copy, Clone)]
 pub enum MBCType {
   None,
@@ -143,10 +323,43 @@ pub enum MBCType {
   Unknown,
 }
 
-pub trait CartState {
+/// Snapshot of the registers inside a cartridge's memory bank controller.
+/// Values are the raw register contents, so `rom_bank` may be 0 even though
+/// the controller maps bank 1 in that case.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MbcState {
+  pub rom_bank: usize,
+  pub ram_bank: usize,
+  pub ram_enabled: bool,
+  /// Controller-specific banking mode. For MBC1 this is the RAM/ROM select
+  /// bit written to 0x6000-0x7fff; controllers without modes report 0.
+  pub mode: u8,
+}
+
+impl core::fmt::Display for MbcState {
+  fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+    let ram = if self.ram_enabled { "enabled" } else { "disabled" };
+    write!(f, "ROM bank {:02X}, RAM bank {:02X} ({}), mode {}", self.rom_bank, self.ram_bank, ram, self.mode)
+  }
+}
+
+pub trait CartState: Send {
   fn write_rom(&mut self, addr: u16, value: u8) {
   }
 
+  fn get_mbc_state(&self) -> MbcState {
+    MbcState {
+      rom_bank: self.get_rom_bank(),
+      ram_bank: self.get_ram_bank(),
+      ram_enabled: false,
+      mode: 0,
+    }
+  }
+
+  /// Restore registers previously captured with get_mbc_state
+  fn set_mbc_state(&mut self, _state: &MbcState) {
+  }
+
   fn get_rom_bank(&self) -> usize {
     1
   }
@@ -155,6 +368,21 @@ pub trait CartState {
     0
   }
 
+  /// The ROM bank mapped to 0x0000-0x3fff, which is bank 0 on everything but
+  /// MBC1 in its RAM banking mode
+  fn get_low_rom_bank(&self) -> usize {
+    0
+  }
+
+  /// Whether a ROM with this many banks could see anything but bank 0 at
+  /// 0x0000-0x3fff
+  fn can_switch_low_rom_bank(&self, _bank_count: usize) -> bool {
+    false
+  }
+
+  /// Returns a value to read from cart RAM instead of the RAM itself, such
+  /// as open bus while RAM is disabled. Writes are ignored while an override
+  /// is active.
   fn get_ram_override(&self, addr: u16) -> Option<u8> {
     None
   }
@@ -176,19 +404,46 @@ pub struct MBC1CartState {
   ram_bank: usize,
   ram_enabled: bool,
   select_ram: bool,
+  multicart: bool,
 }
 
 impl MBC1CartState {
-  fn new() -> Self {
+  pub fn new() -> Self {
     MBC1CartState {
       rom_bank: 1,
       ram_bank: 0,
       ram_enabled: false,
       select_ram: false,
+      multicart: false,
+    }
+  }
+
+  /// An MBC1 on a multicart board, where only four bits of the ROM bank
+  /// register are connected
+  pub fn multicart() -> Self {
+    MBC1CartState {
+      multicart: true,
+      ..Self::new()
+    }
+  }
+
+  /// How far the 2-bit register at 0x4000-0x5fff is shifted when it selects
+  /// a ROM bank
+  fn high_bank_shift(&self) -> usize {
+    if self.multicart {
+      4
+    } else {
+      5
     }
   }
 }
 
+impl Default for MBC1CartState {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 impl CartState for MBC1CartState {
   fn write_rom(&mut self, addr: u16, value: u8) {
     if addr < 0x2000 {
@@ -203,33 +458,55 @@ impl CartState for MBC1CartState {
     }
   }
 
+  fn get_mbc_state(&self) -> MbcState {
+    MbcState {
+      rom_bank: self.rom_bank,
+      ram_bank: self.ram_bank,
+      ram_enabled: self.ram_enabled,
+      mode: self.select_ram as u8,
+    }
+  }
+
+  fn set_mbc_state(&mut self, state: &MbcState) {
+    self.rom_bank = state.rom_bank & 0x1f;
+    self.ram_bank = state.ram_bank & 0x03;
+    self.ram_enabled = state.ram_enabled;
+    self.select_ram = state.mode & 1 == 1;
+  }
+
   fn get_rom_bank(&self) -> usize {
+    // the zero check looks at all five bits, even on a multicart, so games
+    // there can still map bank 0x10 at 0x4000
+    let bank = if self.rom_bank == 0 { 1 } else { self.rom_bank };
+    let low_mask = (1 << self.high_bank_shift()) - 1;
+    (bank & low_mask) | (self.ram_bank << self.high_bank_shift())
+  }
+
+  fn get_ram_bank(&self) -> usize {
     if self.select_ram {
-      self.rom_bank
+      self.ram_bank
     } else {
-      let bank_high = self.ram_bank << 5;
-      let mut bank = self.rom_bank;
-      if bank == 0 {
-        bank = 1;
-      }
-      bank |= bank_high;
-      bank
+      0
     }
   }
 
-  fn get_ram_bank(&self) -> usize {
+  fn get_low_rom_bank(&self) -> usize {
     if self.select_ram {
-      self.ram_bank
+      self.ram_bank << self.high_bank_shift()
     } else {
       0
     }
   }
 
+  fn can_switch_low_rom_bank(&self, bank_count: usize) -> bool {
+    bank_count > 1 << self.high_bank_shift()
+  }
+
   fn get_ram_override(&self, addr: u16) -> Option<u8> {
     if self.ram_enabled {
       None
     } else {
-      Some(0xff)
+      Some(OPEN_BUS)
     }
   }
 }
@@ -267,6 +544,21 @@ impl CartState for MBC3CartState {
     }
   }
 
+  fn get_mbc_state(&self) -> MbcState {
+    MbcState {
+      rom_bank: self.rom_bank,
+      ram_bank: self.ram_bank,
+      ram_enabled: self.ram_enabled,
+      mode: 0,
+    }
+  }
+
+  fn set_mbc_state(&mut self, state: &MbcState) {
+    self.rom_bank = state.rom_bank & 0x7f;
+    self.ram_bank = state.ram_bank & 0x03;
+    self.ram_enabled = state.ram_enabled;
+  }
+
   fn get_rom_bank(&self) -> usize {
     let mut bank = self.rom_bank;
     if bank == 0 {
@@ -278,4 +570,113 @@ impl CartState for MBC3CartState {
   fn get_ram_bank(&self) -> usize {
     self.ram_bank
   }
+
+  fn get_ram_override(&self, _addr: u16) -> Option<u8> {
+    if self.ram_enabled {
+      None
+    } else {
+      Some(OPEN_BUS)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{compute_global_checksum, CartState, CgbSupport, Header, MBC1CartState, MbcState, NullCartState};
+  use alloc::vec;
+
+  #[test]
+  fn header_fields() {
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x134..0x13b].copy_from_slice(b"EXAMPLE");
+    rom[0x143] = 0x80;
+    rom[0x144..0x146].copy_from_slice(b"01");
+    rom[0x146] = 0x03;
+    rom[0x147] = 0x1b;
+    rom[0x149] = 0x03;
+    rom[0x14a] = 0x01;
+    rom[0x14b] = 0x33;
+    rom[0x14c] = 0x02;
+    let global = compute_global_checksum(&rom);
+    rom[0x14e..0x150].copy_from_slice(&global.to_be_bytes());
+    let header = unsafe { core::ptr::read_unaligned(rom[0x100..].as_ptr() as *const Header) };
+
+    assert_eq!(header.get_title(), "EXAMPLE");
+    assert_eq!(header.get_cart_type_string(), "MBC5 (RAM, Battery)");
+    assert_eq!(header.get_rom_bank_count(), 2);
+    assert_eq!(header.get_ram_size_bytes(), 32 * 1024);
+    assert_eq!(header.get_cgb_support(), CgbSupport::Enhanced);
+    assert!(header.supports_sgb());
+    assert_eq!(header.get_licensee_code(), "01");
+    assert!(!header.is_japanese());
+    assert_eq!(header.get_rom_version(), 2);
+    // the global checksum doesn't include itself
+    assert!(header.valid_global_checksum(&rom));
+    rom[0x14f] ^= 0xff;
+    assert!(header.valid_global_checksum(&rom));
+    rom[0x7fff] = 1;
+    assert!(!header.valid_global_checksum(&rom));
+
+    let report = header.report(&rom);
+    assert!(report.contains("Cartridge type:   MBC5 (RAM, Battery) (1B)"));
+    assert!(report.contains("Header checksum:  00 INVALID"));
+  }
+
+  #[test]
+  fn sgb_flag_needs_new_licensee() {
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x146] = 0x03;
+    rom[0x14b] = 0x01;
+    let header = unsafe { core::ptr::read_unaligned(rom[0x100..].as_ptr() as *const Header) };
+    assert!(!header.supports_sgb());
+    rom[0x14b] = 0x33;
+    let header = unsafe { core::ptr::read_unaligned(rom[0x100..].as_ptr() as *const Header) };
+    assert!(header.supports_sgb());
+  }
+
+  #[test]
+  fn mbc1_state_round_trip() {
+    let mut cart = MBC1CartState::new();
+    cart.write_rom(0x0000, 0x0a);
+    cart.write_rom(0x2000, 0x05);
+    cart.write_rom(0x4000, 0x02);
+    cart.write_rom(0x6000, 0x01);
+    let state = cart.get_mbc_state();
+    assert_eq!(state, MbcState { rom_bank: 5, ram_bank: 2, ram_enabled: true, mode: 1 });
+
+    let mut restored = MBC1CartState::new();
+    restored.set_mbc_state(&state);
+    assert_eq!(restored.get_mbc_state(), state);
+    assert_eq!(restored.get_ram_bank(), 2);
+    assert_eq!(state.to_string(), "ROM bank 05, RAM bank 02 (enabled), mode 1");
+  }
+
+  #[test]
+  fn mbc1_bank_wiring() {
+    let mut cart = MBC1CartState::new();
+    cart.write_rom(0x2000, 0x12);
+    cart.write_rom(0x4000, 0x01);
+    assert_eq!(cart.get_rom_bank(), 0x32);
+    assert_eq!(cart.get_low_rom_bank(), 0);
+    cart.write_rom(0x6000, 0x01);
+    assert_eq!(cart.get_low_rom_bank(), 0x20);
+
+    // a multicart drops the top bit of the 5-bit register
+    let mut cart = MBC1CartState::multicart();
+    cart.write_rom(0x2000, 0x12);
+    cart.write_rom(0x4000, 0x01);
+    assert_eq!(cart.get_rom_bank(), 0x12);
+    cart.write_rom(0x2000, 0x10);
+    assert_eq!(cart.get_rom_bank(), 0x10);
+    cart.write_rom(0x6000, 0x01);
+    assert_eq!(cart.get_low_rom_bank(), 0x10);
+    assert!(cart.can_switch_low_rom_bank(64));
+    assert!(!MBC1CartState::new().can_switch_low_rom_bank(32));
+  }
+
+  #[test]
+  fn null_cart_state() {
+    let cart = NullCartState::new();
+    assert_eq!(cart.get_mbc_state(), MbcState { rom_bank: 1, ram_bank: 0, ram_enabled: false, mode: 0 });
+  }
 }
@@ -0,0 +1,228 @@
+//! Cheat codes. Game Genie codes patch the ROM, and are applied as soon as
+//! they are added; GameShark codes poke a byte of RAM, and are applied at the
+//! end of every frame.
+//!
+//! A Game Genie code is written `ABC-DEF` or `ABC-DEF-GHI`. `AB` is the new
+//! value, and `FCDE` is the address with the top nibble inverted. In the long
+//! form, `GI` holds the value that must already be in ROM for the patch to
+//! apply, rotated and XORed with 0xBA; `H` is ignored. Addresses in the
+//! switchable region are patched in every bank, since the real device
+//! intercepts reads no matter which bank is mapped.
+//!
+//! A GameShark code is written `ttvvllhh`: a type `tt`, the value `vv`, and
+//! the address `hhll`. Types 0x80-0x8f write to that bank of cartridge RAM;
+//! anything else writes to whatever is mapped at the address.
+
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Cheat {
+  GameGenie { address: u16, value: u8, compare: Option<u8> },
+  GameShark { kind: u8, address: u16, value: u8 },
+}
+
+impl Cheat {
+  pub fn parse(code: &str) -> Result<Self, String> {
+    let invalid = || format!("Invalid cheat code \"{}\"", code);
+    let digits: Vec<u8> = code
+      .trim()
+      .chars()
+      .filter(|c| *c != '-')
+      .map(|c| c.to_digit(16).map(|digit| digit as u8))
+      .collect::<Option<Vec<u8>>>()
+      .ok_or_else(invalid)?;
+    let byte = |index: usize| (digits[index] << 4) | digits[index + 1];
+    match digits.len() {
+      6 | 9 => {
+        let address = ((digits[5] as u16 ^ 0xf) << 12)
+          | ((digits[2] as u16) << 8)
+          | ((digits[3] as u16) << 4)
+          | digits[4] as u16;
+        if address >= 0x8000 {
+          return Err(format!("Game Genie code \"{}\" doesn't patch ROM", code));
+        }
+        let compare = match digits.len() {
+          9 => Some(((digits[6] << 4) | digits[8]).rotate_right(2) ^ 0xba),
+          _ => None,
+        };
+        Ok(Cheat::GameGenie { address, value: byte(0), compare })
+      },
+      8 if !code.contains('-') => Ok(Cheat::GameShark {
+        kind: byte(0),
+        address: ((byte(6) as u16) << 8) | byte(4) as u16,
+        value: byte(2),
+      }),
+      _ => Err(invalid()),
+    }
+  }
+
+  /// Offsets into the ROM that a Game Genie code changes
+  fn rom_offsets(&self, rom: &[u8]) -> Vec<usize> {
+    let (address, compare) = match self {
+      Cheat::GameGenie { address, compare, .. } => (*address as usize, *compare),
+      Cheat::GameShark { .. } => return Vec::new(),
+    };
+    let offsets: Vec<usize> = if address < 0x4000 {
+      vec![address]
+    } else {
+      (0x4000..rom.len()).step_by(0x4000).map(|bank| bank + address - 0x4000).collect()
+    };
+    offsets
+      .into_iter()
+      .filter(|offset| *offset < rom.len())
+      .filter(|offset| compare.is_none_or(|value| rom[*offset] == value))
+      .collect()
+  }
+}
+
+impl fmt::Display for Cheat {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Cheat::GameGenie { address, value, compare: None } => {
+        write!(f, "Game Genie: {:02X} at {:04X}", value, address)
+      },
+      Cheat::GameGenie { address, value, compare: Some(compare) } => {
+        write!(f, "Game Genie: {:02X} at {:04X} if {:02X}", value, address, compare)
+      },
+      Cheat::GameShark { kind, address, value } => {
+        write!(f, "GameShark: {:02X} at {:04X} (type {:02X})", value, address, kind)
+      },
+    }
+  }
+}
+
+struct Entry {
+  code: String,
+  cheat: Cheat,
+  /// ROM bytes changed by this code, and the values they held before
+  patched: Vec<(usize, u8)>,
+}
+
+#[derive(Default)]
+pub struct CheatList {
+  entries: Vec<Entry>,
+}
+
+impl CheatList {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  /// Codes in the order they were added, as they were written
+  pub fn codes(&self) -> impl Iterator<Item = &str> {
+    self.entries.iter().map(|entry| entry.code.as_str())
+  }
+
+  /// Add a code, patching the ROM if it is a Game Genie code. Returns the
+  /// offsets into the ROM that changed.
+  pub fn add(&mut self, code: &str, rom: &mut [u8]) -> Result<Vec<usize>, String> {
+    let code = code.trim().to_ascii_uppercase();
+    if self.entries.iter().any(|entry| entry.code == code) {
+      return Ok(Vec::new());
+    }
+    let cheat = Cheat::parse(&code)?;
+    let mut entry = Entry { code, cheat, patched: Vec::new() };
+    let offsets = Self::patch(&mut entry, rom);
+    self.entries.push(entry);
+    Ok(offsets)
+  }
+
+  /// Remove a code, restoring any ROM it patched. Returns the offsets into
+  /// the ROM that changed, or None if the code wasn't added.
+  pub fn remove(&mut self, code: &str, rom: &mut [u8]) -> Option<Vec<usize>> {
+    let code = code.trim().to_ascii_uppercase();
+    let index = self.entries.iter().position(|entry| entry.code == code)?;
+    // Codes can patch the same byte, so every patch is undone and the ones
+    // that remain are applied again in order
+    let mut offsets = Vec::new();
+    for entry in self.entries.iter_mut().rev() {
+      for (offset, original) in entry.patched.drain(..).rev() {
+        rom[offset] = original;
+        offsets.push(offset);
+      }
+    }
+    self.entries.remove(index);
+    for entry in self.entries.iter_mut() {
+      Self::patch(entry, rom);
+    }
+    offsets.sort_unstable();
+    offsets.dedup();
+    Some(offsets)
+  }
+
+  fn patch(entry: &mut Entry, rom: &mut [u8]) -> Vec<usize> {
+    let value = match entry.cheat {
+      Cheat::GameGenie { value, .. } => value,
+      Cheat::GameShark { .. } => return Vec::new(),
+    };
+    let offsets = entry.cheat.rom_offsets(rom);
+    for offset in offsets.iter() {
+      entry.patched.push((*offset, rom[*offset]));
+      rom[*offset] = value;
+    }
+    offsets
+  }
+
+  /// GameShark codes, as (type, address, value)
+  pub fn ram_writes(&self) -> impl Iterator<Item = (u8, u16, u8)> + '_ {
+    self.entries.iter().filter_map(|entry| match entry.cheat {
+      Cheat::GameShark { kind, address, value } => Some((kind, address, value)),
+      Cheat::GameGenie { .. } => None,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Cheat, CheatList};
+
+  #[test]
+  fn parse_codes() {
+    assert_eq!(
+      Cheat::parse("3E2-A4F").unwrap(),
+      Cheat::GameGenie { address: 0x02a4, value: 0x3e, compare: None },
+    );
+    // 0x7f XORed with 0xba and rotated left by two is 0x17
+    assert_eq!(
+      Cheat::parse("00A-17B-1E7").unwrap(),
+      Cheat::GameGenie { address: 0x4a17, value: 0x00, compare: Some(0x7f) },
+    );
+    assert_eq!(
+      Cheat::parse("01ff38cd").unwrap(),
+      Cheat::GameShark { kind: 0x01, address: 0xcd38, value: 0xff },
+    );
+    // addresses outside of ROM, and codes of the wrong length
+    assert!(Cheat::parse("00A-170").is_err());
+    assert!(Cheat::parse("00A-17").is_err());
+    assert!(Cheat::parse("01FF-38CD").is_err());
+    assert!(Cheat::parse("nonsense").is_err());
+  }
+
+  #[test]
+  fn patch_and_restore_rom() {
+    let mut rom = vec![0; 0x10000];
+    rom[0x4a17] = 0x7f;
+    rom[0xca17] = 0x7f;
+    let mut cheats = CheatList::new();
+    // only banks holding the compare value are patched
+    assert_eq!(cheats.add("00a-17b-1e7", &mut rom).unwrap(), vec![0x4a17, 0xca17]);
+    assert_eq!(rom[0x4a17], 0x00);
+    assert_eq!(rom[0x8a17], 0x00);
+    // a second code patching the same byte
+    cheats.add("02A-17B", &mut rom).unwrap();
+    assert_eq!(rom[0x4a17], 0x02);
+    assert_eq!(rom[0x8a17], 0x02);
+    assert_eq!(cheats.remove("00A-17B-1E7", &mut rom).unwrap(), vec![0x4a17, 0x8a17, 0xca17]);
+    assert_eq!(rom[0x4a17], 0x02);
+    assert_eq!(rom[0x8a17], 0x02);
+    cheats.remove("02A-17B", &mut rom).unwrap();
+    assert_eq!(rom[0x4a17], 0x7f);
+    assert_eq!(rom[0x8a17], 0x00);
+    assert!(cheats.remove("02A-17B", &mut rom).is_none());
+    assert!(cheats.is_empty());
+  }
+}
@@ -0,0 +1,479 @@
+//! Settings for the emulator binary, read from the command line and from an
+//! optional config.toml in the config directory. Every flag can also be set
+//! in the config file, using its name without the leading dashes, like
+//! `palette = "pocket"`, `scale = 3`, or `verify-jit = true`. Flags given on
+//! the command line take precedence over the file.
+//!
+//! Settings changed while running, like the window's color adjustments, are
+//! written back to the same file.
+
+use crate::system::config_path;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_FILE: &str = "config.toml";
+/// Where color adjustments used to be saved, before they moved into
+/// config.toml
+const LEGACY_DISPLAY_FILE: &str = "display.cfg";
+/// The keys that were kept in the legacy display file
+const DISPLAY_KEYS: [&str; 3] = ["brightness", "contrast", "gamma"];
+
+/// A command line flag, and how it's described in the usage text. Names
+/// listed together are aliases, and each is kept as written.
+pub struct Flag {
+  pub names: &'static [&'static str],
+  /// What the argument following the flag should be, for flags that take one
+  pub value: Option<&'static str>,
+  pub help: &'static str,
+}
+
+const fn switch(names: &'static [&'static str], help: &'static str) -> Flag {
+  Flag { names, value: None, help }
+}
+
+const fn valued(names: &'static [&'static str], value: &'static str, help: &'static str) -> Flag {
+  Flag { names, value: Some(value), help }
+}
+
+/// Every flag the binary accepts, in the order they're listed by `--help`
+pub const FLAGS: [Flag; 54] = [
+  valued(&["--boot-rom"], "<file>", "Run a boot ROM before the game"),
+  valued(&["--patch"], "<file>", "Apply an IPS or BPS patch to the ROM"),
+  valued(&["--rom-size"], "<policy>", "How to load ROMs of the wrong size: header, file, or strict"),
+  valued(&["--scale"], "<n>", "Initial window scale"),
+  valued(&["--palette"], "<name|colors>", "grayscale, green, pocket, pastel, or four hex colors"),
+  valued(&["--filter"], "<name>", "none, grid, scanlines, or green"),
+  valued(&["--brightness"], "<n>", "Added to every shade, from -1.0 to 1.0"),
+  valued(&["--contrast"], "<n>", "Scales shades away from mid-gray"),
+  valued(&["--gamma"], "<n>", "Values above 1.0 lighten the mid-tones"),
+  valued(&["--cheat"], "<codes>", "Comma-separated Game Genie or GameShark codes"),
+  valued(&["--turbo"], "<buttons>", "Comma-separated buttons that repeat while held"),
+  valued(&["--turbo-duty"], "<on/period>", "Frames each turbo press is held, out of its period"),
+  valued(&["--fast-forward"], "<speed>", "Speed while holding Tab: 2x, 4x, or unlimited"),
+  switch(&["--no-frame-skip"], "Draw every frame while fast-forwarding"),
+  switch(&["--no-rewind"], "Don't keep a rewind buffer"),
+  valued(&["--save-dir"], "<dir>", "Where screenshots are saved"),
+  valued(&["--record"], "<file>", "Record video, as .rgba, .avi, or through ffmpeg"),
+  valued(&["--record-link"], "<file>", "Record link cable transfers to a file"),
+  valued(&["--replay-link"], "<file>", "Replay link cable transfers from a recording"),
+  valued(&["--netplay-host"], "<port>", "Host a netplay session"),
+  valued(&["--netplay-join"], "<address>", "Join a netplay session at host:port"),
+  valued(&["--netplay-delay"], "<frames>", "Netplay input delay, in frames"),
+  switch(&["--headless"], "Run without a window"),
+  valued(&["--frames"], "<n>", "Stop after n frames, without a window"),
+  valued(&["--cycles"], "<n>", "Stop after n machine cycles, without a window"),
+  valued(&["--dump-frame"], "<file>", "Save the last frame when stopping, as PNG or PGM"),
+  valued(&["--compare-frame"], "<file>", "Compare the last frame to a reference image"),
+  valued(&["--hash-frames"], "<file>", "Log a hash of every frame, without a window"),
+  valued(&["--check-hashes"], "<file>", "Compare every frame to a hash log, without a window"),
+  switch(&["--test-rom"], "Run a test ROM, and exit with its result"),
+  valued(&["--test-timeout"], "<seconds>", "How long a test ROM can run before it fails"),
+  switch(&["--jit"], "Run on the JIT compiler"),
+  switch(&["--interp", "--interpreter"], "Run on the interpreter"),
+  switch(&["--jit-ram"], "Also compile code running from work or cart RAM"),
+  valued(&["--jit-threshold"], "<n>", "Interpret each block n times before compiling it"),
+  switch(&["--jit-background"], "Compile blocks on another thread, interpreting until then"),
+  switch(&["--verify-jit"], "Check every compiled block against the interpreter"),
+  switch(&["--profile-blocks"], "List the compiled blocks that ran longest on exit"),
+  valued(&["--trace"], "<file>", "Log every instruction that runs"),
+  valued(&["--trace-range"], "<start-end>", "Only log instructions in this address range"),
+  switch(&["--trace-blocks"], "Only log the start of each block, keeping the JIT on"),
+  valued(&["--trace-format"], "<name>", "default, or doctor for Gameboy Doctor logs"),
+  switch(&["--debug"], "Start in the terminal debugger"),
+  valued(&["--break"], "<locations>", "Comma-separated breakpoints, like 0x150 or 03:4f20"),
+  valued(&["--watch"], "<ranges>", "Stop on writes to these addresses or ranges"),
+  switch(&["--stack-check"], "Warn when the stack pointer runs somewhere it shouldn't"),
+  switch(&["--info"], "Print the ROM's header and exit, without running it"),
+  switch(&["--hud"], "Show emulation speed and JIT statistics (Ctrl+P)"),
+  switch(&["--self-test"], "Print a performance baseline before starting"),
+  switch(&["--stats"], "Print the play time of every game and exit"),
+  switch(&["--no-stats"], "Don't count this session's play time"),
+  valued(&["--config"], "<file>", "Read settings from this file instead"),
+  switch(&["--no-config"], "Don't read a config file"),
+  switch(&["-h", "--help"], "Show this message"),
+];
+
+fn find_flag(name: &str) -> Option<&'static Flag> {
+  FLAGS.iter().find(|flag| flag.names.contains(&name))
+}
+
+/// The list of flags shown by `--help`, one per line
+pub fn usage() -> String {
+  let mut text = String::new();
+  for flag in FLAGS.iter() {
+    let mut left = flag.names.join(", ");
+    if let Some(value) = flag.value {
+      left.push(' ');
+      left.push_str(value);
+    }
+    text.push_str(&format!("  {:<24} {}\n", left, flag.help));
+  }
+  text
+}
+
+/// Flags that pick between the JIT and the interpreter. Setting any of them
+/// on the command line overrides all of them in the config file.
+const ENGINE_FLAGS: [&str; 3] = ["--interp", "--interpreter", "--jit"];
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+  /// The ROM file, which is the only argument that isn't a flag
+  pub rom: Option<String>,
+  switches: Vec<String>,
+  values: Vec<(String, String)>,
+}
+
+impl Options {
+  /// Parse command line arguments, not including the program name
+  pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+    let mut options = Self::default();
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+      if arg == "-h" {
+        options.switches.push(String::from("--help"));
+      } else if let Some(flag) = find_flag(&arg) {
+        if flag.value.is_some() {
+          let value = iter.next().ok_or_else(|| format!("{} needs a value", arg))?;
+          options.values.push((arg, value));
+        } else {
+          options.switches.push(arg);
+        }
+      } else if arg.starts_with('-') {
+        return Err(format!("Unknown option \"{}\"", arg));
+      } else if options.rom.is_none() {
+        options.rom = Some(arg);
+      } else {
+        return Err(format!("Unexpected argument \"{}\"", arg));
+      }
+    }
+    Ok(options)
+  }
+
+  pub fn has_flag(&self, flag: &str) -> bool {
+    self.switches.iter().any(|switch| switch == flag)
+  }
+
+  pub fn get_value(&self, flag: &str) -> Option<String> {
+    self.values
+      .iter()
+      .find(|(name, _)| name == flag)
+      .map(|(_, value)| value.clone())
+  }
+
+  /// The config file to read: the one passed with `--config`, or config.toml
+  /// in the config directory. `--no-config` skips it entirely.
+  pub fn config_file(&self) -> Option<PathBuf> {
+    if self.has_flag("--no-config") {
+      return None;
+    }
+    match self.get_value("--config") {
+      Some(name) => Some(PathBuf::from(name)),
+      None => config_path(CONFIG_FILE),
+    }
+  }
+
+  /// Fill in anything that wasn't set on the command line from the contents
+  /// of a config file. Lines that can't be used are skipped, and described
+  /// in the returned warnings.
+  pub fn merge_config(&mut self, text: &str) -> Vec<String> {
+    let (settings, mut warnings) = parse_toml(text);
+    let engine_set = ENGINE_FLAGS.iter().any(|flag| self.has_flag(flag));
+    for (line, key, value) in settings {
+      let flag = format!("--{}", key);
+      let takes_value = find_flag(&flag).map(|known| known.value.is_some());
+      match value {
+        Value::Bool(enabled) if takes_value == Some(false) && flag != "--help" => {
+          if engine_set && ENGINE_FLAGS.contains(&flag.as_str()) {
+            continue;
+          }
+          if enabled && !self.has_flag(&flag) {
+            self.switches.push(flag);
+          }
+        },
+        Value::Text(text) if takes_value == Some(true) && flag != "--config" => {
+          if self.get_value(&flag).is_none() {
+            self.values.push((flag, text));
+          }
+        },
+        _ => warnings.push(format!("Line {}: \"{}\" isn't a setting that takes this value", line, key)),
+      }
+    }
+    warnings
+  }
+}
+
+/// Set each key to its value in the text of a config file, replacing the
+/// line that sets it, or adding one at the end. Every other line is kept as
+/// it was. Values must already be written as TOML.
+pub fn set_config_values(text: &str, values: &[(&str, String)]) -> String {
+  let mut written = vec![false; values.len()];
+  let mut lines = Vec::new();
+  for line in text.lines() {
+    let key = strip_comment(line).split_once('=').map(|(key, _)| key.trim());
+    match values.iter().position(|(name, _)| Some(*name) == key) {
+      Some(index) if !written[index] => {
+        written[index] = true;
+        lines.push(format!("{} = {}", values[index].0, values[index].1));
+      },
+      // a key set twice is only kept once
+      Some(_) => (),
+      None => lines.push(String::from(line)),
+    }
+  }
+  for (index, (key, value)) in values.iter().enumerate() {
+    if !written[index] {
+      lines.push(format!("{} = {}", key, value));
+    }
+  }
+  let mut text = lines.join("\n");
+  text.push('\n');
+  text
+}
+
+/// Write settings into a config file, creating it if it doesn't exist yet
+pub fn save_config_values(path: &Path, values: &[(&str, String)]) -> Result<(), String> {
+  let text = std::fs::read_to_string(path).unwrap_or_default();
+  if let Some(dir) = path.parent() {
+    let _ = std::fs::create_dir_all(dir);
+  }
+  std::fs::write(path, set_config_values(&text, values))
+    .map_err(|_| format!("Unable to save settings to \"{}\"", path.display()))
+}
+
+/// Move color adjustments saved by older versions from display.cfg into
+/// config.toml, and remove the old file. Settings already in config.toml are
+/// kept. Returns a message describing the move, if there was anything to move.
+pub fn migrate_display_config() -> Option<String> {
+  let legacy_path = config_path(LEGACY_DISPLAY_FILE)?;
+  let legacy = std::fs::read_to_string(&legacy_path).ok()?;
+  let path = config_path(CONFIG_FILE)?;
+  let text = std::fs::read_to_string(&path).unwrap_or_default();
+  let values = legacy_display_values(&text, &legacy);
+  if let Err(msg) = save_config_values(&path, &values) {
+    return Some(msg);
+  }
+  let _ = std::fs::remove_file(&legacy_path);
+  Some(format!("Moved display settings from \"{}\" to \"{}\"", legacy_path.display(), path.display()))
+}
+
+/// The settings from a legacy display file that config.toml doesn't set yet
+fn legacy_display_values(config: &str, legacy: &str) -> Vec<(&'static str, String)> {
+  let (existing, _) = parse_toml(config);
+  let (settings, _) = parse_toml(legacy);
+  let mut values = Vec::new();
+  for key in DISPLAY_KEYS.iter() {
+    if existing.iter().any(|(_, name, _)| name == key) {
+      continue;
+    }
+    let value = settings.iter().rev().find_map(|(_, name, value)| match value {
+      Value::Text(text) if name == key => Some(text.clone()),
+      _ => None,
+    });
+    if let Some(value) = value {
+      values.push((*key, value));
+    }
+  }
+  values
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+  Bool(bool),
+  /// Strings and numbers, which are all passed to flags as text
+  Text(String),
+}
+
+/// Parse the subset of TOML used by config files: `key = value` pairs with
+/// string, integer, float, or boolean values, and comments. Returns each
+/// setting with its line number, and warnings for lines that couldn't be
+/// parsed.
+fn parse_toml(text: &str) -> (Vec<(usize, String, Value)>, Vec<String>) {
+  let mut settings = Vec::new();
+  let mut warnings = Vec::new();
+  for (index, raw_line) in text.lines().enumerate() {
+    let line_number = index + 1;
+    let line = strip_comment(raw_line).trim();
+    if line.is_empty() {
+      continue;
+    }
+    if line.starts_with('[') {
+      warnings.push(format!("Line {}: tables aren't supported", line_number));
+      continue;
+    }
+    let (key, value) = match line.split_once('=') {
+      Some((key, value)) => (key.trim(), value.trim()),
+      None => {
+        warnings.push(format!("Line {}: expected key = value", line_number));
+        continue;
+      },
+    };
+    let valid_key = !key.is_empty()
+      && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid_key {
+      warnings.push(format!("Line {}: invalid key \"{}\"", line_number, key));
+      continue;
+    }
+    match parse_value(value) {
+      Some(value) => settings.push((line_number, String::from(key), value)),
+      None => warnings.push(format!("Line {}: invalid value for \"{}\"", line_number, key)),
+    }
+  }
+  (settings, warnings)
+}
+
+/// Remove a trailing comment, ignoring any # inside of a string
+fn strip_comment(line: &str) -> &str {
+  let mut quote = None;
+  let mut escaped = false;
+  for (index, c) in line.char_indices() {
+    match quote {
+      Some('"') if escaped => escaped = false,
+      Some('"') if c == '\\' => escaped = true,
+      Some(q) if c == q => quote = None,
+      Some(_) => (),
+      None if c == '"' || c == '\'' => quote = Some(c),
+      None if c == '#' => return &line[..index],
+      None => (),
+    }
+  }
+  line
+}
+
+fn parse_value(value: &str) -> Option<Value> {
+  match value {
+    "true" => return Some(Value::Bool(true)),
+    "false" => return Some(Value::Bool(false)),
+    _ => (),
+  }
+  if let Some(inner) = value.strip_prefix('\'') {
+    // literal strings have no escapes
+    let inner = inner.strip_suffix('\'')?;
+    if inner.contains('\'') {
+      return None;
+    }
+    return Some(Value::Text(String::from(inner)));
+  }
+  if let Some(inner) = value.strip_prefix('"') {
+    let inner = inner.strip_suffix('"')?;
+    let mut text = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+      match c {
+        '\\' => text.push(match chars.next()? {
+          'n' => '\n',
+          't' => '\t',
+          '\\' => '\\',
+          '"' => '"',
+          _ => return None,
+        }),
+        '"' => return None,
+        _ => text.push(c),
+      }
+    }
+    return Some(Value::Text(text));
+  }
+  let is_number = value.parse::<f64>().is_ok()
+    || value.replace('_', "").parse::<i64>().is_ok();
+  if is_number {
+    return Some(Value::Text(value.replace('_', "")));
+  }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{legacy_display_values, parse_toml, set_config_values, usage, Options, Value, FLAGS};
+
+  fn args(list: &[&str]) -> Vec<String> {
+    list.iter().map(|arg| String::from(*arg)).collect()
+  }
+
+  #[test]
+  fn command_line() {
+    let options = Options::parse_args(args(&["--scale", "3", "game.gb", "--jit", "-h"])).unwrap();
+    assert_eq!(options.rom.as_deref(), Some("game.gb"));
+    assert_eq!(options.get_value("--scale").as_deref(), Some("3"));
+    assert!(options.has_flag("--jit"));
+    assert!(options.has_flag("--help"));
+    assert!(!options.has_flag("--headless"));
+
+    assert!(Options::parse_args(args(&["--frames"])).is_err());
+    assert!(Options::parse_args(args(&["--unknown"])).is_err());
+    assert!(Options::parse_args(args(&["one.gb", "two.gb"])).is_err());
+  }
+
+  #[test]
+  fn flag_table() {
+    let names: Vec<&str> = FLAGS.iter().flat_map(|flag| flag.names.iter().copied()).collect();
+    for (index, name) in names.iter().enumerate() {
+      assert!(!names[index + 1..].contains(name), "{} is listed twice", name);
+    }
+    let options = Options::parse_args(args(&["--interpreter", "--trace-format", "doctor"])).unwrap();
+    assert!(options.has_flag("--interpreter"));
+    assert_eq!(options.get_value("--trace-format").as_deref(), Some("doctor"));
+    assert!(usage().contains("  --interp, --interpreter  Run on the interpreter\n"));
+    assert!(usage().contains("  --trace-format <name>    default, or doctor"));
+  }
+
+  #[test]
+  fn toml_values() {
+    let text = "
+      # display
+      palette = \"pocket\" # trailing comment
+      scale = 3
+      save-dir = 'C:\\saves'
+      headless = true
+      turbo = \"a,#b\\\"\"
+      [window]
+      broken
+      frames = nope
+    ";
+    let (settings, warnings) = parse_toml(text);
+    let values: Vec<(&str, &Value)> = settings.iter().map(|(_, key, value)| (key.as_str(), value)).collect();
+    assert_eq!(values, vec![
+      ("palette", &Value::Text(String::from("pocket"))),
+      ("scale", &Value::Text(String::from("3"))),
+      ("save-dir", &Value::Text(String::from("C:\\saves"))),
+      ("headless", &Value::Bool(true)),
+      ("turbo", &Value::Text(String::from("a,#b\""))),
+    ]);
+    assert_eq!(warnings.len(), 3);
+  }
+
+  #[test]
+  fn command_line_overrides_config() {
+    let mut options = Options::parse_args(args(&["--palette", "green", "--interp"])).unwrap();
+    let warnings = options.merge_config("
+      palette = \"pocket\"
+      scale = 2
+      jit = true
+      no-rewind = false
+      verify-jit = true
+      rom = \"game.gb\"
+      headless = \"yes\"
+    ");
+    assert_eq!(options.get_value("--palette").as_deref(), Some("green"));
+    assert_eq!(options.get_value("--scale").as_deref(), Some("2"));
+    assert!(!options.has_flag("--jit"));
+    assert!(!options.has_flag("--no-rewind"));
+    assert!(options.has_flag("--verify-jit"));
+    assert_eq!(warnings.len(), 2);
+  }
+
+  #[test]
+  fn writing_values() {
+    let text = "# display\nscale = 3\ngamma=1.4 # lighter\n";
+    let values = [("gamma", String::from("1.5")), ("brightness", String::from("0.10"))];
+    assert_eq!(set_config_values(text, &values), "# display\nscale = 3\ngamma = 1.5\nbrightness = 0.10\n");
+    assert_eq!(set_config_values("", &values[..1]), "gamma = 1.5\n");
+    let mut options = Options::default();
+    assert!(options.merge_config(&set_config_values(text, &values)).is_empty());
+    assert_eq!(options.get_value("--brightness").as_deref(), Some("0.10"));
+  }
+
+  #[test]
+  fn legacy_display_file() {
+    let legacy = "brightness=0.1\ncontrast=1.2\ngamma=0.8\n";
+    let values = legacy_display_values("contrast = 1.5\n", legacy);
+    assert_eq!(values, vec![("brightness", String::from("0.1")), ("gamma", String::from("0.8"))]);
+  }
+}
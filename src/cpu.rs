@@ -1,3 +1,4 @@
+#[derive(Clone, Copy, PartialEq, Eq)]
 #[repr(C, packed)]
 pub struct Registers {
   pub af: u32,
@@ -167,3 +168,5 @@ pub const STATUS_HALT: u8 = 2;
 pub const STATUS_INTERRUPT_DISABLE: u8 = 3;
 pub const STATUS_INTERRUPT_ENABLE: u8 = 4;
 pub const STATUS_INTERRUPT_ENABLE_IMMEDIATE: u8 = 5;
+/// An invalid opcode hung the CPU
+pub const STATUS_LOCKED: u8 = 6;
@@ -0,0 +1,141 @@
+//! Breakpoints, optionally qualified by the ROM bank they apply to
+
+use crate::mem::MemoryAreas;
+
+/// A breakpoint at a 16-bit address. Code in the switchable ROM region
+/// (0x4000-0x7fff) can belong to any bank, so a breakpoint may also name the
+/// bank it applies to. Without a bank, it fires in every bank.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Breakpoint {
+  pub bank: Option<usize>,
+  pub address: u16,
+}
+
+impl Breakpoint {
+  pub fn new(address: u16) -> Self {
+    Self {
+      bank: None,
+      address,
+    }
+  }
+
+  pub fn in_bank(bank: usize, address: u16) -> Self {
+    Self {
+      bank: Some(bank),
+      address,
+    }
+  }
+
+  pub fn matches(&self, address: u16, bank: Option<usize>) -> bool {
+    if self.address != address {
+      return false;
+    }
+    match self.bank {
+      Some(expected) => bank == Some(expected),
+      None => true,
+    }
+  }
+}
+
+impl std::fmt::Display for Breakpoint {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.bank {
+      Some(bank) => write!(f, "{:02X}:{:04X}", bank, self.address),
+      None => write!(f, "{:04X}", self.address),
+    }
+  }
+}
+
+/// Determine which ROM bank is visible at an address, using the current MBC
+/// state. Returns None for addresses outside of cartridge ROM.
+pub fn get_bank_for_address(address: u16, mem: &MemoryAreas) -> Option<usize> {
+  match address {
+    0x0000..=0x3fff => Some(mem.get_low_rom_bank()),
+    0x4000..=0x7fff => Some(mem.get_rom_bank()),
+    _ => None,
+  }
+}
+
+pub struct BreakpointSet {
+  breakpoints: Vec<Breakpoint>,
+}
+
+impl BreakpointSet {
+  pub fn new() -> Self {
+    Self {
+      breakpoints: Vec::new(),
+    }
+  }
+
+  pub fn add(&mut self, breakpoint: Breakpoint) {
+    if !self.breakpoints.contains(&breakpoint) {
+      self.breakpoints.push(breakpoint);
+    }
+  }
+
+  /// Returns true if the breakpoint was present
+  pub fn remove(&mut self, breakpoint: Breakpoint) -> bool {
+    let previous_length = self.breakpoints.len();
+    self.breakpoints.retain(|b| *b != breakpoint);
+    self.breakpoints.len() != previous_length
+  }
+
+  pub fn list(&self) -> &[Breakpoint] {
+    &self.breakpoints
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.breakpoints.is_empty()
+  }
+
+  /// Check whether execution at an address should break, given the banks
+  /// currently mapped into memory
+  pub fn should_break(&self, address: u16, mem: &MemoryAreas) -> bool {
+    if self.breakpoints.is_empty() {
+      return false;
+    }
+    let bank = get_bank_for_address(address, mem);
+    self.breakpoints.iter().any(|b| b.matches(address, bank))
+  }
+}
+
+impl Default for BreakpointSet {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Breakpoint, BreakpointSet};
+  use crate::mem::MemoryAreas;
+
+  #[test]
+  fn bank_qualified_match() {
+    let any_bank = Breakpoint::new(0x4f20);
+    let bank_3 = Breakpoint::in_bank(3, 0x4f20);
+    assert!(any_bank.matches(0x4f20, Some(2)));
+    assert!(bank_3.matches(0x4f20, Some(3)));
+    assert!(!bank_3.matches(0x4f20, Some(2)));
+    assert!(!bank_3.matches(0x4f21, Some(3)));
+  }
+
+  #[test]
+  fn display() {
+    assert_eq!(Breakpoint::in_bank(3, 0x4f20).to_string(), "03:4F20");
+    assert_eq!(Breakpoint::new(0x0150).to_string(), "0150");
+  }
+
+  #[test]
+  fn breakpoint_set() {
+    let mem = MemoryAreas::with_rom(vec![0x00].into_boxed_slice());
+    let mut set = BreakpointSet::new();
+    set.add(Breakpoint::in_bank(1, 0x4000));
+    set.add(Breakpoint::in_bank(2, 0x5000));
+    assert!(set.should_break(0x4000, &mem));
+    assert!(!set.should_break(0x5000, &mem));
+    assert!(set.remove(Breakpoint::in_bank(1, 0x4000)));
+    assert!(!set.remove(Breakpoint::in_bank(1, 0x4000)));
+    assert_eq!(set.list().len(), 1);
+  }
+}
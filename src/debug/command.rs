@@ -1,23 +1,59 @@
 //! Command prompt for the interactive debugger
 
+use super::breakpoint::Breakpoint;
+use super::freeze::FreezeRegion;
+use super::io_registers::find_register;
+use super::search::SearchFilter;
+use super::watchpoint::{WatchKind, Watchpoint};
 use std::str::FromStr;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Command {
   /// Clear a breakpoint at a specific address
-  BreakClear(u16),
+  BreakClear(Breakpoint),
   /// Return all active breakpoints
   BreakList,
   /// Set a breakpoint at a specific address
-  BreakSet(u16),
+  BreakSet(Breakpoint),
   // Run the emulator until a breakpoint is hit
   Continue,
+  /// Ignore all writes to a region of OAM or VRAM
+  FreezeAdd(FreezeRegion),
+  /// Return all frozen regions
+  FreezeList,
+  /// Return the most recent writes dropped by frozen regions
+  FreezeLog,
+  /// Allow writes to a frozen region again
+  FreezeRemove(FreezeRegion),
+  /// Return every IO register, with its fields decoded
+  IoList,
+  /// Write a value to the IO register at an address
+  IoPoke(u16, u8),
+  /// Exit the debugger
+  Quit,
   ReadMemory(u16),
   ReadMemoryRange(u16, usize),
   ReadRegisters,
+  /// Narrow down the RAM search in progress
+  SearchFilter(SearchFilter),
+  /// Return the addresses left in the RAM search
+  SearchList,
+  /// Begin a new RAM search from a snapshot of memory
+  SearchStart,
   Step,
+  /// Step, running any called subroutine until it returns
+  StepOver,
+  /// Clear all watchpoints covering exactly this range
+  WatchClear(u16, u16),
+  /// Return all active watchpoints
+  WatchList,
+  /// Stop when the CPU accesses a range of memory
+  WatchSet(Watchpoint),
 }
 
+/// Number of bytes shown by `x` when no length is given
+const DEFAULT_DUMP_LENGTH: usize = 64;
+
 fn normalize_command(token: Option<&str>) -> Option<String> {
   let inner = token?;
   let token_str = String::from_str(inner).ok()?;
@@ -29,13 +65,27 @@ pub fn parse_command(line: &str) -> Option<Command> {
   let first_token = normalize_command(tokens.next())?;
   match first_token.as_str() {
     "break" => {
-      let addr_str = tokens.next()?;
-      let addr = parse_address(addr_str)?;
-      Some(Command::BreakSet(addr))
+      let breakpoint = parse_breakpoint(tokens.next()?)?;
+      Some(Command::BreakSet(breakpoint))
+    },
+    "clear" => {
+      let breakpoint = parse_breakpoint(tokens.next()?)?;
+      Some(Command::BreakClear(breakpoint))
     },
     "c" | "continue" => {
       Some(Command::Continue)
     },
+    "freeze" => {
+      if normalize_command(tokens.clone().next()).as_deref() == Some("log") {
+        return Some(Command::FreezeLog);
+      }
+      let region = parse_freeze_region(&mut tokens)?;
+      Some(Command::FreezeAdd(region))
+    },
+    "unfreeze" => {
+      let region = parse_freeze_region(&mut tokens)?;
+      Some(Command::FreezeRemove(region))
+    },
 
     "info" => {
       let next = normalize_command(tokens.next())?;
@@ -43,6 +93,21 @@ pub fn parse_command(line: &str) -> Option<Command> {
         "reg" | "registers" => {
           Some(Command::ReadRegisters)
         },
+        "break" | "breakpoints" => {
+          Some(Command::BreakList)
+        },
+        "freeze" => {
+          Some(Command::FreezeList)
+        },
+        "io" => {
+          Some(Command::IoList)
+        },
+        "search" => {
+          Some(Command::SearchList)
+        },
+        "watch" | "watchpoints" => {
+          Some(Command::WatchList)
+        },
         _ => None,
       }
     },
@@ -53,9 +118,56 @@ pub fn parse_command(line: &str) -> Option<Command> {
       Some(Command::ReadMemory(addr))
     },
 
+    "poke" => {
+      let register = find_register(tokens.next()?)?;
+      let value = parse_address(tokens.next()?)?;
+      if value > 0xff {
+        return None;
+      }
+      Some(Command::IoPoke(register.address, value as u8))
+    },
+
+    "search" => {
+      let next = normalize_command(tokens.next())?;
+      match next.as_str() {
+        "start" => Some(Command::SearchStart),
+        _ => parse_search_filter(&next, tokens.next()).map(Command::SearchFilter),
+      }
+    },
+
     "s" | "step" => {
       Some(Command::Step)
     },
+    "n" | "next" => {
+      Some(Command::StepOver)
+    },
+
+    "x" => {
+      let addr = parse_address(tokens.next()?)?;
+      let length = match tokens.next() {
+        Some(token) => token.trim().parse().ok()?,
+        None => DEFAULT_DUMP_LENGTH,
+      };
+      Some(Command::ReadMemoryRange(addr, length))
+    },
+
+    "q" | "quit" => {
+      Some(Command::Quit)
+    },
+
+    "watch" | "rwatch" | "awatch" => {
+      let kind = match first_token.as_str() {
+        "watch" => WatchKind::Write,
+        "rwatch" => WatchKind::Read,
+        _ => WatchKind::ReadWrite,
+      };
+      let watchpoint = parse_watchpoint(tokens.next()?, kind)?;
+      Some(Command::WatchSet(watchpoint))
+    },
+    "unwatch" => {
+      let watchpoint = parse_watchpoint(tokens.next()?, WatchKind::ReadWrite)?;
+      Some(Command::WatchClear(watchpoint.start, watchpoint.end))
+    },
   
     _ => None,
   }
@@ -70,9 +182,88 @@ pub fn parse_address(token: &str) -> Option<u16> {
   addr
 }
 
+/// Parse a breakpoint location. A bank-qualified location is written as
+/// BB:AAAA in hex (eg, 03:4F20); anything else is parsed as a plain address.
+pub fn parse_breakpoint(token: &str) -> Option<Breakpoint> {
+  let trimmed = token.trim();
+  match trimmed.find(':') {
+    Some(split) => {
+      let bank = usize::from_str_radix(&trimmed[..split], 16).ok()?;
+      let addr = u16::from_str_radix(&trimmed[(split + 1)..], 16).ok()?;
+      Some(Breakpoint::in_bank(bank, addr))
+    },
+    None => parse_address(trimmed).map(Breakpoint::new),
+  }
+}
+
+/// Parse a watched location, either a single address or an inclusive range
+/// written as START-END (eg, 0xc000-0xc0ff)
+pub fn parse_watchpoint(token: &str, kind: WatchKind) -> Option<Watchpoint> {
+  let (start, end) = parse_range(token)?;
+  Some(Watchpoint::range(start, end, kind))
+}
+
+/// Parse an inclusive range of addresses written as START-END, or a single
+/// address
+pub fn parse_range(token: &str) -> Option<(u16, u16)> {
+  let trimmed = token.trim();
+  match trimmed.find('-') {
+    Some(split) => {
+      let start = parse_address(&trimmed[..split])?;
+      let end = parse_address(&trimmed[(split + 1)..])?;
+      Some((start, end))
+    },
+    None => parse_address(trimmed).map(|address| (address, address)),
+  }
+}
+
+/// Parse a region to freeze: `oam <entry>`, or `tile <index>` for a tile in
+/// VRAM bank 0, or `tile <bank>:<index>`. Numbers are decimal.
+fn parse_freeze_region<'a, I: Iterator<Item = &'a str>>(tokens: &mut I) -> Option<FreezeRegion> {
+  let kind = normalize_command(tokens.next())?;
+  let location = tokens.next()?.trim();
+  let region = match kind.as_str() {
+    "oam" => FreezeRegion::OamEntry(location.parse().ok()?),
+    "tile" => match location.find(':') {
+      Some(split) => FreezeRegion::Tile(location[..split].parse().ok()?, location[(split + 1)..].parse().ok()?),
+      None => FreezeRegion::Tile(0, location.parse().ok()?),
+    },
+    _ => return None,
+  };
+  if region.is_valid() {
+    Some(region)
+  } else {
+    None
+  }
+}
+
+/// Parse a RAM search filter: `eq <value>`, `ne <value>`, `gt`, `lt`,
+/// `changed`, `same`, or `by <amount>`, where the amount may be negative
+fn parse_search_filter(kind: &str, argument: Option<&str>) -> Option<SearchFilter> {
+  let value = || parse_address(argument?).filter(|value| *value <= 0xff).map(|value| value as u8);
+  match kind {
+    "eq" => value().map(SearchFilter::Equal),
+    "ne" => value().map(SearchFilter::NotEqual),
+    "gt" => Some(SearchFilter::Greater),
+    "lt" => Some(SearchFilter::Less),
+    "changed" => Some(SearchFilter::Changed),
+    "same" => Some(SearchFilter::Unchanged),
+    "by" => {
+      let delta: i16 = argument?.trim().parse().ok()?;
+      if (-255..=255).contains(&delta) {
+        Some(SearchFilter::ChangedBy(delta))
+      } else {
+        None
+      }
+    },
+    _ => None,
+  }
+}
+
 #[cfg(test)]
 mod tests {
-  use super::{Command, parse_address, parse_command};
+  use super::{Breakpoint, Command, FreezeRegion, SearchFilter, parse_address, parse_breakpoint, parse_command};
+  use crate::debug::watchpoint::{WatchKind, Watchpoint};
 
   #[test]
   fn parse_stepping() {
@@ -80,12 +271,18 @@ mod tests {
     assert_eq!(parse_command(" continue  "), Some(Command::Continue));
     assert_eq!(parse_command("step"), Some(Command::Step));
     assert_eq!(parse_command("s  "), Some(Command::Step));
+    assert_eq!(parse_command("n"), Some(Command::StepOver));
+    assert_eq!(parse_command("next"), Some(Command::StepOver));
+    assert_eq!(parse_command("q"), Some(Command::Quit));
   }
 
   #[test]
   fn parse_printing() {
     assert_eq!(parse_command("p 0xff0f"), Some(Command::ReadMemory(0xff0f)));
     assert_eq!(parse_command("print 50"), Some(Command::ReadMemory(50)));
+    assert_eq!(parse_command("x 0xc000"), Some(Command::ReadMemoryRange(0xc000, 64)));
+    assert_eq!(parse_command("x 0xc000 16"), Some(Command::ReadMemoryRange(0xc000, 16)));
+    assert_eq!(parse_command("x 0xc000 lots"), None);
   }
 
   #[test]
@@ -97,6 +294,56 @@ mod tests {
   #[test]
   fn parse_info_command() {
     assert_eq!(parse_command("info registers"), Some(Command::ReadRegisters));
+    assert_eq!(parse_command("info break"), Some(Command::BreakList));
+  }
+
+  #[test]
+  fn parse_breakpoints() {
+    assert_eq!(parse_breakpoint("0x150"), Some(Breakpoint::new(0x150)));
+    assert_eq!(parse_breakpoint("03:4F20"), Some(Breakpoint::in_bank(3, 0x4f20)));
+    assert_eq!(parse_breakpoint("03:zz"), None);
+    assert_eq!(parse_command("break 1a:4000"), Some(Command::BreakSet(Breakpoint::in_bank(0x1a, 0x4000))));
+    assert_eq!(parse_command("clear 0x4000"), Some(Command::BreakClear(Breakpoint::new(0x4000))));
   }
-}
 
+  #[test]
+  fn parse_freeze() {
+    assert_eq!(parse_command("freeze oam 12"), Some(Command::FreezeAdd(FreezeRegion::OamEntry(12))));
+    assert_eq!(parse_command("freeze tile 1:200"), Some(Command::FreezeAdd(FreezeRegion::Tile(1, 200))));
+    assert_eq!(parse_command("unfreeze tile 5"), Some(Command::FreezeRemove(FreezeRegion::Tile(0, 5))));
+    assert_eq!(parse_command("freeze oam 40"), None);
+    assert_eq!(parse_command("freeze tile 2:0"), None);
+    assert_eq!(parse_command("info freeze"), Some(Command::FreezeList));
+    assert_eq!(parse_command("freeze log"), Some(Command::FreezeLog));
+    assert_eq!(parse_command("info io"), Some(Command::IoList));
+    assert_eq!(parse_command("poke lcdc 0x91"), Some(Command::IoPoke(0xff40, 0x91)));
+    assert_eq!(parse_command("poke 0xff47 228"), Some(Command::IoPoke(0xff47, 0xe4)));
+    assert_eq!(parse_command("poke lcdc 0x100"), None);
+    assert_eq!(parse_command("poke 0xc000 1"), None);
+  }
+
+  #[test]
+  fn parse_search() {
+    assert_eq!(parse_command("search start"), Some(Command::SearchStart));
+    assert_eq!(parse_command("search eq 0x63"), Some(Command::SearchFilter(SearchFilter::Equal(0x63))));
+    assert_eq!(parse_command("search ne 3"), Some(Command::SearchFilter(SearchFilter::NotEqual(3))));
+    assert_eq!(parse_command("search same"), Some(Command::SearchFilter(SearchFilter::Unchanged)));
+    assert_eq!(parse_command("search by -1"), Some(Command::SearchFilter(SearchFilter::ChangedBy(-1))));
+    assert_eq!(parse_command("search eq 256"), None);
+    assert_eq!(parse_command("search by"), None);
+    assert_eq!(parse_command("info search"), Some(Command::SearchList));
+  }
+
+  #[test]
+  fn parse_watchpoints() {
+    assert_eq!(parse_command("watch 0xc000"), Some(Command::WatchSet(Watchpoint::new(0xc000, WatchKind::Write))));
+    assert_eq!(
+      parse_command("rwatch 0xff80-0xff8f"),
+      Some(Command::WatchSet(Watchpoint::range(0xff80, 0xff8f, WatchKind::Read))),
+    );
+    assert_eq!(parse_command("awatch 0xc000"), Some(Command::WatchSet(Watchpoint::new(0xc000, WatchKind::ReadWrite))));
+    assert_eq!(parse_command("unwatch 0xc000"), Some(Command::WatchClear(0xc000, 0xc000)));
+    assert_eq!(parse_command("watch 0xc000-"), None);
+    assert_eq!(parse_command("info watch"), Some(Command::WatchList));
+  }
+}
@@ -1,6 +1,10 @@
+use crate::debug::breakpoint::get_bank_for_address;
 use crate::decoder;
+use crate::mem::{memory_peek_byte, MemoryAreas};
 
 pub struct Instruction {
+  /// ROM bank containing the instruction, if known
+  bank: Option<usize>,
   address: u16,
   bytes: [u8; 4],
   length: usize,
@@ -9,7 +13,10 @@ pub struct Instruction {
 
 impl std::fmt::Display for Instruction {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    let mut formatted = format!("{:#06X}  ", self.address);
+    let mut formatted = match self.bank {
+      Some(bank) => format!("{:02X}:{:04X}  ", bank, self.address),
+      None => format!("{:#06X}  ", self.address),
+    };
     for i in 0..self.length {
       formatted.push_str(format!("{:02X} ", self.bytes[i]).as_str());
     }
@@ -21,25 +28,106 @@ impl std::fmt::Display for Instruction {
   }
 }
 
+impl Instruction {
+  /// Size of the encoded instruction, in bytes
+  pub fn length(&self) -> usize {
+    self.length
+  }
+
+  pub fn address(&self) -> u16 {
+    self.address
+  }
+}
+
 pub fn disassemble(initial_addr: u16, instructions: &[u8]) -> Vec<Instruction> {
+  disassemble_bank(None, initial_addr, instructions)
+}
+
+/// Disassemble code from a known ROM bank, so that each instruction is listed
+/// with a bank-qualified address like 03:4F20
+pub fn disassemble_bank(bank: Option<usize>, initial_addr: u16, instructions: &[u8]) -> Vec<Instruction> {
   let mut output = Vec::new();
   let mut cursor = 0;
   let mut address = initial_addr;
   while cursor < instructions.len() {
-    let (op, length, _) = decoder::decode(&instructions[cursor..]);
-    let mut bytes: [u8; 4] = [0; 4];
-    for i in 0..length {
-      bytes[i] = instructions[cursor + i];
-    }
-    output.push(Instruction {
-      address,
-      bytes,
-      length,
-      text: op.to_string(),
-    });
-    address = address.wrapping_add(length as u16);
-    cursor += length;
+    let instruction = disassemble_one(bank, address, &instructions[cursor..]);
+    address = address.wrapping_add(instruction.length as u16);
+    cursor += instruction.length;
+    output.push(instruction);
   }
 
   output
-}
\ No newline at end of file
+}
+
+/// Disassemble only the first instruction in a slice. The slice must be long
+/// enough to contain all of its operands.
+pub fn disassemble_one(bank: Option<usize>, address: u16, instructions: &[u8]) -> Instruction {
+  let (op, length, _) = decoder::decode(instructions);
+  let mut bytes: [u8; 4] = [0; 4];
+  bytes[..length].copy_from_slice(&instructions[..length]);
+  Instruction {
+    bank,
+    address,
+    bytes,
+    length,
+    text: op.to_string(),
+  }
+}
+
+/// Disassembles instructions exactly as the CPU would fetch them right now,
+/// reading through the current boot ROM, ROM bank, and WRAM bank mapping.
+/// Reads have no side effects. The listing never ends, and wraps around from
+/// 0xFFFF to 0x0000.
+pub struct MemoryDisassembly<'a> {
+  memory: &'a MemoryAreas,
+  address: u16,
+}
+
+pub fn disassemble_memory(memory: &MemoryAreas, address: u16) -> MemoryDisassembly<'_> {
+  MemoryDisassembly {
+    memory,
+    address,
+  }
+}
+
+impl<'a> Iterator for MemoryDisassembly<'a> {
+  type Item = Instruction;
+
+  fn next(&mut self) -> Option<Instruction> {
+    let mut bytes = [0; 3];
+    for (offset, byte) in bytes.iter_mut().enumerate() {
+      *byte = memory_peek_byte(self.memory, self.address.wrapping_add(offset as u16));
+    }
+    let bank = get_bank_for_address(self.address, self.memory);
+    let instruction = disassemble_one(bank, self.address, &bytes);
+    self.address = self.address.wrapping_add(instruction.length as u16);
+    Some(instruction)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::disassemble_memory;
+  use crate::mem::{memory_write_byte, MemoryAreas};
+
+  #[test]
+  fn follows_memory_map() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x4000..0x4003].copy_from_slice(&[0xc3, 0x00, 0xc0]); // JP 0xc000
+    let mut memory = MemoryAreas::with_rom(rom.into_boxed_slice());
+    let mem_ptr = &mut memory as *mut MemoryAreas;
+    memory_write_byte(mem_ptr, 0xc000, 0x3e); // LD A, 0x12
+    memory_write_byte(mem_ptr, 0xc001, 0x12);
+
+    let listing: Vec<String> = disassemble_memory(&memory, 0x4000).take(1).map(|i| i.to_string()).collect();
+    assert!(listing[0].starts_with("01:4000  C3 00 C0"));
+    let mut ram = disassemble_memory(&memory, 0xc000);
+    let first = ram.next().unwrap();
+    assert!(first.to_string().starts_with("0xC000  3E 12"));
+    assert_eq!(ram.next().unwrap().address(), 0xc002);
+
+    // the end of the address space wraps around
+    let mut wrapped = disassemble_memory(&memory, 0xffff);
+    assert_eq!(wrapped.nth(1).unwrap().address(), 0x0000);
+  }
+}
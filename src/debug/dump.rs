@@ -0,0 +1,172 @@
+//! Listings of compiled blocks, for finding bugs in the emitters without a
+//! debugger. Each op in a block is listed with the Game Boy instructions it
+//! was compiled from on the left, and the host code emitted for it on the
+//! right. The cache writes one file per block as it compiles them, into the
+//! directory named by `GB_DUMP_DIR`, or `jit-dump` by default. A block that
+//! gets compiled again replaces its earlier listing.
+
+use super::disassembly::disassemble_bank;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+pub const DEFAULT_DUMP_DIR: &str = "jit-dump";
+
+/// Width of the Game Boy column, wide enough for a bank-qualified address,
+/// four bytes, and the longest mnemonic
+const SOURCE_WIDTH: usize = 40;
+
+/// A compiled block, and everything needed to line its host code up with the
+/// code it came from
+pub struct BlockDump<'a> {
+  /// ROM bank the block was compiled from, if it's in ROM
+  pub bank: Option<usize>,
+  pub address: u16,
+  /// Game Boy code decoded into the block
+  pub source: &'a [u8],
+  /// Bytes of Game Boy code covered by each op, in order
+  pub op_lengths: &'a [usize],
+  /// Host code for the whole block, including its epilogue
+  pub host_code: &'a [u8],
+  /// Address the host code runs from
+  pub host_address: u64,
+  /// Where the code for each op starts, relative to the start of the block.
+  /// Anything after the last op's code is the epilogue.
+  pub op_offsets: &'a [usize],
+  pub epilogue_offset: usize,
+}
+
+impl BlockDump<'_> {
+  pub fn listing(&self) -> String {
+    let mut listing = format!(
+      "; block at {}, {} bytes compiled to {} bytes at {:#x}\n",
+      self.location(),
+      self.source.len(),
+      self.host_code.len(),
+      self.host_address,
+    );
+    let instructions = disassemble_bank(self.bank, self.address, self.source);
+    let mut instructions = instructions.iter().peekable();
+    let mut source_offset = 0;
+    for (index, length) in self.op_lengths.iter().enumerate() {
+      // An op can stand for several instructions, when the optimizer fuses
+      // them together
+      let mut left = Vec::new();
+      let source_end = source_offset + length;
+      while let Some(instruction) = instructions.next_if(|_| source_offset < source_end) {
+        source_offset += instruction.length();
+        left.push(instruction.to_string());
+      }
+      let host_start = self.op_offsets[index];
+      let host_end = self.op_offsets.get(index + 1).copied().unwrap_or(self.epilogue_offset);
+      let right = host_listing(&self.host_code[host_start..host_end], self.host_address + host_start as u64);
+      push_columns(&mut listing, &left, &right);
+    }
+    let epilogue = host_listing(&self.host_code[self.epilogue_offset..], self.host_address + self.epilogue_offset as u64);
+    push_columns(&mut listing, &[String::from("; epilogue")], &epilogue);
+    listing
+  }
+
+  /// Write the listing to its own file in `directory`, named for the block's
+  /// bank and address
+  pub fn write_to(&self, directory: &str) -> io::Result<PathBuf> {
+    fs::create_dir_all(directory)?;
+    let name = match self.bank {
+      Some(bank) => format!("{:02X}_{:04X}.log", bank, self.address),
+      None => format!("{:04X}.log", self.address),
+    };
+    let path = PathBuf::from(directory).join(name);
+    fs::write(&path, self.listing())?;
+    Ok(path)
+  }
+
+  fn location(&self) -> String {
+    match self.bank {
+      Some(bank) => format!("{:02X}:{:04X}", bank, self.address),
+      None => format!("{:04X}", self.address),
+    }
+  }
+}
+
+/// Directory that block listings are written to
+pub fn dump_directory() -> String {
+  std::env::var("GB_DUMP_DIR").unwrap_or_else(|_| String::from(DEFAULT_DUMP_DIR))
+}
+
+fn push_columns(listing: &mut String, left: &[String], right: &[String]) {
+  let rows = left.len().max(right.len());
+  for row in 0..rows {
+    let source = left.get(row).map(String::as_str).unwrap_or("");
+    let host = right.get(row).map(String::as_str).unwrap_or("");
+    listing.push_str(format!("{:width$}| {}", source, host, width = SOURCE_WIDTH).trim_end());
+    listing.push('\n');
+  }
+  listing.push('\n');
+}
+
+/// Disassemble host code, one instruction per line
+#[cfg(target_arch = "x86_64")]
+pub fn host_listing(code: &[u8], address: u64) -> Vec<String> {
+  use iced_x86::{Decoder, DecoderOptions, Formatter, IntelFormatter};
+
+  let mut decoder = Decoder::with_ip(64, code, address, DecoderOptions::NONE);
+  let mut formatter = IntelFormatter::new();
+  let mut lines = Vec::new();
+  for instruction in &mut decoder {
+    let start = (instruction.ip() - address) as usize;
+    let bytes: Vec<String> = code[start..(start + instruction.len())]
+      .iter()
+      .map(|byte| format!("{:02x}", byte))
+      .collect();
+    let mut text = String::new();
+    formatter.format(&instruction, &mut text);
+    lines.push(format!("{:012x}  {:30}{}", instruction.ip(), bytes.join(" "), text));
+  }
+  lines
+}
+
+/// Only x86_64 code can be disassembled, so other hosts list the raw
+/// instruction words
+#[cfg(not(target_arch = "x86_64"))]
+pub fn host_listing(code: &[u8], address: u64) -> Vec<String> {
+  code
+    .chunks(4)
+    .enumerate()
+    .map(|(index, word)| {
+      let bytes: Vec<String> = word.iter().map(|byte| format!("{:02x}", byte)).collect();
+      format!("{:012x}  {}", address + index as u64 * 4, bytes.join(" "))
+    })
+    .collect()
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+  use super::BlockDump;
+
+  #[test]
+  fn ops_line_up() {
+    // LD A, B / INC A / RET
+    let source = [0x78, 0x3c, 0xc9];
+    // mov al, bl / inc al / ret
+    let host_code = [0x88, 0xd8, 0xfe, 0xc0, 0xc3];
+    let dump = BlockDump {
+      bank: Some(1),
+      address: 0x4000,
+      source: &source,
+      op_lengths: &[1, 1, 1],
+      host_code: &host_code,
+      host_address: 0x1000,
+      op_offsets: &[0, 2, 4],
+      epilogue_offset: 5,
+    };
+    let listing = dump.listing();
+    let lines: Vec<&str> = listing.lines().collect();
+    assert_eq!(lines[0], "; block at 01:4000, 3 bytes compiled to 5 bytes at 0x1000");
+    assert!(lines[1].starts_with("01:4000  78"));
+    assert!(lines[1].ends_with("000000001000  88 d8                         mov al,bl"));
+    assert!(lines[3].starts_with("01:4001  3C"));
+    assert!(lines[3].ends_with("inc al"));
+    assert!(lines[5].ends_with("ret"));
+    assert!(lines[7].starts_with("; epilogue"));
+  }
+}
@@ -0,0 +1,195 @@
+//! Hashes of every frame the emulator presents, for checking that changes to
+//! the PPU or the JIT leave games running exactly as they did before. A run
+//! can log its hashes, one line per frame, and compare them to the log of a
+//! known-good run, which is much smaller than a recording of the same frames.
+//!
+//! Each line holds the frame number, a CRC-32 of the LCD shades, and a CRC-32
+//! of the audio samples produced during the frame. Shades are hashed rather
+//! than colors, so that the palette makes no difference. There is no audio
+//! device yet, so every frame has the hash of no samples.
+
+use crate::patch::crc32;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FrameHash {
+  pub video: u32,
+  pub audio: u32,
+}
+
+impl FrameHash {
+  /// Hash a frame of LCD shades, and the interleaved audio samples that were
+  /// produced along with it
+  pub fn of(lcd: &[u8], audio: &[i16]) -> Self {
+    let audio_bytes: Vec<u8> = audio.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+    Self {
+      video: crc32(lcd),
+      audio: crc32(&audio_bytes),
+    }
+  }
+}
+
+impl fmt::Display for FrameHash {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{:08x} {:08x}", self.video, self.audio)
+  }
+}
+
+/// Read a hash log, where line `n` holds the hashes of frame `n`
+pub fn parse_hash_log(text: &str) -> Result<Vec<FrameHash>, String> {
+  let mut hashes = Vec::new();
+  for (index, line) in text.lines().enumerate() {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let hash = match fields.as_slice() {
+      [frame, video, audio] if frame.parse::<usize>() == Ok(index) => {
+        let video = u32::from_str_radix(video, 16);
+        let audio = u32::from_str_radix(audio, 16);
+        video.and_then(|video| audio.map(|audio| FrameHash { video, audio })).ok()
+      },
+      _ => None,
+    };
+    match hash {
+      Some(hash) => hashes.push(hash),
+      None => return Err(format!("Invalid frame hash on line {}", index + 1)),
+    }
+  }
+  Ok(hashes)
+}
+
+/// The first frame that hashed differently from the baseline
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Mismatch {
+  pub frame: usize,
+  pub actual: FrameHash,
+  pub expected: FrameHash,
+}
+
+impl fmt::Display for Mismatch {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Frame {} differs from the baseline: {}, expected {}", self.frame, self.actual, self.expected)
+  }
+}
+
+/// Hashes each frame, logging the hashes to a file, comparing them to a
+/// baseline, or both
+#[derive(Default)]
+pub struct FrameHasher {
+  frames: usize,
+  log: Option<(String, BufWriter<File>)>,
+  baseline: Option<Vec<FrameHash>>,
+  mismatch: Option<Mismatch>,
+}
+
+impl FrameHasher {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Write the hash of every frame to a new file
+  pub fn log_to(&mut self, name: &str) -> Result<(), String> {
+    let file = File::create(name).map_err(|_| format!("Unable to create hash log \"{}\"", name))?;
+    self.log = Some((String::from(name), BufWriter::new(file)));
+    Ok(())
+  }
+
+  /// Compare every frame to the hashes logged by an earlier run
+  pub fn compare_with(&mut self, name: &str) -> Result<(), String> {
+    let text = std::fs::read_to_string(name).map_err(|_| format!("Unable to read hash log \"{}\"", name))?;
+    let baseline = parse_hash_log(&text).map_err(|msg| format!("{} of \"{}\"", msg, name))?;
+    self.set_baseline(baseline);
+    Ok(())
+  }
+
+  pub fn set_baseline(&mut self, baseline: Vec<FrameHash>) {
+    self.baseline = Some(baseline);
+  }
+
+  /// Hash the next frame. Frames past the end of the baseline aren't
+  /// compared, and neither is anything after the first mismatch.
+  pub fn hash_frame(&mut self, lcd: &[u8], audio: &[i16]) -> Result<(), String> {
+    let hash = FrameHash::of(lcd, audio);
+    let frame = self.frames;
+    self.frames += 1;
+    if let Some((name, writer)) = self.log.as_mut() {
+      writeln!(writer, "{} {}", frame, hash).map_err(|_| format!("Unable to write hash log \"{}\"", name))?;
+    }
+    let expected = self.baseline.as_ref().and_then(|baseline| baseline.get(frame).copied());
+    match expected {
+      Some(expected) if expected != hash && self.mismatch.is_none() => {
+        self.mismatch = Some(Mismatch { frame, actual: hash, expected });
+      },
+      _ => (),
+    }
+    Ok(())
+  }
+
+  pub fn frames_hashed(&self) -> usize {
+    self.frames
+  }
+
+  pub fn first_mismatch(&self) -> Option<&Mismatch> {
+    self.mismatch.as_ref()
+  }
+
+  /// Complete the log, returning a summary of the run
+  pub fn finish(self) -> Result<String, String> {
+    let mut summary = Vec::new();
+    if let Some((name, mut writer)) = self.log {
+      writer.flush().map_err(|_| format!("Unable to write hash log \"{}\"", name))?;
+      summary.push(format!("Logged hashes of {} frames to {}", self.frames, name));
+    }
+    if let Some(baseline) = self.baseline {
+      summary.push(match self.mismatch {
+        Some(mismatch) => mismatch.to_string(),
+        None => format!("{} frames matched the baseline", self.frames.min(baseline.len())),
+      });
+    }
+    Ok(summary.join("\n"))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{parse_hash_log, FrameHash, FrameHasher, Mismatch};
+  use crate::emulator::Core;
+
+  #[test]
+  fn log_format() {
+    let hash = FrameHash::of(&[], &[]);
+    assert_eq!(hash, FrameHash { video: 0, audio: 0 });
+    let log = "0 00000000 00000000\n1 cbf43926 00000000\n";
+    let hashes = parse_hash_log(log).unwrap();
+    assert_eq!(hashes, vec![hash, FrameHash::of(b"123456789", &[])]);
+    assert_eq!(format!("1 {}", hashes[1]), log.lines().nth(1).unwrap());
+    assert_eq!(parse_hash_log("0 00000000 00000000\n2 00000000 00000000"), Err(String::from("Invalid frame hash on line 2")));
+    assert!(parse_hash_log("0 00000000").is_err());
+  }
+
+  #[test]
+  fn compare_runs() {
+    // JR -2
+    let code = vec![0x18, 0xfe].into_boxed_slice();
+    let mut core = Core::with_code_block(code.clone());
+    core.frame_hasher = Some(FrameHasher::new());
+    for _ in 0..3 {
+      core.run_frame();
+    }
+    let hash = FrameHash::of(core.get_screen_buffer(), &[]);
+    assert_eq!(core.frame_hasher.as_ref().unwrap().frames_hashed(), 3);
+
+    let mut hasher = FrameHasher::new();
+    let expected = FrameHash { video: hash.video ^ 1, audio: 0 };
+    hasher.set_baseline(vec![hash, expected, hash]);
+    let mut core = Core::with_code_block(code);
+    core.frame_hasher = Some(hasher);
+    for _ in 0..4 {
+      core.run_frame();
+    }
+    let hasher = core.frame_hasher.take().unwrap();
+    assert_eq!(hasher.first_mismatch(), Some(&Mismatch { frame: 1, actual: hash, expected }));
+    let summary = format!("Frame 1 differs from the baseline: {}, expected {}", hash, expected);
+    assert_eq!(hasher.finish(), Ok(summary));
+  }
+}
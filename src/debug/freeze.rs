@@ -0,0 +1,224 @@
+//! Freezing regions of OAM or VRAM, to track down code that corrupts graphics.
+//! While a region is frozen, writes to it are dropped and logged, along with
+//! the instruction that made them. The debugger's `freeze log` command lists
+//! the log.
+
+use std::collections::VecDeque;
+
+/// Number of sprite entries in OAM
+pub const OAM_ENTRIES: usize = 40;
+/// Number of 16-byte tiles in a single VRAM bank
+pub const TILES_PER_BANK: usize = 384;
+/// Only the most recent blocked writes are kept
+const LOG_LIMIT: usize = 256;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FreezeRegion {
+  /// A 4-byte sprite attribute entry
+  OamEntry(u8),
+  /// A 16-byte tile in tile data memory (0x8000-0x97ff), in a specific bank
+  Tile(u8, u16),
+}
+
+impl FreezeRegion {
+  pub fn is_valid(&self) -> bool {
+    match self {
+      FreezeRegion::OamEntry(entry) => (*entry as usize) < OAM_ENTRIES,
+      FreezeRegion::Tile(bank, tile) => *bank < 2 && (*tile as usize) < TILES_PER_BANK,
+    }
+  }
+}
+
+impl std::fmt::Display for FreezeRegion {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      FreezeRegion::OamEntry(entry) => write!(f, "OAM entry {} ({:#06X})", entry, 0xfe00 + *entry as u16 * 4),
+      FreezeRegion::Tile(bank, tile) => write!(f, "Tile {}:{} ({:#06X})", bank, tile, 0x8000 + tile * 16),
+    }
+  }
+}
+
+/// A write that was dropped because its destination was frozen
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BlockedWrite {
+  /// Instruction pointer of the write. In compiled code, this is the start
+  /// of the block containing it.
+  pub ip: u16,
+  pub address: u16,
+  pub vram_bank: u8,
+  pub value: u8,
+}
+
+impl std::fmt::Display for BlockedWrite {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if (0x8000..0xa000).contains(&self.address) {
+      write!(f, "{:04X}: {:02X} to {}:{:04X}", self.ip, self.value, self.vram_bank, self.address)
+    } else {
+      write!(f, "{:04X}: {:02X} to {:04X}", self.ip, self.value, self.address)
+    }
+  }
+}
+
+pub struct FreezeTable {
+  oam_entries: u64,
+  tiles: [u64; TILES_PER_BANK * 2 / 64],
+  log: VecDeque<BlockedWrite>,
+  current_ip: u16,
+}
+
+impl FreezeTable {
+  pub fn new() -> Self {
+    Self {
+      oam_entries: 0,
+      tiles: [0; TILES_PER_BANK * 2 / 64],
+      log: VecDeque::with_capacity(LOG_LIMIT),
+      current_ip: 0,
+    }
+  }
+
+  /// Record the instruction about to run, so that blocked writes can be
+  /// attributed to it
+  pub fn set_ip(&mut self, ip: u16) {
+    self.current_ip = ip;
+  }
+
+  /// Returns false if the region does not exist
+  pub fn add(&mut self, region: FreezeRegion) -> bool {
+    if !region.is_valid() {
+      return false;
+    }
+    match region {
+      FreezeRegion::OamEntry(entry) => self.oam_entries |= 1 << entry,
+      FreezeRegion::Tile(bank, tile) => {
+        let index = bank as usize * TILES_PER_BANK + tile as usize;
+        self.tiles[index / 64] |= 1 << (index % 64);
+      },
+    }
+    true
+  }
+
+  /// Returns true if the region was frozen
+  pub fn remove(&mut self, region: FreezeRegion) -> bool {
+    let was_frozen = self.list().contains(&region);
+    match region {
+      FreezeRegion::OamEntry(entry) if region.is_valid() => self.oam_entries &= !(1 << entry),
+      FreezeRegion::Tile(bank, tile) if region.is_valid() => {
+        let index = bank as usize * TILES_PER_BANK + tile as usize;
+        self.tiles[index / 64] &= !(1 << (index % 64));
+      },
+      _ => (),
+    }
+    was_frozen
+  }
+
+  pub fn list(&self) -> Vec<FreezeRegion> {
+    let mut regions = Vec::new();
+    for entry in 0..OAM_ENTRIES {
+      if self.oam_entries & (1 << entry) != 0 {
+        regions.push(FreezeRegion::OamEntry(entry as u8));
+      }
+    }
+    for index in 0..(TILES_PER_BANK * 2) {
+      if self.tiles[index / 64] & (1 << (index % 64)) != 0 {
+        let bank = index / TILES_PER_BANK;
+        let tile = index % TILES_PER_BANK;
+        regions.push(FreezeRegion::Tile(bank as u8, tile as u16));
+      }
+    }
+    regions
+  }
+
+  /// The most recent blocked writes, oldest first
+  pub fn get_log(&self) -> &VecDeque<BlockedWrite> {
+    &self.log
+  }
+
+  /// Whether any tile is frozen, so that writes to VRAM need checking
+  pub fn has_tiles(&self) -> bool {
+    self.tiles.iter().any(|bits| *bits != 0)
+  }
+
+  /// Called for every write to VRAM. Returns true if the write should be
+  /// dropped.
+  pub fn blocks_vram_write(&mut self, vram_bank: usize, address: u16, value: u8) -> bool {
+    if address >= 0x9800 {
+      return false;
+    }
+    let index = vram_bank * TILES_PER_BANK + ((address as usize & 0x1fff) >> 4);
+    if self.tiles[index / 64] & (1 << (index % 64)) == 0 {
+      return false;
+    }
+    self.log_write(address, vram_bank as u8, value);
+    true
+  }
+
+  /// Called for every write to OAM. Returns true if the write should be
+  /// dropped.
+  pub fn blocks_oam_write(&mut self, address: u16, value: u8) -> bool {
+    let entry = (address as usize & 0xff) >> 2;
+    if self.oam_entries & (1 << entry) == 0 {
+      return false;
+    }
+    self.log_write(address, 0, value);
+    true
+  }
+
+  fn log_write(&mut self, address: u16, vram_bank: u8, value: u8) {
+    if self.log.len() >= LOG_LIMIT {
+      self.log.pop_front();
+    }
+    self.log.push_back(BlockedWrite { ip: self.current_ip, address, vram_bank, value });
+  }
+}
+
+impl Default for FreezeTable {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{BlockedWrite, FreezeRegion, FreezeTable, LOG_LIMIT};
+
+  #[test]
+  fn freeze_tiles() {
+    let mut table = FreezeTable::new();
+    table.set_ip(0x150);
+    assert!(table.add(FreezeRegion::Tile(0, 2)));
+    assert!(!table.add(FreezeRegion::Tile(0, 384)));
+    assert!(!table.blocks_vram_write(0, 0x801f, 0x55));
+    assert!(table.blocks_vram_write(0, 0x8020, 0x55));
+    assert!(table.blocks_vram_write(0, 0x802f, 0x55));
+    assert!(!table.blocks_vram_write(1, 0x8020, 0x55));
+    assert!(!table.blocks_vram_write(0, 0x9820, 0x55));
+    assert_eq!(table.get_log().len(), 2);
+    assert_eq!(table.get_log()[0], BlockedWrite { ip: 0x150, address: 0x8020, vram_bank: 0, value: 0x55 });
+    assert_eq!(table.get_log()[0].to_string(), "0150: 55 to 0:8020");
+  }
+
+  #[test]
+  fn freeze_oam() {
+    let mut table = FreezeTable::new();
+    table.add(FreezeRegion::OamEntry(1));
+    table.add(FreezeRegion::Tile(1, 383));
+    assert_eq!(table.list(), vec![FreezeRegion::OamEntry(1), FreezeRegion::Tile(1, 383)]);
+    assert!(!table.blocks_oam_write(0xfe03, 0x10));
+    assert!(table.blocks_oam_write(0xfe04, 0x10));
+    assert!(table.remove(FreezeRegion::OamEntry(1)));
+    assert!(!table.remove(FreezeRegion::OamEntry(1)));
+    assert!(!table.blocks_oam_write(0xfe04, 0x10));
+  }
+
+  #[test]
+  fn log_is_bounded() {
+    let mut table = FreezeTable::new();
+    table.add(FreezeRegion::OamEntry(0));
+    for i in 0..(LOG_LIMIT + 10) {
+      table.set_ip(i as u16);
+      table.blocks_oam_write(0xfe00, 0);
+    }
+    assert_eq!(table.get_log().len(), LOG_LIMIT);
+    assert_eq!(table.get_log()[0].ip, 10);
+  }
+}
@@ -0,0 +1,150 @@
+//! Checks run by the fuzz targets in `fuzz/`, kept in the library so that
+//! the tests below can run them on fixed inputs without a fuzzer. Each check
+//! panics on failure, which is what the fuzzer looks for.
+
+use crate::decoder::decode;
+#[cfg(jit_backend)]
+use crate::emulator::{Core, RunState};
+#[cfg(jit_backend)]
+use crate::timing::MachineCycles;
+#[cfg(jit_backend)]
+use super::verify::{Divergence, MemorySnapshot};
+
+/// Address the fuzzed code is placed at, past the cartridge header so that
+/// the cartridge type stays fixed
+pub const CODE_START: usize = 0x150;
+/// Longest sequence of fuzzed code
+pub const MAX_CODE_LENGTH: usize = 0x400;
+/// Blocks run before giving up on code that never halts
+pub const MAX_BLOCKS: usize = 64;
+
+/// Decode an instruction at every offset in `data`. Instructions at the end
+/// are padded with zeroes, like the straddling reads the cache makes.
+pub fn check_decode(data: &[u8]) {
+  for start in 0..data.len() {
+    let mut padded = [0; 3];
+    let available = (data.len() - start).min(3);
+    padded[..available].copy_from_slice(&data[start..(start + available)]);
+    let (op, length, cycles) = decode(&padded);
+    assert!((1..=3).contains(&length), "{:02X?} decoded with length {}", padded, length);
+    assert!(cycles > 0 && cycles % 4 == 0, "{:02X?} decoded with {} cycles", padded, cycles);
+    // every opcode prints, for the disassembler
+    assert!(!op.to_string().is_empty());
+  }
+}
+
+/// Run fuzzed code on two cores from the same starting state, one with the
+/// interpreter and one with the JIT, a block at a time. Each core has its
+/// own peripherals, so code that touches IO is compared fairly. Panics with
+/// a report if the cores ever disagree.
+///
+/// The first ten bytes set AF, BC, DE, HL, and SP, and the rest are the code.
+/// Comparison stops once the code halts or jumps outside of itself.
+#[cfg(jit_backend)]
+pub fn check_lockstep(data: &[u8]) {
+  let (registers, code) = data.split_at(data.len().min(10));
+  let register = |index: usize| {
+    let low = registers.get(index * 2).copied().unwrap_or(0) as u32;
+    let high = registers.get(index * 2 + 1).copied().unwrap_or(0) as u32;
+    (high << 8) | low
+  };
+  // The rest of ROM is HALTs, so that a block running off the end of the
+  // code stops there. Otherwise, the interpreter would keep going through
+  // VRAM and cartridge RAM, where the JIT ends its blocks.
+  let mut rom = vec![0; 0x8000];
+  for byte in rom[CODE_START..].iter_mut() {
+    *byte = 0x76;
+  }
+  let code = &code[..code.len().min(MAX_CODE_LENGTH)];
+  let code_range = (CODE_START as u32)..((CODE_START + code.len()) as u32);
+  rom[CODE_START..(CODE_START + code.len())].copy_from_slice(code);
+
+  let mut cores = [false, true].iter().map(|&jit| {
+    let mut core = Core::with_code_block(rom.clone().into_boxed_slice());
+    core.registers.af = register(0) & 0xfff0;
+    core.registers.bc = register(1);
+    core.registers.de = register(2);
+    core.registers.hl = register(3);
+    core.registers.sp = register(4);
+    core.registers.ip = CODE_START as u32;
+    core.set_jit_enabled(jit).unwrap();
+    // compiled code has to stop after every block, like the interpreter
+    core.cache.link_blocks = false;
+    core.jit_cycle_budget = MachineCycles(0);
+    core
+  }).collect::<Vec<_>>();
+  let (interp, jit) = cores.split_at_mut(1);
+  let (interp, jit) = (&mut interp[0], &mut jit[0]);
+
+  for _ in 0..MAX_BLOCKS {
+    // Blocks can only be compared when both engines end them in the same
+    // place, which is only certain within the fuzzed code
+    let ip = interp.registers.ip;
+    if interp.run_state != RunState::Run || !code_range.contains(&ip) {
+      break;
+    }
+    let initial = MemorySnapshot::capture(&interp.memory);
+    interp.run_code_block();
+    jit.run_code_block();
+    let divergence = Divergence::compare(
+      &initial,
+      (0, &interp.registers, &MemorySnapshot::capture(&interp.memory)),
+      (0, &jit.registers, &MemorySnapshot::capture(&jit.memory)),
+    );
+    if let Some(divergence) = divergence {
+      panic!("Engines disagree after the block at {:04X}:\n{}", ip, divergence);
+    }
+    // anything not covered above, like the run state and the peripherals
+    let (interp_state, jit_state) = (interp.save_state(), jit.save_state());
+    if let Some(offset) = interp_state.iter().zip(jit_state.iter()).position(|(a, b)| a != b) {
+      panic!("Engines disagree after the block at {:04X}: save states differ at byte {}", ip, offset);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::check_decode;
+  #[cfg(jit_backend)]
+  use super::check_lockstep;
+
+  fn random_bytes(seed: &mut u32, length: usize) -> Vec<u8> {
+    (0..length).map(|_| {
+      *seed ^= *seed << 13;
+      *seed ^= *seed >> 17;
+      *seed ^= *seed << 5;
+      *seed as u8
+    }).collect()
+  }
+
+  #[test]
+  fn decode_arbitrary_bytes() {
+    let every_pair: Vec<u8> = (0..=0xffu8).flat_map(|first| [first, 0xcb, first].to_vec()).collect();
+    check_decode(&every_pair);
+    check_decode(&[0xcb]);
+    check_decode(&[0x01, 0x02]);
+  }
+
+  #[cfg(jit_backend)]
+  #[test]
+  fn lockstep_random_code() {
+    let mut seed = 0x2468_ace1;
+    // registers pointing into WRAM, then a few loads, stores, and ALU ops
+    check_lockstep(&[
+      0xb0, 0x01, 0x00, 0xc0, 0x10, 0xc0, 0x20, 0xc0, 0xfe, 0xcf,
+      0x3e, 0x42, 0x22, 0x2a, 0x80, 0x12, 0xc5, 0xd1, 0xcb, 0x37, 0x76,
+    ]);
+    // inputs the fuzzer has found problems with: LD (HL-), A wrapping
+    // around, a CALL pushing into BGP and DMA, and DMA ignoring writes to
+    // itself while running
+    check_lockstep(&[0xb0, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0x32, 0x76]);
+    check_lockstep(&[0, 0x08, 0, 0, 0, 0, 0, 0, 0x48, 0xff, 0xcd, 0x00, 0x00]);
+    check_lockstep(&[
+      0x65, 0x08, 0x46, 0x15, 0xf5, 0x96, 0x33, 0x33, 0x16, 0x24,
+      0xe2, 0x85, 0xea, 0x2d, 0xa5, 0x51, 0xe2, 0xb4, 0x76,
+    ]);
+    for _ in 0..64 {
+      check_lockstep(&random_bytes(&mut seed, 48));
+    }
+  }
+}
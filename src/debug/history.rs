@@ -0,0 +1,186 @@
+//! A record of the most recently executed blocks, for post-mortem analysis.
+//! It is cheap enough to always be enabled, so a crash report can show the
+//! path that led up to a failure without needing a full trace.
+//!
+//! The history is kept per thread, so that the panic hook can print it for
+//! whichever core was running when the panic occurred. Alongside it is the
+//! cartridge's banking state as of the latest block, since a bad bank switch
+//! is a common cause of running off into garbage.
+
+use crate::cart::MbcState;
+use std::cell::RefCell;
+
+/// Number of blocks remembered
+pub const HISTORY_LENGTH: usize = 32;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Engine {
+  Compiled,
+  Interpreted,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BlockEntry {
+  pub address: u16,
+  /// ROM bank the block was run from, if it is in ROM
+  pub bank: Option<usize>,
+  pub engine: Engine,
+}
+
+impl std::fmt::Display for BlockEntry {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let engine = match self.engine {
+      Engine::Compiled => "JIT",
+      Engine::Interpreted => "interpreter",
+    };
+    match self.bank {
+      Some(bank) => write!(f, "{:02X}:{:04X} ({})", bank, self.address, engine),
+      None => write!(f, "--:{:04X} ({})", self.address, engine),
+    }
+  }
+}
+
+/// Ring buffer of the last HISTORY_LENGTH block entries
+pub struct BlockHistory {
+  entries: [Option<BlockEntry>; HISTORY_LENGTH],
+  next: usize,
+  /// Banking registers when the most recent block started
+  mbc_state: Option<MbcState>,
+}
+
+impl BlockHistory {
+  pub fn new() -> Self {
+    Self {
+      entries: [None; HISTORY_LENGTH],
+      next: 0,
+      mbc_state: None,
+    }
+  }
+
+  pub fn set_mbc_state(&mut self, state: MbcState) {
+    self.mbc_state = Some(state);
+  }
+
+  pub fn record(&mut self, entry: BlockEntry) {
+    self.entries[self.next] = Some(entry);
+    self.next = (self.next + 1) % HISTORY_LENGTH;
+  }
+
+  /// Returns the recorded entries, oldest first
+  pub fn entries(&self) -> Vec<BlockEntry> {
+    let (newer, older) = self.entries.split_at(self.next);
+    older.iter().chain(newer.iter()).filter_map(|entry| *entry).collect()
+  }
+
+  /// Format the history as a report, one block per line
+  pub fn report(&self) -> String {
+    let entries = self.entries();
+    if entries.is_empty() {
+      return String::from("No blocks have been executed\n");
+    }
+    let mut report = format!("Last {} blocks executed, most recent last:\n", entries.len());
+    for entry in entries {
+      report.push_str(&format!("  {}\n", entry));
+    }
+    if let Some(state) = self.mbc_state {
+      report.push_str(&format!("MBC: {}\n", state));
+    }
+    report
+  }
+}
+
+impl Default for BlockHistory {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+thread_local! {
+  static HISTORY: RefCell<BlockHistory> = RefCell::new(BlockHistory::new());
+}
+
+/// Record the start of a block on the current thread, and the banking state
+/// it starts with
+pub fn record(address: u16, bank: Option<usize>, engine: Engine, mbc_state: MbcState) {
+  HISTORY.with(|history| {
+    let mut history = history.borrow_mut();
+    history.record(BlockEntry { address, bank, engine });
+    history.set_mbc_state(mbc_state);
+  });
+}
+
+/// Returns the blocks most recently executed on the current thread, oldest
+/// first
+pub fn recent_blocks() -> Vec<BlockEntry> {
+  HISTORY.with(|history| history.borrow().entries())
+}
+
+/// A report of the blocks most recently executed on the current thread
+pub fn report() -> String {
+  HISTORY.with(|history| history.borrow().report())
+}
+
+/// Print the block history after the regular panic message
+pub fn install_panic_hook() {
+  let default_hook = std::panic::take_hook();
+  std::panic::set_hook(Box::new(move |info| {
+    default_hook(info);
+    // the panic may have happened while the history was being updated
+    let report = HISTORY.with(|history| history.try_borrow().map(|history| history.report()));
+    if let Ok(report) = report {
+      eprint!("{}", report);
+    }
+  }));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{BlockEntry, BlockHistory, Engine, HISTORY_LENGTH};
+  use crate::cart::MbcState;
+
+  fn entry(address: u16) -> BlockEntry {
+    BlockEntry {
+      address,
+      bank: Some(0),
+      engine: Engine::Compiled,
+    }
+  }
+
+  #[test]
+  fn keeps_order() {
+    let mut history = BlockHistory::new();
+    assert!(history.entries().is_empty());
+    history.record(entry(0x100));
+    history.record(entry(0x150));
+    assert_eq!(history.entries(), vec![entry(0x100), entry(0x150)]);
+  }
+
+  #[test]
+  fn drops_oldest_entries() {
+    let mut history = BlockHistory::new();
+    for address in 0..(HISTORY_LENGTH as u16 + 3) {
+      history.record(entry(address));
+    }
+    let entries = history.entries();
+    assert_eq!(entries.len(), HISTORY_LENGTH);
+    assert_eq!(entries[0], entry(3));
+    assert_eq!(entries[HISTORY_LENGTH - 1], entry(HISTORY_LENGTH as u16 + 2));
+  }
+
+  #[test]
+  fn report() {
+    let mut history = BlockHistory::new();
+    history.record(entry(0x150));
+    history.record(BlockEntry {
+      address: 0xc000,
+      bank: None,
+      engine: Engine::Interpreted,
+    });
+    assert_eq!(
+      history.report(),
+      "Last 2 blocks executed, most recent last:\n  00:0150 (JIT)\n  --:C000 (interpreter)\n",
+    );
+    history.set_mbc_state(MbcState { rom_bank: 3, ram_bank: 0, ram_enabled: false, mode: 0 });
+    assert!(history.report().ends_with("  --:C000 (interpreter)\nMBC: ROM bank 03, RAM bank 00 (disabled), mode 0\n"));
+  }
+}
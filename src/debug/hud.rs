@@ -0,0 +1,238 @@
+//! Performance counters for the on-screen HUD, or for printing once a second
+//! without a window. The core only accumulates totals; the HUD compares two
+//! snapshots of them a second apart to see how fast things are going.
+
+use crate::emulator::Core;
+use crate::timing::CYCLES_PER_SECOND;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Host time spent running each half of the emulator. Timing every block has
+/// a cost of its own, so the core only keeps these while the HUD is shown.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PerfCounters {
+  /// Running CPU code, in either engine, including compiling it
+  pub cpu: Duration,
+  /// Catching up the PPU, along with the timer and serial port that run
+  /// alongside it
+  pub ppu: Duration,
+}
+
+/// Running totals taken from the core at one point in time
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PerfSnapshot {
+  pub cycles: u64,
+  /// Frames completed by the PPU, whether or not they were shown
+  pub frames: u32,
+  pub translations: usize,
+  pub lookups: usize,
+  pub hits: usize,
+  pub counters: PerfCounters,
+  pub paused: bool,
+}
+
+impl PerfSnapshot {
+  pub fn take(core: &Core) -> Self {
+    #[cfg(jit_backend)]
+    let stats = core.cache.get_stats();
+    #[cfg(jit_backend)]
+    let (translations, lookups, hits) = (stats.translations, stats.lookups, stats.hits);
+    #[cfg(not(jit_backend))]
+    let (translations, lookups, hits) = (0, 0, 0);
+    Self {
+      cycles: core.cycles_elapsed(),
+      frames: core.memory.io.video.get_frame_count(),
+      translations,
+      lookups,
+      hits,
+      counters: core.perf_counters.unwrap_or_default(),
+      paused: core.is_paused(),
+    }
+  }
+}
+
+/// Rates measured between two snapshots
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PerfReport {
+  /// Emulated time as a percentage of real time
+  pub speed: f64,
+  /// Frames the shell showed each second
+  pub host_fps: f64,
+  /// Frames the emulated PPU completed each second
+  pub emulated_fps: f64,
+  /// Blocks compiled each second
+  pub blocks_compiled: f64,
+  /// Percentage of compiled block lookups that found a block already in the
+  /// cache, if any lookups happened
+  pub cache_hit_rate: Option<f64>,
+  /// Share of the measured time spent on the CPU and on the PPU, as
+  /// percentages
+  pub cpu_share: f64,
+  pub ppu_share: f64,
+  /// The core was paused when the later snapshot was taken
+  pub paused: bool,
+}
+
+impl PerfReport {
+  /// Compare two snapshots taken `elapsed` apart, during which the shell
+  /// showed `host_frames` frames. A game loaded in between resets some of
+  /// the totals, which just reads as a slow second.
+  pub fn between(earlier: &PerfSnapshot, later: &PerfSnapshot, host_frames: u32, elapsed: Duration) -> Self {
+    let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+    let cycles = later.cycles.saturating_sub(earlier.cycles);
+    let frames = later.frames.saturating_sub(earlier.frames);
+    let lookups = later.lookups.saturating_sub(earlier.lookups);
+    let hits = later.hits.saturating_sub(earlier.hits);
+    let cpu = later.counters.cpu.saturating_sub(earlier.counters.cpu).as_secs_f64();
+    let ppu = later.counters.ppu.saturating_sub(earlier.counters.ppu).as_secs_f64();
+    let share = |time: f64| if cpu + ppu > 0.0 { time * 100.0 / (cpu + ppu) } else { 0.0 };
+    Self {
+      speed: cycles as f64 * 100.0 / (CYCLES_PER_SECOND as f64 * seconds),
+      host_fps: host_frames as f64 / seconds,
+      emulated_fps: frames as f64 / seconds,
+      blocks_compiled: later.translations.saturating_sub(earlier.translations) as f64 / seconds,
+      cache_hit_rate: if lookups > 0 { Some(hits as f64 * 100.0 / lookups as f64) } else { None },
+      cpu_share: share(cpu),
+      ppu_share: share(ppu),
+      paused: later.paused,
+    }
+  }
+
+  /// The report as short lines for the HUD, which only has capital letters
+  pub fn hud_text(&self) -> String {
+    let hit_rate = match self.cache_hit_rate {
+      Some(rate) => format!("{:.1}%", rate),
+      None => String::from("-"),
+    };
+    format!(
+      "{}SPEED {:.0}%\nFPS {:.0}/{:.0}\nJIT {:.0}/S HIT {}\nCPU {:.0}% PPU {:.0}%",
+      if self.paused { "PAUSED\n" } else { "" },
+      self.speed,
+      self.host_fps,
+      self.emulated_fps,
+      self.blocks_compiled,
+      hit_rate,
+      self.cpu_share,
+      self.ppu_share,
+    )
+  }
+}
+
+impl fmt::Display for PerfReport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "speed {:.1}%, {:.1} fps shown, {:.1} fps emulated, {:.0} blocks compiled/s",
+      self.speed,
+      self.host_fps,
+      self.emulated_fps,
+      self.blocks_compiled,
+    )?;
+    if let Some(rate) = self.cache_hit_rate {
+      write!(f, ", {:.1}% cache hits", rate)?;
+    }
+    write!(f, ", CPU {:.0}% / PPU {:.0}%", self.cpu_share, self.ppu_share)?;
+    if self.paused {
+      write!(f, ", paused")?;
+    }
+    Ok(())
+  }
+}
+
+/// Samples the core once a second, and keeps the latest report
+pub struct PerfHud {
+  last_snapshot: PerfSnapshot,
+  last_sample: Instant,
+  host_frames: u32,
+  report: Option<PerfReport>,
+}
+
+impl PerfHud {
+  /// Start measuring, which turns on the core's timers
+  pub fn start(core: &mut Core) -> Self {
+    core.perf_counters.get_or_insert_with(PerfCounters::default);
+    Self {
+      last_snapshot: PerfSnapshot::take(core),
+      last_sample: Instant::now(),
+      host_frames: 0,
+      report: None,
+    }
+  }
+
+  /// Stop measuring, and turn the core's timers back off
+  pub fn stop(self, core: &mut Core) {
+    core.perf_counters = None;
+  }
+
+  /// Call each time the shell finishes a frame. Returns a new report once a
+  /// second has passed since the last one.
+  pub fn frame_shown(&mut self, core: &Core) -> Option<PerfReport> {
+    self.host_frames += 1;
+    let elapsed = self.last_sample.elapsed();
+    if elapsed < Duration::from_secs(1) {
+      return None;
+    }
+    let snapshot = PerfSnapshot::take(core);
+    let report = PerfReport::between(&self.last_snapshot, &snapshot, self.host_frames, elapsed);
+    self.last_snapshot = snapshot;
+    self.last_sample = Instant::now();
+    self.host_frames = 0;
+    self.report = Some(report);
+    self.report
+  }
+
+  /// The most recent report, once a second has been measured
+  pub fn report(&self) -> Option<&PerfReport> {
+    self.report.as_ref()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{PerfCounters, PerfReport, PerfSnapshot};
+  use crate::timing::CYCLES_PER_SECOND;
+  use std::time::Duration;
+
+  #[test]
+  fn report_rates() {
+    let earlier = PerfSnapshot {
+      cycles: 1000,
+      frames: 10,
+      translations: 50,
+      lookups: 100,
+      hits: 90,
+      counters: PerfCounters::default(),
+      paused: false,
+    };
+    let later = PerfSnapshot {
+      cycles: 1000 + CYCLES_PER_SECOND,
+      frames: 10 + 59,
+      translations: 70,
+      lookups: 300,
+      hits: 270,
+      counters: PerfCounters {
+        cpu: Duration::from_millis(300),
+        ppu: Duration::from_millis(100),
+      },
+      paused: false,
+    };
+    let report = PerfReport::between(&earlier, &later, 30, Duration::from_millis(500));
+    assert_eq!(report.speed, 200.0);
+    assert_eq!(report.host_fps, 60.0);
+    assert_eq!(report.emulated_fps, 118.0);
+    assert_eq!(report.blocks_compiled, 40.0);
+    assert_eq!(report.cache_hit_rate, Some(90.0));
+    assert_eq!(report.cpu_share, 75.0);
+    assert_eq!(report.hud_text().lines().next(), Some("SPEED 200%"));
+
+    // a new game starts its totals over
+    let report = PerfReport::between(&later, &earlier, 0, Duration::from_secs(1));
+    assert_eq!(report.speed, 0.0);
+    assert_eq!(report.cache_hit_rate, None);
+
+    let paused = PerfSnapshot { paused: true, ..later };
+    let report = PerfReport::between(&later, &paused, 60, Duration::from_secs(1));
+    assert_eq!(report.hud_text().lines().next(), Some("PAUSED"));
+    assert!(report.to_string().ends_with(", paused"));
+  }
+}
@@ -0,0 +1,192 @@
+//! Named view of the IO registers, so that the debugger and tests can read
+//! and write them by name without knowing which device holds each one.
+//! Reads have no side effects, while pokes go through the bus like a CPU
+//! write, so that writing DMA starts a transfer and turning off the LCD
+//! blanks it.
+
+use crate::emulator::Core;
+use crate::mem::{memory_peek_byte, memory_write_byte};
+use std::fmt;
+
+/// A group of bits within a register
+#[derive(Debug, Eq, PartialEq)]
+pub struct Field {
+  pub name: &'static str,
+  pub mask: u8,
+}
+
+impl Field {
+  pub fn decode(&self, value: u8) -> u8 {
+    (value & self.mask) >> self.mask.trailing_zeros()
+  }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct IoRegister {
+  pub name: &'static str,
+  pub address: u16,
+  /// Bits worth decoding, from the highest down. Registers that hold a
+  /// single number have none.
+  pub fields: &'static [Field],
+}
+
+const fn field(name: &'static str, mask: u8) -> Field {
+  Field { name, mask }
+}
+
+const INTERRUPT_FIELDS: &[Field] = &[
+  field("JOYPAD", 0x10),
+  field("SERIAL", 0x08),
+  field("TIMER", 0x04),
+  field("STAT", 0x02),
+  field("VBLANK", 0x01),
+];
+
+const PALETTE_FIELDS: &[Field] = &[
+  field("COLOR3", 0xc0),
+  field("COLOR2", 0x30),
+  field("COLOR1", 0x0c),
+  field("COLOR0", 0x03),
+];
+
+/// Every register mapped on the DMG, in address order
+pub const IO_REGISTERS: &[IoRegister] = &[
+  IoRegister {
+    name: "P1",
+    address: 0xff00,
+    fields: &[field("ACTIONS", 0x20), field("DIRECTIONS", 0x10), field("LINES", 0x0f)],
+  },
+  IoRegister { name: "SB", address: 0xff01, fields: &[] },
+  IoRegister { name: "SC", address: 0xff02, fields: &[field("START", 0x80), field("CLOCK", 0x01)] },
+  IoRegister { name: "DIV", address: 0xff04, fields: &[] },
+  IoRegister { name: "TIMA", address: 0xff05, fields: &[] },
+  IoRegister { name: "TMA", address: 0xff06, fields: &[] },
+  IoRegister { name: "TAC", address: 0xff07, fields: &[field("ENABLE", 0x04), field("CLOCK", 0x03)] },
+  IoRegister { name: "IF", address: 0xff0f, fields: INTERRUPT_FIELDS },
+  IoRegister {
+    name: "LCDC",
+    address: 0xff40,
+    fields: &[
+      field("LCD", 0x80),
+      field("WIN_MAP", 0x40),
+      field("WIN", 0x20),
+      field("TILES", 0x10),
+      field("BG_MAP", 0x08),
+      field("OBJ_SIZE", 0x04),
+      field("OBJ", 0x02),
+      field("BG", 0x01),
+    ],
+  },
+  IoRegister {
+    name: "STAT",
+    address: 0xff41,
+    fields: &[
+      field("LYC_INT", 0x40),
+      field("MODE2_INT", 0x20),
+      field("MODE1_INT", 0x10),
+      field("MODE0_INT", 0x08),
+      field("LYC", 0x04),
+      field("MODE", 0x03),
+    ],
+  },
+  IoRegister { name: "SCY", address: 0xff42, fields: &[] },
+  IoRegister { name: "SCX", address: 0xff43, fields: &[] },
+  IoRegister { name: "LY", address: 0xff44, fields: &[] },
+  IoRegister { name: "LYC", address: 0xff45, fields: &[] },
+  IoRegister { name: "DMA", address: 0xff46, fields: &[] },
+  IoRegister { name: "BGP", address: 0xff47, fields: PALETTE_FIELDS },
+  IoRegister { name: "OBP0", address: 0xff48, fields: PALETTE_FIELDS },
+  IoRegister { name: "OBP1", address: 0xff49, fields: PALETTE_FIELDS },
+  IoRegister { name: "WY", address: 0xff4a, fields: &[] },
+  IoRegister { name: "WX", address: 0xff4b, fields: &[] },
+  IoRegister { name: "IE", address: 0xffff, fields: INTERRUPT_FIELDS },
+];
+
+/// Look up a register by its name, in any case, or by its address
+pub fn find_register(name: &str) -> Option<&'static IoRegister> {
+  let name = name.trim();
+  let address = super::command::parse_address(name);
+  IO_REGISTERS
+    .iter()
+    .find(|register| register.name.eq_ignore_ascii_case(name) || Some(register.address) == address)
+}
+
+/// A register along with the value it held when it was read
+#[derive(Debug, Eq, PartialEq)]
+pub struct RegisterValue {
+  pub register: &'static IoRegister,
+  pub value: u8,
+}
+
+impl RegisterValue {
+  /// Each field's name and value
+  pub fn fields(&self) -> Vec<(&'static str, u8)> {
+    self.register.fields.iter().map(|field| (field.name, field.decode(self.value))).collect()
+  }
+}
+
+impl fmt::Display for RegisterValue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{:04X} {:<4} = {:02X}", self.register.address, self.register.name, self.value)?;
+    for (name, value) in self.fields() {
+      write!(f, " {}={}", name, value)?;
+    }
+    Ok(())
+  }
+}
+
+pub fn read_register(core: &Core, register: &'static IoRegister) -> RegisterValue {
+  RegisterValue {
+    register,
+    value: memory_peek_byte(&core.memory, register.address),
+  }
+}
+
+/// Read every register, in address order
+pub fn read_all_registers(core: &Core) -> Vec<RegisterValue> {
+  IO_REGISTERS.iter().map(|register| read_register(core, register)).collect()
+}
+
+/// Write a register as the CPU would. Like any other write, pokes are seen
+/// by watchpoints, and ignored while a DMA has the bus.
+pub fn poke_register(core: &mut Core, register: &IoRegister, value: u8) {
+  memory_write_byte(&mut core.memory, register.address, value);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{find_register, poke_register, read_all_registers, read_register, IO_REGISTERS};
+  use crate::emulator::Core;
+
+  #[test]
+  fn lookup() {
+    assert_eq!(find_register("lcdc").map(|register| register.address), Some(0xff40));
+    assert_eq!(find_register("0xff47").map(|register| register.name), Some("BGP"));
+    assert_eq!(find_register("65535").map(|register| register.name), Some("IE"));
+    assert!(find_register("NR52").is_none());
+    assert!(IO_REGISTERS.windows(2).all(|pair| pair[0].address < pair[1].address));
+  }
+
+  #[test]
+  fn read_and_poke() {
+    let mut core = Core::with_code_block(vec![0x18, 0xfe].into_boxed_slice());
+    let lcdc = find_register("LCDC").unwrap();
+    poke_register(&mut core, lcdc, 0x91);
+    let value = read_register(&core, lcdc);
+    assert_eq!(value.value, 0x91);
+    assert_eq!(value.fields()[0], ("LCD", 1));
+    assert_eq!(
+      value.to_string(),
+      "FF40 LCDC = 91 LCD=1 WIN_MAP=0 WIN=0 TILES=1 BG_MAP=0 OBJ_SIZE=0 OBJ=0 BG=1",
+    );
+
+    poke_register(&mut core, find_register("IE").unwrap(), 0xff);
+    poke_register(&mut core, find_register("BGP").unwrap(), 0b11100100);
+    let all = read_all_registers(&core);
+    assert_eq!(all.len(), IO_REGISTERS.len());
+    let bgp = all.iter().find(|value| value.register.name == "BGP").unwrap();
+    assert_eq!(bgp.fields(), vec![("COLOR3", 3), ("COLOR2", 2), ("COLOR1", 1), ("COLOR0", 0)]);
+    // IE only has five bits
+    assert_eq!(all.last().unwrap().to_string(), "FFFF IE   = 1F JOYPAD=1 SERIAL=1 TIMER=1 STAT=1 VBLANK=1");
+  }
+}
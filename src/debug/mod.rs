@@ -1,3 +1,40 @@
+#[cfg(feature = "std")]
+pub mod breakpoint;
+#[cfg(feature = "std")]
 pub mod command;
+#[cfg(feature = "std")]
 pub mod disassembly;
+#[cfg(all(jit_backend, feature = "dump_disassembly"))]
+pub mod dump;
+#[cfg(feature = "std")]
+pub mod framehash;
+#[cfg(feature = "std")]
+pub mod freeze;
+#[cfg(feature = "std")]
+pub mod fuzz;
+#[cfg(feature = "std")]
+pub mod history;
+#[cfg(feature = "std")]
+pub mod hud;
+#[cfg(feature = "std")]
+pub mod io_registers;
+#[cfg(feature = "std")]
+pub mod perf;
+#[cfg(all(jit_backend, feature = "std"))]
+pub mod profile;
+#[cfg(feature = "std")]
 pub mod protocol;
+#[cfg(feature = "std")]
+pub mod search;
+#[cfg(feature = "std")]
+pub mod stack;
+#[cfg(feature = "std")]
+pub mod testrom;
+#[cfg(feature = "std")]
+pub mod trace;
+#[cfg(feature = "debugger")]
+pub mod tui;
+#[cfg(all(jit_backend, feature = "std"))]
+pub mod verify;
+// the only debugging support the core needs without std
+pub mod watchpoint;
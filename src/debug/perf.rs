@@ -0,0 +1,188 @@
+//! A quick performance self-test, run at startup with `--self-test`. It times
+//! the interpreter and the JIT on a small fixed loop, so that bug reports can
+//! include a rough baseline for the machine and build configuration they came
+//! from. The numbers are only comparable between runs of the same test.
+
+use crate::emulator::Core;
+use std::time::{Duration, Instant};
+
+/// Instructions run by the interpreter
+pub const INTERPRETER_OPS: usize = 10_000_000;
+/// Times the loop body is compiled from scratch
+pub const COMPILE_ITERATIONS: usize = 1_000;
+/// Times the compiled loop body is called
+pub const EXECUTE_ITERATIONS: usize = 1_000_000;
+
+/// Address of the loop body, which is also a complete block
+#[cfg(jit_backend)]
+const LOOP_START: u16 = 0x02;
+/// Instructions in the loop body
+const LOOP_OPS: usize = 5;
+
+fn create_core() -> Core {
+  let code = vec![
+    0x06, 0x00, // LD B, 0x00
+    0x3e, 0x00, // LD A, 0x00
+    0x80, // ADD A, B
+    0x3c, // INC A
+    0x05, // DEC B
+    0x20, 0xf9, // JR NZ, -7
+    0xc3, 0x00, 0x00, // JP 0x0000
+  ];
+  let mut core = Core::with_code_block(code.into_boxed_slice());
+  core.skip_idle_loops = false;
+  core
+}
+
+pub struct JitTimings {
+  pub compile: Duration,
+  pub execute: Duration,
+}
+
+pub struct PerfReport {
+  pub interpreter_ops: usize,
+  pub interpreter: Duration,
+  /// Only measured on architectures with a JIT backend
+  pub jit: Option<JitTimings>,
+  pub compile_iterations: usize,
+  pub execute_iterations: usize,
+}
+
+/// Interpret `ops` instructions, including the peripheral updates that
+/// follow each one
+pub fn time_interpreter(ops: usize) -> Duration {
+  let mut core = create_core();
+  let start = Instant::now();
+  for _ in 0..ops {
+    core.run_interp();
+  }
+  start.elapsed()
+}
+
+/// Time compiling the loop body `compile_iterations` times, and then calling
+/// it `execute_iterations` times without any peripheral updates
+#[cfg(jit_backend)]
+pub fn time_jit(compile_iterations: usize, execute_iterations: usize) -> JitTimings {
+  use crate::timing::MachineCycles;
+
+  let mut core = create_core();
+  core.cache.link_blocks = false;
+  let mem_ptr = core.memory.as_ptr();
+
+  let start = Instant::now();
+  let mut address = 0;
+  for _ in 0..compile_iterations {
+    core.cache.flush();
+    address = core.cache.translate_code_block(&core.memory.rom, LOOP_START as usize, mem_ptr).unwrap();
+  }
+  let compile = start.elapsed();
+
+  let start = Instant::now();
+  for _ in 0..execute_iterations {
+    core.registers.ip = LOOP_START as u32;
+    core.cache.call(address, &mut core.registers, &mut core.memory, MachineCycles(0));
+  }
+  let execute = start.elapsed();
+
+  JitTimings { compile, execute }
+}
+
+pub fn run_self_test() -> PerfReport {
+  run_with_counts(INTERPRETER_OPS, COMPILE_ITERATIONS, EXECUTE_ITERATIONS)
+}
+
+fn run_with_counts(interpreter_ops: usize, compile_iterations: usize, execute_iterations: usize) -> PerfReport {
+  let interpreter = time_interpreter(interpreter_ops);
+  #[cfg(jit_backend)]
+  let jit = Some(time_jit(compile_iterations, execute_iterations));
+  #[cfg(not(jit_backend))]
+  let jit = None;
+  PerfReport {
+    interpreter_ops,
+    interpreter,
+    jit,
+    compile_iterations,
+    execute_iterations,
+  }
+}
+
+/// Describes the build, since debug builds and optional features change the
+/// results dramatically
+pub fn build_configuration() -> String {
+  let features: Vec<&str> = [
+    ("jit", cfg!(feature = "jit")),
+    ("optimizer", cfg!(feature = "optimizer")),
+    ("strict_io", cfg!(feature = "strict_io")),
+    ("debug_freeze", cfg!(feature = "debug_freeze")),
+    ("video", cfg!(feature = "video")),
+    ("timer-accurate", cfg!(feature = "timer-accurate")),
+    ("serial", cfg!(feature = "serial")),
+    ("shell-window", cfg!(feature = "shell-window")),
+  ]
+    .iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(name, _)| *name)
+    .collect();
+  let profile = if cfg!(debug_assertions) { "debug" } else { "release" };
+  let features = if features.is_empty() { String::from("none") } else { features.join(", ") };
+  format!("{} build for {}, features: {}", profile, std::env::consts::ARCH, features)
+}
+
+/// Millions of instructions per second
+fn mips(ops: usize, time: Duration) -> f64 {
+  ops as f64 / time.as_secs_f64().max(f64::EPSILON) / 1_000_000.0
+}
+
+impl std::fmt::Display for PerfReport {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    writeln!(f, "Self-test, {}", build_configuration())?;
+    writeln!(
+      f,
+      "  Interpreter: {} ops in {:.3}s ({:.1} MIPS)",
+      self.interpreter_ops,
+      self.interpreter.as_secs_f64(),
+      mips(self.interpreter_ops, self.interpreter),
+    )?;
+    match &self.jit {
+      Some(jit) => {
+        let per_block = jit.compile.as_secs_f64() * 1_000_000.0 / self.compile_iterations.max(1) as f64;
+        writeln!(
+          f,
+          "  JIT compile: {} blocks in {:.3}s ({:.1}us per block)",
+          self.compile_iterations,
+          jit.compile.as_secs_f64(),
+          per_block,
+        )?;
+        let ops = self.execute_iterations * LOOP_OPS;
+        writeln!(
+          f,
+          "  JIT execute: {} blocks in {:.3}s ({:.1} MIPS)",
+          self.execute_iterations,
+          jit.execute.as_secs_f64(),
+          mips(ops, jit.execute),
+        )
+      },
+      None => writeln!(f, "  JIT: not available on this architecture"),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::run_with_counts;
+
+  #[test]
+  fn short_self_test() {
+    let report = run_with_counts(1000, 2, 100);
+    let text = report.to_string();
+    let lines: Vec<&str> = text.lines().collect();
+    assert!(lines[0].starts_with("Self-test, "));
+    assert!(lines[1].starts_with("  Interpreter: 1000 ops in "));
+    if cfg!(jit_backend) {
+      assert!(lines[2].starts_with("  JIT compile: 2 blocks in "));
+      assert!(lines[3].starts_with("  JIT execute: 100 blocks in "));
+    } else {
+      assert_eq!(lines[2], "  JIT: not available on this architecture");
+    }
+  }
+}
@@ -0,0 +1,99 @@
+//! Reports on the compiled blocks that use the most cycles, from the counts
+//! the code cache keeps while profiling. Each block is listed with its
+//! instructions, to show where a game spends its time and which blocks are
+//! worth optimizing harder.
+
+use crate::cache::blocks::MemoryLocation;
+use crate::emulator::Core;
+use super::disassembly::{disassemble_bank, disassemble_memory, Instruction};
+
+/// Blocks listed when no count is asked for
+pub const DEFAULT_REPORT_BLOCKS: usize = 20;
+
+/// The instructions in a block. ROM blocks are read from the bank they were
+/// compiled from, but blocks in RAM can only be read as they are now, which
+/// may not be the code that ran.
+fn block_instructions(core: &Core, location: &MemoryLocation, length: usize) -> Vec<Instruction> {
+  let address = location.address;
+  if address >= 0x8000 {
+    let end = address as usize + length;
+    return disassemble_memory(&core.memory, address)
+      .take_while(|instruction| (instruction.address() as usize) < end)
+      .collect();
+  }
+  let bank = location.bank as usize;
+  let start = bank * 0x4000 + (address as usize & 0x3fff);
+  match core.memory.rom.get(start..start + length) {
+    Some(code) => disassemble_bank(Some(bank), address, code),
+    None => Vec::new(),
+  }
+}
+
+/// The `count` blocks that took the most cycles, with their instructions
+pub fn profile_report(core: &Core, count: usize) -> String {
+  let profile = match core.cache.get_profile() {
+    Some(profile) => profile,
+    None => return String::from("Block profiling is off"),
+  };
+  let total = profile.total_cycles();
+  let mut report = format!(
+    "{} cycles in {} runs of compiled blocks\n",
+    total,
+    profile.total_runs(),
+  );
+  for (rank, (location, counts)) in profile.hottest().iter().take(count).enumerate() {
+    let share = counts.cycles as f64 * 100.0 / total.max(1) as f64;
+    // banks are only shown for ROM, like in the disassembly
+    let address = match location.address {
+      0x0000..=0x7fff => format!("{:02X}:{:04X}", location.bank, location.address),
+      address => format!("{:#06X}", address),
+    };
+    report.push_str(&format!(
+      "{:>3}. {:<7} {:>10} cycles {:>5.1}%  {:>9} runs\n",
+      rank + 1,
+      address,
+      counts.cycles,
+      share,
+      counts.runs,
+    ));
+    for instruction in block_instructions(core, location, counts.length) {
+      report.push_str(&format!("       {}\n", instruction));
+    }
+  }
+  report
+}
+
+#[cfg(test)]
+mod tests {
+  use super::profile_report;
+  use crate::emulator::Core;
+
+  #[test]
+  fn hottest_blocks() {
+    let code = vec![
+      0x3e, 0x05, // LD A, 5
+      0x3d, // DEC A
+      0x20, 0xfd, // JR NZ, -3
+      0x18, 0xf9, // JR -7
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    if core.set_jit_enabled(true).is_err() {
+      return;
+    }
+    assert_eq!(profile_report(&core, 1), "Block profiling is off");
+    core.skip_idle_loops = false;
+    core.cache.set_profiling(true);
+    for _ in 0..100 {
+      core.run_code_block();
+    }
+    let report = profile_report(&core, 1);
+    let lines: Vec<&str> = report.lines().collect();
+    // the inner loop runs four times for every pass through the outer one
+    assert!(lines[1].starts_with("  1. 00:0002"), "{}", report);
+    assert!(lines[2].contains("DEC A"), "{}", report);
+    assert!(lines[3].contains("JR NZ"), "{}", report);
+    assert_eq!(lines.len(), 4);
+    let profile = core.cache.get_profile().unwrap();
+    assert_eq!(profile.total_runs(), 100);
+  }
+}
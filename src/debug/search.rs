@@ -0,0 +1,111 @@
+//! RAM search, for finding where a game keeps a value like a life counter.
+//! A search starts from a snapshot of every byte of work RAM and high RAM,
+//! and each filter keeps only the addresses whose values pass it, comparing
+//! them either to a fixed value or to the snapshot taken by the previous
+//! filter. Once a few addresses remain, they can be turned into GameShark
+//! codes or watched.
+
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SearchFilter {
+  /// The value is exactly this
+  Equal(u8),
+  /// The value is anything but this
+  NotEqual(u8),
+  /// The value went up since the previous snapshot
+  Greater,
+  /// The value went down since the previous snapshot
+  Less,
+  Changed,
+  Unchanged,
+  /// The value changed by exactly this amount since the previous snapshot,
+  /// wrapping around like an 8-bit counter
+  ChangedBy(i16),
+}
+
+impl SearchFilter {
+  pub fn matches(&self, previous: u8, current: u8) -> bool {
+    match self {
+      SearchFilter::Equal(value) => current == *value,
+      SearchFilter::NotEqual(value) => current != *value,
+      SearchFilter::Greater => current > previous,
+      SearchFilter::Less => current < previous,
+      SearchFilter::Changed => current != previous,
+      SearchFilter::Unchanged => current == previous,
+      SearchFilter::ChangedBy(delta) => current == previous.wrapping_add(*delta as u8),
+    }
+  }
+}
+
+impl fmt::Display for SearchFilter {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      SearchFilter::Equal(value) => write!(f, "equal to {:02X}", value),
+      SearchFilter::NotEqual(value) => write!(f, "not equal to {:02X}", value),
+      SearchFilter::Greater => write!(f, "greater"),
+      SearchFilter::Less => write!(f, "less"),
+      SearchFilter::Changed => write!(f, "changed"),
+      SearchFilter::Unchanged => write!(f, "unchanged"),
+      SearchFilter::ChangedBy(delta) => write!(f, "changed by {}", delta),
+    }
+  }
+}
+
+/// An address still in the running, and its value in the latest snapshot
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SearchResult {
+  pub address: u16,
+  pub value: u8,
+}
+
+pub struct RamSearch {
+  candidates: Vec<SearchResult>,
+}
+
+impl RamSearch {
+  /// Start a search from a snapshot of every searchable address
+  pub fn new<I: IntoIterator<Item = SearchResult>>(snapshot: I) -> Self {
+    Self {
+      candidates: snapshot.into_iter().collect(),
+    }
+  }
+
+  /// Keep the addresses whose value passes the filter, given a way to read
+  /// their current values, and remember those values for the next filter.
+  /// Returns the number of addresses left.
+  pub fn filter<F: Fn(u16) -> u8>(&mut self, filter: SearchFilter, read: F) -> usize {
+    self.candidates.retain_mut(|candidate| {
+      let current = read(candidate.address);
+      let keep = filter.matches(candidate.value, current);
+      candidate.value = current;
+      keep
+    });
+    self.candidates.len()
+  }
+
+  pub fn results(&self) -> &[SearchResult] {
+    &self.candidates
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{RamSearch, SearchFilter, SearchResult};
+
+  #[test]
+  fn narrowing_down() {
+    let mut memory = [3u8, 3, 7, 3];
+    let snapshot = memory.iter().enumerate().map(|(i, value)| SearchResult { address: 0xc000 + i as u16, value: *value });
+    let mut search = RamSearch::new(snapshot);
+    assert_eq!(search.filter(SearchFilter::Equal(3), |address| memory[address as usize - 0xc000]), 3);
+    // one counter goes down, another wraps around
+    memory = [2, 3, 7, 0xff];
+    assert_eq!(search.filter(SearchFilter::Changed, |address| memory[address as usize - 0xc000]), 2);
+    memory = [1, 3, 7, 0xfd];
+    assert_eq!(search.filter(SearchFilter::ChangedBy(-2), |address| memory[address as usize - 0xc000]), 1);
+    assert_eq!(search.results(), &[SearchResult { address: 0xc003, value: 0xfd }]);
+    memory[3] = 0xfe;
+    assert_eq!(search.filter(SearchFilter::Less, |address| memory[address as usize - 0xc000]), 0);
+  }
+}
@@ -0,0 +1,190 @@
+//! Stack diagnostics, for catching games (or emulator regressions) that let
+//! the stack pointer run somewhere it shouldn't. A warning is raised when SP
+//! moves so that the next push lands in ROM or the IO registers, and when a
+//! push or pop wraps around the end of the address space. Wrapping below
+//! 0x0000 writes to IE at 0xffff, which silently changes which interrupts are
+//! enabled.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Number of recent stack pointer changes kept with each warning
+pub const TRAJECTORY_LENGTH: usize = 16;
+/// Once this many warnings have been raised, further ones are only counted
+pub const MAX_WARNINGS: usize = 64;
+
+/// A single step never moves SP further than an instruction and an interrupt
+/// dispatch could, so larger jumps across the end of memory aren't wraps
+const MAX_STEP_DISTANCE: u16 = 4;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StackProblem {
+  /// Pushes would be written to 0x0000-0x7fff
+  EnteredRom,
+  /// Pushes would be written to 0xff00-0xff7f
+  EnteredIo,
+  /// A push moved SP below 0x0000
+  Overflow,
+  /// A pop moved SP past 0xffff
+  Underflow,
+}
+
+impl StackProblem {
+  /// The region that the next push will be written to, if it's a problem
+  fn for_push_address(sp: u16) -> Option<Self> {
+    match sp.wrapping_sub(1) {
+      0x0000..=0x7fff => Some(StackProblem::EnteredRom),
+      0xff00..=0xff7f => Some(StackProblem::EnteredIo),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StackStep {
+  /// The instruction that moved the stack pointer
+  pub ip: u16,
+  pub sp: u16,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StackWarning {
+  pub problem: StackProblem,
+  pub ip: u16,
+  pub sp: u16,
+  /// Recent changes to SP, oldest first, ending with the one that caused the
+  /// warning
+  pub trajectory: Vec<StackStep>,
+}
+
+impl fmt::Display for StackWarning {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let description = match self.problem {
+      StackProblem::EnteredRom => "Stack pointer moved into ROM",
+      StackProblem::EnteredIo => "Stack pointer moved into IO registers",
+      StackProblem::Overflow => "Stack overflow wrapped past 0000",
+      StackProblem::Underflow => "Stack underflow wrapped past FFFF",
+    };
+    write!(f, "{} at PC:{:04X}, SP:{:04X}", description, self.ip, self.sp)?;
+    for step in self.trajectory.iter() {
+      write!(f, "\n  PC:{:04X} SP:{:04X}", step.ip, step.sp)?;
+    }
+    Ok(())
+  }
+}
+
+pub struct StackMonitor {
+  last_sp: u16,
+  trajectory: VecDeque<StackStep>,
+  warnings: Vec<StackWarning>,
+  suppressed: usize,
+}
+
+impl StackMonitor {
+  pub fn new(sp: u16) -> Self {
+    Self {
+      last_sp: sp,
+      trajectory: VecDeque::with_capacity(TRAJECTORY_LENGTH),
+      warnings: Vec::new(),
+      suppressed: 0,
+    }
+  }
+
+  /// Check the stack pointer after the instruction at `ip` has run. When SP
+  /// was loaded directly, rather than moved by pushes and pops, it can't have
+  /// wrapped. Returns the new warning, if one was raised.
+  pub fn check(&mut self, ip: u16, sp: u16, loaded: bool) -> Option<&StackWarning> {
+    let last_sp = self.last_sp;
+    if sp == last_sp {
+      return None;
+    }
+    self.last_sp = sp;
+    if self.trajectory.len() == TRAJECTORY_LENGTH {
+      self.trajectory.pop_front();
+    }
+    self.trajectory.push_back(StackStep { ip, sp });
+
+    let moved_down = last_sp.wrapping_sub(sp);
+    let moved_up = sp.wrapping_sub(last_sp);
+    let problem = if !loaded && moved_down <= MAX_STEP_DISTANCE && sp > last_sp {
+      Some(StackProblem::Overflow)
+    } else if !loaded && moved_up <= MAX_STEP_DISTANCE && sp < last_sp {
+      Some(StackProblem::Underflow)
+    } else {
+      let region = StackProblem::for_push_address(sp);
+      if region != StackProblem::for_push_address(last_sp) {
+        region
+      } else {
+        None
+      }
+    }?;
+
+    if self.warnings.len() == MAX_WARNINGS {
+      self.suppressed += 1;
+      return None;
+    }
+    self.warnings.push(StackWarning {
+      problem,
+      ip,
+      sp,
+      trajectory: self.trajectory.iter().copied().collect(),
+    });
+    self.warnings.last()
+  }
+
+  pub fn warnings(&self) -> &[StackWarning] {
+    &self.warnings
+  }
+
+  /// Number of warnings dropped after reaching `MAX_WARNINGS`
+  pub fn suppressed(&self) -> usize {
+    self.suppressed
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{StackMonitor, StackProblem, StackStep, MAX_WARNINGS};
+
+  #[test]
+  fn regions() {
+    let mut monitor = StackMonitor::new(0xfffe);
+    assert!(monitor.check(0x100, 0xfffc, false).is_none());
+    assert!(monitor.check(0x100, 0xc000, true).is_none());
+    let warning = monitor.check(0x103, 0x8000, true).unwrap();
+    assert_eq!(warning.problem, StackProblem::EnteredRom);
+    // staying in the same region doesn't warn again
+    assert!(monitor.check(0x106, 0x7ffe, false).is_none());
+    assert_eq!(monitor.check(0x107, 0xff80, true).unwrap().problem, StackProblem::EnteredIo);
+    // an empty stack at 0x0000 pushes to the top of memory
+    assert!(monitor.check(0x10a, 0x0000, true).is_none());
+  }
+
+  #[test]
+  fn wrapping() {
+    let mut monitor = StackMonitor::new(0x0001);
+    let warning = monitor.check(0x150, 0xffff, false).unwrap();
+    assert_eq!(warning.problem, StackProblem::Overflow);
+    assert_eq!(warning.trajectory, vec![StackStep { ip: 0x150, sp: 0xffff }]);
+    assert_eq!(monitor.check(0x151, 0x0001, false).unwrap().problem, StackProblem::Underflow);
+    // loading SP directly isn't a wrap, though the next push lands in ROM
+    assert!(monitor.check(0x152, 0xfffe, true).is_none());
+    assert_eq!(monitor.check(0x155, 0x0001, true).unwrap().problem, StackProblem::EnteredRom);
+  }
+
+  #[test]
+  fn trajectory_and_limit() {
+    let mut monitor = StackMonitor::new(0x0001);
+    for i in 0..(MAX_WARNINGS / 2 + 3) {
+      let ip = i as u16;
+      monitor.check(ip, 0xffff, false);
+      monitor.check(ip, 0x0001, false);
+    }
+    assert_eq!(monitor.warnings().len(), MAX_WARNINGS);
+    assert_eq!(monitor.suppressed(), 6);
+    let trajectory = &monitor.warnings()[21].trajectory;
+    assert_eq!(trajectory.len(), 16);
+    assert_eq!(trajectory[0], StackStep { ip: 3, sp: 0xffff });
+    assert_eq!(trajectory[15], StackStep { ip: 10, sp: 0x0001 });
+  }
+}
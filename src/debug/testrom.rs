@@ -0,0 +1,233 @@
+//! Runs the common hardware test ROMs to completion and reports whether they
+//! passed. Two conventions are recognized:
+//!  - Blargg's tests print their results over the serial port, ending with
+//!    "Passed" or "Failed".
+//!  - Mooneye-gb's tests execute `LD B, B` when they finish. On success, the
+//!    registers B through L hold the Fibonacci numbers 3, 5, 8, 13, 21, 34.
+//!    On failure, they all hold 0x42.
+
+use crate::devices::link::LinkPeer;
+use crate::emulator::{BreakEvent, Core};
+use std::sync::{Arc, Mutex};
+
+/// Machine cycles in one second of emulated time
+pub const CYCLES_PER_SECOND: u64 = 1_048_576;
+/// Long enough for the full cpu_instrs suite, which is the slowest of them
+pub const DEFAULT_TIMEOUT_SECONDS: u64 = 120;
+
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAIL: [u8; 6] = [0x42; 6];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TestResult {
+  Passed,
+  /// Contains the test's serial output, if it printed anything
+  Failed(String),
+  /// The test neither passed nor failed within the time limit
+  TimedOut(String),
+}
+
+impl std::fmt::Display for TestResult {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      TestResult::Passed => write!(f, "Passed"),
+      TestResult::Failed(output) => write!(f, "Failed\n{}", output),
+      TestResult::TimedOut(output) => write!(f, "Timed out\n{}", output),
+    }
+  }
+}
+
+/// Keeps every byte sent over the serial port, replying as if nothing was
+/// connected
+pub struct SerialCapture {
+  output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl SerialCapture {
+  /// Returns the peer, and a handle for reading what it has captured
+  pub fn new() -> (Self, Arc<Mutex<Vec<u8>>>) {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    (Self { output: output.clone() }, output)
+  }
+}
+
+impl LinkPeer for SerialCapture {
+  fn exchange(&mut self, _timestamp: u64, outgoing: u8) -> u8 {
+    self.output.lock().unwrap().push(outgoing);
+    0xff
+  }
+
+  fn poll_external(&mut self, _timestamp: u64, _outgoing: u8) -> Option<u8> {
+    None
+  }
+}
+
+/// Checks the registers at an `LD B, B` against the mooneye-gb convention
+fn mooneye_result(core: &Core) -> Option<bool> {
+  let registers = &core.registers;
+  let [b, c] = (registers.get_bc() as u16).to_be_bytes();
+  let [d, e] = (registers.get_de() as u16).to_be_bytes();
+  let [h, l] = (registers.get_hl() as u16).to_be_bytes();
+  let values = [b, c, d, e, h, l];
+  if values == MOONEYE_PASS {
+    Some(true)
+  } else if values == MOONEYE_FAIL {
+    Some(false)
+  } else {
+    None
+  }
+}
+
+/// Checks serial output against the blargg convention. The result only
+/// counts once its line is complete, since failures are followed by details.
+fn blargg_result(output: &str) -> Option<bool> {
+  let line_complete = |keyword: &str| match output.find(keyword) {
+    Some(position) => output[position..].contains('\n'),
+    None => false,
+  };
+  if line_complete("Passed") {
+    Some(true)
+  } else if line_complete("Failed") {
+    Some(false)
+  } else {
+    None
+  }
+}
+
+/// Run a test ROM until it reports a result, or until `timeout_seconds` of
+/// emulated time have passed. Its serial output is captured rather than
+/// printed. In a `strict_io` build, a run that touched any register the
+/// emulator doesn't model fails, with a summary of those accesses.
+pub fn run_test_rom(core: &mut Core, timeout_seconds: u64) -> TestResult {
+  let result = run_until_result(core, timeout_seconds);
+  #[cfg(feature = "strict_io")]
+  if let Err(summary) = core.memory.strict_io.check() {
+    return match result {
+      TestResult::Passed => TestResult::Failed(summary),
+      TestResult::Failed(output) => TestResult::Failed(format!("{}\n{}", output, summary)),
+      TestResult::TimedOut(output) => TestResult::TimedOut(format!("{}\n{}", output, summary)),
+    };
+  }
+  result
+}
+
+fn run_until_result(core: &mut Core, timeout_seconds: u64) -> TestResult {
+  let (capture, output) = SerialCapture::new();
+  // without a serial port, only the mooneye-gb convention can be detected
+  if let Some(serial) = core.memory.io.serial_port() {
+    serial.connect(Box::new(capture));
+  }
+  core.software_breakpoints = true;
+
+  let cycle_limit = core.cycles_elapsed() + timeout_seconds * CYCLES_PER_SECOND;
+  let mut checked_length = 0;
+  while core.cycles_elapsed() < cycle_limit {
+    if let Some(BreakEvent::SoftwareBreakpoint { .. }) = core.update() {
+      // other uses of LD B, B are skipped over by the next update
+      match mooneye_result(core) {
+        Some(true) => return TestResult::Passed,
+        Some(false) => return TestResult::Failed(serial_text(&output)),
+        None => (),
+      }
+    }
+    // the output only needs to be checked when it grows
+    let length = output.lock().unwrap().len();
+    if length != checked_length {
+      checked_length = length;
+      match blargg_result(&serial_text(&output)) {
+        Some(true) => return TestResult::Passed,
+        Some(false) => return TestResult::Failed(serial_text(&output)),
+        None => (),
+      }
+    }
+  }
+  TestResult::TimedOut(serial_text(&output))
+}
+
+fn serial_text(output: &Arc<Mutex<Vec<u8>>>) -> String {
+  String::from_utf8_lossy(&output.lock().unwrap()).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{run_test_rom, TestResult};
+  use crate::emulator::Core;
+
+  /// Every test runs once with the interpreter, and again with the JIT where
+  /// it's available
+  fn run_both_engines(code: &[u8], expected: TestResult) {
+    let mut interpreted = Core::with_code_block(code.to_vec().into_boxed_slice());
+    interpreted.set_jit_enabled(false).unwrap();
+    assert_eq!(run_test_rom(&mut interpreted, 1), expected);
+    let mut compiled = Core::with_code_block(code.to_vec().into_boxed_slice());
+    if compiled.set_jit_enabled(true).is_ok() {
+      assert_eq!(run_test_rom(&mut compiled, 1), expected);
+    }
+  }
+
+  /// Prints "Passed" or "Failed" over serial, the way blargg's tests do
+  fn serial_program(message: &[u8]) -> Vec<u8> {
+    let mut code = vec![
+      0x21, 0x20, 0x00, // LD HL, 0x0020
+      0x2a, // LD A, (HL+)
+      0xb7, // OR A
+      0x28, 0x09, // JR Z, +9
+      0xe0, 0x01, // LDH (0x01), A
+      0x3e, 0x81, // LD A, 0x81
+      0xe0, 0x02, // LDH (0x02), A
+      0xc3, 0x03, 0x00, // JP 0x0003
+      0x18, 0xfe, // JR -2
+    ];
+    code.resize(0x20, 0);
+    code.extend_from_slice(message);
+    code.push(0);
+    code
+  }
+
+  /// Loads the registers, then runs LD B, B, the way mooneye-gb tests do
+  fn breakpoint_program(b: u8, c: u8, d: u8, e: u8, h: u8, l: u8) -> Vec<u8> {
+    vec![
+      0x40, // LD B, B
+      0x06, b, // LD B, b
+      0x0e, c, // LD C, c
+      0x16, d, // LD D, d
+      0x1e, e, // LD E, e
+      0x26, h, // LD H, h
+      0x2e, l, // LD L, l
+      0x00, // NOP
+      0x40, // LD B, B
+      0x18, 0xfe, // JR -2
+    ]
+  }
+
+  #[cfg(feature = "serial")]
+  #[test]
+  fn serial_pass_and_fail() {
+    run_both_engines(&serial_program(b"Passed\n"), TestResult::Passed);
+    run_both_engines(&serial_program(b"Failed #3\n"), TestResult::Failed(String::from("Failed #3\n")));
+  }
+
+  #[test]
+  fn breakpoint_pass_and_fail() {
+    run_both_engines(&breakpoint_program(3, 5, 8, 13, 21, 34), TestResult::Passed);
+    run_both_engines(&breakpoint_program(0x42, 0x42, 0x42, 0x42, 0x42, 0x42), TestResult::Failed(String::new()));
+  }
+
+  #[cfg(feature = "strict_io")]
+  #[test]
+  fn unmodeled_access_fails() {
+    // LDH A, (0x26) reads the sound controller, before the test passes
+    let mut code = vec![0xf0, 0x26];
+    code.extend_from_slice(&breakpoint_program(3, 5, 8, 13, 21, 34));
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    match run_test_rom(&mut core, 1) {
+      TestResult::Failed(summary) => assert!(summary.contains("0xFF26"), "{}", summary),
+      result => panic!("Expected a failure, got {}", result),
+    }
+  }
+
+  #[test]
+  fn time_out() {
+    run_both_engines(&[0x18, 0xfe], TestResult::TimedOut(String::new()));
+  }
+}
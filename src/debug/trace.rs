@@ -0,0 +1,240 @@
+//! Execution trace, logging instructions as they run along with the register
+//! state before each one. Traces can be written to a file for diffing against
+//! other emulators, or kept in a ring buffer of the most recent entries.
+//!
+//! Tracing every instruction requires the interpreter, so while a trace is
+//! active, all code is interpreted unless the trace is limited to blocks. In
+//! that case, compiled blocks run as usual and only their first instruction
+//! is logged.
+//!
+//! Besides the default human-readable format, traces can be written in the
+//! format used by Gameboy Doctor, so they can be compared line by line with
+//! its reference logs. Those logs are made with LY fixed at 0x90, and they
+//! start from the state the boot ROM leaves behind, so they only line up when
+//! running without a boot ROM.
+
+use super::disassembly::disassemble_one;
+use super::history::Engine;
+use crate::cpu::Registers;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// Number of entries kept by a ring buffer trace, unless configured
+pub const DEFAULT_RING_LENGTH: usize = 4096;
+
+#[derive(Copy, Clone)]
+pub struct TraceEntry {
+  pub address: u16,
+  /// ROM bank the instruction was run from, if it is in ROM
+  pub bank: Option<usize>,
+  /// Bytes starting at the instruction, enough to decode it
+  pub bytes: [u8; 4],
+  /// Registers before the instruction ran
+  pub registers: Registers,
+  /// Machine cycles elapsed before the instruction ran
+  pub cycles: u64,
+  /// Compiled entries mark the start of a block that ran as a whole
+  pub engine: Engine,
+}
+
+impl std::fmt::Display for TraceEntry {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let instruction = disassemble_one(self.bank, self.address, &self.bytes[..3]);
+    let registers = &self.registers;
+    write!(
+      f,
+      "{:<40} AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} CY={}",
+      instruction.to_string(),
+      registers.get_af(),
+      registers.get_bc(),
+      registers.get_de(),
+      registers.get_hl(),
+      registers.get_sp(),
+      self.cycles,
+    )?;
+    if self.engine == Engine::Compiled {
+      write!(f, " [JIT]")?;
+    }
+    Ok(())
+  }
+}
+
+impl TraceEntry {
+  /// Format the entry the way Gameboy Doctor expects, like
+  /// `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01`
+  pub fn to_doctor_line(&self) -> String {
+    let registers = &self.registers;
+    let [a, f] = (registers.get_af() as u16).to_be_bytes();
+    let [b, c] = (registers.get_bc() as u16).to_be_bytes();
+    let [d, e] = (registers.get_de() as u16).to_be_bytes();
+    let [h, l] = (registers.get_hl() as u16).to_be_bytes();
+    format!(
+      "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+      a, f, b, c, d, e, h, l,
+      registers.get_sp(),
+      self.address,
+      self.bytes[0], self.bytes[1], self.bytes[2], self.bytes[3],
+    )
+  }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TraceFormat {
+  /// Disassembly, registers, and cycle count
+  Default,
+  /// CPU state only, matching Gameboy Doctor logs
+  Doctor,
+}
+
+impl TraceFormat {
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "default" => Some(TraceFormat::Default),
+      "doctor" => Some(TraceFormat::Doctor),
+      _ => None,
+    }
+  }
+
+  pub fn format(&self, entry: &TraceEntry) -> String {
+    match self {
+      TraceFormat::Default => entry.to_string(),
+      TraceFormat::Doctor => entry.to_doctor_line(),
+    }
+  }
+}
+
+enum TraceOutput {
+  Ring(VecDeque<TraceEntry>, usize),
+  File(BufWriter<File>),
+}
+
+pub struct Tracer {
+  output: TraceOutput,
+  /// When set, only instructions in this inclusive range are logged
+  range: Option<(u16, u16)>,
+  /// When set, compiled code is allowed to run, and only the start of each
+  /// block is logged
+  pub blocks_only: bool,
+  /// How entries are written to a file
+  pub format: TraceFormat,
+}
+
+impl Tracer {
+  /// Keep the most recent `length` entries in memory
+  pub fn ring(length: usize) -> Self {
+    Self {
+      output: TraceOutput::Ring(VecDeque::with_capacity(length), length),
+      range: None,
+      blocks_only: false,
+      format: TraceFormat::Default,
+    }
+  }
+
+  /// Write every entry to a file, one per line
+  pub fn to_file(name: &str) -> Result<Self, String> {
+    let file = File::create(name).map_err(|_| format!("Unable to create trace file \"{}\"", name))?;
+    Ok(Self {
+      output: TraceOutput::File(BufWriter::new(file)),
+      range: None,
+      blocks_only: false,
+      format: TraceFormat::Default,
+    })
+  }
+
+  /// Only log instructions between `start` and `end`, inclusive
+  pub fn set_range(&mut self, start: u16, end: u16) {
+    self.range = Some((start.min(end), start.max(end)));
+  }
+
+  pub fn includes(&self, address: u16) -> bool {
+    match self.range {
+      Some((start, end)) => address >= start && address <= end,
+      None => true,
+    }
+  }
+
+  pub fn record(&mut self, entry: TraceEntry) {
+    if !self.includes(entry.address) {
+      return;
+    }
+    match &mut self.output {
+      TraceOutput::Ring(entries, length) => {
+        if entries.len() == *length {
+          entries.pop_front();
+        }
+        entries.push_back(entry);
+      },
+      TraceOutput::File(writer) => {
+        // a trace is best-effort, and shouldn't stop emulation if the disk
+        // fills up
+        let _ = writeln!(writer, "{}", self.format.format(&entry));
+      },
+    }
+  }
+
+  /// Returns the entries held by a ring buffer, oldest first. A trace written
+  /// to a file holds no entries.
+  pub fn entries(&self) -> Vec<TraceEntry> {
+    match &self.output {
+      TraceOutput::Ring(entries, _) => entries.iter().copied().collect(),
+      TraceOutput::File(_) => Vec::new(),
+    }
+  }
+
+  pub fn flush(&mut self) {
+    if let TraceOutput::File(writer) = &mut self.output {
+      let _ = writer.flush();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{TraceEntry, TraceFormat, Tracer};
+  use crate::cpu::Registers;
+  use crate::debug::history::Engine;
+
+  fn entry(address: u16) -> TraceEntry {
+    TraceEntry {
+      address,
+      bank: Some(0),
+      bytes: [0x3e, 0x12, 0x00, 0xc3],
+      registers: Registers::new(),
+      cycles: 20,
+      engine: Engine::Interpreted,
+    }
+  }
+
+  #[test]
+  fn ring_keeps_recent_entries() {
+    let mut tracer = Tracer::ring(2);
+    tracer.set_range(0x0200, 0x0100);
+    for address in [0x0050, 0x0100, 0x0150, 0x0200, 0x0250] {
+      tracer.record(entry(address));
+    }
+    let addresses: Vec<u16> = tracer.entries().iter().map(|e| e.address).collect();
+    assert_eq!(addresses, vec![0x0150, 0x0200]);
+  }
+
+  #[test]
+  fn format_entry() {
+    let line = entry(0x0150).to_string();
+    assert!(line.starts_with("00:0150  3E 12"));
+    assert!(line.ends_with("AF=0000 BC=0000 DE=0000 HL=0000 SP=0000 CY=20"));
+    let mut compiled = entry(0x0150);
+    compiled.engine = Engine::Compiled;
+    assert!(compiled.to_string().ends_with("CY=20 [JIT]"));
+  }
+
+  #[test]
+  fn doctor_format() {
+    let mut doctor = entry(0x0100);
+    doctor.registers = Registers::after_boot();
+    assert_eq!(
+      TraceFormat::Doctor.format(&doctor),
+      "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:3E,12,00,C3",
+    );
+    assert_eq!(TraceFormat::from_name("doctor"), Some(TraceFormat::Doctor));
+  }
+}
@@ -0,0 +1,532 @@
+//! Interactive terminal debugger. While the prompt is shown, the core is
+//! paused, and the registers and the code around the instruction pointer are
+//! printed after every command that runs the CPU. Code is always interpreted
+//! one instruction at a time, so breakpoints stop exactly where they are set.
+//! Breakpoints and watchpoints are stored on the core, and remain set after
+//! the debugger exits.
+
+use super::command::{parse_command, Command};
+use super::disassembly::{disassemble_memory, Instruction};
+use super::io_registers::{find_register, poke_register, read_all_registers, read_register};
+use crate::decoder::{self, ops::Op};
+use crate::emulator::{BreakEvent, Core, RunState};
+use crate::mem::{memory_peek_byte, OPEN_BUS};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Number of instructions listed starting at the instruction pointer
+const LISTING_LENGTH: usize = 6;
+/// Number of previously executed instructions listed above the current one
+const TRAIL_LENGTH: usize = 3;
+
+/// Most RAM search results listed at once
+const SEARCH_LIST_LIMIT: usize = 32;
+/// Most blocked writes listed at once, starting from the latest
+#[cfg(feature = "debug_freeze")]
+const FREEZE_LOG_LIMIT: usize = 32;
+
+/// Set by Ctrl-C while the core is running, to pause it
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+pub struct Debugger {
+  /// Addresses of the most recently executed instructions, oldest first
+  trail: VecDeque<u16>,
+}
+
+impl Debugger {
+  pub fn new() -> Self {
+    Self {
+      trail: VecDeque::with_capacity(TRAIL_LENGTH),
+    }
+  }
+
+  /// Run a single command, and return the text to display
+  pub fn execute(&mut self, core: &mut Core, command: Command) -> String {
+    match command {
+      Command::BreakSet(breakpoint) => {
+        core.add_breakpoint(breakpoint);
+        format!("Breakpoint set at {}", breakpoint)
+      },
+      Command::BreakClear(breakpoint) => {
+        if core.remove_breakpoint(breakpoint) {
+          format!("Breakpoint cleared at {}", breakpoint)
+        } else {
+          format!("No breakpoint at {}", breakpoint)
+        }
+      },
+      Command::BreakList => {
+        if core.breakpoints().is_empty() {
+          return String::from("No breakpoints");
+        }
+        let list: Vec<String> = core.breakpoints().iter().map(|b| b.to_string()).collect();
+        list.join("\n")
+      },
+      Command::WatchSet(watchpoint) => {
+        core.add_watchpoint(watchpoint);
+        format!("Watchpoint set at {}", watchpoint)
+      },
+      Command::WatchClear(start, end) => {
+        let matching: Vec<_> = core.watchpoints().iter().filter(|w| w.start == start && w.end == end).copied().collect();
+        if matching.is_empty() {
+          return format!("No watchpoint at {:04X}", start);
+        }
+        for watchpoint in matching.iter() {
+          core.remove_watchpoint(*watchpoint);
+        }
+        let list: Vec<String> = matching.iter().map(|w| format!("Watchpoint cleared at {}", w)).collect();
+        list.join("\n")
+      },
+      Command::WatchList => {
+        if core.watchpoints().is_empty() {
+          return String::from("No watchpoints");
+        }
+        let list: Vec<String> = core.watchpoints().iter().map(|w| w.to_string()).collect();
+        list.join("\n")
+      },
+      Command::Continue => {
+        let reason = self.run_until(core, |_| false);
+        format!("{}\n{}", reason.unwrap_or_default(), self.view(core))
+      },
+      Command::Step => match self.step(core) {
+        Some(event) => format!("{}\n{}", event, self.view(core)),
+        None => self.view(core),
+      },
+      Command::StepOver => {
+        let ip = core.registers.ip as u16;
+        let (op, length, _) = decoder::decode(&fetch(core, ip));
+        match op {
+          Op::Call(_, _) | Op::ResetVector(_) => {
+            let return_address = ip.wrapping_add(length as u16);
+            let sp = core.registers.sp;
+            let reason = self.run_until(core, |core| {
+              core.registers.ip as u16 == return_address && { core.registers.sp } >= sp
+            });
+            match reason {
+              Some(reason) => format!("{}\n{}", reason, self.view(core)),
+              None => self.view(core),
+            }
+          },
+          _ => match self.step(core) {
+            Some(event) => format!("{}\n{}", event, self.view(core)),
+            None => self.view(core),
+          },
+        }
+      },
+      Command::ReadMemory(address) => format!("{:04X}: {:02X}", address, peek(core, address)),
+      Command::ReadMemoryRange(address, length) => hexdump(core, address, length),
+      Command::ReadRegisters => format_registers(core),
+      Command::FreezeAdd(_) | Command::FreezeList | Command::FreezeLog | Command::FreezeRemove(_) => freeze(core, command),
+      Command::SearchStart => format!("Searching {} addresses", core.start_ram_search()),
+      Command::SearchFilter(filter) => match core.filter_ram_search(filter) {
+        Ok(count) => format!("{} addresses {}", count, filter),
+        Err(message) => message,
+      },
+      Command::SearchList => search_results(core),
+      Command::IoList => {
+        let registers: Vec<String> = read_all_registers(core).iter().map(|value| value.to_string()).collect();
+        registers.join("\n")
+      },
+      Command::IoPoke(address, value) => match find_register(&address.to_string()) {
+        Some(register) => {
+          poke_register(core, register, value);
+          read_register(core, register).to_string()
+        },
+        None => format!("No IO register at {:04X}", address),
+      },
+      Command::Quit => String::new(),
+    }
+  }
+
+  /// Registers, followed by the most recent instructions and the ones about
+  /// to run
+  pub fn view(&self, core: &Core) -> String {
+    let mut view = format_registers(core);
+    view.push('\n');
+    for address in self.trail.iter() {
+      view.push_str(&format!("   {}\n", disassemble_at(core, *address)));
+    }
+    let listing = disassemble_memory(&core.memory, core.registers.ip as u16);
+    for (i, instruction) in listing.take(LISTING_LENGTH).enumerate() {
+      let marker = if i == 0 { "=> " } else { "   " };
+      view.push_str(&format!("{}{}\n", marker, instruction));
+    }
+    view
+  }
+
+  /// Run a single instruction. While the CPU is halted or stopped, only the
+  /// peripherals advance. Returns an event if a watchpoint was hit.
+  fn step(&mut self, core: &mut Core) -> Option<BreakEvent> {
+    if core.run_state == RunState::Run {
+      if self.trail.len() == TRAIL_LENGTH {
+        self.trail.pop_front();
+      }
+      self.trail.push_back(core.registers.ip as u16);
+    }
+    core.step_instruction()
+  }
+
+  /// Step until `done` returns true, a breakpoint or watchpoint is hit, or
+  /// the user presses Ctrl-C. Returns the reason for stopping early, if any.
+  fn run_until<F: Fn(&Core) -> bool>(&mut self, core: &mut Core, done: F) -> Option<String> {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    let _handler = InterruptHandler::install();
+    loop {
+      if let Some(event) = self.step(core) {
+        return Some(event.to_string());
+      }
+      if done(core) {
+        return None;
+      }
+      if let Some(event) = core.check_breakpoint() {
+        return Some(event.to_string());
+      }
+      if INTERRUPTED.swap(false, Ordering::SeqCst) {
+        return Some(String::from("Interrupted"));
+      }
+    }
+  }
+}
+
+impl Default for Debugger {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Catches Ctrl-C for as long as it is alive, instead of exiting
+struct InterruptHandler {
+  #[cfg(unix)]
+  previous: libc::sighandler_t,
+}
+
+#[cfg(unix)]
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+  INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+impl InterruptHandler {
+  #[cfg(unix)]
+  fn install() -> Self {
+    let handler = on_interrupt as extern "C" fn(libc::c_int);
+    let previous = unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+    Self { previous }
+  }
+
+  #[cfg(not(unix))]
+  fn install() -> Self {
+    Self {}
+  }
+}
+
+impl Drop for InterruptHandler {
+  fn drop(&mut self) {
+    #[cfg(unix)]
+    unsafe {
+      libc::signal(libc::SIGINT, self.previous);
+    }
+  }
+}
+
+/// Read memory for display. Unlike regular reads, cart RAM is shown even
+/// while the MBC has it disabled.
+fn peek(core: &Core, address: u16) -> u8 {
+  if (0xa000..0xc000).contains(&address) {
+    let offset = 0x2000 * core.memory.cart_state.get_ram_bank() + (address as usize & 0x1fff);
+    return core.memory.cart_ram.get(offset).copied().unwrap_or(OPEN_BUS);
+  }
+  memory_peek_byte(&core.memory, address)
+}
+
+/// Read enough bytes to decode the instruction the CPU would fetch from an
+/// address
+fn fetch(core: &Core, address: u16) -> [u8; 3] {
+  let mut bytes = [0; 3];
+  for (offset, byte) in bytes.iter_mut().enumerate() {
+    *byte = memory_peek_byte(&core.memory, address.wrapping_add(offset as u16));
+  }
+  bytes
+}
+
+/// Disassemble the instruction the CPU would fetch from an address
+fn disassemble_at(core: &Core, address: u16) -> Instruction {
+  // the listing never ends
+  disassemble_memory(&core.memory, address).next().unwrap()
+}
+
+pub fn format_registers(core: &Core) -> String {
+  let registers = &core.registers;
+  let flags = registers.get_af() as u8;
+  let flag = |mask: u8, name: char| if flags & mask != 0 { name } else { '-' };
+  let state = match core.run_state {
+    RunState::Run => "",
+    RunState::Halt => " HALTED",
+    RunState::Stop => " STOPPED",
+    RunState::Locked => " LOCKED",
+  };
+  format!(
+    "AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} IP={:04X} [{}{}{}{}]{}\nMBC: {}",
+    registers.get_af(),
+    registers.get_bc(),
+    registers.get_de(),
+    registers.get_hl(),
+    registers.get_sp(),
+    registers.get_ip(),
+    flag(0x80, 'Z'),
+    flag(0x40, 'N'),
+    flag(0x20, 'H'),
+    flag(0x10, 'C'),
+    state,
+    core.memory.get_mbc_state(),
+  )
+}
+
+/// Format memory as rows of 16 bytes, each followed by its printable ASCII
+pub fn hexdump(core: &Core, address: u16, length: usize) -> String {
+  let mut rows = Vec::new();
+  let mut offset = 0;
+  while offset < length {
+    let row_start = address.wrapping_add(offset as u16);
+    let row_length = (length - offset).min(16);
+    let bytes: Vec<u8> = (0..row_length).map(|i| peek(core, row_start.wrapping_add(i as u16))).collect();
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    let text: String = bytes
+      .iter()
+      .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' })
+      .collect();
+    rows.push(format!("{}: {:<47}  {}", banked_address(core, row_start), hex.join(" "), text));
+    offset += 16;
+  }
+  rows.join("\n")
+}
+
+/// Addresses in ROM and cartridge RAM are shown with the bank currently
+/// mapped there
+fn banked_address(core: &Core, address: u16) -> String {
+  let memory = &core.memory;
+  match address {
+    0x0000..=0x3fff => format!("{:02X}:{:04X}", memory.get_low_rom_bank(), address),
+    0x4000..=0x7fff => format!("{:02X}:{:04X}", memory.get_rom_bank(), address),
+    0xa000..=0xbfff => format!("{:02X}:{:04X}", memory.cart_state.get_ram_bank(), address),
+    _ => format!("{:04X}", address),
+  }
+}
+
+/// Addresses left in the RAM search, unless there are too many to be useful
+fn search_results(core: &Core) -> String {
+  let results = core.ram_search_results();
+  if results.is_empty() {
+    return String::from("No addresses found");
+  }
+  if results.len() > SEARCH_LIST_LIMIT {
+    return format!("{} addresses, narrow the search to list them", results.len());
+  }
+  let list: Vec<String> = results.iter().map(|result| format!("{:04X}: {:02X}", result.address, result.value)).collect();
+  list.join("\n")
+}
+
+#[cfg(feature = "debug_freeze")]
+fn freeze(core: &mut Core, command: Command) -> String {
+  let table = &mut core.memory.freeze;
+  match command {
+    Command::FreezeAdd(region) => {
+      table.add(region);
+      format!("Froze {}", region)
+    },
+    Command::FreezeRemove(region) => {
+      if table.remove(region) {
+        format!("Unfroze {}", region)
+      } else {
+        format!("{} is not frozen", region)
+      }
+    },
+    Command::FreezeLog => {
+      let log = table.get_log();
+      if log.is_empty() {
+        return String::from("No blocked writes");
+      }
+      let skipped = log.len().saturating_sub(FREEZE_LOG_LIMIT);
+      let mut list: Vec<String> = log.iter().skip(skipped).map(|write| write.to_string()).collect();
+      if skipped > 0 {
+        list.insert(0, format!("{} earlier writes not shown", skipped));
+      }
+      list.join("\n")
+    },
+    _ => {
+      let regions: Vec<String> = table.list().iter().map(|r| r.to_string()).collect();
+      if regions.is_empty() {
+        String::from("No frozen regions")
+      } else {
+        regions.join("\n")
+      }
+    },
+  }
+}
+
+#[cfg(not(feature = "debug_freeze"))]
+fn freeze(_core: &mut Core, _command: Command) -> String {
+  String::from("Freezing memory requires the debug_freeze feature")
+}
+
+/// Run the debugger on stdin and stdout until it is quit, or input ends
+pub fn run(core: &mut Core) {
+  let mut debugger = Debugger::new();
+  print!("{}", debugger.view(core));
+  let stdin = io::stdin();
+  let mut lines = stdin.lock().lines();
+  loop {
+    print!("(gbdb) ");
+    let _ = io::stdout().flush();
+    let line = match lines.next() {
+      Some(Ok(line)) => line,
+      _ => return,
+    };
+    if line.trim().is_empty() {
+      continue;
+    }
+    match parse_command(&line) {
+      Some(Command::Quit) => return,
+      Some(command) => {
+        let output = debugger.execute(core, command);
+        // warnings raised while the command ran come before its output
+        for message in core.take_messages() {
+          println!("{}", message);
+        }
+        println!("{}", output.trim_end());
+      },
+      None => println!("Unknown command \"{}\"", line.trim()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{hexdump, Debugger};
+  use crate::debug::breakpoint::Breakpoint;
+  use crate::debug::command::Command;
+  use crate::debug::search::SearchFilter;
+  use crate::debug::watchpoint::{WatchKind, Watchpoint};
+  use crate::emulator::Core;
+
+  fn create_core() -> Core {
+    let code = vec![
+      0x31, 0x00, 0xc1, // LD SP, 0xc100
+      0xcd, 0x0a, 0x00, // CALL 0x000a
+      0x3e, 0x05, // LD A, 0x05
+      0x18, 0xfe, // JR -2
+      0x06, 0x01, // LD B, 0x01
+      0x0e, 0x02, // LD C, 0x02
+      0xc9, // RET
+    ];
+    Core::with_code_block(code.into_boxed_slice())
+  }
+
+  #[test]
+  fn step_and_view() {
+    let mut core = create_core();
+    let mut debugger = Debugger::new();
+    let view = debugger.execute(&mut core, Command::Step);
+    assert_eq!(core.registers.get_ip(), 0x03);
+    let lines: Vec<&str> = view.lines().collect();
+    assert!(lines[0].starts_with("AF=0000 BC=0000 DE=0000 HL=0000 SP=C100 IP=0003"));
+    assert_eq!(lines[1], "MBC: ROM bank 01, RAM bank 00 (disabled), mode 0");
+    assert!(lines[2].starts_with("   00:0000"));
+    assert!(lines[3].starts_with("=> 00:0003"));
+    assert!(lines[3].ends_with("CALL 0x000A"));
+  }
+
+  #[test]
+  fn step_over_call() {
+    let mut core = create_core();
+    let mut debugger = Debugger::new();
+    debugger.execute(&mut core, Command::Step);
+    debugger.execute(&mut core, Command::StepOver);
+    assert_eq!(core.registers.get_ip(), 0x06);
+    assert_eq!(core.registers.get_bc(), 0x0102);
+    assert_eq!(core.registers.get_sp(), 0xc100);
+    // stepping over anything else is a regular step
+    debugger.execute(&mut core, Command::StepOver);
+    assert_eq!(core.registers.get_ip(), 0x08);
+  }
+
+  #[test]
+  fn continue_to_breakpoint() {
+    let mut core = create_core();
+    let mut debugger = Debugger::new();
+    debugger.execute(&mut core, Command::BreakSet(Breakpoint::new(0x0e)));
+    let output = debugger.execute(&mut core, Command::Continue);
+    assert!(output.starts_with("Breakpoint hit at 00:000E"));
+    assert_eq!(core.registers.get_ip(), 0x0e);
+    assert_eq!(core.registers.get_bc(), 0x0102);
+    debugger.execute(&mut core, Command::BreakClear(Breakpoint::new(0x0e)));
+    debugger.execute(&mut core, Command::BreakSet(Breakpoint::new(0x08)));
+    debugger.execute(&mut core, Command::Continue);
+    assert_eq!(core.registers.get_ip(), 0x08);
+    assert_eq!(debugger.execute(&mut core, Command::BreakList), "0008");
+  }
+
+  #[test]
+  fn step_over_stops_at_breakpoint() {
+    let mut core = create_core();
+    let mut debugger = Debugger::new();
+    debugger.execute(&mut core, Command::Step);
+    debugger.execute(&mut core, Command::BreakSet(Breakpoint::new(0x0c)));
+    let output = debugger.execute(&mut core, Command::StepOver);
+    assert!(output.starts_with("Breakpoint hit at 00:000C"));
+    assert_eq!(core.registers.get_ip(), 0x0c);
+  }
+
+  #[test]
+  fn memory_dump() {
+    let core = create_core();
+    assert_eq!(
+      hexdump(&core, 0x0000, 18),
+      format!("00:0000: 31 00 C1 CD 0A 00 3E 05 18 FE 06 01 0E 02 C9 76  1.....>........v\n00:0010: {:<47}  ..", "FF FF"),
+    );
+    assert!(hexdump(&core, 0x4000, 1).starts_with("01:4000: FF"));
+    assert!(hexdump(&core, 0xc000, 1).starts_with("C000: 00"));
+  }
+
+  #[test]
+  fn io_registers() {
+    let mut core = create_core();
+    let mut debugger = Debugger::new();
+    let output = debugger.execute(&mut core, Command::IoPoke(0xff06, 0x42));
+    assert_eq!(output, "FF06 TMA  = 42");
+    let output = debugger.execute(&mut core, Command::IoList);
+    assert!(output.lines().any(|line| line == "FF06 TMA  = 42"));
+    assert!(output.starts_with("FF00 P1"));
+  }
+
+  #[test]
+  fn continue_to_watchpoint() {
+    let mut core = create_core();
+    let mut debugger = Debugger::new();
+    debugger.execute(&mut core, Command::WatchSet(Watchpoint::range(0xc0fe, 0xc0ff, WatchKind::Write)));
+    // the CALL pushes its return address
+    let output = debugger.execute(&mut core, Command::Continue);
+    assert!(output.starts_with("Watchpoint hit: Write 00 at C0FF, by the instruction at 0003"));
+    assert_eq!(core.registers.get_ip(), 0x0a);
+    assert_eq!(debugger.execute(&mut core, Command::WatchList), "C0FE-C0FF (write)");
+    debugger.execute(&mut core, Command::WatchClear(0xc0fe, 0xc0ff));
+    assert_eq!(debugger.execute(&mut core, Command::WatchList), "No watchpoints");
+  }
+
+  #[test]
+  fn ram_search() {
+    let mut core = create_core();
+    let mut debugger = Debugger::new();
+    let output = debugger.execute(&mut core, Command::SearchFilter(SearchFilter::Changed));
+    assert_eq!(output, "No RAM search has been started");
+    assert_eq!(debugger.execute(&mut core, Command::SearchStart), "Searching 8319 addresses");
+    assert_eq!(debugger.execute(&mut core, Command::SearchList), "8319 addresses, narrow the search to list them");
+    // the CALL pushes its return address, 0x0006
+    debugger.execute(&mut core, Command::Step);
+    debugger.execute(&mut core, Command::Step);
+    let output = debugger.execute(&mut core, Command::SearchFilter(SearchFilter::Changed));
+    assert_eq!(output, "1 addresses changed");
+    assert_eq!(debugger.execute(&mut core, Command::SearchList), "C0FE: 06");
+    debugger.execute(&mut core, Command::SearchFilter(SearchFilter::Equal(7)));
+    assert_eq!(debugger.execute(&mut core, Command::SearchList), "No addresses found");
+  }
+}
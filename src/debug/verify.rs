@@ -0,0 +1,269 @@
+//! Lockstep verification of the dynamic recompiler against the interpreter.
+//! Each block is first run by the interpreter on a snapshot of the machine
+//! state, then the snapshot is restored and the same block is run by compiled
+//! code. Any difference in registers, status, or RAM contents means the
+//! emitter generated incorrect code for that block, and every difference is
+//! reported along with a disassembly of the block.
+//!
+//! Memory-mapped IO is not snapshotted, so peripherals observe every IO access
+//! twice. Blocks that poll or write IO registers may report false positives.
+
+use crate::cart::MbcState;
+use crate::cpu::Registers;
+use crate::emulator::Core;
+use crate::interpreter;
+use crate::mem::MemoryAreas;
+use crate::timing::MachineCycles;
+use std::fmt;
+
+pub struct MemorySnapshot {
+  video_ram: Box<[u8]>,
+  cart_ram: Box<[u8]>,
+  work_ram: Box<[u8]>,
+  oam_ram: Box<[u8]>,
+  high_ram: Box<[u8]>,
+  mbc_state: MbcState,
+}
+
+impl MemorySnapshot {
+  pub fn capture(mem: &MemoryAreas) -> Self {
+    Self {
+      video_ram: mem.video_ram.clone(),
+      cart_ram: mem.cart_ram.clone(),
+      work_ram: mem.work_ram.clone(),
+      oam_ram: mem.oam_ram.clone(),
+      high_ram: mem.high_ram.clone(),
+      mbc_state: mem.get_mbc_state(),
+    }
+  }
+
+  pub fn restore(&self, mem: &mut MemoryAreas) {
+    mem.video_ram.copy_from_slice(&self.video_ram);
+    mem.cart_ram.copy_from_slice(&self.cart_ram);
+    mem.work_ram.copy_from_slice(&self.work_ram);
+    mem.oam_ram.copy_from_slice(&self.oam_ram);
+    mem.high_ram.copy_from_slice(&self.high_ram);
+    mem.set_mbc_state(&self.mbc_state);
+  }
+
+  /// Each area of RAM, with its name and the address it starts at
+  fn areas(&self) -> [(&'static str, u16, &[u8]); 5] {
+    [
+      ("VRAM", 0x8000, &self.video_ram),
+      ("Cart RAM", 0xa000, &self.cart_ram),
+      ("WRAM", 0xc000, &self.work_ram),
+      ("OAM", 0xfe00, &self.oam_ram),
+      ("HRAM", 0xff80, &self.high_ram),
+    ]
+  }
+}
+
+/// Most memory differences listed in a report. Past this, the rest are only
+/// counted.
+const MAX_LISTED_BYTES: usize = 16;
+
+/// A byte of RAM that the interpreter and the JIT left with different values
+#[derive(Debug, Eq, PartialEq)]
+pub struct MemoryDifference {
+  pub area: &'static str,
+  pub offset: usize,
+  /// Address the byte appears at, if its bank is the one mapped in
+  pub address: u16,
+  /// Value before the block ran
+  pub before: u8,
+  pub interp: u8,
+  pub jit: u8,
+}
+
+/// Everything the interpreter and the JIT disagreed on after running the
+/// same block from the same state
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Divergence {
+  /// Block status returned by the interpreter and the JIT
+  pub status: Option<(u8, u8)>,
+  /// Each register that differs, with the interpreter's value and the JIT's
+  pub registers: Vec<(&'static str, u32, u32)>,
+  pub memory: Vec<MemoryDifference>,
+  pub mbc_state: Option<(MbcState, MbcState)>,
+}
+
+impl Divergence {
+  pub fn compare(
+    initial: &MemorySnapshot,
+    interp: (u8, &Registers, &MemorySnapshot),
+    jit: (u8, &Registers, &MemorySnapshot),
+  ) -> Option<Self> {
+    let (interp_status, interp_registers, interp_memory) = interp;
+    let (jit_status, jit_registers, jit_memory) = jit;
+    let mut divergence = Self::default();
+    if interp_status != jit_status {
+      divergence.status = Some((interp_status, jit_status));
+    }
+    let registers = [
+      ("AF", interp_registers.get_af(), jit_registers.get_af()),
+      ("BC", interp_registers.get_bc(), jit_registers.get_bc()),
+      ("DE", interp_registers.get_de(), jit_registers.get_de()),
+      ("HL", interp_registers.get_hl(), jit_registers.get_hl()),
+      ("SP", interp_registers.get_sp(), jit_registers.get_sp()),
+      ("IP", interp_registers.get_ip(), jit_registers.get_ip()),
+      ("cycles", { interp_registers.cycles }, { jit_registers.cycles }),
+    ];
+    divergence.registers = registers.iter().copied().filter(|(_, a, b)| a != b).collect();
+    let (initial_areas, interp_areas, jit_areas) = (initial.areas(), interp_memory.areas(), jit_memory.areas());
+    for (index, (area, base, before)) in initial_areas.iter().enumerate() {
+      let (expected, actual) = (interp_areas[index].2, jit_areas[index].2);
+      for (offset, value) in before.iter().enumerate() {
+        if expected[offset] != actual[offset] {
+          divergence.memory.push(MemoryDifference {
+            area,
+            offset,
+            address: base.wrapping_add(offset as u16),
+            before: *value,
+            interp: expected[offset],
+            jit: actual[offset],
+          });
+        }
+      }
+    }
+    if interp_memory.mbc_state != jit_memory.mbc_state {
+      divergence.mbc_state = Some((interp_memory.mbc_state, jit_memory.mbc_state));
+    }
+    if divergence == Self::default() {
+      None
+    } else {
+      Some(divergence)
+    }
+  }
+}
+
+/// Flag letters set in F, with dashes for the ones that are clear
+fn flag_string(af: u32) -> String {
+  [(0x80, 'Z'), (0x40, 'N'), (0x20, 'H'), (0x10, 'C')]
+    .iter()
+    .map(|(mask, letter)| if af & mask != 0 { *letter } else { '-' })
+    .collect()
+}
+
+impl fmt::Display for Divergence {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if let Some((interp, jit)) = self.status {
+      writeln!(f, "Status: interpreter {}, JIT {}", interp, jit)?;
+    }
+    for (name, interp, jit) in self.registers.iter() {
+      write!(f, "{}: interpreter {:04X}, JIT {:04X}", name, interp, jit)?;
+      if *name == "AF" && (interp ^ jit) & 0xf0 != 0 {
+        write!(f, " (flags {} vs {})", flag_string(*interp), flag_string(*jit))?;
+      }
+      writeln!(f)?;
+    }
+    for byte in self.memory.iter().take(MAX_LISTED_BYTES) {
+      writeln!(
+        f,
+        "{} offset {:#06X} (near {:#06X}): was {:02X}, interpreter wrote {:02X}, JIT wrote {:02X}",
+        byte.area,
+        byte.offset,
+        byte.address,
+        byte.before,
+        byte.interp,
+        byte.jit,
+      )?;
+    }
+    if self.memory.len() > MAX_LISTED_BYTES {
+      writeln!(f, "...and {} more bytes", self.memory.len() - MAX_LISTED_BYTES)?;
+    }
+    if let Some((interp, jit)) = &self.mbc_state {
+      writeln!(f, "MBC state: interpreter {:?}, JIT {:?}", interp, jit)?;
+    }
+    Ok(())
+  }
+}
+
+/// Run the compiled block at `address` with verification against the
+/// interpreter. Panics with a dump of the block and everything that differed
+/// on the first mismatch.
+pub fn run_verified_block(core: &mut Core, address: usize) -> u8 {
+  let initial_registers = core.registers;
+  let initial_memory = MemorySnapshot::capture(&core.memory);
+
+  let mut interp_registers = initial_registers;
+  let mem_ptr = &mut core.memory as *mut MemoryAreas;
+  let interp_status = interpreter::run_code_block(&mut interp_registers, mem_ptr);
+  let interp_memory = MemorySnapshot::capture(&core.memory);
+
+  initial_memory.restore(&mut core.memory);
+  // A budget of zero ensures only a single block runs, like the interpreter
+  let jit_status = core.cache.call(address, &mut core.registers, mem_ptr, MachineCycles(0));
+  let jit_memory = MemorySnapshot::capture(&core.memory);
+
+  let divergence = Divergence::compare(
+    &initial_memory,
+    (interp_status, &interp_registers, &interp_memory),
+    (jit_status, &core.registers, &jit_memory),
+  );
+  if let Some(divergence) = divergence {
+    dump_block(core, &initial_registers);
+    print!("{}", divergence);
+    panic!("JIT verification failed at {:#06X}", { initial_registers.ip });
+  }
+
+  jit_status
+}
+
+fn dump_block(core: &Core, initial_registers: &Registers) {
+  let ip = initial_registers.ip as usize;
+  println!("Mismatch in block at {:#06X}", ip);
+  println!("Initial state: {:?}", initial_registers);
+  let block = core.cache.get_block(ip);
+  let source = core.cache.get_executable_memory_segment(ip, core.memory.as_ptr());
+  if let (Some(block), Ok(source)) = (block, source) {
+    let source_length = block.bytes_translated.min(source.len());
+    let bank = super::breakpoint::get_bank_for_address(ip as u16, &core.memory);
+    for instr in super::disassembly::disassemble_bank(bank, ip as u16, &source[..source_length]).iter() {
+      println!("{}", instr);
+    }
+    println!("Emitted {} bytes:", block.length);
+    let emitted = core.cache.get_emitted_bytes(block);
+    #[cfg(feature = "dump_disassembly")]
+    for line in super::dump::host_listing(emitted, emitted.as_ptr() as u64) {
+      println!("  {}", line);
+    }
+    #[cfg(not(feature = "dump_disassembly"))]
+    for line in emitted.chunks(16) {
+      let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+      println!("  {}", hex.join(" "));
+    }
+  }
+  print!("{}", super::history::report());
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Divergence, MemorySnapshot};
+  use crate::cpu::Registers;
+  use crate::mem::MemoryAreas;
+
+  #[test]
+  fn divergence_report() {
+    let mut mem = MemoryAreas::with_rom(vec![0; 0x8000].into_boxed_slice());
+    let initial = MemorySnapshot::capture(&mem);
+    let mut interp_registers = Registers::new();
+    interp_registers.af = 0x12b0;
+    interp_registers.ip = 0x0105;
+    mem.work_ram[0x10] = 0x34;
+    let interp_memory = MemorySnapshot::capture(&mem);
+    let mut jit_registers = interp_registers;
+    jit_registers.af = 0x1230;
+    mem.work_ram[0x10] = 0x43;
+    let jit_memory = MemorySnapshot::capture(&mem);
+
+    assert_eq!(Divergence::compare(&initial, (0, &interp_registers, &interp_memory), (0, &interp_registers, &interp_memory)), None);
+    let divergence = Divergence::compare(&initial, (0, &interp_registers, &interp_memory), (2, &jit_registers, &jit_memory)).unwrap();
+    let report = divergence.to_string();
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines, [
+      "Status: interpreter 0, JIT 2",
+      "AF: interpreter 12B0, JIT 1230 (flags Z-HC vs --HC)",
+      "WRAM offset 0x0010 (near 0xC010): was 00, interpreter wrote 34, JIT wrote 43",
+    ]);
+  }
+}
@@ -0,0 +1,235 @@
+//! Watchpoints, which stop execution when the CPU accesses a range of memory.
+//! They are checked by the memory bus itself, so this module only relies on
+//! `core` and `alloc`.
+
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Access {
+  Read,
+  Write,
+}
+
+/// Which kinds of access trigger a watchpoint
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WatchKind {
+  Read,
+  Write,
+  ReadWrite,
+}
+
+impl WatchKind {
+  pub fn includes(&self, access: Access) -> bool {
+    match self {
+      WatchKind::Read => access == Access::Read,
+      WatchKind::Write => access == Access::Write,
+      WatchKind::ReadWrite => true,
+    }
+  }
+}
+
+/// Watches an inclusive range of addresses
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Watchpoint {
+  pub start: u16,
+  pub end: u16,
+  pub kind: WatchKind,
+}
+
+impl Watchpoint {
+  pub fn new(address: u16, kind: WatchKind) -> Self {
+    Self {
+      start: address,
+      end: address,
+      kind,
+    }
+  }
+
+  pub fn range(start: u16, end: u16, kind: WatchKind) -> Self {
+    Self {
+      start: start.min(end),
+      end: start.max(end),
+      kind,
+    }
+  }
+
+  pub fn matches(&self, address: u16, access: Access) -> bool {
+    address >= self.start && address <= self.end && self.kind.includes(access)
+  }
+}
+
+impl core::fmt::Display for Watchpoint {
+  fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+    let kind = match self.kind {
+      WatchKind::Read => "read",
+      WatchKind::Write => "write",
+      WatchKind::ReadWrite => "read/write",
+    };
+    if self.start == self.end {
+      write!(f, "{:04X} ({})", self.start, kind)
+    } else {
+      write!(f, "{:04X}-{:04X} ({})", self.start, self.end, kind)
+    }
+  }
+}
+
+/// An access that triggered a watchpoint
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct WatchHit {
+  pub address: u16,
+  /// The value read, or the value being written
+  pub value: u8,
+  pub access: Access,
+}
+
+/// Memory reads only have shared access to the bus, so the first hit since it
+/// was last taken is stored in a Cell
+pub struct WatchpointSet {
+  watchpoints: Vec<Watchpoint>,
+  hit: Cell<Option<WatchHit>>,
+}
+
+impl WatchpointSet {
+  pub fn new() -> Self {
+    Self {
+      watchpoints: Vec::new(),
+      hit: Cell::new(None),
+    }
+  }
+
+  pub fn add(&mut self, watchpoint: Watchpoint) {
+    if !self.watchpoints.contains(&watchpoint) {
+      self.watchpoints.push(watchpoint);
+    }
+  }
+
+  /// Returns true if the watchpoint was present
+  pub fn remove(&mut self, watchpoint: Watchpoint) -> bool {
+    let previous_length = self.watchpoints.len();
+    self.watchpoints.retain(|w| *w != watchpoint);
+    self.watchpoints.len() != previous_length
+  }
+
+  pub fn list(&self) -> &[Watchpoint] {
+    &self.watchpoints
+  }
+
+  #[inline(always)]
+  pub fn is_empty(&self) -> bool {
+    self.watchpoints.is_empty()
+  }
+
+  /// Record an access if it matches any watchpoint. Only the first hit is
+  /// kept until it is taken.
+  pub fn check(&self, address: u16, value: u8, access: Access) {
+    let hit = self.hit.get();
+    if hit.is_some() {
+      return;
+    }
+    if self.watchpoints.iter().any(|w| w.matches(address, access)) {
+      self.hit.set(Some(WatchHit { address, value, access }));
+    }
+  }
+
+  pub fn take_hit(&self) -> Option<WatchHit> {
+    self.hit.take()
+  }
+}
+
+impl Default for WatchpointSet {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Like a WatchpointSet, but keeps every matching access rather than only
+/// the first, so that callbacks can see all of them once the instruction
+/// making them has finished
+pub struct AccessLog {
+  ranges: Vec<Watchpoint>,
+  hits: RefCell<Vec<WatchHit>>,
+}
+
+impl AccessLog {
+  pub fn new() -> Self {
+    Self {
+      ranges: Vec::new(),
+      hits: RefCell::new(Vec::new()),
+    }
+  }
+
+  /// Replace the ranges being logged
+  pub fn set_ranges(&mut self, ranges: Vec<Watchpoint>) {
+    self.ranges = ranges;
+  }
+
+  #[inline(always)]
+  pub fn is_empty(&self) -> bool {
+    self.ranges.is_empty()
+  }
+
+  pub fn check(&self, address: u16, value: u8, access: Access) {
+    if self.ranges.iter().any(|w| w.matches(address, access)) {
+      self.hits.borrow_mut().push(WatchHit { address, value, access });
+    }
+  }
+
+  /// Take every access logged since the last call, in the order they were
+  /// made
+  pub fn take_hits(&self) -> Vec<WatchHit> {
+    self.hits.take()
+  }
+}
+
+impl Default for AccessLog {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Access, AccessLog, WatchHit, WatchKind, Watchpoint, WatchpointSet};
+  use alloc::string::ToString;
+  use alloc::vec;
+
+  #[test]
+  fn match_kind_and_range() {
+    let write = Watchpoint::new(0xc000, WatchKind::Write);
+    assert!(write.matches(0xc000, Access::Write));
+    assert!(!write.matches(0xc000, Access::Read));
+    assert!(!write.matches(0xc001, Access::Write));
+    let range = Watchpoint::range(0xc010, 0xc000, WatchKind::ReadWrite);
+    assert!(range.matches(0xc000, Access::Read));
+    assert!(range.matches(0xc010, Access::Write));
+    assert!(!range.matches(0xc011, Access::Write));
+    assert_eq!(range.to_string(), "C000-C010 (read/write)");
+  }
+
+  #[test]
+  fn keeps_first_hit() {
+    let mut set = WatchpointSet::new();
+    set.add(Watchpoint::range(0xff80, 0xff8f, WatchKind::Write));
+    set.check(0xff80, 1, Access::Read);
+    assert_eq!(set.take_hit(), None);
+    set.check(0xff81, 2, Access::Write);
+    set.check(0xff82, 3, Access::Write);
+    assert_eq!(set.take_hit(), Some(WatchHit { address: 0xff81, value: 2, access: Access::Write }));
+    assert_eq!(set.take_hit(), None);
+  }
+
+  #[test]
+  fn logs_every_hit() {
+    let mut log = AccessLog::new();
+    log.set_ranges(vec![Watchpoint::new(0xc000, WatchKind::ReadWrite)]);
+    log.check(0xc000, 1, Access::Write);
+    log.check(0xc001, 2, Access::Write);
+    log.check(0xc000, 1, Access::Read);
+    assert_eq!(log.take_hits(), vec![
+      WatchHit { address: 0xc000, value: 1, access: Access::Write },
+      WatchHit { address: 0xc000, value: 1, access: Access::Read },
+    ]);
+    assert!(log.take_hits().is_empty());
+  }
+}
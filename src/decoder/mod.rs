@@ -735,3 +735,85 @@ fn read_u16(instructions: &[u8]) -> u16 {
   let high = instructions[1] as u16;
   (high << 8) | low
 }
+
+#[cfg(test)]
+mod tests {
+  use super::decode;
+  use super::ops::Op;
+
+  /// Length of every primary opcode, with 0 for the CB prefix and for the
+  /// opcodes that don't exist
+  const LENGTHS: [usize; 256] = [
+    1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1,
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 0, 3, 3, 2, 1,
+    1, 1, 3, 0, 3, 1, 2, 1, 1, 1, 3, 0, 3, 0, 2, 1,
+    2, 1, 1, 0, 0, 1, 2, 1, 2, 1, 3, 0, 0, 0, 2, 1,
+    2, 1, 1, 1, 0, 1, 2, 1, 2, 1, 3, 1, 0, 0, 2, 1,
+  ];
+
+  /// Clock cycles of every primary opcode. Conditional branches are listed
+  /// with their cost when not taken, and unconditional JR, CALL, and RET
+  /// with their cost before the jump itself, which is added when it runs.
+  const CYCLES: [usize; 256] = [
+    4, 12, 8, 8, 4, 4, 8, 4, 20, 8, 8, 8, 4, 4, 8, 4,
+    4, 12, 8, 8, 4, 4, 8, 4, 8, 8, 8, 8, 4, 4, 8, 4,
+    8, 12, 8, 8, 4, 4, 8, 4, 8, 8, 8, 8, 4, 4, 8, 4,
+    8, 12, 8, 8, 12, 12, 12, 4, 8, 8, 8, 8, 4, 4, 8, 4,
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+    8, 8, 8, 8, 8, 8, 4, 8, 4, 4, 4, 4, 4, 4, 8, 4,
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4,
+    8, 12, 12, 16, 12, 16, 8, 16, 8, 4, 12, 0, 12, 12, 8, 16,
+    8, 12, 12, 0, 12, 16, 8, 16, 8, 16, 12, 0, 12, 0, 8, 16,
+    12, 12, 8, 0, 0, 16, 8, 16, 16, 4, 16, 0, 0, 0, 8, 16,
+    12, 12, 8, 4, 0, 16, 8, 16, 12, 8, 16, 4, 0, 0, 8, 16,
+  ];
+
+  #[test]
+  fn primary_opcodes() {
+    for opcode in 0..=0xffu8 {
+      let (op, length, cycles) = decode(&[opcode, 0, 0]);
+      if opcode == 0xcb {
+        continue;
+      }
+      if LENGTHS[opcode as usize] == 0 {
+        assert!(matches!(op, Op::Invalid(code) if code == opcode), "{:02X} should be invalid", opcode);
+        continue;
+      }
+      assert!(!matches!(op, Op::Invalid(_)), "{:02X} is not decoded", opcode);
+      assert_eq!(length, LENGTHS[opcode as usize], "length of {:02X}", opcode);
+      assert_eq!(cycles, CYCLES[opcode as usize], "cycles of {:02X}", opcode);
+    }
+  }
+
+  #[test]
+  fn cb_opcodes() {
+    for opcode in 0..=0xffu8 {
+      let (op, length, cycles) = decode(&[0xcb, opcode]);
+      assert!(!matches!(op, Op::Invalid(_)), "CB {:02X} is not decoded", opcode);
+      assert_eq!(length, 2);
+      // (HL) ops read and write memory, except BIT which only reads it
+      let expected = match (opcode & 7, opcode >> 6) {
+        (6, 1) => 12,
+        (6, _) => 16,
+        _ => 8,
+      };
+      assert_eq!(cycles, expected, "cycles of CB {:02X}", opcode);
+    }
+  }
+}
@@ -105,13 +105,14 @@ impl Op {
       Op::InterruptDisable => true,
       Op::Stop => true,
       Op::Halt => true,
+      Op::Invalid(_) => true,
       _ => false,
     }
   }
 }
 
-impl std::fmt::Display for Op {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Op {
+  fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
     match self {
       Op::Invalid(_) => f.write_str("INVALID"),
       Op::NoOp => f.write_str("NOP"),
@@ -254,8 +255,8 @@ pub enum IndirectLocation {
   HLDecrement,
 }
 
-impl std::fmt::Display for IndirectLocation {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for IndirectLocation {
+  fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
     match self {
       IndirectLocation::BC => f.write_str("(BC)"),
       IndirectLocation::DE => f.write_str("(DE)"),
@@ -266,7 +267,7 @@ impl std::fmt::Display for IndirectLocation {
   }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Register8 {
   A,
   B,
@@ -277,8 +278,8 @@ pub enum Register8 {
   L,
 }
 
-impl std::fmt::Display for Register8 {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Register8 {
+  fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
     match self {
       Register8::A => f.write_str("A"),
       Register8::B => f.write_str("B"),
@@ -291,7 +292,7 @@ impl std::fmt::Display for Register8 {
   }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Register16 {
   AF,
   BC,
@@ -300,8 +301,8 @@ pub enum Register16 {
   SP,
 }
 
-impl std::fmt::Display for Register16 {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Register16 {
+  fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
     match self {
       Register16::AF => f.write_str("AF"),
       Register16::BC => f.write_str("BC"),
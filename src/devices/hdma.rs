@@ -0,0 +1,212 @@
+use alloc::string::String;
+use crate::savestate::{StateReader, StateWriter};
+
+/// CGB VRAM DMA, controlled by the HDMA1-HDMA5 registers at 0xff51-0xff55.
+/// Data is always copied in blocks of 16 bytes into VRAM. A general-purpose
+/// DMA copies every block at once, halting the CPU until it completes. An
+/// H-blank DMA copies one block at the start of each H-blank period.
+/// The actual copy needs access to the memory bus, so it is performed by
+/// MemoryAreas; this struct only tracks the register state.
+pub struct HDMA {
+  source: u16,
+  dest: u16,
+  /// Number of 16-byte blocks left to copy
+  remaining_blocks: u8,
+  hblank_active: bool,
+  /// The line on which the most recent H-blank block was copied, to ensure
+  /// only one block is copied per H-blank
+  last_hblank_line: Option<u8>,
+}
+
+/// Describes a transfer requested by a write to HDMA5
+#[derive(Debug, Eq, PartialEq)]
+pub enum HDMARequest {
+  None,
+  GeneralPurpose(u8),
+  HBlank,
+}
+
+impl HDMA {
+  pub fn new() -> Self {
+    Self {
+      source: 0,
+      dest: 0,
+      remaining_blocks: 0,
+      hblank_active: false,
+      last_hblank_line: None,
+    }
+  }
+
+  pub fn set_source_high(&mut self, value: u8) {
+    self.source = (self.source & 0x00f0) | ((value as u16) << 8);
+  }
+
+  pub fn set_source_low(&mut self, value: u8) {
+    // the lower four bits are ignored
+    self.source = (self.source & 0xff00) | (value as u16 & 0xf0);
+  }
+
+  pub fn set_dest_high(&mut self, value: u8) {
+    // the destination is always within VRAM
+    self.dest = (self.dest & 0x00f0) | (((value & 0x1f) as u16) << 8);
+  }
+
+  pub fn set_dest_low(&mut self, value: u8) {
+    self.dest = (self.dest & 0x1f00) | (value as u16 & 0xf0);
+  }
+
+  pub fn get_source(&self) -> u16 {
+    self.source
+  }
+
+  pub fn get_dest(&self) -> u16 {
+    0x8000 | self.dest
+  }
+
+  /// Handle a write to HDMA5, which starts or cancels a transfer
+  pub fn set_control(&mut self, value: u8) -> HDMARequest {
+    let blocks = (value & 0x7f) + 1;
+    if self.hblank_active && value & 0x80 == 0 {
+      // Writing with bit 7 clear during an H-blank DMA stops it
+      self.hblank_active = false;
+      return HDMARequest::None;
+    }
+    self.remaining_blocks = blocks;
+    if value & 0x80 == 0 {
+      HDMARequest::GeneralPurpose(blocks)
+    } else {
+      self.hblank_active = true;
+      self.last_hblank_line = None;
+      HDMARequest::HBlank
+    }
+  }
+
+  /// Reading HDMA5 returns the number of blocks remaining, minus one. Bit 7
+  /// is clear while an H-blank transfer is active. A completed transfer reads
+  /// as 0xff.
+  pub fn get_control(&self) -> u8 {
+    let remaining = self.remaining_blocks.wrapping_sub(1) & 0x7f;
+    if self.hblank_active {
+      remaining
+    } else {
+      0x80 | remaining
+    }
+  }
+
+  /// Returns true if an H-blank block should be copied now, given the current
+  /// PPU mode and line
+  pub fn should_copy_hblank_block(&mut self, mode: u8, line: u8) -> bool {
+    if !self.hblank_active || mode != 0 || line >= 144 {
+      return false;
+    }
+    if self.last_hblank_line == Some(line) {
+      return false;
+    }
+    self.last_hblank_line = Some(line);
+    true
+  }
+
+  /// Returns the number of H-blank blocks to copy after the PPU has entered
+  /// `entries` H-blank periods, the latest of them on `line`. Each visible
+  /// line gets one block, even when several pass by in a single catch-up.
+  pub fn hblank_blocks_entered(&mut self, entries: u8, line: u8) -> u8 {
+    if !self.hblank_active || entries == 0 {
+      return 0;
+    }
+    let mut blocks = entries;
+    if self.last_hblank_line == Some(line) {
+      // a copy has already been made for the latest line
+      blocks -= 1;
+    }
+    self.last_hblank_line = Some(line);
+    blocks.min(self.remaining_blocks)
+  }
+
+  /// Advance the addresses after a 16-byte block has been copied
+  pub fn complete_block(&mut self) {
+    self.source = self.source.wrapping_add(0x10);
+    self.dest = (self.dest + 0x10) & 0x1ff0;
+    self.remaining_blocks = self.remaining_blocks.saturating_sub(1);
+    if self.remaining_blocks == 0 {
+      self.hblank_active = false;
+    }
+  }
+
+  pub fn save_state(&self, state: &mut StateWriter) {
+    state.u16(self.source);
+    state.u16(self.dest);
+    state.u8(self.remaining_blocks);
+    state.bool(self.hblank_active);
+    // H-blank copies never happen on lines past 143
+    state.u8(self.last_hblank_line.unwrap_or(0xff));
+  }
+
+  pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.source = state.u16()?;
+    self.dest = state.u16()? & 0x1ff0;
+    self.remaining_blocks = state.u8()?;
+    self.hblank_active = state.bool()?;
+    self.last_hblank_line = match state.u8()? {
+      0xff => None,
+      line => Some(line),
+    };
+    Ok(())
+  }
+}
+
+impl Default for HDMA {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{HDMA, HDMARequest};
+
+  #[test]
+  fn register_addresses() {
+    let mut hdma = HDMA::new();
+    hdma.set_source_high(0xc1);
+    hdma.set_source_low(0x2f);
+    hdma.set_dest_high(0xf3);
+    hdma.set_dest_low(0x4a);
+    assert_eq!(hdma.get_source(), 0xc120);
+    assert_eq!(hdma.get_dest(), 0x9340);
+  }
+
+  #[test]
+  fn hblank_transfer() {
+    let mut hdma = HDMA::new();
+    assert_eq!(hdma.set_control(0x81), HDMARequest::HBlank);
+    assert_eq!(hdma.get_control(), 0x01);
+    assert!(!hdma.should_copy_hblank_block(3, 0));
+    assert!(hdma.should_copy_hblank_block(0, 0));
+    assert!(!hdma.should_copy_hblank_block(0, 0));
+    hdma.complete_block();
+    assert_eq!(hdma.get_control(), 0x00);
+    assert!(hdma.should_copy_hblank_block(0, 1));
+    hdma.complete_block();
+    assert_eq!(hdma.get_control(), 0xff);
+  }
+
+  #[test]
+  fn several_hblanks_at_once() {
+    let mut hdma = HDMA::new();
+    hdma.set_control(0x84);
+    assert_eq!(hdma.hblank_blocks_entered(3, 2), 3);
+    assert!(!hdma.should_copy_hblank_block(0, 2));
+    assert_eq!(hdma.hblank_blocks_entered(4, 6), 4);
+    hdma.set_control(0x81);
+    assert_eq!(hdma.hblank_blocks_entered(4, 10), 2);
+  }
+
+  #[test]
+  fn cancel_hblank_transfer() {
+    let mut hdma = HDMA::new();
+    hdma.set_control(0x83);
+    hdma.complete_block();
+    assert_eq!(hdma.set_control(0x00), HDMARequest::None);
+    assert_eq!(hdma.get_control(), 0x82);
+  }
+}
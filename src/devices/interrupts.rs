@@ -40,7 +40,7 @@ impl InterruptFlag {
   }
 }
 
-impl std::ops::BitOr for InterruptFlag {
+impl core::ops::BitOr for InterruptFlag {
   type Output = Self;
 
   fn bitor(self, rhs: Self) -> Self::Output {
@@ -48,7 +48,7 @@ impl std::ops::BitOr for InterruptFlag {
   }
 }
 
-impl std::ops::BitOrAssign for InterruptFlag {
+impl core::ops::BitOrAssign for InterruptFlag {
   fn bitor_assign(&mut self, rhs: Self) {
     self.0 |= rhs.0;
   }
@@ -1,43 +1,115 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::any::Any;
+use crate::savestate::{StateReader, StateWriter};
 use crate::timing::ClockCycles;
 
+use super::IoDevice;
 use super::interrupts::InterruptFlag;
 use super::joypad::Joypad;
 use super::serial::SerialComms;
+use super::sgb::Sgb;
 use super::timer::Timer;
 use super::video::VideoState;
 
+/// Stands in for a device that was left out of the build. Its registers read
+/// as open bus, and writes are ignored.
+pub struct Unmapped;
+
+impl IoDevice for Unmapped {
+  fn read(&self, _register: u8) -> u8 {
+    0xff
+  }
+
+  fn write(&mut self, _register: u8, _value: u8) -> InterruptFlag {
+    InterruptFlag::empty()
+  }
+
+  fn run_clock_cycles(&mut self, _cycles: ClockCycles) -> InterruptFlag {
+    InterruptFlag::empty()
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn Any {
+    self
+  }
+}
+
 pub struct IO {
   pub interrupt_flag: InterruptFlag,
   pub interrupt_mask: u8,
-  pub joypad: Box<Joypad>,
-  pub serial: Box<SerialComms>,
-  pub timer: Box<Timer>,
+  /// P1, at 0xff00
+  pub joypad: Box<dyn IoDevice>,
+  /// SB and SC, at 0xff01-0xff02
+  pub serial: Box<dyn IoDevice>,
+  /// DIV, TIMA, TMA, and TAC, at 0xff04-0xff07
+  pub timer: Box<dyn IoDevice>,
   pub video: Box<VideoState>,
+  /// Listens for Super Game Boy packets on P1, for games that support it
+  pub sgb: Option<Box<Sgb>>,
 }
 
 impl IO {
   pub fn new() -> Self {
+    let serial: Box<dyn IoDevice> = if cfg!(feature = "serial") {
+      Box::new(SerialComms::new())
+    } else {
+      Box::new(Unmapped)
+    };
     Self {
       interrupt_flag: InterruptFlag::empty(),
       interrupt_mask: 0,
       joypad: Box::new(Joypad::new()),
-      serial: Box::new(SerialComms::new()),
+      serial,
       timer: Box::new(Timer::new()),
       video: Box::new(VideoState::new()),
+      sgb: None,
     }
   }
 
+  /// Return every register to its power-on value, as a hard reset does. The
+  /// link cable stays connected, buttons held on the host stay held, and the
+  /// screen keeps its palette.
+  pub fn reset(&mut self) {
+    let palette = *self.video.get_palette();
+    self.interrupt_flag = InterruptFlag::empty();
+    self.interrupt_mask = 0;
+    // devices supplied by an embedder stay in place
+    self.joypad.reset();
+    self.serial.reset();
+    self.timer.reset();
+    *self.video = VideoState::new();
+    self.video.set_palette(palette);
+    if let Some(sgb) = self.sgb.as_mut() {
+      **sgb = Sgb::new();
+    }
+  }
+
+  /// The built-in joypad, unless it was replaced with another device
+  pub fn joypad_port(&mut self) -> Option<&mut Joypad> {
+    self.joypad.as_any_mut().downcast_mut::<Joypad>()
+  }
+
+  /// The built-in serial port, unless it was left out of the build or
+  /// replaced with another device
+  pub fn serial_port(&mut self) -> Option<&mut SerialComms> {
+    self.serial.as_any_mut().downcast_mut::<SerialComms>()
+  }
+
   pub fn set_byte(&mut self, addr: u16, value: u8) {
     match addr & 0xff {
-      0x00 => self.joypad.set_value(value),
-      0x01 => self.serial.set_data(value),
-      0x02 => self.serial.set_control(value),
+      0x00 => {
+        self.joypad.write(0x00, value);
+        if let Some(sgb) = self.sgb.as_mut() {
+          sgb.write_joypad(value);
+        }
+      },
+      0x01..=0x02 => {
+        let flag = self.serial.write(addr as u8, value);
+        self.interrupt_flag |= flag;
+      },
       0x03 => (),
-      0x04 => self.timer.reset_divider(),
-      0x05 => self.timer.set_counter(value),
-      0x06 => self.timer.set_modulo(value),
-      0x07 => {
-        let flag = self.timer.set_timer_control(value);
+      0x04..=0x07 => {
+        let flag = self.timer.write(addr as u8, value);
         self.interrupt_flag |= flag;
       },
 
@@ -68,19 +140,20 @@ impl IO {
 
   pub fn get_byte(&self, addr: u16) -> u8 {
     match addr & 0xff {
-      0x00 => self.joypad.get_value(),
+      0x00 => match self.sgb.as_ref() {
+        Some(sgb) => sgb.read_joypad(self.joypad.read(0x00)),
+        None => self.joypad.read(0x00),
+      },
+      0x01..=0x02 => self.serial.read(addr as u8),
 
       0x03 => 0xff,
-      0x04 => self.timer.get_divider(),
-      0x05 => self.timer.get_counter(),
-      0x06 => self.timer.get_modulo(),
-      0x07 => self.timer.get_timer_control(),
+      0x04..=0x07 => self.timer.read(addr as u8),
 
       // unconnected lines should be tied high
       0x0f => self.interrupt_flag.as_u8() | 0xe0,
 
       0x40 => self.video.get_lcd_control(),
-      0x41 => self.video.get_lcd_status(),
+      0x41 => self.video.get_lcd_status() | 0x80,
       0x42 => self.video.get_scroll_y(),
       0x43 => self.video.get_scroll_x(),
       0x44 => self.video.get_ly(),
@@ -101,6 +174,21 @@ impl IO {
     self.interrupt_flag.as_u8() & self.interrupt_mask
   }
 
+  /// Clock cycles until the next peripheral event that needs to happen at a
+  /// precise time. The CPU should not run past this point before peripherals
+  /// are caught up.
+  pub fn cycles_until_next_event(&self) -> Option<ClockCycles> {
+    [
+      self.serial.cycles_until_next_event(),
+      self.timer.cycles_until_next_event(),
+      self.video.cycles_until_next_event(),
+    ]
+      .iter()
+      .flatten()
+      .copied()
+      .min_by_key(|cycles| cycles.as_usize())
+  }
+
   /// Catch up the internal clocks of peripherals on the bus.
   /// Every time the CPU runs for a series of instructions, this should be
   /// called to keep the rest of the devices in sync.
@@ -108,10 +196,75 @@ impl IO {
   /// cycles, the submitted number of cycles should be 4x the number of machine
   /// cycles that have passed.
   pub fn run_clock_cycles(&mut self, cycles: ClockCycles, vram: &Box<[u8]>, oam: &Box<[u8]>) {
-    let mut flags = self.timer.run_cycles(cycles);
+    let mut flags = self.timer.run_clock_cycles(cycles);
+    let frame = self.video.get_frame_count();
     flags |= self.video.run_clock_cycles(cycles, vram, oam);
-    flags |= self.joypad.get_interrupt();
+    if let Some(sgb) = self.sgb.as_mut() {
+      if self.video.get_frame_count() != frame {
+        sgb.end_frame(&self.video, vram);
+      }
+    }
+    flags |= self.serial.run_clock_cycles(cycles);
+    flags |= self.joypad.run_clock_cycles(cycles);
 
     self.interrupt_flag |= flags;
   }
+
+  pub fn save_state(&self, state: &mut StateWriter) {
+    state.u8(self.interrupt_flag.as_u8());
+    state.u8(self.interrupt_mask);
+    self.joypad.save_state(state);
+    self.serial.save_state(state);
+    self.timer.save_state(state);
+    self.video.save_state(state);
+    state.bool(self.sgb.is_some());
+    if let Some(sgb) = self.sgb.as_ref() {
+      sgb.save_state(state);
+    }
+  }
+
+  pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.interrupt_flag = InterruptFlag::new(state.u8()? & 0x1f);
+    self.interrupt_mask = state.u8()?;
+    self.joypad.load_state(state)?;
+    self.serial.load_state(state)?;
+    self.timer.load_state(state)?;
+    self.video.load_state(state)?;
+    if state.bool()? {
+      self.sgb.get_or_insert_with(|| Box::new(Sgb::new())).load_state(state)?;
+    } else {
+      self.sgb = None;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Unmapped, IO};
+  use alloc::boxed::Box;
+
+  #[test]
+  fn reset_keeps_replaced_devices() {
+    let mut io = IO::new();
+    io.timer = Box::new(Unmapped);
+    io.set_byte(0xff06, 0x12);
+    io.reset();
+    assert!(io.timer.as_any_mut().downcast_mut::<Unmapped>().is_some());
+
+    // the built-in timer returns to its power-on values
+    let mut io = IO::new();
+    io.set_byte(0xff06, 0x12);
+    io.reset();
+    assert_eq!(io.get_byte(0xff06), 0);
+  }
+
+  #[test]
+  fn replaced_joypad() {
+    let mut io = IO::new();
+    io.joypad = Box::new(Unmapped);
+    assert!(io.joypad_port().is_none());
+    io.set_byte(0xff00, 0x10);
+    assert_eq!(io.get_byte(0xff00), 0xff);
+  }
 }
@@ -1,5 +1,11 @@
+use alloc::string::String;
+use core::any::Any;
+use crate::savestate::{StateReader, StateWriter};
+use crate::timing::ClockCycles;
+use super::IoDevice;
 use super::interrupts::InterruptFlag;
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Button {
   A,
   B,
@@ -11,6 +17,33 @@ pub enum Button {
   Down,
 }
 
+impl Button {
+  pub const ALL: [Button; 8] = [
+    Button::A,
+    Button::B,
+    Button::Select,
+    Button::Start,
+    Button::Right,
+    Button::Left,
+    Button::Up,
+    Button::Down,
+  ];
+
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "a" => Some(Button::A),
+      "b" => Some(Button::B),
+      "select" => Some(Button::Select),
+      "start" => Some(Button::Start),
+      "right" => Some(Button::Right),
+      "left" => Some(Button::Left),
+      "up" => Some(Button::Up),
+      "down" => Some(Button::Down),
+      _ => None,
+    }
+  }
+}
+
 pub struct Joypad {
   action_state: u8,
   direction_state: u8,
@@ -31,7 +64,7 @@ impl Joypad {
   }
 
   pub fn press_button(&mut self, button: Button) {
-    let prev_value = self.get_value() & 0x0f;
+    let prev_value = self.get_value();
     match button {
       Button::A => self.action_state |= 0x01,
       Button::B => self.action_state |= 0x02,
@@ -43,11 +76,7 @@ impl Joypad {
       Button::Up => self.direction_state |= 0x04,
       Button::Down => self.direction_state |= 0x08,
     }
-    let new_value = self.get_value() & 0x0f;
-
-    if new_value < prev_value { // a bit went low
-      self.next_interrupt = InterruptFlag::joypad();
-    }
+    self.check_falling_lines(prev_value);
   }
 
   pub fn release_button(&mut self, button: Button) {
@@ -64,16 +93,30 @@ impl Joypad {
     }
   }
 
+  /// Writing to P1 selects which set of buttons is read back. Bits 4 and 5
+  /// select the directions and the actions, and are active low.
   pub fn set_value(&mut self, value: u8) {
-    let prev_value = self.get_value() & 0x0f;
+    let prev_value = self.get_value();
     self.select_direction = value & 0x10 == 0;
     self.select_action = value & 0x20 == 0;
-    let new_value = self.get_value() & 0x0f;
-    if new_value < prev_value {
+    self.check_falling_lines(prev_value);
+  }
+
+  /// The joypad interrupt fires when any of the four input lines goes from
+  /// high to low, whether from a button press or a change in selection
+  fn check_falling_lines(&mut self, prev_value: u8) {
+    let fallen = prev_value & !self.get_value() & 0x0f;
+    if fallen != 0 {
       self.next_interrupt = InterruptFlag::joypad();
     }
   }
 
+  /// Whether a selected button is pressed, pulling one of the input lines
+  /// low. This is what wakes the CPU from STOP.
+  pub fn is_any_line_low(&self) -> bool {
+    self.get_value() & 0x0f != 0x0f
+  }
+
   pub fn get_value(&self) -> u8 {
     let mut value = 0xc0;
 
@@ -90,7 +133,60 @@ impl Joypad {
   }
 
   pub fn get_interrupt(&mut self) -> InterruptFlag {
-    std::mem::replace(&mut self.next_interrupt, InterruptFlag::empty())
+    core::mem::replace(&mut self.next_interrupt, InterruptFlag::empty())
+  }
+
+  /// Pressed buttons come from the host, so only the selected lines and any
+  /// pending interrupt are saved
+  pub fn save_state(&self, state: &mut StateWriter) {
+    state.bool(self.select_action);
+    state.bool(self.select_direction);
+    state.u8(self.next_interrupt.as_u8());
+  }
+
+  pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.select_action = state.bool()?;
+    self.select_direction = state.bool()?;
+    self.next_interrupt = InterruptFlag::new(state.u8()?);
+    Ok(())
+  }
+}
+
+impl IoDevice for Joypad {
+  fn read(&self, register: u8) -> u8 {
+    match register {
+      0x00 => self.get_value(),
+      _ => 0xff,
+    }
+  }
+
+  fn write(&mut self, register: u8, value: u8) -> InterruptFlag {
+    if register == 0x00 {
+      self.set_value(value);
+    }
+    InterruptFlag::empty()
+  }
+
+  /// Button presses happen between steps, so any interrupt they raised is
+  /// delivered on the next one
+  fn run_clock_cycles(&mut self, _cycles: ClockCycles) -> InterruptFlag {
+    self.get_interrupt()
+  }
+
+  fn reset(&mut self) {
+    self.set_value(0x30);
+  }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    Joypad::save_state(self, state);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    Joypad::load_state(self, state)
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn Any {
+    self
   }
 }
 
@@ -157,6 +253,24 @@ mod tests {
     assert_eq!(joypad.get_interrupt(), InterruptFlag::joypad());
   }
 
+  #[test]
+  pub fn joypad_reselect_interrupt() {
+    // switching from directions to actions can raise one line while lowering
+    // another, which still fires the interrupt
+    let mut joypad = Joypad::new();
+    joypad.press_button(Button::Right);
+    joypad.press_button(Button::Start);
+    joypad.set_value(0x20);
+    let _ = joypad.get_interrupt();
+    assert_eq!(joypad.get_value() & 0x0f, 0x0e);
+    joypad.set_value(0x10);
+    assert_eq!(joypad.get_value() & 0x0f, 0x07);
+    assert_eq!(joypad.get_interrupt(), InterruptFlag::joypad());
+    assert!(joypad.is_any_line_low());
+    joypad.set_value(0x30);
+    assert!(!joypad.is_any_line_low());
+  }
+
   #[test]
   pub fn joypad_double_select_interrupt() {
     // If both action and direction are selected, pushing a button may not pull
@@ -0,0 +1,420 @@
+//! The other end of the link cable. A serial transfer shifts one byte out of
+//! each connected Game Boy and into the other, so every transfer is an
+//! exchange. Transfers can also be recorded with the time they occurred, and
+//! one side of a recorded session can later be replayed to a single emulator.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::Write;
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex};
+
+/// A device connected to the serial port. Timestamps are the number of clock
+/// cycles the local machine has run since power-on. Like the core it's
+/// plugged into, a peer can be moved to another thread.
+pub trait LinkPeer: Send {
+  /// The local side started a transfer using its internal clock. Returns the
+  /// byte shifted in from the peer.
+  fn exchange(&mut self, timestamp: u64, outgoing: u8) -> u8;
+
+  /// The local side is waiting for the peer to clock a transfer. If the peer
+  /// does so, returns the byte it sent.
+  fn poll_external(&mut self, timestamp: u64, outgoing: u8) -> Option<u8>;
+
+  /// Returns a problem the peer has run into since it was last asked, like
+  /// a replayed session diverging, to be passed on to the frontend
+  fn take_report(&mut self) -> Option<String> {
+    None
+  }
+}
+
+/// With nothing connected, the data line is pulled high, and transfers on an
+/// external clock never happen.
+pub struct Unplugged;
+
+impl LinkPeer for Unplugged {
+  fn exchange(&mut self, _timestamp: u64, _outgoing: u8) -> u8 {
+    0xff
+  }
+
+  fn poll_external(&mut self, _timestamp: u64, _outgoing: u8) -> Option<u8> {
+    None
+  }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Transfer {
+  pub timestamp: u64,
+  pub sent: u8,
+  pub received: u8,
+  /// Whether the local side provided the clock for this transfer
+  pub internal_clock: bool,
+}
+
+impl Transfer {
+  /// Each transfer is stored as a line of text:
+  /// `<timestamp> <sent> <received> <int|ext>`, with bytes in hex
+  pub fn serialize(&self) -> String {
+    format!(
+      "{} {:02x} {:02x} {}",
+      self.timestamp,
+      self.sent,
+      self.received,
+      if self.internal_clock { "int" } else { "ext" },
+    )
+  }
+
+  pub fn deserialize(line: &str) -> Result<Self, String> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() != 4 {
+      return Err(format!("Malformed transfer \"{}\"", line));
+    }
+    let timestamp = parts[0].parse::<u64>().map_err(|_| format!("Invalid timestamp \"{}\"", parts[0]))?;
+    let sent = u8::from_str_radix(parts[1], 16).map_err(|_| format!("Invalid byte \"{}\"", parts[1]))?;
+    let received = u8::from_str_radix(parts[2], 16).map_err(|_| format!("Invalid byte \"{}\"", parts[2]))?;
+    let internal_clock = match parts[3] {
+      "int" => true,
+      "ext" => false,
+      other => return Err(format!("Invalid clock source \"{}\"", other)),
+    };
+    Ok(Self { timestamp, sent, received, internal_clock })
+  }
+}
+
+pub struct LinkRecording {
+  pub transfers: Vec<Transfer>,
+}
+
+impl LinkRecording {
+  pub fn new() -> Self {
+    Self {
+      transfers: Vec::new(),
+    }
+  }
+
+  pub fn serialize(&self) -> String {
+    let mut text = String::new();
+    for transfer in self.transfers.iter() {
+      text.push_str(&transfer.serialize());
+      text.push('\n');
+    }
+    text
+  }
+
+  /// Blank lines and lines starting with # are ignored
+  pub fn deserialize(text: &str) -> Result<Self, String> {
+    let mut transfers = Vec::new();
+    for line in text.lines() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      transfers.push(Transfer::deserialize(line)?);
+    }
+    Ok(Self { transfers })
+  }
+
+  #[cfg(feature = "std")]
+  pub fn load(name: &str) -> Result<Self, String> {
+    let text = std::fs::read_to_string(name).map_err(|_| String::from("Unable to open link recording"))?;
+    Self::deserialize(&text)
+  }
+}
+
+impl Default for LinkRecording {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Passes transfers through to another peer, recording each one. If a log
+/// file is provided, transfers are also appended to it as they happen, so the
+/// recording survives even if the emulator does not exit cleanly.
+pub struct RecordingPeer {
+  inner: Box<dyn LinkPeer>,
+  recording: LinkRecording,
+  #[cfg(feature = "std")]
+  log: Option<File>,
+  /// Set once appending to the log file has failed. Transfers are still
+  /// recorded in memory.
+  log_failed: bool,
+  log_failure_reported: bool,
+}
+
+impl RecordingPeer {
+  pub fn new(inner: Box<dyn LinkPeer>) -> Self {
+    Self {
+      inner,
+      recording: LinkRecording::new(),
+      #[cfg(feature = "std")]
+      log: None,
+      log_failed: false,
+      log_failure_reported: false,
+    }
+  }
+
+  #[cfg(feature = "std")]
+  pub fn with_log_file(inner: Box<dyn LinkPeer>, name: &str) -> Result<Self, String> {
+    let log = File::create(name).map_err(|_| String::from("Unable to create link recording"))?;
+    let mut peer = Self::new(inner);
+    peer.log = Some(log);
+    Ok(peer)
+  }
+
+  pub fn get_recording(&self) -> &LinkRecording {
+    &self.recording
+  }
+
+  pub fn has_log_failed(&self) -> bool {
+    self.log_failed
+  }
+
+  fn record(&mut self, transfer: Transfer) {
+    #[cfg(feature = "std")]
+    if let Some(log) = self.log.as_mut() {
+      if writeln!(log, "{}", transfer.serialize()).is_err() {
+        self.log = None;
+        self.log_failed = true;
+      }
+    }
+    self.recording.transfers.push(transfer);
+  }
+}
+
+impl LinkPeer for RecordingPeer {
+  fn exchange(&mut self, timestamp: u64, outgoing: u8) -> u8 {
+    let received = self.inner.exchange(timestamp, outgoing);
+    self.record(Transfer { timestamp, sent: outgoing, received, internal_clock: true });
+    received
+  }
+
+  fn poll_external(&mut self, timestamp: u64, outgoing: u8) -> Option<u8> {
+    let received = self.inner.poll_external(timestamp, outgoing)?;
+    self.record(Transfer { timestamp, sent: outgoing, received, internal_clock: false });
+    Some(received)
+  }
+
+  fn take_report(&mut self) -> Option<String> {
+    if self.log_failed && !self.log_failure_reported {
+      self.log_failure_reported = true;
+      return Some(String::from("Unable to write to link recording"));
+    }
+    self.inner.take_report()
+  }
+}
+
+/// Plays back the remote side of a recorded session. Transfers clocked by the
+/// remote side are triggered once the local clock reaches the time they were
+/// recorded at. If the local side sends something other than what was
+/// recorded, the session has diverged; playback continues, but the index of
+/// the first mismatched transfer is kept for inspection, along with the byte
+/// sent in its place.
+pub struct ReplayPeer {
+  transfers: Vec<Transfer>,
+  position: usize,
+  divergence: Option<(usize, u8)>,
+  divergence_reported: bool,
+}
+
+impl ReplayPeer {
+  pub fn new(recording: LinkRecording) -> Self {
+    Self {
+      transfers: recording.transfers,
+      position: 0,
+      divergence: None,
+      divergence_reported: false,
+    }
+  }
+
+  pub fn is_finished(&self) -> bool {
+    self.position >= self.transfers.len()
+  }
+
+  pub fn get_divergence(&self) -> Option<usize> {
+    self.divergence.map(|(position, _)| position)
+  }
+
+  fn advance(&mut self, outgoing: u8, internal_clock: bool) -> u8 {
+    let transfer = self.transfers[self.position];
+    if self.divergence.is_none() && (transfer.sent != outgoing || transfer.internal_clock != internal_clock) {
+      self.divergence = Some((self.position, outgoing));
+    }
+    self.position += 1;
+    transfer.received
+  }
+}
+
+impl LinkPeer for ReplayPeer {
+  fn exchange(&mut self, _timestamp: u64, outgoing: u8) -> u8 {
+    if self.is_finished() {
+      return 0xff;
+    }
+    self.advance(outgoing, true)
+  }
+
+  fn poll_external(&mut self, timestamp: u64, outgoing: u8) -> Option<u8> {
+    let next = self.transfers.get(self.position)?;
+    if next.internal_clock || timestamp < next.timestamp {
+      return None;
+    }
+    Some(self.advance(outgoing, false))
+  }
+
+  fn take_report(&mut self) -> Option<String> {
+    let (position, sent) = self.divergence.filter(|_| !self.divergence_reported)?;
+    self.divergence_reported = true;
+    Some(format!(
+      "Link replay diverged at transfer {}: recorded {:02x}, sent {:02x}",
+      position,
+      self.transfers[position].sent,
+      sent,
+    ))
+  }
+}
+
+/// State of a cable between two emulated Game Boys, indexed by side
+#[cfg(feature = "std")]
+struct Wire {
+  /// Byte each side has ready while it waits for the other to clock a
+  /// transfer
+  waiting: [Option<u8>; 2],
+  /// Byte clocked into each side, not yet seen by it
+  incoming: [Option<u8>; 2],
+}
+
+/// One end of a cable connecting two cores in the same process. The cores
+/// need to run in small, alternating slices for transfers to line up.
+#[cfg(feature = "std")]
+pub struct CableEnd {
+  side: usize,
+  wire: Arc<Mutex<Wire>>,
+}
+
+/// Create both ends of a cable
+#[cfg(feature = "std")]
+pub fn link_cable() -> (CableEnd, CableEnd) {
+  let wire = Arc::new(Mutex::new(Wire {
+    waiting: [None; 2],
+    incoming: [None; 2],
+  }));
+  (
+    CableEnd { side: 0, wire: wire.clone() },
+    CableEnd { side: 1, wire },
+  )
+}
+
+#[cfg(feature = "std")]
+impl LinkPeer for CableEnd {
+  fn exchange(&mut self, _timestamp: u64, outgoing: u8) -> u8 {
+    let mut wire = self.wire.lock().unwrap();
+    let other = 1 - self.side;
+    // if the other side isn't waiting on a transfer, nothing is shifted in
+    // and the line stays high
+    match wire.waiting[other].take() {
+      Some(received) => {
+        wire.incoming[other] = Some(outgoing);
+        received
+      },
+      None => 0xff,
+    }
+  }
+
+  fn poll_external(&mut self, _timestamp: u64, outgoing: u8) -> Option<u8> {
+    let mut wire = self.wire.lock().unwrap();
+    match wire.incoming[self.side].take() {
+      Some(received) => Some(received),
+      None => {
+        wire.waiting[self.side] = Some(outgoing);
+        None
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  #[cfg(feature = "std")]
+  use super::link_cable;
+  use super::{LinkPeer, LinkRecording, RecordingPeer, ReplayPeer, Transfer};
+  use alloc::boxed::Box;
+
+  /// Responds to each byte with its complement, and clocks a transfer of its
+  /// own every 1000 cycles
+  struct Complement {
+    last_external: u64,
+  }
+
+  impl LinkPeer for Complement {
+    fn exchange(&mut self, _timestamp: u64, outgoing: u8) -> u8 {
+      !outgoing
+    }
+
+    fn poll_external(&mut self, timestamp: u64, outgoing: u8) -> Option<u8> {
+      if timestamp - self.last_external < 1000 {
+        return None;
+      }
+      self.last_external = timestamp;
+      Some(!outgoing)
+    }
+  }
+
+  #[test]
+  fn transfer_round_trip() {
+    let transfer = Transfer { timestamp: 70224, sent: 0x3c, received: 0xc3, internal_clock: false };
+    assert_eq!(transfer.serialize(), "70224 3c c3 ext");
+    assert_eq!(Transfer::deserialize(&transfer.serialize()), Ok(transfer));
+    assert!(Transfer::deserialize("70224 3c c3").is_err());
+    assert!(Transfer::deserialize("70224 3c zz int").is_err());
+  }
+
+  #[test]
+  fn record_and_replay() {
+    let mut recorder = RecordingPeer::new(Box::new(Complement { last_external: 0 }));
+    assert_eq!(recorder.exchange(100, 0x01), 0xfe);
+    assert_eq!(recorder.poll_external(500, 0x02), None);
+    assert_eq!(recorder.poll_external(1200, 0x02), Some(0xfd));
+    assert_eq!(recorder.exchange(1500, 0x03), 0xfc);
+
+    let text = recorder.get_recording().serialize();
+    let recording = LinkRecording::deserialize(&text).unwrap();
+    assert_eq!(recording.transfers.len(), 3);
+
+    let mut replay = ReplayPeer::new(recording);
+    assert_eq!(replay.exchange(90, 0x01), 0xfe);
+    // the remote side's transfer waits until the time it was recorded
+    assert_eq!(replay.poll_external(1000, 0x02), None);
+    assert_eq!(replay.poll_external(1200, 0x02), Some(0xfd));
+    assert_eq!(replay.exchange(1400, 0x03), 0xfc);
+    assert!(replay.is_finished());
+    assert_eq!(replay.get_divergence(), None);
+    assert_eq!(replay.exchange(1600, 0x04), 0xff);
+  }
+
+  #[test]
+  fn replay_divergence() {
+    let recording = LinkRecording::deserialize("# session\n10 01 fe int\n20 02 fd int\n").unwrap();
+    let mut replay = ReplayPeer::new(recording);
+    assert_eq!(replay.exchange(10, 0x01), 0xfe);
+    assert_eq!(replay.exchange(20, 0x07), 0xfd);
+    assert_eq!(replay.get_divergence(), Some(1));
+    assert_eq!(replay.take_report().unwrap(), "Link replay diverged at transfer 1: recorded 02, sent 07");
+    assert_eq!(replay.take_report(), None);
+  }
+
+  #[test]
+  #[cfg(feature = "std")]
+  fn cable_transfer() {
+    let (mut first, mut second) = link_cable();
+    // nobody is listening yet
+    assert_eq!(first.exchange(0, 0x01), 0xff);
+    assert_eq!(second.poll_external(10, 0x20), None);
+    assert_eq!(first.exchange(20, 0x02), 0x20);
+    assert_eq!(second.poll_external(30, 0x20), Some(0x02));
+    assert_eq!(second.poll_external(40, 0x21), None);
+  }
+}
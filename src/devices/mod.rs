@@ -1,6 +1,56 @@
+pub mod hdma;
 pub mod interrupts;
 pub mod io;
 pub mod joypad;
+pub mod link;
 pub mod serial;
+pub mod sgb;
+pub mod strict;
 pub mod timer;
 pub mod video;
+
+use alloc::string::String;
+use core::any::Any;
+use crate::savestate::{StateReader, StateWriter};
+use crate::timing::ClockCycles;
+use interrupts::InterruptFlag;
+
+/// A peripheral mapped into the IO register page. Registers are addressed by
+/// the low byte of their address, so TIMA at 0xff05 is register 0x05.
+/// Implementing this lets an embedder swap in their own version of a device,
+/// or leave the built-in one out of the build. Devices have to be `Send`, so
+/// that the core holding them can run on its own thread.
+pub trait IoDevice: Send {
+  fn read(&self, register: u8) -> u8;
+
+  /// Since writing a register can trigger an interrupt, this returns a flag
+  /// value
+  fn write(&mut self, register: u8, value: u8) -> InterruptFlag;
+
+  /// Catch the device up with the CPU
+  fn run_clock_cycles(&mut self, cycles: ClockCycles) -> InterruptFlag;
+
+  /// Clock cycles until the device needs to raise an interrupt at a precise
+  /// time, if it has anything scheduled
+  fn cycles_until_next_event(&self) -> Option<ClockCycles> {
+    None
+  }
+
+  /// Return the device to its power-on state, as a hard reset does. Devices
+  /// that don't implement this are left as they are.
+  fn reset(&mut self) {
+  }
+
+  /// Write the device's internal state to a save state. Devices that don't
+  /// implement this are left as they are when a state is loaded.
+  fn save_state(&self, _state: &mut StateWriter) {
+  }
+
+  fn load_state(&mut self, _state: &mut StateReader) -> Result<(), String> {
+    Ok(())
+  }
+
+  /// Allows the concrete device to be recovered, for configuration that
+  /// doesn't go through registers
+  fn as_any_mut(&mut self) -> &mut dyn Any;
+}
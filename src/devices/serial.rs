@@ -1,6 +1,29 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::any::Any;
+use crate::savestate::{StateReader, StateWriter};
+use crate::timing::ClockCycles;
+use super::IoDevice;
+use super::interrupts::InterruptFlag;
+use super::link::LinkPeer;
+
+/// With the internal clock, each bit takes 512 clock cycles to shift (8192Hz)
+const NORMAL_BIT_CYCLES: u64 = 512;
+/// The CGB can select a faster internal clock of 262144Hz
+const FAST_BIT_CYCLES: u64 = 16;
+
 pub struct SerialComms {
   latch: u8,
   control: u8,
+  /// Clock cycles since power-on, used to timestamp transfers
+  clock: u64,
+  /// When nothing is connected, transferred bytes are written to stdout. Test
+  /// ROMs commonly use this to report their results.
+  peer: Option<Box<dyn LinkPeer>>,
+  /// A transfer on the internal clock completes at an exact time, along with
+  /// the byte that will have been shifted in by then
+  pending_transfer: Option<(u64, u8)>,
+  transfer_complete: bool,
 }
 
 impl SerialComms {
@@ -8,9 +31,26 @@ impl SerialComms {
     Self {
       latch: 0,
       control: 0,
+      clock: 0,
+      peer: None,
+      pending_transfer: None,
+      transfer_complete: false,
     }
   }
 
+  pub fn connect(&mut self, peer: Box<dyn LinkPeer>) {
+    self.peer = Some(peer);
+  }
+
+  pub fn disconnect(&mut self) -> Option<Box<dyn LinkPeer>> {
+    self.peer.take()
+  }
+
+  /// Returns a problem reported by the connected peer, if there is one
+  pub fn take_peer_report(&mut self) -> Option<String> {
+    self.peer.as_mut().and_then(|peer| peer.take_report())
+  }
+
   pub fn get_data(&self) -> u8 {
     self.latch
   }
@@ -20,17 +60,183 @@ impl SerialComms {
   }
 
   pub fn get_control(&self) -> u8 {
-    self.control
+    // unused bits read high
+    self.control | 0x7c
   }
 
   pub fn set_control(&mut self, value: u8) {
-    use std::io::{self, Write};
-
     self.control = value;
+    self.pending_transfer = None;
 
-    if value & 0x80 != 0 {
+    if value & 0x80 == 0 {
+      return;
+    }
+    // with nothing connected, echo the outgoing byte to the console, which
+    // test ROMs use to report results
+    #[cfg(feature = "std")]
+    if self.peer.is_none() {
+      use std::io::{self, Write};
       let _ = io::stdout().write(&[self.latch]);
       let _ = io::stdout().flush();
     }
+    if value & 1 != 0 {
+      // On the internal clock, the peer receives the byte and replies
+      // immediately, but the transfer is not visible until all 8 bits have
+      // been shifted
+      let received = match self.peer.as_mut() {
+        Some(peer) => peer.exchange(self.clock, self.latch),
+        None => 0xff,
+      };
+      let bit_cycles = if value & 2 != 0 { FAST_BIT_CYCLES } else { NORMAL_BIT_CYCLES };
+      self.pending_transfer = Some((self.clock + bit_cycles * 8, received));
+    }
+  }
+
+  fn complete_transfer(&mut self, received: u8) {
+    self.latch = received;
+    self.control &= 0x7f;
+    self.transfer_complete = true;
+  }
+
+  /// Clock cycles until the current transfer completes, if one is running on
+  /// the internal clock. Running the CPU for no longer than this ensures the
+  /// serial interrupt is raised at the correct point.
+  pub fn cycles_until_complete(&self) -> Option<ClockCycles> {
+    self.pending_transfer
+      .map(|(end, _)| ClockCycles(end.saturating_sub(self.clock) as usize))
+  }
+
+  /// Advance the serial clock. If a transfer is waiting on the peer's clock,
+  /// the peer gets a chance to perform it.
+  pub fn run_clock_cycles(&mut self, cycles: ClockCycles) -> InterruptFlag {
+    self.clock += cycles.as_usize() as u64;
+    if let Some((end, received)) = self.pending_transfer {
+      if self.clock >= end {
+        self.pending_transfer = None;
+        self.complete_transfer(received);
+      }
+    }
+    if self.control & 0x81 == 0x80 {
+      if let Some(peer) = self.peer.as_mut() {
+        if let Some(received) = peer.poll_external(self.clock, self.latch) {
+          self.complete_transfer(received);
+        }
+      }
+    }
+    if self.transfer_complete {
+      self.transfer_complete = false;
+      InterruptFlag::serial()
+    } else {
+      InterruptFlag::empty()
+    }
+  }
+}
+
+impl IoDevice for SerialComms {
+  fn read(&self, register: u8) -> u8 {
+    match register {
+      0x01 => self.get_data(),
+      0x02 => self.get_control(),
+      _ => 0xff,
+    }
+  }
+
+  fn write(&mut self, register: u8, value: u8) -> InterruptFlag {
+    match register {
+      0x01 => self.set_data(value),
+      0x02 => self.set_control(value),
+      _ => (),
+    }
+    InterruptFlag::empty()
+  }
+
+  fn run_clock_cycles(&mut self, cycles: ClockCycles) -> InterruptFlag {
+    SerialComms::run_clock_cycles(self, cycles)
+  }
+
+  fn cycles_until_next_event(&self) -> Option<ClockCycles> {
+    self.cycles_until_complete()
+  }
+
+  /// Clearing SC cancels a transfer in progress. The peer stays connected.
+  fn reset(&mut self) {
+    self.set_data(0);
+    self.set_control(0);
+  }
+
+  /// The connected peer isn't part of the state, and stays connected when a
+  /// state is loaded
+  fn save_state(&self, state: &mut StateWriter) {
+    state.u8(self.latch);
+    state.u8(self.control);
+    state.u64(self.clock);
+    state.bool(self.pending_transfer.is_some());
+    let (end, incoming) = self.pending_transfer.unwrap_or((0, 0));
+    state.u64(end);
+    state.u8(incoming);
+    state.bool(self.transfer_complete);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.latch = state.u8()?;
+    self.control = state.u8()?;
+    self.clock = state.u64()?;
+    let pending = state.bool()?;
+    let transfer = (state.u64()?, state.u8()?);
+    self.pending_transfer = if pending { Some(transfer) } else { None };
+    self.transfer_complete = state.bool()?;
+    Ok(())
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn Any {
+    self
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::SerialComms;
+  use crate::devices::interrupts::InterruptFlag;
+  use crate::devices::link::{LinkRecording, ReplayPeer, Unplugged};
+  use crate::timing::ClockCycles;
+  use alloc::boxed::Box;
+
+  #[test]
+  fn replayed_transfers() {
+    let recording = LinkRecording::deserialize("0 01 10 int\n4400 02 20 ext\n").unwrap();
+    let mut serial = SerialComms::new();
+    serial.connect(Box::new(ReplayPeer::new(recording)));
+
+    serial.set_data(0x01);
+    serial.set_control(0x81);
+    assert_eq!(serial.cycles_until_complete(), Some(ClockCycles(4096)));
+    assert_eq!(serial.run_clock_cycles(ClockCycles(4092)), InterruptFlag::empty());
+    assert_eq!(serial.get_control() & 0x80, 0x80);
+    assert_eq!(serial.run_clock_cycles(ClockCycles(4)), InterruptFlag::serial());
+    assert_eq!(serial.get_data(), 0x10);
+    assert_eq!(serial.get_control() & 0x80, 0);
+
+    serial.set_data(0x02);
+    serial.set_control(0x80);
+    assert_eq!(serial.run_clock_cycles(ClockCycles(200)), InterruptFlag::empty());
+    assert_eq!(serial.get_control() & 0x80, 0x80);
+    assert_eq!(serial.run_clock_cycles(ClockCycles(200)), InterruptFlag::serial());
+    assert_eq!(serial.get_data(), 0x20);
+  }
+
+  #[test]
+  fn transfer_timing() {
+    let mut serial = SerialComms::new();
+    serial.connect(Box::new(Unplugged));
+    // the CGB fast clock shifts each bit in 16 cycles
+    serial.set_control(0x83);
+    assert_eq!(serial.cycles_until_complete(), Some(ClockCycles(128)));
+    assert_eq!(serial.run_clock_cycles(ClockCycles(200)), InterruptFlag::serial());
+    assert_eq!(serial.get_data(), 0xff);
+    assert_eq!(serial.cycles_until_complete(), None);
+    // an external clock only runs when the peer drives it
+    serial.set_control(0x80);
+    assert_eq!(serial.cycles_until_complete(), None);
+    assert_eq!(serial.run_clock_cycles(ClockCycles(8192)), InterruptFlag::empty());
   }
 }
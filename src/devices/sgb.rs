@@ -0,0 +1,638 @@
+//! Super Game Boy commands. A game running on an SGB talks to the SNES through
+//! the joypad port, sending 16-byte packets one bit at a time by pulling P14
+//! or P15 low. The commands handled here color the screen with four palettes
+//! assigned to areas of it, draw a border around it, and read extra
+//! controllers. Larger data, like the border's tiles, is copied out of VRAM
+//! once the game has put it on screen.
+//!
+//! Only games that set the SGB flag in their header are given one, since
+//! others may write to P1 in ways that look like packets.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::savestate::{StateReader, StateWriter};
+use super::video::lcd::{LCD_HEIGHT, LCD_WIDTH};
+use super::video::palette::Palette;
+use super::video::VideoState;
+
+/// Size of the picture the SNES draws, with the border around the screen
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+/// Where the Game Boy screen sits within the border
+pub const SCREEN_X: usize = 48;
+pub const SCREEN_Y: usize = 40;
+
+const PACKET_BYTES: usize = 16;
+/// Bytes copied by a VRAM transfer, which is 256 tiles
+const TRANSFER_BYTES: usize = 0x1000;
+/// The screen is colored in 8x8 areas, each with one of the four palettes
+const ATTRIBUTE_WIDTH: usize = LCD_WIDTH / 8;
+const ATTRIBUTE_HEIGHT: usize = LCD_HEIGHT / 8;
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const PAL_SET: u8 = 0x0a;
+const PAL_TRN: u8 = 0x0b;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const MASK_EN: u8 = 0x17;
+
+/// Data waiting to be copied out of VRAM at the end of the frame
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Transfer {
+  /// 512 palettes that PAL_SET picks from
+  SystemPalettes,
+  /// Half of the border's 256 tiles, starting from tile 0 or tile 0x80
+  BorderTiles(usize),
+  /// The border's tile map, and its palettes
+  BorderMap,
+}
+
+/// What the SNES shows in place of the Game Boy screen, which games use to
+/// hide it while they set up a transfer
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mask {
+  None,
+  /// Keep showing the last frame
+  Freeze,
+  Black,
+  /// Fill the screen with the shared background color
+  Backdrop,
+}
+
+/// Everything needed to color a frame and draw the border around it. Colors
+/// are in the SNES format, five bits each of red, green, and blue.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SgbScreen {
+  /// The four palettes the screen is colored with. Color 0 of the first one
+  /// is shared by all of them, and shows through the border.
+  palettes: [[u16; 4]; 4],
+  /// Palette of each 8x8 area of the screen
+  attributes: Vec<u8>,
+  /// 256 tiles of four bits per pixel, in the SNES layout
+  border_tiles: Vec<u8>,
+  /// 32x28 tile map entries, followed by palettes 4-7 of the border. Empty
+  /// until the game sends a border.
+  border_map: Vec<u8>,
+  mask: Mask,
+  /// The screen as it was when the game froze it
+  frozen: Option<Vec<u8>>,
+}
+
+impl SgbScreen {
+  pub fn new() -> Self {
+    Self {
+      palettes: [[0x7fff, 0x56b5, 0x294a, 0x0000]; 4],
+      attributes: vec![0; ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT],
+      border_tiles: vec![0; TRANSFER_BYTES * 2],
+      border_map: Vec::new(),
+      mask: Mask::None,
+      frozen: None,
+    }
+  }
+
+  pub fn has_border(&self) -> bool {
+    !self.border_map.is_empty()
+  }
+
+  pub fn get_mask(&self) -> Mask {
+    self.mask
+  }
+
+  /// Width and height of the picture, which only includes the border once
+  /// the game has sent one
+  pub fn size(&self) -> (usize, usize) {
+    if self.has_border() {
+      (SGB_WIDTH, SGB_HEIGHT)
+    } else {
+      (LCD_WIDTH, LCD_HEIGHT)
+    }
+  }
+
+  /// Color an LCD buffer of shades, and place it in the border if there is
+  /// one. The output is one 0RGB pixel per u32, at the size returned by
+  /// `size`.
+  pub fn render(&self, lcd: &[u8], output: &mut Vec<u32>) {
+    let (width, height) = self.size();
+    output.clear();
+    output.resize(width * height, 0);
+    let backdrop = to_rgb(self.palettes[0][0]);
+    let (screen_x, screen_y) = if self.has_border() {
+      self.draw_border(output, backdrop);
+      (SCREEN_X, SCREEN_Y)
+    } else {
+      (0, 0)
+    };
+    let lcd = self.frozen.as_deref().unwrap_or(lcd);
+    for y in 0..LCD_HEIGHT {
+      let row = &mut output[(screen_y + y) * width + screen_x..][..LCD_WIDTH];
+      for (x, pixel) in row.iter_mut().enumerate() {
+        *pixel = match self.mask {
+          Mask::Black => 0,
+          Mask::Backdrop => backdrop,
+          _ => {
+            let palette = self.attributes[(y / 8) * ATTRIBUTE_WIDTH + x / 8] as usize;
+            let shade = Palette::shade_index(lcd[y * LCD_WIDTH + x]);
+            // the first color is shared by all of the palettes
+            match shade {
+              0 => backdrop,
+              _ => to_rgb(self.palettes[palette][shade]),
+            }
+          },
+        };
+      }
+    }
+  }
+
+  fn draw_border(&self, output: &mut [u32], backdrop: u32) {
+    for cell_y in 0..(SGB_HEIGHT / 8) {
+      for cell_x in 0..(SGB_WIDTH / 8) {
+        let entry = read_u16(&self.border_map, cell_y * 32 + cell_x);
+        let tile = &self.border_tiles[(entry & 0xff) as usize * 32..][..32];
+        let palette = ((entry >> 10) & 7) as usize;
+        for row in 0..8 {
+          let tile_row = if entry & 0x8000 != 0 { 7 - row } else { row };
+          let planes = [
+            tile[tile_row * 2],
+            tile[tile_row * 2 + 1],
+            tile[tile_row * 2 + 16],
+            tile[tile_row * 2 + 17],
+          ];
+          for column in 0..8 {
+            let bit = if entry & 0x4000 != 0 { column } else { 7 - column };
+            let color = planes
+              .iter()
+              .enumerate()
+              .fold(0, |color, (plane, byte)| color | (((byte >> bit) & 1) as usize) << plane);
+            // color 0 is see-through, and palettes 0-3 belong to the screen
+            let rgb = match palette {
+              4..=7 if color != 0 => {
+                to_rgb(read_u16(&self.border_map, 0x400 + (palette - 4) * 16 + color))
+              },
+              _ => backdrop,
+            };
+            output[(cell_y * 8 + row) * SGB_WIDTH + cell_x * 8 + column] = rgb;
+          }
+        }
+      }
+    }
+  }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    for color in self.palettes.iter().flatten() {
+      state.u16(*color);
+    }
+    state.bytes(&self.attributes);
+    state.bytes(&self.border_tiles);
+    state.bytes(&self.border_map);
+    state.u8(self.mask as u8);
+    match self.frozen.as_ref() {
+      Some(frozen) => state.bytes(frozen),
+      None => state.bytes(&[]),
+    }
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    for color in self.palettes.iter_mut().flatten() {
+      *color = state.u16()?;
+    }
+    state.bytes_into(&mut self.attributes)?;
+    state.bytes_into(&mut self.border_tiles)?;
+    self.border_map = state.bytes()?.to_vec();
+    self.mask = mask_from_u8(state.u8()?);
+    let frozen = state.bytes()?;
+    self.frozen = if frozen.is_empty() { None } else { Some(frozen.to_vec()) };
+    Ok(())
+  }
+}
+
+impl Default for SgbScreen {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+pub struct Sgb {
+  /// The packet being received, and the number of bits received so far. A
+  /// packet ends with a stop bit after its 128 data bits.
+  packet: [u8; PACKET_BYTES],
+  bits_received: Option<usize>,
+  /// Packets of a command that spans several of them, which runs once they
+  /// have all arrived
+  command: Vec<u8>,
+  packets_remaining: usize,
+  /// P14 and P15 from the last write to P1
+  lines: u8,
+  /// Controllers being read, and which one P1 returns
+  players: u8,
+  current_player: u8,
+  /// Palettes copied over by PAL_TRN, four colors each
+  system_palettes: Vec<u8>,
+  pending_transfer: Option<Transfer>,
+  screen: SgbScreen,
+}
+
+impl Sgb {
+  pub fn new() -> Self {
+    Self {
+      packet: [0; PACKET_BYTES],
+      bits_received: None,
+      command: Vec::new(),
+      packets_remaining: 0,
+      lines: 0x30,
+      players: 1,
+      current_player: 0,
+      system_palettes: vec![0; TRANSFER_BYTES],
+      pending_transfer: None,
+      screen: SgbScreen::new(),
+    }
+  }
+
+  pub fn get_screen(&self) -> &SgbScreen {
+    &self.screen
+  }
+
+  /// Watch a write to P1 for the pulses that make up a packet. Pulling both
+  /// lines low starts a packet, then each bit is sent by pulling P14 low for
+  /// a 0 or P15 low for a 1, with both lines going high in between.
+  pub fn write_joypad(&mut self, value: u8) {
+    let lines = value & 0x30;
+    let previous = core::mem::replace(&mut self.lines, lines);
+    if lines == 0 {
+      self.packet = [0; PACKET_BYTES];
+      self.bits_received = Some(0);
+      return;
+    }
+    let received = match self.bits_received {
+      Some(received) => received,
+      None => {
+        // With several controllers, each full read of the buttons moves on
+        // to the next one
+        if self.players > 1 && previous & 0x20 == 0 && lines == 0x30 {
+          self.current_player = (self.current_player + 1) % self.players;
+        }
+        return;
+      },
+    };
+    if previous != 0x30 || lines == 0x30 {
+      return;
+    }
+    let bit = lines == 0x10;
+    if received == PACKET_BYTES * 8 {
+      // a 1 in place of the stop bit throws the packet away
+      self.bits_received = None;
+      if !bit {
+        self.receive_packet();
+      }
+      return;
+    }
+    if bit {
+      self.packet[received / 8] |= 1 << (received % 8);
+    }
+    self.bits_received = Some(received + 1);
+  }
+
+  /// Reading P1 with neither set of buttons selected returns the current
+  /// controller, once the game has asked for more than one. Buttons only
+  /// come from the first.
+  pub fn read_joypad(&self, value: u8) -> u8 {
+    if self.players == 1 {
+      value
+    } else if value & 0x30 == 0x30 {
+      (value & 0xf0) | (0x0f - self.current_player)
+    } else if self.current_player != 0 {
+      value | 0x0f
+    } else {
+      value
+    }
+  }
+
+  fn receive_packet(&mut self) {
+    if self.packets_remaining == 0 {
+      self.command.clear();
+      self.packets_remaining = ((self.packet[0] & 7) as usize).max(1);
+    }
+    self.command.extend_from_slice(&self.packet);
+    self.packets_remaining -= 1;
+    if self.packets_remaining == 0 {
+      let command = core::mem::take(&mut self.command);
+      self.run_command(&command);
+    }
+  }
+
+  fn run_command(&mut self, data: &[u8]) {
+    match data[0] >> 3 {
+      PAL01 => self.set_palette_pair(data, 0, 1),
+      PAL23 => self.set_palette_pair(data, 2, 3),
+      PAL03 => self.set_palette_pair(data, 0, 3),
+      PAL12 => self.set_palette_pair(data, 1, 2),
+      ATTR_BLK => {
+        let count = (data[1] as usize).min((data.len() - 2) / 6);
+        for block in data[2..].chunks_exact(6).take(count) {
+          self.set_block_attributes(block);
+        }
+      },
+      PAL_SET => {
+        for (index, palette) in self.screen.palettes.iter_mut().enumerate() {
+          let system_palette = (read_u16(&data[1..], index) & 0x1ff) as usize;
+          for (color, value) in palette.iter_mut().enumerate() {
+            *value = read_u16(&self.system_palettes, system_palette * 4 + color);
+          }
+        }
+        // attribute files aren't supported, but the mask can still be lifted
+        if data[9] & 0x40 != 0 {
+          self.set_mask(Mask::None);
+        }
+      },
+      PAL_TRN => self.pending_transfer = Some(Transfer::SystemPalettes),
+      MLT_REQ => {
+        self.players = match data[1] & 3 {
+          1 => 2,
+          3 => 4,
+          _ => 1,
+        };
+        self.current_player = 0;
+      },
+      CHR_TRN => self.pending_transfer = Some(Transfer::BorderTiles((data[1] & 1) as usize)),
+      PCT_TRN => self.pending_transfer = Some(Transfer::BorderMap),
+      MASK_EN => self.set_mask(mask_from_u8(data[1])),
+      // sound, attribute files, and SNES code are left to the SNES
+      _ => (),
+    }
+  }
+
+  /// Colors 1-3 of two palettes, along with the color they all share
+  fn set_palette_pair(&mut self, data: &[u8], first: usize, second: usize) {
+    let shared = read_u16(&data[1..], 0);
+    for palette in self.screen.palettes.iter_mut() {
+      palette[0] = shared;
+    }
+    for color in 1..4 {
+      self.screen.palettes[first][color] = read_u16(&data[1..], color);
+      self.screen.palettes[second][color] = read_u16(&data[1..], color + 3);
+    }
+  }
+
+  /// Assign palettes to the areas inside, on the edge of, and outside of a
+  /// rectangle. An area whose flag is set, but not the edge's, also takes
+  /// the edge with it.
+  fn set_block_attributes(&mut self, block: &[u8]) {
+    let control = block[0] & 7;
+    let inside = block[1] & 3;
+    let edge = (block[1] >> 2) & 3;
+    let outside = (block[1] >> 4) & 3;
+    let edge = match control {
+      1 => Some(inside),
+      4 => Some(outside),
+      _ if control & 2 != 0 => Some(edge),
+      _ => None,
+    };
+    let (left, top) = (block[2] as usize, block[3] as usize);
+    let (right, bottom) = (block[4] as usize, block[5] as usize);
+    for y in 0..ATTRIBUTE_HEIGHT {
+      for x in 0..ATTRIBUTE_WIDTH {
+        let within = (left..=right).contains(&x) && (top..=bottom).contains(&y);
+        let on_edge = within && (x == left || x == right || y == top || y == bottom);
+        let palette = if on_edge {
+          edge
+        } else if within {
+          (control & 1 != 0).then_some(inside)
+        } else {
+          (control & 4 != 0).then_some(outside)
+        };
+        if let Some(palette) = palette {
+          self.screen.attributes[y * ATTRIBUTE_WIDTH + x] = palette;
+        }
+      }
+    }
+  }
+
+  fn set_mask(&mut self, mask: Mask) {
+    self.screen.mask = mask;
+    if mask != Mask::Freeze {
+      self.screen.frozen = None;
+    }
+  }
+
+  /// Once a frame has been drawn, copy the data for any transfer out of
+  /// VRAM, and hold onto the frame if the screen has just been frozen.
+  ///
+  /// The SNES reads transfers from the picture itself, as 256 tiles laid out
+  /// across the screen in order. Rather than decoding them back from the
+  /// shades, the same tiles are read from VRAM through the background map,
+  /// which is how games set them up.
+  pub fn end_frame(&mut self, video: &VideoState, video_ram: &[u8]) {
+    if self.screen.mask == Mask::Freeze && self.screen.frozen.is_none() {
+      self.screen.frozen = Some(video.get_visible_buffer().to_vec());
+    }
+    let transfer = match self.pending_transfer.take() {
+      Some(transfer) => transfer,
+      None => return,
+    };
+    let map_offset = if video.get_lcd_control() & 0x08 == 0 { 0x1800 } else { 0x1c00 };
+    let mut data = Vec::with_capacity(TRANSFER_BYTES);
+    for index in 0..(TRANSFER_BYTES / 16) {
+      let (x, y) = (index % ATTRIBUTE_WIDTH, index / ATTRIBUTE_WIDTH);
+      let tile = video_ram[map_offset + y * 32 + x] as usize;
+      let address = video.get_tile_address(tile);
+      data.extend_from_slice(&video_ram[address..(address + 16)]);
+    }
+    match transfer {
+      Transfer::SystemPalettes => self.system_palettes = data,
+      Transfer::BorderTiles(half) => {
+        self.screen.border_tiles[(half * TRANSFER_BYTES)..][..TRANSFER_BYTES].copy_from_slice(&data);
+      },
+      Transfer::BorderMap => {
+        data.truncate(0x880);
+        self.screen.border_map = data;
+      },
+    }
+  }
+
+  pub fn save_state(&self, state: &mut StateWriter) {
+    state.bytes(&self.packet);
+    state.u8(self.bits_received.map_or(0xff, |bits| bits as u8));
+    state.bytes(&self.command);
+    state.u8(self.packets_remaining as u8);
+    state.u8(self.lines);
+    state.u8(self.players);
+    state.u8(self.current_player);
+    state.bytes(&self.system_palettes);
+    let transfer = match self.pending_transfer {
+      None => 0,
+      Some(Transfer::SystemPalettes) => 1,
+      Some(Transfer::BorderTiles(half)) => 2 + half as u8,
+      Some(Transfer::BorderMap) => 4,
+    };
+    state.u8(transfer);
+    self.screen.save_state(state);
+  }
+
+  pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    state.bytes_into(&mut self.packet)?;
+    self.bits_received = match state.u8()? {
+      0xff => None,
+      bits => Some(bits as usize),
+    };
+    self.command = state.bytes()?.to_vec();
+    self.packets_remaining = state.u8()? as usize;
+    self.lines = state.u8()?;
+    self.players = state.u8()?;
+    self.current_player = state.u8()?;
+    state.bytes_into(&mut self.system_palettes)?;
+    self.pending_transfer = match state.u8()? {
+      1 => Some(Transfer::SystemPalettes),
+      2 => Some(Transfer::BorderTiles(0)),
+      3 => Some(Transfer::BorderTiles(1)),
+      4 => Some(Transfer::BorderMap),
+      _ => None,
+    };
+    self.screen.load_state(state)
+  }
+}
+
+impl Default for Sgb {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+fn mask_from_u8(value: u8) -> Mask {
+  match value & 3 {
+    1 => Mask::Freeze,
+    2 => Mask::Black,
+    3 => Mask::Backdrop,
+    _ => Mask::None,
+  }
+}
+
+/// The little-endian u16 at `index` in a list of them
+fn read_u16(data: &[u8], index: usize) -> u16 {
+  data[index * 2] as u16 | ((data[index * 2 + 1] as u16) << 8)
+}
+
+/// Convert an SNES color to 0RGB
+fn to_rgb(color: u16) -> u32 {
+  let channel = |shift: u16| {
+    let value = ((color >> shift) & 0x1f) as u32;
+    (value << 3) | (value >> 2)
+  };
+  (channel(0) << 16) | (channel(5) << 8) | channel(10)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Mask, Sgb, LCD_HEIGHT, LCD_WIDTH, SCREEN_X, SCREEN_Y, SGB_HEIGHT, SGB_WIDTH};
+  use crate::devices::video::VideoState;
+  use alloc::vec;
+  use alloc::vec::Vec;
+
+  fn send_packet(sgb: &mut Sgb, packet: &[u8]) {
+    send_bits(sgb, packet, false);
+  }
+
+  fn send_bits(sgb: &mut Sgb, packet: &[u8], stop: bool) {
+    let mut bytes = [0; 16];
+    bytes[..packet.len()].copy_from_slice(packet);
+    sgb.write_joypad(0x00);
+    sgb.write_joypad(0x30);
+    for index in 0..128 {
+      let bit = (bytes[index / 8] >> (index % 8)) & 1;
+      sgb.write_joypad(if bit == 1 { 0x10 } else { 0x20 });
+      sgb.write_joypad(0x30);
+    }
+    sgb.write_joypad(if stop { 0x10 } else { 0x20 });
+    sgb.write_joypad(0x30);
+  }
+
+  #[test]
+  fn palettes_and_attributes() {
+    let mut sgb = Sgb::new();
+    // PAL01: a shared white, then reds for palette 0 and blues for palette 1
+    send_packet(&mut sgb, &[
+      0x01, 0xff, 0x7f,
+      0x1f, 0x00, 0x10, 0x00, 0x08, 0x00,
+      0x00, 0x7c, 0x00, 0x40, 0x00, 0x20,
+    ]);
+    // ATTR_BLK: palette 1 inside and on the edge of the top left 2x2 areas
+    send_packet(&mut sgb, &[0x21, 0x01, 0x03, 0x05, 0x00, 0x00, 0x01, 0x01]);
+    let screen = sgb.get_screen();
+    assert!(!screen.has_border());
+    assert_eq!(screen.size(), (LCD_WIDTH, LCD_HEIGHT));
+
+    let mut lcd = vec![0u8; LCD_WIDTH * LCD_HEIGHT];
+    lcd[LCD_WIDTH] = 255;
+    let mut output = Vec::new();
+    screen.render(&lcd, &mut output);
+    assert_eq!(output.len(), LCD_WIDTH * LCD_HEIGHT);
+    assert_eq!(output[0], 0x000042);
+    assert_eq!(output[LCD_WIDTH], 0xffffff);
+    assert_eq!(output[16], 0x420000);
+    assert_eq!(output[LCD_WIDTH * 16], 0x420000);
+
+    // MASK_EN, then another one that is cancelled by a 1 in place of its
+    // stop bit
+    send_packet(&mut sgb, &[0xb9, 0x02]);
+    send_bits(&mut sgb, &[0xb9, 0x03], true);
+    assert_eq!(sgb.get_screen().get_mask(), Mask::Black);
+  }
+
+  #[test]
+  fn multiple_controllers() {
+    let mut sgb = Sgb::new();
+    assert_eq!(sgb.read_joypad(0x3f), 0x3f);
+    // MLT_REQ for two players
+    send_packet(&mut sgb, &[0x89, 0x01]);
+    assert_eq!(sgb.read_joypad(0x3f), 0x3f);
+    // reading the buttons moves on to the next controller
+    for value in [0x20, 0x10, 0x30] {
+      sgb.write_joypad(value);
+    }
+    assert_eq!(sgb.read_joypad(0x3f), 0x3e);
+    assert_eq!(sgb.read_joypad(0x1e), 0x1f);
+    for value in [0x20, 0x10, 0x30] {
+      sgb.write_joypad(value);
+    }
+    assert_eq!(sgb.read_joypad(0x3f), 0x3f);
+  }
+
+  #[test]
+  fn border_transfer() {
+    let mut sgb = Sgb::new();
+    let mut video = VideoState::new();
+    video.set_lcd_control(0x91);
+    let mut video_ram = vec![0u8; 0x2000];
+    for (index, tile) in video_ram[0x1800..0x1c00].iter_mut().enumerate() {
+      *tile = ((index / 32) * 20 + index % 32) as u8;
+    }
+    // CHR_TRN: tile 1 of the border is color 1 in its first row
+    video_ram[32] = 0xff;
+    send_packet(&mut sgb, &[0x99, 0x00]);
+    sgb.end_frame(&video, &video_ram);
+    // PCT_TRN: every map entry uses tile 1 and palette 4, whose color 1 is
+    // pure red
+    video_ram[..0x1000].fill(0);
+    for entry in video_ram[..0x700].chunks_exact_mut(2) {
+      entry.copy_from_slice(&[0x01, 0x10]);
+    }
+    video_ram[0x802] = 0x1f;
+    send_packet(&mut sgb, &[0xa1]);
+    sgb.end_frame(&video, &video_ram);
+
+    let screen = sgb.get_screen();
+    assert!(screen.has_border());
+    let lcd = vec![0u8; LCD_WIDTH * LCD_HEIGHT];
+    let mut output = Vec::new();
+    screen.render(&lcd, &mut output);
+    assert_eq!(output.len(), SGB_WIDTH * SGB_HEIGHT);
+    assert_eq!(output[0], 0xff0000);
+    // the rest of the tile is see-through
+    assert_eq!(output[SGB_WIDTH], 0xffffff);
+    assert_eq!(output[SCREEN_Y * SGB_WIDTH + SCREEN_X], 0);
+  }
+}
@@ -0,0 +1,138 @@
+//! Strict IO mode, for finding registers that games rely on but the emulator
+//! does not model yet. Normally, accesses to these registers are silently
+//! ignored. In strict mode, each one is recorded along with the instruction
+//! pointer, so a test run can fail with a summary of what was touched.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+
+/// Returns true for IO registers (0xff00-0xff7f) that exist on hardware but
+/// are not implemented by any device yet. Addresses with no hardware register
+/// behind them are not included, since ignoring those is the correct behavior.
+/// As devices are implemented, their registers should be removed from here.
+pub fn is_unmodeled(addr: u16) -> bool {
+  matches!(
+    addr & 0xff,
+    0x10..=0x26 // sound channels and control
+      | 0x30..=0x3f // wave pattern RAM
+      | 0x4d // CGB speed switch
+      | 0x4f // CGB VRAM bank
+      | 0x56 // CGB infrared port
+      | 0x68..=0x6c // CGB palettes and object priority
+      | 0x70 // CGB WRAM bank
+      | 0x72..=0x77 // undocumented CGB registers
+  )
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UnmodeledAccess {
+  pub address: u16,
+  /// Instruction pointer at the time of the access. In compiled code, this is
+  /// the start of the block containing the access.
+  pub ip: u16,
+  pub write: bool,
+  /// Number of identical accesses
+  pub count: usize,
+}
+
+/// Memory reads only have shared access to the bus, so the log uses interior
+/// mutability
+pub struct StrictIo {
+  current_ip: Cell<u16>,
+  accesses: RefCell<Vec<UnmodeledAccess>>,
+}
+
+impl StrictIo {
+  pub fn new() -> Self {
+    Self {
+      current_ip: Cell::new(0),
+      accesses: RefCell::new(Vec::new()),
+    }
+  }
+
+  pub fn set_ip(&self, ip: u16) {
+    self.current_ip.set(ip);
+  }
+
+  /// Record an access if it hits an unmodeled register
+  pub fn check_access(&self, address: u16, write: bool) {
+    if !is_unmodeled(address) {
+      return;
+    }
+    let ip = self.current_ip.get();
+    let mut accesses = self.accesses.borrow_mut();
+    let existing = accesses
+      .iter_mut()
+      .find(|a| a.address == address && a.ip == ip && a.write == write);
+    match existing {
+      Some(access) => access.count += 1,
+      None => accesses.push(UnmodeledAccess { address, ip, write, count: 1 }),
+    }
+  }
+
+  pub fn get_accesses(&self) -> Vec<UnmodeledAccess> {
+    self.accesses.borrow().clone()
+  }
+
+  /// Returns a summary of every unmodeled access as an error, if any occurred
+  pub fn check(&self) -> Result<(), String> {
+    let accesses = self.accesses.borrow();
+    if accesses.is_empty() {
+      return Ok(());
+    }
+    let mut summary = format!("{} unmodeled IO register access(es):", accesses.len());
+    for access in accesses.iter() {
+      summary.push_str(&format!(
+        "\n  {} {:#06X} at {:#06X} ({}x)",
+        if access.write { "write" } else { "read " },
+        access.address,
+        access.ip,
+        access.count,
+      ));
+    }
+    Err(summary)
+  }
+}
+
+impl Default for StrictIo {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{is_unmodeled, StrictIo, UnmodeledAccess};
+  use alloc::vec;
+
+  #[test]
+  fn unmodeled_registers() {
+    assert!(is_unmodeled(0xff26));
+    assert!(is_unmodeled(0xff4f));
+    assert!(!is_unmodeled(0xff40));
+    assert!(!is_unmodeled(0xff03));
+  }
+
+  #[test]
+  fn records_accesses() {
+    let strict = StrictIo::new();
+    strict.check_access(0xff40, true);
+    assert!(strict.check().is_ok());
+    strict.set_ip(0x150);
+    strict.check_access(0xff26, true);
+    strict.check_access(0xff26, true);
+    strict.set_ip(0x200);
+    strict.check_access(0xff70, false);
+    assert_eq!(
+      strict.get_accesses(),
+      vec![
+        UnmodeledAccess { address: 0xff26, ip: 0x150, write: true, count: 2 },
+        UnmodeledAccess { address: 0xff70, ip: 0x200, write: false, count: 1 },
+      ],
+    );
+    let summary = strict.check().unwrap_err();
+    assert!(summary.contains("write 0xFF26 at 0x0150 (2x)"));
+  }
+}
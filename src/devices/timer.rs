@@ -1,4 +1,8 @@
+use alloc::string::String;
+use core::any::Any;
+use crate::savestate::{StateReader, StateWriter};
 use crate::timing::ClockCycles;
+use super::IoDevice;
 use super::interrupts::InterruptFlag;
 
 pub struct Timer {
@@ -18,8 +22,20 @@ pub struct Timer {
   enabled_mask: u32,
   timer_clock_mask: u32,
   control_value: u8,
+  /// When the timer counter overflows, it reads 0 for one machine cycle
+  /// before it is reloaded from the modulo and the interrupt is raised. This
+  /// counts the clock cycles left until then. Writing to the counter during
+  /// that cycle cancels the reload.
+  reload_delay: u32,
+  /// Clock cycles left in the machine cycle where the modulo is loaded. For
+  /// its duration, writes to the counter are ignored, and writes to the
+  /// modulo also go to the counter.
+  reload_window: u32,
 }
 
+/// Clock cycles between the counter overflowing and the modulo being loaded
+const RELOAD_DELAY: u32 = 4;
+
 impl Timer {
   pub fn new() -> Self {
     Self {
@@ -27,13 +43,21 @@ impl Timer {
       counter: 0,
       modulo: 0,
       enabled_mask: 0,
-      timer_clock_mask: 0,
+      timer_clock_mask: 1 << 9,
       control_value: 0,
+      reload_delay: 0,
+      reload_window: 0,
     }
   }
 
+  /// Writing to DIV clears the whole internal counter. If the bit watched by
+  /// the timer was high, clearing it is seen as a falling edge.
   pub fn reset_divider(&mut self) {
+    let masked_bit = self.cycle_count & self.timer_clock_mask & self.enabled_mask;
     self.cycle_count = 0;
+    if masked_bit != 0 {
+      self.increment_counter();
+    }
   }
 
   pub fn get_divider(&self) -> u8 {
@@ -56,6 +80,12 @@ impl Timer {
   }
 
   pub fn set_counter(&mut self, value: u8) {
+    if self.reload_window > 0 {
+      // the modulo is being loaded, and takes precedence
+      return;
+    }
+    // writing during the overflow delay cancels the reload and interrupt
+    self.reload_delay = 0;
     self.counter = value;
   }
 
@@ -63,18 +93,27 @@ impl Timer {
     self.counter
   }
 
-  fn increment_counter(&mut self) -> InterruptFlag {
+  fn increment_counter(&mut self) {
     if self.counter == 0xff {
-      self.counter = self.modulo;
-      InterruptFlag::timer()
+      // the counter reads 0 until the modulo is loaded
+      self.counter = 0;
+      self.reload_delay = RELOAD_DELAY;
     } else {
-      self.counter = self.counter.wrapping_add(1);
-      InterruptFlag::empty()
+      self.counter += 1;
     }
   }
 
+  fn reload_counter(&mut self) -> InterruptFlag {
+    self.counter = self.modulo;
+    self.reload_window = 4;
+    InterruptFlag::timer()
+  }
+
   pub fn set_modulo(&mut self, value: u8) {
     self.modulo = value;
+    if self.reload_window > 0 {
+      self.counter = value;
+    }
   }
 
   pub fn get_modulo(&self) -> u8 {
@@ -107,12 +146,13 @@ impl Timer {
       },
     };
 
-    // Check if changing the mask or enabled flag triggered a falling edge
-    if old_masked_bit != 0 {
+    // Check if changing the mask or enabled flag triggered a falling edge.
+    // Few games rely on this, so it can be left out of fast builds.
+    if cfg!(feature = "timer-accurate") && old_masked_bit != 0 {
       let new_masked_bit = self.cycle_count & self.timer_clock_mask & self.enabled_mask;
       if new_masked_bit == 0 {
         // This will be seen as a falling edge
-        return self.increment_counter();
+        self.increment_counter();
       }
     }
 
@@ -125,28 +165,110 @@ impl Timer {
 
   pub fn run_cycles(&mut self, clock_cycles: ClockCycles) -> InterruptFlag {
     let cycles = clock_cycles.as_u32();
-    if self.enabled_mask == 0 {
+    if self.enabled_mask == 0 && self.reload_delay == 0 && self.reload_window == 0 {
       // skip the edge checking
       self.cycle_count += cycles;
       self.cycle_count &= 0xffff;
       return InterruptFlag::empty();
     }
+    self.run_edges(cycles)
+  }
+
+  /// Jump from one event to the next, rather than stepping through each
+  /// clock cycle. Events are falling edges of the watched bit, and the steps
+  /// of reloading the counter after it overflows.
+  fn run_edges(&mut self, cycles: u32) -> InterruptFlag {
+    let period = self.timer_clock_mask << 1;
     let mut flag = InterruptFlag::empty();
-    let mut to_increment = cycles;
-    while to_increment > 0 {
-      let mask_prev = self.cycle_count & self.timer_clock_mask;
-      self.cycle_count += 1;
-      let mask_new = self.cycle_count & self.timer_clock_mask;
-      if mask_prev != 0 && mask_new == 0 {
-        flag |= self.increment_counter();
+    let mut remaining = cycles;
+    while remaining > 0 {
+      let mut step = remaining;
+      if self.enabled_mask != 0 {
+        step = step.min(period - (self.cycle_count & (period - 1)));
+      }
+      if self.reload_delay > 0 {
+        step = step.min(self.reload_delay);
+      }
+      if self.reload_window > 0 {
+        step = step.min(self.reload_window);
+      }
+      self.cycle_count = (self.cycle_count + step) & 0xffff;
+      remaining -= step;
+      // the reload window and the delay can't both be running
+      self.reload_window = self.reload_window.saturating_sub(step);
+      if self.reload_delay > 0 {
+        self.reload_delay -= step;
+        if self.reload_delay == 0 {
+          flag |= self.reload_counter();
+        }
+      }
+      if self.enabled_mask != 0 && self.cycle_count & (period - 1) == 0 {
+        self.increment_counter();
       }
-      to_increment -= 1;
     }
-    self.cycle_count &= 0xffff;
     flag
   }
 }
 
+impl IoDevice for Timer {
+  fn read(&self, register: u8) -> u8 {
+    match register {
+      0x04 => self.get_divider(),
+      0x05 => self.get_counter(),
+      0x06 => self.get_modulo(),
+      // only the low three bits of TAC are used
+      0x07 => self.get_timer_control() | 0xf8,
+      _ => 0xff,
+    }
+  }
+
+  fn write(&mut self, register: u8, value: u8) -> InterruptFlag {
+    match register {
+      0x04 => self.reset_divider(),
+      0x05 => self.set_counter(value),
+      0x06 => self.set_modulo(value),
+      0x07 => return self.set_timer_control(value),
+      _ => (),
+    }
+    InterruptFlag::empty()
+  }
+
+  fn run_clock_cycles(&mut self, cycles: ClockCycles) -> InterruptFlag {
+    self.run_cycles(cycles)
+  }
+
+  fn reset(&mut self) {
+    *self = Timer::new();
+  }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    state.u32(self.cycle_count);
+    state.u8(self.counter);
+    state.u8(self.modulo);
+    state.u8(self.control_value);
+    state.u8(self.reload_delay as u8);
+    state.u8(self.reload_window as u8);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    let cycle_count = state.u32()?;
+    self.counter = state.u8()?;
+    self.modulo = state.u8()?;
+    // the divider is cleared while TAC is restored, so that it can't be
+    // mistaken for a falling edge
+    self.cycle_count = 0;
+    self.set_timer_control(state.u8()?);
+    self.cycle_count = cycle_count & 0xffff;
+    self.reload_delay = (state.u8()? as u32).min(RELOAD_DELAY);
+    self.reload_window = (state.u8()? as u32).min(4);
+    Ok(())
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn Any {
+    self
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::{ClockCycles, InterruptFlag, Timer};
@@ -164,6 +286,7 @@ mod tests {
     assert_eq!(timer.get_counter(), 1);
   }
 
+  #[cfg(feature = "timer-accurate")]
   #[test]
   fn timer_resolution_glitch() {
     let mut timer = Timer::new();
@@ -174,6 +297,7 @@ mod tests {
     assert_eq!(timer.get_counter(), 1);
   }
 
+  #[cfg(feature = "timer-accurate")]
   #[test]
   fn timer_disable_glitch() {
     let mut timer = Timer::new();
@@ -191,7 +315,70 @@ mod tests {
     timer.set_timer_control(5);
     assert_eq!(timer.run_cycles(ClockCycles(255 * 16)), InterruptFlag::empty());
     assert_eq!(timer.get_counter(), 255);
-    assert_eq!(timer.run_cycles(ClockCycles(16)), InterruptFlag::timer());
+    // the counter reads 0 for a machine cycle before the modulo is loaded
+    assert_eq!(timer.run_cycles(ClockCycles(16)), InterruptFlag::empty());
+    assert_eq!(timer.get_counter(), 0);
+    assert_eq!(timer.run_cycles(ClockCycles(4)), InterruptFlag::timer());
+    assert_eq!(timer.get_counter(), 200);
+  }
+
+  #[test]
+  fn overflow_writes() {
+    let mut timer = Timer::new();
+    timer.set_modulo(200);
+    timer.set_timer_control(5);
+    timer.set_counter(0xff);
+    // writing the counter during the delay cancels the reload
+    timer.run_cycles(ClockCycles(16));
+    timer.set_counter(50);
+    assert_eq!(timer.run_cycles(ClockCycles(4)), InterruptFlag::empty());
+    assert_eq!(timer.get_counter(), 50);
+
+    // while the modulo is loaded, writes to the counter are ignored, and
+    // writes to the modulo go through to the counter
+    timer.set_counter(0xff);
+    timer.run_cycles(ClockCycles(12));
+    assert_eq!(timer.run_cycles(ClockCycles(4)), InterruptFlag::timer());
+    timer.set_counter(10);
     assert_eq!(timer.get_counter(), 200);
+    timer.set_modulo(100);
+    assert_eq!(timer.get_counter(), 100);
+    timer.run_cycles(ClockCycles(4));
+    timer.set_counter(10);
+    assert_eq!(timer.get_counter(), 10);
+  }
+
+  #[test]
+  fn divider_reset_edge() {
+    let mut timer = Timer::new();
+    timer.set_timer_control(5);
+    timer.run_cycles(ClockCycles(8));
+    assert_eq!(timer.get_counter(), 0);
+    // bit 3 is high, so clearing it increments the counter
+    timer.reset_divider();
+    assert_eq!(timer.get_counter(), 1);
+    assert_eq!(timer.get_cycle_count(), 0);
+    timer.run_cycles(ClockCycles(4));
+    timer.reset_divider();
+    assert_eq!(timer.get_counter(), 1);
+  }
+
+  #[test]
+  fn edges_match_stepping() {
+    let mut stepped = Timer::new();
+    let mut counted = Timer::new();
+    stepped.set_modulo(0xf0);
+    counted.set_modulo(0xf0);
+    stepped.set_timer_control(5);
+    counted.set_timer_control(5);
+    for length in [4, 12, 28, 100, 7, 256, 4000] {
+      let mut flag = InterruptFlag::empty();
+      for _ in 0..length {
+        flag |= stepped.run_cycles(ClockCycles(1));
+      }
+      assert_eq!(counted.run_edges(length), flag);
+      assert_eq!(counted.get_counter(), stepped.get_counter());
+      assert_eq!(counted.get_cycle_count(), stepped.get_cycle_count());
+    }
   }
 }
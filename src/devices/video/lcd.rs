@@ -1,3 +1,8 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::savestate::{StateReader, StateWriter};
+
 pub struct LCD {
   visible_buffer: Box<[u8]>,
   writing_buffer: Box<[u8]>,
@@ -45,7 +50,14 @@ impl LCD {
   }
 
   pub fn swap_buffers(&mut self) {
-    std::mem::swap(&mut self.visible_buffer, &mut self.writing_buffer);
+    core::mem::swap(&mut self.visible_buffer, &mut self.writing_buffer);
+  }
+
+  /// Fill both buffers with a single shade, so that the screen is blank
+  /// right away
+  pub fn clear(&mut self, shade: u8) {
+    self.visible_buffer.fill(shade);
+    self.writing_buffer.fill(shade);
   }
 
   pub fn set_enabled(&mut self, enabled: bool) {
@@ -55,4 +67,19 @@ impl LCD {
   pub fn is_enabled(&self) -> bool {
     self.enabled
   }
+
+  /// Both buffers are saved, so that the restored frame is shown right away
+  /// and a frame in progress is finished where it left off
+  pub fn save_state(&self, state: &mut StateWriter) {
+    state.bytes(&self.visible_buffer);
+    state.bytes(&self.writing_buffer);
+    state.bool(self.enabled);
+  }
+
+  pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    state.bytes_into(&mut self.visible_buffer)?;
+    state.bytes_into(&mut self.writing_buffer)?;
+    self.enabled = state.bool()?;
+    Ok(())
+  }
 }
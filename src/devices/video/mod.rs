@@ -1,15 +1,28 @@
 pub mod lcd;
+pub mod palette;
+pub mod sprite;
 pub mod tile;
+pub mod views;
 
-use std::u8;
-
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
 use lcd::LCD;
-use crate::timing::ClockCycles;
+use palette::Palette;
+use crate::savestate::{StateReader, StateWriter};
+use crate::timing::{ClockCycles, FRAME_CYCLES};
 
 use super::interrupts::InterruptFlag;
 
 const SHADES: [u8; 4] = [255, 170, 85, 0];
 
+/// Without the `video` feature, the LCD keeps its timing and interrupts, but
+/// lines are never drawn
+const DRAW_LINES: bool = cfg!(feature = "video");
+
+/// Each frame is 154 lines of 456 dots
+const FRAME_DOTS: usize = FRAME_CYCLES as usize * 4;
+
 struct ObjectAttributes {
   pub palette: u8,
   pub x_coord: u8,
@@ -45,8 +58,16 @@ pub struct VideoState {
   current_mode: u8,
   current_mode_dots: usize,
   current_line: u8,
+  /// Number of BG tiles fetched so far on the current line. The fetcher
+  /// adds this to the coarse scroll from SCX at the moment of each fetch, so
+  /// writes to SCX partway through a line move the rest of the line.
   next_cached_tile_x: usize,
+  /// Number of window tiles fetched so far on the current line
+  next_cached_window_tile_x: usize,
   current_tile_cache: u16,
+  /// Pixels left to shift out of `current_tile_cache` before the next tile
+  /// is fetched
+  tile_pixels_remaining: usize,
   /// At the beginning of each line, the objects for that line are pre-cached.
   /// The pixels are drawn into this buffer, where each byte represents a
   /// single object pixel in the following 8-bit format:
@@ -64,7 +85,43 @@ pub struct VideoState {
   /// additional 8 pixels are added before and after the visible buffer.
   object_line_cache: [u8; 176],
   current_obj_line_cache_pixel: usize,
-  current_window_line: Option<usize>,
+  /// Set once the window has started drawing on the current line
+  drawing_window: bool,
+  /// Set if the window was drawn at any point on the current line
+  window_drawn_on_line: bool,
+  /// The window keeps its own line counter, which only advances on lines
+  /// where the window was drawn. Hiding the window for a few lines resumes
+  /// it where it left off, rather than skipping ahead.
+  window_line: usize,
+  /// Set once LY has matched WY during the current frame. From then on, the
+  /// window can be drawn on every line, even if WY changes.
+  window_y_triggered: bool,
+  /// While set, timing and interrupts run as usual but nothing is drawn, and
+  /// the last frame that was drawn stays visible
+  skip_rendering: bool,
+  /// While the LCD is off, the PPU is stopped, but dots are still counted so
+  /// that frames keep completing at the usual rate
+  lcd_off_dots: usize,
+  /// Incremented every time a frame completes, at the end of vblank or every
+  /// 70224 dots while the LCD is off. It wraps around, and is only useful for
+  /// noticing that a new frame has started.
+  frame_count: u32,
+  /// Set when the LCD is turned on, until the end of the first OAM search.
+  /// The first line after turning on doesn't report mode 2, and leaves OAM
+  /// unlocked.
+  lcd_starting: bool,
+  /// The first frame after turning the LCD on isn't shown, so the screen
+  /// stays blank for an extra frame
+  hide_next_frame: bool,
+  /// Number of H-blank periods entered since the last call to
+  /// `take_hblank_entries`. A single catch-up can cover several lines, and
+  /// H-blank DMA needs to copy a block for each of them.
+  hblank_entries: u8,
+  /// The line on which the most recent H-blank period began
+  last_hblank_line: u8,
+  /// Colors that the LCD's shades are shown with. This is a display setting,
+  /// so it isn't part of save states.
+  palette: Palette,
 }
 
 impl VideoState {
@@ -78,8 +135,10 @@ impl VideoState {
       object_double_height: false,
       object_enabled: false,
       window_enabled: false,
-      bg_window_enabled: false,
-      lcd_control_value: 0,
+      // the boot ROM leaves the LCD on, with the BG enabled and tiles at
+      // 0x8000
+      bg_window_enabled: true,
+      lcd_control_value: 0x91,
       ly_compare: 0,
       interrupt_on_lyc: false,
       interrupt_on_mode_2: false,
@@ -99,10 +158,23 @@ impl VideoState {
       current_mode_dots: 0,
       current_line: 144,
       next_cached_tile_x: 0,
+      next_cached_window_tile_x: 0,
       current_tile_cache: 0,
+      tile_pixels_remaining: 0,
       object_line_cache: [0; 176],
       current_obj_line_cache_pixel: 0,
-      current_window_line: None,
+      drawing_window: false,
+      window_drawn_on_line: false,
+      window_line: 0,
+      window_y_triggered: false,
+      skip_rendering: false,
+      lcd_off_dots: 0,
+      frame_count: 0,
+      lcd_starting: false,
+      hide_next_frame: false,
+      hblank_entries: 0,
+      last_hblank_line: 0,
+      palette: Palette::grayscale(),
     }
   }
 
@@ -110,7 +182,88 @@ impl VideoState {
     self.current_mode
   }
 
+  /// Returns the number of H-blank periods entered since the last call, along
+  /// with the line the latest one began on
+  pub fn take_hblank_entries(&mut self) -> (u8, u8) {
+    (core::mem::replace(&mut self.hblank_entries, 0), self.last_hblank_line)
+  }
+
+  fn is_lcd_enabled(&self) -> bool {
+    self.lcd_control_value & 0x80 != 0
+  }
+
+  /// While a line is being drawn, the PPU has exclusive access to VRAM. CPU
+  /// reads return 0xff, and writes are ignored.
+  pub fn is_vram_locked(&self) -> bool {
+    self.is_lcd_enabled() && self.current_mode == 3
+  }
+
+  /// OAM is locked from the start of the sprite search until the line has
+  /// been drawn
+  pub fn is_oam_locked(&self) -> bool {
+    self.is_lcd_enabled()
+      && ((self.current_mode == 2 && !self.lcd_starting) || self.current_mode == 3)
+  }
+
+  /// Clock cycles until the PPU next changes mode or line. Compiled code
+  /// stops there, so that the STAT mode, LY, and the VRAM and OAM locks it
+  /// sees are never more than a block out of date.
+  pub fn cycles_until_next_event(&self) -> Option<ClockCycles> {
+    if !self.is_lcd_enabled() {
+      return None;
+    }
+    let mode_length = match self.current_mode {
+      0 | 3 => 188,
+      1 => 456,
+      _ => 80,
+    };
+    Some(ClockCycles(mode_length - self.current_mode_dots))
+  }
+
+  pub fn get_frame_count(&self) -> u32 {
+    self.frame_count
+  }
+
+  pub fn set_skip_rendering(&mut self, skip: bool) {
+    self.skip_rendering = skip;
+  }
+
+  pub fn get_palette(&self) -> &Palette {
+    &self.palette
+  }
+
+  pub fn set_palette(&mut self, palette: Palette) {
+    self.palette = palette;
+  }
+
+  fn should_draw(&self) -> bool {
+    DRAW_LINES && !self.skip_rendering
+  }
+
+  /// Writing to LCDC. Turning the LCD off stops the PPU on line 0 and blanks
+  /// the screen. Turning it back on starts again from the top of a frame.
   pub fn set_lcd_control(&mut self, value: u8) {
+    let was_enabled = self.is_lcd_enabled();
+    self.apply_lcd_control(value);
+    if was_enabled && !self.is_lcd_enabled() {
+      // frames continue from wherever this one was interrupted
+      self.lcd_off_dots = self.dots_into_frame();
+      self.current_line = 0;
+      self.current_mode = 0;
+      self.current_mode_dots = 0;
+      self.lcd.clear(SHADES[0]);
+    } else if !was_enabled && self.is_lcd_enabled() {
+      self.current_line = 0;
+      self.current_mode = 2;
+      self.current_mode_dots = 0;
+      self.lcd_starting = true;
+      self.hide_next_frame = true;
+      self.reset_window();
+    }
+  }
+
+  /// Decode the fields of LCDC, without starting or stopping the PPU
+  fn apply_lcd_control(&mut self, value: u8) {
     self.lcd.set_enabled(value & 0x80 != 0);
     self.window_map_offset = if value & 0x40 == 0 {
       0x1800
@@ -137,6 +290,16 @@ impl VideoState {
     self.lcd_control_value = value;
   }
 
+  /// The number of dots since the frame began, at the end of the last vblank
+  fn dots_into_frame(&self) -> usize {
+    let line_dots = match self.current_mode {
+      0 => 80 + 188 + self.current_mode_dots,
+      3 => 80 + self.current_mode_dots,
+      _ => self.current_mode_dots,
+    };
+    self.current_line as usize * 456 + line_dots
+  }
+
   pub fn get_lcd_control(&self) -> u8 {
     self.lcd_control_value
   }
@@ -167,7 +330,9 @@ impl VideoState {
       status |= 4;
     }
     // set the lower two bits to the current mode
-    status |= self.current_mode;
+    if !self.lcd_starting {
+      status |= self.current_mode;
+    }
     status
   }
 
@@ -250,19 +415,19 @@ impl VideoState {
       + self.tile_address_offset
   }
 
-  fn get_bg_tile(&self, x: usize, y: usize, vram: &Box<[u8]>) -> u8 {
+  fn get_bg_tile(&self, x: usize, y: usize, vram: &[u8]) -> u8 {
     let offset = x + y * 32;
     let address = self.bg_map_offset + offset;
     vram[address]
   }
 
-  fn get_window_tile(&self, x: usize, y: usize, vram: &Box<[u8]>) -> u8 {
+  fn get_window_tile(&self, x: usize, y: usize, vram: &[u8]) -> u8 {
     let offset = x + y * 32;
     let address = self.window_map_offset + offset;
     vram[address]
   }
 
-  pub fn get_tile_row(&self, video_ram: &Box<[u8]>, tile: usize, row: usize) -> u16 {
+  pub fn get_tile_row(&self, video_ram: &[u8], tile: usize, row: usize) -> u16 {
     let mut address = self.get_tile_address(tile);
     address += row * 2;
     let low = video_ram[address];
@@ -301,13 +466,14 @@ impl VideoState {
     }
     let current_line = self.current_line as isize;
     let object_height = if self.object_double_height { 16 } else { 8 };
-    // iterate over all objects in OAM (every 4 bytes)
-    let mut objects_found: Vec<Option<ObjectAttributes>> = Vec::with_capacity(10);
+    // iterate over all objects in OAM (every 4 bytes), selecting the first 10
+    // that fall on the current line
+    let mut objects_found: Vec<ObjectAttributes> = Vec::with_capacity(10);
     let mut offset = 0;
     while offset < 160 && objects_found.len() < 10 {
       let object_y = oam[offset] as isize;
       let object_x = oam[offset + 1];
-      let tile_index = oam[offset + 2] as usize;
+      let mut tile_index = oam[offset + 2] as usize;
       let attributes = oam[offset + 3];
       offset += 4;
       let mut object_line = current_line + 16 - object_y;
@@ -319,102 +485,136 @@ impl VideoState {
       if flip_y {
         object_line = object_height - object_line - 1;
       }
+      if self.object_double_height {
+        // tall objects ignore the lowest bit of the tile index, so the top
+        // half is always an even tile and the bottom half is the odd one
+        tile_index &= 0xfe;
+      }
 
       let row_data = self.get_object_row(video_ram, tile_index, object_line as usize, flip_x);
 
       objects_found.push(
-        Some(
-          ObjectAttributes {
-            has_priority: attributes & 0x80 == 0,
-            palette: (attributes & 0x10) >> 4,
-            row_data,
-            x_coord: object_x,
-          }
-        )
+        ObjectAttributes {
+          has_priority: attributes & 0x80 == 0,
+          palette: (attributes & 0x10) >> 4,
+          row_data,
+          x_coord: object_x,
+        }
       );
     }
 
-    let total_objects = objects_found.len();
-    if total_objects == 0 {
-      return;
-    }
-    let mut drawn_count = 0;
-    // line_x sweeps across every pixel in the object line cache
-    // The first 8 pixels won't be drawn to the screen, nor the pixels beyond
-    // index 168.
-    let mut line_x = 0;
-    // At each pixel, seek through the objects on the current line until one is
-    // found starting on the current pixel. If one is located, its pixels are
-    // copied to the unpopulated pixels in the line cache. After that, the
-    // object is removed from the current set, so that future sweeps through
-    // the object list can ignore it.
-    while line_x < 168 && drawn_count < total_objects {
-      for obj_index in 0..total_objects {
-        let obj_found = objects_found.get(obj_index);
-
-        let drawn = if let Some(Some(obj)) = obj_found {
-          if obj.x_coord != line_x {
-            false
-          } else {
-            // this object begins drawing at line_x
-            // Copy enough data to object_line_cache to be rendered when the
-            // LCD line is actually drawn.
-            let mut pixel_data = obj.row_data;
-            for x in 0..8 {
-              let offset = (line_x as usize) + x;
-              if self.object_line_cache[offset] & 0x80 == 0 {
-                let priority = if obj.has_priority { 0x40 } else { 0 };
-                let palette = obj.palette << 2;
-                let color_index = ((pixel_data >> 14) & 3) as u8;
-                if color_index != 0 {
-                  self.object_line_cache[offset] = (
-                    0x80 | // present
-                    priority |
-                    palette |
-                    color_index
-                  );
-                }
-              }
-              pixel_data <<= 2;
-            }
-            drawn_count += 1;
-            true
-          }
-        } else {
-          false
-        };
-        if drawn {
-          // remove the object from the set
-          if let Some(inner) = objects_found.get_mut(obj_index) {
-            let _ = inner.take();
-          }
+    // On the DMG, where objects overlap, the one with the lower X coordinate
+    // is drawn on top. Ties go to the object that comes first in OAM, which
+    // a stable sort preserves.
+    objects_found.sort_by_key(|obj| obj.x_coord);
+    for obj in objects_found.iter() {
+      // Objects at X >= 168 are entirely off the right edge of the screen
+      if obj.x_coord >= 168 {
+        break;
+      }
+      // Copy the object's row to the pixels of the line cache that haven't
+      // been claimed by a higher priority object, to be rendered when the
+      // LCD line is actually drawn. Transparent pixels (color 0) don't claim
+      // a pixel, so lower priority objects can show through them.
+      let priority = if obj.has_priority { 0x40 } else { 0 };
+      let palette = obj.palette << 2;
+      let mut pixel_data = obj.row_data;
+      for x in 0..8 {
+        let offset = (obj.x_coord as usize) + x;
+        let color_index = ((pixel_data >> 14) & 3) as u8;
+        if self.object_line_cache[offset] & 0x80 == 0 && color_index != 0 {
+          self.object_line_cache[offset] =
+            0x80 | // present
+            priority |
+            palette |
+            color_index;
         }
+        pixel_data <<= 2;
       }
-
-      line_x += 1;
     }
   }
 
-  fn cache_next_tile_row(&mut self, vram: &Box<[u8]>) {
-    let tile_x = self.next_cached_tile_x;
+  fn cache_next_tile_row(&mut self, vram: &[u8]) {
+    let tile_x = ((self.scroll_x >> 3) as usize + self.next_cached_tile_x) % 32;
     let relative_tile_line = self.current_line.wrapping_add(self.scroll_y) as usize;
     let tile_y = relative_tile_line >> 3;
     let tile_index = self.get_bg_tile(tile_x, tile_y, vram) as usize;
     let tile_row = relative_tile_line & 7;
     self.current_tile_cache = self.get_tile_row(vram, tile_index, tile_row);
+    self.tile_pixels_remaining = 8;
     self.next_cached_tile_x += 1;
-    self.next_cached_tile_x %= 32;
   }
 
-  fn cache_next_window_tile_row(&mut self, vram: &Box<[u8]>) {
-    let tile_x = self.next_cached_tile_x;
-    let relative_tile_line = self.current_line.wrapping_sub(self.window_y) as usize;
+  fn cache_next_window_tile_row(&mut self, vram: &[u8]) {
+    let tile_x = self.next_cached_window_tile_x % 32;
+    let relative_tile_line = self.window_line & 0xff;
     let tile_y = relative_tile_line >> 3;
     let tile_index = self.get_window_tile(tile_x, tile_y, vram) as usize;
     let tile_row = relative_tile_line & 7;
     self.current_tile_cache = self.get_tile_row(vram, tile_index, tile_row);
-    self.next_cached_tile_x += 1;
-    self.next_cached_tile_x %= 32;
+    self.tile_pixels_remaining = 8;
+    self.next_cached_window_tile_x += 1;
+  }
+
+  /// Whether the window covers the current line. Like the rest of the
+  /// fetcher state, this is checked as each tile is fetched, so enabling or
+  /// disabling the window partway through a line takes effect immediately.
+  fn is_window_on_line(&self) -> bool {
+    self.window_enabled && self.window_y_triggered
+  }
+
+  /// Reset the window for the start of a new frame
+  fn reset_window(&mut self) {
+    self.window_line = 0;
+    self.window_y_triggered = false;
+  }
+
+  /// Draw the next pixel of the current line at screen position `x`. Each
+  /// register is read at the moment the pixel or its tile is fetched, so
+  /// that raster effects produced by writing to them during mode 3 show up
+  /// partway through the line.
+  fn draw_next_pixel(&mut self, x: usize, vram: &[u8]) {
+    if !self.drawing_window && self.is_window_on_line() && x + 7 == self.window_x as usize {
+      // the window starts on this pixel, discarding the rest of the BG tile
+      self.drawing_window = true;
+      self.window_drawn_on_line = true;
+      self.cache_next_window_tile_row(vram);
+    } else if self.tile_pixels_remaining == 0 {
+      if self.drawing_window && !self.window_enabled {
+        // turning off the window mid-line resumes fetching BG tiles
+        self.drawing_window = false;
+      }
+      if self.drawing_window {
+        self.cache_next_window_tile_row(vram);
+      } else {
+        self.cache_next_tile_row(vram);
+      }
+    }
+
+    // fetch a pixel out of the object line cache
+    let object_pixel = self.object_line_cache[self.current_obj_line_cache_pixel];
+    self.current_obj_line_cache_pixel += 1;
+
+    // shift a pixel out of the current tile cache
+    let palette_index = ((self.current_tile_cache & 0xc000) >> 14) as u8;
+    self.current_tile_cache <<= 2;
+    self.tile_pixels_remaining -= 1;
+
+    // Objects flagged as behind the BG only show through BG color 0,
+    // regardless of which shade the palette maps it to. With the BG disabled,
+    // objects are always drawn on top.
+    let obj_has_priority = (object_pixel & 0x40) != 0
+      || palette_index == 0
+      || !self.bg_window_enabled;
+    let color = if object_pixel & 0x80 != 0 && obj_has_priority {
+      // sprite is present
+      let palette_index = (object_pixel & 0x1c) >> 2;
+      let pal_offset = palette_index as usize * 4;
+      self.object_palettes[pal_offset + ((object_pixel & 3) as usize)]
+    } else {
+      self.bg_palette[palette_index as usize]
+    };
+    self.lcd.get_writing_buffer_line(self.current_line as usize)[x] = color;
   }
 
   fn check_current_line(&self) -> InterruptFlag {
@@ -439,6 +639,14 @@ impl VideoState {
   }
 
   pub fn run_clock_cycles(&mut self, cycles: ClockCycles, vram: &Box<[u8]>, oam: &Box<[u8]>) -> InterruptFlag {
+    if !self.is_lcd_enabled() {
+      self.lcd_off_dots += cycles.as_usize();
+      while self.lcd_off_dots >= FRAME_DOTS {
+        self.lcd_off_dots -= FRAME_DOTS;
+        self.frame_count = self.frame_count.wrapping_add(1);
+      }
+      return InterruptFlag::empty();
+    }
     let mut cycles_remaining = cycles.as_usize();
     let mut interrupt_state = InterruptFlag::empty();
     while cycles_remaining > 0 {
@@ -456,17 +664,19 @@ impl VideoState {
           // cleanly when drawing 4 dots at a time, for each machine cycle.
           if self.current_mode_dots >= 188 {
             self.current_mode_dots -= 188;
+            self.current_line += 1;
+            interrupt_state |= self.check_current_line();
             if self.current_line < 144 {
-              self.current_line += 1;
               self.current_mode = 2;
               interrupt_state |= self.check_mode_interrupt();
-              interrupt_state |= self.check_current_line();
-              // pre-compute up to 10 sprites that overlap the current line
-              self.find_current_line_sprites(vram, oam);
             } else {
               // On line 144, enter VBLANK and set appropriate flags
               self.current_mode = 1;
-              self.lcd.swap_buffers();
+              if self.hide_next_frame {
+                self.hide_next_frame = false;
+              } else if !self.skip_rendering {
+                self.lcd.swap_buffers();
+              }
               interrupt_state |= self.check_mode_interrupt();
               interrupt_state |= InterruptFlag::vblank();
             }
@@ -485,130 +695,68 @@ impl VideoState {
               // VBLANK ended, start in mode 2 on line 0
               self.current_line = 0;
               self.current_mode = 2;
-              // pre-compute up to 10 sprites that overlap the current line
-              self.find_current_line_sprites(vram, oam);
+              self.frame_count = self.frame_count.wrapping_add(1);
+              self.reset_window();
               interrupt_state |= self.check_mode_interrupt();
             }
           }
         },
         2 => {
           // During mode 2, the GB is searching for active sprites.
-          // Since this is pre-computed on the first dot of mode 2, there's no
-          // work to do here.
+          // Since this is computed all at once at the end of mode 2, there's
+          // no work to do until then.
           if self.current_mode_dots >= 80 {
             self.current_mode_dots -= 80;
             self.current_mode = 3;
+            self.lcd_starting = false;
+            // pre-compute up to 10 sprites that overlap the current line
+            if self.should_draw() {
+              self.find_current_line_sprites(vram, oam);
+            }
 
-            // For each screen line, determine if part of the window is visible
-            let mut use_window = false;
-            self.current_window_line = if !self.window_enabled || self.current_line < self.window_y {
-              None
-            } else {
-              use_window = true;
-              Some((self.current_line - self.window_y) as usize)
-            };
-            if use_window && self.window_x <= 7 {
-              // first tile drawn will be the window
-              let first_window_pixel = 7 - self.window_x;
-              self.next_cached_tile_x = 0;
+            // Start the fetcher on the first tile of the line. The fine
+            // scroll is latched here, by discarding the first pixels of
+            // either the window or the BG tile.
+            self.next_cached_tile_x = 0;
+            self.next_cached_window_tile_x = 0;
+            if self.current_line == self.window_y {
+              self.window_y_triggered = true;
+            }
+            self.drawing_window = self.is_window_on_line() && self.window_x <= 7;
+            self.window_drawn_on_line = self.drawing_window;
+            let discarded_pixels = if self.drawing_window {
               self.cache_next_window_tile_row(vram);
-              let window_shift = first_window_pixel as usize * 2;
-              self.current_tile_cache <<= window_shift;
+              7 - self.window_x as usize
             } else {
-              // first tile drawn will be the bg
-              self.next_cached_tile_x = (self.scroll_x >> 3) as usize % 32;
               self.cache_next_tile_row(vram);
-              let fine_scroll_x = self.scroll_x as usize & 7;
-              let shift = fine_scroll_x * 2;
-              self.current_tile_cache <<= shift;
-            }
+              self.scroll_x as usize & 7
+            };
+            self.current_tile_cache <<= discarded_pixels * 2;
+            self.tile_pixels_remaining -= discarded_pixels;
           }
         },
         3 => {
           // During mode 3, the actual screen line is drawn.
           // As the dot counter is incremented, draw 4 dots to the line buffer
-          // at a time. Each time the end of the current tile is reached,
+          // at a time. Each time the end of the current tile is reached, the
+          // next one is fetched.
 
           if self.current_mode_dots >= 188 {
             self.current_mode_dots -= 188;
             self.current_mode = 0;
-            interrupt_state |= self.check_mode_interrupt();
-          } else if self.current_mode_dots <= 160 && self.current_line < 144 {
-            let mut tile_x: usize = previous_dot_count & 7;
-            let fine_scroll_x = self.scroll_x as usize & 7;
-            tile_x += fine_scroll_x;
-            tile_x &= 7;
-
-            let mut draw_window = false;
-            let window_x = self.window_x as usize;
-            if let Some(_y) = self.current_window_line {
-              draw_window = true;
-              if previous_dot_count + 7 >= window_x {
-                // draw the window instead of the bg
-                tile_x = (previous_dot_count + 7 - window_x) & 7;
-              }
-              // If this is the first pixel of the line, the tile will have
-              // already been cached during Mode 2.
-              // In other cases, it will have been cached once the draw loop
-              // increments the current pixel and does a window check.
+            self.hblank_entries = self.hblank_entries.saturating_add(1);
+            self.last_hblank_line = self.current_line;
+            if self.window_drawn_on_line {
+              self.window_line += 1;
             }
-
-            let mut dots_remaining: usize = 4;
-            let mut current_write_index: usize = previous_dot_count;
-
-            // Shift 4 pixels out of the current tile and into the line buffer.
-            // If the end of the tile is reached, compute and cache the next tile.
-            loop {
-              let current_line_buffer = self.lcd.get_writing_buffer_line(self.current_line as usize);
-              while tile_x < 8 && dots_remaining > 0 {
-                // fetch a pixel out of the object line cache
-                let object_pixel = self.object_line_cache[self.current_obj_line_cache_pixel];
-                self.current_obj_line_cache_pixel += 1;
-
-                // shift a pixel out of the current tile cache
-                let palette_index = ((self.current_tile_cache & 0xc000) >> 14) as u8;
-                let bg_color = self.bg_palette[palette_index as usize];
-
-                let obj_has_priority = (object_pixel & 0x40) != 0 || palette_index == 0;
-                if object_pixel & 0x80 != 0 && obj_has_priority {
-                  // sprite is present
-                  let palette_index = (object_pixel & 0x1c) >> 2;
-                  let pal_offset = palette_index as usize * 4;
-                  let obj_color = self.object_palettes[pal_offset + ((object_pixel & 3) as usize)];
-                  current_line_buffer[current_write_index] = obj_color;
-                } else {
-                  current_line_buffer[current_write_index] = bg_color;
-                }
-
-                self.current_tile_cache <<= 2;
-                tile_x += 1;
-                dots_remaining -= 1;
-                current_write_index += 1;
-
-                if draw_window && current_write_index + 7 == window_x {
-                  // the next pixel needs to be the start of the window
-                  self.next_cached_tile_x = 0;
-                  tile_x = 8;
-                }
-              }
-              // At the end of the loop, either the tile has finished drawing
-              // or time has expired and the video processor needs to yield to
-              // other devices.
-              if tile_x >= 8 {
-                if draw_window && (current_write_index + 7 >= window_x) {
-                  self.cache_next_window_tile_row(vram);
-                } else {
-                  self.cache_next_tile_row(vram);
-                }
-                tile_x = 0;
-              }
-              if dots_remaining == 0 {
-                break;
-              }
+            interrupt_state |= self.check_mode_interrupt();
+          } else if self.should_draw() && self.current_mode_dots <= 160 && self.current_line < 144 {
+            for x in previous_dot_count..self.current_mode_dots {
+              self.draw_next_pixel(x, vram);
             }
           }
         },
-        _ => unsafe { std::hint::unreachable_unchecked() },
+        _ => unsafe { core::hint::unreachable_unchecked() },
       };
     }
 
@@ -622,12 +770,83 @@ impl VideoState {
   pub fn get_visible_buffer(&self) -> &Box<[u8]> {
     self.lcd.get_visible_buffer()
   }
+
+  pub fn save_state(&self, state: &mut StateWriter) {
+    self.lcd.save_state(state);
+    state.u8(self.lcd_control_value);
+    state.u8(self.get_lcd_status());
+    state.u8(self.ly_compare);
+    state.u8(self.bg_palette_value);
+    for value in self.object_palette_values.iter() {
+      state.u8(*value);
+    }
+    state.u8(self.scroll_x);
+    state.u8(self.scroll_y);
+    state.u8(self.window_x);
+    state.u8(self.window_y);
+
+    state.u8(self.current_mode);
+    state.u32(self.current_mode_dots as u32);
+    state.u8(self.current_line);
+    state.u8(self.next_cached_tile_x as u8);
+    state.u8(self.next_cached_window_tile_x as u8);
+    state.u16(self.current_tile_cache);
+    state.u8(self.tile_pixels_remaining as u8);
+    state.bytes(&self.object_line_cache);
+    state.u8(self.current_obj_line_cache_pixel as u8);
+    state.bool(self.drawing_window);
+    state.bool(self.window_drawn_on_line);
+    state.u8(self.window_line as u8);
+    state.bool(self.window_y_triggered);
+    state.u32(self.lcd_off_dots as u32);
+    state.bool(self.lcd_starting);
+    state.bool(self.hide_next_frame);
+  }
+
+  pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.lcd.load_state(state)?;
+    // registers go through their setters, which also update the values
+    // decoded from them
+    let lcd_enabled = self.lcd.is_enabled();
+    self.apply_lcd_control(state.u8()?);
+    self.lcd.set_enabled(lcd_enabled);
+    let _ = self.set_lcd_status(state.u8()?);
+    self.ly_compare = state.u8()?;
+    self.set_bgp(state.u8()?);
+    for palette in 0..self.object_palette_values.len() {
+      self.set_obj_palette(palette, state.u8()?);
+    }
+    self.scroll_x = state.u8()?;
+    self.scroll_y = state.u8()?;
+    self.window_x = state.u8()?;
+    self.window_y = state.u8()?;
+
+    self.current_mode = state.u8()? & 3;
+    self.current_mode_dots = state.u32()? as usize;
+    self.current_line = state.u8()?;
+    self.next_cached_tile_x = state.u8()? as usize;
+    self.next_cached_window_tile_x = state.u8()? as usize;
+    self.current_tile_cache = state.u16()?;
+    self.tile_pixels_remaining = (state.u8()? as usize).min(8);
+    state.bytes_into(&mut self.object_line_cache)?;
+    self.current_obj_line_cache_pixel = (state.u8()? as usize).min(self.object_line_cache.len() - 1);
+    self.drawing_window = state.bool()?;
+    self.window_drawn_on_line = state.bool()?;
+    self.window_line = state.u8()? as usize;
+    self.window_y_triggered = state.bool()?;
+    self.lcd_off_dots = state.u32()? as usize % FRAME_DOTS;
+    self.lcd_starting = state.bool()?;
+    self.hide_next_frame = state.bool()?;
+    Ok(())
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use crate::timing::ClockCycles;
   use super::VideoState;
+  use alloc::vec;
+  use alloc::vec::Vec;
 
   #[test]
   fn tile_blocks() {
@@ -641,6 +860,21 @@ mod tests {
     assert_eq!(video.get_tile_address(255), 0xff0);
   }
 
+  #[test]
+  fn next_mode_change() {
+    let vram = vec![0; 0x2000].into_boxed_slice();
+    let oam = vec![0; 0xa0].into_boxed_slice();
+    let mut video = VideoState::new();
+    // each line of vblank counts as a change
+    assert_eq!(video.cycles_until_next_event(), Some(ClockCycles(456)));
+    video.run_clock_cycles(ClockCycles(456 * 10), &vram, &oam);
+    assert_eq!(video.cycles_until_next_event(), Some(ClockCycles(80)));
+    video.run_clock_cycles(ClockCycles(100), &vram, &oam);
+    assert_eq!(video.cycles_until_next_event(), Some(ClockCycles(168)));
+    video.set_lcd_control(0x11);
+    assert_eq!(video.cycles_until_next_event(), None);
+  }
+
   #[test]
   fn video_mode_timing() {
     let mut vram_vec = Vec::with_capacity(0x2000);
@@ -675,6 +909,7 @@ mod tests {
     assert_eq!(video.get_ly(), 1);
   }
 
+  #[cfg(feature = "video")]
   #[test]
   fn basic_bg_drawing() {
     let mut vram_vec = Vec::with_capacity(0x2000);
@@ -758,4 +993,217 @@ mod tests {
       assert_eq!(video.get_writing_buffer()[160 + i], 255);
     }
   }
+
+  #[cfg(feature = "video")]
+  #[test]
+  fn mid_line_register_changes() {
+    let mut vram = vec![0u8; 0x2000].into_boxed_slice();
+    let oam = vec![0u8; 0xa0].into_boxed_slice();
+    solid_tile(&mut vram, 1, 3);
+    solid_tile(&mut vram, 2, 2);
+    // BG columns alternate between white and black tiles, and the window is
+    // filled with dark gray
+    for i in 0..32 {
+      vram[0x1800 + i] = (i & 1) as u8;
+    }
+    for i in 0..0x400 {
+      vram[0x1c00 + i] = 2;
+    }
+    let mut video = VideoState::new();
+    video.set_bgp(0b11100100);
+    video.set_lcd_control(0x91);
+    video.set_window_x(55);
+    // advance to the start of mode 3 on line 0
+    video.run_clock_cycles(ClockCycles(456 * 10 + 80), &mut vram, &oam);
+    assert_eq!(video.get_lcd_status() & 3, 3);
+    video.run_clock_cycles(ClockCycles(16), &mut vram, &oam);
+    // coarse scrolling applies from the next tile fetch
+    video.set_scroll_x(8);
+    video.run_clock_cycles(ClockCycles(16), &mut vram, &oam);
+    // the window can be turned on partway through the line
+    video.set_lcd_control(0xf1);
+    video.run_clock_cycles(ClockCycles(32), &mut vram, &oam);
+    // and turning it off again resumes the BG at the next tile
+    video.set_lcd_control(0xd1);
+    video.run_clock_cycles(ClockCycles(456), &mut vram, &oam);
+    let line = &video.get_writing_buffer()[..160];
+    assert_eq!(&line[0..8], &[255; 8]);
+    assert_eq!(&line[8..16], &[0; 8]);
+    assert_eq!(&line[16..24], &[0; 8]);
+    assert_eq!(&line[24..32], &[255; 8]);
+    assert_eq!(&line[40..48], &[255; 8]);
+    assert_eq!(&line[48..64], &[85; 16]);
+    assert_eq!(&line[64..72], &[0; 8]);
+  }
+
+  #[test]
+  fn lcd_off_and_on() {
+    let mut vram = vec![0u8; 0x2000].into_boxed_slice();
+    let oam = vec![0u8; 0xa0].into_boxed_slice();
+    let mut video = VideoState::new();
+    video.set_bgp(0b11100100);
+    video.run_clock_cycles(ClockCycles(456 * 10 + 88), &mut vram, &oam);
+    assert_eq!(video.get_frame_count(), 1);
+    assert_eq!(video.get_lcd_status() & 3, 3);
+
+    // turning the LCD off stops the PPU on line 0 and blanks the screen
+    video.set_lcd_control(0x11);
+    assert_eq!(video.get_ly(), 0);
+    assert_eq!(video.get_lcd_status() & 3, 0);
+    assert!(video.get_visible_buffer().iter().all(|shade| *shade == 255));
+    assert!(!video.is_vram_locked());
+    // frames continue at the same rate while it is off
+    video.run_clock_cycles(ClockCycles(456 * 154 - 92), &mut vram, &oam);
+    assert_eq!(video.get_ly(), 0);
+    assert_eq!(video.get_frame_count(), 1);
+    video.run_clock_cycles(ClockCycles(4), &mut vram, &oam);
+    assert_eq!(video.get_frame_count(), 2);
+    video.run_clock_cycles(ClockCycles(456 * 154 * 2), &mut vram, &oam);
+    assert_eq!(video.get_frame_count(), 4);
+
+    // turning it back on starts at line 0, without reporting mode 2 or
+    // locking OAM
+    for y in 0..8 {
+      vram[y * 2] = 0xff;
+      vram[y * 2 + 1] = 0xff;
+    }
+    video.set_lcd_control(0x91);
+    assert_eq!(video.get_lcd_status() & 3, 0);
+    assert!(!video.is_oam_locked());
+    video.run_clock_cycles(ClockCycles(80), &mut vram, &oam);
+    assert_eq!(video.get_lcd_status() & 3, 3);
+    video.run_clock_cycles(ClockCycles(376), &mut vram, &oam);
+    assert_eq!(video.get_ly(), 1);
+    assert_eq!(video.get_lcd_status() & 3, 2);
+    assert!(video.is_oam_locked());
+
+    // the first frame after turning on isn't shown
+    video.run_clock_cycles(ClockCycles(456 * 143), &mut vram, &oam);
+    assert_eq!(video.get_lcd_status() & 3, 1);
+    assert!(video.get_visible_buffer().iter().all(|shade| *shade == 255));
+    video.run_clock_cycles(ClockCycles(456 * 154), &mut vram, &oam);
+    assert_eq!(video.get_frame_count(), 5);
+    if cfg!(feature = "video") {
+      assert!(video.get_visible_buffer().iter().all(|shade| *shade == 0));
+    }
+  }
+
+  #[cfg(feature = "video")]
+  #[test]
+  fn window_line_counter() {
+    let mut vram = vec![0u8; 0x2000].into_boxed_slice();
+    let oam = vec![0u8; 0xa0].into_boxed_slice();
+    solid_tile(&mut vram, 1, 3);
+    solid_tile(&mut vram, 2, 2);
+    // the first row of the window is black, and the second is dark gray
+    for i in 0..32 {
+      vram[0x1c00 + i] = 1;
+      vram[0x1c20 + i] = 2;
+    }
+    let mut video = VideoState::new();
+    video.set_bgp(0b11100100);
+    video.set_window_x(7);
+    video.set_window_y(0);
+    // WY matches on line 0, but the window stays hidden for 8 lines
+    video.run_clock_cycles(ClockCycles(456 * 18), &mut vram, &oam);
+    assert_eq!(video.get_ly(), 8);
+    // once shown, it starts from its own first line, and moving WY past the
+    // current line doesn't hide it again
+    video.set_lcd_control(0xf1);
+    video.set_window_y(100);
+    video.run_clock_cycles(ClockCycles(456 * 16), &mut vram, &oam);
+    let buffer = video.get_writing_buffer();
+    assert_eq!(&buffer[160 * 7..160 * 8], &[255; 160]);
+    assert!(buffer[160 * 8..160 * 16].iter().all(|shade| *shade == 0));
+    assert!(buffer[160 * 16..160 * 24].iter().all(|shade| *shade == 85));
+
+    // in the next frame, WY has to match again before the window appears
+    video.run_clock_cycles(ClockCycles(456 * (130 + 108)), &mut vram, &oam);
+    let buffer = video.get_writing_buffer();
+    assert!(buffer[..160 * 100].iter().all(|shade| *shade == 255));
+    assert!(buffer[160 * 100..160 * 108].iter().all(|shade| *shade == 0));
+  }
+
+  /// Fill every row of a tile with a single color index
+  #[cfg(feature = "video")]
+  fn solid_tile(vram: &mut [u8], tile: usize, color: u8) {
+    for y in 0..8 {
+      vram[tile * 16 + y * 2] = if color & 1 != 0 { 0xff } else { 0 };
+      vram[tile * 16 + y * 2 + 1] = if color & 2 != 0 { 0xff } else { 0 };
+    }
+  }
+
+  #[cfg(feature = "video")]
+  #[test]
+  fn object_priority() {
+    let mut vram = vec![0u8; 0x2000].into_boxed_slice();
+    let mut oam = vec![0u8; 0xa0].into_boxed_slice();
+    solid_tile(&mut vram, 1, 3);
+    solid_tile(&mut vram, 2, 1);
+    // tile 4 is transparent on the left half, and black on the right
+    for y in 0..8 {
+      vram[64 + y * 2] = 0x0f;
+      vram[64 + y * 2 + 1] = 0x0f;
+    }
+    // the BG is tile 0 (color 0) everywhere but 64..72, which uses tile 2
+    vram[0x1808] = 2;
+    let objects: [(u8, u8, u8); 8] = [
+      // a later object with a lower X is drawn on top
+      (20, 2, 0),
+      (16, 1, 0),
+      // objects at the same X are ordered by OAM index
+      (40, 1, 0),
+      (40, 2, 0),
+      // behind-BG objects only show over BG color 0
+      (60, 1, 0x80),
+      (72, 1, 0x80),
+      // transparent pixels let lower priority objects show through
+      (84, 4, 0),
+      (86, 2, 0),
+    ];
+    for (i, (x, tile, attributes)) in objects.iter().enumerate() {
+      oam[i * 4..i * 4 + 4].copy_from_slice(&[16, *x, *tile, *attributes]);
+    }
+    let mut video = VideoState::new();
+    video.set_bgp(0b11100100);
+    video.set_obj_palette(0, 0b11100100);
+    video.set_lcd_control(0x93); // LCD, BG, and objects enabled
+    video.run_clock_cycles(ClockCycles(456 * 10), &mut vram, &oam);
+    video.run_clock_cycles(ClockCycles(456), &mut vram, &oam);
+    let line = &video.get_writing_buffer()[..160];
+    assert_eq!(&line[8..12], &[0; 4]);
+    assert_eq!(&line[12..16], &[0; 4]);
+    assert_eq!(&line[16..20], &[170; 4]);
+    assert_eq!(&line[32..40], &[0; 8]);
+    assert_eq!(&line[52..60], &[0; 8]);
+    assert_eq!(&line[64..72], &[170; 8]);
+    assert_eq!(&line[76..78], &[255; 2]);
+    assert_eq!(&line[78..80], &[170; 2]);
+    assert_eq!(&line[80..84], &[0; 4]);
+    assert_eq!(&line[84..86], &[170; 2]);
+
+    // with the BG disabled, every object is drawn on top of it
+    video.set_lcd_control(0x92);
+    video.run_clock_cycles(ClockCycles(456), &mut vram, &oam);
+    assert_eq!(&video.get_writing_buffer()[160 + 64..160 + 72], &[0; 8]);
+  }
+
+  #[cfg(feature = "video")]
+  #[test]
+  fn tall_objects() {
+    let mut vram = vec![0u8; 0x2000].into_boxed_slice();
+    let mut oam = vec![0u8; 0xa0].into_boxed_slice();
+    solid_tile(&mut vram, 2, 1);
+    solid_tile(&mut vram, 3, 3);
+    solid_tile(&mut vram, 4, 2);
+    // an 8x16 object using tile 3 is drawn from tiles 2 and 3, so its second
+    // half starts on line 0
+    oam[0..4].copy_from_slice(&[8, 8, 3, 0]);
+    let mut video = VideoState::new();
+    video.set_bgp(0b11100100);
+    video.set_obj_palette(0, 0b11100100);
+    video.set_lcd_control(0x97);
+    video.run_clock_cycles(ClockCycles(456 * 11), &mut vram, &oam);
+    assert_eq!(&video.get_writing_buffer()[0..8], &[0; 8]);
+  }
 }
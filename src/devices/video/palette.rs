@@ -0,0 +1,182 @@
+//! Converts the LCD buffer into RGB colors. The buffer stores one of the four
+//! DMG shades per pixel, from white (255) down to black (0), so a palette
+//! only needs a color for each of them.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::SHADES;
+
+/// Built-in palettes, by name, with colors from lightest to darkest
+pub const PRESETS: [(&str, [[u8; 3]; 4]); 4] = [
+  ("grayscale", [[255, 255, 255], [170, 170, 170], [85, 85, 85], [0, 0, 0]]),
+  // the original DMG screen
+  ("green", [[0x9b, 0xbc, 0x0f], [0x8b, 0xac, 0x0f], [0x30, 0x62, 0x30], [0x0f, 0x38, 0x0f]]),
+  // the Game Boy Pocket's black and white screen, which had a slight tint
+  ("pocket", [[0xc4, 0xcf, 0xa1], [0x8b, 0x95, 0x6d], [0x4d, 0x53, 0x3c], [0x1f, 0x1f, 0x1f]]),
+  // the light green tones many other emulators default to
+  ("pastel", [[0xe0, 0xf8, 0xd0], [0x88, 0xc0, 0x70], [0x34, 0x68, 0x56], [0x08, 0x18, 0x20]]),
+];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Palette {
+  /// Colors for each shade, from lightest to darkest
+  colors: [[u8; 3]; 4],
+}
+
+impl Palette {
+  pub fn new(colors: [[u8; 3]; 4]) -> Self {
+    Self { colors }
+  }
+
+  /// Read either the name of a preset, or four colors as hex RGB values
+  /// separated by commas, from lightest to darkest
+  pub fn parse(value: &str) -> Result<Self, String> {
+    let value = value.trim();
+    if let Some((_, colors)) = PRESETS.iter().find(|(name, _)| name.eq_ignore_ascii_case(value)) {
+      return Ok(Self::new(*colors));
+    }
+    let mut colors = [[0; 3]; 4];
+    let mut parts = value.split(',');
+    for color in colors.iter_mut() {
+      let part = parts.next().map(|part| part.trim().trim_start_matches('#')).unwrap_or("");
+      let rgb = match u32::from_str_radix(part, 16) {
+        Ok(rgb) if part.len() == 6 => rgb,
+        _ => return Err(format!("Unknown palette \"{}\"", value)),
+      };
+      *color = [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8];
+    }
+    if parts.next().is_some() {
+      return Err(format!("Palette \"{}\" has more than four colors", value));
+    }
+    Ok(Self::new(colors))
+  }
+
+  /// The name of the preset with these colors, if there is one
+  pub fn name(&self) -> Option<&'static str> {
+    PRESETS.iter().find(|(_, colors)| *colors == self.colors).map(|(name, _)| *name)
+  }
+
+  /// The preset after this one, for cycling through all of them
+  pub fn next_preset(&self) -> Self {
+    let index = PRESETS.iter().position(|(_, colors)| *colors == self.colors);
+    let next = match index {
+      Some(index) => (index + 1) % PRESETS.len(),
+      None => 0,
+    };
+    Self::new(PRESETS[next].1)
+  }
+
+  /// Shows each shade as the gray it's stored as
+  pub fn grayscale() -> Self {
+    let mut colors = [[0; 3]; 4];
+    for (color, shade) in colors.iter_mut().zip(SHADES.iter()) {
+      *color = [*shade; 3];
+    }
+    Self { colors }
+  }
+
+  /// The greens of the original DMG screen
+  pub fn green() -> Self {
+    Self::new(PRESETS[1].1)
+  }
+
+  /// Find the shade closest to a buffer value, which only matters if the
+  /// buffer has been modified outside of the video device
+  pub fn shade_index(shade: u8) -> usize {
+    (255 - shade as usize + 42) / 85
+  }
+
+  pub fn to_rgb(&self, shade: u8) -> [u8; 3] {
+    self.colors[Self::shade_index(shade)]
+  }
+
+  /// Blend between the two nearest colors, for buffer values that have been
+  /// adjusted to fall in between the four shades
+  pub fn interpolate(&self, shade: u8) -> [u8; 3] {
+    let darkness = 255 - shade as usize;
+    let index = (darkness / 85).min(2);
+    let progress = darkness - index * 85;
+    let mut color = [0; 3];
+    for (channel, value) in color.iter_mut().enumerate() {
+      let from = self.colors[index][channel] as usize;
+      let to = self.colors[index + 1][channel] as usize;
+      *value = ((from * (85 - progress) + to * progress) / 85) as u8;
+    }
+    color
+  }
+
+  /// Convert a whole buffer to RGBA8, with every pixel fully opaque
+  pub fn to_rgba(&self, pixels: &[u8]) -> Vec<u8> {
+    let mut rgba = Vec::with_capacity(pixels.len() * 4);
+    for shade in pixels {
+      let [r, g, b] = self.to_rgb(*shade);
+      rgba.extend_from_slice(&[r, g, b, 0xff]);
+    }
+    rgba
+  }
+}
+
+impl Default for Palette {
+  fn default() -> Self {
+    Self::grayscale()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Palette, PRESETS};
+  use alloc::vec;
+
+  #[test]
+  fn shade_lookup() {
+    assert_eq!(Palette::shade_index(255), 0);
+    assert_eq!(Palette::shade_index(170), 1);
+    assert_eq!(Palette::shade_index(85), 2);
+    assert_eq!(Palette::shade_index(0), 3);
+    assert_eq!(Palette::shade_index(160), 1);
+  }
+
+  #[test]
+  fn rgba_conversion() {
+    let palette = Palette::new([[0xe0, 0xf8, 0xd0], [0x88, 0xc0, 0x70], [0x34, 0x68, 0x56], [0x08, 0x18, 0x20]]);
+    assert_eq!(
+      palette.to_rgba(&[255, 0, 85]),
+      vec![0xe0, 0xf8, 0xd0, 0xff, 0x08, 0x18, 0x20, 0xff, 0x34, 0x68, 0x56, 0xff],
+    );
+    assert_eq!(Palette::grayscale().to_rgb(170), [170, 170, 170]);
+  }
+
+  #[test]
+  fn parsing() {
+    let green = Palette::parse("Green").unwrap();
+    assert_eq!(green.name(), Some("green"));
+    let custom = Palette::parse("#ffffff, aabbcc,123456,000000").unwrap();
+    assert_eq!(custom.to_rgb(170), [0xaa, 0xbb, 0xcc]);
+    assert_eq!(custom.name(), None);
+    assert!(Palette::parse("ffffff,aabbcc,123456").is_err());
+    assert!(Palette::parse("ffffff,aabbcc,123456,000000,000000").is_err());
+    assert!(Palette::parse("fff,abc,123,000").is_err());
+    assert_eq!(Palette::parse("grayscale"), Ok(Palette::grayscale()));
+  }
+
+  #[test]
+  fn presets() {
+    assert_eq!(Palette::grayscale().next_preset().name(), Some("green"));
+    let last = Palette::new(PRESETS[PRESETS.len() - 1].1);
+    assert_eq!(last.next_preset(), Palette::grayscale());
+    let custom = Palette::new([[1, 2, 3]; 4]);
+    assert_eq!(custom.next_preset(), Palette::grayscale());
+  }
+
+  #[test]
+  fn interpolation() {
+    let grayscale = Palette::grayscale();
+    for shade in [0, 1, 84, 85, 128, 200, 255] {
+      assert_eq!(grayscale.interpolate(shade), [shade; 3]);
+    }
+    let green = Palette::parse("green").unwrap();
+    assert_eq!(green.interpolate(0), [0x0f, 0x38, 0x0f]);
+    assert_eq!(green.interpolate(85), [0x30, 0x62, 0x30]);
+  }
+}
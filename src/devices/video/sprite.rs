@@ -0,0 +1,142 @@
+//! Rasterizer for individual OAM entries, used by debug views. Unlike the
+//! scanline renderer, it draws a whole object at once, regardless of its
+//! position on screen or the limit of 10 objects per line.
+
+use alloc::vec::Vec;
+use core::fmt;
+use super::{tile, VideoState};
+
+pub const OAM_ENTRY_COUNT: usize = 40;
+
+pub struct SpriteImage {
+  pub y: u8,
+  pub x: u8,
+  pub tile: u8,
+  pub attributes: u8,
+  /// Either 8 or 16 rows, depending on the object size in LCDC
+  pub height: usize,
+  /// Shade of each pixel, 8 pixels per row. Transparent pixels are None.
+  pub pixels: Vec<Option<u8>>,
+}
+
+impl SpriteImage {
+  /// Objects at these coordinates are hidden beyond the edges of the screen
+  pub fn is_offscreen(&self) -> bool {
+    self.y == 0 || self.y >= 160 || self.x == 0 || self.x >= 168
+  }
+}
+
+/// The entry's fields, as one line of an OAM listing
+impl fmt::Display for SpriteImage {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "x {:3} y {:3} tile {:02X} attr {:02X}", self.x, self.y, self.tile, self.attributes)?;
+    if self.is_offscreen() {
+      write!(f, " offscreen")?;
+    }
+    Ok(())
+  }
+}
+
+impl VideoState {
+  /// Draw one OAM entry with its flips and palette applied, using the current
+  /// object size. In 8x16 mode, the lowest bit of the tile index is ignored
+  /// and vertical flips swap the two tiles.
+  pub fn render_sprite(&self, video_ram: &[u8], oam: &[u8], index: usize) -> SpriteImage {
+    let offset = (index % OAM_ENTRY_COUNT) * 4;
+    let y = oam[offset];
+    let x = oam[offset + 1];
+    let tile = oam[offset + 2];
+    let attributes = oam[offset + 3];
+
+    let (height, first_tile) = if self.object_double_height {
+      (16, tile & 0xfe)
+    } else {
+      (8, tile)
+    };
+    let flip_y = attributes & 0x40 != 0;
+    let flip_x = attributes & 0x20 != 0;
+    let palette_offset = (((attributes & 0x10) >> 4) as usize) * 4;
+
+    let mut pixels = Vec::with_capacity(8 * height);
+    for row in 0..height {
+      let tile_row = if flip_y { height - row - 1 } else { row };
+      let address = ((first_tile as usize) << 4) + tile_row * 2;
+      let (mut low, mut high) = (video_ram[address], video_ram[address + 1]);
+      if flip_x {
+        low = low.reverse_bits();
+        high = high.reverse_bits();
+      }
+      let mut row_data = tile::interleave(low, high);
+      for _ in 0..8 {
+        let color = ((row_data & 0xc000) >> 14) as usize;
+        pixels.push(if color == 0 {
+          None
+        } else {
+          Some(self.object_palettes[palette_offset + color])
+        });
+        row_data <<= 2;
+      }
+    }
+
+    SpriteImage {
+      y,
+      x,
+      tile,
+      attributes,
+      height,
+      pixels,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::VideoState;
+  use alloc::string::ToString;
+  use alloc::vec;
+  use alloc::vec::Vec;
+
+  fn create_tiles() -> Vec<u8> {
+    let mut vram = vec![0; 0x2000];
+    // tile 2: a single color-3 pixel in the top-left corner
+    vram[0x20] = 0x80;
+    vram[0x21] = 0x80;
+    // tile 3: the bottom row is filled with color 1
+    vram[0x3e] = 0xff;
+    vram
+  }
+
+  #[test]
+  fn applies_flips_and_palette() {
+    let vram = create_tiles();
+    let mut video = VideoState::new();
+    video.set_obj_palette(1, 0b11100100);
+    let oam = vec![16, 8, 2, 0x70, 0, 0, 0, 0];
+    let sprite = video.render_sprite(&vram, &oam, 0);
+    assert_eq!(sprite.height, 8);
+    // flipped both ways, the pixel moves to the bottom-right corner
+    assert_eq!(sprite.pixels[63], Some(0));
+    assert_eq!(sprite.pixels.iter().filter(|p| p.is_some()).count(), 1);
+    assert!(!sprite.is_offscreen());
+
+    assert_eq!(sprite.to_string(), "x   8 y  16 tile 02 attr 70");
+
+    let sprite = video.render_sprite(&vram, &oam, 1);
+    assert!(sprite.is_offscreen());
+    assert!(sprite.to_string().ends_with(" offscreen"));
+  }
+
+  #[test]
+  fn double_height() {
+    let vram = create_tiles();
+    let mut video = VideoState::new();
+    video.set_lcd_control(0x86);
+    video.set_obj_palette(0, 0b11100100);
+    let oam = vec![16, 8, 3, 0];
+    let sprite = video.render_sprite(&vram, &oam, 0);
+    assert_eq!(sprite.height, 16);
+    assert_eq!(sprite.pixels[0], Some(0));
+    assert_eq!(sprite.pixels[15 * 8], Some(170));
+    assert_eq!(sprite.pixels[8], None);
+  }
+}
@@ -0,0 +1,155 @@
+//! Debug views of VRAM as a whole, rather than what made it onto the screen:
+//! the full background map, every tile in tile data, and the palettes. Like
+//! the LCD, each view is a buffer with one shade per pixel.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use super::{tile, VideoState, SHADES};
+
+/// Size of a background map, which wraps around at its edges
+pub const MAP_SIZE: usize = 256;
+/// Tiles per row in the tile data view, which holds all 384 of them
+pub const TILE_COLUMNS: usize = 16;
+pub const TILE_COUNT: usize = 384;
+/// Width and height of each color in the palette view
+pub const SWATCH_SIZE: usize = 16;
+
+pub struct DebugImage {
+  pub width: usize,
+  pub height: usize,
+  pub pixels: Vec<u8>,
+}
+
+impl DebugImage {
+  pub fn new(width: usize, height: usize) -> Self {
+    Self {
+      width,
+      height,
+      pixels: vec![SHADES[0]; width * height],
+    }
+  }
+
+  /// Draw one row of a tile, from the interleaved value of its two planes
+  fn draw_tile_row(&mut self, x: usize, y: usize, mut row_data: u16, shades: &[u8]) {
+    for column in 0..8 {
+      let color = ((row_data & 0xc000) >> 14) as usize;
+      self.pixels[y * self.width + x + column] = shades[color];
+      row_data <<= 2;
+    }
+  }
+}
+
+impl VideoState {
+  /// The whole background map in use, with the tiles and palette that the
+  /// background is drawn with. The 160x144 area that the scroll registers
+  /// show is outlined by inverting the pixels around it, wrapping around
+  /// the edges of the map like the screen does.
+  pub fn render_bg_map(&self, video_ram: &[u8]) -> DebugImage {
+    let mut image = DebugImage::new(MAP_SIZE, MAP_SIZE);
+    for y in 0..MAP_SIZE {
+      for tile_x in 0..(MAP_SIZE / 8) {
+        let tile = self.get_bg_tile(tile_x, y / 8, video_ram) as usize;
+        let row_data = self.get_tile_row(video_ram, tile, y % 8);
+        image.draw_tile_row(tile_x * 8, y, row_data, &self.bg_palette);
+      }
+    }
+
+    let (scroll_x, scroll_y) = (self.scroll_x as usize, self.scroll_y as usize);
+    for y in 0..MAP_SIZE {
+      let screen_y = (y + MAP_SIZE - scroll_y) % MAP_SIZE;
+      for x in 0..MAP_SIZE {
+        let screen_x = (x + MAP_SIZE - scroll_x) % MAP_SIZE;
+        let within = screen_x < 160 && screen_y < 144;
+        let on_edge = screen_x == 0 || screen_x == 159 || screen_y == 0 || screen_y == 143;
+        if within && on_edge {
+          let pixel = &mut image.pixels[y * MAP_SIZE + x];
+          *pixel = 255 - *pixel;
+        }
+      }
+    }
+    image
+  }
+
+  /// Every tile in tile data, in the order they are stored from 0x8000. The
+  /// raw colors are shown, so that tiles stay visible whatever the palettes
+  /// are set to.
+  pub fn render_tile_data(&self, video_ram: &[u8]) -> DebugImage {
+    let rows = TILE_COUNT / TILE_COLUMNS;
+    let mut image = DebugImage::new(TILE_COLUMNS * 8, rows * 8);
+    for index in 0..TILE_COUNT {
+      let (x, y) = ((index % TILE_COLUMNS) * 8, (index / TILE_COLUMNS) * 8);
+      for row in 0..8 {
+        let address = index * 16 + row * 2;
+        let row_data = tile::interleave(video_ram[address], video_ram[address + 1]);
+        image.draw_tile_row(x, y + row, row_data, &SHADES);
+      }
+    }
+    image
+  }
+
+  /// One row of swatches for each of BGP, OBP0, and OBP1, from color 0 to
+  /// color 3
+  pub fn render_palettes(&self) -> DebugImage {
+    let palettes = [&self.bg_palette[..], &self.object_palettes[0..4], &self.object_palettes[4..8]];
+    let mut image = DebugImage::new(SWATCH_SIZE * 4, SWATCH_SIZE * palettes.len());
+    for (y, row) in image.pixels.chunks_exact_mut(SWATCH_SIZE * 4).enumerate() {
+      let palette = palettes[y / SWATCH_SIZE];
+      for (x, pixel) in row.iter_mut().enumerate() {
+        *pixel = palette[x / SWATCH_SIZE];
+      }
+    }
+    image
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::VideoState;
+  use super::{MAP_SIZE, SWATCH_SIZE, TILE_COLUMNS};
+  use alloc::vec;
+
+  #[test]
+  fn map_and_tiles() {
+    let mut vram = vec![0; 0x2000];
+    // tile 1 has a color 3 pixel in its top-left corner
+    vram[0x10] = 0x80;
+    vram[0x11] = 0x80;
+    // which is placed in the last column of the second row of the map
+    vram[0x1800 + 32 + 31] = 1;
+    let mut video = VideoState::new();
+    video.set_lcd_control(0x91);
+    video.set_bgp(0b11100100);
+    video.set_scroll_x(8);
+    video.set_scroll_y(4);
+
+    let map = video.render_bg_map(&vram);
+    assert_eq!((map.width, map.height), (MAP_SIZE, MAP_SIZE));
+    assert_eq!(map.pixels[8 * MAP_SIZE + 248], 0);
+    // the outline starts at the scroll position, and wraps around
+    assert_eq!(map.pixels[5 * MAP_SIZE + 8], 0);
+    assert_eq!(map.pixels[5 * MAP_SIZE + 9], 255);
+    assert_eq!(map.pixels[4 * MAP_SIZE + 100], 0);
+    assert_eq!(map.pixels[147 * MAP_SIZE + 100], 0);
+    assert_eq!(map.pixels[148 * MAP_SIZE + 100], 255);
+    assert_eq!(map.pixels[5 * MAP_SIZE + 167], 0);
+
+    // tile data ignores the palette
+    video.set_bgp(0);
+    let tiles = video.render_tile_data(&vram);
+    assert_eq!((tiles.width, tiles.height), (TILE_COLUMNS * 8, 192));
+    assert_eq!(tiles.pixels[8], 0);
+    assert_eq!(tiles.pixels[9], 255);
+  }
+
+  #[test]
+  fn palette_swatches() {
+    let mut video = VideoState::new();
+    video.set_bgp(0b00011011);
+    video.set_obj_palette(1, 0b11111111);
+    let palettes = video.render_palettes();
+    assert_eq!((palettes.width, palettes.height), (SWATCH_SIZE * 4, SWATCH_SIZE * 3));
+    assert_eq!(palettes.pixels[0], 0);
+    assert_eq!(palettes.pixels[SWATCH_SIZE * 3], 255);
+    assert_eq!(palettes.pixels[palettes.width * SWATCH_SIZE * 2], 0);
+  }
+}
@@ -0,0 +1,377 @@
+//! Encoders for the small subset of A64 instructions used by the emitter.
+//! Each function returns a single 32-bit instruction word. Unless a function
+//! name ends in `_64`, it encodes the 32-bit (W register) form. Register 31
+//! refers to WZR in data processing instructions, and to SP when used as the
+//! base of a load or store.
+
+pub const ZR: u32 = 31;
+pub const HOST_SP: u32 = 31;
+
+pub const COND_EQ: u32 = 0x0;
+pub const COND_NE: u32 = 0x1;
+pub const COND_HS: u32 = 0x2;
+pub const COND_HI: u32 = 0x8;
+
+pub fn add_imm(rd: u32, rn: u32, imm: u32) -> u32 {
+  assert!(imm < 0x1000, "Immediate out of range");
+  0x11000000 | (imm << 10) | (rn << 5) | rd
+}
+
+pub fn sub_imm(rd: u32, rn: u32, imm: u32) -> u32 {
+  assert!(imm < 0x1000, "Immediate out of range");
+  0x51000000 | (imm << 10) | (rn << 5) | rd
+}
+
+pub fn add_imm_64(rd: u32, rn: u32, imm: u32) -> u32 {
+  assert!(imm < 0x1000, "Immediate out of range");
+  0x91000000 | (imm << 10) | (rn << 5) | rd
+}
+
+pub fn cmp_imm(rn: u32, imm: u32) -> u32 {
+  assert!(imm < 0x1000, "Immediate out of range");
+  0x71000000 | (imm << 10) | (rn << 5) | ZR
+}
+
+pub fn add_reg(rd: u32, rn: u32, rm: u32) -> u32 {
+  0x0b000000 | (rm << 16) | (rn << 5) | rd
+}
+
+pub fn sub_reg(rd: u32, rn: u32, rm: u32) -> u32 {
+  0x4b000000 | (rm << 16) | (rn << 5) | rd
+}
+
+pub fn cmp_reg(rn: u32, rm: u32) -> u32 {
+  0x6b000000 | (rm << 16) | (rn << 5) | ZR
+}
+
+pub fn neg(rd: u32, rm: u32) -> u32 {
+  sub_reg(rd, ZR, rm)
+}
+
+pub fn and_reg(rd: u32, rn: u32, rm: u32) -> u32 {
+  0x0a000000 | (rm << 16) | (rn << 5) | rd
+}
+
+pub fn orr_reg(rd: u32, rn: u32, rm: u32) -> u32 {
+  orr_reg_lsl(rd, rn, rm, 0)
+}
+
+pub fn orr_reg_lsl(rd: u32, rn: u32, rm: u32, shift: u32) -> u32 {
+  0x2a000000 | (rm << 16) | (shift << 10) | (rn << 5) | rd
+}
+
+pub fn orr_reg_lsr(rd: u32, rn: u32, rm: u32, shift: u32) -> u32 {
+  0x2a400000 | (rm << 16) | (shift << 10) | (rn << 5) | rd
+}
+
+pub fn eor_reg(rd: u32, rn: u32, rm: u32) -> u32 {
+  0x4a000000 | (rm << 16) | (rn << 5) | rd
+}
+
+pub fn bic_reg(rd: u32, rn: u32, rm: u32) -> u32 {
+  0x0a200000 | (rm << 16) | (rn << 5) | rd
+}
+
+pub fn mov_reg(rd: u32, rm: u32) -> u32 {
+  orr_reg(rd, ZR, rm)
+}
+
+pub fn mov_reg_64(rd: u32, rm: u32) -> u32 {
+  0xaa000000 | (rm << 16) | (ZR << 5) | rd
+}
+
+pub fn and_imm(rd: u32, rn: u32, value: u32) -> u32 {
+  0x12000000 | logical_immediate(value) | (rn << 5) | rd
+}
+
+pub fn orr_imm(rd: u32, rn: u32, value: u32) -> u32 {
+  0x32000000 | logical_immediate(value) | (rn << 5) | rd
+}
+
+pub fn eor_imm(rd: u32, rn: u32, value: u32) -> u32 {
+  0x52000000 | logical_immediate(value) | (rn << 5) | rd
+}
+
+pub fn tst_imm(rn: u32, value: u32) -> u32 {
+  0x72000000 | logical_immediate(value) | (rn << 5) | ZR
+}
+
+/// Extract `width` bits starting at `lsb`, zero-extending the result
+pub fn ubfx(rd: u32, rn: u32, lsb: u32, width: u32) -> u32 {
+  ubfm(rd, rn, lsb, lsb + width - 1)
+}
+
+pub fn lsl_imm(rd: u32, rn: u32, shift: u32) -> u32 {
+  ubfm(rd, rn, (32 - shift) % 32, 31 - shift)
+}
+
+pub fn lsr_imm(rd: u32, rn: u32, shift: u32) -> u32 {
+  ubfm(rd, rn, shift, 31)
+}
+
+fn ubfm(rd: u32, rn: u32, immr: u32, imms: u32) -> u32 {
+  0x53000000 | (immr << 16) | (imms << 10) | (rn << 5) | rd
+}
+
+/// Replace `width` bits of rd starting at `lsb` with the low bits of rn
+pub fn bfi(rd: u32, rn: u32, lsb: u32, width: u32) -> u32 {
+  0x33000000 | (((32 - lsb) % 32) << 16) | ((width - 1) << 10) | (rn << 5) | rd
+}
+
+pub fn cset(rd: u32, cond: u32) -> u32 {
+  // csinc rd, wzr, wzr, !cond
+  0x1a800400 | (ZR << 16) | ((cond ^ 1) << 12) | (ZR << 5) | rd
+}
+
+pub fn csel(rd: u32, rn: u32, rm: u32, cond: u32) -> u32 {
+  0x1a800000 | (rm << 16) | (cond << 12) | (rn << 5) | rd
+}
+
+pub fn movz(rd: u32, value: u16) -> u32 {
+  0x52800000 | ((value as u32) << 5) | rd
+}
+
+fn movz_64(rd: u32, value: u16, shift: u32) -> u32 {
+  0xd2800000 | ((shift / 16) << 21) | ((value as u32) << 5) | rd
+}
+
+fn movk_64(rd: u32, value: u16, shift: u32) -> u32 {
+  0xf2800000 | ((shift / 16) << 21) | ((value as u32) << 5) | rd
+}
+
+/// Load a full 64-bit address. This always takes four instructions, so that
+/// sequences containing it have a fixed length.
+pub fn load_address(rd: u32, address: u64) -> [u32; 4] {
+  [
+    movz_64(rd, address as u16, 0),
+    movk_64(rd, (address >> 16) as u16, 16),
+    movk_64(rd, (address >> 32) as u16, 32),
+    movk_64(rd, (address >> 48) as u16, 48),
+  ]
+}
+
+pub fn ldr(rt: u32, rn: u32, offset: u32) -> u32 {
+  0xb9400000 | ((offset / 4) << 10) | (rn << 5) | rt
+}
+
+pub fn str(rt: u32, rn: u32, offset: u32) -> u32 {
+  0xb9000000 | ((offset / 4) << 10) | (rn << 5) | rt
+}
+
+pub fn ldrh(rt: u32, rn: u32, offset: u32) -> u32 {
+  0x79400000 | ((offset / 2) << 10) | (rn << 5) | rt
+}
+
+pub fn strh(rt: u32, rn: u32, offset: u32) -> u32 {
+  0x79000000 | ((offset / 2) << 10) | (rn << 5) | rt
+}
+
+pub fn ldrb(rt: u32, rn: u32, offset: u32) -> u32 {
+  0x39400000 | (offset << 10) | (rn << 5) | rt
+}
+
+pub fn ldr_64(rt: u32, rn: u32, offset: u32) -> u32 {
+  0xf9400000 | ((offset / 8) << 10) | (rn << 5) | rt
+}
+
+pub fn str_64(rt: u32, rn: u32, offset: u32) -> u32 {
+  0xf9000000 | ((offset / 8) << 10) | (rn << 5) | rt
+}
+
+/// stp rt, rt2, [rn, #offset]!
+pub fn stp_pre_64(rt: u32, rt2: u32, rn: u32, offset: i32) -> u32 {
+  0xa9800000 | pair_offset(offset) | (rt2 << 10) | (rn << 5) | rt
+}
+
+pub fn stp_64(rt: u32, rt2: u32, rn: u32, offset: i32) -> u32 {
+  0xa9000000 | pair_offset(offset) | (rt2 << 10) | (rn << 5) | rt
+}
+
+pub fn ldp_64(rt: u32, rt2: u32, rn: u32, offset: i32) -> u32 {
+  0xa9400000 | pair_offset(offset) | (rt2 << 10) | (rn << 5) | rt
+}
+
+/// ldp rt, rt2, [rn], #offset
+pub fn ldp_post_64(rt: u32, rt2: u32, rn: u32, offset: i32) -> u32 {
+  0xa8c00000 | pair_offset(offset) | (rt2 << 10) | (rn << 5) | rt
+}
+
+fn pair_offset(offset: i32) -> u32 {
+  (((offset / 8) as u32) & 0x7f) << 15
+}
+
+pub fn br(rn: u32) -> u32 {
+  0xd61f0000 | (rn << 5)
+}
+
+pub fn blr(rn: u32) -> u32 {
+  0xd63f0000 | (rn << 5)
+}
+
+pub fn ret() -> u32 {
+  0xd65f03c0
+}
+
+/// Branches take their offset as a number of instructions relative to the
+/// branch itself
+pub fn b(offset: i32) -> u32 {
+  0x14000000 | ((offset as u32) & 0x3ffffff)
+}
+
+pub fn b_cond(cond: u32, offset: i32) -> u32 {
+  0x54000000 | (((offset as u32) & 0x7ffff) << 5) | cond
+}
+
+pub fn cbnz(rt: u32, offset: i32) -> u32 {
+  0x35000000 | (((offset as u32) & 0x7ffff) << 5) | rt
+}
+
+/// Branch if bit `bit` of rt is zero
+pub fn tbz(rt: u32, bit: u32, offset: i32) -> u32 {
+  0x36000000 | (bit << 19) | (((offset as u32) & 0x3fff) << 5) | rt
+}
+
+/// Branch if bit `bit` of rt is one
+pub fn tbnz(rt: u32, bit: u32, offset: i32) -> u32 {
+  0x37000000 | (bit << 19) | (((offset as u32) & 0x3fff) << 5) | rt
+}
+
+/// Encode the N:immr:imms fields of a 32-bit logical immediate. Only values
+/// made of a repeating, rotated run of ones can be encoded; anything else is a
+/// bug in the emitter, so it panics.
+fn logical_immediate(value: u32) -> u32 {
+  if value == 0 || value == 0xffffffff {
+    panic!("Cannot encode {:#x} as a logical immediate", value);
+  }
+  // find the smallest element size that repeats to fill the register
+  let mut size = 32;
+  while size > 2 {
+    let half = size / 2;
+    let mask = (1u32 << half) - 1;
+    if (value & mask) != ((value >> half) & mask) {
+      break;
+    }
+    size = half;
+  }
+  let mask = if size == 32 { 0xffffffff } else { (1u32 << size) - 1 };
+  let element = value & mask;
+  let ones = element.count_ones();
+  let run = if ones == 32 { 0xffffffff } else { (1u32 << ones) - 1 };
+  // find the rotation that turns a run of ones at the bottom into the element
+  let rotation = (0..size).find(|&r| {
+    let rotated = if r == 0 { run } else { ((run >> r) | (run << (size - r))) & mask };
+    rotated == element
+  });
+  let immr = match rotation {
+    Some(r) => r,
+    None => panic!("Cannot encode {:#x} as a logical immediate", value),
+  };
+  let imms = ((!(size - 1) << 1) & 0x3f) | (ones - 1);
+  (immr << 16) | (imms << 10)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Expected words were assembled with `llvm-mc -triple=aarch64`
+
+  #[test]
+  fn arithmetic() {
+    assert_eq!(add_imm(24, 24, 3), 0x11000f18); // add w24, w24, #3
+    assert_eq!(sub_imm(23, 23, 2), 0x51000af7); // sub w23, w23, #2
+    assert_eq!(add_imm_64(29, HOST_SP, 0), 0x910003fd); // mov x29, sp
+    assert_eq!(cmp_imm(9, 0x99), 0x7102653f); // cmp w9, #0x99
+    assert_eq!(add_reg(11, 9, 10), 0x0b0a012b); // add w11, w9, w10
+    assert_eq!(sub_reg(11, 9, 10), 0x4b0a012b); // sub w11, w9, w10
+    assert_eq!(cmp_reg(26, 27), 0x6b1b035f); // cmp w26, w27
+    assert_eq!(neg(13, 13), 0x4b0d03ed); // neg w13, w13
+  }
+
+  #[test]
+  fn logical() {
+    assert_eq!(and_reg(11, 9, 10), 0x0a0a012b); // and w11, w9, w10
+    assert_eq!(orr_reg_lsl(12, 12, 13, 7), 0x2a0d1d8c); // orr w12, w12, w13, lsl #7
+    assert_eq!(orr_reg_lsr(11, 11, 9, 7), 0x2a491d6b); // orr w11, w11, w9, lsr #7
+    assert_eq!(eor_reg(13, 9, 10), 0x4a0a012d); // eor w13, w9, w10
+    assert_eq!(bic_reg(19, 19, 13), 0x0a2d0273); // bic w19, w19, w13
+    assert_eq!(mov_reg(20, 21), 0x2a1503f4); // mov w20, w21
+    assert_eq!(mov_reg_64(27, 3), 0xaa0303fb); // mov x27, x3
+  }
+
+  #[test]
+  fn logical_immediates() {
+    assert_eq!(and_imm(22, 22, 0xffff), 0x12003ed6); // and w22, w22, #0xffff
+    assert_eq!(and_imm(19, 0, 0xfff0), 0x121c2c13); // and w19, w0, #0xfff0
+    assert_eq!(and_imm(19, 19, 0xffffff1f), 0x12187273); // and w19, w19, #0xffffff1f
+    assert_eq!(and_imm(20, 20, 0xfffffeff), 0x12177a94); // and w20, w20, #0xfffffeff
+    assert_eq!(orr_imm(1, 1, 0xff00), 0x32181c21); // orr w1, w1, #0xff00
+    assert_eq!(orr_imm(19, 19, 0x20), 0x321b0273); // orr w19, w19, #0x20
+    assert_eq!(eor_imm(19, 19, 0xff00), 0x52181e73); // eor w19, w19, #0xff00
+    assert_eq!(eor_imm(19, 19, 0x10), 0x521c0273); // eor w19, w19, #0x10
+    assert_eq!(tst_imm(11, 0xff), 0x72001d7f); // tst w11, #0xff
+    assert_eq!(tst_imm(9, 0x1f), 0x7200113f); // tst w9, #0x1f
+    assert_eq!(tst_imm(9, 0x55555555), 0x7200f13f); // tst w9, #0x55555555
+  }
+
+  #[test]
+  #[should_panic]
+  fn unencodable_immediate() {
+    and_imm(19, 19, 0xffffff5f);
+  }
+
+  #[test]
+  fn bitfields() {
+    assert_eq!(ubfx(9, 19, 8, 8), 0x53083e69); // ubfx w9, w19, #8, #8
+    assert_eq!(ubfx(12, 19, 4, 1), 0x5304126c); // ubfx w12, w19, #4, #1
+    assert_eq!(lsl_imm(11, 9, 1), 0x531f792b); // lsl w11, w9, #1
+    assert_eq!(lsr_imm(11, 9, 1), 0x53017d2b); // lsr w11, w9, #1
+    assert_eq!(bfi(19, 11, 8, 8), 0x33181d73); // bfi w19, w11, #8, #8
+    assert_eq!(bfi(20, 12, 0, 8), 0x33001d94); // bfxil w20, w12, #0, #8
+    assert_eq!(cset(13, COND_EQ), 0x1a9f17ed); // cset w13, eq
+    assert_eq!(csel(13, 14, 13, COND_NE), 0x1a8d11cd); // csel w13, w14, w13, ne
+  }
+
+  #[test]
+  fn moves() {
+    assert_eq!(movz(24, 0x1234), 0x52824698); // mov w24, #0x1234
+    assert_eq!(
+      load_address(16, 0x0000_7fff_1234_5678),
+      [
+        0xd28acf10, // mov x16, #0x5678
+        0xf2a24690, // movk x16, #0x1234, lsl #16
+        0xf2cffff0, // movk x16, #0x7fff, lsl #32
+        0xf2e00010, // movk x16, #0, lsl #48
+      ],
+    );
+  }
+
+  #[test]
+  fn loads_and_stores() {
+    assert_eq!(ldr(20, 0, 4), 0xb9400414); // ldr w20, [x0, #4]
+    assert_eq!(str(22, 0, 12), 0xb9000c16); // str w22, [x0, #12]
+    assert_eq!(ldrh(24, 0, 20), 0x79402818); // ldrh w24, [x0, #20]
+    assert_eq!(strh(26, 0, 24), 0x7900301a); // strh w26, [x0, #24]
+    assert_eq!(ldrb(9, 9, 0), 0x39400129); // ldrb w9, [x9]
+    assert_eq!(ldr_64(0, HOST_SP, 96), 0xf94033e0); // ldr x0, [sp, #96]
+    assert_eq!(str_64(0, HOST_SP, 96), 0xf90033e0); // str x0, [sp, #96]
+    assert_eq!(stp_pre_64(29, 30, HOST_SP, -112), 0xa9b97bfd); // stp x29, x30, [sp, #-112]!
+    assert_eq!(stp_64(19, 20, HOST_SP, 16), 0xa90153f3); // stp x19, x20, [sp, #16]
+    assert_eq!(ldp_64(27, 28, HOST_SP, 80), 0xa94573fb); // ldp x27, x28, [sp, #80]
+    assert_eq!(ldp_post_64(29, 30, HOST_SP, 112), 0xa8c77bfd); // ldp x29, x30, [sp], #112
+  }
+
+  #[test]
+  fn branches() {
+    assert_eq!(br(28), 0xd61f0380); // br x28
+    assert_eq!(blr(16), 0xd63f0200); // blr x16
+    assert_eq!(ret(), 0xd65f03c0); // ret
+    assert_eq!(b(1), 0x14000001); // b .+4
+    assert_eq!(b(-3), 0x17fffffd); // b .-12
+    assert_eq!(b_cond(COND_NE, 6), 0x540000c1); // b.ne .+24
+    assert_eq!(b_cond(COND_HS, 3), 0x54000062); // b.hs .+12
+    assert_eq!(cbnz(25, 4), 0x35000099); // cbnz w25, .+16
+    assert_eq!(tbz(19, 7, 3), 0x36380073); // tbz w19, #7, .+12
+    assert_eq!(tbnz(19, 4, 4), 0x37200093); // tbnz w19, #4, .+16
+  }
+}
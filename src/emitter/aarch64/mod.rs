@@ -0,0 +1,1569 @@
+pub mod encoding;
+#[cfg(test)]
+mod sim;
+
+use crate::cpu;
+use crate::decoder::ops::{Op, IndirectLocation, JumpCondition, Register8, Register16};
+use crate::ir::{Block, Instruction, PairSource};
+use crate::mem::MemoryAreas;
+use std::cell::Cell;
+use std::mem::offset_of;
+use encoding::*;
+
+// Register Usage
+// When running compiled code, the emulator keeps all GB CPU state in
+// callee-saved registers, so that calls out to Rust code (which follow the
+// standard AAPCS64 procedure call convention) leave it untouched.
+//
+// ARM64 |  GB / Emulator State
+// ----------------------------
+// W19   |  AF
+// W20   |  BC
+// W21   |  DE
+// W22   |  HL
+// W23   |  SP
+// W24   |  IP
+// W25   |  Code block return state
+// W26   |  Accumulated CPU cycles
+// X27   |  Pointer to MemoryAreas
+// X28   |  Address of the epilogue function
+//
+// The cycle budget for a call is kept in the prelude's stack frame, at
+// [sp, #104]. Rust code called to access memory takes the MemoryAreas pointer
+// as its first argument, which is copied from X27 rather than built from an
+// immediate at each call, leaving compiled blocks independent of where the
+// memory happens to live.
+//
+// The 8-bit GB registers can't be addressed directly, so they are extracted
+// into scratch registers with UBFX and written back with BFI. Arithmetic ops
+// load their operands into W9 and W10 and leave the unmasked result in W11,
+// which is what the flags are computed from. W12 and W13 are temporaries, and
+// X16 holds the address of any Rust function being called.
+
+const AF: u32 = 19;
+const BC: u32 = 20;
+const DE: u32 = 21;
+const HL: u32 = 22;
+const SP: u32 = 23;
+const IP: u32 = 24;
+const STATUS: u32 = 25;
+const CYCLES: u32 = 26;
+const MEM: u32 = 27;
+const EPILOGUE: u32 = 28;
+
+const LHS: u32 = 9;
+const RHS: u32 = 10;
+const RESULT: u32 = 11;
+const TEMP: u32 = 12;
+const TEMP2: u32 = 13;
+const CALL_TARGET: u32 = 16;
+
+/// Offset of the patchable branch within a linkable epilogue
+pub const LINK_PATCH_OFFSET: usize = 36;
+
+/// Where the cycle budget is stored in the prelude's stack frame
+const BUDGET_SLOT: u32 = 104;
+
+/// Locations of the interrupt registers relative to the memory pointer. Byte
+/// loads can only reach 4095 bytes past their base register.
+const INTERRUPT_FLAG_OFFSET: u32 = offset_of!(MemoryAreas, io.interrupt_flag) as u32;
+const INTERRUPT_MASK_OFFSET: u32 = offset_of!(MemoryAreas, io.interrupt_mask) as u32;
+const _: () = assert!(INTERRUPT_FLAG_OFFSET < 0x1000 && INTERRUPT_MASK_OFFSET < 0x1000);
+
+pub struct Emitter {
+  /// Flags that may be read before the next op overwrites them. Any flag not
+  /// in this mask is left stale instead of being computed.
+  live_flags: Cell<u8>,
+}
+
+impl Default for Emitter {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Emitter {
+  pub fn new() -> Self {
+    Self {
+      live_flags: Cell::new(0xf0),
+    }
+  }
+
+  /// Set the flags that must be materialized by the next encoded op. See
+  /// `ir::Block::compute_live_flags` for how these are computed.
+  pub fn set_live_flags(&self, flags: u8) {
+    self.live_flags.set(flags);
+  }
+
+  fn store_flags(&self, mask: u8, negative: bool, exec: &mut [u8]) -> usize {
+    let mask = mask & self.live_flags.get();
+    if mask == 0 {
+      return 0;
+    }
+    emit_store_flags(mask, negative, false, exec)
+  }
+
+  /// Like `store_flags`, but carries are taken from a 16-bit result
+  fn store_flags_16(&self, mask: u8, exec: &mut [u8]) -> usize {
+    let mask = mask & self.live_flags.get();
+    if mask == 0 {
+      return 0;
+    }
+    emit_store_flags(mask, false, true, exec)
+  }
+
+  fn force_flags_off(&self, flags: u8, exec: &mut [u8]) -> usize {
+    let flags = flags & self.live_flags.get();
+    if flags == 0 {
+      return 0;
+    }
+    emit_force_flags_off(flags, exec)
+  }
+
+  fn force_flags_on(&self, flags: u8, exec: &mut [u8]) -> usize {
+    let flags = flags & self.live_flags.get();
+    if flags == 0 {
+      return 0;
+    }
+    emit_force_flags_on(flags, exec)
+  }
+
+  /// Called with (registers, block address, epilogue address, cycle budget,
+  /// memory)
+  pub fn write_prelude_function(exec: &mut [u8]) -> usize {
+    let code = [
+      // preserve callee-saved registers that will be modified
+      stp_pre_64(29, 30, HOST_SP, -112), // stp x29, x30, [sp, #-112]!
+      add_imm_64(29, HOST_SP, 0), // mov x29, sp
+      stp_64(19, 20, HOST_SP, 16), // stp x19, x20, [sp, #16]
+      stp_64(21, 22, HOST_SP, 32), // stp x21, x22, [sp, #32]
+      stp_64(23, 24, HOST_SP, 48), // stp x23, x24, [sp, #48]
+      stp_64(25, 26, HOST_SP, 64), // stp x25, x26, [sp, #64]
+      stp_64(27, 28, HOST_SP, 80), // stp x27, x28, [sp, #80]
+      // preserve the registers pointer for the epilogue
+      str_64(0, HOST_SP, 96), // str x0, [sp, #96]
+      str_64(3, HOST_SP, BUDGET_SLOT), // str x3, [sp, #104]
+      mov_reg_64(MEM, 4), // mov x27, x4
+      mov_reg_64(EPILOGUE, 2), // mov x28, x2
+      // set initial return code
+      movz(STATUS, 0), // mov w25, #0
+      // load all registers from the struct in memory
+      ldr(AF, 0, 0), // ldr w19, [x0]
+      ldr(BC, 0, 4), // ldr w20, [x0, #4]
+      ldr(DE, 0, 8), // ldr w21, [x0, #8]
+      ldr(HL, 0, 12), // ldr w22, [x0, #12]
+      ldrh(SP, 0, 16), // ldrh w23, [x0, #16]
+      ldrh(IP, 0, 20), // ldrh w24, [x0, #20]
+      ldrh(CYCLES, 0, 24), // ldrh w26, [x0, #24]
+      // jump to the actual code
+      br(1), // br x1
+    ];
+    write_instructions(&code, exec)
+  }
+
+  pub fn write_epilogue_function(exec: &mut [u8]) -> usize {
+    let code = [
+      // restore the registers to the struct before returning
+      ldr_64(0, HOST_SP, 96), // ldr x0, [sp, #96]
+      str(AF, 0, 0), // str w19, [x0]
+      str(BC, 0, 4), // str w20, [x0, #4]
+      str(DE, 0, 8), // str w21, [x0, #8]
+      str(HL, 0, 12), // str w22, [x0, #12]
+      strh(SP, 0, 16), // strh w23, [x0, #16]
+      strh(IP, 0, 20), // strh w24, [x0, #20]
+      strh(CYCLES, 0, 24), // strh w26, [x0, #24]
+      // set return value from the status register
+      mov_reg(0, STATUS), // mov w0, w25
+      // restore callee-saved registers to their original value
+      ldp_64(27, 28, HOST_SP, 80), // ldp x27, x28, [sp, #80]
+      ldp_64(25, 26, HOST_SP, 64), // ldp x25, x26, [sp, #64]
+      ldp_64(23, 24, HOST_SP, 48), // ldp x23, x24, [sp, #48]
+      ldp_64(21, 22, HOST_SP, 32), // ldp x21, x22, [sp, #32]
+      ldp_64(19, 20, HOST_SP, 16), // ldp x19, x20, [sp, #16]
+      ldp_post_64(29, 30, HOST_SP, 112), // ldp x29, x30, [sp], #112
+      ret(),
+    ];
+    write_instructions(&code, exec)
+  }
+
+  pub fn encode_epilogue(&self, exec: &mut [u8]) -> usize {
+    write_instructions(&[br(EPILOGUE)], exec) // br x28
+  }
+
+  /// Epilogue for a block that can be linked to its successor. Before exiting
+  /// to the dispatcher, it checks whether the block requested any special
+  /// handling, whether an enabled interrupt is waiting to be serviced, and
+  /// whether the cycle budget for this call has been used up, meaning
+  /// peripherals need to catch up. If none are true, it takes the branch at
+  /// LINK_PATCH_OFFSET.
+  /// While that branch is unpatched, it targets the following instruction and
+  /// falls through to the regular epilogue.
+  pub fn encode_linkable_epilogue(&self, exec: &mut [u8]) -> usize {
+    let code = [
+      // Writes to IE or IF within a chain can raise an interrupt, which the
+      // dispatcher needs to handle before the chain continues
+      ldrb(LHS, MEM, INTERRUPT_FLAG_OFFSET), // ldrb w9, [x27, #interrupt_flag]
+      ldrb(RHS, MEM, INTERRUPT_MASK_OFFSET), // ldrb w10, [x27, #interrupt_mask]
+      and_reg(LHS, LHS, RHS), // and w9, w9, w10
+      tst_imm(LHS, 0x1f), // tst w9, #0x1f
+      b_cond(COND_NE, 6), // b.ne exit
+      cbnz(STATUS, 5), // cbnz w25, exit
+      ldr_64(RHS, HOST_SP, BUDGET_SLOT), // ldr x10, [sp, #104]
+      cmp_reg(CYCLES, RHS), // cmp w26, w10
+      b_cond(COND_HS, 2), // b.hs exit
+      b(1), // b linked block
+      // exit:
+      br(EPILOGUE), // br x28
+    ];
+    write_instructions(&code, exec)
+  }
+
+  /// Encode every op in a block. When the block collapses IP increments, each
+  /// op before the last is encoded without one, and their combined length is
+  /// added to the IP right before the last op, which may depend on it.
+  pub fn encode_block(&self, block: Block, exec: &mut [u8]) -> usize {
+    self.encode_ops(block, exec, None)
+  }
+
+  /// Encode a block like `encode_block`, also recording where the code for
+  /// each op starts, relative to the start of the block
+  pub fn encode_block_with_offsets(&self, block: Block, exec: &mut [u8], offsets: &mut Vec<usize>) -> usize {
+    self.encode_ops(block, exec, Some(offsets))
+  }
+
+  fn encode_ops(&self, block: Block, exec: &mut [u8], mut offsets: Option<&mut Vec<usize>>) -> usize {
+    let mut len = 0;
+    let mut pending_ip = 0;
+    let last_index = block.ops.len().saturating_sub(1);
+    for (index, ir_op) in block.ops.into_iter().enumerate() {
+      if let Some(offsets) = offsets.as_mut() {
+        offsets.push(len);
+      }
+      self.set_live_flags(ir_op.live_flags);
+      let deferred = block.collapse_ip && index < last_index;
+      let ip_increment = if deferred {
+        pending_ip += ir_op.length;
+        0
+      } else {
+        len += emit_ip_increment(pending_ip, &mut exec[len..]);
+        pending_ip = 0;
+        ir_op.length
+      };
+      len += match ir_op.instruction {
+        Instruction::Single(op) => self.encode_op(op, ip_increment, &mut exec[len..]),
+        Instruction::BitTestSequence(tests) => {
+          let tests: Vec<(u8, usize)> = if deferred {
+            tests.iter().map(|(mask, _)| (*mask, 0)).collect()
+          } else {
+            tests
+          };
+          self.encode_bit_test_indirect_sequence(&tests, &mut exec[len..])
+        },
+        Instruction::LoadPair(dest, source) => self.encode_load_pair(dest, source, ip_increment, &mut exec[len..]),
+      };
+    }
+    len
+  }
+
+  pub fn encode_op(&self, op: Op, ip_increment: usize, exec: &mut [u8]) -> usize {
+    match op {
+      Op::NoOp => self.encode_noop(ip_increment, exec),
+      Op::Load8(dest, src) => self.encode_load_8_register(dest, src, ip_increment, exec),
+      Op::Load16(reg, value) => self.encode_load_16(reg, value, ip_increment, exec),
+      Op::LoadToIndirect(location, value) => self.encode_load_to_indirect(location, value, ip_increment, exec),
+      Op::LoadImmediateToHLIndirect(value) => self.encode_load_immediate_to_hl_indirect(value, ip_increment, exec),
+      Op::LoadFromIndirect(reg, location) => self.encode_load_from_indirect(reg, location, ip_increment, exec),
+      Op::Load8Immediate(reg, value) => self.encode_load_8(reg, value, ip_increment, exec),
+      Op::Increment8(reg) => self.encode_increment_8(reg, ip_increment, exec),
+      Op::Decrement8(reg) => self.encode_decrement_8(reg, ip_increment, exec),
+      Op::Increment16(reg) => self.encode_increment_16(reg, ip_increment, exec),
+      Op::Decrement16(reg) => self.encode_decrement_16(reg, ip_increment, exec),
+      Op::IncrementHLIndirect => self.encode_increment_hl_indirect(ip_increment, exec),
+      Op::DecrementHLIndirect => self.encode_decrement_hl_indirect(ip_increment, exec),
+      Op::Add8(dest, src) => self.encode_alu_register(AluOp::Add, dest, src, ip_increment, exec),
+      Op::AddWithCarry8(dest, src) => self.encode_alu_register(AluOp::AddWithCarry, dest, src, ip_increment, exec),
+      Op::AddHL(src) => self.encode_add_hl(src, ip_increment, exec),
+      Op::AddAbsolute8(value) => self.encode_alu_absolute(AluOp::Add, value, ip_increment, exec),
+      Op::AddAbsoluteWithCarry8(value) => self.encode_alu_absolute(AluOp::AddWithCarry, value, ip_increment, exec),
+      Op::AddIndirect => self.encode_alu_indirect(AluOp::Add, ip_increment, exec),
+      Op::AddIndirectWithCarry => self.encode_alu_indirect(AluOp::AddWithCarry, ip_increment, exec),
+      Op::Sub8(dest, src) => self.encode_alu_register(AluOp::Sub, dest, src, ip_increment, exec),
+      Op::SubWithCarry8(dest, src) => self.encode_alu_register(AluOp::SubWithCarry, dest, src, ip_increment, exec),
+      Op::SubAbsolute8(value) => self.encode_alu_absolute(AluOp::Sub, value, ip_increment, exec),
+      Op::SubAbsoluteWithCarry8(value) => self.encode_alu_absolute(AluOp::SubWithCarry, value, ip_increment, exec),
+      Op::SubIndirect => self.encode_alu_indirect(AluOp::Sub, ip_increment, exec),
+      Op::SubIndirectWithCarry => self.encode_alu_indirect(AluOp::SubWithCarry, ip_increment, exec),
+      Op::And8(dest, src) => self.encode_alu_register(AluOp::And, dest, src, ip_increment, exec),
+      Op::AndAbsolute8(value) => self.encode_alu_absolute(AluOp::And, value, ip_increment, exec),
+      Op::AndIndirect => self.encode_alu_indirect(AluOp::And, ip_increment, exec),
+      Op::Xor8(dest, src) => self.encode_alu_register(AluOp::Xor, dest, src, ip_increment, exec),
+      Op::XorAbsolute8(value) => self.encode_alu_absolute(AluOp::Xor, value, ip_increment, exec),
+      Op::XorIndirect => self.encode_alu_indirect(AluOp::Xor, ip_increment, exec),
+      Op::Or8(dest, src) => self.encode_alu_register(AluOp::Or, dest, src, ip_increment, exec),
+      Op::OrAbsolute8(value) => self.encode_alu_absolute(AluOp::Or, value, ip_increment, exec),
+      Op::OrIndirect => self.encode_alu_indirect(AluOp::Or, ip_increment, exec),
+      Op::Compare8(reg) => self.encode_alu_register(AluOp::Compare, Register8::A, reg, ip_increment, exec),
+      Op::CompareIndirect => self.encode_alu_indirect(AluOp::Compare, ip_increment, exec),
+      Op::CompareAbsolute8(value) => self.encode_alu_absolute(AluOp::Compare, value, ip_increment, exec),
+      Op::RotateLeftA => self.encode_rotate_a(ShiftOp::RotateLeft, ip_increment, exec),
+      Op::RotateLeftCarryA => self.encode_rotate_a(ShiftOp::RotateLeftCarry, ip_increment, exec),
+      Op::RotateLeft(reg) => self.encode_shift(ShiftOp::RotateLeft, reg, ip_increment, exec),
+      Op::RotateLeftIndirect => self.encode_shift_indirect(ShiftOp::RotateLeft, ip_increment, exec),
+      Op::RotateLeftCarry(reg) => self.encode_shift(ShiftOp::RotateLeftCarry, reg, ip_increment, exec),
+      Op::RotateLeftCarryIndirect => self.encode_shift_indirect(ShiftOp::RotateLeftCarry, ip_increment, exec),
+      Op::RotateRightA => self.encode_rotate_a(ShiftOp::RotateRight, ip_increment, exec),
+      Op::RotateRightCarryA => self.encode_rotate_a(ShiftOp::RotateRightCarry, ip_increment, exec),
+      Op::RotateRight(reg) => self.encode_shift(ShiftOp::RotateRight, reg, ip_increment, exec),
+      Op::RotateRightIndirect => self.encode_shift_indirect(ShiftOp::RotateRight, ip_increment, exec),
+      Op::RotateRightCarry(reg) => self.encode_shift(ShiftOp::RotateRightCarry, reg, ip_increment, exec),
+      Op::RotateRightCarryIndirect => self.encode_shift_indirect(ShiftOp::RotateRightCarry, ip_increment, exec),
+      Op::ShiftLeft(reg) => self.encode_shift(ShiftOp::ShiftLeft, reg, ip_increment, exec),
+      Op::ShiftLeftIndirect => self.encode_shift_indirect(ShiftOp::ShiftLeft, ip_increment, exec),
+      Op::ShiftRight(reg) => self.encode_shift(ShiftOp::ShiftRight, reg, ip_increment, exec),
+      Op::ShiftRightIndirect => self.encode_shift_indirect(ShiftOp::ShiftRight, ip_increment, exec),
+      Op::ShiftRightLogical(reg) => self.encode_shift(ShiftOp::ShiftRightLogical, reg, ip_increment, exec),
+      Op::ShiftRightLogicalIndirect => self.encode_shift_indirect(ShiftOp::ShiftRightLogical, ip_increment, exec),
+      Op::Swap(reg) => self.encode_shift(ShiftOp::Swap, reg, ip_increment, exec),
+      Op::SwapIndirect => self.encode_shift_indirect(ShiftOp::Swap, ip_increment, exec),
+      Op::ComplementA => self.encode_complement_a(ip_increment, exec),
+      Op::SetCarryFlag => self.encode_set_carry(ip_increment, exec),
+      Op::ComplementCarryFlag => self.encode_complement_carry(ip_increment, exec),
+      Op::BitSet(reg, mask) => self.encode_bit_set(reg, mask, ip_increment, exec),
+      Op::BitSetIndirect(mask) => self.encode_bit_set_indirect(mask, ip_increment, exec),
+      Op::BitClear(reg, mask) => self.encode_bit_clear(reg, mask, ip_increment, exec),
+      Op::BitClearIndirect(mask) => self.encode_bit_clear_indirect(mask, ip_increment, exec),
+      Op::BitTest(reg, mask) => self.encode_bit_test(reg, mask, ip_increment, exec),
+      Op::BitTestIndirect(mask) => self.encode_bit_test_indirect(mask, ip_increment, exec),
+      Op::LoadStackPointerToMemory(addr) => self.encode_load_stack_to_memory(addr, ip_increment, exec),
+      Op::LoadAToMemory(addr, extra_cycle) => self.encode_load_a_to_memory(addr, extra_cycle, ip_increment, exec),
+      Op::LoadAFromMemory(addr, extra_cycle) => self.encode_load_a_from_memory(addr, extra_cycle, ip_increment, exec),
+      Op::LoadToHighMem => self.encode_load_to_high_mem(ip_increment, exec),
+      Op::LoadFromHighMem => self.encode_load_from_high_mem(ip_increment, exec),
+      Op::Push(reg) => self.encode_push(reg, ip_increment, exec),
+      Op::Pop(reg) => self.encode_pop(reg, ip_increment, exec),
+      Op::AddSP(offset) => self.encode_add_sp(offset, ip_increment, exec),
+      Op::LoadToStackPointer => self.encode_load_to_sp(ip_increment, exec),
+      Op::LoadStackOffset(offset) => self.encode_load_stack_offset(offset, ip_increment, exec),
+      Op::DAA => self.encode_daa(ip_increment, exec),
+
+      Op::Jump(cond, address) => self.encode_jump(cond, address, exec),
+      Op::JumpHL => self.encode_jump_hl(exec),
+      Op::JumpRelative(cond, offset) => self.encode_jump_relative(cond, offset, exec),
+      Op::Call(cond, address) => self.encode_call(cond, address, exec),
+      Op::ResetVector(vector) => self.encode_reset(vector, exec),
+      Op::Return(cond) => self.encode_return(cond, exec),
+      Op::ReturnFromInterrupt => self.encode_return_from_interrupt(exec),
+
+      Op::Stop => self.encode_status(cpu::STATUS_STOP, ip_increment, exec),
+      Op::Halt => self.encode_status(cpu::STATUS_HALT, ip_increment, exec),
+      Op::InterruptEnable => self.encode_status(cpu::STATUS_INTERRUPT_ENABLE, ip_increment, exec),
+      Op::InterruptDisable => self.encode_status(cpu::STATUS_INTERRUPT_DISABLE, ip_increment, exec),
+
+      // the CPU locks up, leaving the IP pointing at the invalid opcode
+      Op::Invalid(_) => self.encode_status(cpu::STATUS_LOCKED, 0, exec),
+    }
+  }
+
+  pub fn encode_noop(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let len = emit_ip_increment(ip_increment, exec);
+    len + emit_cycle_increment(1, &mut exec[len..])
+  }
+
+  /// STOP, HALT, EI, and DI are all handled by the dispatcher, which reads the
+  /// status code set here
+  pub fn encode_status(&self, status: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = write_instructions(&[movz(STATUS, status as u16)], exec);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(1, &mut exec[len..])
+  }
+
+  pub fn encode_load_16(&self, dest: Register16, value: u16, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = write_instructions(&[movz(map_register_16(dest), value)], exec);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(3, &mut exec[len..])
+  }
+
+  /// Two 8-bit loads into the halves of a register pair, done as one move
+  pub fn encode_load_pair(&self, dest: Register16, source: PairSource, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let dest = map_register_16(dest);
+    let (instruction, cycles) = match source {
+      PairSource::Immediate(value) => (movz(dest, value), 4),
+      PairSource::Register(src) => (mov_reg(dest, map_register_16(src)), 2),
+    };
+    let mut len = write_instructions(&[instruction], exec);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(cycles, &mut exec[len..])
+  }
+
+  pub fn encode_load_8(&self, dest: Register8, value: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let code = [
+      movz(TEMP, value as u16), // mov w12, #value
+      write_register_8(dest, TEMP), // bfi dest, w12
+    ];
+    let mut len = write_instructions(&code, exec);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(2, &mut exec[len..])
+  }
+
+  pub fn encode_load_8_register(&self, dest: Register8, src: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let code = [
+      read_register_8(TEMP, src), // ubfx w12, src
+      write_register_8(dest, TEMP), // bfi dest, w12
+    ];
+    let mut len = write_instructions(&code, exec);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(1, &mut exec[len..])
+  }
+
+  pub fn encode_increment_8(&self, dest: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = write_instructions(&[read_register_8(LHS, dest)], exec);
+    len += emit_increment_8(false, &mut exec[len..]);
+    len += write_instructions(&[write_register_8(dest, RESULT)], &mut exec[len..]);
+    len += self.store_flags(0xe0, false, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(1, &mut exec[len..])
+  }
+
+  pub fn encode_decrement_8(&self, dest: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = write_instructions(&[read_register_8(LHS, dest)], exec);
+    len += emit_increment_8(true, &mut exec[len..]);
+    len += write_instructions(&[write_register_8(dest, RESULT)], &mut exec[len..]);
+    len += self.store_flags(0xe0, true, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(1, &mut exec[len..])
+  }
+
+  pub fn encode_increment_16(&self, dest: Register16, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let reg = map_register_16(dest);
+    let code = [
+      add_imm(reg, reg, 1), // add reg, reg, #1
+      and_imm(reg, reg, 0xffff), // and reg, reg, #0xffff
+    ];
+    let mut len = write_instructions(&code, exec);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(2, &mut exec[len..])
+  }
+
+  pub fn encode_decrement_16(&self, dest: Register16, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let reg = map_register_16(dest);
+    let code = [
+      sub_imm(reg, reg, 1), // sub reg, reg, #1
+      and_imm(reg, reg, 0xffff), // and reg, reg, #0xffff
+    ];
+    let mut len = write_instructions(&code, exec);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(2, &mut exec[len..])
+  }
+
+  pub fn encode_increment_hl_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = emit_hl_indirect_read(LHS, exec);
+    len += emit_increment_8(false, &mut exec[len..]);
+    len += self.store_flags(0xe0, false, &mut exec[len..]);
+    len += emit_hl_indirect_write(RESULT, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(3, &mut exec[len..])
+  }
+
+  pub fn encode_decrement_hl_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = emit_hl_indirect_read(LHS, exec);
+    len += emit_increment_8(true, &mut exec[len..]);
+    len += self.store_flags(0xe0, true, &mut exec[len..]);
+    len += emit_hl_indirect_write(RESULT, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(3, &mut exec[len..])
+  }
+
+  /// Store the flags produced by an 8-bit ALU op, using the same masks as the
+  /// x86_64 backend
+  fn store_alu_flags(&self, op: AluOp, exec: &mut [u8]) -> usize {
+    match op {
+      AluOp::Add | AluOp::AddWithCarry => self.store_flags(0xf0, false, exec),
+      AluOp::Sub | AluOp::SubWithCarry | AluOp::Compare => self.store_flags(0xf0, true, exec),
+      AluOp::And => {
+        let mut len = self.store_flags(0x80, false, exec);
+        len += self.force_flags_off(0x50, &mut exec[len..]);
+        len + self.force_flags_on(0x20, &mut exec[len..])
+      },
+      AluOp::Or | AluOp::Xor => {
+        let len = self.store_flags(0x80, false, exec);
+        len + self.force_flags_off(0x70, &mut exec[len..])
+      },
+    }
+  }
+
+  pub fn encode_alu_register(&self, op: AluOp, dest: Register8, src: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let code = [
+      read_register_8(LHS, dest), // ubfx w9, dest
+      read_register_8(RHS, src), // ubfx w10, src
+    ];
+    let mut len = write_instructions(&code, exec);
+    len += emit_alu(op, dest, &mut exec[len..]);
+    len += self.store_alu_flags(op, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(1, &mut exec[len..])
+  }
+
+  pub fn encode_alu_absolute(&self, op: AluOp, value: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let code = [
+      read_register_8(LHS, Register8::A), // ubfx w9, w19, #8, #8
+      movz(RHS, value as u16), // mov w10, #value
+    ];
+    let mut len = write_instructions(&code, exec);
+    len += emit_alu(op, Register8::A, &mut exec[len..]);
+    len += self.store_alu_flags(op, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(2, &mut exec[len..])
+  }
+
+  pub fn encode_alu_indirect(&self, op: AluOp, ip_increment: usize, exec: &mut [u8]) -> usize {
+    // the call clobbers every scratch register, so A is read afterwards
+    let mut len = emit_hl_indirect_read(RHS, exec);
+    len += write_instructions(&[read_register_8(LHS, Register8::A)], &mut exec[len..]);
+    len += emit_alu(op, Register8::A, &mut exec[len..]);
+    len += self.store_alu_flags(op, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(2, &mut exec[len..])
+  }
+
+  pub fn encode_add_hl(&self, src: Register16, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let code = [
+      mov_reg(LHS, HL), // mov w9, w22
+      mov_reg(RHS, map_register_16(src)), // mov w10, src
+      add_reg(RESULT, LHS, RHS), // add w11, w9, w10
+      and_imm(HL, RESULT, 0xffff), // and w22, w11, #0xffff
+    ];
+    let mut len = write_instructions(&code, exec);
+    len += self.store_flags_16(0x70, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(2, &mut exec[len..])
+  }
+
+  /// RLCA, RLA, RRCA, and RRA always clear the zero flag
+  pub fn encode_rotate_a(&self, op: ShiftOp, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = write_instructions(&[read_register_8(LHS, Register8::A)], exec);
+    len += emit_shift(op, &mut exec[len..]);
+    len += write_instructions(&[write_register_8(Register8::A, RESULT)], &mut exec[len..]);
+    len += self.store_flags(0x10, false, &mut exec[len..]);
+    len += self.force_flags_off(0xe0, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(1, &mut exec[len..])
+  }
+
+  pub fn encode_shift(&self, op: ShiftOp, reg: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = write_instructions(&[read_register_8(LHS, reg)], exec);
+    len += emit_shift(op, &mut exec[len..]);
+    len += write_instructions(&[write_register_8(reg, RESULT)], &mut exec[len..]);
+    len += self.store_flags(0x90, false, &mut exec[len..]);
+    len += self.force_flags_off(0x60, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(2, &mut exec[len..])
+  }
+
+  pub fn encode_shift_indirect(&self, op: ShiftOp, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = emit_hl_indirect_read(LHS, exec);
+    len += emit_shift(op, &mut exec[len..]);
+    len += self.store_flags(0x90, false, &mut exec[len..]);
+    len += self.force_flags_off(0x60, &mut exec[len..]);
+    len += emit_hl_indirect_write(RESULT, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(4, &mut exec[len..])
+  }
+
+  pub fn encode_complement_a(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = write_instructions(&[eor_imm(AF, AF, 0xff00)], exec); // eor w19, w19, #0xff00
+    len += self.force_flags_on(0x60, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(1, &mut exec[len..])
+  }
+
+  pub fn encode_set_carry(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = self.force_flags_off(0x60, exec);
+    len += self.force_flags_on(0x10, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(1, &mut exec[len..])
+  }
+
+  pub fn encode_complement_carry(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = self.force_flags_off(0x60, exec);
+    len += write_instructions(&[eor_imm(AF, AF, 0x10)], &mut exec[len..]); // eor w19, w19, #0x10
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(1, &mut exec[len..])
+  }
+
+  pub fn encode_bit_set(&self, reg: Register8, mask: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let (pair, lsb) = map_register_8(reg);
+    let mut len = write_instructions(&[orr_imm(pair, pair, (mask as u32) << lsb)], exec);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(2, &mut exec[len..])
+  }
+
+  pub fn encode_bit_set_indirect(&self, mask: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = emit_hl_indirect_read(RESULT, exec);
+    len += write_instructions(&[orr_imm(RESULT, RESULT, mask as u32)], &mut exec[len..]);
+    len += emit_hl_indirect_write(RESULT, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(4, &mut exec[len..])
+  }
+
+  pub fn encode_bit_clear(&self, reg: Register8, mask: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let (pair, lsb) = map_register_8(reg);
+    let mut len = write_instructions(&[and_imm(pair, pair, !((mask as u32) << lsb))], exec);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(2, &mut exec[len..])
+  }
+
+  pub fn encode_bit_clear_indirect(&self, mask: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = emit_hl_indirect_read(RESULT, exec);
+    len += write_instructions(&[and_imm(RESULT, RESULT, !(mask as u32))], &mut exec[len..]);
+    len += emit_hl_indirect_write(RESULT, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(4, &mut exec[len..])
+  }
+
+  pub fn encode_bit_test(&self, reg: Register8, mask: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let (pair, lsb) = map_register_8(reg);
+    let mut len = emit_bit_test(pair, (mask as u32) << lsb, exec);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(2, &mut exec[len..])
+  }
+
+  pub fn encode_bit_test_indirect(&self, mask: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    self.encode_bit_test_indirect_sequence(&[(mask, ip_increment)], exec)
+  }
+
+  /// Polling loops often test several bits of the same (HL) byte back to back.
+  /// Each entry in `tests` is a (mask, ip_increment) pair for a consecutive
+  /// BIT n,(HL) op. Since nothing between them can write memory or change HL,
+  /// the byte is read once and every test is run against the cached value.
+  pub fn encode_bit_test_indirect_sequence(&self, tests: &[(u8, usize)], exec: &mut [u8]) -> usize {
+    let mut len = emit_hl_indirect_read(LHS, exec);
+    for (mask, ip_increment) in tests {
+      len += emit_bit_test(LHS, *mask as u32, &mut exec[len..]);
+      len += emit_ip_increment(*ip_increment, &mut exec[len..]);
+      len += emit_cycle_increment(3, &mut exec[len..]);
+    }
+    len
+  }
+
+  pub fn encode_load_to_indirect(&self, location: IndirectLocation, value: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let code = [
+      mov_reg(1, map_indirect_location_to_register(location)), // mov w1, address
+      read_register_8(2, value), // ubfx w2, value
+    ];
+    let mut len = write_instructions(&code, exec);
+    len += emit_memory_call(crate::mem::memory_write_byte as *const () as usize, &mut exec[len..]);
+    len += emit_hl_adjust(location, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(2, &mut exec[len..])
+  }
+
+  pub fn encode_load_immediate_to_hl_indirect(&self, value: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let code = [
+      mov_reg(1, HL), // mov w1, w22
+      movz(2, value as u16), // mov w2, #value
+    ];
+    let mut len = write_instructions(&code, exec);
+    len += emit_memory_call(crate::mem::memory_write_byte as *const () as usize, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(3, &mut exec[len..])
+  }
+
+  pub fn encode_load_from_indirect(&self, reg: Register8, location: IndirectLocation, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let code = [
+      mov_reg(1, map_indirect_location_to_register(location)), // mov w1, address
+    ];
+    let mut len = write_instructions(&code, exec);
+    len += emit_memory_call(crate::mem::memory_read_byte as *const () as usize, &mut exec[len..]);
+    len += write_instructions(&[write_register_8(reg, 0)], &mut exec[len..]); // bfi reg, w0
+    len += emit_hl_adjust(location, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(2, &mut exec[len..])
+  }
+
+  pub fn encode_load_stack_to_memory(&self, addr: u16, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let code = [
+      movz(1, addr), // mov w1, #addr
+      mov_reg(2, SP), // mov w2, w23
+    ];
+    let mut len = write_instructions(&code, exec);
+    len += emit_memory_call(crate::mem::memory_write_word as *const () as usize, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(5, &mut exec[len..])
+  }
+
+  pub fn encode_load_a_to_memory(&self, addr: u16, extra_cycle: bool, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let code = [
+      movz(1, addr), // mov w1, #addr
+      read_register_8(2, Register8::A), // ubfx w2, w19, #8, #8
+    ];
+    let mut len = write_instructions(&code, exec);
+    len += emit_memory_call(crate::mem::memory_write_byte as *const () as usize, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(if extra_cycle { 4 } else { 3 }, &mut exec[len..])
+  }
+
+  pub fn encode_load_a_from_memory(&self, addr: u16, extra_cycle: bool, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = write_instructions(&[movz(1, addr)], exec); // mov w1, #addr
+    len += emit_memory_call(crate::mem::memory_read_byte as *const () as usize, &mut exec[len..]);
+    len += write_instructions(&[write_register_8(Register8::A, 0)], &mut exec[len..]); // bfi w19, w0, #8, #8
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(if extra_cycle { 4 } else { 3 }, &mut exec[len..])
+  }
+
+  pub fn encode_load_to_high_mem(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let code = [
+      read_register_8(1, Register8::C), // ubfx w1, w20, #0, #8
+      orr_imm(1, 1, 0xff00), // orr w1, w1, #0xff00
+      read_register_8(2, Register8::A), // ubfx w2, w19, #8, #8
+    ];
+    let mut len = write_instructions(&code, exec);
+    len += emit_memory_call(crate::mem::memory_write_byte as *const () as usize, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(2, &mut exec[len..])
+  }
+
+  pub fn encode_load_from_high_mem(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let code = [
+      read_register_8(1, Register8::C), // ubfx w1, w20, #0, #8
+      orr_imm(1, 1, 0xff00), // orr w1, w1, #0xff00
+    ];
+    let mut len = write_instructions(&code, exec);
+    len += emit_memory_call(crate::mem::memory_read_byte as *const () as usize, &mut exec[len..]);
+    len += write_instructions(&[write_register_8(Register8::A, 0)], &mut exec[len..]); // bfi w19, w0, #8, #8
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(2, &mut exec[len..])
+  }
+
+  pub fn encode_push(&self, reg: Register16, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = emit_push(map_register_16(reg), exec);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(4, &mut exec[len..])
+  }
+
+  pub fn encode_pop(&self, reg: Register16, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = emit_pop(map_register_16(reg), exec);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(3, &mut exec[len..])
+  }
+
+  pub fn encode_add_sp(&self, offset: i8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = emit_sp_offset_flags(offset, exec);
+    len += emit_signed_offset(SP, SP, offset, &mut exec[len..]);
+    len += self.store_flags(0x30, false, &mut exec[len..]);
+    len += self.force_flags_off(0xc0, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(4, &mut exec[len..])
+  }
+
+  pub fn encode_load_to_sp(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = write_instructions(&[mov_reg(SP, HL)], exec); // mov w23, w22
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(2, &mut exec[len..])
+  }
+
+  pub fn encode_load_stack_offset(&self, offset: i8, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = emit_sp_offset_flags(offset, exec);
+    len += emit_signed_offset(HL, SP, offset, &mut exec[len..]);
+    len += self.store_flags(0x70, false, &mut exec[len..]);
+    len += self.force_flags_off(0x80, &mut exec[len..]);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(3, &mut exec[len..])
+  }
+
+  pub fn encode_daa(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = emit_daa(exec);
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    len + emit_cycle_increment(1, &mut exec[len..])
+  }
+
+  // On the GB, a conditional jump either changes the IP to an entirely new
+  // address, or it increments it to the next instruction.
+  // A Jump will end a code block, so this instruction doesn't need to
+  // "jump" on the host processor. It only needs to change the IP register
+  // and return.
+  // To implement this, all code goes through the default fallthrough path
+  // incrementing the IP. Then, it tests the conditional flag for the jump.
+  // If the condition fails, it branches over the code that modifies the IP.
+
+  pub fn encode_jump(&self, condition: JumpCondition, address: u16, exec: &mut [u8]) -> usize {
+    if let JumpCondition::Always = condition {
+      let len = write_instructions(&[movz(IP, address)], exec); // mov w24, #address
+      return len + emit_cycle_increment(4, &mut exec[len..]);
+    }
+    let mut len = emit_ip_increment(3, exec);
+    len += emit_cycle_increment(3, &mut exec[len..]);
+    let branch = len;
+    len += 4;
+    len += emit_cycle_increment(1, &mut exec[len..]);
+    len += write_instructions(&[movz(IP, address)], &mut exec[len..]);
+    patch_condition_skip(condition, branch, len, exec);
+    len
+  }
+
+  pub fn encode_jump_relative(&self, condition: JumpCondition, offset: i8, exec: &mut [u8]) -> usize {
+    if let JumpCondition::Always = condition {
+      let mut len = emit_ip_increment(2, exec);
+      len += emit_signed_offset(IP, IP, offset, &mut exec[len..]);
+      return len + emit_cycle_increment(3, &mut exec[len..]);
+    }
+    let mut len = emit_ip_increment(2, exec);
+    len += emit_cycle_increment(2, &mut exec[len..]);
+    let branch = len;
+    len += 4;
+    len += emit_cycle_increment(1, &mut exec[len..]);
+    len += emit_signed_offset(IP, IP, offset, &mut exec[len..]);
+    patch_condition_skip(condition, branch, len, exec);
+    len
+  }
+
+  pub fn encode_jump_hl(&self, exec: &mut [u8]) -> usize {
+    let len = write_instructions(&[mov_reg(IP, HL)], exec); // mov w24, w22
+    len + emit_cycle_increment(1, &mut exec[len..])
+  }
+
+  pub fn encode_call(&self, condition: JumpCondition, address: u16, exec: &mut [u8]) -> usize {
+    if let JumpCondition::Always = condition {
+      let mut len = emit_ip_increment(3, exec);
+      len += emit_push(IP, &mut exec[len..]);
+      len += write_instructions(&[movz(IP, address)], &mut exec[len..]);
+      return len + emit_cycle_increment(6, &mut exec[len..]);
+    }
+    let mut len = emit_ip_increment(3, exec);
+    len += emit_cycle_increment(3, &mut exec[len..]);
+    let branch = len;
+    len += 4;
+    len += emit_push(IP, &mut exec[len..]);
+    len += emit_cycle_increment(3, &mut exec[len..]);
+    len += write_instructions(&[movz(IP, address)], &mut exec[len..]);
+    patch_condition_skip(condition, branch, len, exec);
+    len
+  }
+
+  pub fn encode_reset(&self, vector: u16, exec: &mut [u8]) -> usize {
+    let mut len = emit_ip_increment(1, exec);
+    len += emit_push(IP, &mut exec[len..]);
+    len += emit_cycle_increment(4, &mut exec[len..]);
+    len + write_instructions(&[movz(IP, vector)], &mut exec[len..])
+  }
+
+  pub fn encode_return(&self, condition: JumpCondition, exec: &mut [u8]) -> usize {
+    if let JumpCondition::Always = condition {
+      let len = emit_pop(IP, exec);
+      return len + emit_cycle_increment(4, &mut exec[len..]);
+    }
+    let mut len = emit_ip_increment(1, exec);
+    len += emit_cycle_increment(2, &mut exec[len..]);
+    let branch = len;
+    len += 4;
+    len += emit_pop(IP, &mut exec[len..]);
+    len += emit_cycle_increment(3, &mut exec[len..]);
+    patch_condition_skip(condition, branch, len, exec);
+    len
+  }
+
+  pub fn encode_return_from_interrupt(&self, exec: &mut [u8]) -> usize {
+    let mut len = emit_pop(IP, exec);
+    len += emit_cycle_increment(4, &mut exec[len..]);
+    // unlike EI, RETI enables interrupts without a delay
+    len + write_instructions(&[movz(STATUS, cpu::STATUS_INTERRUPT_ENABLE_IMMEDIATE as u16)], &mut exec[len..])
+  }
+}
+
+/// Point the branch of a linkable epilogue at a compiled block. With no
+/// target, it branches to the following instruction, which exits the chain.
+pub fn write_link_displacement(exec: &mut [u8], patch_offset: usize, target_offset: Option<usize>) {
+  let displacement = match target_offset {
+    Some(offset) => (offset as isize - patch_offset as isize) / 4,
+    None => 1,
+  };
+  write_instructions(&[b(displacement as i32)], &mut exec[patch_offset..]);
+  flush_instruction_cache(&exec[patch_offset..(patch_offset + 4)]);
+}
+
+/// ARM cores don't keep the instruction cache coherent with data writes, so
+/// any newly written code must be cleaned from the data cache and invalidated
+/// in the instruction cache before it runs.
+#[cfg(target_arch = "aarch64")]
+pub fn flush_instruction_cache(code: &[u8]) {
+  use std::arch::asm;
+  let start = code.as_ptr() as usize;
+  let end = start + code.len();
+  let cache_type: usize;
+  unsafe { asm!("mrs {}, ctr_el0", out(reg) cache_type) };
+  let data_line = 4 << ((cache_type >> 16) & 0xf);
+  let instruction_line = 4 << (cache_type & 0xf);
+  let mut address = start & !(data_line - 1);
+  while address < end {
+    unsafe { asm!("dc cvau, {}", in(reg) address) };
+    address += data_line;
+  }
+  unsafe { asm!("dsb ish") };
+  address = start & !(instruction_line - 1);
+  while address < end {
+    unsafe { asm!("ic ivau, {}", in(reg) address) };
+    address += instruction_line;
+  }
+  unsafe { asm!("dsb ish", "isb") };
+}
+
+/// When the backend is only built to be tested on another host, the code is
+/// never run natively
+#[cfg(not(target_arch = "aarch64"))]
+pub fn flush_instruction_cache(_code: &[u8]) {}
+
+#[derive(Copy, Clone)]
+pub enum AluOp {
+  Add,
+  AddWithCarry,
+  Sub,
+  SubWithCarry,
+  And,
+  Or,
+  Xor,
+  Compare,
+}
+
+#[derive(Copy, Clone)]
+pub enum ShiftOp {
+  RotateLeft,
+  RotateLeftCarry,
+  RotateRight,
+  RotateRightCarry,
+  ShiftLeft,
+  ShiftRight,
+  ShiftRightLogical,
+  Swap,
+}
+
+fn write_instructions(code: &[u32], exec: &mut [u8]) -> usize {
+  for (index, instruction) in code.iter().enumerate() {
+    exec[(index * 4)..(index * 4 + 4)].copy_from_slice(&instruction.to_le_bytes());
+  }
+  code.len() * 4
+}
+
+/// Fill in the branch at `branch` that skips the taken path of a conditional
+/// op, which runs until `end`. The branch is taken when the GB condition
+/// fails.
+fn patch_condition_skip(condition: JumpCondition, branch: usize, end: usize, exec: &mut [u8]) {
+  let offset = ((end - branch) / 4) as i32;
+  let instruction = match condition {
+    JumpCondition::Zero => tbz(AF, 7, offset),
+    JumpCondition::NonZero => tbnz(AF, 7, offset),
+    JumpCondition::Carry => tbz(AF, 4, offset),
+    JumpCondition::NoCarry => tbnz(AF, 4, offset),
+    JumpCondition::Always => unreachable!("Unconditional ops don't skip"),
+  };
+  write_instructions(&[instruction], &mut exec[branch..]);
+}
+
+fn read_register_8(dest: u32, reg: Register8) -> u32 {
+  let (pair, lsb) = map_register_8(reg);
+  ubfx(dest, pair, lsb, 8)
+}
+
+fn write_register_8(reg: Register8, src: u32) -> u32 {
+  let (pair, lsb) = map_register_8(reg);
+  bfi(pair, src, lsb, 8)
+}
+
+fn emit_ip_increment(amount: usize, exec: &mut [u8]) -> usize {
+  if amount == 0 {
+    return 0;
+  }
+  if amount < 0x1000 {
+    return write_instructions(&[add_imm(IP, IP, amount as u32)], exec); // add w24, w24, #amount
+  }
+  // collapsed increments may not fit in an immediate
+  let code = [
+    movz(TEMP, amount as u16), // mov w12, #amount
+    add_reg(IP, IP, TEMP), // add w24, w24, w12
+  ];
+  write_instructions(&code, exec)
+}
+
+fn emit_cycle_increment(amount: usize, exec: &mut [u8]) -> usize {
+  write_instructions(&[add_imm(CYCLES, CYCLES, amount as u32)], exec) // add w26, w26, #amount
+}
+
+/// Add a sign-extended 8-bit offset to a 16-bit register, wrapping around
+fn emit_signed_offset(dest: u32, src: u32, offset: i8, exec: &mut [u8]) -> usize {
+  let add = if offset < 0 {
+    sub_imm(dest, src, offset.unsigned_abs() as u32)
+  } else {
+    add_imm(dest, src, offset as u32)
+  };
+  write_instructions(&[add, and_imm(dest, dest, 0xffff)], exec)
+}
+
+/// ADD SP,e and LD HL,SP+e set their carry flags as if the offset were added
+/// to the low byte of SP as an unsigned value
+fn emit_sp_offset_flags(offset: i8, exec: &mut [u8]) -> usize {
+  let code = [
+    and_imm(LHS, SP, 0xff), // and w9, w23, #0xff
+    movz(RHS, offset as u8 as u16), // mov w10, #offset
+    add_reg(RESULT, LHS, RHS), // add w11, w9, w10
+  ];
+  write_instructions(&code, exec)
+}
+
+/// Compute w11 = w9 + 1, or w9 - 1, with w10 holding the operand for the
+/// half-carry calculation
+fn emit_increment_8(decrement: bool, exec: &mut [u8]) -> usize {
+  let code = [
+    movz(RHS, 1), // mov w10, #1
+    if decrement {
+      sub_reg(RESULT, LHS, RHS) // sub w11, w9, w10
+    } else {
+      add_reg(RESULT, LHS, RHS) // add w11, w9, w10
+    },
+  ];
+  write_instructions(&code, exec)
+}
+
+/// Compute w11 = w9 (op) w10, and write the result back to `dest` unless the
+/// op is a comparison
+fn emit_alu(op: AluOp, dest: Register8, exec: &mut [u8]) -> usize {
+  let mut code = Vec::with_capacity(4);
+  match op {
+    AluOp::Add => code.push(add_reg(RESULT, LHS, RHS)),
+    AluOp::AddWithCarry => {
+      code.push(ubfx(TEMP, AF, 4, 1)); // ubfx w12, w19, #4, #1
+      code.push(add_reg(RESULT, LHS, RHS)); // add w11, w9, w10
+      code.push(add_reg(RESULT, RESULT, TEMP)); // add w11, w11, w12
+    },
+    AluOp::Sub | AluOp::Compare => code.push(sub_reg(RESULT, LHS, RHS)),
+    AluOp::SubWithCarry => {
+      code.push(ubfx(TEMP, AF, 4, 1)); // ubfx w12, w19, #4, #1
+      code.push(sub_reg(RESULT, LHS, RHS)); // sub w11, w9, w10
+      code.push(sub_reg(RESULT, RESULT, TEMP)); // sub w11, w11, w12
+    },
+    AluOp::And => code.push(and_reg(RESULT, LHS, RHS)),
+    AluOp::Or => code.push(orr_reg(RESULT, LHS, RHS)),
+    AluOp::Xor => code.push(eor_reg(RESULT, LHS, RHS)),
+  }
+  if !matches!(op, AluOp::Compare) {
+    code.push(write_register_8(dest, RESULT));
+  }
+  write_instructions(&code, exec)
+}
+
+/// Compute the shifted or rotated value of w9 into w11. Bit 8 of the result
+/// holds the bit shifted out, which becomes the new carry flag.
+fn emit_shift(op: ShiftOp, exec: &mut [u8]) -> usize {
+  let code: Vec<u32> = match op {
+    ShiftOp::RotateLeftCarry => vec![
+      lsl_imm(RESULT, LHS, 1), // lsl w11, w9, #1
+      orr_reg_lsr(RESULT, RESULT, LHS, 7), // orr w11, w11, w9, lsr #7
+    ],
+    ShiftOp::RotateLeft => vec![
+      ubfx(TEMP, AF, 4, 1), // ubfx w12, w19, #4, #1
+      lsl_imm(RESULT, LHS, 1), // lsl w11, w9, #1
+      orr_reg(RESULT, RESULT, TEMP), // orr w11, w11, w12
+    ],
+    ShiftOp::RotateRightCarry => vec![
+      lsr_imm(RESULT, LHS, 1), // lsr w11, w9, #1
+      and_imm(TEMP, LHS, 1), // and w12, w9, #1
+      orr_reg_lsl(RESULT, RESULT, TEMP, 7), // orr w11, w11, w12, lsl #7
+      orr_reg_lsl(RESULT, RESULT, TEMP, 8), // orr w11, w11, w12, lsl #8
+    ],
+    ShiftOp::RotateRight => vec![
+      ubfx(TEMP, AF, 4, 1), // ubfx w12, w19, #4, #1
+      lsr_imm(RESULT, LHS, 1), // lsr w11, w9, #1
+      orr_reg_lsl(RESULT, RESULT, TEMP, 7), // orr w11, w11, w12, lsl #7
+      and_imm(TEMP, LHS, 1), // and w12, w9, #1
+      orr_reg_lsl(RESULT, RESULT, TEMP, 8), // orr w11, w11, w12, lsl #8
+    ],
+    ShiftOp::ShiftLeft => vec![
+      lsl_imm(RESULT, LHS, 1), // lsl w11, w9, #1
+    ],
+    ShiftOp::ShiftRight => vec![
+      lsr_imm(RESULT, LHS, 1), // lsr w11, w9, #1
+      and_imm(TEMP, LHS, 0x80), // and w12, w9, #0x80
+      orr_reg(RESULT, RESULT, TEMP), // orr w11, w11, w12
+      and_imm(TEMP, LHS, 1), // and w12, w9, #1
+      orr_reg_lsl(RESULT, RESULT, TEMP, 8), // orr w11, w11, w12, lsl #8
+    ],
+    ShiftOp::ShiftRightLogical => vec![
+      lsr_imm(RESULT, LHS, 1), // lsr w11, w9, #1
+      and_imm(TEMP, LHS, 1), // and w12, w9, #1
+      orr_reg_lsl(RESULT, RESULT, TEMP, 8), // orr w11, w11, w12, lsl #8
+    ],
+    ShiftOp::Swap => vec![
+      lsl_imm(RESULT, LHS, 4), // lsl w11, w9, #4
+      orr_reg_lsr(RESULT, RESULT, LHS, 4), // orr w11, w11, w9, lsr #4
+      and_imm(RESULT, RESULT, 0xff), // and w11, w11, #0xff
+    ],
+  };
+  write_instructions(&code, exec)
+}
+
+/// Set the flags in `mask` from the operands in w9 and w10 and the unmasked
+/// result in w11. Zero is taken from the low byte of the result. The carry
+/// and half-carry come from bits 8 and 4, or from bits 16 and 12 when `wide`
+/// is set.
+fn emit_store_flags(mask: u8, negative: bool, wide: bool, exec: &mut [u8]) -> usize {
+  let (half_carry_bit, carry_bit) = if wide { (12, 16) } else { (4, 8) };
+  let mut code = Vec::with_capacity(16);
+  code.push(movz(TEMP, 0)); // mov w12, #0
+  if mask & 0x80 != 0 {
+    code.push(tst_imm(RESULT, 0xff)); // tst w11, #0xff
+    code.push(cset(TEMP2, COND_EQ)); // cset w13, eq
+    code.push(orr_reg_lsl(TEMP, TEMP, TEMP2, 7)); // orr w12, w12, w13, lsl #7
+  }
+  if negative && (mask & 0x40 != 0) {
+    code.push(orr_imm(TEMP, TEMP, 0x40)); // orr w12, w12, #0x40
+  }
+  if mask & 0x20 != 0 {
+    code.push(eor_reg(TEMP2, LHS, RHS)); // eor w13, w9, w10
+    code.push(eor_reg(TEMP2, TEMP2, RESULT)); // eor w13, w13, w11
+    code.push(ubfx(TEMP2, TEMP2, half_carry_bit, 1)); // ubfx w13, w13, #half_carry_bit, #1
+    code.push(orr_reg_lsl(TEMP, TEMP, TEMP2, 5)); // orr w12, w12, w13, lsl #5
+  }
+  if mask & 0x10 != 0 {
+    code.push(ubfx(TEMP2, RESULT, carry_bit, 1)); // ubfx w13, w11, #carry_bit, #1
+    code.push(orr_reg_lsl(TEMP, TEMP, TEMP2, 4)); // orr w12, w12, w13, lsl #4
+  }
+  code.push(movz(TEMP2, mask as u16)); // mov w13, #mask
+  code.push(bic_reg(AF, AF, TEMP2)); // bic w19, w19, w13
+  code.push(orr_reg(AF, AF, TEMP)); // orr w19, w19, w12
+  write_instructions(&code, exec)
+}
+
+fn emit_force_flags_off(flags: u8, exec: &mut [u8]) -> usize {
+  let code = [
+    movz(TEMP2, flags as u16), // mov w13, #flags
+    bic_reg(AF, AF, TEMP2), // bic w19, w19, w13
+  ];
+  write_instructions(&code, exec)
+}
+
+fn emit_force_flags_on(flags: u8, exec: &mut [u8]) -> usize {
+  let code = [
+    movz(TEMP2, flags as u16), // mov w13, #flags
+    orr_reg(AF, AF, TEMP2), // orr w19, w19, w13
+  ];
+  write_instructions(&code, exec)
+}
+
+/// Test `mask` against `reg`. Zero is set if the bit is clear, half-carry is
+/// set, negative is cleared, and carry is left alone.
+fn emit_bit_test(reg: u32, mask: u32, exec: &mut [u8]) -> usize {
+  let code = [
+    tst_imm(reg, mask), // tst reg, #mask
+    cset(TEMP, COND_EQ), // cset w12, eq
+    and_imm(AF, AF, !0xe0), // and w19, w19, #0xffffff1f
+    orr_imm(AF, AF, 0x20), // orr w19, w19, #0x20
+    orr_reg_lsl(AF, AF, TEMP, 7), // orr w19, w19, w12, lsl #7
+  ];
+  write_instructions(&code, exec)
+}
+
+/// The infamous DAA, used to adjust BCD math. Rather than branching, both the
+/// addition and subtraction adjustments are computed, and the correct one is
+/// selected by the negative flag.
+fn emit_daa(exec: &mut [u8]) -> usize {
+  let code = [
+    read_register_8(LHS, Register8::A), // ubfx w9, w19, #8, #8
+    // after an addition, the low nibble needs fixing if it overflowed or is
+    // above 9; after a subtraction, only if it borrowed
+    and_imm(TEMP, LHS, 0x0f), // and w12, w9, #0xf
+    cmp_imm(TEMP, 9), // cmp w12, #9
+    cset(TEMP, COND_HI), // cset w12, hi
+    ubfx(TEMP2, AF, 5, 1), // ubfx w13, w19, #5, #1
+    orr_reg(TEMP, TEMP, TEMP2), // orr w12, w12, w13
+    tst_imm(AF, 0x40), // tst w19, #0x40
+    csel(TEMP, TEMP2, TEMP, COND_NE), // csel w12, w13, w12, ne
+    // likewise for the high nibble, which also sets the carry
+    cmp_imm(LHS, 0x99), // cmp w9, #0x99
+    cset(RESULT, COND_HI), // cset w11, hi
+    ubfx(TEMP2, AF, 4, 1), // ubfx w13, w19, #4, #1
+    orr_reg(RESULT, RESULT, TEMP2), // orr w11, w11, w13
+    tst_imm(AF, 0x40), // tst w19, #0x40
+    csel(RESULT, TEMP2, RESULT, COND_NE), // csel w11, w13, w11, ne
+    // combine the adjustments into w10
+    neg(TEMP, TEMP), // neg w12, w12
+    and_imm(TEMP, TEMP, 0x06), // and w12, w12, #0x6
+    neg(TEMP2, RESULT), // neg w13, w11
+    and_imm(TEMP2, TEMP2, 0x60), // and w13, w13, #0x60
+    orr_reg(RHS, TEMP, TEMP2), // orr w10, w12, w13
+    // apply the adjustment in the direction of the last operation
+    add_reg(TEMP, LHS, RHS), // add w12, w9, w10
+    sub_reg(TEMP2, LHS, RHS), // sub w13, w9, w10
+    tst_imm(AF, 0x40), // tst w19, #0x40
+    csel(TEMP, TEMP2, TEMP, COND_NE), // csel w12, w13, w12, ne
+    write_register_8(Register8::A, TEMP), // bfi w19, w12, #8, #8
+    // set flags: Z from the result, H cleared, N preserved, C from the
+    // high nibble adjustment
+    movz(TEMP2, 0xb0), // mov w13, #0xb0
+    bic_reg(AF, AF, TEMP2), // bic w19, w19, w13
+    orr_reg_lsl(AF, AF, RESULT, 4), // orr w19, w19, w11, lsl #4
+    tst_imm(TEMP, 0xff), // tst w12, #0xff
+    cset(TEMP2, COND_EQ), // cset w13, eq
+    orr_reg_lsl(AF, AF, TEMP2, 7), // orr w19, w19, w13, lsl #7
+  ];
+  write_instructions(&code, exec)
+}
+
+/// Call a memory access function, with the address already in w1 and any
+/// value to write in w2. A read returns its result in w0. Every scratch
+/// register may be modified by the call.
+fn emit_memory_call(function: usize, exec: &mut [u8]) -> usize {
+  let mut code = Vec::with_capacity(6);
+  code.push(mov_reg_64(0, MEM)); // mov x0, x27
+  code.extend_from_slice(&load_address(CALL_TARGET, function as u64)); // mov x16, function
+  code.push(blr(CALL_TARGET)); // blr x16
+  write_instructions(&code, exec)
+}
+
+/// Read the value stored at (HL) into `dest`
+fn emit_hl_indirect_read(dest: u32, exec: &mut [u8]) -> usize {
+  let mut len = write_instructions(&[mov_reg(1, HL)], exec); // mov w1, w22
+  len += emit_memory_call(crate::mem::memory_read_byte as *const () as usize, &mut exec[len..]);
+  len + write_instructions(&[mov_reg(dest, 0)], &mut exec[len..]) // mov dest, w0
+}
+
+/// Write the low byte of `source` to (HL)
+fn emit_hl_indirect_write(source: u32, exec: &mut [u8]) -> usize {
+  let code = [
+    mov_reg(1, HL), // mov w1, w22
+    and_imm(2, source, 0xff), // and w2, source, #0xff
+  ];
+  let len = write_instructions(&code, exec);
+  len + emit_memory_call(crate::mem::memory_write_byte as *const () as usize, &mut exec[len..])
+}
+
+/// Apply the post-increment or post-decrement of LD (HL+) and LD (HL-)
+fn emit_hl_adjust(location: IndirectLocation, exec: &mut [u8]) -> usize {
+  let adjust = match location {
+    IndirectLocation::HLIncrement => add_imm(HL, HL, 1),
+    IndirectLocation::HLDecrement => sub_imm(HL, HL, 1),
+    _ => return 0,
+  };
+  write_instructions(&[adjust, and_imm(HL, HL, 0xffff)], exec)
+}
+
+fn emit_push(source: u32, exec: &mut [u8]) -> usize {
+  let code = [
+    sub_imm(SP, SP, 2), // sub w23, w23, #2
+    and_imm(SP, SP, 0xffff), // and w23, w23, #0xffff
+    mov_reg(1, SP), // mov w1, w23
+    mov_reg(2, source), // mov w2, source
+  ];
+  let len = write_instructions(&code, exec);
+  len + emit_memory_call(crate::mem::memory_push_word as *const () as usize, &mut exec[len..])
+}
+
+fn emit_pop(dest: u32, exec: &mut [u8]) -> usize {
+  let code = [
+    mov_reg(1, SP), // mov w1, w23
+    add_imm(SP, SP, 2), // add w23, w23, #2
+    and_imm(SP, SP, 0xffff), // and w23, w23, #0xffff
+  ];
+  let mut len = write_instructions(&code, exec);
+  len += emit_memory_call(crate::mem::memory_read_word as *const () as usize, &mut exec[len..]);
+  let store = if dest == AF {
+    // the low 4 bits of F are always zero
+    and_imm(AF, 0, 0xfff0) // and w19, w0, #0xfff0
+  } else {
+    mov_reg(dest, 0) // mov dest, w0
+  };
+  len + write_instructions(&[store], &mut exec[len..])
+}
+
+fn map_register_16(gb_reg: Register16) -> u32 {
+  match gb_reg {
+    Register16::AF => AF,
+    Register16::BC => BC,
+    Register16::DE => DE,
+    Register16::HL => HL,
+    Register16::SP => SP,
+  }
+}
+
+/// Returns the host register holding an 8-bit register, and the position of
+/// its lowest bit
+fn map_register_8(gb_reg: Register8) -> (u32, u32) {
+  match gb_reg {
+    Register8::A => (AF, 8),
+    Register8::B => (BC, 8),
+    Register8::C => (BC, 0),
+    Register8::D => (DE, 8),
+    Register8::E => (DE, 0),
+    Register8::H => (HL, 8),
+    Register8::L => (HL, 0),
+  }
+}
+
+fn map_indirect_location_to_register(location: IndirectLocation) -> u32 {
+  match location {
+    IndirectLocation::BC => BC,
+    IndirectLocation::DE => DE,
+    IndirectLocation::HL
+      | IndirectLocation::HLDecrement
+      | IndirectLocation::HLIncrement => HL,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Emitter, LINK_PATCH_OFFSET, write_link_displacement};
+  use super::sim::Machine;
+  use crate::cpu::{self, Registers};
+  use crate::decoder::decode;
+  use crate::interpreter;
+  use crate::ir::Block;
+  use crate::mem::{get_executable_memory_slice, MemoryAreas};
+
+  // Every test compiles GB code with the AArch64 backend and runs it in the
+  // simulator, then compares the result against the interpreter running the
+  // same code on its own copy of memory.
+
+  struct Random(u32);
+
+  impl Random {
+    fn next(&mut self) -> u32 {
+      self.0 ^= self.0 << 13;
+      self.0 ^= self.0 >> 17;
+      self.0 ^= self.0 << 5;
+      self.0
+    }
+
+    fn byte(&mut self) -> u8 {
+      self.next() as u8
+    }
+  }
+
+  /// The prelude and epilogue functions, followed by any compiled blocks
+  struct CompiledCode {
+    code: Vec<u8>,
+    epilogue: usize,
+    cursor: usize,
+  }
+
+  impl CompiledCode {
+    fn new() -> Self {
+      let mut code = vec![0; 0x4000];
+      let prelude_length = Emitter::write_prelude_function(&mut code);
+      let epilogue_length = Emitter::write_epilogue_function(&mut code[prelude_length..]);
+      Self {
+        code,
+        epilogue: prelude_length,
+        cursor: prelude_length + epilogue_length,
+      }
+    }
+
+    /// Compile the block at `ip`, returning the offset of its code and of its
+    /// epilogue
+    fn compile(&mut self, mem: *mut MemoryAreas, ip: usize, linkable: bool) -> (usize, usize) {
+      let mut ops = Vec::new();
+      let mut index = ip;
+      loop {
+        let (op, length, _) = decode(get_executable_memory_slice(index, mem).unwrap());
+        index += length;
+        let block_ended = op.is_block_end();
+        ops.push((op, length));
+        if block_ended {
+          break;
+        }
+      }
+      let mut block = Block::from_ops(ops, ip);
+      block.compute_live_flags();
+      let emitter = Emitter::new();
+      let start = self.cursor;
+      self.cursor += emitter.encode_block(block, &mut self.code[self.cursor..]);
+      let epilogue = self.cursor;
+      self.cursor += if linkable {
+        emitter.encode_linkable_epilogue(&mut self.code[self.cursor..])
+      } else {
+        emitter.encode_epilogue(&mut self.code[self.cursor..])
+      };
+      (start, epilogue)
+    }
+
+    fn run(&self, block: usize, registers: &mut Registers, mem: *mut MemoryAreas, budget: u64) -> u8 {
+      let base = self.code.as_ptr() as u64;
+      let args = [
+        registers as *mut Registers as u64,
+        base + block as u64,
+        base + self.epilogue as u64,
+        budget,
+        mem as u64,
+      ];
+      Machine::new().call(&self.code, 0, &args) as u8
+    }
+  }
+
+  fn random_memory(code: &[u8], random: &mut Random) -> MemoryAreas {
+    let mut mem = MemoryAreas::with_rom(code.to_vec().into_boxed_slice());
+    // random ops can move pointers anywhere, so give every RAM area a backing
+    mem.cart_ram = vec![0; 0x2000].into_boxed_slice();
+    mem.work_ram = vec![0; 0x2000].into_boxed_slice();
+    for byte in mem.work_ram.iter_mut() {
+      *byte = random.byte();
+    }
+    for byte in mem.high_ram.iter_mut() {
+      *byte = random.byte();
+    }
+    mem
+  }
+
+  /// Register pairs point into the first bank of WRAM, so that indirect ops
+  /// access plain RAM
+  fn random_registers(random: &mut Random) -> Registers {
+    let mut registers = Registers::new();
+    registers.af = random.next() & 0xfff0;
+    registers.bc = 0xc000 | (random.next() & 0xfff);
+    registers.de = 0xc000 | (random.next() & 0xfff);
+    registers.hl = 0xc000 | (random.next() & 0xfff);
+    registers.sp = 0xc100 | (random.next() & 0xefe);
+    registers
+  }
+
+  fn describe(registers: &Registers) -> String {
+    let Registers { af, bc, de, hl, sp, ip, cycles } = *registers;
+    format!(
+      "AF: {:04X} BC: {:04X} DE: {:04X} HL: {:04X} SP: {:04X} IP: {:04X} cycles: {}",
+      af, bc, de, hl, sp, ip, cycles,
+    )
+  }
+
+  /// Run the first block of `code` with the interpreter and with compiled
+  /// code, starting from the same random state, and panic on any difference
+  fn compare_block(code: &[u8], seed: u32) {
+    let mut random = Random(seed);
+    let registers = random_registers(&mut random);
+    let mut interpreted_mem = random_memory(code, &mut Random(seed));
+    let mut compiled_mem = random_memory(code, &mut Random(seed));
+
+    let mut interpreted = registers;
+    let interpreted_status = interpreter::run_code_block(&mut interpreted, &mut interpreted_mem);
+    // compiled code stores the IP as 16 bits, but the interpreter doesn't wrap
+    interpreted.ip &= 0xffff;
+
+    let mut compiled = registers;
+    let mut code_cache = CompiledCode::new();
+    let (block, _) = code_cache.compile(&mut compiled_mem, 0, false);
+    let compiled_status = code_cache.run(block, &mut compiled, &mut compiled_mem, 0xffff);
+
+    let context = format!("{:02X?} from {}", code, describe(&registers));
+    assert!(
+      interpreted == compiled,
+      "{}\n  interpreter: {}\n  compiled:    {}",
+      context,
+      describe(&interpreted),
+      describe(&compiled),
+    );
+    assert_eq!(interpreted_status, compiled_status, "Status differs for {}", context);
+    assert!(interpreted_mem.work_ram == compiled_mem.work_ram, "WRAM differs for {}", context);
+    assert!(interpreted_mem.high_ram == compiled_mem.high_ram, "HRAM differs for {}", context);
+  }
+
+  /// Build an instruction from `opcode`, filling any operands with random
+  /// bytes. Any 16-bit operand is an address in the first bank of WRAM.
+  /// Returns None for opcodes that don't exist.
+  fn random_instruction(opcode: &[u8], random: &mut Random) -> Option<Vec<u8>> {
+    let mut bytes = opcode.to_vec();
+    if bytes.len() == 1 {
+      bytes.push(random.byte());
+      bytes.push(0xc0 | (random.byte() & 0x07));
+    }
+    let (op, length, _) = decode(&bytes);
+    if let crate::decoder::ops::Op::Invalid(_) = op {
+      return None;
+    }
+    bytes.truncate(length);
+    Some(bytes)
+  }
+
+  #[test]
+  fn every_op() {
+    let mut random = Random(0x1234_5678);
+    for opcode in 0..=0xffu8 {
+      // The interpreter only advances past the first byte of STOP, while
+      // both backends skip its padding byte too
+      if opcode == 0xcb || opcode == 0x10 {
+        continue;
+      }
+      for _ in 0..16 {
+        if let Some(code) = random_instruction(&[opcode], &mut random) {
+          compare_block(&code, random.next());
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn every_cb_op() {
+    let mut random = Random(0x8765_4321);
+    for opcode in 0..=0xffu8 {
+      for _ in 0..8 {
+        let code = random_instruction(&[0xcb, opcode], &mut random).unwrap();
+        compare_block(&code, random.next());
+      }
+    }
+  }
+
+  #[test]
+  fn op_sequences() {
+    // Longer blocks exercise flags that are skipped when they're overwritten
+    // before being read
+    let mut random = Random(0x0bad_cafe);
+    for _ in 0..500 {
+      let mut code = Vec::new();
+      while code.len() < 24 {
+        let opcode = random.byte();
+        let instruction = if opcode == 0xcb {
+          random_instruction(&[0xcb, random.byte()], &mut random)
+        } else {
+          random_instruction(&[opcode], &mut random)
+        };
+        if let Some(instruction) = instruction {
+          let (op, _, _) = decode(&instruction);
+          if !op.is_block_end() {
+            code.extend(instruction);
+          }
+        }
+      }
+      compare_block(&code, random.next());
+    }
+  }
+
+  #[test]
+  fn linked_blocks() {
+    // JP 0x0010, followed at 0x0010 by INC A; HALT
+    let mut code = vec![0; 0x11];
+    code[0..3].copy_from_slice(&[0xc3, 0x10, 0x00]);
+    code[0x10] = 0x3c;
+    let mut mem = MemoryAreas::with_rom(code.into_boxed_slice());
+
+    let mut code_cache = CompiledCode::new();
+    let (first, epilogue) = code_cache.compile(&mut mem, 0, true);
+    let (second, _) = code_cache.compile(&mut mem, 0x10, false);
+    let patch_offset = epilogue + LINK_PATCH_OFFSET;
+
+    let mut expected = Registers::new();
+    interpreter::run_code_block(&mut expected, &mut mem);
+    let mut registers = Registers::new();
+    // unlinked, the first block exits back to the dispatcher
+    assert_eq!(code_cache.run(first, &mut registers, &mut mem, 0xffff), cpu::STATUS_NORMAL);
+    assert!(registers == expected, "{}", describe(&registers));
+
+    write_link_displacement(&mut code_cache.code, patch_offset, Some(second));
+    // once linked, it continues into the second block
+    let mut registers = Registers::new();
+    assert_eq!(code_cache.run(first, &mut registers, &mut mem, 0xffff), cpu::STATUS_HALT);
+    interpreter::run_code_block(&mut expected, &mut mem);
+    assert!(registers == expected, "{}", describe(&registers));
+
+    // unless the cycle budget has already been used up
+    let mut registers = Registers::new();
+    assert_eq!(code_cache.run(first, &mut registers, &mut mem, 1), cpu::STATUS_NORMAL);
+    assert_eq!({ registers.ip }, 0x10);
+
+    write_link_displacement(&mut code_cache.code, patch_offset, None);
+    let mut registers = Registers::new();
+    assert_eq!(code_cache.run(first, &mut registers, &mut mem, 0xffff), cpu::STATUS_NORMAL);
+    assert_eq!({ registers.ip }, 0x10);
+  }
+}
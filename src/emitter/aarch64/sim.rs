@@ -0,0 +1,499 @@
+//! A minimal interpreter for the A64 instructions the emitter produces, so
+//! that the backend can be tested on hosts that can't run it natively. Loads
+//! and stores go straight to host memory, and calls to the memory access
+//! functions are forwarded to the real Rust implementations.
+
+use crate::mem::{self, MemoryAreas};
+
+/// Return address used for the outermost call. Reaching it ends the run.
+const SENTINEL: u64 = 0x5e47_1e1e_0000;
+
+const STACK_SIZE: usize = 1024;
+
+#[derive(Copy, Clone)]
+enum HostFunction {
+  ReadByte,
+  WriteByte,
+  ReadWord,
+  WriteWord,
+  PushWord,
+}
+
+pub struct Machine {
+  x: [u64; 31],
+  sp: u64,
+  pc: u64,
+  n: bool,
+  z: bool,
+  c: bool,
+  v: bool,
+  stack: Vec<u64>,
+  host_functions: Vec<(u64, HostFunction)>,
+}
+
+impl Machine {
+  pub fn new() -> Self {
+    let mut machine = Self {
+      x: [0; 31],
+      sp: 0,
+      pc: 0,
+      n: false,
+      z: false,
+      c: false,
+      v: false,
+      stack: vec![0; STACK_SIZE],
+      host_functions: vec![
+        (mem::memory_read_byte as *const () as u64, HostFunction::ReadByte),
+        (mem::memory_write_byte as *const () as u64, HostFunction::WriteByte),
+        (mem::memory_read_word as *const () as u64, HostFunction::ReadWord),
+        (mem::memory_write_word as *const () as u64, HostFunction::WriteWord),
+        (mem::memory_push_word as *const () as u64, HostFunction::PushWord),
+      ],
+    };
+    machine.sp = machine.stack_top();
+    machine
+  }
+
+  fn stack_top(&self) -> u64 {
+    (self.stack.as_ptr() as u64 + (STACK_SIZE * 8) as u64) & !0xf
+  }
+
+  /// Call the function at `entry` with up to eight arguments, following the
+  /// standard procedure call convention, and return the value left in X0.
+  /// Panics if the callee-saved registers or stack aren't restored.
+  pub fn call(&mut self, code: &[u8], entry: usize, args: &[u64]) -> u64 {
+    for (index, reg) in self.x.iter_mut().enumerate() {
+      *reg = 0x0bad_0000_0000_0000 | (index as u64);
+    }
+    for (index, arg) in args.iter().enumerate() {
+      self.x[index] = *arg;
+    }
+    self.sp = self.stack_top();
+    self.x[30] = SENTINEL;
+    self.pc = code.as_ptr() as u64 + entry as u64;
+    let saved: Vec<u64> = self.x[19..30].to_vec();
+
+    let base = code.as_ptr() as u64;
+    let mut steps = 0;
+    while self.pc != SENTINEL {
+      assert!(self.pc >= base && self.pc < base + code.len() as u64, "Branched outside of code: {:#x}", self.pc);
+      let offset = (self.pc - base) as usize;
+      let mut word = [0; 4];
+      word.copy_from_slice(&code[offset..(offset + 4)]);
+      self.step(u32::from_le_bytes(word));
+      steps += 1;
+      assert!(steps < 1_000_000, "Code did not return");
+    }
+
+    assert_eq!(&self.x[19..30], &saved[..], "Callee-saved registers were not restored");
+    assert_eq!(self.sp, self.stack_top(), "Stack pointer was not restored");
+    self.x[0]
+  }
+
+  fn read(&self, reg: u32) -> u64 {
+    if reg == 31 { 0 } else { self.x[reg as usize] }
+  }
+
+  fn read_or_sp(&self, reg: u32) -> u64 {
+    if reg == 31 { self.sp } else { self.x[reg as usize] }
+  }
+
+  fn write(&mut self, reg: u32, value: u64, wide: bool) {
+    if reg != 31 {
+      self.x[reg as usize] = if wide { value } else { value & 0xffffffff };
+    }
+  }
+
+  fn write_or_sp(&mut self, reg: u32, value: u64, wide: bool) {
+    let value = if wide { value } else { value & 0xffffffff };
+    if reg == 31 {
+      self.sp = value;
+    } else {
+      self.x[reg as usize] = value;
+    }
+  }
+
+  fn condition(&self, cond: u32) -> bool {
+    let result = match cond >> 1 {
+      0 => self.z,
+      1 => self.c,
+      2 => self.n,
+      3 => self.v,
+      4 => self.c && !self.z,
+      5 => self.n == self.v,
+      6 => self.n == self.v && !self.z,
+      _ => true,
+    };
+    if cond & 1 != 0 && cond != 0xf {
+      !result
+    } else {
+      result
+    }
+  }
+
+  /// Add with carry, updating NZCV when `set_flags` is true
+  fn add_with_carry(&mut self, a: u64, b: u64, carry: u64, wide: bool, set_flags: bool) -> u64 {
+    let (mask, sign) = if wide { (u64::MAX, 63) } else { (0xffffffff, 31) };
+    let a = a & mask;
+    let b = b & mask;
+    let sum = (a as u128) + (b as u128) + (carry as u128);
+    let result = (sum as u64) & mask;
+    if set_flags {
+      self.n = (result >> sign) & 1 != 0;
+      self.z = result == 0;
+      self.c = sum > mask as u128;
+      let a_sign = (a >> sign) & 1;
+      let b_sign = (b >> sign) & 1;
+      let r_sign = (result >> sign) & 1;
+      self.v = a_sign == b_sign && a_sign != r_sign;
+    }
+    result
+  }
+
+  fn call_host(&mut self, function: HostFunction) {
+    assert_eq!(self.sp & 0xf, 0, "Stack must be 16-byte aligned at calls");
+    let areas = self.x[0] as *mut MemoryAreas;
+    let address = self.x[1] as u16;
+    let result = match function {
+      HostFunction::ReadByte => mem::memory_read_byte(areas, address) as u64,
+      HostFunction::WriteByte => {
+        mem::memory_write_byte(areas, address, self.x[2] as u8);
+        0xdead
+      },
+      HostFunction::ReadWord => mem::memory_read_word(areas, address) as u64,
+      HostFunction::WriteWord => {
+        mem::memory_write_word(areas, address, self.x[2] as u16);
+        0xdead
+      },
+      HostFunction::PushWord => {
+        mem::memory_push_word(areas, address, self.x[2] as u16);
+        0xdead
+      },
+    };
+    // the callee may use any caller-saved register
+    for reg in 1..=18 {
+      self.x[reg] = 0xdead_beef_0000_0000 | (reg as u64);
+    }
+    self.x[0] = result;
+  }
+
+  fn step(&mut self, insn: u32) {
+    let rd = insn & 0x1f;
+    let rn = (insn >> 5) & 0x1f;
+    let rm = (insn >> 16) & 0x1f;
+    let wide = insn & 0x8000_0000 != 0;
+    let mut next = self.pc.wrapping_add(4);
+
+    if insn & 0x1f00_0000 == 0x1100_0000 {
+      // add/sub immediate
+      let mut imm = ((insn >> 10) & 0xfff) as u64;
+      if insn & (1 << 22) != 0 {
+        imm <<= 12;
+      }
+      let subtract = insn & (1 << 30) != 0;
+      let set_flags = insn & (1 << 29) != 0;
+      let a = self.read_or_sp(rn);
+      let result = if subtract {
+        self.add_with_carry(a, !imm, 1, wide, set_flags)
+      } else {
+        self.add_with_carry(a, imm, 0, wide, set_flags)
+      };
+      if set_flags {
+        self.write(rd, result, wide);
+      } else {
+        self.write_or_sp(rd, result, wide);
+      }
+    } else if insn & 0x1f20_0000 == 0x0b00_0000 {
+      // add/sub shifted register
+      let b = shift(self.read(rm), (insn >> 22) & 3, (insn >> 10) & 0x3f, wide);
+      let subtract = insn & (1 << 30) != 0;
+      let set_flags = insn & (1 << 29) != 0;
+      let a = self.read(rn);
+      let result = if subtract {
+        self.add_with_carry(a, !b, 1, wide, set_flags)
+      } else {
+        self.add_with_carry(a, b, 0, wide, set_flags)
+      };
+      self.write(rd, result, wide);
+    } else if insn & 0x1f00_0000 == 0x0a00_0000 {
+      // logical shifted register
+      let mut b = shift(self.read(rm), (insn >> 22) & 3, (insn >> 10) & 0x3f, wide);
+      if insn & (1 << 21) != 0 {
+        b = !b;
+      }
+      let opc = (insn >> 29) & 3;
+      let result = self.logical(opc, self.read(rn), b, wide);
+      self.write(rd, result, wide);
+    } else if insn & 0x1f80_0000 == 0x1200_0000 {
+      // logical immediate
+      assert!(!wide, "Only 32-bit logical immediates are supported");
+      let imm = decode_bit_masks((insn >> 22) & 1, (insn >> 16) & 0x3f, (insn >> 10) & 0x3f);
+      let opc = (insn >> 29) & 3;
+      let result = self.logical(opc, self.read(rn), imm, wide);
+      if opc == 3 {
+        self.write(rd, result, wide);
+      } else {
+        self.write_or_sp(rd, result, wide);
+      }
+    } else if insn & 0x1f80_0000 == 0x1280_0000 {
+      // move wide
+      let position = ((insn >> 21) & 3) * 16;
+      let imm = (((insn >> 5) & 0xffff) as u64) << position;
+      let result = match (insn >> 29) & 3 {
+        0 => !imm,
+        2 => imm,
+        3 => (self.read(rd) & !(0xffff << position)) | imm,
+        _ => panic!("Unallocated move wide: {:#010x}", insn),
+      };
+      self.write(rd, result, wide);
+    } else if insn & 0x1f80_0000 == 0x1300_0000 {
+      // bitfield move
+      assert!(!wide, "Only 32-bit bitfield moves are supported");
+      let immr = (insn >> 16) & 0x3f;
+      let imms = (insn >> 10) & 0x3f;
+      let src = self.read(rn) as u32;
+      let (field, dest_mask) = if imms >= immr {
+        let width = imms - immr + 1;
+        let mask = low_mask(width);
+        ((src >> immr) & mask, mask)
+      } else {
+        let mask = low_mask(imms + 1);
+        ((src & mask) << (32 - immr), mask << (32 - immr))
+      };
+      let result = match (insn >> 29) & 3 {
+        1 => (self.read(rd) as u32 & !dest_mask) | field,
+        2 => field,
+        _ => panic!("Unsupported bitfield move: {:#010x}", insn),
+      };
+      self.write(rd, result as u64, wide);
+    } else if insn & 0x3fe0_0000 == 0x1a80_0000 {
+      // conditional select
+      let cond = (insn >> 12) & 0xf;
+      let result = if self.condition(cond) {
+        self.read(rn)
+      } else {
+        match (insn >> 10) & 3 {
+          0 => self.read(rm),
+          1 => self.read(rm).wrapping_add(1),
+          _ => panic!("Unsupported conditional select: {:#010x}", insn),
+        }
+      };
+      self.write(rd, result, wide);
+    } else if insn & 0x7c00_0000 == 0x1400_0000 {
+      // b, bl
+      if insn & 0x8000_0000 != 0 {
+        self.x[30] = next;
+      }
+      next = self.pc.wrapping_add(sign_extend(insn & 0x3ff_ffff, 26) << 2);
+    } else if insn & 0xff00_0010 == 0x5400_0000 {
+      // b.cond
+      if self.condition(insn & 0xf) {
+        next = self.pc.wrapping_add(sign_extend((insn >> 5) & 0x7ffff, 19) << 2);
+      }
+    } else if insn & 0x7e00_0000 == 0x3400_0000 {
+      // cbz, cbnz
+      let value = if wide { self.read(rd) } else { self.read(rd) & 0xffffffff };
+      let nonzero = insn & (1 << 24) != 0;
+      if (value != 0) == nonzero {
+        next = self.pc.wrapping_add(sign_extend((insn >> 5) & 0x7ffff, 19) << 2);
+      }
+    } else if insn & 0x7e00_0000 == 0x3600_0000 {
+      // tbz, tbnz
+      let bit = ((insn >> 19) & 0x1f) | ((insn >> 26) & 0x20);
+      let set = (self.read(rd) >> bit) & 1 != 0;
+      let nonzero = insn & (1 << 24) != 0;
+      if set == nonzero {
+        next = self.pc.wrapping_add(sign_extend((insn >> 5) & 0x3fff, 14) << 2);
+      }
+    } else if insn & 0xff9f_fc1f == 0xd61f_0000 {
+      // br, blr, ret
+      let target = self.read(rn);
+      let link = insn & (1 << 21) != 0;
+      let host_function = self.host_functions
+        .iter()
+        .find(|(address, _)| *address == target)
+        .map(|(_, function)| *function);
+      match host_function {
+        Some(function) => {
+          assert!(link, "Host functions must be called with blr");
+          self.call_host(function);
+        },
+        None => {
+          if link {
+            self.x[30] = next;
+          }
+          next = target;
+        },
+      }
+    } else if insn & 0x3b00_0000 == 0x3900_0000 {
+      // load/store, unsigned offset
+      let size = insn >> 30;
+      let address = self.read_or_sp(rn) + ((((insn >> 10) & 0xfff) as u64) << size);
+      let load = match (insn >> 22) & 3 {
+        0 => false,
+        1 => true,
+        _ => panic!("Unsupported load/store: {:#010x}", insn),
+      };
+      unsafe {
+        if load {
+          let value = match size {
+            0 => *(address as *const u8) as u64,
+            1 => (address as *const u16).read_unaligned() as u64,
+            2 => (address as *const u32).read_unaligned() as u64,
+            _ => (address as *const u64).read_unaligned(),
+          };
+          self.write(rd, value, true);
+        } else {
+          let value = self.read(rd);
+          match size {
+            0 => *(address as *mut u8) = value as u8,
+            1 => (address as *mut u16).write_unaligned(value as u16),
+            2 => (address as *mut u32).write_unaligned(value as u32),
+            _ => (address as *mut u64).write_unaligned(value),
+          }
+        }
+      }
+    } else if insn & 0xfe00_0000 == 0xa800_0000 {
+      // 64-bit load/store pair
+      let rt2 = (insn >> 10) & 0x1f;
+      let offset = sign_extend((insn >> 15) & 0x7f, 7) << 3;
+      let base = self.read_or_sp(rn);
+      let (address, writeback) = match (insn >> 23) & 3 {
+        1 => (base, Some(base.wrapping_add(offset))),
+        2 => (base.wrapping_add(offset), None),
+        3 => (base.wrapping_add(offset), Some(base.wrapping_add(offset))),
+        _ => panic!("Unsupported load/store pair: {:#010x}", insn),
+      };
+      unsafe {
+        if insn & (1 << 22) != 0 {
+          let first = *(address as *const u64);
+          let second = *((address + 8) as *const u64);
+          self.write(rd, first, true);
+          self.write(rt2, second, true);
+        } else {
+          *(address as *mut u64) = self.read(rd);
+          *((address + 8) as *mut u64) = self.read(rt2);
+        }
+      }
+      if let Some(value) = writeback {
+        self.write_or_sp(rn, value, true);
+      }
+    } else {
+      panic!("Unsupported instruction: {:#010x}", insn);
+    }
+
+    self.pc = next;
+  }
+
+  fn logical(&mut self, opc: u32, a: u64, b: u64, wide: bool) -> u64 {
+    let mask = if wide { u64::MAX } else { 0xffffffff };
+    let result = match opc {
+      0 | 3 => a & b,
+      1 => a | b,
+      _ => a ^ b,
+    } & mask;
+    if opc == 3 {
+      let sign = if wide { 63 } else { 31 };
+      self.n = (result >> sign) & 1 != 0;
+      self.z = result == 0;
+      self.c = false;
+      self.v = false;
+    }
+    result
+  }
+}
+
+fn low_mask(width: u32) -> u32 {
+  if width >= 32 { 0xffffffff } else { (1 << width) - 1 }
+}
+
+fn sign_extend(value: u32, bits: u32) -> u64 {
+  let shift = 64 - bits;
+  (((value as u64) << shift) as i64 >> shift) as u64
+}
+
+fn shift(value: u64, kind: u32, amount: u32, wide: bool) -> u64 {
+  if wide {
+    match kind {
+      0 => value << amount,
+      1 => value >> amount,
+      2 => ((value as i64) >> amount) as u64,
+      _ => value.rotate_right(amount),
+    }
+  } else {
+    let value = value as u32;
+    (match kind {
+      0 => value << amount,
+      1 => value >> amount,
+      2 => ((value as i32) >> amount) as u32,
+      _ => value.rotate_right(amount),
+    }) as u64
+  }
+}
+
+/// Expand the N:immr:imms fields of a 32-bit logical immediate
+fn decode_bit_masks(n: u32, immr: u32, imms: u32) -> u64 {
+  assert_eq!(n, 0, "64-bit element sizes are not valid for 32-bit operations");
+  let length = 31 - (!imms & 0x3f).leading_zeros();
+  let size = 1 << length;
+  let levels = size - 1;
+  let ones = (imms & levels) + 1;
+  let rotation = immr & levels;
+  let element_mask = low_mask(size);
+  let run = low_mask(ones);
+  let element = if rotation == 0 {
+    run
+  } else {
+    ((run >> rotation) | (run << (size - rotation))) & element_mask
+  };
+  let mut value = 0u32;
+  let mut position = 0;
+  while position < 32 {
+    value |= element << position;
+    position += size;
+  }
+  value as u64
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::encoding::*;
+
+  fn assemble(code: &[u32]) -> Vec<u8> {
+    code.iter().flat_map(|word| word.to_le_bytes()).collect()
+  }
+
+  #[test]
+  fn bit_masks() {
+    for value in [0xff00, 0xffffff1f, 0x55555555, 0x10, 0xfff0, 0x7fffffff] {
+      let insn = and_imm(0, 0, value);
+      assert_eq!(
+        decode_bit_masks((insn >> 22) & 1, (insn >> 16) & 0x3f, (insn >> 10) & 0x3f),
+        value as u64,
+      );
+    }
+  }
+
+  #[test]
+  fn flags_and_branches() {
+    let code = assemble(&[
+      cmp_imm(0, 5), // cmp w0, #5
+      cset(0, COND_HS), // cset w0, hs
+      b_cond(COND_EQ, 2), // b.eq +2
+      orr_imm(0, 0, 0x10), // orr w0, w0, #0x10
+      ret(),
+    ]);
+    let mut machine = Machine::new();
+    assert_eq!(machine.call(&code, 0, &[7]), 0x11);
+    assert_eq!(machine.call(&code, 0, &[5]), 0x01);
+    assert_eq!(machine.call(&code, 0, &[3]), 0x10);
+  }
+
+  #[test]
+  #[should_panic(expected = "Callee-saved")]
+  fn detects_clobbered_registers() {
+    let code = assemble(&[movz(19, 0), ret()]);
+    Machine::new().call(&code, 0, &[]);
+  }
+}
@@ -0,0 +1,109 @@
+//! Lazy flag evaluation. Materializing GB flags into AL takes a dozen host
+//! instructions after most arithmetic ops, but the result is usually
+//! overwritten by the next arithmetic op before anything reads it. Before a
+//! block is emitted, a backwards pass over its ops (see
+//! `ir::Block::compute_live_flags`) determines which flags are still needed
+//! after each op, and the emitter skips computing the rest.
+
+use crate::decoder::ops::{JumpCondition, Op, Register16};
+
+pub const FLAG_ZERO: u8 = 0x80;
+pub const FLAG_NEGATIVE: u8 = 0x40;
+pub const FLAG_HALF_CARRY: u8 = 0x20;
+pub const FLAG_CARRY: u8 = 0x10;
+pub const ALL_FLAGS: u8 = 0xf0;
+
+/// Flags whose current value affects the result of an op
+pub fn flags_read(op: &Op) -> u8 {
+  match op {
+    Op::AddWithCarry8(_, _)
+      | Op::AddAbsoluteWithCarry8(_)
+      | Op::AddIndirectWithCarry
+      | Op::SubWithCarry8(_, _)
+      | Op::SubAbsoluteWithCarry8(_)
+      | Op::SubIndirectWithCarry
+      | Op::RotateLeftA
+      | Op::RotateLeft(_)
+      | Op::RotateLeftIndirect
+      | Op::RotateRightA
+      | Op::RotateRight(_)
+      | Op::RotateRightIndirect
+      | Op::ComplementCarryFlag => FLAG_CARRY,
+    Op::DAA => FLAG_NEGATIVE | FLAG_HALF_CARRY | FLAG_CARRY,
+    Op::Jump(condition, _)
+      | Op::JumpRelative(condition, _)
+      | Op::Call(condition, _)
+      | Op::Return(condition) => match condition {
+        JumpCondition::Always => 0,
+        JumpCondition::Zero | JumpCondition::NonZero => FLAG_ZERO,
+        JumpCondition::Carry | JumpCondition::NoCarry => FLAG_CARRY,
+      },
+    Op::Push(Register16::AF) => ALL_FLAGS,
+    Op::Invalid(_) => ALL_FLAGS,
+    _ => 0,
+  }
+}
+
+/// Flags that an op always overwrites, regardless of their previous value
+pub fn flags_written(op: &Op) -> u8 {
+  match op {
+    Op::Increment8(_)
+      | Op::Decrement8(_)
+      | Op::IncrementHLIndirect
+      | Op::DecrementHLIndirect
+      | Op::BitTest(_, _)
+      | Op::BitTestIndirect(_) => FLAG_ZERO | FLAG_NEGATIVE | FLAG_HALF_CARRY,
+    Op::AddHL(_) => FLAG_NEGATIVE | FLAG_HALF_CARRY | FLAG_CARRY,
+    Op::Add8(_, _)
+      | Op::AddWithCarry8(_, _)
+      | Op::AddAbsolute8(_)
+      | Op::AddAbsoluteWithCarry8(_)
+      | Op::AddIndirect
+      | Op::AddIndirectWithCarry
+      | Op::Sub8(_, _)
+      | Op::SubAbsolute8(_)
+      | Op::SubWithCarry8(_, _)
+      | Op::SubAbsoluteWithCarry8(_)
+      | Op::SubIndirect
+      | Op::SubIndirectWithCarry
+      | Op::And8(_, _)
+      | Op::AndAbsolute8(_)
+      | Op::AndIndirect
+      | Op::Or8(_, _)
+      | Op::OrAbsolute8(_)
+      | Op::OrIndirect
+      | Op::Xor8(_, _)
+      | Op::XorAbsolute8(_)
+      | Op::XorIndirect
+      | Op::Compare8(_)
+      | Op::CompareAbsolute8(_)
+      | Op::CompareIndirect
+      | Op::RotateLeftCarryA
+      | Op::RotateLeftCarry(_)
+      | Op::RotateLeftCarryIndirect
+      | Op::RotateLeftA
+      | Op::RotateLeft(_)
+      | Op::RotateLeftIndirect
+      | Op::RotateRightCarryA
+      | Op::RotateRightCarry(_)
+      | Op::RotateRightCarryIndirect
+      | Op::RotateRightA
+      | Op::RotateRight(_)
+      | Op::RotateRightIndirect
+      | Op::ShiftLeft(_)
+      | Op::ShiftLeftIndirect
+      | Op::ShiftRight(_)
+      | Op::ShiftRightIndirect
+      | Op::ShiftRightLogical(_)
+      | Op::ShiftRightLogicalIndirect
+      | Op::Swap(_)
+      | Op::SwapIndirect
+      | Op::AddSP(_)
+      | Op::LoadStackOffset(_)
+      | Op::Pop(Register16::AF) => ALL_FLAGS,
+    Op::DAA => FLAG_ZERO | FLAG_HALF_CARRY | FLAG_CARRY,
+    Op::ComplementA => FLAG_NEGATIVE | FLAG_HALF_CARRY,
+    Op::ComplementCarryFlag | Op::SetCarryFlag => FLAG_NEGATIVE | FLAG_HALF_CARRY | FLAG_CARRY,
+    _ => 0,
+  }
+}
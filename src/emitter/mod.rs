@@ -1,3 +1,43 @@
+pub mod aarch64;
+pub mod flags;
 pub mod x86_64;
 
-pub use x86_64::Emitter;
+// The backend is chosen by the host architecture. Both are always compiled,
+// so that the AArch64 backend can be tested on x86_64 hosts.
+#[cfg(target_arch = "aarch64")]
+pub use aarch64::{flush_instruction_cache, write_link_displacement, Emitter, LINK_PATCH_OFFSET};
+#[cfg(not(target_arch = "aarch64"))]
+pub use x86_64::{flush_instruction_cache, write_link_displacement, Emitter, LINK_PATCH_OFFSET};
+
+/// Most host code that a single GB op is encoded to on either backend, with
+/// room to spare. Blocks are encoded into a buffer with this much space for
+/// each op, plus an epilogue.
+pub const MAX_OP_LENGTH: usize = 256;
+pub const MAX_EPILOGUE_LENGTH: usize = 64;
+
+#[cfg(test)]
+mod tests {
+  use super::{aarch64, x86_64, MAX_EPILOGUE_LENGTH, MAX_OP_LENGTH};
+  use crate::decoder::decode;
+  use crate::ir::Block;
+
+  #[test]
+  fn encoded_lengths_fit_buffers() {
+    let mut exec = vec![0; MAX_OP_LENGTH * 2];
+    for prefix in [None, Some(0xcb)] {
+      for byte in 0..=255 {
+        let bytes = match prefix {
+          Some(prefix) => [prefix, byte, 0x12, 0x34],
+          None => [byte, 0x12, 0x34, 0x00],
+        };
+        let (op, length, _) = decode(&bytes);
+        let x86_length = x86_64::Emitter::new().encode_block(Block::from_ops(vec![(op, length)], 0x150), &mut exec);
+        let (op, length, _) = decode(&bytes);
+        let arm_length = aarch64::Emitter::new().encode_block(Block::from_ops(vec![(op, length)], 0x150), &mut exec);
+        assert!(x86_length.max(arm_length) <= MAX_OP_LENGTH, "{:02x?}", bytes);
+      }
+    }
+    assert!(x86_64::Emitter::new().encode_linkable_epilogue(&mut exec) <= MAX_EPILOGUE_LENGTH);
+    assert!(aarch64::Emitter::new().encode_linkable_epilogue(&mut exec) <= MAX_EPILOGUE_LENGTH);
+  }
+}
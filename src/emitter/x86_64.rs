@@ -1,6 +1,9 @@
 use crate::cpu;
 use crate::decoder::ops::{Op, IndirectLocation, JumpCondition, Register8, Register16};
+use crate::ir::{Block, Instruction, PairSource};
 use crate::mem::MemoryAreas;
+use std::cell::Cell;
+use std::mem::offset_of;
 
 // Register Usage
 // When running compiled code, the emulator keeps all GB CPU state in registers.
@@ -16,16 +19,74 @@ use crate::mem::MemoryAreas;
 // R13  |  IP
 // R14  |  Code block return state
 // R15  |  Accumulated CPU cycles
+// RBP  |  Pointer to MemoryAreas
+//
+// The cycle budget for a call is kept on the stack, above the epilogue address
+// at [rsp + 8]. Calls out to Rust code are free to clobber any of the
+// remaining scratch registers, so none of them can hold it.
+//
+// Rust code called to access memory takes the MemoryAreas pointer as its first
+// argument, which is copied from RBP rather than embedded in each call. This
+// keeps every call site short, and leaves compiled blocks independent of where
+// the memory happens to live.
+
+/// Offset of the patchable rel32 displacement within a linkable epilogue
+pub const LINK_PATCH_OFFSET: usize = 34;
+
+/// Where compiled code finds the page table, relative to the memory pointer in
+/// RBP, along with the bits marking writes to high RAM
+const READ_PAGES: u32 = offset_of!(MemoryAreas, pages.read) as u32;
+const WRITE_PAGES: u32 = offset_of!(MemoryAreas, pages.write) as u32;
+const HIGH_RAM_PAGE: u32 = offset_of!(MemoryAreas, pages.high_ram) as u32;
+const HIGH_RAM_DIRTY: u32 = offset_of!(MemoryAreas, dirty_ram.high_ram) as u32;
 
 pub struct Emitter {
-  mem: *const MemoryAreas,
+  /// Flags that may be read before the next op overwrites them. Any flag not
+  /// in this mask is left stale instead of being computed.
+  live_flags: Cell<u8>,
+}
+
+impl Default for Emitter {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Emitter {
-  pub fn new(mem: *const MemoryAreas) -> Self {
+  pub fn new() -> Self {
     Self {
-      mem,
+      live_flags: Cell::new(0xf0),
+    }
+  }
+
+  /// Set the flags that must be materialized by the next encoded op. See
+  /// `flags::live_flags_after` for how these are computed.
+  pub fn set_live_flags(&self, flags: u8) {
+    self.live_flags.set(flags);
+  }
+
+  fn store_flags(&self, mask: u8, negative: bool, exec: &mut [u8]) -> usize {
+    let mask = mask & self.live_flags.get();
+    if mask == 0 {
+      return 0;
+    }
+    emit_store_flags(mask, negative, exec)
+  }
+
+  fn force_flags_off(&self, flags: u8, exec: &mut [u8]) -> usize {
+    let flags = flags & self.live_flags.get();
+    if flags == 0 {
+      return 0;
     }
+    emit_force_flags_off(flags, exec)
+  }
+
+  fn force_flags_on(&self, flags: u8, exec: &mut [u8]) -> usize {
+    let flags = flags & self.live_flags.get();
+    if flags == 0 {
+      return 0;
+    }
+    emit_force_flags_on(flags, exec)
   }
 
   pub fn write_prelude_function(exec: &mut [u8]) -> usize {
@@ -39,7 +100,10 @@ impl Emitter {
       0x41, 0x57, // push r15
       // preserve arguments that are needed later
       0x57, // push rdi
+      0x51, // push rcx (cycle budget)
       0x52, // push rdx
+      // keep the memory pointer for the rest of the call
+      0x4c, 0x89, 0xc5, // mov rbp, r8
       // set initial return code
       0x4d, 0x31, 0xf6, // xor r14, r14
       // begin code block, load all registers from a struct in memory
@@ -62,6 +126,7 @@ impl Emitter {
   pub fn write_epilogue_function(exec: &mut [u8]) -> usize {
     let code = [
       // restore the registers to the struct before returning
+      0x5e, // pop rsi (discard the cycle budget)
       0x5f, // pop rdi
       0x89, 0x07, // mov [rdi], eax
       0x89, 0x5f, 0x04, // mov [rdi + 4], ebx
@@ -96,9 +161,90 @@ impl Emitter {
     length
   }
 
+  /// Epilogue for a block that can be linked to its successor. Before exiting
+  /// to the dispatcher, it checks whether the block requested any special
+  /// handling, whether an enabled interrupt is waiting to be serviced, and
+  /// whether the cycle budget for this call has been used up, meaning
+  /// peripherals need to catch up. If none are true, it takes the jump at
+  /// LINK_PATCH_OFFSET.
+  /// While that jump is unpatched, its zero displacement falls straight
+  /// through to the regular epilogue.
+  pub fn encode_linkable_epilogue(&self, exec: &mut [u8]) -> usize {
+    let flag_offset = (offset_of!(MemoryAreas, io.interrupt_flag) as u32).to_le_bytes();
+    let mask_offset = (offset_of!(MemoryAreas, io.interrupt_mask) as u32).to_le_bytes();
+    let code = vec![
+      // Writes to IE or IF within a chain can raise an interrupt, which the
+      // dispatcher needs to handle before the chain continues
+      0x40, 0x8a, 0xb5, // mov sil, [rbp + interrupt_flag]
+        flag_offset[0], flag_offset[1], flag_offset[2], flag_offset[3],
+      0x40, 0x22, 0xb5, // and sil, [rbp + interrupt_mask]
+        mask_offset[0], mask_offset[1], mask_offset[2], mask_offset[3],
+      0x40, 0xf6, 0xc6, 0x1f, // test sil, 0x1f
+      0x75, 0x12, // jnz exit
+      0x45, 0x84, 0xf6, // test r14b, r14b
+      0x75, 0x0d, // jnz exit
+      0x66, 0x44, 0x3b, 0x7c, 0x24, 0x08, // cmp r15w, [rsp + 8]
+      0x73, 0x05, // jae exit
+      0xe9, 0x00, 0x00, 0x00, 0x00, // jmp linked block
+      // exit:
+      0x5f, // pop rdi
+      0xff, 0xe7, // jmp rdi
+    ];
+    let length = code.len();
+    exec[..length].copy_from_slice(&code);
+    length
+  }
+
+  /// Encode every op in a block. When the block collapses IP increments, each
+  /// op before the last is encoded without one, and their combined length is
+  /// added to the IP right before the last op, which may depend on it.
+  pub fn encode_block(&self, block: Block, exec: &mut [u8]) -> usize {
+    self.encode_ops(block, exec, None)
+  }
+
+  /// Encode a block like `encode_block`, also recording where the code for
+  /// each op starts, relative to the start of the block
+  pub fn encode_block_with_offsets(&self, block: Block, exec: &mut [u8], offsets: &mut Vec<usize>) -> usize {
+    self.encode_ops(block, exec, Some(offsets))
+  }
+
+  fn encode_ops(&self, block: Block, exec: &mut [u8], mut offsets: Option<&mut Vec<usize>>) -> usize {
+    let mut len = 0;
+    let mut pending_ip = 0;
+    let last_index = block.ops.len().saturating_sub(1);
+    for (index, ir_op) in block.ops.into_iter().enumerate() {
+      if let Some(offsets) = offsets.as_mut() {
+        offsets.push(len);
+      }
+      self.set_live_flags(ir_op.live_flags);
+      let deferred = block.collapse_ip && index < last_index;
+      let ip_increment = if deferred {
+        pending_ip += ir_op.length;
+        0
+      } else {
+        len += emit_ip_increment(pending_ip, &mut exec[len..]);
+        pending_ip = 0;
+        ir_op.length
+      };
+      len += match ir_op.instruction {
+        Instruction::Single(op) => self.encode_op(op, ip_increment, &mut exec[len..]),
+        Instruction::BitTestSequence(tests) => {
+          let tests: Vec<(u8, usize)> = if deferred {
+            tests.iter().map(|(mask, _)| (*mask, 0)).collect()
+          } else {
+            tests
+          };
+          self.encode_bit_test_indirect_sequence(&tests, &mut exec[len..])
+        },
+        Instruction::LoadPair(dest, source) => self.encode_load_pair(dest, source, ip_increment, &mut exec[len..]),
+      };
+    }
+    len
+  }
+
   pub fn encode_op(&self, op: Op, ip_increment: usize, exec: &mut [u8]) -> usize {
     match op {
-      Op::NoOp => self.encode_noop(exec),
+      Op::NoOp => self.encode_noop(ip_increment, exec),
       Op::Load8(dest, src) => self.encode_load_8_register(dest, src, ip_increment, exec),
       Op::Load16(reg, value) => self.encode_load_16(reg, value, ip_increment, exec),
       Op::LoadToIndirect(location, value) => self.encode_load_to_indirect(location, value, ip_increment, exec),
@@ -190,12 +336,12 @@ impl Emitter {
       Op::InterruptEnable => self.encode_interrupt_enable(ip_increment, exec),
       Op::InterruptDisable => self.encode_interrupt_disable(ip_increment, exec),
 
-      Op::Invalid(code) => panic!("Invalid OP: {:#04x}", code),
+      Op::Invalid(_) => self.encode_invalid(exec),
     }
   }
 
-  pub fn encode_noop(&self, exec: &mut [u8]) -> usize {
-    let len = emit_ip_increment(1, exec);
+  pub fn encode_noop(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let len = emit_ip_increment(ip_increment, exec);
     len + emit_cycle_increment(1, &mut exec[len..])
   }
 
@@ -211,6 +357,12 @@ impl Emitter {
     len + emit_cycle_increment(1, &mut exec[len..])
   }
 
+  /// Invalid opcodes lock up the CPU, leaving the IP pointing at them
+  pub fn encode_invalid(&self, exec: &mut [u8]) -> usize {
+    let len = emit_return_code(cpu::STATUS_LOCKED, exec);
+    len + emit_cycle_increment(1, &mut exec[len..])
+  }
+
   pub fn encode_interrupt_enable(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_return_code(cpu::STATUS_INTERRUPT_ENABLE, exec);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
@@ -229,6 +381,20 @@ impl Emitter {
     len + emit_cycle_increment(3, &mut exec[len..])
   }
 
+  /// Two 8-bit loads into the halves of a register pair, done as one move
+  pub fn encode_load_pair(&self, dest: Register16, source: PairSource, ip_increment: usize, exec: &mut [u8]) -> usize {
+    let mut len = match source {
+      PairSource::Immediate(value) => emit_move_16(map_register_16(dest), value, exec),
+      PairSource::Register(src) => emit_reg_to_reg_move_16(map_register_16(dest), map_register_16(src), exec),
+    };
+    len += emit_ip_increment(ip_increment, &mut exec[len..]);
+    let cycles = match source {
+      PairSource::Immediate(_) => 4,
+      PairSource::Register(_) => 2,
+    };
+    len + emit_cycle_increment(cycles, &mut exec[len..])
+  }
+
   pub fn encode_load_8(&self, dest: Register8, value: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_move_8(map_register_8(dest), value, exec);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
@@ -243,14 +409,14 @@ impl Emitter {
 
   pub fn encode_increment_8(&self, dest: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_increment_8(map_register_8(dest), exec);
-    len += emit_store_flags(0xe0, false, &mut exec[len..]);
+    len += self.store_flags(0xe0, false, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(1, &mut exec[len..])
   }
 
   pub fn encode_decrement_8(&self, dest: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_decrement_8(map_register_8(dest), exec);
-    len += emit_store_flags(0xe0, true, &mut exec[len..]);
+    len += self.store_flags(0xe0, true, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(1, &mut exec[len..])
   }
@@ -268,26 +434,26 @@ impl Emitter {
   }
 
   pub fn encode_increment_hl_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_hl_indirect_partial_read(self.mem as usize, exec);
+    let mut len = emit_hl_indirect_partial_read(exec);
     len += emit_increment_8(X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0xe0, false, &mut exec[len..]);
-    len += emit_hl_indirect_partial_write(self.mem as usize, &mut exec[len..]);
+    len += self.store_flags(0xe0, false, &mut exec[len..]);
+    len += emit_hl_indirect_partial_write(&mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(3, &mut exec[len..])
   }
 
   pub fn encode_decrement_hl_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_hl_indirect_partial_read(self.mem as usize, exec);
+    let mut len = emit_hl_indirect_partial_read(exec);
     len += emit_decrement_8(X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0xe0, true, &mut exec[len..]);
-    len += emit_hl_indirect_partial_write(self.mem as usize, &mut exec[len..]);
+    len += self.store_flags(0xe0, true, &mut exec[len..]);
+    len += emit_hl_indirect_partial_write(&mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(3, &mut exec[len..])
   }
 
   pub fn encode_add_register_8(&self, dest: Register8, src: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_add_register_8(map_register_8(dest), map_register_8(src), exec);
-    len += emit_store_flags(0xf0, false, &mut exec[len..]);
+    len += self.store_flags(0xf0, false, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(1, &mut exec[len..])
   }
@@ -295,16 +461,16 @@ impl Emitter {
   pub fn encode_add_register_8_with_carry(&self, dest: Register8, src: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_restore_carry(exec);
     len += emit_add_register_8_with_carry(map_register_8(dest), map_register_8(src), &mut exec[len..]);
-    len += emit_store_flags(0xf0, false, &mut exec[len..]);
+    len += self.store_flags(0xf0, false, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(1, &mut exec[len..])
   }
 
   pub fn encode_add_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_push_register(X86Reg64::RDX, exec);
-    len += emit_hl_indirect_read(self.mem as usize, &mut exec[len..]);
+    len += emit_hl_indirect_read(&mut exec[len..]);
     len += emit_add_register_8(X86Reg8::AH, X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0xf0, false, &mut exec[len..]);
+    len += self.store_flags(0xf0, false, &mut exec[len..]);
     len += emit_pop_register(X86Reg64::RDX, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
@@ -312,10 +478,10 @@ impl Emitter {
 
   pub fn encode_add_indirect_with_carry(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_push_register(X86Reg64::RDX, exec);
-    len += emit_hl_indirect_read(self.mem as usize, &mut exec[len..]);
+    len += emit_hl_indirect_read(&mut exec[len..]);
     len += emit_restore_carry(&mut exec[len..]);
     len += emit_add_register_8_with_carry(X86Reg8::AH, X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0xf0, false, &mut exec[len..]);
+    len += self.store_flags(0xf0, false, &mut exec[len..]);
     len += emit_pop_register(X86Reg64::RDX, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
@@ -323,7 +489,7 @@ impl Emitter {
 
   pub fn encode_sub_register_8(&self, dest: Register8, src: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_sub_register_8(map_register_8(dest), map_register_8(src), exec);
-    len += emit_store_flags(0xf0, true, &mut exec[len..]);
+    len += self.store_flags(0xf0, true, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(1, &mut exec[len..])
   }
@@ -331,16 +497,16 @@ impl Emitter {
   pub fn encode_sub_register_8_with_carry(&self, dest: Register8, src: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_restore_carry(exec);
     len += emit_sub_register_8_with_carry(map_register_8(dest), map_register_8(src), &mut exec[len..]);
-    len += emit_store_flags(0xf0, true, &mut exec[len..]);
+    len += self.store_flags(0xf0, true, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(1, &mut exec[len..])
   }
 
   pub fn encode_sub_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_push_register(X86Reg64::RDX, exec);
-    len += emit_hl_indirect_read(self.mem as usize, &mut exec[len..]);
+    len += emit_hl_indirect_read(&mut exec[len..]);
     len += emit_sub_register_8(X86Reg8::AH, X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0xf0, true, &mut exec[len..]);
+    len += self.store_flags(0xf0, true, &mut exec[len..]);
     len += emit_pop_register(X86Reg64::RDX, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
@@ -348,10 +514,10 @@ impl Emitter {
 
   pub fn encode_sub_indirect_with_carry(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_push_register(X86Reg64::RDX, exec);
-    len += emit_hl_indirect_read(self.mem as usize, &mut exec[len..]);
+    len += emit_hl_indirect_read(&mut exec[len..]);
     len += emit_restore_carry(&mut exec[len..]);
     len += emit_sub_register_8_with_carry(X86Reg8::AH, X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0xf0, true, &mut exec[len..]);
+    len += self.store_flags(0xf0, true, &mut exec[len..]);
     len += emit_pop_register(X86Reg64::RDX, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
@@ -359,20 +525,20 @@ impl Emitter {
 
   pub fn encode_and_register_8(&self, dest: Register8, src: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_and_register_8(map_register_8(dest), map_register_8(src), exec);
-    len += emit_store_flags(0x80, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x50, &mut exec[len..]);
-    len += emit_force_flags_on(0x20, &mut exec[len..]);
+    len += self.store_flags(0x80, false, &mut exec[len..]);
+    len += self.force_flags_off(0x50, &mut exec[len..]);
+    len += self.force_flags_on(0x20, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(1, &mut exec[len..])
   }
 
   pub fn encode_and_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_push_register(X86Reg64::RDX, exec);
-    len += emit_hl_indirect_read(self.mem as usize, &mut exec[len..]);
+    len += emit_hl_indirect_read(&mut exec[len..]);
     len += emit_and_register_8(X86Reg8::AH, X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0x80, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x50, &mut exec[len..]);
-    len += emit_force_flags_on(0x20, &mut exec[len..]);
+    len += self.store_flags(0x80, false, &mut exec[len..]);
+    len += self.force_flags_off(0x50, &mut exec[len..]);
+    len += self.force_flags_on(0x20, &mut exec[len..]);
     len += emit_pop_register(X86Reg64::RDX, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
@@ -380,18 +546,18 @@ impl Emitter {
 
   pub fn encode_or_register_8(&self, dest: Register8, src: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_or_register_8(map_register_8(dest), map_register_8(src), exec);
-    len += emit_store_flags(0x80, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x70, &mut exec[len..]);
+    len += self.store_flags(0x80, false, &mut exec[len..]);
+    len += self.force_flags_off(0x70, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(1, &mut exec[len..])
   }
 
   pub fn encode_or_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_push_register(X86Reg64::RDX, exec);
-    len += emit_hl_indirect_read(self.mem as usize, &mut exec[len..]);
+    len += emit_hl_indirect_read(&mut exec[len..]);
     len += emit_or_register_8(X86Reg8::AH, X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0x80, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x70, &mut exec[len..]);
+    len += self.store_flags(0x80, false, &mut exec[len..]);
+    len += self.force_flags_off(0x70, &mut exec[len..]);
     len += emit_pop_register(X86Reg64::RDX, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
@@ -399,18 +565,18 @@ impl Emitter {
 
   pub fn encode_xor_register_8(&self, dest: Register8, src: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_xor_register_8(map_register_8(dest), map_register_8(src), exec);
-    len += emit_store_flags(0x80, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x70, &mut exec[len..]);
+    len += self.store_flags(0x80, false, &mut exec[len..]);
+    len += self.force_flags_off(0x70, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(1, &mut exec[len..])
   }
 
   pub fn encode_xor_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_push_register(X86Reg64::RDX, exec);
-    len += emit_hl_indirect_read(self.mem as usize, &mut exec[len..]);
+    len += emit_hl_indirect_read(&mut exec[len..]);
     len += emit_xor_register_8(X86Reg8::AH, X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0x80, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x70, &mut exec[len..]);
+    len += self.store_flags(0x80, false, &mut exec[len..]);
+    len += self.force_flags_off(0x70, &mut exec[len..]);
     len += emit_pop_register(X86Reg64::RDX, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
@@ -418,16 +584,16 @@ impl Emitter {
 
   pub fn encode_compare(&self, reg: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_compare(map_register_8(reg), exec);
-    len += emit_store_flags(0xf0, true, &mut exec[len..]);
+    len += self.store_flags(0xf0, true, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(1, &mut exec[len..])
   }
 
   pub fn encode_compare_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_push_register(X86Reg64::RDX, exec);
-    len += emit_hl_indirect_read(self.mem as usize, &mut exec[len..]);
+    len += emit_hl_indirect_read(&mut exec[len..]);
     len += emit_compare(X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0xf0, true, &mut exec[len..]);
+    len += self.store_flags(0xf0, true, &mut exec[len..]);
     len += emit_pop_register(X86Reg64::RDX, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
@@ -435,7 +601,7 @@ impl Emitter {
 
   pub fn encode_add_absolute_8(&self, value: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_add_absolute_8(value, exec);
-    len += emit_store_flags(0xf0, false, &mut exec[len..]);
+    len += self.store_flags(0xf0, false, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
@@ -443,14 +609,14 @@ impl Emitter {
   pub fn encode_adc_absolute_8(&self, value: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_restore_carry(exec);
     len += emit_adc_absolute_8(value, &mut exec[len..]);
-    len += emit_store_flags(0xf0, false, &mut exec[len..]);
+    len += self.store_flags(0xf0, false, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
 
   pub fn encode_sub_absolute_8(&self, value: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_sub_absolute_8(value, exec);
-    len += emit_store_flags(0xf0, true, &mut exec[len..]);
+    len += self.store_flags(0xf0, true, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
@@ -458,46 +624,46 @@ impl Emitter {
   pub fn encode_sbc_absolute_8(&self, value: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_restore_carry(exec);
     len += emit_sbc_absolute_8(value, &mut exec[len..]);
-    len += emit_store_flags(0xf0, true, &mut exec[len..]);
+    len += self.store_flags(0xf0, true, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
 
   pub fn encode_and_absolute_8(&self, value: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_and_absolute_8(value, exec);
-    len += emit_store_flags(0xc0, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x10, &mut exec[len..]);
-    len += emit_force_flags_on(0x20, &mut exec[len..]);
+    len += self.store_flags(0xc0, false, &mut exec[len..]);
+    len += self.force_flags_off(0x10, &mut exec[len..]);
+    len += self.force_flags_on(0x20, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
 
   pub fn encode_or_absolute_8(&self, value: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_or_absolute_8(value, exec);
-    len += emit_store_flags(0x80, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x70, &mut exec[len..]);
+    len += self.store_flags(0x80, false, &mut exec[len..]);
+    len += self.force_flags_off(0x70, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
 
   pub fn encode_xor_absolute_8(&self, value: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_xor_absolute_8(value, exec);
-    len += emit_store_flags(0x80, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x70, &mut exec[len..]);
+    len += self.store_flags(0x80, false, &mut exec[len..]);
+    len += self.force_flags_off(0x70, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
 
   pub fn encode_cmp_absolute_8(&self, value: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_cmp_absolute_8(value, exec);
-    len += emit_store_flags(0xf0, true, &mut exec[len..]);
+    len += self.store_flags(0xf0, true, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
 
   pub fn encode_add_hl(&self, src: Register16, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_add_hl(map_register_16(src), exec);
-    len += emit_store_flags(0x70, false, &mut exec[len..]);
+    len += self.store_flags(0x70, false, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
@@ -505,8 +671,8 @@ impl Emitter {
   pub fn encode_rotate_left_a(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_restore_carry(exec);
     len += emit_rotate_left_through_carry(X86Reg8::AH, &mut exec[len..]);
-    len += emit_store_flags(0x10, false, &mut exec[len..]);
-    len += emit_force_flags_off(0xe0, &mut exec[len..]);
+    len += self.store_flags(0x10, false, &mut exec[len..]);
+    len += self.force_flags_off(0xe0, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(1, &mut exec[len..])
   }
@@ -515,29 +681,29 @@ impl Emitter {
     let xreg = map_register_8(reg);
     let mut len = emit_restore_carry(exec);
     len += emit_rotate_left_through_carry(xreg, &mut exec[len..]);
-    len += emit_store_flags(0x10, false, &mut exec[len..]);
-    len += emit_force_flags_off(0xe0, &mut exec[len..]);
+    len += self.store_flags(0x10, false, &mut exec[len..]);
+    len += self.force_flags_off(0xe0, &mut exec[len..]);
     len += emit_zero_flag_test(xreg, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
 
   pub fn encode_rotate_left_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_hl_indirect_partial_read(self.mem as usize, exec);
+    let mut len = emit_hl_indirect_partial_read(exec);
     len += emit_restore_carry(&mut exec[len..]);
     len += emit_rotate_left_through_carry(X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0x10, false, &mut exec[len..]);
-    len += emit_force_flags_off(0xe0, &mut exec[len..]);
+    len += self.store_flags(0x10, false, &mut exec[len..]);
+    len += self.force_flags_off(0xe0, &mut exec[len..]);
     len += emit_zero_flag_test(X86Reg8::DL, &mut exec[len..]);
-    len += emit_hl_indirect_partial_write(self.mem as usize, &mut exec[len..]);
+    len += emit_hl_indirect_partial_write(&mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(4, &mut exec[len..])
   }
 
   pub fn encode_rotate_left_carry_a(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_rotate_left(X86Reg8::AH, exec);
-    len += emit_store_flags(0x10, false, &mut exec[len..]);
-    len += emit_force_flags_off(0xe0, &mut exec[len..]);
+    len += self.store_flags(0x10, false, &mut exec[len..]);
+    len += self.force_flags_off(0xe0, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(1, &mut exec[len..])
   }
@@ -545,20 +711,20 @@ impl Emitter {
   pub fn encode_rotate_left_carry(&self, reg: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let xreg = map_register_8(reg);
     let mut len = emit_rotate_left(xreg, exec);
-    len += emit_store_flags(0x10, false, &mut exec[len..]);
-    len += emit_force_flags_off(0xe0, &mut exec[len..]);
+    len += self.store_flags(0x10, false, &mut exec[len..]);
+    len += self.force_flags_off(0xe0, &mut exec[len..]);
     len += emit_zero_flag_test(xreg, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
 
   pub fn encode_rotate_left_carry_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_hl_indirect_partial_read(self.mem as usize, exec);
+    let mut len = emit_hl_indirect_partial_read(exec);
     len += emit_rotate_left(X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0x10, false, &mut exec[len..]);
-    len += emit_force_flags_off(0xe0, &mut exec[len..]);
+    len += self.store_flags(0x10, false, &mut exec[len..]);
+    len += self.force_flags_off(0xe0, &mut exec[len..]);
     len += emit_zero_flag_test(X86Reg8::DL, &mut exec[len..]);
-    len += emit_hl_indirect_partial_write(self.mem as usize, &mut exec[len..]);
+    len += emit_hl_indirect_partial_write(&mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(4, &mut exec[len..])
   }
@@ -566,8 +732,8 @@ impl Emitter {
   pub fn encode_rotate_right_a(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_restore_carry(exec);
     len += emit_rotate_right_through_carry(X86Reg8::AH, &mut exec[len..]);
-    len += emit_store_flags(0x10, false, &mut exec[len..]);
-    len += emit_force_flags_off(0xe0, &mut exec[len..]);
+    len += self.store_flags(0x10, false, &mut exec[len..]);
+    len += self.force_flags_off(0xe0, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(1, &mut exec[len..])
   }
@@ -576,29 +742,29 @@ impl Emitter {
     let xreg = map_register_8(reg);
     let mut len = emit_restore_carry(exec);
     len += emit_rotate_right_through_carry(xreg, &mut exec[len..]);
-    len += emit_store_flags(0x10, false, &mut exec[len..]);
-    len += emit_force_flags_off(0xe0, &mut exec[len..]);
+    len += self.store_flags(0x10, false, &mut exec[len..]);
+    len += self.force_flags_off(0xe0, &mut exec[len..]);
     len += emit_zero_flag_test(xreg, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
 
   pub fn encode_rotate_right_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_hl_indirect_partial_read(self.mem as usize, exec);
+    let mut len = emit_hl_indirect_partial_read(exec);
     len += emit_restore_carry(&mut exec[len..]);
     len += emit_rotate_right_through_carry(X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0x10, false, &mut exec[len..]);
-    len += emit_force_flags_off(0xe0, &mut exec[len..]);
+    len += self.store_flags(0x10, false, &mut exec[len..]);
+    len += self.force_flags_off(0xe0, &mut exec[len..]);
     len += emit_zero_flag_test(X86Reg8::DL, &mut exec[len..]);
-    len += emit_hl_indirect_partial_write(self.mem as usize, &mut exec[len..]);
+    len += emit_hl_indirect_partial_write(&mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(4, &mut exec[len..])
   }
 
   pub fn encode_rotate_right_carry_a(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_rotate_right(X86Reg8::AH, exec);
-    len += emit_store_flags(0x10, false, &mut exec[len..]);
-    len += emit_force_flags_off(0xe0, &mut exec[len..]);
+    len += self.store_flags(0x10, false, &mut exec[len..]);
+    len += self.force_flags_off(0xe0, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(1, &mut exec[len..])
   }
@@ -606,94 +772,94 @@ impl Emitter {
   pub fn encode_rotate_right_carry(&self, reg: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let xreg = map_register_8(reg);
     let mut len = emit_rotate_right(xreg, exec);
-    len += emit_store_flags(0x10, false, &mut exec[len..]);
-    len += emit_force_flags_off(0xe0, &mut exec[len..]);
+    len += self.store_flags(0x10, false, &mut exec[len..]);
+    len += self.force_flags_off(0xe0, &mut exec[len..]);
     len += emit_zero_flag_test(xreg, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
 
   pub fn encode_rotate_right_carry_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_hl_indirect_partial_read(self.mem as usize, exec);
+    let mut len = emit_hl_indirect_partial_read(exec);
     len += emit_rotate_right(X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0x10, false, &mut exec[len..]);
-    len += emit_force_flags_off(0xe0, &mut exec[len..]);
+    len += self.store_flags(0x10, false, &mut exec[len..]);
+    len += self.force_flags_off(0xe0, &mut exec[len..]);
     len += emit_zero_flag_test(X86Reg8::DL, &mut exec[len..]);
-    len += emit_hl_indirect_partial_write(self.mem as usize, &mut exec[len..]);
+    len += emit_hl_indirect_partial_write(&mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(4, &mut exec[len..])
   }
 
   pub fn encode_shift_left(&self, reg: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_shift_left(map_register_8(reg), exec);
-    len += emit_store_flags(0x90, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x60, &mut exec[len..]);
+    len += self.store_flags(0x90, false, &mut exec[len..]);
+    len += self.force_flags_off(0x60, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
 
   pub fn encode_shift_left_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_hl_indirect_partial_read(self.mem as usize, exec);
+    let mut len = emit_hl_indirect_partial_read(exec);
     len += emit_shift_left(X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0x90, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x60, &mut exec[len..]);
-    len += emit_hl_indirect_partial_write(self.mem as usize, &mut exec[len..]);
+    len += self.store_flags(0x90, false, &mut exec[len..]);
+    len += self.force_flags_off(0x60, &mut exec[len..]);
+    len += emit_hl_indirect_partial_write(&mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(4, &mut exec[len..])
   }
 
   pub fn encode_shift_right(&self, reg: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_shift_right(map_register_8(reg), exec);
-    len += emit_store_flags(0x90, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x60, &mut exec[len..]);
+    len += self.store_flags(0x90, false, &mut exec[len..]);
+    len += self.force_flags_off(0x60, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
 
   pub fn encode_shift_right_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_hl_indirect_partial_read(self.mem as usize, exec);
+    let mut len = emit_hl_indirect_partial_read(exec);
     len += emit_shift_right(X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0x90, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x60, &mut exec[len..]);
-    len += emit_hl_indirect_partial_write(self.mem as usize, &mut exec[len..]);
+    len += self.store_flags(0x90, false, &mut exec[len..]);
+    len += self.force_flags_off(0x60, &mut exec[len..]);
+    len += emit_hl_indirect_partial_write(&mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(4, &mut exec[len..])
   }
 
   pub fn encode_shift_right_logical(&self, reg: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_shift_right_logical(map_register_8(reg), exec);
-    len += emit_store_flags(0x90, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x60, &mut exec[len..]);
+    len += self.store_flags(0x90, false, &mut exec[len..]);
+    len += self.force_flags_off(0x60, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
 
   pub fn encode_shift_right_logical_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_hl_indirect_partial_read(self.mem as usize, exec);
+    let mut len = emit_hl_indirect_partial_read(exec);
     len += emit_shift_right_logical(X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0x90, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x60, &mut exec[len..]);
-    len += emit_hl_indirect_partial_write(self.mem as usize, &mut exec[len..]);
+    len += self.store_flags(0x90, false, &mut exec[len..]);
+    len += self.force_flags_off(0x60, &mut exec[len..]);
+    len += emit_hl_indirect_partial_write(&mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(4, &mut exec[len..])
   }
 
   pub fn encode_complement_a(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_complement_a(exec);
-    len += emit_force_flags_on(0x60, &mut exec[len..]);
+    len += self.force_flags_on(0x60, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(1, &mut exec[len..])
   }
 
   pub fn encode_set_carry(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_force_flags_off(0x60, exec);
-    len += emit_force_flags_on(0x10, &mut exec[len..]);
+    let mut len = self.force_flags_off(0x60, exec);
+    len += self.force_flags_on(0x10, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(1, &mut exec[len..])
   }
 
   pub fn encode_complement_carry(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_force_flags_off(0x60, exec);
+    let mut len = self.force_flags_off(0x60, exec);
     len += emit_complement_carry(&mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(1, &mut exec[len..])
@@ -706,11 +872,11 @@ impl Emitter {
   }
 
   pub fn encode_bit_set_indirect(&self, mask: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_hl_indirect_partial_read(self.mem as usize, exec);
+    let mut len = emit_hl_indirect_partial_read(exec);
     len += emit_register_or(X86Reg8::DL, mask, &mut exec[len..]);
-    len += emit_hl_indirect_partial_write(self.mem as usize, &mut exec[len..]);
+    len += emit_hl_indirect_partial_write(&mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
-    len + emit_cycle_increment(2, &mut exec[len..])
+    len + emit_cycle_increment(4, &mut exec[len..])
   }
 
   pub fn encode_bit_clear(&self, reg: Register8, mask: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
@@ -720,11 +886,11 @@ impl Emitter {
   }
 
   pub fn encode_bit_clear_indirect(&self, mask: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_hl_indirect_partial_read(self.mem as usize, exec);
+    let mut len = emit_hl_indirect_partial_read(exec);
     len += emit_register_and(X86Reg8::DL, !mask, &mut exec[len..]);
-    len += emit_hl_indirect_partial_write(self.mem as usize, &mut exec[len..]);
+    len += emit_hl_indirect_partial_write(&mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
-    len + emit_cycle_increment(2, &mut exec[len..])
+    len + emit_cycle_increment(4, &mut exec[len..])
   }
 
   pub fn encode_bit_test(&self, reg: Register8, mask: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
@@ -734,37 +900,47 @@ impl Emitter {
   }
 
   pub fn encode_bit_test_indirect(&self, mask: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_hl_indirect_partial_read(self.mem as usize, exec);
-    len += emit_bit_test(X86Reg8::DL, mask, &mut exec[len..]);
-    len += emit_hl_indirect_partial_write(self.mem as usize, &mut exec[len..]);
-    len += emit_ip_increment(ip_increment, &mut exec[len..]);
-    len + emit_cycle_increment(2, &mut exec[len..])
+    self.encode_bit_test_indirect_sequence(&[(mask, ip_increment)], exec)
+  }
+
+  /// Polling loops often test several bits of the same (HL) byte back to back.
+  /// Each entry in `tests` is a (mask, ip_increment) pair for a consecutive
+  /// BIT n,(HL) op. Since nothing between them can write memory or change HL,
+  /// the byte is read once and every test is run against the cached value.
+  pub fn encode_bit_test_indirect_sequence(&self, tests: &[(u8, usize)], exec: &mut [u8]) -> usize {
+    let mut len = emit_hl_indirect_partial_read(exec);
+    for (mask, ip_increment) in tests {
+      len += emit_bit_test(X86Reg8::DL, *mask, &mut exec[len..]);
+      len += emit_ip_increment(*ip_increment, &mut exec[len..]);
+      len += emit_cycle_increment(3, &mut exec[len..]);
+    }
+    len + emit_hl_indirect_partial_release(&mut exec[len..])
   }
 
   pub fn encode_swap(&self, reg: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let x86_reg = map_register_8(reg);
     let mut len = emit_swap(x86_reg, exec);
     len += emit_or_register_8(x86_reg, x86_reg, &mut exec[len..]);
-    len += emit_store_flags(0x80, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x70, &mut exec[len..]);
+    len += self.store_flags(0x80, false, &mut exec[len..]);
+    len += self.force_flags_off(0x70, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
 
   pub fn encode_swap_indirect(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_hl_indirect_partial_read(self.mem as usize, exec);
+    let mut len = emit_hl_indirect_partial_read(exec);
     len += emit_swap(X86Reg8::DL, &mut exec[len..]);
     len += emit_or_register_8(X86Reg8::DL, X86Reg8::DL, &mut exec[len..]);
-    len += emit_store_flags(0x80, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x70, &mut exec[len..]);
-    len += emit_hl_indirect_partial_write(self.mem as usize, &mut exec[len..]);
+    len += self.store_flags(0x80, false, &mut exec[len..]);
+    len += self.force_flags_off(0x70, &mut exec[len..]);
+    len += emit_hl_indirect_partial_write(&mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(4, &mut exec[len..])
   }
 
   pub fn encode_load_to_indirect(&self, location: IndirectLocation, value: Register8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let indirect_address = map_indirect_location_to_register(location);
-    let mut len= emit_memory_write(exec, self.mem as usize, indirect_address, map_register_8(value));
+    let mut len= emit_memory_write(exec, indirect_address, map_register_8(value));
     len += match location {
       IndirectLocation::HLIncrement => emit_increment_16(X86Reg16::CX, &mut exec[len..]),
       IndirectLocation::HLDecrement => emit_decrement_16(X86Reg16::CX, &mut exec[len..]),
@@ -776,7 +952,7 @@ impl Emitter {
 
   pub fn endcode_load_immediate_to_hl_indirect(&self, value: u8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let indirect_address = map_indirect_location_to_register(IndirectLocation::HL);
-    let mut len = emit_memory_write_literal(exec, self.mem as usize, indirect_address, value);
+    let mut len = emit_memory_write_literal(exec, indirect_address, value);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(3, &mut exec[len..])
   }
@@ -784,7 +960,7 @@ impl Emitter {
   pub fn encode_load_from_indirect(&self, reg: Register8, location: IndirectLocation, ip_increment: usize, exec: &mut [u8]) -> usize {
     let indirect_address = map_indirect_location_to_register(location);
     let dest_register = map_register_8(reg);
-    let mut len = emit_memory_read(exec, self.mem as usize, indirect_address, dest_register);
+    let mut len = emit_memory_read(exec, indirect_address, dest_register);
     len += match location {
       IndirectLocation::HLIncrement => emit_increment_16(X86Reg16::CX, &mut exec[len..]),
       IndirectLocation::HLDecrement => emit_decrement_16(X86Reg16::CX, &mut exec[len..]),
@@ -795,53 +971,53 @@ impl Emitter {
   }
 
   pub fn encode_load_stack_to_memory(&self, addr: u16, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_write_stack_to_memory(exec, self.mem as usize, addr);
+    let mut len = emit_write_stack_to_memory(exec, addr);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(5, &mut exec[len..])
   }
 
   pub fn encode_load_a_to_memory(&self, addr: u16, extra_cycle: bool, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_write_a_to_memory(exec, self.mem as usize, addr);
+    let mut len = emit_write_a_to_memory(exec, addr);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(if extra_cycle { 4 } else { 3 }, &mut exec[len..])
   }
 
   pub fn encode_load_a_from_memory(&self, addr: u16, extra_cycle: bool, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_read_a_from_memory(exec, self.mem as usize, addr);
+    let mut len = emit_read_a_from_memory(exec, addr);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(if extra_cycle { 4 } else { 3 }, &mut exec[len..])
   }
 
   pub fn encode_load_to_high_mem(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_load_to_high_mem(exec, self.mem as usize);
+    let mut len = emit_load_to_high_mem(exec);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
 
   pub fn encode_load_from_high_mem(&self, ip_increment: usize, exec: &mut [u8]) -> usize {
-    let mut len = emit_load_from_high_mem(exec, self.mem as usize);
+    let mut len = emit_load_from_high_mem(exec);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(2, &mut exec[len..])
   }
 
   pub fn encode_push(&self, reg: Register16, ip_increment: usize, exec: &mut [u8]) -> usize {
     let source = map_register_16(reg);
-    let mut len = emit_push(source, self.mem as usize, exec);
+    let mut len = emit_push(source, exec);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(4, &mut exec[len..])
   }
 
   pub fn encode_pop(&self, reg: Register16, ip_increment: usize, exec: &mut [u8]) -> usize {
     let dest = map_register_16(reg);
-    let mut len = emit_pop(dest, self.mem as usize, exec);
+    let mut len = emit_pop(dest, exec);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
-    len + emit_cycle_increment(4, &mut exec[len..])
+    len + emit_cycle_increment(3, &mut exec[len..])
   }
 
   pub fn encode_add_sp(&self, offset: i8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_sp_signed_offset(offset, exec);
-    len += emit_store_flags(0x30, false, &mut exec[len..]);
-    len += emit_force_flags_off(0xc0, &mut exec[len..]);
+    len += self.store_flags(0x30, false, &mut exec[len..]);
+    len += self.force_flags_off(0xc0, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(4, &mut exec[len..])
   }
@@ -854,8 +1030,8 @@ impl Emitter {
 
   pub fn encode_load_stack_offset(&self, offset: i8, ip_increment: usize, exec: &mut [u8]) -> usize {
     let mut len = emit_load_stack_offset(offset, exec);
-    len += emit_store_flags(0x70, false, &mut exec[len..]);
-    len += emit_force_flags_off(0x80, &mut exec[len..]);
+    len += self.store_flags(0x70, false, &mut exec[len..]);
+    len += self.force_flags_off(0x80, &mut exec[len..]);
     len += emit_ip_increment(ip_increment, &mut exec[len..]);
     len + emit_cycle_increment(3, &mut exec[len..])
   }
@@ -916,7 +1092,7 @@ impl Emitter {
         len += emit_cycle_increment(3, &mut exec[len..]);
         len += emit_flag_test(0x10, &mut exec[len..]);
         len += emit_jump_nonzero(4 + 5, &mut exec[len..]);
-        len += emit_cycle_increment(3, &mut exec[len..]);
+        len += emit_cycle_increment(1, &mut exec[len..]);
         len += emit_move_16(X86Reg16::R13, address, &mut exec[len..]);
       },
     }
@@ -977,7 +1153,7 @@ impl Emitter {
     match condition {
       JumpCondition::Always => {
         len = emit_ip_increment(3, exec);
-        len += emit_push(X86Reg16::R13, self.mem as usize, &mut exec[len..]);
+        len += emit_push(X86Reg16::R13, &mut exec[len..]);
         len += emit_jump(address, &mut exec[len..]);
         len += emit_cycle_increment(6, &mut exec[len..]);
       },
@@ -989,7 +1165,7 @@ impl Emitter {
         // the byte when the full block is written.
         len += emit_jump_zero(0, &mut exec[len..]);
         let offset_location = len;
-        len += emit_push(X86Reg16::R13, self.mem as usize, &mut exec[len..]);
+        len += emit_push(X86Reg16::R13, &mut exec[len..]);
         len += emit_cycle_increment(3, &mut exec[len..]);
         len += emit_move_16(X86Reg16::R13, address, &mut exec[len..]);
         let delta = len - offset_location;
@@ -1001,7 +1177,7 @@ impl Emitter {
         len += emit_flag_test(0x80, &mut exec[len..]);
         len += emit_jump_nonzero(0, &mut exec[len..]);
         let offset_location = len;
-        len += emit_push(X86Reg16::R13, self.mem as usize, &mut exec[len..]);
+        len += emit_push(X86Reg16::R13, &mut exec[len..]);
         len += emit_cycle_increment(3, &mut exec[len..]);
         len += emit_move_16(X86Reg16::R13, address, &mut exec[len..]);
         let delta = len - offset_location;
@@ -1013,7 +1189,7 @@ impl Emitter {
         len += emit_flag_test(0x10, &mut exec[len..]);
         len += emit_jump_zero(0, &mut exec[len..]);
         let offset_location = len;
-        len += emit_push(X86Reg16::R13, self.mem as usize, &mut exec[len..]);
+        len += emit_push(X86Reg16::R13, &mut exec[len..]);
         len += emit_cycle_increment(3, &mut exec[len..]);
         len += emit_move_16(X86Reg16::R13, address, &mut exec[len..]);
         let delta = len - offset_location;
@@ -1025,7 +1201,7 @@ impl Emitter {
         len += emit_flag_test(0x10, &mut exec[len..]);
         len += emit_jump_nonzero(0, &mut exec[len..]);
         let offset_location = len;
-        len += emit_push(X86Reg16::R13, self.mem as usize, &mut exec[len..]);
+        len += emit_push(X86Reg16::R13, &mut exec[len..]);
         len += emit_cycle_increment(3, &mut exec[len..]);
         len += emit_move_16(X86Reg16::R13, address, &mut exec[len..]);
         let delta = len - offset_location;
@@ -1037,7 +1213,7 @@ impl Emitter {
 
   pub fn encode_reset(&self, vector: u16, exec: &mut [u8]) -> usize {
     let mut len = emit_ip_increment(1, exec);
-    len += emit_push(X86Reg16::R13, self.mem as usize, &mut exec[len..]);
+    len += emit_push(X86Reg16::R13, &mut exec[len..]);
     len += emit_cycle_increment(4, &mut exec[len..]);
     len + emit_jump(vector, &mut exec[len..])
   }
@@ -1046,7 +1222,7 @@ impl Emitter {
     let mut len;
     match condition {
       JumpCondition::Always => {
-        len = emit_pop(X86Reg16::R13, self.mem as usize, exec);
+        len = emit_pop(X86Reg16::R13, exec);
         len += emit_cycle_increment(4, &mut exec[len..]);
       },
       JumpCondition::Zero => {
@@ -1055,7 +1231,7 @@ impl Emitter {
         len += emit_flag_test(0x80, &mut exec[len..]);
         len += emit_jump_zero(0, &mut exec[len..]);
         let offset_location = len;
-        len += emit_pop(X86Reg16::R13, self.mem as usize, &mut exec[len..]);
+        len += emit_pop(X86Reg16::R13, &mut exec[len..]);
         len += emit_cycle_increment(3, &mut exec[len..]);
         let delta = len - offset_location;
         exec[offset_location - 1] = delta as u8;
@@ -1066,7 +1242,7 @@ impl Emitter {
         len += emit_flag_test(0x80, &mut exec[len..]);
         len += emit_jump_nonzero(0, &mut exec[len..]);
         let offset_location = len;
-        len += emit_pop(X86Reg16::R13, self.mem as usize, &mut exec[len..]);
+        len += emit_pop(X86Reg16::R13, &mut exec[len..]);
         len += emit_cycle_increment(3, &mut exec[len..]);
         let delta = len - offset_location;
         exec[offset_location - 1] = delta as u8;
@@ -1077,7 +1253,7 @@ impl Emitter {
         len += emit_flag_test(0x10, &mut exec[len..]);
         len += emit_jump_zero(0, &mut exec[len..]);
         let offset_location = len;
-        len += emit_pop(X86Reg16::R13, self.mem as usize, &mut exec[len..]);
+        len += emit_pop(X86Reg16::R13, &mut exec[len..]);
         len += emit_cycle_increment(3, &mut exec[len..]);
         let delta = len - offset_location;
         exec[offset_location - 1] = delta as u8;
@@ -1088,7 +1264,7 @@ impl Emitter {
         len += emit_flag_test(0x10, &mut exec[len..]);
         len += emit_jump_nonzero(0, &mut exec[len..]);
         let offset_location = len;
-        len += emit_pop(X86Reg16::R13, self.mem as usize, &mut exec[len..]);
+        len += emit_pop(X86Reg16::R13, &mut exec[len..]);
         len += emit_cycle_increment(3, &mut exec[len..]);
         let delta = len - offset_location;
         exec[offset_location - 1] = delta as u8;
@@ -1098,12 +1274,27 @@ impl Emitter {
   }
 
   pub fn encode_return_from_interrupt(&self, exec: &mut [u8]) -> usize {
-    let mut len = emit_pop(X86Reg16::R13, self.mem as usize, exec);
+    let mut len = emit_pop(X86Reg16::R13, exec);
     len += emit_cycle_increment(4, &mut exec[len..]);
-    len + emit_return_code(cpu::STATUS_INTERRUPT_ENABLE, &mut exec[len..])
+    // unlike EI, RETI enables interrupts without a delay
+    len + emit_return_code(cpu::STATUS_INTERRUPT_ENABLE_IMMEDIATE, &mut exec[len..])
   }
 }
 
+/// Set the rel32 displacement of a link jump. With no target, the
+/// displacement is zero and the jump falls through to the block's exit.
+pub fn write_link_displacement(exec: &mut [u8], patch_offset: usize, target_offset: Option<usize>) {
+  let displacement = match target_offset {
+    Some(offset) => offset as isize - (patch_offset + 4) as isize,
+    None => 0,
+  };
+  exec[patch_offset..(patch_offset + 4)].copy_from_slice(&(displacement as i32).to_le_bytes());
+}
+
+/// x86 keeps the instruction cache coherent with writes, so newly written code
+/// needs no maintenance before it runs
+pub fn flush_instruction_cache(_code: &[u8]) {}
+
 fn emit_immediate_u16(value: u16, exec: &mut [u8]) {
   exec[0] = (value & 0xff) as u8;
   exec[1] = (value >> 8) as u8;
@@ -1163,6 +1354,20 @@ fn emit_reg_to_reg_move(to: X86Reg8, from: X86Reg8, exec: &mut [u8]) -> usize {
   2
 }
 
+fn emit_reg_to_reg_move_16(to: X86Reg16, from: X86Reg16, exec: &mut [u8]) -> usize {
+  let register_index = |reg: X86Reg16| match reg {
+    X86Reg16::AX => 0,
+    X86Reg16::CX => 1,
+    X86Reg16::DX => 2,
+    X86Reg16::BX => 3,
+    _ => panic!("Cannot move between 16-bit registers"),
+  };
+  exec[0] = 0x66; // mov to, from
+  exec[1] = 0x89;
+  exec[2] = 0xc0 | (register_index(from) << 3) | register_index(to);
+  3
+}
+
 fn emit_add_register_8(dest: X86Reg8, src: X86Reg8, exec: &mut [u8]) -> usize {
   exec[0] = 0x00;
   exec[1] = register_to_register(src, dest);
@@ -1637,11 +1842,22 @@ fn emit_jump_zero(relative: i8, exec: &mut [u8]) -> usize {
 }
 
 fn emit_ip_increment(amount: usize, exec: &mut [u8]) -> usize {
+  if amount == 0 {
+    return 0;
+  }
+  if amount < 0x80 {
+    exec[0] = 0x49; // add r13, amount
+    exec[1] = 0x83;
+    exec[2] = 0xc5;
+    exec[3] = amount as u8;
+    return 4;
+  }
+  // collapsed increments may not fit in a sign-extended byte
   exec[0] = 0x49; // add r13, amount
-  exec[1] = 0x83;
+  exec[1] = 0x81;
   exec[2] = 0xc5;
-  exec[3] = amount as u8;
-  4
+  exec[3..7].copy_from_slice(&(amount as u32).to_le_bytes());
+  7
 }
 
 fn emit_ip_signed_offset(offset: i8, exec: &mut [u8]) -> usize {
@@ -1783,12 +1999,12 @@ fn emit_load_stack_offset(offset: i8, exec: &mut [u8]) -> usize {
   length
 }
 
-fn emit_memory_read(exec: &mut [u8], memory_base: usize, indirect_address: X86Reg16, dest_register: X86Reg8) -> usize {
+fn emit_memory_read(exec: &mut [u8], indirect_address: X86Reg16, dest_register: X86Reg8) -> usize {
   let fn_pointer = address_as_bytes(crate::mem::memory_read_byte as u64);
   let address_source = match indirect_address {
-    X86Reg16::BX => 0xde,
-    X86Reg16::CX => 0xce,
-    X86Reg16::DX => 0xd6,
+    X86Reg16::BX => 0xf3,
+    X86Reg16::CX => 0xf1,
+    X86Reg16::DX => 0xf2,
     _ => panic!("cannot read from address at register"),
   };
   let stack_offset = match dest_register {
@@ -1801,22 +2017,12 @@ fn emit_memory_read(exec: &mut [u8], memory_base: usize, indirect_address: X86Re
     X86Reg8::AL => 24,
     X86Reg8::AH => 25,
   };
-  let memory_pointer = address_as_bytes(memory_base as u64);
-  let code = [
+  let slow_path = [
     0x50, // push rax
     0x51, // push rcx
     0x52, // push rdx
     0x53, // push rbx
-    0x48, 0x89, address_source, // mov rsi, indirect_address
-    0x48, 0xbf, // movabs rdi, memory_pointer
-      memory_pointer[0],
-      memory_pointer[1],
-      memory_pointer[2],
-      memory_pointer[3],
-      memory_pointer[4],
-      memory_pointer[5],
-      memory_pointer[6],
-      memory_pointer[7],
+    0x48, 0x89, 0xef, // mov rdi, rbp
 
     0x48, 0xb8, // movabs rax, fn_pointer
       fn_pointer[0],
@@ -1837,34 +2043,24 @@ fn emit_memory_read(exec: &mut [u8], memory_base: usize, indirect_address: X86Re
     0x59, // pop rcx
     0x58, // pop rax
   ];
-  let length = code.len();
-  exec[..length].copy_from_slice(&code);
-  length
+  exec[..3].copy_from_slice(&[0x0f, 0xb7, address_source]); // movzx esi, indirect_address
+  let access = [0x8a, page_operand(dest_register), 0x37]; // mov dest_register, [rdi + rsi]
+  3 + emit_paged_access(READ_PAGES, &access, &slow_path, &mut exec[3..])
 }
 
-fn emit_memory_write(exec: &mut [u8], memory_base: usize, indirect_address: X86Reg16, source: X86Reg8) -> usize {
+fn emit_memory_write(exec: &mut [u8], indirect_address: X86Reg16, source: X86Reg8) -> usize {
   let fn_pointer = address_as_bytes(crate::mem::memory_write_byte as u64);
   let address_dest = match indirect_address {
-    X86Reg16::BX => 0xde,
-    X86Reg16::CX => 0xce,
-    X86Reg16::DX => 0xd6,
+    X86Reg16::BX => 0xf3,
+    X86Reg16::CX => 0xf1,
+    X86Reg16::DX => 0xf2,
     _ => panic!("cannot read from address at register"),
   };
-  let memory_pointer = address_as_bytes(memory_base as u64);
-  let code = [
+  let slow_path = [
     0x50, // push rax
     0x51, // push rcx
     0x52, // push rdx
-    0x48, 0x89, address_dest, // mov rsi, indirect_address
-    0x48, 0xbf, // movabs rdi, memory_pointer
-      memory_pointer[0],
-      memory_pointer[1],
-      memory_pointer[2],
-      memory_pointer[3],
-      memory_pointer[4],
-      memory_pointer[5],
-      memory_pointer[6],
-      memory_pointer[7],
+    0x48, 0x89, 0xef, // mov rdi, rbp
     0x88, register_to_register(source, X86Reg8::DL), // mov dl, source
     0x48, 0x81, 0xe2, 0xff, 0x00, 0x00, 0x00, // and rdx, 0xff
 
@@ -1882,34 +2078,24 @@ fn emit_memory_write(exec: &mut [u8], memory_base: usize, indirect_address: X86R
     0x59, // pop rcx
     0x58, // pop rax
   ];
-  let length = code.len();
-  exec[..length].copy_from_slice(&code);
-  length
+  exec[..3].copy_from_slice(&[0x0f, 0xb7, address_dest]); // movzx esi, indirect_address
+  let access = [0x88, page_operand(source), 0x37]; // mov [rdi + rsi], source
+  3 + emit_paged_access(WRITE_PAGES, &access, &slow_path, &mut exec[3..])
 }
 
-fn emit_memory_write_literal(exec: &mut [u8], memory_base: usize, indirect_address: X86Reg16, value: u8) -> usize {
+fn emit_memory_write_literal(exec: &mut [u8], indirect_address: X86Reg16, value: u8) -> usize {
   let fn_pointer = address_as_bytes(crate::mem::memory_write_byte as u64);
   let address_dest = match indirect_address {
-    X86Reg16::BX => 0xde,
-    X86Reg16::CX => 0xce,
-    X86Reg16::DX => 0xd6,
+    X86Reg16::BX => 0xf3,
+    X86Reg16::CX => 0xf1,
+    X86Reg16::DX => 0xf2,
     _ => panic!("cannot read from address at register"),
   };
-  let memory_pointer = address_as_bytes(memory_base as u64);
-  let code = [
+  let slow_path = [
     0x50, // push rax
     0x51, // push rcx
     0x52, // push rdx
-    0x48, 0x89, address_dest, // mov rsi, indirect_address
-    0x48, 0xbf, // movabs rdi, memory_pointer
-      memory_pointer[0],
-      memory_pointer[1],
-      memory_pointer[2],
-      memory_pointer[3],
-      memory_pointer[4],
-      memory_pointer[5],
-      memory_pointer[6],
-      memory_pointer[7],
+    0x48, 0x89, 0xef, // mov rdi, rbp
     0xb2, value, // mov dl, value
     0x48, 0x81, 0xe2, 0xff, 0x00, 0x00, 0x00, // and rdx, 0xff
 
@@ -1927,28 +2113,19 @@ fn emit_memory_write_literal(exec: &mut [u8], memory_base: usize, indirect_addre
     0x59, // pop rcx
     0x58, // pop rax
   ];
-  let length = code.len();
-  exec[..length].copy_from_slice(&code);
-  length
+  exec[..3].copy_from_slice(&[0x0f, 0xb7, address_dest]); // movzx esi, indirect_address
+  let access = [0xc6, 0x04, 0x37, value]; // mov byte [rdi + rsi], value
+  3 + emit_paged_access(WRITE_PAGES, &access, &slow_path, &mut exec[3..])
 }
 
-fn emit_write_stack_to_memory(exec: &mut [u8], memory_base: usize, address: u16) -> usize {
+fn emit_write_stack_to_memory(exec: &mut [u8], address: u16) -> usize {
   let fn_pointer = address_as_bytes(crate::mem::memory_write_word as u64);
-  let memory_pointer = address_as_bytes(memory_base as u64);
   let code = [
     0x50, // push rax
     0x51, // push rcx
     0x52, // push rdx
-    0x66, 0xbe, (address & 0xff) as u8, (address >> 8) as u8, // mov si, address
-    0x48, 0xbf, // movabs rdi, memory_pointer
-      memory_pointer[0],
-      memory_pointer[1],
-      memory_pointer[2],
-      memory_pointer[3],
-      memory_pointer[4],
-      memory_pointer[5],
-      memory_pointer[6],
-      memory_pointer[7],
+    0xbe, (address & 0xff) as u8, (address >> 8) as u8, 0x00, 0x00, // mov esi, address
+    0x48, 0x89, 0xef, // mov rdi, rbp
     0x4c, 0x89, 0xe2, // mov rdx, r12
 
     0x48, 0xb8, // movabs rax, fn_pointer
@@ -1970,24 +2147,15 @@ fn emit_write_stack_to_memory(exec: &mut [u8], memory_base: usize, address: u16)
   length
 }
 
-fn emit_write_a_to_memory(exec: &mut [u8], memory_base: usize, address: u16) -> usize {
+fn emit_write_a_to_memory(exec: &mut [u8], address: u16) -> usize {
   let fn_pointer = address_as_bytes(crate::mem::memory_write_byte as u64);
-  let memory_pointer = address_as_bytes(memory_base as u64);
-  let code = [
+  let slow_path = [
     0x50, // push rax
     0x51, // push rcx
     0x52, // push rdx
-    0x66, 0xbe, (address & 0xff) as u8, (address >> 8) as u8, // mov si, address
-    0x48, 0xbf, // movabs rdi, memory_pointer
-      memory_pointer[0],
-      memory_pointer[1],
-      memory_pointer[2],
-      memory_pointer[3],
-      memory_pointer[4],
-      memory_pointer[5],
-      memory_pointer[6],
-      memory_pointer[7],
-    0x88, 0xe2, // mov dl, ah
+    0xbe, (address & 0xff) as u8, (address >> 8) as u8, 0x00, 0x00, // mov esi, address
+    0x48, 0x89, 0xef, // mov rdi, rbp
+    0x0f, 0xb6, 0xd4, // movzx edx, ah
 
     0x48, 0xb8, // movabs rax, fn_pointer
       fn_pointer[0],
@@ -2003,28 +2171,17 @@ fn emit_write_a_to_memory(exec: &mut [u8], memory_base: usize, address: u16) ->
     0x59, // pop rcx
     0x58, // pop rax
   ];
-  let length = code.len();
-  exec[..length].copy_from_slice(&code);
-  length
+  emit_direct_a_access(address, true, &slow_path, exec)
 }
 
-fn emit_read_a_from_memory(exec: &mut [u8], memory_base: usize, address: u16) -> usize {
+fn emit_read_a_from_memory(exec: &mut [u8], address: u16) -> usize {
   let fn_pointer = address_as_bytes(crate::mem::memory_read_byte as u64);
-  let memory_pointer = address_as_bytes(memory_base as u64);
-  let code = [
+  let slow_path = [
     0x50, // push rax
     0x51, // push rcx
     0x52, // push rdx
-    0x66, 0xbe, (address & 0xff) as u8, (address >> 8) as u8, // mov si, address
-    0x48, 0xbf, // movabs rdi, memory_pointer
-      memory_pointer[0],
-      memory_pointer[1],
-      memory_pointer[2],
-      memory_pointer[3],
-      memory_pointer[4],
-      memory_pointer[5],
-      memory_pointer[6],
-      memory_pointer[7],
+    0xbe, (address & 0xff) as u8, (address >> 8) as u8, 0x00, 0x00, // mov esi, address
+    0x48, 0x89, 0xef, // mov rdi, rbp
 
     0x48, 0xb8, // movabs rax, fn_pointer
       fn_pointer[0],
@@ -2041,30 +2198,19 @@ fn emit_read_a_from_memory(exec: &mut [u8], memory_base: usize, address: u16) ->
     0x59, // pop rcx
     0x58, // pop rax
   ];
-  let length = code.len();
-  exec[..length].copy_from_slice(&code);
-  length
+  emit_direct_a_access(address, false, &slow_path, exec)
 }
 
-fn emit_load_to_high_mem(exec: &mut [u8], memory_base: usize) -> usize {
+fn emit_load_to_high_mem(exec: &mut [u8]) -> usize {
   let fn_pointer = address_as_bytes(crate::mem::memory_write_byte as u64);
-  let memory_pointer = address_as_bytes(memory_base as u64);
   let code = [
     0x50, // push rax
     0x51, // push rcx
     0x52, // push rdx
-    0x66, 0x89, 0xde, // mov si, bx
-    0x66, 0x81, 0xce, 0x00, 0xff, // or si, 0xff00
-    0x48, 0xbf, // movabs rdi, memory_pointer
-      memory_pointer[0],
-      memory_pointer[1],
-      memory_pointer[2],
-      memory_pointer[3],
-      memory_pointer[4],
-      memory_pointer[5],
-      memory_pointer[6],
-      memory_pointer[7],
-    0x88, 0xe2, // mov dl, ah
+    0x0f, 0xb6, 0xf3, // movzx esi, bl
+    0x81, 0xce, 0x00, 0xff, 0x00, 0x00, // or esi, 0xff00
+    0x48, 0x89, 0xef, // mov rdi, rbp
+    0x0f, 0xb6, 0xd4, // movzx edx, ah
 
     0x48, 0xb8, // movabs rax, fn_pointer
       fn_pointer[0],
@@ -2085,24 +2231,15 @@ fn emit_load_to_high_mem(exec: &mut [u8], memory_base: usize) -> usize {
   length
 }
 
-fn emit_load_from_high_mem(exec: &mut [u8], memory_base: usize) -> usize {
+fn emit_load_from_high_mem(exec: &mut [u8]) -> usize {
   let fn_pointer = address_as_bytes(crate::mem::memory_read_byte as u64);
-  let memory_pointer = address_as_bytes(memory_base as u64);
   let code = [
     0x50, // push rax
     0x51, // push rcx
     0x52, // push rdx
-    0x66, 0x89, 0xde, // mov si, bx
-    0x66, 0x81, 0xce, 0x00, 0xff, // or si, 0xff00
-    0x48, 0xbf, // movabs rdi, memory_pointer
-      memory_pointer[0],
-      memory_pointer[1],
-      memory_pointer[2],
-      memory_pointer[3],
-      memory_pointer[4],
-      memory_pointer[5],
-      memory_pointer[6],
-      memory_pointer[7],
+    0x0f, 0xb6, 0xf3, // movzx esi, bl
+    0x81, 0xce, 0x00, 0xff, 0x00, 0x00, // or esi, 0xff00
+    0x48, 0x89, 0xef, // mov rdi, rbp
 
     0x48, 0xb8, // movabs rax, fn_pointer
       fn_pointer[0],
@@ -2124,9 +2261,8 @@ fn emit_load_from_high_mem(exec: &mut [u8], memory_base: usize) -> usize {
   length
 }
 
-fn emit_push(source: X86Reg16, memory_base: usize, exec: &mut [u8]) -> usize {
-  let fn_pointer = address_as_bytes(crate::mem::memory_write_word as u64);
-  let memory_pointer = address_as_bytes(memory_base as u64);
+fn emit_push(source: X86Reg16, exec: &mut [u8]) -> usize {
+  let fn_pointer = address_as_bytes(crate::mem::memory_push_word as u64);
   let load_source_bytes = match source {
     X86Reg16::AX => (0x89, 0xc2, 0x90),
     X86Reg16::BX => (0x89, 0xda, 0x90),
@@ -2142,15 +2278,7 @@ fn emit_push(source: X86Reg16, memory_base: usize, exec: &mut [u8]) -> usize {
     0x49, 0x83, 0xec, 0x02, // sub r12, 2
     0x49, 0x81, 0xe4, 0xff, 0xff, 0x00, 0x00, // and r12, 0xffff
     0x4c, 0x89, 0xe6, // mov rsi, r12
-    0x48, 0xbf, // movabs rdi, memory_pointer
-      memory_pointer[0],
-      memory_pointer[1],
-      memory_pointer[2],
-      memory_pointer[3],
-      memory_pointer[4],
-      memory_pointer[5],
-      memory_pointer[6],
-      memory_pointer[7],
+    0x48, 0x89, 0xef, // mov rdi, rbp
     0x66, load_source_bytes.0, load_source_bytes.1, load_source_bytes.2, // mov dx, source
     0x48, 0x81, 0xe2, 0xff, 0xff, 0x00, 0x00, // and rdx, 0xffff
 
@@ -2173,9 +2301,8 @@ fn emit_push(source: X86Reg16, memory_base: usize, exec: &mut [u8]) -> usize {
   length
 }
 
-fn emit_pop(dest: X86Reg16, memory_base: usize, exec: &mut [u8]) -> usize {
+fn emit_pop(dest: X86Reg16, exec: &mut [u8]) -> usize {
   let fn_pointer = address_as_bytes(crate::mem::memory_read_word as u64);
-  let memory_pointer = address_as_bytes(memory_base as u64);
   let stack_offset = match dest {
     X86Reg16::AX => 32,
     X86Reg16::BX => 8,
@@ -2193,15 +2320,7 @@ fn emit_pop(dest: X86Reg16, memory_base: usize, exec: &mut [u8]) -> usize {
     0x4c, 0x89, 0xe6, // mov rsi, r12
     0x49, 0x83, 0xc4, 0x02, // add r12, 2
     0x49, 0x81, 0xe4, 0xff, 0xff, 0x00, 0x00, // and r12, 0xffff
-    0x48, 0xbf, // movabs rdi, memory_pointer
-      memory_pointer[0],
-      memory_pointer[1],
-      memory_pointer[2],
-      memory_pointer[3],
-      memory_pointer[4],
-      memory_pointer[5],
-      memory_pointer[6],
-      memory_pointer[7],
+    0x48, 0x89, 0xef, // mov rdi, rbp
     0x48, 0xb8, // movabs rax, fn_pointer
       fn_pointer[0],
       fn_pointer[1],
@@ -2233,23 +2352,16 @@ fn emit_pop(dest: X86Reg16, memory_base: usize, exec: &mut [u8]) -> usize {
   length
 }
 
-fn emit_hl_indirect_partial_read(memory_base: usize, exec: &mut [u8]) -> usize {
+fn emit_hl_indirect_partial_read(exec: &mut [u8]) -> usize {
   let fn_pointer = address_as_bytes(crate::mem::memory_read_byte as u64);
-  let memory_pointer = address_as_bytes(memory_base as u64);
   let code = [
     0x50, // push rax
     0x51, // push rcx
     0x52, // push rdx
-    0x48, 0x89, 0xce, // mov rsi, rcx
-    0x48, 0xbf, // movabs rdi, memory_pointer
-      memory_pointer[0],
-      memory_pointer[1],
-      memory_pointer[2],
-      memory_pointer[3],
-      memory_pointer[4],
-      memory_pointer[5],
-      memory_pointer[6],
-      memory_pointer[7],
+    0x0f, 0xb7, 0xf1, // movzx esi, cx
+  ];
+  let slow_path = [
+    0x48, 0x89, 0xef, // mov rdi, rbp
 
     0x48, 0xb8, // movabs rax, fn_pointer
       fn_pointer[0],
@@ -2266,24 +2378,19 @@ fn emit_hl_indirect_partial_read(memory_base: usize, exec: &mut [u8]) -> usize {
   ];
   let length = code.len();
   exec[..length].copy_from_slice(&code);
-  length
+  let access = [0x0f, 0xb6, 0x14, 0x37]; // movzx edx, byte [rdi + rsi]
+  length + emit_paged_access(READ_PAGES, &access, &slow_path, &mut exec[length..])
 }
 
-fn emit_hl_indirect_partial_write(memory_base: usize, exec: &mut [u8]) -> usize {
+fn emit_hl_indirect_partial_write(exec: &mut [u8]) -> usize {
   let fn_pointer = address_as_bytes(crate::mem::memory_write_byte as u64);
-  let memory_pointer = address_as_bytes(memory_base as u64);
   let code = [
     0x88, 0x44, 0x24, 0x10, // mov [rsp + 16], al
-    0x48, 0x8b, 0x74, 0x24, 0x08, // mov rsi, [rsp + 8]
-    0x48, 0xbf, // movabs rdi, memory_pointer
-      memory_pointer[0],
-      memory_pointer[1],
-      memory_pointer[2],
-      memory_pointer[3],
-      memory_pointer[4],
-      memory_pointer[5],
-      memory_pointer[6],
-      memory_pointer[7],
+    0x0f, 0xb7, 0x74, 0x24, 0x08, // movzx esi, word [rsp + 8]
+  ];
+  let slow_path = [
+    0x0f, 0xb6, 0xd2, // movzx edx, dl
+    0x48, 0x89, 0xef, // mov rdi, rbp
 
     0x48, 0xb8, // movabs rax, fn_pointer
       fn_pointer[0],
@@ -2295,6 +2402,25 @@ fn emit_hl_indirect_partial_write(memory_base: usize, exec: &mut [u8]) -> usize
       fn_pointer[6],
       fn_pointer[7],
     0xff, 0xd0, // call rax
+  ];
+  let mut length = code.len();
+  exec[..length].copy_from_slice(&code);
+  let access = [0x88, 0x14, 0x37]; // mov [rdi + rsi], dl
+  length += emit_paged_access(WRITE_PAGES, &access, &slow_path, &mut exec[length..]);
+  let restore = [
+    0x5a, // pop rdx
+    0x59, // pop rcx
+    0x58, // pop rax
+  ];
+  exec[length..(length + restore.len())].copy_from_slice(&restore);
+  length + restore.len()
+}
+
+/// Restore the registers saved by a partial read without writing anything
+/// back to memory. Flags in AL are preserved.
+fn emit_hl_indirect_partial_release(exec: &mut [u8]) -> usize {
+  let code = [
+    0x88, 0x44, 0x24, 0x10, // mov [rsp + 16], al
     0x5a, // pop rdx
     0x59, // pop rcx
     0x58, // pop rax
@@ -2306,22 +2432,12 @@ fn emit_hl_indirect_partial_write(memory_base: usize, exec: &mut [u8]) -> usize
 
 /// Read the value stored at (HL) into E
 /// Make sure $rdx can be restored after this result is used
-fn emit_hl_indirect_read(memory_base: usize, exec: &mut [u8]) -> usize {
+fn emit_hl_indirect_read(exec: &mut [u8]) -> usize {
   let fn_pointer = address_as_bytes(crate::mem::memory_read_byte as u64);
-  let memory_pointer = address_as_bytes(memory_base as u64);
-  let code = [
+  let slow_path = [
     0x50, // push rax
     0x51, // push rcx
-    0x48, 0x89, 0xce, // mov rsi, rcx
-    0x48, 0xbf, // movabs rdi, memory_pointer
-      memory_pointer[0],
-      memory_pointer[1],
-      memory_pointer[2],
-      memory_pointer[3],
-      memory_pointer[4],
-      memory_pointer[5],
-      memory_pointer[6],
-      memory_pointer[7],
+    0x48, 0x89, 0xef, // mov rdi, rbp
 
     0x48, 0xb8, // movabs rax, fn_pointer
       fn_pointer[0],
@@ -2337,11 +2453,94 @@ fn emit_hl_indirect_read(memory_base: usize, exec: &mut [u8]) -> usize {
     0x59, // pop rcx
     0x58, // pop rax
   ];
+  exec[..3].copy_from_slice(&[0x0f, 0xb7, 0xf1]); // movzx esi, cx
+  let access = [0x0f, 0xb6, 0x14, 0x37]; // movzx edx, byte [rdi + rsi]
+  3 + emit_paged_access(READ_PAGES, &access, &slow_path, &mut exec[3..])
+}
+
+/// Read or write a byte through the page table, with its address already
+/// zero-extended in esi. When the address is on a page that compiled code can
+/// access directly, rdi is pointed at the page and esi reduced to the offset
+/// within it, and `access` reads or writes [rdi + rsi]. Only rdi and rsi are
+/// modified along the way. Otherwise, `slow_path` runs with the address still
+/// in esi.
+fn emit_paged_access(table: u32, access: &[u8], slow_path: &[u8], exec: &mut [u8]) -> usize {
+  debug_assert!(slow_path.len() < 0x80);
+  let table = table.to_le_bytes();
+  let mut code = vec![
+    0x89, 0xf7, // mov edi, esi
+    0xc1, 0xef, 0x0c, // shr edi, 12
+    0x48, 0x8b, 0xbc, 0xfd, // mov rdi, [rbp + rdi * 8 + table]
+      table[0], table[1], table[2], table[3],
+    0x48, 0x85, 0xff, // test rdi, rdi
+    0x74, (6 + access.len() + 2) as u8, // jz slow_path
+    0x81, 0xe6, 0xff, 0x0f, 0x00, 0x00, // and esi, 0xfff
+  ];
+  code.extend_from_slice(access);
+  code.extend_from_slice(&[0xeb, slow_path.len() as u8]); // jmp done
+  // slow_path:
+  code.extend_from_slice(slow_path);
+  // done:
+  let length = code.len();
+  exec[..length].copy_from_slice(&code);
+  length
+}
+
+/// Read or write A at a fixed address, directly if its page can be accessed
+/// and through `slow_path` if not. The last page is mostly IO, so only high
+/// RAM is looked up there.
+fn emit_direct_a_access(address: u16, write: bool, slow_path: &[u8], exec: &mut [u8]) -> usize {
+  debug_assert!(slow_path.len() < 0x80);
+  let (entry, offset) = match address {
+    0xff80..=0xfffe => (HIGH_RAM_PAGE, address as u32 - 0xff80),
+    0xf000..=0xffff => {
+      exec[..slow_path.len()].copy_from_slice(slow_path);
+      return slow_path.len();
+    },
+    _ => {
+      let table = if write { WRITE_PAGES } else { READ_PAGES };
+      (table + (address as u32 >> 12) * 8, address as u32 & 0xfff)
+    },
+  };
+  let offset_bytes = offset.to_le_bytes();
+  let mut access = if write {
+    vec![0x88, 0xa7] // mov [rdi + offset], ah
+  } else {
+    vec![0x8a, 0xa7] // mov ah, [rdi + offset]
+  };
+  access.extend_from_slice(&offset_bytes);
+  if write && entry == HIGH_RAM_PAGE {
+    // writes to high RAM are always tracked, in case code there was compiled
+    let dirty = (HIGH_RAM_DIRTY + offset / 8).to_le_bytes();
+    access.extend_from_slice(&[
+      0x80, 0x8d, // or byte [rbp + dirty], bit
+        dirty[0], dirty[1], dirty[2], dirty[3],
+        1 << (offset % 8),
+    ]);
+  }
+  let entry = entry.to_le_bytes();
+  let mut code = vec![
+    0x48, 0x8b, 0xbd, // mov rdi, [rbp + entry]
+      entry[0], entry[1], entry[2], entry[3],
+    0x48, 0x85, 0xff, // test rdi, rdi
+    0x74, (access.len() + 2) as u8, // jz slow_path
+  ];
+  code.extend_from_slice(&access);
+  code.extend_from_slice(&[0xeb, slow_path.len() as u8]); // jmp done
+  // slow_path:
+  code.extend_from_slice(slow_path);
+  // done:
   let length = code.len();
   exec[..length].copy_from_slice(&code);
   length
 }
 
+/// ModRM byte addressing [rdi + rsi] through a SIB byte, for an 8-bit
+/// register operand
+fn page_operand(reg: X86Reg8) -> u8 {
+  (register_to_register(reg, X86Reg8::AL) & 0x38) | 0b100
+}
+
 fn emit_push_register(reg: X86Reg64, exec: &mut [u8]) -> usize {
   match reg {
     X86Reg64::RAX => {
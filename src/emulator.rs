@@ -1,16 +1,81 @@
+#[cfg(jit_backend)]
 use crate::cache::CodeCache;
 use crate::cart::Header;
+use crate::cheats::{Cheat, CheatList};
 use crate::cpu::{self, Registers};
-use crate::interpreter;
-use crate::mem::{MemoryAreas, can_dynarec, memory_write_byte, memory_write_word};
-use crate::timing::{ClockCycles, MachineCycles};
+use crate::debug::breakpoint::{get_bank_for_address, Breakpoint, BreakpointSet};
+use crate::debug::watchpoint::{Access, WatchHit, Watchpoint};
+use crate::decoder;
+use crate::error::{Error, JitError, MemError, RomError};
+use crate::debug::framehash::FrameHasher;
+use crate::debug::history::{self, Engine};
+use crate::debug::hud::PerfCounters;
+use crate::debug::search::{RamSearch, SearchFilter, SearchResult};
+use crate::debug::stack::StackMonitor;
+use crate::debug::testrom::{self, TestResult};
+use crate::debug::trace::{TraceEntry, Tracer};
+use crate::hooks::{HookId, Hooks};
+use crate::input::InputDispatcher;
+use crate::interpreter::{self, idle::{self, IdleLoop}};
+use crate::netplay::NetplaySession;
+use crate::patch::crc32;
+use crate::recording::VideoRecorder;
+use crate::rewind::RewindBuffer;
+use crate::savestate::{StateReader, StateWriter, STATE_MAGIC, STATE_VERSION};
+use crate::stats::PlaySession;
+use crate::system::{self, RomSizePolicy};
+#[cfg(jit_backend)]
+use crate::mem::{can_dynarec, can_dynarec_cart_ram, can_dynarec_hram, can_dynarec_wram};
+use crate::mem::{MemoryAreas, memory_peek_byte, memory_push_word, memory_read_byte, memory_write_byte};
+use crate::timing::{self, ClockCycles, MachineCycles};
 use std::fs::File;
+use std::time::Instant;
+
+/// Upper bound on idle loop iterations skipped at once, so that the shell
+/// regains control periodically even if the loop never exits
+const MAX_IDLE_ITERATIONS: usize = 1024;
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum RunState {
   Run,
   Stop,
   Halt,
+  /// The CPU ran an invalid opcode and hung, as real hardware does. Nothing
+  /// but a reset brings it back, though the rest of the system keeps running.
+  Locked,
+}
+
+/// Reason for execution stopping before it was asked to
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BreakEvent {
+  /// The CPU reached a breakpoint. The instruction at the address has not
+  /// run yet.
+  Breakpoint { address: u16, bank: Option<usize> },
+  /// The instruction at `ip` accessed a watched address, and has completed
+  Watchpoint { ip: u16, hit: WatchHit },
+  /// The CPU reached an `LD B, B`, which test ROMs and homebrew use as a
+  /// breakpoint. The instruction has not run yet.
+  SoftwareBreakpoint { address: u16 },
+  /// The CPU couldn't fetch its next instruction, and has stopped
+  Fault(MemError),
+}
+
+impl std::fmt::Display for BreakEvent {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      BreakEvent::Breakpoint { address, bank: Some(bank) } => write!(f, "Breakpoint hit at {:02X}:{:04X}", bank, address),
+      BreakEvent::Breakpoint { address, bank: None } => write!(f, "Breakpoint hit at {:04X}", address),
+      BreakEvent::Watchpoint { ip, hit } => {
+        let access = match hit.access {
+          Access::Read => "Read",
+          Access::Write => "Write",
+        };
+        write!(f, "Watchpoint hit: {} {:02X} at {:04X}, by the instruction at {:04X}", access, hit.value, hit.address, ip)
+      },
+      BreakEvent::SoftwareBreakpoint { address } => write!(f, "Software breakpoint hit at {:04X}", address),
+      BreakEvent::Fault(error) => write!(f, "CPU stopped: {}", error),
+    }
+  }
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -21,42 +86,628 @@ pub enum InterruptState {
 }
 
 pub struct Core {
+  #[cfg(jit_backend)]
   pub cache: CodeCache,
   pub registers: Registers,
   pub last_block_cycle_length: usize,
   pub memory: MemoryAreas,
   pub interrupts_enabled: InterruptState,
   pub run_state: RunState,
+  /// Turns host input into joypad state, once per frame
+  pub input: InputDispatcher,
+  /// When set, every compiled block is checked against the interpreter
+  pub verify_jit: bool,
+  /// When set, the interpreter fast-forwards through loops that are only
+  /// waiting for an external event
+  pub skip_idle_loops: bool,
+  /// How long compiled code may run before returning, so that peripherals
+  /// can catch up
+  pub jit_cycle_budget: MachineCycles,
+  /// When set, executed instructions are logged to a file or ring buffer
+  pub tracer: Option<Tracer>,
+  /// When set, every `LD B, B` instruction is treated as a breakpoint
+  pub software_breakpoints: bool,
+  /// When set, the stack pointer is checked after every instruction, and
+  /// warnings are printed if it moves somewhere suspicious
+  pub stack_monitor: Option<StackMonitor>,
+  /// When set, frames are run in lockstep with another instance, along with
+  /// a core for the remote player
+  pub netplay: Option<Box<NetplaySession>>,
+  /// When set, every frame completed by `run_frame` is recorded
+  pub recorder: Option<VideoRecorder>,
+  /// When set, every frame completed by `run_frame` is hashed, to be logged
+  /// or compared to an earlier run
+  pub frame_hasher: Option<FrameHasher>,
+  /// When set, play time for the current game is added to its statistics
+  /// on shutdown
+  pub play_session: Option<PlaySession>,
+  /// When set, a save state is kept every few frames so that the game can be
+  /// rewound
+  pub rewind: Option<RewindBuffer>,
+  /// Anything unusual about how the ROM was loaded, like a patch that was
+  /// found next to it, or a file that had to be resized. Reporting them is
+  /// left to the frontend.
+  pub load_warnings: Vec<String>,
+  /// Warnings and reports raised while running, like a stack warning, an
+  /// error that ended netplay or a recording, or the summary written when a
+  /// recording finishes. They are kept until the frontend takes them with
+  /// `take_messages`.
+  pub messages: Vec<String>,
+  /// Set when the next op run by `run_interp` begins a new block
+  interp_block_start: bool,
+  /// When set, ROM code is compiled and run by the dynarec. Otherwise, every
+  /// op is interpreted.
+  use_jit: bool,
+  breakpoints: BreakpointSet,
+  /// Address of the last breakpoint reported, so that resuming runs the
+  /// instruction there instead of stopping on it again
+  resume_ip: Option<u16>,
+  /// Set while `run_frame` is partway through a frame, so that input is only
+  /// committed once per frame even if a break interrupts it
+  frame_in_progress: bool,
+  /// While set, frames only run one at a time through `advance_frame`
+  paused: bool,
+  /// Why the CPU stopped, if it ran into memory it can't fetch code from,
+  /// until `update` reports it
+  fault: Option<MemError>,
+  /// Machine cycles run since the core was created
+  cycles_elapsed: u64,
+  /// Clock cycles that `step_cycles` ran past the end of its last request,
+  /// which are taken out of the next one
+  step_overshoot: ClockCycles,
+  /// Callbacks registered by tools built on the core
+  hooks: Hooks,
+  cheats: CheatList,
+  /// RAM search in progress, narrowed down by each filter
+  ram_search: Option<RamSearch>,
+  /// When set, host time spent on the CPU and the PPU is measured for the
+  /// performance HUD
+  pub perf_counters: Option<PerfCounters>,
+  /// Kept so that a reset can start over from the same place
+  boot_rom: Option<Box<[u8]>>,
+  start_registers: Registers,
+}
+
+/// Whether new cores run compiled code. Tests can pick the engine for the
+/// cores they create, so that the same test covers both.
+fn default_use_jit() -> bool {
+  #[cfg(test)]
+  if let Some(use_jit) = tests::ENGINE.with(|engine| engine.get()) {
+    return use_jit;
+  }
+  cfg!(all(feature = "jit", jit_backend))
 }
 
 impl Core {
+  /// Create a core that runs `code` as its ROM, without a cartridge header.
+  /// Blocks are compiled the first time they run, rather than once they are
+  /// hot, so that tests and benchmarks see compiled code right away.
   pub fn with_code_block(code: Box<[u8]>) -> Self {
-    Self {
+    let mut core = Self {
+      #[cfg(jit_backend)]
       cache: CodeCache::new(),
       registers: Registers::new(),
       last_block_cycle_length: 0,
       memory: MemoryAreas::with_rom(code),
       interrupts_enabled: InterruptState::Disabled,
       run_state: RunState::Run,
+      input: InputDispatcher::new(),
+      verify_jit: false,
+      skip_idle_loops: true,
+      jit_cycle_budget: timing::SCANLINE_CYCLES,
+      tracer: None,
+      software_breakpoints: false,
+      stack_monitor: None,
+      netplay: None,
+      recorder: None,
+      frame_hasher: None,
+      play_session: None,
+      rewind: None,
+      load_warnings: Vec::new(),
+      messages: Vec::new(),
+      interp_block_start: true,
+      use_jit: default_use_jit(),
+      breakpoints: BreakpointSet::new(),
+      resume_ip: None,
+      frame_in_progress: false,
+      paused: false,
+      fault: None,
+      cycles_elapsed: 0,
+      step_overshoot: ClockCycles(0),
+      hooks: Hooks::new(),
+      cheats: CheatList::new(),
+      ram_search: None,
+      perf_counters: None,
+      boot_rom: None,
+      start_registers: Registers::new(),
+    };
+    #[cfg(jit_backend)]
+    {
+      core.cache.compile_threshold = 0;
+      core.check_jit_memory();
+    }
+    core
+  }
+
+  /// Create a core for a cartridge ROM. If a boot ROM is provided, execution
+  /// begins inside of it; otherwise, the CPU starts in the state the boot ROM
+  /// would have left it in.
+  pub fn from_rom_file(rom_file: &mut File, header: Header, size_policy: RomSizePolicy, boot_rom: Option<Box<[u8]>>) -> Result<Self, Error> {
+    let (memory, warning) = MemoryAreas::with_rom_file(rom_file, &header, size_policy)?;
+    Ok(Self::with_cartridge(memory, boot_rom, warning))
+  }
+
+  /// Create a core for a cartridge ROM that has already been read into
+  /// memory, like one that has been patched
+  pub fn from_rom_data(data: Vec<u8>, header: Header, size_policy: RomSizePolicy, boot_rom: Option<Box<[u8]>>) -> Result<Self, Error> {
+    let (memory, warning) = MemoryAreas::with_rom_data(data, &header, size_policy)?;
+    Ok(Self::with_cartridge(memory, boot_rom, warning))
+  }
+
+  /// Create a core from a ROM image in memory, or a zip archive containing
+  /// one, without touching the filesystem. The ROM is padded or truncated to
+  /// the size in its header, and the header checksum is left for the caller
+  /// to check.
+  pub fn from_rom_bytes(data: Vec<u8>) -> Result<Self, Error> {
+    let data = crate::zip::unpack_rom(data).map_err(RomError::Invalid)?;
+    let header = system::read_header_from_bytes(&data)?;
+    Self::from_rom_data(data, header, RomSizePolicy::Header, None)
+  }
+
+  /// Open a ROM file, or a zip archive containing one, and check its header
+  /// before creating a core for it. A patch is applied from `patch_file` if
+  /// one is given, or from an IPS or BPS file sitting next to the ROM, and
+  /// noted in `load_warnings`. Plain ROM files are mapped rather than read
+  /// into memory.
+  pub fn open_rom_file(name: &str, size_policy: RomSizePolicy, patch_file: Option<&str>, boot_rom: Option<Box<[u8]>>) -> Result<Self, Error> {
+    let patch_file = patch_file.map(String::from).or_else(|| system::find_patch_file(name));
+    if let Some(patch_file) = patch_file {
+      // the header is read from the patched ROM, since patches often change
+      // the title or size
+      let data = system::load_patched_rom(name, &patch_file)?;
+      let mut core = Self::open_rom_data(data, size_policy, boot_rom)?;
+      core.load_warnings.insert(0, format!("Applied patch \"{}\"", patch_file));
+      return Ok(core);
+    }
+    // Archives can't be mapped, so the ROM inside is read into memory
+    if system::is_zip_file(name) {
+      return Self::open_rom_data(system::read_rom_data(name)?, size_policy, boot_rom);
     }
+    let mut rom_file = system::open_rom_file(String::from(name))?;
+    let header = system::read_header(&mut rom_file)?;
+    check_header(&header)?;
+    Self::from_rom_file(&mut rom_file, header, size_policy, boot_rom)
   }
 
-  pub fn from_rom_file(rom_file: &mut File, header: Header) -> Self {
-    Self {
+  fn open_rom_data(data: Vec<u8>, size_policy: RomSizePolicy, boot_rom: Option<Box<[u8]>>) -> Result<Self, Error> {
+    let header = system::read_header_from_bytes(&data)?;
+    check_header(&header)?;
+    Self::from_rom_data(data, header, size_policy, boot_rom)
+  }
+
+  fn with_cartridge(mut memory: MemoryAreas, boot_rom: Option<Box<[u8]>>, warning: Option<String>) -> Self {
+    let saved_boot_rom = boot_rom.clone();
+    let registers = match boot_rom {
+      Some(boot_rom) => {
+        memory.map_boot_rom(boot_rom);
+        Registers::new()
+      },
+      None => Registers::after_boot(),
+    };
+    let mut core = Self {
+      #[cfg(jit_backend)]
       cache: CodeCache::new(),
-      registers: Registers::after_boot(),
+      registers,
       last_block_cycle_length: 0,
-      memory: MemoryAreas::with_rom_file(rom_file, &header),
+      memory,
       interrupts_enabled: InterruptState::Disabled,
       run_state: RunState::Run,
+      input: InputDispatcher::new(),
+      verify_jit: false,
+      skip_idle_loops: true,
+      jit_cycle_budget: timing::SCANLINE_CYCLES,
+      tracer: None,
+      software_breakpoints: false,
+      stack_monitor: None,
+      netplay: None,
+      recorder: None,
+      frame_hasher: None,
+      play_session: None,
+      rewind: None,
+      load_warnings: warning.into_iter().collect(),
+      messages: Vec::new(),
+      interp_block_start: true,
+      use_jit: default_use_jit(),
+      breakpoints: BreakpointSet::new(),
+      resume_ip: None,
+      frame_in_progress: false,
+      paused: false,
+      fault: None,
+      cycles_elapsed: 0,
+      step_overshoot: ClockCycles(0),
+      hooks: Hooks::new(),
+      cheats: CheatList::new(),
+      ram_search: None,
+      perf_counters: None,
+      boot_rom: saved_boot_rom,
+      start_registers: registers,
+    };
+    #[cfg(jit_backend)]
+    core.check_jit_memory();
+    core
+  }
+
+  /// Returns true if the dynarec can generate code for this architecture
+  pub const fn jit_available() -> bool {
+    cfg!(jit_backend)
+  }
+
+  pub fn is_jit_enabled(&self) -> bool {
+    self.use_jit
+  }
+
+  /// Switch between compiled and interpreted execution. The JIT is on by
+  /// default when built with the `jit` feature, but it can be toggled at any
+  /// time on architectures that have a backend.
+  pub fn set_jit_enabled(&mut self, enabled: bool) -> Result<(), Error> {
+    if enabled && !Self::jit_available() {
+      return Err(Error::Jit(JitError::Unavailable));
+    }
+    #[cfg(jit_backend)]
+    if enabled {
+      self.cache.check_available()?;
+    }
+    self.use_jit = enabled;
+    Ok(())
+  }
+
+  /// Fall back to the interpreter if the code cache has no executable memory,
+  /// either because it couldn't be allocated or because it was given up
+  #[cfg(jit_backend)]
+  fn check_jit_memory(&mut self) {
+    if let (true, Err(error)) = (self.use_jit, self.cache.check_available()) {
+      self.use_jit = false;
+      self.messages.push(format!("{}, falling back to the interpreter", error));
+    }
+  }
+
+  pub fn is_ram_compilation_enabled(&self) -> bool {
+    self.memory.dirty_ram.enabled
+  }
+
+  /// Compile code that runs from work RAM or cartridge RAM, instead of
+  /// interpreting it. Every write to those is tracked while this is on, so
+  /// that blocks are discarded once the code they were compiled from
+  /// changes. Code in high RAM is always compiled.
+  pub fn set_ram_compilation(&mut self, enabled: bool) {
+    let dirty = &mut self.memory.dirty_ram;
+    // blocks compiled before it was last turned off may be out of date
+    if enabled && !dirty.enabled {
+      dirty.mark_all();
+    }
+    dirty.enabled = enabled;
+  }
+
+  pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+    self.breakpoints.add(breakpoint);
+  }
+
+  /// Returns true if the breakpoint was present
+  pub fn remove_breakpoint(&mut self, breakpoint: Breakpoint) -> bool {
+    self.breakpoints.remove(breakpoint)
+  }
+
+  pub fn breakpoints(&self) -> &[Breakpoint] {
+    self.breakpoints.list()
+  }
+
+  /// Watchpoints are checked on every memory access, so while any are set,
+  /// all code is interpreted
+  pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+    self.memory.watchpoints.add(watchpoint);
+  }
+
+  /// Returns true if the watchpoint was present
+  pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+    self.memory.watchpoints.remove(watchpoint)
+  }
+
+  pub fn watchpoints(&self) -> &[Watchpoint] {
+    self.memory.watchpoints.list()
+  }
+
+  /// Returns a break event if a breakpoint is set at the instruction about
+  /// to run
+  pub fn check_breakpoint(&self) -> Option<BreakEvent> {
+    if self.run_state != RunState::Run {
+      return None;
+    }
+    let address = self.registers.ip as u16;
+    if self.breakpoints.should_break(address, &self.memory) {
+      let bank = get_bank_for_address(address, &self.memory);
+      return Some(BreakEvent::Breakpoint { address, bank });
+    }
+    if self.is_software_breakpoint(address) {
+      return Some(BreakEvent::SoftwareBreakpoint { address });
+    }
+    None
+  }
+
+  fn is_software_breakpoint(&self, address: u16) -> bool {
+    // LD B, B
+    self.software_breakpoints && memory_peek_byte(&self.memory, address) == 0x40
+  }
+
+  /// Whether any kind of breakpoint could stop execution
+  fn has_breakpoints(&self) -> bool {
+    self.software_breakpoints || !self.breakpoints.is_empty()
+  }
+
+  /// Call `hook` at the end of every frame
+  pub fn on_frame<F: FnMut(&mut Core) + Send + 'static>(&mut self, hook: F) -> HookId {
+    self.hooks.add_frame_hook(Box::new(hook))
+  }
+
+  /// Call `hook` after every instruction that makes a matching access to
+  /// memory, once for each access
+  pub fn on_memory_access<F: FnMut(&mut Core, WatchHit) + Send + 'static>(&mut self, range: Watchpoint, hook: F) -> HookId {
+    let id = self.hooks.add_memory_hook(range, Box::new(hook));
+    self.memory.access_log.set_ranges(self.hooks.memory_ranges());
+    id
+  }
+
+  /// Returns true if the hook was registered. A hook can remove itself, or
+  /// any other, while it runs.
+  pub fn remove_hook(&mut self, id: HookId) -> bool {
+    let removed = self.hooks.remove(id);
+    self.memory.access_log.set_ranges(self.hooks.memory_ranges());
+    removed
+  }
+
+  fn run_frame_hooks(&mut self) {
+    if !self.hooks.has_frame_hooks() {
+      return;
+    }
+    let mut running = self.hooks.take_running();
+    for hook in running.frame_hooks_mut() {
+      hook(self);
+    }
+    self.hooks.restore(running);
+    self.memory.access_log.set_ranges(self.hooks.memory_ranges());
+  }
+
+  /// Call the hooks for any logged accesses made by the last instruction
+  fn run_memory_hooks(&mut self) {
+    if self.memory.access_log.is_empty() {
+      return;
+    }
+    let hits = self.memory.access_log.take_hits();
+    if hits.is_empty() {
+      return;
+    }
+    let mut running = self.hooks.take_running();
+    for hit in hits {
+      for hook in running.memory_hooks_for(hit) {
+        hook(self, hit);
+      }
+    }
+    self.hooks.restore(running);
+    self.memory.access_log.set_ranges(self.hooks.memory_ranges());
+  }
+
+  /// Add a Game Genie or GameShark code. Game Genie codes patch the ROM right
+  /// away, while GameShark codes are applied at the end of every frame.
+  pub fn add_cheat(&mut self, code: &str) -> Result<Cheat, String> {
+    let cheat = Cheat::parse(code)?;
+    let offsets = self.cheats.add(code, self.memory.writable_rom())?;
+    self.invalidate_rom(&offsets);
+    Ok(cheat)
+  }
+
+  /// Returns true if the code had been added
+  pub fn remove_cheat(&mut self, code: &str) -> bool {
+    match self.cheats.remove(code, self.memory.writable_rom()) {
+      Some(offsets) => {
+        self.invalidate_rom(&offsets);
+        true
+      },
+      None => false,
+    }
+  }
+
+  pub fn cheat_codes(&self) -> Vec<String> {
+    self.cheats.codes().map(String::from).collect()
+  }
+
+  /// Begin a new RAM search over work RAM and high RAM, as they are currently
+  /// mapped. Returns the number of addresses being searched.
+  pub fn start_ram_search(&mut self) -> usize {
+    let addresses = (0xc000..0xe000).chain(0xff80..0xffff);
+    let snapshot = addresses.map(|address| SearchResult { address, value: self.search_peek(address) });
+    let search = RamSearch::new(snapshot);
+    let count = search.results().len();
+    self.ram_search = Some(search);
+    count
+  }
+
+  /// Narrow down the RAM search, comparing memory now to the values it held
+  /// when the search started or was last filtered. Returns the number of
+  /// addresses left.
+  pub fn filter_ram_search(&mut self, filter: SearchFilter) -> Result<usize, String> {
+    let mut search = self.ram_search.take().ok_or_else(|| String::from("No RAM search has been started"))?;
+    let count = search.filter(filter, |address| self.search_peek(address));
+    self.ram_search = Some(search);
+    Ok(count)
+  }
+
+  /// Addresses remaining in the RAM search, with their most recent values
+  pub fn ram_search_results(&self) -> &[SearchResult] {
+    match &self.ram_search {
+      Some(search) => search.results(),
+      None => &[],
+    }
+  }
+
+  pub fn end_ram_search(&mut self) {
+    self.ram_search = None;
+  }
+
+  /// Read work RAM or high RAM directly, so that a search isn't thrown off by
+  /// an OAM DMA occupying the bus
+  fn search_peek(&self, address: u16) -> u8 {
+    match address {
+      0xc000..=0xcfff => self.memory.work_ram[address as usize - 0xc000],
+      0xd000..=0xdfff => self.memory.work_ram[0x1000 * self.memory.wram_bank + (address as usize - 0xd000)],
+      _ => self.memory.high_ram[address as usize - 0xff80],
+    }
+  }
+
+  /// Drop compiled code that was translated from ROM that has since changed
+  #[cfg_attr(not(jit_backend), allow(unused_variables))]
+  fn invalidate_rom(&mut self, offsets: &[usize]) {
+    #[cfg(jit_backend)]
+    for offset in offsets.iter() {
+      let bank = offset / 0x4000;
+      let address = match bank {
+        0 => *offset,
+        _ => 0x4000 + (offset & 0x3fff),
+      };
+      self.cache.invalidate_rom(bank, address as u16);
+    }
+  }
+
+  /// GameShark codes write their values once per frame
+  fn apply_ram_cheats(&mut self) {
+    if self.cheats.is_empty() {
+      return;
+    }
+    let writes: Vec<(u8, u16, u8)> = self.cheats.ram_writes().collect();
+    for (kind, address, value) in writes {
+      if (0x80..=0x8f).contains(&kind) && (0xa000..0xc000).contains(&address) {
+        let offset = (kind as usize & 0x0f) * 0x2000 + (address as usize - 0xa000);
+        if let Some(byte) = self.memory.cart_ram.get_mut(offset) {
+          *byte = value;
+        }
+        // the bank may not be mapped, but compiled code is discarded from
+        // every bank
+        if self.memory.dirty_ram.enabled {
+          self.memory.dirty_ram.mark(address);
+        }
+      } else {
+        memory_write_byte(&mut self.memory, address, value);
+      }
+    }
+  }
+
+  /// Report the first watched access since the last check, made by the
+  /// instruction at `ip`
+  fn take_watch_event(&self, ip: u16) -> Option<BreakEvent> {
+    let hit = self.memory.watchpoints.take_hit()?;
+    Some(BreakEvent::Watchpoint { ip, hit })
+  }
+
+  /// Compiled code can't stop partway through a block, so a block must be
+  /// interpreted one instruction at a time if it contains a breakpoint
+  /// after its first instruction, if any watchpoints or memory hooks are set,
+  /// or if every instruction is being traced or having its stack usage
+  /// checked
+  fn needs_interpreter(&self) -> bool {
+    if !self.memory.watchpoints.is_empty() || !self.memory.access_log.is_empty() || self.stack_monitor.is_some() {
+      return true;
+    }
+    if let Some(tracer) = self.tracer.as_ref() {
+      if !tracer.blocks_only {
+        return true;
+      }
+    }
+    if !self.has_breakpoints() {
+      return false;
+    }
+    let mut address = self.registers.ip as u16;
+    loop {
+      let mut bytes = [0; 3];
+      for (offset, byte) in bytes.iter_mut().enumerate() {
+        *byte = memory_peek_byte(&self.memory, address.wrapping_add(offset as u16));
+      }
+      let (op, length, _) = decoder::decode(&bytes);
+      if op.is_block_end() {
+        return false;
+      }
+      address = address.wrapping_add(length as u16);
+      if address == self.registers.ip as u16 {
+        // wrapped around the entire address space without ending a block
+        return false;
+      }
+      if self.breakpoints.should_break(address, &self.memory) || self.is_software_breakpoint(address) {
+        return true;
+      }
+    }
+  }
+
+  /// Interpret exactly one instruction, even if a breakpoint is set on it.
+  /// While the CPU is halted or stopped, only the peripherals advance.
+  /// Returns an event if the instruction accessed a watched address.
+  pub fn step_instruction(&mut self) -> Option<BreakEvent> {
+    let ip = self.registers.ip as u16;
+    self.resume_ip = None;
+    match self.run_state {
+      RunState::Run => self.run_interp(),
+      _ => self.run_halted(),
+    }
+    self.take_watch_event(ip)
+  }
+
+  /// Advance the CPU and the peripherals together by `cycles` clock cycles.
+  /// Instructions are interpreted one at a time, and the peripherals catch up
+  /// after each one, so nothing runs more than a single instruction (or an
+  /// interrupt dispatch) ahead of anything else. An instruction is never
+  /// split, though: if the last one ends past the requested cycle, the extra
+  /// time is taken out of the next call, so that a series of calls keeps to
+  /// the total asked for.
+  /// Stops early with an event if a breakpoint or watchpoint is hit, and the
+  /// rest of the requested cycles are dropped.
+  pub fn step_cycles(&mut self, cycles: ClockCycles) -> Option<BreakEvent> {
+    let requested = cycles.as_usize() as u64;
+    let overshoot = self.step_overshoot.as_usize() as u64;
+    if requested <= overshoot {
+      self.step_overshoot = ClockCycles((overshoot - requested) as usize);
+      return None;
+    }
+    let target = self.cycles_elapsed * 4 + requested - overshoot;
+    self.step_overshoot = ClockCycles(0);
+    // skipping an idle loop would run far past the target
+    let skip_idle_loops = core::mem::replace(&mut self.skip_idle_loops, false);
+    let mut event = None;
+    while self.cycles_elapsed * 4 < target {
+      let ip = self.registers.ip as u16;
+      if self.run_state == RunState::Run && self.resume_ip.take() != Some(ip) {
+        if let Some(hit) = self.check_breakpoint() {
+          self.resume_ip = Some(ip);
+          event = Some(hit);
+          break;
+        }
+      }
+      event = self.step_instruction();
+      if event.is_some() {
+        break;
+      }
     }
+    self.skip_idle_loops = skip_idle_loops;
+    if event.is_none() {
+      self.step_overshoot = ClockCycles((self.cycles_elapsed * 4 - target) as usize);
+    }
+    event
   }
 
   /// If interrupts are enabled, check the current interrupt flags and enter the
   /// highest-priority active interrupt.
   pub fn handle_interrupt(&mut self) {
     let interrupts = self.memory.io.get_active_interrupts();
-    if interrupts == 0 {
+    if interrupts == 0 || self.run_state == RunState::Locked {
+      return;
+    }
+    // only the joypad can end STOP, which is checked while stopped
+    if self.run_state == RunState::Stop {
       return;
     }
 
@@ -69,28 +720,31 @@ impl Core {
       _ => return,
     }
 
-    // Initiate the dispatch process
-    // The dispatch is not instantaneous. During this time, it's possible the
-    // flags can change.
+    // Dispatch takes five machine cycles, and the peripherals keep running
+    // through them, so an interrupt raised partway through can still change
+    // which one is entered:
+    //  - two internal cycles
+    //  - the high byte of IP is pushed
+    //  - IE & IF are checked again, and the low byte is pushed
+    //  - the vector is loaded into IP
+    // Memory accesses happen at the start of their cycle, like everywhere
+    // else in the core.
     self.interrupts_enabled = InterruptState::Disabled;
 
-    let old_ip = self.registers.ip as u16;
     let old_ip_high = (self.registers.ip >> 8) as u8;
     let old_ip_low = (self.registers.ip & 0xff) as u8;
     let mem_ptr = &mut self.memory as *mut MemoryAreas;
-    // push high byte of IP
-    {
-      self.registers.sp = self.registers.sp.wrapping_sub(1);
-      let sp = self.registers.sp as u16;
-      memory_write_byte(mem_ptr, sp, old_ip_high);
-    }
+    self.run_peripherals(MachineCycles(2).to_clock_cycles());
+    // The high byte can land on IE or IF, changing which interrupts are
+    // still active before the vector is chosen
+    self.registers.sp = self.registers.sp.wrapping_sub(1);
+    memory_write_byte(mem_ptr, self.registers.sp as u16, old_ip_high);
     let interrupts_updated = self.memory.io.get_active_interrupts();
-    // push low byte of IP
-    {
-      self.registers.sp = self.registers.sp.wrapping_sub(1);
-      let sp = self.registers.sp as u16;
-      memory_write_byte(mem_ptr, sp, old_ip_low);
-    }
+    self.run_peripherals(MachineCycles(1).to_clock_cycles());
+    // The vector has already been chosen, so the low byte landing on IE
+    // doesn't cancel anything
+    self.registers.sp = self.registers.sp.wrapping_sub(1);
+    memory_write_byte(mem_ptr, self.registers.sp as u16, old_ip_low);
 
     let (vector, clear): (u32, u8) = if interrupts_updated == 0 {
       // If the interrupt was cleared since the dispatch began, the original
@@ -110,10 +764,10 @@ impl Core {
     };
 
     self.memory.io.interrupt_flag.clear(clear);
-    // for timing accuracy, skip five machine cycles
-    self.registers.cycles += 5;
+    self.run_peripherals(MachineCycles(2).to_clock_cycles());
 
     self.registers.ip = vector;
+    self.interp_block_start = true;
   }
 
   /// Push the instruction pointer onto the stack, such as at the start of an
@@ -123,97 +777,248 @@ impl Core {
     let ip = self.registers.ip as u16;
     let sp = self.registers.sp as u16;
     let mem_ptr = &mut self.memory as *mut MemoryAreas;
-    memory_write_word(mem_ptr, sp, ip);
+    memory_push_word(mem_ptr, sp, ip);
+  }
+
+  /// Compiled code may run for the configured budget, but should stop early if
+  /// a peripheral has an event scheduled before then
+  #[cfg(jit_backend)]
+  fn get_cycle_budget(&self) -> MachineCycles {
+    let budget = self.jit_cycle_budget;
+    match self.memory.io.cycles_until_next_event() {
+      Some(cycles) => {
+        let until_event = cycles.as_usize().div_ceil(4);
+        MachineCycles(budget.as_usize().min(until_event))
+      },
+      None => budget,
+    }
+  }
+
+  /// Machine cycles run since the core was created
+  pub fn cycles_elapsed(&self) -> u64 {
+    self.cycles_elapsed
+  }
+
+  /// Catch up memory-mapped devices
+  fn run_peripherals(&mut self, cycles: ClockCycles) {
+    self.cycles_elapsed += (cycles.as_usize() / 4) as u64;
+    self.memory.run_clock_cycles(cycles);
+  }
+
+  /// Log the instruction about to run, if it is being traced
+  fn trace(&mut self, engine: Engine) {
+    let address = self.registers.ip as u16;
+    let tracer = match self.tracer.as_mut() {
+      Some(tracer) if tracer.includes(address) => tracer,
+      _ => return,
+    };
+    let mut bytes = [0; 4];
+    for (offset, byte) in bytes.iter_mut().enumerate() {
+      *byte = memory_peek_byte(&self.memory, address.wrapping_add(offset as u16));
+    }
+    tracer.record(TraceEntry {
+      address,
+      bank: get_bank_for_address(address, &self.memory),
+      bytes,
+      registers: self.registers,
+      cycles: self.cycles_elapsed,
+      engine,
+    });
+  }
+
+  /// Record the start of a block in the execution history
+  fn record_block(&self, engine: Engine) {
+    let ip = self.registers.ip as u16;
+    history::record(ip, get_bank_for_address(ip, &self.memory), engine, self.memory.get_mbc_state());
   }
 
   /// Run the next code block, then check for interrupts
   pub fn run_code_block(&mut self) {
-    // if running in interpreted mode, disable any dynamic compilation
-    #[cfg(not(feature = "jit"))]
-    let result = {
-      let mem_ptr = &mut self.memory as *mut MemoryAreas;
-      interpreter::run_code_block(&mut self.registers, mem_ptr)
-    };
-    // otherwise, use the code cache and dynarec
-    #[cfg(feature = "jit")]
-    let result = {
-      let ip = self.registers.ip as usize;
-      // Since RAM is invalidated by writes, it's messy to compile and track
-      // code found in RAM. Only ROM code should be recompiled, the rest
-      // should be interpreted.
-      if can_dynarec(ip) {
-        let address = {
-          let found_address = self.cache.get_address_for_ip(ip);
-          if let Some(addr) = found_address {
-            addr
-          } else {
-            self.cache.translate_code_block(&self.memory.rom, ip, self.memory.as_ptr())
-          }
-        };
-        self.cache.call(address, &mut self.registers)
-      } else {
+    // EI takes effect after the instruction following it, which is run on its
+    // own so that a pending interrupt is dispatched right after it
+    if self.interrupts_enabled == InterruptState::EnableNext {
+      self.run_interp();
+      return;
+    }
+    let started = self.perf_counters.map(|_| Instant::now());
+    let result = match self.use_jit {
+      #[cfg(jit_backend)]
+      true => self.run_compiled_block(),
+      // if running in interpreted mode, disable any dynamic compilation
+      _ => {
+        self.record_block(Engine::Interpreted);
+        self.trace(Engine::Interpreted);
         let mem_ptr = &mut self.memory as *mut MemoryAreas;
         interpreter::run_code_block(&mut self.registers, mem_ptr)
-      }
+      },
     };
 
     // for all modes, update the processor state and "catch up" all peripherals
     match result {
       cpu::STATUS_STOP => {
-        self.run_state = RunState::Stop;
-        //println!("STOP");
+        self.enter_stop();
       },
       cpu::STATUS_HALT => {
         self.run_state = RunState::Halt;
         //println!("HALT");
       },
+      cpu::STATUS_LOCKED => {
+        self.run_state = RunState::Locked;
+      },
       cpu::STATUS_INTERRUPT_DISABLE => {
         self.interrupts_enabled = InterruptState::Disabled;
         //println!("DISABLE INT");
       },
-      cpu::STATUS_INTERRUPT_ENABLE
-        | cpu::STATUS_INTERRUPT_ENABLE_IMMEDIATE => {
+      cpu::STATUS_INTERRUPT_ENABLE => {
+        // EI always ends a block, so the delay is handled when the next one
+        // starts
+        if let InterruptState::Disabled = self.interrupts_enabled {
+          self.interrupts_enabled = InterruptState::EnableNext;
+        }
+      },
+      cpu::STATUS_INTERRUPT_ENABLE_IMMEDIATE => {
+        // RETI has no delay, a pending interrupt is dispatched right away
         self.interrupts_enabled = InterruptState::Enabled;
-        //println!("ENABLE INT");
       },
       _ => (),
     }
-    let cycles_consumed = MachineCycles(self.registers.get_consumed_cycles());
+    let stalled = self.memory.take_stalled_cycles().as_usize();
+    let cycles_consumed = MachineCycles(self.registers.get_consumed_cycles() + stalled);
     self.last_block_cycle_length = cycles_consumed.as_usize();
+    let cpu_done = started.map(|_| Instant::now());
     // catch up memmapped devices
-    self.memory.run_clock_cycles(cycles_consumed.to_clock_cycles());
+    self.run_peripherals(cycles_consumed.to_clock_cycles());
+    if let (Some(counters), Some(started), Some(cpu_done)) = (self.perf_counters.as_mut(), started, cpu_done) {
+      counters.cpu += cpu_done - started;
+      counters.ppu += cpu_done.elapsed();
+    }
     self.handle_interrupt();
   }
 
+  /// Run the next block with the code cache and dynarec
+  #[cfg(jit_backend)]
+  fn run_compiled_block(&mut self) -> u8 {
+    let ip = self.registers.ip as usize;
+    // Code in RAM can be rewritten at any time, so it is only compiled where
+    // writes are tracked: always in high RAM, and in work RAM and enabled
+    // cartridge RAM when turned on. Everywhere else, it is interpreted. The
+    // boot ROM only runs once, so it is always interpreted, which keeps it
+    // out of the cache.
+    let in_ram = can_dynarec_hram(ip) || (
+      self.memory.dirty_ram.enabled
+        && (can_dynarec_wram(ip) || can_dynarec_cart_ram(ip, &self.memory))
+    );
+    let compiled = if (can_dynarec(ip) || in_ram) && !self.memory.is_boot_rom_mapped() {
+      if in_ram {
+        self.invalidate_written_ram();
+      }
+      self.cache.publish_finished();
+      self.cache.set_rom_bank(self.memory.get_rom_bank());
+      self.cache.set_low_rom_bank(self.memory.get_low_rom_bank());
+      self.cache.set_cart_ram_bank(self.memory.cart_state.get_ram_bank());
+      self.cache.set_wram_bank(self.memory.wram_bank);
+      match self.cache.lookup_block(ip) {
+        Some(addr) => Some(addr),
+        // blocks are interpreted until they have run often enough to be
+        // worth compiling, and while the worker is compiling them. A block
+        // that can't be compiled is always interpreted.
+        None if self.cache.count_visit(ip) => self.cache.request_block(ip, self.memory.as_ptr()),
+        None => None,
+      }
+    } else {
+      None
+    };
+    if let Some(address) = compiled {
+      self.record_block(Engine::Compiled);
+      self.trace(Engine::Compiled);
+      // compiled code doesn't track the IP of each instruction, so strict
+      // IO accesses and frozen writes are attributed to the start of the block
+      #[cfg(feature = "strict_io")]
+      self.memory.strict_io.set_ip(ip as u16);
+      #[cfg(feature = "debug_freeze")]
+      self.memory.freeze.set_ip(ip as u16);
+      let status = if self.verify_jit {
+        crate::debug::verify::run_verified_block(self, address)
+      } else {
+        // A linked block could contain a breakpoint, so with any set, only a
+        // single block runs at a time. Tracing and profiling blocks also need
+        // to see the start of every one.
+        let budget = if !self.has_breakpoints() && self.tracer.is_none() && !self.cache.is_profiling() {
+          self.get_cycle_budget()
+        } else {
+          MachineCycles(0)
+        };
+        self.cache.call(address, &mut self.registers, &mut self.memory, budget)
+      };
+      if self.cache.is_profiling() {
+        self.cache.record_block_run(ip, self.registers.cycles as usize);
+      }
+      status
+    } else {
+      self.check_jit_memory();
+      self.record_block(Engine::Interpreted);
+      self.trace(Engine::Interpreted);
+      let mem_ptr = &mut self.memory as *mut MemoryAreas;
+      interpreter::run_code_block(&mut self.registers, mem_ptr)
+    }
+  }
+
+  /// Discard compiled blocks whose code in RAM has been written since the
+  /// last check. A block that rewrites its own later instructions still runs
+  /// to the end as it was compiled; the change is seen the next time it runs.
+  #[cfg(jit_backend)]
+  fn invalidate_written_ram(&mut self) {
+    let dirty = &mut self.memory.dirty_ram;
+    self.cache.invalidate_dirty_cart_ram(&dirty.cart_ram);
+    self.cache.invalidate_dirty_wram(&dirty.work_ram);
+    self.cache.invalidate_dirty_hram(&dirty.high_ram);
+    dirty.clear();
+  }
+
   pub fn run_interp(&mut self) {
     // TODO: check if the current instruction starts a compiled block,
     // and run that instead
 
+    // An idle loop can only start where a block does, so the detector doesn't
+    // need to run on every instruction. A trace should include every
+    // iteration of an idle loop.
+    if self.interp_block_start && self.skip_idle_loops && self.tracer.is_none() {
+      self.skip_idle_loop();
+    }
+
+    if self.interp_block_start {
+      self.record_block(Engine::Interpreted);
+    }
+    self.trace(Engine::Interpreted);
+
     let result = {
       let mem_ptr = &mut self.memory as *mut MemoryAreas;
       match interpreter::run_next_op(&mut self.registers, mem_ptr) {
         Some((status, is_block_end)) => {
-          if is_block_end {
-            // mark this block as visited, keep a hit count
-          }
+          self.interp_block_start = is_block_end;
           if let InterruptState::EnableNext = self.interrupts_enabled {
             self.interrupts_enabled = InterruptState::Enabled;
           }
           status
         },
+        // nothing past the end of the code can run, so the CPU stops there
         None => {
-          panic!("Reached the end of an executable section");
+          self.fault = Some(MemError::NotExecutable(self.registers.ip as u16));
+          cpu::STATUS_LOCKED
         },
       }
     };
 
     match result {
       cpu::STATUS_STOP => {
-        self.run_state = RunState::Stop;
+        self.enter_stop();
       },
       cpu::STATUS_HALT => {
         self.run_state = RunState::Halt;
       },
+      cpu::STATUS_LOCKED => {
+        self.run_state = RunState::Locked;
+      },
       cpu::STATUS_INTERRUPT_DISABLE => {
         self.interrupts_enabled = InterruptState::Disabled;
       },
@@ -227,8 +1032,63 @@ impl Core {
       },
       _ => (),
     }
-    let cycles_consumed = MachineCycles(self.registers.get_consumed_cycles());
-    self.memory.run_clock_cycles(cycles_consumed.to_clock_cycles());
+    let stalled = self.memory.take_stalled_cycles().as_usize();
+    let cycles_consumed = MachineCycles(self.registers.get_consumed_cycles() + stalled);
+    self.run_peripherals(cycles_consumed.to_clock_cycles());
+    self.handle_interrupt();
+  }
+
+  /// If the CPU is sitting in an idle loop, run the peripherals forward one
+  /// loop iteration at a time until the polled value changes or an interrupt
+  /// is raised, without interpreting the loop body. The CPU is left at the
+  /// start of the loop (or at an interrupt vector), so the next iteration is
+  /// interpreted normally and observes whatever changed.
+  fn skip_idle_loop(&mut self) {
+    let ip = self.registers.ip as u16;
+    let idle_loop = match idle::detect_idle_loop(ip, self.memory.as_ptr()) {
+      Some(idle_loop) => idle_loop,
+      None => return,
+    };
+    let polled_address = match idle_loop {
+      IdleLoop::Poll { address, .. } => Some(address),
+      IdleLoop::Spin { .. } => None,
+    };
+    let mem_ptr = &mut self.memory as *mut MemoryAreas;
+    // The loop body has no side effects, so it can be test-run against a copy
+    // of the registers to see whether the current value keeps it looping
+    let mut trial_registers = self.registers;
+    for _ in 0..idle::MAX_LOOP_OPS {
+      interpreter::run_next_op(&mut trial_registers, mem_ptr);
+      if trial_registers.ip as u16 == ip {
+        break;
+      }
+    }
+    if trial_registers.ip as u16 != ip {
+      return;
+    }
+    let initial_value = polled_address.map(|address| memory_read_byte(mem_ptr, address));
+    let iteration_cycles = MachineCycles(idle_loop.get_cycles()).to_clock_cycles();
+    // frames are counted by watching for vblank, which can't be skipped over
+    // even if its interrupt is disabled
+    let in_vblank = self.memory.io.video.get_current_mode() == 1;
+    let frame = self.memory.io.video.get_frame_count();
+
+    for _ in 0..MAX_IDLE_ITERATIONS {
+      self.run_peripherals(iteration_cycles);
+      if self.memory.io.get_active_interrupts() != 0 {
+        break;
+      }
+      if (self.memory.io.video.get_current_mode() == 1) != in_vblank {
+        break;
+      }
+      if self.memory.io.video.get_frame_count() != frame {
+        break;
+      }
+      let current_value = polled_address.map(|address| memory_read_byte(mem_ptr, address));
+      if current_value != initial_value {
+        break;
+      }
+    }
     self.handle_interrupt();
   }
 
@@ -240,45 +1100,506 @@ impl Core {
   /// recompilation.
   /// If the current instruction is the start of a compiled block, the emulator
   /// will run the full block and then catch-up the peripherals.
-  pub fn update(&mut self) {
+  /// Returns an event if a breakpoint or watchpoint stopped execution. When
+  /// stopped at a breakpoint, the next update runs the instruction there.
+  pub fn update(&mut self) -> Option<BreakEvent> {
+    let ip = self.registers.ip as u16;
+    // LD SP, nn and LD SP, HL set the stack pointer without pushing
+    let loads_sp = self.stack_monitor.is_some()
+      && self.run_state == RunState::Run
+      && matches!(memory_peek_byte(&self.memory, ip), 0x31 | 0xf9);
     match self.run_state {
       RunState::Run => {
-        #[cfg(not(feature = "jit"))]
-        {
-          self.run_interp();
+        if self.resume_ip.take() != Some(ip) {
+          if let Some(event) = self.check_breakpoint() {
+            self.resume_ip = Some(ip);
+            return Some(event);
+          }
         }
-
-        #[cfg(feature = "jit")]
-        {
+        if self.use_jit && !self.needs_interpreter() {
           self.run_code_block();
+        } else {
+          self.run_interp();
         }
       },
-      _ => {
-        // while CPU is blocked, update the peripherals one cycle at a time
-        self.memory.run_clock_cycles(ClockCycles(4));
-        self.handle_interrupt();
-      },
+      // interrupt dispatch can also write to watched stack addresses
+      _ => self.run_halted(),
     }
+    self.check_stack(ip, loads_sp);
+    self.run_memory_hooks();
+    if let Some(error) = self.fault.take() {
+      return Some(BreakEvent::Fault(error));
+    }
+    self.take_watch_event(ip)
   }
 
-  pub fn get_screen_buffer(&self) -> &Box<[u8]> {
-    self.memory.io.video.get_visible_buffer()
+  /// Report the stack pointer's new position to the stack monitor, if there
+  /// is one, and pass on any warning it raises
+  fn check_stack(&mut self, ip: u16, loads_sp: bool) {
+    let sp = self.registers.sp as u16;
+    if let Some(monitor) = self.stack_monitor.as_mut() {
+      if let Some(warning) = monitor.check(ip, sp, loads_sp) {
+        self.messages.push(warning.to_string());
+      }
+    }
   }
 
-  pub fn run_frame(&mut self) {
-    while self.memory.io.video.get_current_mode() != 1 {
-      self.update();
+  /// Every message raised since the last call, oldest first. Frontends
+  /// should take them regularly, such as once per frame, and show them
+  /// however suits them.
+  pub fn take_messages(&mut self) -> Vec<String> {
+    core::mem::take(&mut self.messages)
+  }
+
+  /// While CPU is blocked, update the peripherals one cycle at a time
+  fn run_halted(&mut self) {
+    self.run_peripherals(ClockCycles(4));
+    if self.run_state == RunState::Stop {
+      // STOP ends when a selected button is pressed, even if the joypad
+      // interrupt isn't enabled
+      if self.memory.io.joypad.read(0x00) & 0x0f == 0x0f {
+        return;
+      }
+      self.run_state = RunState::Run;
     }
-    while self.memory.io.video.get_current_mode() == 1 {
+    self.handle_interrupt();
+  }
+
+  /// STOP also resets the divider
+  fn enter_stop(&mut self) {
+    self.run_state = RunState::Stop;
+    self.memory.io.set_byte(0xff04, 0);
+  }
+
+  /// Run a blargg or mooneye-gb test ROM until it reports a result, or until
+  /// `timeout_seconds` of emulated time have passed
+  pub fn run_test_rom(&mut self, timeout_seconds: u64) -> TestResult {
+    testrom::run_test_rom(self, timeout_seconds)
+  }
+
+  /// Run until at least `cycle` machine cycles have elapsed since the core
+  /// was created. Breakpoints and watchpoints are skipped over.
+  pub fn run_until_cycle(&mut self, cycle: u64) {
+    while self.cycles_elapsed < cycle {
       self.update();
     }
   }
-}
 
-#[cfg(test)]
-mod tests {
-  use super::{Core, InterruptState, RunState};
+  /// Run until a breakpoint or watchpoint is hit
+  pub fn run_until_break(&mut self) -> BreakEvent {
+    loop {
+      if let Some(event) = self.update() {
+        return event;
+      }
+    }
+  }
+
+  pub fn get_screen_buffer(&self) -> &Box<[u8]> {
+    self.memory.io.video.get_visible_buffer()
+  }
+
+  /// The last completed frame as RGBA8 pixels, one row after another
+  pub fn screenshot(&self) -> Vec<u8> {
+    self.memory.io.video.get_palette().to_rgba(self.get_screen_buffer())
+  }
+
+  /// Run until the end of the next frame, or until a breakpoint or
+  /// watchpoint is hit. After a break, the rest of the frame runs on the
+  /// next call.
+  pub fn run_frame(&mut self) -> Option<BreakEvent> {
+    if self.paused {
+      return None;
+    }
+    if let Some(mut session) = self.netplay.take() {
+      match session.run_frame(self) {
+        Ok(()) => self.netplay = Some(session),
+        Err(msg) => self.messages.push(format!("{}, continuing without netplay", msg)),
+      }
+      self.apply_ram_cheats();
+      self.run_frame_hooks();
+      self.record_frame();
+      self.hash_frame();
+      return None;
+    }
+    if !self.frame_in_progress {
+      self.input.commit_frame(self.memory.io.joypad_port());
+      self.frame_in_progress = true;
+    }
+    // A frame ends with vblank, or after a frame's worth of cycles if the LCD
+    // is off
+    let frame = self.memory.io.video.get_frame_count();
+    while self.memory.io.video.get_frame_count() == frame {
+      if let Some(event) = self.update() {
+        return Some(event);
+      }
+    }
+    self.frame_in_progress = false;
+    self.apply_ram_cheats();
+    self.run_frame_hooks();
+    self.record_frame();
+    self.hash_frame();
+    self.capture_rewind_state();
+    self.collect_link_reports();
+    None
+  }
+
+  pub fn is_paused(&self) -> bool {
+    self.paused
+  }
+
+  /// Stop running frames, or start again. While paused, `run_frame` and
+  /// `skip_frame` return right away, so a shell can keep drawing the last
+  /// frame, and `advance_frame` steps through them one at a time. The other
+  /// side of a netplay session can't be kept waiting, so it can't be paused.
+  pub fn set_paused(&mut self, paused: bool) -> Result<(), String> {
+    if paused && self.netplay.is_some() {
+      return Err(String::from("Pause is not available during netplay"));
+    }
+    self.paused = paused;
+    Ok(())
+  }
+
+  /// Run a single frame, even while paused
+  pub fn advance_frame(&mut self) -> Option<BreakEvent> {
+    let paused = core::mem::replace(&mut self.paused, false);
+    let event = self.run_frame();
+    self.paused = paused;
+    event
+  }
+
+  /// Run a frame without drawing it, for fast-forwarding. The screen keeps
+  /// showing the last frame that was drawn.
+  pub fn skip_frame(&mut self) -> Option<BreakEvent> {
+    self.memory.io.video.set_skip_rendering(true);
+    let event = self.run_frame();
+    self.memory.io.video.set_skip_rendering(false);
+    event
+  }
+
+  fn record_frame(&mut self) {
+    if self.recorder.is_none() {
+      return;
+    }
+    let frame = self.screenshot();
+    if let Some(recorder) = self.recorder.as_mut() {
+      if let Err(msg) = recorder.record(&frame) {
+        self.messages.push(format!("{}, stopping the recording", msg));
+        self.stop_recording();
+      }
+    }
+  }
+
+  /// Finish the recording in progress, if there is one
+  pub fn stop_recording(&mut self) {
+    if let Some(recorder) = self.recorder.take() {
+      let message = match recorder.finish() {
+        Ok(summary) => summary,
+        Err(msg) => msg,
+      };
+      self.messages.push(message);
+    }
+  }
+
+  /// Pass on anything the link cable peer has reported, like a replayed
+  /// session diverging
+  fn collect_link_reports(&mut self) {
+    if let Some(serial) = self.memory.io.serial_port() {
+      while let Some(report) = serial.take_peer_report() {
+        self.messages.push(report);
+      }
+    }
+  }
+
+  fn hash_frame(&mut self) {
+    let hasher = match self.frame_hasher.as_mut() {
+      Some(hasher) => hasher,
+      None => return,
+    };
+    // there is no audio device yet, so no samples go along with the frame
+    if let Err(msg) = hasher.hash_frame(self.memory.io.video.get_visible_buffer(), &[]) {
+      self.messages.push(format!("{}, stopping frame hashing", msg));
+      self.stop_frame_hashing();
+    }
+  }
+
+  /// Finish logging or comparing frame hashes, if either is in progress
+  pub fn stop_frame_hashing(&mut self) {
+    if let Some(hasher) = self.frame_hasher.take() {
+      let message = match hasher.finish() {
+        Ok(summary) => summary,
+        Err(msg) => msg,
+      };
+      self.messages.push(message);
+    }
+  }
+
+  fn capture_rewind_state(&mut self) {
+    let due = match self.rewind.as_mut() {
+      Some(rewind) => rewind.frame_completed(),
+      None => false,
+    };
+    if due {
+      let state = self.save_state();
+      if let Some(rewind) = self.rewind.as_mut() {
+        rewind.push(state);
+      }
+    }
+  }
+
+  /// Go back about `frames` frames, to the nearest state in the rewind
+  /// buffer. Returns the number of frames actually rewound.
+  pub fn rewind(&mut self, frames: u32) -> Result<u32, String> {
+    if self.netplay.is_some() {
+      return Err(String::from("Rewind is not available during netplay"));
+    }
+    let mut rewind = self.rewind.take().ok_or_else(|| String::from("Rewind is not enabled"))?;
+    let result = match rewind.step_back(frames) {
+      Some((state, rewound)) => self.load_state(state).map(|_| rewound),
+      None => Err(String::from("Nothing to rewind to yet")),
+    };
+    self.rewind = Some(rewind);
+    result
+  }
+
+  /// Identifies the ROM a state was saved from, without hashing all of it
+  fn rom_id(&self) -> u32 {
+    crc32(&self.memory.rom[0x100..0x150])
+  }
+
+  /// Capture the whole machine in a save state. The ROM isn't included, so
+  /// the state can only be loaded into a core running the same game.
+  pub fn save_state(&self) -> Vec<u8> {
+    let mut state = StateWriter::new();
+    for byte in STATE_MAGIC.iter() {
+      state.u8(*byte);
+    }
+    state.u8(STATE_VERSION);
+    state.u32(self.rom_id());
 
+    let registers = &self.registers;
+    for value in [registers.af, registers.bc, registers.de, registers.hl, registers.sp, registers.ip, registers.cycles].iter() {
+      state.u32(*value);
+    }
+    state.u8(match self.interrupts_enabled {
+      InterruptState::Disabled => 0,
+      InterruptState::Enabled => 1,
+      InterruptState::EnableNext => 2,
+    });
+    state.u8(match self.run_state {
+      RunState::Run => 0,
+      RunState::Stop => 1,
+      RunState::Halt => 2,
+      RunState::Locked => 3,
+    });
+    state.u64(self.cycles_elapsed);
+    state.bool(self.frame_in_progress);
+    self.memory.save_state(&mut state);
+    state.into_bytes()
+  }
+
+  /// Restore a state made by `save_state`. The header is checked before
+  /// anything is changed, but if the rest of the state is malformed the core
+  /// may be left partially restored.
+  pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+    let mut state = StateReader::new(data);
+    let mut magic = [0; 4];
+    for byte in magic.iter_mut() {
+      *byte = state.u8()?;
+    }
+    if magic != STATE_MAGIC {
+      return Err(String::from("Not a save state"));
+    }
+    if state.u8()? != STATE_VERSION {
+      return Err(String::from("Save state was made by a different version"));
+    }
+    if state.u32()? != self.rom_id() {
+      return Err(String::from("Save state is for a different game"));
+    }
+
+    self.registers.af = state.u32()?;
+    self.registers.bc = state.u32()?;
+    self.registers.de = state.u32()?;
+    self.registers.hl = state.u32()?;
+    self.registers.sp = state.u32()?;
+    self.registers.ip = state.u32()?;
+    self.registers.cycles = state.u32()?;
+    self.interrupts_enabled = match state.u8()? {
+      1 => InterruptState::Enabled,
+      2 => InterruptState::EnableNext,
+      _ => InterruptState::Disabled,
+    };
+    self.run_state = match state.u8()? {
+      1 => RunState::Stop,
+      2 => RunState::Halt,
+      3 => RunState::Locked,
+      _ => RunState::Run,
+    };
+    self.cycles_elapsed = state.u64()?;
+    self.frame_in_progress = state.bool()?;
+    self.memory.load_state(&mut state)?;
+    if !state.is_finished() {
+      return Err(String::from("Save state has unexpected data at the end"));
+    }
+
+    self.interp_block_start = true;
+    self.resume_ip = None;
+    self.step_overshoot = ClockCycles(0);
+    Ok(())
+  }
+
+  /// The boot ROM the core started with, if any
+  pub fn boot_rom(&self) -> Option<&[u8]> {
+    self.boot_rom.as_deref()
+  }
+
+  /// Restart the game as if the power had been cycled. The ROM, cartridge
+  /// RAM, and the emulator's own settings are kept, and the boot ROM runs
+  /// again if the core started with one.
+  pub fn reset(&mut self) -> Result<(), String> {
+    if self.netplay.is_some() {
+      return Err(String::from("Reset is not available during netplay"));
+    }
+    self.memory.reset(self.boot_rom.clone());
+    self.registers = self.start_registers;
+    #[cfg(jit_backend)]
+    self.cache.flush();
+    self.last_block_cycle_length = 0;
+    self.interrupts_enabled = InterruptState::Disabled;
+    self.run_state = RunState::Run;
+    self.interp_block_start = true;
+    self.resume_ip = None;
+    self.frame_in_progress = false;
+    self.step_overshoot = ClockCycles(0);
+    if self.stack_monitor.is_some() {
+      self.stack_monitor = Some(StackMonitor::new(self.registers.sp as u16));
+    }
+    Ok(())
+  }
+
+  /// Swap in a core running another game, like one opened while the
+  /// emulator is running. The current game is shut down, and the new one
+  /// takes over its settings, input, and code cache.
+  pub fn replace_game(&mut self, mut next: Core) -> Result<(), String> {
+    if self.netplay.is_some() {
+      return Err(String::from("Another game can't be loaded during netplay"));
+    }
+    let tracking_play_time = self.play_session.is_some();
+    self.shutdown();
+
+    #[cfg(jit_backend)]
+    {
+      core::mem::swap(&mut next.cache, &mut self.cache);
+      next.cache.flush();
+      next.cache.clear_visits();
+      // the old game's report was queued when it shut down
+      if let Some(profile) = next.cache.get_profile_mut() {
+        profile.clear();
+      }
+    }
+    next.use_jit = self.use_jit;
+    next.verify_jit = self.verify_jit;
+    next.set_ram_compilation(self.is_ram_compilation_enabled());
+    next.skip_idle_loops = self.skip_idle_loops;
+    next.jit_cycle_budget = self.jit_cycle_budget;
+    next.software_breakpoints = self.software_breakpoints;
+    next.input = core::mem::take(&mut self.input);
+    // anything the old game raised, including its shutdown reports, is still
+    // waiting to be taken
+    next.messages = core::mem::take(&mut self.messages);
+    next.tracer = self.tracer.take();
+    next.perf_counters = self.perf_counters.take();
+    next.memory.io.video.set_palette(*self.memory.io.video.get_palette());
+    if self.stack_monitor.is_some() {
+      next.stack_monitor = Some(StackMonitor::new(next.registers.sp as u16));
+    }
+    if self.rewind.is_some() {
+      next.rewind = Some(RewindBuffer::default());
+    }
+    if tracking_play_time {
+      next.play_session = Some(PlaySession::start(&next.memory.rom, next.cycles_elapsed()));
+    }
+    *self = next;
+    Ok(())
+  }
+
+  /// Write out everything that is saved when the emulator exits. Shells
+  /// should call this before ending the process, since it may not return
+  /// through the core's destructor, and then show any messages it leaves.
+  pub fn shutdown(&mut self) {
+    if let Some(tracer) = self.tracer.as_mut() {
+      tracer.flush();
+    }
+    self.stop_recording();
+    self.stop_frame_hashing();
+    #[cfg(jit_backend)]
+    if self.cache.is_profiling() {
+      let report = crate::debug::profile::profile_report(self, crate::debug::profile::DEFAULT_REPORT_BLOCKS);
+      self.messages.push(report.trim_end().to_string());
+    }
+    if let Some(session) = self.play_session.take() {
+      if let Err(msg) = session.finish(self.cycles_elapsed) {
+        self.messages.push(format!("Unable to save play statistics: {}", msg));
+      }
+    }
+  }
+}
+
+fn check_header(header: &Header) -> Result<(), Error> {
+  if !header.valid_checksum() {
+    return Err(Error::Rom(RomError::InvalidChecksum));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{BreakEvent, Core, InterruptState, RunState};
+  use crate::error::{Error, RomError};
+  use crate::debug::breakpoint::Breakpoint;
+  use crate::debug::history::Engine;
+  use crate::debug::stack::{StackMonitor, StackProblem};
+  use crate::debug::trace::Tracer;
+  use crate::rewind::RewindBuffer;
+  use crate::debug::watchpoint::{Access, WatchHit, WatchKind, Watchpoint};
+  use crate::mem::{memory_peek_byte, memory_read_byte, memory_write_byte};
+  use crate::devices::interrupts::InterruptFlag;
+  use crate::devices::joypad::Button;
+  use crate::timing::{ClockCycles, MachineCycles, FRAME_CYCLES};
+  use std::cell::Cell;
+
+  thread_local! {
+    /// The engine that cores created by the current test start on, if the
+    /// test chooses one
+    pub(super) static ENGINE: Cell<Option<bool>> = const { Cell::new(None) };
+  }
+
+  /// Run each test on the interpreter, and again on compiled code from the
+  /// host's backend, whichever engine the build would otherwise use. Each
+  /// test becomes a module with a test for each engine.
+  macro_rules! engine_tests {
+    ($(#[test] fn $name:ident() $body:block)*) => {
+      $(
+        mod $name {
+          use super::*;
+
+          #[test]
+          fn interpreter() {
+            ENGINE.with(|engine| engine.set(Some(false)));
+            $body
+          }
+
+          #[cfg(jit_backend)]
+          #[test]
+          fn jit() {
+            ENGINE.with(|engine| engine.set(Some(true)));
+            $body
+          }
+        }
+      )*
+    };
+  }
+
+  engine_tests! {
   #[test]
   fn load_8_bit_absolute() {
     let code = vec![
@@ -1570,93 +2891,822 @@ mod tests {
     ];
     let mut core = Core::with_code_block(code.into_boxed_slice());
     core.run_code_block();
-    assert_eq!(core.registers.get_bc(), 0x3900);
-    assert_eq!(core.registers.get_af(), 0x0010);
-    core.run_code_block();
-    assert_eq!(core.registers.get_bc(), 0x3978);
-    assert_eq!(core.registers.get_af(), 0x0000);
-    core.run_code_block();
-    assert_eq!(core.registers.get_de(), 0x0000);
-    assert_eq!(core.registers.get_af(), 0x0090);
-    core.run_code_block();
-    assert_eq!(core.registers.get_de(), 0x0000);
-    assert_eq!(core.registers.get_af(), 0x0080);
-    core.run_code_block();
-    assert_eq!(core.registers.get_hl(), 0x7700);
-    assert_eq!(core.registers.get_af(), 0x0000);
-    core.run_code_block();
-    assert_eq!(core.registers.get_hl(), 0x774c);
-    assert_eq!(core.registers.get_af(), 0x0010);
-    core.run_code_block();
-    assert_eq!(core.registers.get_af(), 0x0f00);
-    core.run_code_block();
-    assert_eq!(core.memory.work_ram[0x20], 0x62);
-    assert_eq!(core.registers.get_af(), 0x0f00);
+    assert_eq!(core.registers.get_bc(), 0x3900);
+    assert_eq!(core.registers.get_af(), 0x0010);
+    core.run_code_block();
+    assert_eq!(core.registers.get_bc(), 0x3978);
+    assert_eq!(core.registers.get_af(), 0x0000);
+    core.run_code_block();
+    assert_eq!(core.registers.get_de(), 0x0000);
+    assert_eq!(core.registers.get_af(), 0x0090);
+    core.run_code_block();
+    assert_eq!(core.registers.get_de(), 0x0000);
+    assert_eq!(core.registers.get_af(), 0x0080);
+    core.run_code_block();
+    assert_eq!(core.registers.get_hl(), 0x7700);
+    assert_eq!(core.registers.get_af(), 0x0000);
+    core.run_code_block();
+    assert_eq!(core.registers.get_hl(), 0x774c);
+    assert_eq!(core.registers.get_af(), 0x0010);
+    core.run_code_block();
+    assert_eq!(core.registers.get_af(), 0x0f00);
+    core.run_code_block();
+    assert_eq!(core.memory.work_ram[0x20], 0x62);
+    assert_eq!(core.registers.get_af(), 0x0f00);
+  }
+
+  #[test]
+  fn swap() {
+    let code = vec![
+      0x06, 0xd7, // LD B, 0xd7
+      0xcb, 0x30, // SWAP B
+      0xc3, 0x07, 0x00, // JP 0x0007
+      0x0e, 0x00, // LD C, 0x00
+      0xcb, 0x31, // SWAP C
+      0xc3, 0x0e, 0x00, // JP 0x000e
+      0x16, 0xff, // LD D, 0xff
+      0xcb, 0x32, // SWAP D
+      0xc3, 0x15, 0x00, // JP 0x0015
+      0x1e, 0x38, // LD E, 0x38
+      0xcb, 0x33, // SWAP E
+      0xc3, 0x1c, 0x00, // JP 0x001c
+      0x26, 0x40, // LD H, 0x40
+      0xcb, 0x34, // SWAP H
+      0xc3, 0x23, 0x00, // JP 0x0023
+      0x2e, 0x1f, // LD L, 0x1f
+      0xcb, 0x35, // SWAP L
+      0xc3, 0x2a, 0x00, // JP 0x002a
+      0x3e, 0x36, // LD A, 0x36
+      0xcb, 0x37, // SWAP A
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.registers.af = 0x00f0;
+    core.run_code_block();
+    assert_eq!(core.registers.get_bc(), 0x7d00);
+    assert_eq!(core.registers.get_af(), 0x0000);
+    core.run_code_block();
+    assert_eq!(core.registers.get_bc(), 0x7d00);
+    assert_eq!(core.registers.get_af(), 0x0080);
+    core.run_code_block();
+    assert_eq!(core.registers.get_de(), 0xff00);
+    assert_eq!(core.registers.get_af(), 0x0000);
+    core.run_code_block();
+    assert_eq!(core.registers.get_de(), 0xff83);
+    assert_eq!(core.registers.get_af(), 0x0000);
+    core.run_code_block();
+    assert_eq!(core.registers.get_hl(), 0x0400);
+    assert_eq!(core.registers.get_af(), 0x0000);
+    core.run_code_block();
+    assert_eq!(core.registers.get_hl(), 0x04f1);
+    assert_eq!(core.registers.get_af(), 0x0000);
+    core.run_code_block();
+    assert_eq!(core.registers.get_af(), 0x6300);
+  }
+
+  #[test]
+  fn bit_test() {
+    let code = vec![
+      0x06, 0x0f, // LD B, 0x0f
+      0xcb, 0x40, // BIT 0,B
+      0xc3, 0x07, 0x00, // JMP 0x0007
+      0x0e, 0xf8, // LD C, 0xf8
+      0xcb, 0x49, // BIT 1, C
+      0xc3, 0x0e, 0x00, // JMP 0x000e
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.run_code_block();
+    assert_eq!(core.registers.get_af(), 0x0020);
+    core.run_code_block();
+    assert_eq!(core.registers.get_af(), 0x00a0);
+  }
+
+  #[test]
+  fn bit_test_indirect_sequence() {
+    let code = vec![
+      0x21, 0x00, 0xc0, // LD HL, 0xc000
+      0x36, 0x81, // LD (HL), 0x81
+      0xcb, 0x46, // BIT 0,(HL)
+      0xcb, 0x4e, // BIT 1,(HL)
+      0xc3, 0x0c, 0x00, // JMP 0x000c
+      0xcb, 0x7e, // BIT 7,(HL)
+      0xcb, 0x46, // BIT 0,(HL)
+      0xc3, 0x13, 0x00, // JMP 0x0013
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.run_code_block();
+    assert_eq!(core.registers.get_af(), 0x00a0);
+    assert_eq!(core.registers.get_ip(), 0x000c);
+    assert_eq!(core.memory.work_ram[0], 0x81);
+    core.run_code_block();
+    assert_eq!(core.registers.get_af(), 0x0020);
+    assert_eq!(core.registers.get_ip(), 0x0013);
+  }
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn bit_test_sequence_after_write() {
+    let code = vec![
+      0x21, 0x00, 0xc0, // LD HL, 0xc000
+      0x36, 0x01, // LD (HL), 0x01
+      0xcb, 0x46, // BIT 0,(HL)
+      0xcb, 0x7e, // BIT 7,(HL)
+      0x36, 0x80, // LD (HL), 0x80
+      0xcb, 0x46, // BIT 0,(HL)
+      0xcb, 0x7e, // BIT 7,(HL)
+      0x76, // HALT
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.run_code_block();
+    assert!(core.cache.get_block(0).is_some());
+    // the second pair of tests reads the value written after the first pair,
+    // rather than the one the first pair read
+    assert_eq!(core.registers.get_af(), 0x0020);
+    assert_eq!(core.registers.get_ip(), 0x0010);
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn verify_jit_block() {
+    let code = vec![
+      0x21, 0x00, 0xc0, // LD HL, 0xc000
+      0x3e, 0x12, // LD A, 0x12
+      0x22, // LD (HL+), A
+      0x3c, // INC A
+      0x77, // LD (HL), A
+      0xc3, 0x09, 0x00, // JMP 0x0009
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.verify_jit = true;
+    core.run_code_block();
+    assert_eq!(core.registers.get_hl(), 0xc001);
+    assert_eq!(core.memory.work_ram[0], 0x12);
+    assert_eq!(core.memory.work_ram[1], 0x13);
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn lazy_flags() {
+    let code = vec![
+      0x3e, 0x0f, // LD A, 0x0f
+      0x06, 0x01, // LD B, 0x01
+      0x80, // ADD A, B
+      0x0c, // INC C
+      0x90, // SUB A, B
+      0x88, // ADC A, B
+      0x15, // DEC D
+      0xfe, 0x10, // CP 0x10
+      0x76, // HALT
+    ];
+    let mut lazy = Core::with_code_block(code.clone().into_boxed_slice());
+    lazy.verify_jit = true;
+    lazy.run_code_block();
+    let mut eager = Core::with_code_block(code.into_boxed_slice());
+    eager.cache.lazy_flags = false;
+    eager.run_code_block();
+
+    assert_eq!(lazy.registers.get_af(), eager.registers.get_af());
+    assert_eq!(lazy.registers.get_af(), 0x10c0);
+    assert_eq!(lazy.registers.get_bc(), 0x0101);
+    assert_eq!(lazy.registers.get_de(), 0xff00);
+    assert!(lazy.cache.get_stats().bytes_used < eager.cache.get_stats().bytes_used);
+  }
+
+  #[cfg(all(feature = "jit", feature = "optimizer", jit_backend))]
+  #[test]
+  fn optimized_block() {
+    let code = vec![
+      0x06, 0x12, // LD B, 0x12
+      0x0e, 0x34, // LD C, 0x34
+      0x50, // LD D, B
+      0x59, // LD E, C
+      0x3e, 0x01, // LD A, 1
+      0x3d, // DEC A
+      0x20, 0x00, // JR NZ, +0
+      0x76, // HALT
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.verify_jit = true;
+    core.run_code_block();
+    assert_eq!(core.registers.get_bc(), 0x1234);
+    assert_eq!(core.registers.get_de(), 0x1234);
+    assert_eq!({ core.registers.ip }, 0x0b);
+    assert_eq!(core.last_block_cycle_length, 4 + 2 + 2 + 1 + 2);
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn linked_blocks() {
+    let code = vec![
+      0x3e, 0x10, // LD A, 0x10
+      0xc3, 0x08, 0x00, // JP 0x0008
+      0x00, 0x00, 0x00,
+      0x3c, // INC A
+      0x18, 0x02, // JR +2
+      0x00, 0x00,
+      0x3c, // INC A
+      0x76, // HALT
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    // compile each block once
+    core.run_code_block();
+    core.run_code_block();
+    core.run_code_block();
+    assert_eq!(core.registers.get_a(), 0x12);
+    assert_eq!({ core.registers.ip }, 0x0f);
+
+    // running from the start now continues through every linked block
+    core.registers.ip = 0;
+    core.run_state = RunState::Run;
+    core.run_code_block();
+    assert_eq!(core.registers.get_a(), 0x12);
+    assert_eq!({ core.registers.ip }, 0x0f);
+    assert_eq!(core.last_block_cycle_length, 2 + 4 + 1 + 3 + 1 + 1);
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn hot_blocks_compiled() {
+    let code = vec![
+      0x04, // INC B
+      0x18, 0xfd, // JR -3
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.cache.compile_threshold = 2;
+    core.run_code_block();
+    core.run_code_block();
+    // interpreted blocks run one at a time
+    assert_eq!(core.registers.get_bc(), 0x0200);
+    assert!(core.cache.get_block(0).is_none());
+    core.run_code_block();
+    assert!(core.cache.get_block(0).is_some());
+    assert_eq!(core.cache.get_stats().translations, 1);
+    // a block that was hot before a flush is compiled as soon as it runs again
+    core.cache.flush();
+    core.run_code_block();
+    assert_eq!(core.cache.get_stats().translations, 2);
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn interpreter_without_executable_memory() {
+    let code = vec![
+      0x04, // INC B
+      0x18, 0xfd, // JR -3
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.run_code_block();
+    assert!(core.cache.get_block(0).is_some());
+    core.cache.discard_memory();
+    let bc = core.registers.get_bc();
+    core.run_code_block();
+    // interpreted blocks run one at a time
+    assert_eq!(core.registers.get_bc(), bc + 0x100);
+    assert!(core.cache.get_block(0).is_none());
+    assert!(!core.is_jit_enabled());
+    assert_eq!(core.take_messages(), vec!["Unable to set up executable memory for the JIT, falling back to the interpreter"]);
+    assert_eq!(core.set_jit_enabled(true), Err(Error::Jit(crate::error::JitError::NoExecutableMemory)));
+    assert!(!core.is_jit_enabled());
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn long_straight_line_block() {
+    // NOPs running for most of bank 0, then HALT
+    let mut code = vec![0x00; 0x3000];
+    code.push(0x76);
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.run_code_block();
+    assert_eq!(core.cache.get_block(0).unwrap().bytes_translated, 0x3001);
+    assert_eq!(core.registers.get_ip(), 0x3001);
+    assert_eq!(core.run_state, RunState::Halt);
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn background_compilation() {
+    let code = vec![
+      0x04, // INC B
+      0x18, 0xfd, // JR -3
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.cache.set_background_compilation(true);
+    // the block is interpreted while the worker compiles it
+    core.run_code_block();
+    assert_eq!(core.registers.get_bc(), 0x0100);
+    assert!(core.cache.get_block(0).is_none());
+    core.cache.wait_for_worker();
+    assert!(core.cache.get_block(0).is_some());
+    core.run_code_block();
+    assert_eq!(core.cache.get_stats().translations, 1);
+
+    // a block finished after a flush may be out of date, and is thrown away
+    core.cache.flush();
+    core.run_code_block();
+    core.cache.flush();
+    core.cache.wait_for_worker();
+    assert!(core.cache.get_block(0).is_none());
+    core.run_code_block();
+    core.cache.wait_for_worker();
+    assert!(core.cache.get_block(0).is_some());
+    assert_eq!(core.cache.get_stats().translations, 2);
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn jit_cycle_budget() {
+    let code = vec![
+      0x04, // INC B
+      0x18, 0xfd, // JR -3
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    // each iteration takes 4 cycles, and the loop exits once the budget is
+    // used up
+    core.jit_cycle_budget = MachineCycles(20);
+    core.run_code_block();
+    assert_eq!(core.last_block_cycle_length, 20);
+    assert_eq!(core.registers.get_bc(), 0x0500);
+    core.jit_cycle_budget = MachineCycles(22);
+    core.run_code_block();
+    assert_eq!(core.last_block_cycle_length, 24);
+    assert_eq!(core.registers.get_bc(), 0x0b00);
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn linked_chain_exits_on_interrupt() {
+    let code = vec![
+      0xe0, 0x0f, // LDH (0x0f), A
+      0x18, 0x00, // JR +0
+      0x18, 0xfe, // JR -2
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    // compile and link both blocks without raising an interrupt
+    core.run_code_block();
+    core.run_code_block();
+    assert_eq!(core.registers.get_ip(), 0x04);
+
+    core.registers.ip = 0;
+    core.registers.af = 0x0100;
+    core.memory.io.interrupt_mask = 0x01;
+    core.run_code_block();
+    // the write to IF raises a VBlank interrupt, so the chain exits at the
+    // first link instead of spinning until the budget is used up
+    assert_eq!(core.registers.get_ip(), 0x04);
+    assert_eq!(core.last_block_cycle_length, 6);
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn banked_blocks() {
+    use crate::cart::MBC1CartState;
+
+    let mut rom = vec![0xff; 0xc000];
+    let bank_0 = [
+      0xc3, 0x00, 0x40, // JP 0x4000
+    ];
+    let switch_bank = [
+      0x3e, 0x02, // LD A, 2
+      0xea, 0x00, 0x20, // LD (0x2000), A
+      0xc3, 0x00, 0x40, // JP 0x4000
+    ];
+    let bank_1 = [
+      0x06, 0x11, // LD B, 0x11
+      0xc3, 0x10, 0x00, // JP 0x0010
+    ];
+    let bank_2 = [
+      0x06, 0x22, // LD B, 0x22
+      0x76, // HALT
+    ];
+    rom[0..3].copy_from_slice(&bank_0);
+    rom[0x10..0x18].copy_from_slice(&switch_bank);
+    rom[0x4000..0x4005].copy_from_slice(&bank_1);
+    rom[0x8000..0x8003].copy_from_slice(&bank_2);
+
+    let mut core = Core::with_code_block(vec![].into_boxed_slice());
+    core.memory.rom = rom.into_boxed_slice();
+    core.memory.cart_state = Box::new(MBC1CartState::new());
+    for _ in 0..8 {
+      if core.run_state == RunState::Halt {
+        break;
+      }
+      core.run_code_block();
+    }
+    // the block at 0x4000 in bank 1 must not be reused for bank 2
+    assert_eq!(core.run_state, RunState::Halt);
+    assert_eq!(core.registers.get_bc() >> 8, 0x22);
+    assert_eq!(core.cache.get_stats().blocks, 4);
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn bank_switch_stubs() {
+    use crate::cart::MBC1CartState;
+
+    // the same stub sits at 0x4000 in banks 1 and 2, and switches to bank 2
+    // before jumping further into the switchable bank
+    let stub = [
+      0x3e, 0x02, // LD A, 2
+      0xea, 0x00, 0x20, // LD (0x2000), A
+      0xc3, 0x10, 0x40, // JP 0x4010
+    ];
+    let mut rom = vec![0xff; 0xc000];
+    rom[0x4000..0x4008].copy_from_slice(&stub);
+    rom[0x8000..0x8008].copy_from_slice(&stub);
+    rom[0x4010..0x4013].copy_from_slice(&[0x06, 0x11, 0x76]); // LD B, 0x11; HALT
+    rom[0x8010..0x8013].copy_from_slice(&[0x06, 0x22, 0x76]); // LD B, 0x22; HALT
+
+    let mut core = Core::with_code_block(vec![].into_boxed_slice());
+    core.memory.rom = rom.into_boxed_slice();
+    core.memory.cart_state = Box::new(MBC1CartState::new());
+    // compile the block at 0x4010 in bank 1 first
+    core.registers.ip = 0x4010;
+    core.run_code_block();
+    assert_eq!(core.registers.get_bc() >> 8, 0x11);
+
+    core.registers.ip = 0x4000;
+    core.run_state = RunState::Run;
+    for _ in 0..4 {
+      if core.run_state == RunState::Halt {
+        break;
+      }
+      core.run_code_block();
+    }
+    // the stub must not be linked to the block in the bank it switched away
+    // from
+    assert_eq!(core.run_state, RunState::Halt);
+    assert_eq!(core.registers.get_bc() >> 8, 0x22);
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn unlink_banked_blocks() {
+    use crate::cart::MBC1CartState;
+
+    // banks 1 and 2 both have a block at 0x4000 linked to one at 0x4010
+    let mut rom = vec![0xff; 0xc000];
+    for (bank, base) in [(0x11, 0x4000), (0x22, 0x8000)] {
+      rom[base..(base + 5)].copy_from_slice(&[0x06, bank, 0xc3, 0x10, 0x40]); // LD B, n; JP 0x4010
+      rom[(base + 0x10)..(base + 0x13)].copy_from_slice(&[0x0e, bank, 0x76]); // LD C, n; HALT
+    }
+    let mut core = Core::with_code_block(vec![].into_boxed_slice());
+    core.memory.rom = rom.into_boxed_slice();
+    core.memory.cart_state = Box::new(MBC1CartState::new());
+    let run_from = |core: &mut Core, ip: u16| {
+      core.registers.ip = ip as u32;
+      core.run_state = RunState::Run;
+      for _ in 0..4 {
+        if core.run_state == RunState::Halt {
+          break;
+        }
+        core.run_code_block();
+      }
+    };
+    run_from(&mut core, 0x4000);
+    memory_write_byte(&mut core.memory, 0x2000, 2);
+    run_from(&mut core, 0x4000);
+    assert_eq!(core.cache.get_stats().blocks, 4);
+
+    // patching the block in bank 1 leaves the one in bank 2 and its link
+    core.memory.rom[0x4001] = 0x33;
+    core.invalidate_rom(&[0x4001]);
+    assert_eq!(core.cache.get_stats().blocks, 3);
+    // patching the target in bank 2 must unlink the jump into it
+    core.memory.rom[0x8011] = 0x44;
+    core.invalidate_rom(&[0x8011]);
+    run_from(&mut core, 0x4000);
+    assert_eq!(core.run_state, RunState::Halt);
+    assert_eq!(core.registers.get_bc(), 0x2244);
+  }
+
+  engine_tests! {
+  #[test]
+  fn ppu_locks_vram_and_oam() {
+    let code = vec![
+      0x21, 0x00, 0x80, // LD HL, 0x8000
+      0x06, 0x12, // LD B, 0x12
+      0xf0, 0x41, // LDH A, (0x41)
+      0xe6, 0x03, // AND 3
+      0xfe, 0x03, // CP 3
+      0x20, 0xf8, // JR NZ, -8
+      0x70, // LD (HL), B
+      0x4e, // LD C, (HL)
+      0x78, // LD A, B
+      0xea, 0x00, 0xfe, // LD (0xfe00), A
+      0x76, // HALT
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    // wait for the PPU to start drawing, then try to access its memory
+    for _ in 0..2000 {
+      if core.run_state == RunState::Halt {
+        break;
+      }
+      core.run_code_block();
+    }
+    assert_eq!(core.run_state, RunState::Halt);
+    assert_eq!(core.memory.io.video.get_current_mode(), 3);
+    assert_eq!(core.memory.video_ram[0], 0);
+    assert_eq!(core.registers.get_bc() & 0xff, 0xff);
+    assert_eq!(core.memory.oam_ram[0], 0);
+  }
+
+
+  #[test]
+  fn rom_bank_wraps() {
+    use crate::cart::MBC1CartState;
+
+    let mut rom = vec![0xff; 0x10000];
+    rom[0x4000] = 0x01;
+    rom[0xc000] = 0x03;
+    let code = vec![
+      0x3e, 0x07, // LD A, 7
+      0xea, 0x00, 0x20, // LD (0x2000), A
+      0xfa, 0x00, 0x40, // LD A, (0x4000)
+      0x76, // HALT
+    ];
+    rom[0..code.len()].copy_from_slice(&code);
+    let mut core = Core::with_code_block(vec![].into_boxed_slice());
+    core.memory.rom = rom.into_boxed_slice();
+    core.memory.cart_state = Box::new(MBC1CartState::new());
+    core.run_code_block();
+    // a 4-bank ROM ignores the upper bits of bank 7
+    assert_eq!(core.memory.get_rom_bank(), 3);
+    assert_eq!(core.registers.get_a(), 0x03);
+  }
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn code_cache_flush() {
+    use crate::cache::MEMORY_MINIMUM_SIZE;
+
+    let code = vec![
+      0x3c, // INC A
+      0xc3, 0x08, 0x00, // JP 0x0008
+      0x00, 0x00, 0x00, 0x00,
+      0x3c, // INC A
+      0xc3, 0x00, 0x00, // JP 0x0000
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    // leave room for a single block before the cache is considered full
+    core.cache.set_capacity(MEMORY_MINIMUM_SIZE + 16);
+    core.run_code_block();
+    let stats = core.cache.get_stats();
+    assert_eq!(stats.blocks, 1);
+    assert!(stats.bytes_used > 16);
+    assert_eq!(stats.flushes, 0);
+
+    core.run_code_block();
+    let stats = core.cache.get_stats();
+    assert_eq!(stats.blocks, 1);
+    assert_eq!(stats.translations, 2);
+    assert_eq!(stats.flushes, 1);
+    assert!(core.cache.get_block(0).is_none());
+    assert!(core.cache.get_block(8).is_some());
+    assert_eq!(core.registers.get_a(), 0x02);
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn compiled_ram_code() {
+    let code = vec![
+      0x31, 0x00, 0xd0, // LD SP, 0xd000
+      0x3e, 0x04, // LD A, 0x04 (INC B)
+      0xea, 0x00, 0xc0, // LD (0xc000), A
+      0x3e, 0xc9, // LD A, 0xc9 (RET)
+      0xea, 0x01, 0xc0, // LD (0xc001), A
+      0xcd, 0x00, 0xc0, // CALL 0xc000
+      0x3e, 0x0c, // LD A, 0x0c (INC C)
+      0xea, 0x00, 0xc0, // LD (0xc000), A
+      0xcd, 0x00, 0xc0, // CALL 0xc000
+      0x18, 0xfe, // JR -2
+    ];
+    let mut core = Core::with_code_block(code.clone().into_boxed_slice());
+    for _ in 0..8 {
+      core.run_code_block();
+    }
+    // without tracking writes, RAM is interpreted
+    assert!(core.cache.get_block(0xc000).is_none());
+
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.set_ram_compilation(true);
+    for _ in 0..8 {
+      core.run_code_block();
+    }
+    // the routine was compiled, then compiled again after it was rewritten
+    assert_eq!(core.registers.get_bc(), 0x0101);
+    assert!(core.cache.get_block(0xc000).is_some());
+    assert_eq!(core.registers.get_ip(), 0x18);
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn compiled_high_ram_code() {
+    let code = vec![
+      0x31, 0x00, 0xd0, // LD SP, 0xd000
+      0x3e, 0x04, // LD A, 0x04 (INC B)
+      0xe0, 0xfd, // LDH (0xfd), A
+      0x3e, 0xc9, // LD A, 0xc9 (RET)
+      0xe0, 0xfe, // LDH (0xfe), A
+      0xcd, 0xfd, 0xff, // CALL 0xfffd
+      0x3e, 0x0c, // LD A, 0x0c (INC C)
+      0xe0, 0xfd, // LDH (0xfd), A
+      0xcd, 0xfd, 0xff, // CALL 0xfffd
+      0x18, 0xfe, // JR -2
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    for _ in 0..8 {
+      core.run_code_block();
+    }
+    // the routine is compiled without any option, and compiled again after
+    // it is rewritten
+    assert_eq!(core.registers.get_bc(), 0x0101);
+    assert!(core.cache.get_block(0xfffd).is_some());
+    assert_eq!(core.registers.get_ip(), 0x15);
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn compiled_cart_ram_code() {
+    use crate::cart::MBC1CartState;
+
+    let code = vec![
+      0x31, 0x00, 0xd0, // LD SP, 0xd000
+      0x3e, 0x0a, // LD A, 0x0a
+      0xea, 0x00, 0x00, // LD (0x0000), A
+      0x3e, 0x04, // LD A, 0x04 (INC B)
+      0xea, 0x00, 0xa0, // LD (0xa000), A
+      0x3e, 0xc9, // LD A, 0xc9 (RET)
+      0xea, 0x01, 0xa0, // LD (0xa001), A
+      0xcd, 0x00, 0xa0, // CALL 0xa000
+      0x3e, 0x0c, // LD A, 0x0c (INC C)
+      0xea, 0x00, 0xa0, // LD (0xa000), A
+      0xcd, 0x00, 0xa0, // CALL 0xa000
+      0x18, 0xfe, // JR -2
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.memory.cart_state = Box::new(MBC1CartState::new());
+    core.memory.cart_ram = vec![0; 0x2000].into_boxed_slice();
+    core.set_ram_compilation(true);
+    for _ in 0..8 {
+      core.run_code_block();
+    }
+    assert_eq!(core.registers.get_bc(), 0x0101);
+    assert!(core.cache.get_block(0xa000).is_some());
+    assert_eq!(core.registers.get_ip(), 0x1d);
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn linked_chain_respects_vram_lock() {
+    let mut code = Vec::new();
+    // four linked blocks of 8 cycles each, long enough to run from the start
+    // of mode 2 into mode 3
+    for _ in 0..4 {
+      code.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x00]); // NOP x5; JR +0
+    }
+    code.extend_from_slice(&[
+      0x21, 0x00, 0x80, // LD HL, 0x8000
+      0x3e, 0x12, // LD A, 0x12
+      0x77, // LD (HL), A
+      0x76, // HALT
+    ]);
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    // compile and link every block with the LCD off
+    core.memory.io.set_byte(0xff40, 0x00);
+    for _ in 0..8 {
+      if core.run_state == RunState::Halt {
+        break;
+      }
+      core.run_code_block();
+    }
+    assert_eq!(core.memory.video_ram[0], 0x12);
+
+    core.memory.video_ram[0] = 0;
+    core.memory.io.set_byte(0xff40, 0x91);
+    while core.memory.io.video.get_current_mode() != 0 {
+      core.memory.run_clock_cycles(ClockCycles(4));
+    }
+    while core.memory.io.video.get_current_mode() != 2 {
+      core.memory.run_clock_cycles(ClockCycles(4));
+    }
+    core.registers.ip = 0;
+    core.run_state = RunState::Run;
+    for _ in 0..8 {
+      if core.run_state == RunState::Halt {
+        break;
+      }
+      core.run_code_block();
+    }
+    // the write happens during mode 3, so VRAM can't be left in the page
+    // table from when the chain started in mode 2
+    assert_eq!(core.memory.io.video.get_current_mode(), 3);
+    assert_eq!(core.memory.video_ram[0], 0);
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn ppu_limits_budget() {
+    let code = vec![
+      0x18, 0xfe, // JR -2
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.jit_cycle_budget = MachineCycles(10000);
+    // the core starts at the beginning of a vblank line, 114 cycles long
+    core.run_code_block();
+    assert_eq!(core.last_block_cycle_length, 114);
+    assert_eq!(core.memory.io.video.get_ly(), 145);
   }
 
+  #[cfg(all(feature = "jit", jit_backend))]
   #[test]
-  fn swap() {
+  fn serial_completion_limits_budget() {
     let code = vec![
-      0x06, 0xd7, // LD B, 0xd7
-      0xcb, 0x30, // SWAP B
-      0xc3, 0x07, 0x00, // JP 0x0007
-      0x0e, 0x00, // LD C, 0x00
-      0xcb, 0x31, // SWAP C
-      0xc3, 0x0e, 0x00, // JP 0x000e
-      0x16, 0xff, // LD D, 0xff
-      0xcb, 0x32, // SWAP D
-      0xc3, 0x15, 0x00, // JP 0x0015
-      0x1e, 0x38, // LD E, 0x38
-      0xcb, 0x33, // SWAP E
-      0xc3, 0x1c, 0x00, // JP 0x001c
-      0x26, 0x40, // LD H, 0x40
-      0xcb, 0x34, // SWAP H
-      0xc3, 0x23, 0x00, // JP 0x0023
-      0x2e, 0x1f, // LD L, 0x1f
-      0xcb, 0x35, // SWAP L
-      0xc3, 0x2a, 0x00, // JP 0x002a
-      0x3e, 0x36, // LD A, 0x36
-      0xcb, 0x37, // SWAP A
+      0x3e, 0x81, // LD A, 0x81
+      0xe0, 0x02, // LDH (0x02), A
+      0x18, 0xfe, // JR -2
     ];
     let mut core = Core::with_code_block(code.into_boxed_slice());
-    core.registers.af = 0x00f0;
-    core.run_code_block();
-    assert_eq!(core.registers.get_bc(), 0x7d00);
-    assert_eq!(core.registers.get_af(), 0x0000);
-    core.run_code_block();
-    assert_eq!(core.registers.get_bc(), 0x7d00);
-    assert_eq!(core.registers.get_af(), 0x0080);
-    core.run_code_block();
-    assert_eq!(core.registers.get_de(), 0xff00);
-    assert_eq!(core.registers.get_af(), 0x0000);
-    core.run_code_block();
-    assert_eq!(core.registers.get_de(), 0xff83);
-    assert_eq!(core.registers.get_af(), 0x0000);
-    core.run_code_block();
-    assert_eq!(core.registers.get_hl(), 0x0400);
-    assert_eq!(core.registers.get_af(), 0x0000);
+    core.jit_cycle_budget = MachineCycles(10000);
+    // with the LCD on, the loop would also stop at each PPU mode change
+    core.memory.io.set_byte(0xff40, 0x00);
     core.run_code_block();
-    assert_eq!(core.registers.get_hl(), 0x04f1);
-    assert_eq!(core.registers.get_af(), 0x0000);
+    assert_eq!(core.last_block_cycle_length, 8);
+    // The transfer completes 1024 cycles after it started. The loop stops at
+    // the first iteration that passes that point.
     core.run_code_block();
-    assert_eq!(core.registers.get_af(), 0x6300);
+    assert_eq!(core.last_block_cycle_length, 1017);
+    assert_eq!(core.memory.io.interrupt_flag.as_u8() & 0x08, 0x08);
   }
 
+  #[cfg(feature = "serial")]
   #[test]
-  fn bit_test() {
+  fn link_replay_divergence_reported() {
+    use crate::devices::link::{LinkRecording, ReplayPeer};
+
     let code = vec![
-      0x06, 0x0f, // LD B, 0x0f
-      0xcb, 0x40, // BIT 0,B
-      0xc3, 0x07, 0x00, // JMP 0x0007
-      0x0e, 0xf8, // LD C, 0xf8
-      0xcb, 0x49, // BIT 1, C
-      0xc3, 0x0e, 0x00, // JMP 0x000e
+      0x3e, 0x07, // LD A, 0x07
+      0xe0, 0x01, // LDH (0x01), A
+      0x3e, 0x81, // LD A, 0x81
+      0xe0, 0x02, // LDH (0x02), A
+      0x18, 0xfe, // JR -2
     ];
     let mut core = Core::with_code_block(code.into_boxed_slice());
+    let recording = LinkRecording::deserialize("0 01 10 int\n").unwrap();
+    core.memory.io.serial_port().unwrap().connect(Box::new(ReplayPeer::new(recording)));
+    core.run_frame();
+    assert_eq!(core.take_messages(), vec!["Link replay diverged at transfer 0: recorded 01, sent 07"]);
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn spin_loop_services_interrupts() {
+    let code = vec![
+      0x31, 0xfe, 0xff, // LD SP, 0xfffe
+      0x3e, 0x04, // LD A, 0x04
+      0xe0, 0xff, // LDH (0xff), A
+      0x3e, 0x05, // LD A, 0x05
+      0xe0, 0x07, // LDH (0x07), A
+      0xfb, // EI
+      0x18, 0xfe, // JR -2
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    for _ in 0..100 {
+      core.run_code_block();
+      if core.registers.get_ip() == 0x50 {
+        break;
+      }
+    }
+    assert_eq!(core.registers.get_ip(), 0x50);
+  }
+
+  engine_tests! {
+  #[test]
+  fn general_purpose_hdma() {
+    let code = vec![
+      0x3e, 0xc0, // LD A, 0xc0
+      0xe0, 0x51, // LDH (0x51), A
+      0xaf, // XOR A
+      0xe0, 0x52, // LDH (0x52), A
+      0x3e, 0x01, // LD A, 0x01
+      0xe0, 0x53, // LDH (0x53), A
+      0xaf, // XOR A
+      0xe0, 0x54, // LDH (0x54), A
+      0x3e, 0x01, // LD A, 0x01
+      0xe0, 0x55, // LDH (0x55), A
+      0xf0, 0x55, // LDH A, (0x55)
+      0xc3, 0x17, 0x00, // JMP 0x0017
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.memory.cgb_mode = true;
+    for i in 0..0x20 {
+      core.memory.work_ram[i] = i as u8 + 1;
+    }
     core.run_code_block();
-    assert_eq!(core.registers.get_af(), 0x0020);
-    core.run_code_block();
-    assert_eq!(core.registers.get_af(), 0x00a0);
+    assert_eq!(&core.memory.video_ram[0x100..0x120], &core.memory.work_ram[0..0x20]);
+    assert_eq!(core.registers.get_af() >> 8, 0xff);
+    // two blocks stall the CPU for 16 machine cycles
+    assert_eq!(core.last_block_cycle_length, 16 + 30);
   }
 
   #[test]
@@ -2116,6 +4166,186 @@ mod tests {
   }
 
 
+  #[test]
+  fn skip_idle_poll_loop() {
+    let code = vec![
+      0x3e, 0x80, // LD A, 0x80
+      0xe0, 0x40, // LDH (0x40), A
+      0xf0, 0x44, // LDH A, (0x44)
+      0xfe, 0x90, // CP 0x90
+      0x20, 0xfa, // JR NZ, -6
+      0x00, // NOP
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    let mut steps = 0;
+    while core.registers.get_ip() != 0x000a {
+      core.run_interp();
+      steps += 1;
+      assert!(steps < 1000);
+    }
+    assert_eq!(core.registers.get_af() >> 8, 0x90);
+    assert_eq!(core.memory.io.video.get_ly(), 0x90);
+  }
+
+  #[test]
+  fn idle_loop_exits_immediately() {
+    let code = vec![
+      0x3e, 0x42, // LD A, 0x42
+      0xe0, 0x80, // LDH (0x80), A
+      0xf0, 0x80, // LDH A, (0x80)
+      0xfe, 0x42, // CP 0x42
+      0x20, 0xfa, // JR NZ, -6
+      0x00, // NOP
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    for _ in 0..5 {
+      core.run_interp();
+    }
+    assert_eq!(core.registers.get_ip(), 0x000a);
+  }
+
+  #[test]
+  fn idle_loop_stops_at_vblank() {
+    let code = vec![
+      0x3e, 0x80, // LD A, 0x80
+      0xe0, 0x40, // LDH (0x40), A
+      0x18, 0xfe, // JR -2
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.run_frame();
+    // with the vblank interrupt disabled, skipping the loop must still stop
+    // at each frame
+    for _ in 0..10 {
+      let start = core.cycles_elapsed();
+      core.run_frame();
+      let frame_cycles = core.cycles_elapsed() - start;
+      assert!(frame_cycles < 17556 + 2 * 114, "{}", frame_cycles);
+    }
+  }
+  }
+
+  #[cfg(feature = "debug_freeze")]
+  #[test]
+  fn frozen_tile_writes() {
+    use crate::debug::freeze::FreezeRegion;
+
+    let code = vec![
+      0x21, 0x10, 0x80, // LD HL, 0x8010
+      0x3e, 0x55, // LD A, 0x55
+      0x22, // LD (HL+), A
+      0x2e, 0x20, // LD L, 0x20
+      0x77, // LD (HL), A
+      0x76, // HALT
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.memory.freeze.add(FreezeRegion::Tile(0, 1));
+    core.run_code_block();
+    assert_eq!(core.memory.video_ram[0x10], 0x00);
+    assert_eq!(core.memory.video_ram[0x20], 0x55);
+    assert_eq!(core.memory.freeze.get_log().len(), 1);
+  }
+
+  #[cfg(feature = "strict_io")]
+  #[test]
+  fn strict_io_accesses() {
+    let code = vec![
+      0xe0, 0x26, // LDH (0x26), A
+      0xf0, 0x40, // LDH A, (0x40)
+      0xf0, 0x4f, // LDH A, (0x4f)
+      0x76, // HALT
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.run_code_block();
+    let accesses = core.memory.strict_io.get_accesses();
+    assert_eq!(accesses.len(), 2);
+    assert_eq!((accesses[0].address, accesses[0].ip, accesses[0].write), (0xff26, 0, true));
+    assert_eq!(accesses[1].address, 0xff4f);
+    assert!(!accesses[1].write);
+    let summary = core.memory.strict_io.check().unwrap_err();
+    assert!(summary.contains("read  0xFF4F"));
+  }
+
+  engine_tests! {
+  #[test]
+  fn oam_dma_timing() {
+    let code = vec![
+      0x3e, 0xc0, // LD A, 0xc0
+      0xe0, 0x46, // LDH (0x46), A
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    for i in 0..0xa0 {
+      core.memory.work_ram[i] = i as u8;
+    }
+    core.memory.high_ram[0] = 0x12;
+    core.run_code_block();
+    let mem_ptr = &mut core.memory as *mut crate::mem::MemoryAreas;
+    // only IO and HRAM are accessible while the DMA runs
+    assert_eq!(memory_read_byte(mem_ptr, 0xc001), 0xff);
+    assert_eq!(memory_read_byte(mem_ptr, 0xff80), 0x12);
+    assert_eq!(memory_read_byte(mem_ptr, 0xff46), 0xc0);
+    assert_eq!(memory_read_byte(mem_ptr, 0xff44), core.memory.io.video.get_ly());
+    memory_write_byte(mem_ptr, 0xc001, 0x55);
+    core.memory.run_clock_cycles(MachineCycles(80).to_clock_cycles());
+    assert_eq!(core.memory.oam_ram[0x40], 0x40);
+    assert_eq!(core.memory.oam_ram[0x90], 0x00);
+    core.memory.run_clock_cycles(MachineCycles(80).to_clock_cycles());
+    assert!(core.memory.oam_dma.is_none());
+    assert_eq!(core.memory.oam_ram[0x9f], 0x9f);
+    assert_eq!(memory_read_byte(mem_ptr, 0xc001), 0x01);
+    assert_eq!(memory_read_byte(mem_ptr, 0xff46), 0xc0);
+  }
+
+  #[test]
+  fn oam_dma_restart() {
+    let code = vec![
+      0x3e, 0xc0, // LD A, 0xc0
+      0xe0, 0x46, // LDH (0x46), A
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    for i in 0..0xa0 {
+      core.memory.work_ram[i] = i as u8;
+      core.memory.work_ram[0x100 + i] = !(i as u8);
+    }
+    core.run_code_block();
+    let mem_ptr = &mut core.memory as *mut crate::mem::MemoryAreas;
+    core.memory.run_clock_cycles(MachineCycles(0x40).to_clock_cycles());
+    assert_eq!(core.memory.oam_ram[0x3f], 0x3f);
+    // writing the register again starts over from the new source
+    memory_write_byte(mem_ptr, 0xff46, 0xc1);
+    assert_eq!(memory_read_byte(mem_ptr, 0xff46), 0xc1);
+    core.memory.run_clock_cycles(MachineCycles(0x20).to_clock_cycles());
+    assert_eq!(core.memory.oam_ram[0x1f], 0xe0);
+    assert_eq!(core.memory.oam_ram[0x3f], 0x3f);
+    assert!(core.memory.oam_dma.is_some());
+    core.memory.run_clock_cycles(MachineCycles(0x80).to_clock_cycles());
+    assert!(core.memory.oam_dma.is_none());
+    assert_eq!(core.memory.oam_ram[0x3f], 0xc0);
+    assert_eq!(core.memory.oam_ram[0x9f], 0x60);
+  }
+
+  #[test]
+  fn boot_rom_overlay() {
+    let code = vec![
+      0x3e, 0x11, // LD A, 0x11
+      0x00, // NOP
+      0x00, // NOP
+      0xc3, 0x00, 0x00, // JMP 0x0000
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    let mut boot_rom = vec![0; 0x100];
+    boot_rom[0..4].copy_from_slice(&[
+      0x3e, 0x01, // LD A, 0x01
+      0xe0, 0x50, // LDH (0x50), A
+    ]);
+    core.memory.map_boot_rom(boot_rom.into_boxed_slice());
+    core.run_code_block();
+    assert!(!core.memory.is_boot_rom_mapped());
+    assert_eq!(core.registers.get_af() >> 8, 0x01);
+    assert_eq!(core.registers.get_ip(), 0x0000);
+    core.run_code_block();
+    assert_eq!(core.registers.get_af() >> 8, 0x11);
+  }
+
   #[test]
   fn push_wrap() {
     let code = vec![
@@ -2143,6 +4373,100 @@ mod tests {
     assert_eq!(core.memory.io.interrupt_mask, 0x02);
   }
 
+  #[test]
+  fn push_wrap_warning() {
+    let code = vec![
+      0x31, 0x02, 0x00, // LD SP, 0x0002
+      0xc5, // PUSH BC
+      0xc5, // PUSH BC
+      0x18, 0xfe, // JR -2
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.stack_monitor = Some(StackMonitor::new(0));
+    for _ in 0..4 {
+      core.update();
+    }
+    let warnings = core.stack_monitor.as_ref().unwrap().warnings();
+    assert_eq!(warnings.len(), 2);
+    // the first push from 0x0002 writes to ROM
+    assert_eq!(warnings[0].problem, StackProblem::EnteredRom);
+    assert_eq!(warnings[1].problem, StackProblem::Overflow);
+    assert_eq!(warnings[1].ip, 0x0004);
+    assert_eq!(warnings[1].sp, 0xfffe);
+    assert_eq!(warnings[1].trajectory.len(), 3);
+    // the core leaves printing them to the frontend
+    let messages = core.take_messages();
+    assert_eq!(messages.len(), 2);
+    assert!(messages[1].starts_with("Stack overflow wrapped past 0000 at PC:0004"));
+    assert!(core.take_messages().is_empty());
+  }
+
+  #[test]
+  fn save_state_round_trip() {
+    let code = vec![
+      0x21, 0x00, 0xc0, // LD HL, 0xc000
+      0x34, // INC (HL)
+      0x18, 0xfd, // JR -3
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.run_frame();
+    let state = core.save_state();
+    let cycles = core.cycles_elapsed();
+    core.run_frame();
+    let counter_after_frame = memory_read_byte(&core.memory, 0xc000);
+    core.run_frame();
+
+    core.load_state(&state).unwrap();
+    assert_eq!(core.cycles_elapsed(), cycles);
+    assert_eq!(core.save_state(), state);
+    // the restored core runs exactly as it did the first time
+    core.run_frame();
+    assert_eq!(memory_read_byte(&core.memory, 0xc000), counter_after_frame);
+
+    assert_eq!(core.load_state(b"nope"), Err(String::from("Not a save state")));
+    assert_eq!(core.load_state(&state[..100]), Err(String::from("Save state is truncated")));
+  }
+
+  #[test]
+  fn rewind_frames() {
+    let code = vec![
+      0x18, 0xfe, // JR -2
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    assert!(core.rewind(60).is_err());
+    core.rewind = Some(RewindBuffer::new(2, 1024 * 1024));
+    let mut frame_ends = Vec::new();
+    for _ in 0..8 {
+      core.run_frame();
+      frame_ends.push(core.cycles_elapsed());
+    }
+    // states were captured after frames 2, 4, 6, and 8
+    assert_eq!(core.rewind(3), Ok(4));
+    assert_eq!(core.cycles_elapsed(), frame_ends[3]);
+  }
+
+  #[test]
+  fn pause_and_advance() {
+    let code = vec![
+      0x18, 0xfe, // JR -2
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.run_frame();
+    let start = core.cycles_elapsed();
+    core.set_paused(true).unwrap();
+    assert_eq!(core.run_frame(), None);
+    assert_eq!(core.skip_frame(), None);
+    assert_eq!(core.cycles_elapsed(), start);
+    // frames can still be run one at a time
+    core.advance_frame();
+    assert!(core.is_paused());
+    let advanced = core.cycles_elapsed() - start;
+    assert!(advanced > FRAME_CYCLES / 2 && advanced < FRAME_CYCLES * 3 / 2);
+    core.set_paused(false).unwrap();
+    core.run_frame();
+    assert!(core.cycles_elapsed() > start + advanced);
+  }
+
   #[test]
   fn absolute_jump() {
     let code = vec![
@@ -2327,6 +4651,11 @@ mod tests {
       0xcd, 0x12, 0x00, // CALL 0x0012
     ];
     let mut core = Core::with_code_block(code.into_boxed_slice());
+    // step through one block at a time
+    #[cfg(jit_backend)]
+    {
+      core.cache.link_blocks = false;
+    }
     core.run_code_block();
     assert_eq!(core.registers.get_ip(), 0x10);
     core.run_code_block();
@@ -2357,29 +4686,174 @@ mod tests {
     core.run_code_block();
     assert_eq!(core.registers.get_ip(), 0x12);
     core.run_code_block();
-    assert_eq!(core.registers.get_ip(), 0x13);
+    assert_eq!(core.registers.get_ip(), 0x13);
+    core.run_code_block();
+    assert_eq!(core.registers.get_ip(), 0x20);
+  }
+
+  #[test]
+  fn reti() {
+    let code = vec![
+      0x31, 0x00, 0xc1, // LD SP, 0xc100
+      0xcd, 0x08, 0x00, // CALL 0x0008
+      0x00, 0x00,
+      0xd9, // RETI
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.interrupts_enabled = InterruptState::Disabled;
+    core.run_code_block();
+    assert_eq!(core.registers.get_ip(), 0x08);
+    core.run_code_block();
+    assert_eq!(core.registers.get_sp(), 0xc100);
+    assert_eq!(core.registers.get_ip(), 0x06);
+    assert_eq!(core.interrupts_enabled, InterruptState::Enabled);
+  }
+  }
+  
+  /// Program that calls a routine ending in RETI while a timer interrupt is
+  /// already pending, so the interrupt fires as soon as RETI returns
+  fn reti_with_pending_interrupt() -> Core {
+    let code = vec![
+      0x31, 0x00, 0xc1, // LD SP, 0xc100
+      0x3e, 0x04, // LD A, 0x04
+      0xe0, 0xff, // LD (0xff00 + 0xff), A
+      0xe0, 0x0f, // LD (0xff00 + 0x0f), A
+      0xcd, 0x10, 0x00, // CALL 0x0010
+      0x00, 0x00, 0x00, 0x00,
+      0xd9, // RETI
+    ];
+    Core::with_code_block(code.into_boxed_slice())
+  }
+
+  engine_tests! {
+  #[test]
+  fn reti_interrupt_interpreted() {
+    let mut core = reti_with_pending_interrupt();
+    for _ in 0..5 {
+      core.run_interp();
+    }
+    assert_eq!(core.registers.get_ip(), 0x10);
+    assert_eq!(core.interrupts_enabled, InterruptState::Disabled);
+    let cycles = core.cycles_elapsed();
+    core.run_interp();
+    // no instruction runs between RETI and the dispatch
+    assert_eq!(core.registers.get_ip(), 0x50);
+    assert_eq!(core.registers.get_sp(), 0xc0fe);
+    assert_eq!(core.memory.work_ram[0xfe], 0x0c);
+    assert_eq!(core.memory.work_ram[0xff], 0x00);
+    assert_eq!(core.memory.io.interrupt_flag.as_u8(), 0x00);
+    assert_eq!(core.interrupts_enabled, InterruptState::Disabled);
+    // RETI, then the five machine cycles of the dispatch, which have already
+    // been run on the peripherals
+    assert_eq!(core.cycles_elapsed() - cycles, 4 + 5);
+    assert_eq!({ core.registers.cycles }, 0);
+  }
+
+  #[test]
+  fn reti_interrupt_block() {
+    let mut core = reti_with_pending_interrupt();
+    core.run_code_block();
+    assert_eq!(core.registers.get_ip(), 0x10);
+    let cycles = core.cycles_elapsed();
     core.run_code_block();
-    assert_eq!(core.registers.get_ip(), 0x20);
+    assert_eq!(core.registers.get_ip(), 0x50);
+    assert_eq!(core.registers.get_sp(), 0xc0fe);
+    assert_eq!(core.memory.work_ram[0xfe], 0x0c);
+    assert_eq!(core.memory.work_ram[0xff], 0x00);
+    assert_eq!(core.memory.io.interrupt_flag.as_u8(), 0x00);
+    assert_eq!(core.cycles_elapsed() - cycles, 4 + 5);
   }
 
   #[test]
-  fn reti() {
+  fn halt_dispatch_cycles() {
     let code = vec![
       0x31, 0x00, 0xc1, // LD SP, 0xc100
-      0xcd, 0x08, 0x00, // CALL 0x0008
-      0x00, 0x00,
-      0xd9, // RETI
+      0x3e, 0x04, // LD A, 0x04
+      0xe0, 0xff, // LD (0xff00 + 0xff), A
+      0xfb, // EI
+      0x76, // HALT
     ];
     let mut core = Core::with_code_block(code.into_boxed_slice());
-    core.interrupts_enabled = InterruptState::Disabled;
-    core.run_code_block();
-    assert_eq!(core.registers.get_ip(), 0x08);
-    core.run_code_block();
-    assert_eq!(core.registers.get_sp(), 0xc100);
-    assert_eq!(core.registers.get_ip(), 0x06);
-    assert_eq!(core.interrupts_enabled, InterruptState::Enabled);
+    while core.run_state == RunState::Run {
+      core.update();
+    }
+    assert_eq!(core.registers.get_ip(), 0x09);
+    assert_eq!({ core.registers.cycles }, 0);
+    // stays halted until an interrupt is requested
+    core.update();
+    assert_eq!(core.run_state, RunState::Halt);
+    core.memory.io.interrupt_flag |= InterruptFlag::timer();
+    let cycles = core.cycles_elapsed();
+    core.update();
+    assert_eq!(core.run_state, RunState::Run);
+    assert_eq!(core.registers.get_ip(), 0x50);
+    assert_eq!(core.memory.work_ram[0xfe], 0x09);
+    // one halted cycle, then the dispatch
+    assert_eq!(core.cycles_elapsed() - cycles, 1 + 5);
   }
-  
+  }
+
+  /// Core with a joypad interrupt waiting to be dispatched, and the timer
+  /// about to overflow after `cycles` more clock cycles
+  fn dispatch_with_timer_overflow(cycles: usize) -> Core {
+    let mut core = Core::with_code_block(vec![0x00].into_boxed_slice());
+    core.registers.sp = 0xc100;
+    core.registers.ip = 0x1234;
+    core.memory.io.set_byte(0xff07, 0x05);
+    core.memory.io.set_byte(0xff04, 0x00);
+    core.memory.io.set_byte(0xff05, 0xff);
+    core.memory.run_clock_cycles(ClockCycles(16 - cycles));
+    core.memory.io.interrupt_mask = 0x14;
+    core.memory.io.interrupt_flag = InterruptFlag::joypad();
+    core.interrupts_enabled = InterruptState::Enabled;
+    core.handle_interrupt();
+    core
+  }
+
+  engine_tests! {
+  #[test]
+  fn interrupt_raised_during_dispatch() {
+    // The timer raises its interrupt a cycle after overflowing. Raised within
+    // the first two cycles, it takes over the dispatch.
+    let core = dispatch_with_timer_overflow(4);
+    assert_eq!(core.registers.get_ip(), 0x50);
+    assert_eq!(core.memory.io.interrupt_flag.as_u8(), 0x10);
+    // Raised once the high byte has been pushed, it is too late
+    let core = dispatch_with_timer_overflow(8);
+    assert_eq!(core.registers.get_ip(), 0x60);
+    assert_eq!(core.memory.io.interrupt_flag.as_u8(), 0x04);
+    assert_eq!(core.memory.work_ram[0xfe], 0x34);
+    assert_eq!(core.memory.work_ram[0xff], 0x12);
+  }
+
+  #[test]
+  fn interrupt_push_to_ie() {
+    let dispatch = |sp: u32, ip: u32| {
+      let mut core = Core::with_code_block(vec![0x00].into_boxed_slice());
+      core.registers.sp = sp;
+      core.registers.ip = ip;
+      core.memory.io.interrupt_mask = 0x01;
+      core.memory.io.interrupt_flag = InterruptFlag::vblank();
+      core.interrupts_enabled = InterruptState::Enabled;
+      core.handle_interrupt();
+      core
+    };
+    // pushing the high byte into IE cancels the dispatch, which jumps to
+    // 0x0000 instead, leaving the interrupt requested
+    let core = dispatch(0x0000, 0x0234);
+    assert_eq!(core.registers.get_ip(), 0x0000);
+    assert_eq!(core.memory.io.interrupt_mask, 0x02);
+    assert_eq!(core.memory.io.interrupt_flag.as_u8(), 0x01);
+    // a high byte that keeps the interrupt enabled doesn't
+    let core = dispatch(0x0000, 0x0134);
+    assert_eq!(core.registers.get_ip(), 0x40);
+    assert_eq!(core.memory.io.interrupt_flag.as_u8(), 0x00);
+    // the vector has already been chosen when the low byte lands on IE
+    let core = dispatch(0x0001, 0x0210);
+    assert_eq!(core.registers.get_ip(), 0x40);
+    assert_eq!(core.memory.io.interrupt_mask, 0x10);
+  }
+
   #[test]
   fn stop() {
     let code = vec![
@@ -2399,6 +4873,164 @@ mod tests {
     core.run_code_block();
     assert_eq!(core.run_state, RunState::Halt);
   }
+  }
+
+  /// Run one instruction through compiled code with lockstep verification,
+  /// which panics if it disagrees with the interpreter. Register pairs and
+  /// operands point into WRAM and HRAM, so that both only touch plain memory.
+  #[cfg(jit_backend)]
+  fn verify_instruction(opcode: &[u8], seed: &mut u32) {
+    let mut random = || {
+      *seed ^= *seed << 13;
+      *seed ^= *seed >> 17;
+      *seed ^= *seed << 5;
+      *seed
+    };
+    // start past the beginning of ROM, so relative jumps can't underflow
+    let mut code = vec![0; 0x100];
+    code.extend_from_slice(opcode);
+    let (_, length, _) = super::decoder::decode(&[opcode[0], 0, 0]);
+    match (opcode[0], length) {
+      // LDH (n), A and LDH A, (n)
+      (0xe0, _) | (0xf0, _) => code.push(0x80 | (random() as u8 & 0x7e)),
+      (_, 2) if opcode.len() == 1 => code.push(random() as u8),
+      (_, 3) => code.extend_from_slice(&[random() as u8, 0xc0 | (random() as u8 & 0x0f)]),
+      _ => (),
+    }
+    // HALT, for instructions that don't end the block themselves
+    code.push(0x76);
+
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    for byte in core.memory.work_ram.iter_mut() {
+      *byte = random() as u8;
+    }
+    core.registers.af = random() & 0xfff0;
+    // C also addresses HRAM, for LD (C), A and LD A, (C)
+    core.registers.bc = 0xc080 | (random() & 0x0f7e);
+    core.registers.de = 0xc000 | (random() & 0x0fff);
+    core.registers.hl = 0xc000 | (random() & 0x0fff);
+    core.registers.sp = 0xc100 | (random() & 0x0efe);
+    core.registers.ip = 0x100;
+    core.use_jit = true;
+    core.verify_jit = true;
+    core.run_code_block();
+  }
+
+  #[cfg(jit_backend)]
+  #[test]
+  fn every_opcode_verified() {
+    let mut seed = 0x1234_5678;
+    for opcode in 0..=0xffu8 {
+      if opcode == 0xcb {
+        continue;
+      }
+      for _ in 0..4 {
+        verify_instruction(&[opcode], &mut seed);
+        verify_instruction(&[0xcb, opcode], &mut seed);
+      }
+    }
+  }
+
+  engine_tests! {
+  #[test]
+  fn execute_from_echo_ram() {
+    let code = vec![
+      0x21, 0x00, 0xc0, // LD HL, 0xc000
+      0x36, 0x3c, // LD (HL), 0x3c (INC A)
+      0x2c, // INC L
+      0x36, 0x76, // LD (HL), 0x76 (HALT)
+      0xc3, 0x00, 0xe0, // JP 0xe000
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    let a = core.registers.get_a();
+    while core.run_state == RunState::Run {
+      core.update();
+    }
+    assert_eq!(core.registers.get_ip(), 0xe002);
+    assert_eq!(core.registers.get_a(), a.wrapping_add(1));
+  }
+
+  #[test]
+  fn stop_until_button_press() {
+    let code = vec![
+      0x3e, 0x10, // LD A, 0x10
+      0xe0, 0x00, // LD (0xff00 + 0x00), A
+      0x10, 0x00, // STOP
+      0x04, // INC B
+      0x76, // HALT
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.memory.io.set_byte(0xffff, 0);
+    while core.run_state == RunState::Run {
+      core.update();
+    }
+    assert_eq!(core.run_state, RunState::Stop);
+    assert_eq!(core.registers.get_ip(), 6);
+    assert_eq!(memory_read_byte(&core.memory, 0xff04), 0);
+    // a direction isn't selected, so it doesn't wake the CPU
+    core.memory.io.joypad_port().unwrap().press_button(Button::Down);
+    for _ in 0..1000 {
+      core.update();
+    }
+    assert_eq!(core.run_state, RunState::Stop);
+    // an action button wakes it, even with the joypad interrupt disabled
+    core.memory.io.joypad_port().unwrap().press_button(Button::Start);
+    let b = core.registers.get_b();
+    while core.run_state == RunState::Stop {
+      core.update();
+    }
+    while core.run_state == RunState::Run {
+      core.update();
+    }
+    assert_eq!(core.registers.get_b(), b.wrapping_add(1));
+  }
+
+  #[test]
+  fn frames_with_lcd_off() {
+    let code = vec![
+      0xaf, // XOR A
+      0xe0, 0x40, // LD (0xff00 + 0x40), A
+      0x18, 0xfe, // JR -2
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.run_frame();
+    assert_eq!(core.memory.io.video.get_ly(), 0);
+    // frames keep the same length while the LCD is off, give or take the
+    // instruction that crosses the end of the frame
+    let first_frame = core.cycles_elapsed();
+    core.run_frame();
+    let frame_length = core.cycles_elapsed() - first_frame;
+    assert!(frame_length.abs_diff(FRAME_CYCLES) < 4);
+  }
+
+  #[test]
+  fn invalid_opcode() {
+    let code = vec![
+      0x3e, 0x04, // LD A, 0x04
+      0xe0, 0xff, // LD (0xff00 + 0xff), A
+      0xfb, // EI
+      0x00, // NOP
+      0xd3, // invalid
+    ];
+    let mut core = Core::with_code_block(code.clone().into_boxed_slice());
+    while core.run_state == RunState::Run {
+      core.update();
+    }
+    assert_eq!(core.run_state, RunState::Locked);
+    assert_eq!(core.registers.get_ip(), 0x06);
+    // unlike HALT, an interrupt doesn't wake the CPU
+    core.memory.io.interrupt_flag |= InterruptFlag::timer();
+    core.update();
+    assert_eq!(core.run_state, RunState::Locked);
+    assert_eq!(core.registers.get_ip(), 0x06);
+
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    while core.run_state == RunState::Run {
+      core.run_code_block();
+    }
+    assert_eq!(core.run_state, RunState::Locked);
+    assert_eq!(core.registers.get_ip(), 0x06);
+  }
 
   #[test]
   fn interrupt_disabled() {
@@ -2430,6 +5062,7 @@ mod tests {
     ];
     let mut core = Core::with_code_block(code.into_boxed_slice());
     core.run_code_block(); // EI will end a block
+    core.run_code_block(); // the instruction after it runs on its own
     core.run_code_block();
     assert_eq!(core.registers.get_ip(), 0x60);
     assert_eq!(core.memory.work_ram[0xfe], 0x00);
@@ -2450,6 +5083,7 @@ mod tests {
     ];
     let mut core = Core::with_code_block(code.into_boxed_slice());
     core.run_code_block(); // EI will end a block
+    core.run_code_block(); // the instruction after it runs on its own
     core.run_code_block();
     assert_eq!(core.registers.get_ip(), 0x0f);
     assert_eq!(core.registers.get_sp(), 0xc0ff);
@@ -2469,6 +5103,7 @@ mod tests {
     ];
     let mut core = Core::with_code_block(code.into_boxed_slice());
     core.run_code_block(); // EI will end a block
+    core.run_code_block(); // the instruction after it runs on its own
     core.run_code_block();
     assert_eq!(core.registers.get_ip(), 0x48);
     assert_eq!(core.memory.work_ram[0xfe], 0x00);
@@ -2490,6 +5125,11 @@ mod tests {
       0xc3, 0x10, 0x00, // JP 0x0010
     ];
     let mut core = Core::with_code_block(code.into_boxed_slice());
+    // the interrupt needs to fire after exactly one iteration of the loop
+    #[cfg(jit_backend)]
+    {
+      core.cache.link_blocks = false;
+    }
     core.run_code_block();
     assert_eq!(core.last_block_cycle_length, 4);
     core.run_interp();
@@ -2498,14 +5138,16 @@ mod tests {
     core.run_interp();
     core.run_interp();
     core.run_interp();
-    assert_eq!(core.memory.io.timer.get_counter(), 0);
+    assert_eq!(core.memory.io.get_byte(0xff05), 0);
     for i in 1..=255 {
       core.run_interp();
       assert_eq!(core.registers.get_ip(), 0x10);
-      assert_eq!(core.memory.io.timer.get_counter(), i);
+      assert_eq!(core.memory.io.get_byte(0xff05), i);
     }
     core.run_code_block();
-    assert_eq!(core.memory.io.timer.get_counter(), 0);
+    // the timer overflowed during the jump, and kept counting through the
+    // dispatch
+    assert_eq!(core.memory.io.get_byte(0xff05), 2);
     assert_eq!(core.registers.get_ip(), 0x50);
   }
 
@@ -2587,6 +5229,7 @@ mod tests {
     ];
     let mut core = Core::with_code_block(code.into_boxed_slice());
     core.run_code_block();
+    core.run_code_block();
     for _ in 0..10000 { // simulate an infinite loop, but don't block all tests
       core.run_code_block();
       if core.registers.get_ip() != 0x09 {
@@ -2611,6 +5254,53 @@ mod tests {
     assert_eq!(core.run_state, RunState::Halt);
   }
 
+  #[test]
+  fn ei_ret_block() {
+    let code = vec![
+      0x31, 0x00, 0xc1, // LD SP, 0xc100
+      0x3e, 0x04, // LD A, 0x04
+      0xe0, 0xff, // LD (0xff00 + 0xff), A
+      0xe0, 0x0f, // LD (0xff00 + 0x0f), A
+      0xcd, 0x10, 0x00, // CALL 0x0010
+      0x76, // HALT
+      0x00, 0x00, 0x00,
+      0xfb, // EI
+      0xc9, // RET
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.run_code_block();
+    core.run_code_block();
+    assert_eq!(core.registers.get_ip(), 0x11);
+    assert_eq!(core.interrupts_enabled, InterruptState::EnableNext);
+    // the RET runs before the interrupt is taken, so the handler returns to
+    // the caller
+    core.run_code_block();
+    assert_eq!(core.registers.get_ip(), 0x50);
+    assert_eq!(core.registers.get_sp(), 0xc0fe);
+    assert_eq!(core.memory.work_ram[0xfe], 0x0c);
+    assert_eq!(core.memory.work_ram[0xff], 0x00);
+  }
+
+  #[test]
+  fn ei_halt_block() {
+    let code = vec![
+      0x31, 0x00, 0xc1, // LD SP, 0xc100
+      0x3e, 0x04, // LD A, 0x04
+      0xe0, 0xff, // LD (0xff00 + 0xff), A
+      0xe0, 0x0f, // LD (0xff00 + 0x0f), A
+      0xfb, // EI
+      0x76, // HALT
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.run_code_block();
+    assert_eq!(core.interrupts_enabled, InterruptState::EnableNext);
+    // the pending interrupt ends the HALT right away, returning past it
+    core.run_code_block();
+    assert_eq!(core.run_state, RunState::Run);
+    assert_eq!(core.registers.get_ip(), 0x50);
+    assert_eq!(core.memory.work_ram[0xfe], 0x0b);
+  }
+
   #[test]
   fn ei() {
     let code = vec![
@@ -2634,4 +5324,320 @@ mod tests {
     assert_eq!(core.registers.get_a(), 0xbb);
     assert_eq!(core.registers.get_ip(), 0x60);
   }
+
+  #[test]
+  fn records_block_history() {
+    use crate::debug::history::{recent_blocks, Engine};
+
+    let code = vec![
+      0x3e, 0x01, // LD A, 0x01
+      0x18, 0x00, // JR +0
+      0x3c, // INC A
+      0x76, // HALT
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    for _ in 0..4 {
+      core.run_interp();
+    }
+    let blocks: Vec<(u16, Engine)> = recent_blocks()
+      .iter()
+      .map(|entry| (entry.address, entry.engine))
+      .collect();
+    assert_eq!(&blocks[blocks.len() - 2..], &[(0x0000, Engine::Interpreted), (0x0004, Engine::Interpreted)]);
+  }
+
+  #[test]
+  fn runtime_jit_switch() {
+    let code = vec![
+      0x3e, 0x05, // LD A, 0x05
+      0x3c, // INC A
+      0x76, // HALT
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.set_jit_enabled(false).unwrap();
+    assert!(!core.is_jit_enabled());
+    match core.set_jit_enabled(true) {
+      Ok(()) => assert!(Core::jit_available() && core.is_jit_enabled()),
+      Err(_) => assert!(!Core::jit_available() && !core.is_jit_enabled()),
+    }
+    // whichever engine is in use, the results are the same
+    while core.run_state == RunState::Run {
+      core.update();
+    }
+    assert_eq!(core.registers.get_af() >> 8, 0x06);
+  }
+  }
+
+  fn break_test_core() -> Core {
+    let code = vec![
+      0x3e, 0x01, // LD A, 0x01
+      0x06, 0x02, // LD B, 0x02
+      0x0e, 0x03, // LD C, 0x03
+      0xea, 0x00, 0xc0, // LD (0xc000), A
+      0x18, 0xfe, // JR -2
+    ];
+    Core::with_code_block(code.into_boxed_slice())
+  }
+
+  fn run_to_breakpoint(core: &mut Core) {
+    core.add_breakpoint(Breakpoint::new(0x04));
+    assert_eq!(core.run_until_break(), BreakEvent::Breakpoint { address: 0x04, bank: Some(0) });
+    assert_eq!(core.registers.get_ip(), 0x04);
+    assert_eq!(core.registers.get_bc(), 0x0200);
+    // resuming runs the instruction at the breakpoint instead of stopping again
+    assert_eq!(core.update(), None);
+    assert_eq!(core.registers.get_bc(), 0x0203);
+    core.update();
+    assert_eq!(memory_read_byte(core.memory.as_ptr(), 0xc000), 0x01);
+  }
+
+  engine_tests! {
+  #[test]
+  fn breakpoint_interpreted() {
+    let mut core = break_test_core();
+    core.set_jit_enabled(false).unwrap();
+    run_to_breakpoint(&mut core);
+  }
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn breakpoint_inside_compiled_block() {
+    let mut core = break_test_core();
+    run_to_breakpoint(&mut core);
+  }
+
+  engine_tests! {
+  #[test]
+  fn step_cycles() {
+    let mut core = break_test_core();
+    // LD A, n takes eight clock cycles, and isn't split in half
+    assert_eq!(core.step_cycles(ClockCycles(4)), None);
+    assert_eq!(core.registers.get_ip(), 0x02);
+    assert_eq!(core.cycles_elapsed(), 2);
+    // which covers the next request too
+    assert_eq!(core.step_cycles(ClockCycles(4)), None);
+    assert_eq!(core.registers.get_ip(), 0x02);
+    assert_eq!(core.cycles_elapsed(), 2);
+    assert_eq!(core.step_cycles(ClockCycles(10)), None);
+    assert_eq!(core.registers.get_ip(), 0x06);
+    assert_eq!(core.cycles_elapsed(), 6);
+
+    core.add_breakpoint(Breakpoint::new(0x09));
+    assert_eq!(core.step_cycles(ClockCycles(100)), Some(BreakEvent::Breakpoint { address: 0x09, bank: Some(0) }));
+    assert_eq!(core.cycles_elapsed(), 10);
+    // resuming runs the instruction at the breakpoint
+    assert_eq!(core.step_cycles(ClockCycles(12)), None);
+    assert_eq!(core.registers.get_ip(), 0x09);
+    assert_eq!(core.cycles_elapsed(), 13);
+  }
+
+  #[test]
+  fn watchpoint_write() {
+    let mut core = break_test_core();
+    core.add_watchpoint(Watchpoint::new(0xc000, WatchKind::Write));
+    let hit = WatchHit { address: 0xc000, value: 0x01, access: Access::Write };
+    assert_eq!(core.run_until_break(), BreakEvent::Watchpoint { ip: 0x06, hit });
+    // the instruction has already completed
+    assert_eq!(core.registers.get_ip(), 0x09);
+    assert!(core.remove_watchpoint(Watchpoint::new(0xc000, WatchKind::Write)));
+    assert!(core.watchpoints().is_empty());
+  }
+  }
+
+  fn traced_core(blocks_only: bool) -> Core {
+    let code = vec![
+      0x3e, 0x01, // LD A, 0x01
+      0x3c, // INC A
+      0x18, 0x00, // JR +0
+      0x76, // HALT
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    let mut tracer = Tracer::ring(16);
+    tracer.blocks_only = blocks_only;
+    core.tracer = Some(tracer);
+    while core.run_state == RunState::Run {
+      core.update();
+    }
+    core
+  }
+
+  engine_tests! {
+  #[test]
+  fn trace_instructions() {
+    // every instruction is traced, even when the JIT is enabled
+    let core = traced_core(false);
+    let entries = core.tracer.as_ref().unwrap().entries();
+    let addresses: Vec<u16> = entries.iter().map(|e| e.address).collect();
+    assert_eq!(addresses, vec![0x00, 0x02, 0x03, 0x05]);
+    assert_eq!(entries[2].registers.get_af() >> 8, 0x02);
+    let cycles: Vec<u64> = entries.iter().map(|e| e.cycles).collect();
+    assert_eq!(cycles, vec![0, 2, 3, 6]);
+    assert!(entries.iter().all(|e| e.engine == Engine::Interpreted));
+  }
+  }
+
+  #[cfg(all(feature = "jit", jit_backend))]
+  #[test]
+  fn trace_compiled_blocks() {
+    let core = traced_core(true);
+    let entries = core.tracer.as_ref().unwrap().entries();
+    let blocks: Vec<(u16, Engine)> = entries.iter().map(|e| (e.address, e.engine)).collect();
+    assert_eq!(blocks, vec![(0x00, Engine::Compiled), (0x05, Engine::Compiled)]);
+  }
+
+  engine_tests! {
+  #[test]
+  fn rom_from_bytes() {
+    // a ROM shorter than its header can't be loaded
+    assert!(Core::from_rom_bytes(vec![0; 0x100]).is_err());
+    let mut rom = vec![0; 0x4000];
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]);
+    // the ROM is mirrored out to the 32KiB its header declares, which is
+    // reported rather than printed
+    let core = Core::from_rom_bytes(rom).unwrap();
+    assert_eq!(core.memory.rom.len(), 0x8000);
+    assert_eq!(core.load_warnings, vec![String::from("ROM file is 16384 bytes, mirroring to 32768 bytes")]);
+    assert_eq!(core.memory.rom[0x4101], 0xc3);
+    let ip = core.registers.ip;
+    assert_eq!(ip, 0x100);
+  }
+
+  #[test]
+  fn unsupported_cart_type() {
+    let mut rom = cartridge_rom(b"MBC5");
+    rom[0x147] = 0x19;
+    let error = Core::from_rom_bytes(rom).err();
+    assert_eq!(error, Some(Error::Rom(RomError::UnsupportedCartType(0x19))));
+  }
+  }
+
+  /// A ROM with an MBC1, 8KiB of cartridge RAM, and the given title, which
+  /// loops forever at 0x150
+  fn cartridge_rom(title: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]);
+    rom[0x150..0x152].copy_from_slice(&[0x18, 0xfe]);
+    rom[0x134..0x134 + title.len()].copy_from_slice(title);
+    rom[0x147] = 0x03;
+    rom[0x149] = 0x02;
+    rom
+  }
+
+  engine_tests! {
+  #[test]
+  fn mbc1_multicart() {
+    use crate::system::{read_header_from_bytes, RomSizePolicy};
+
+    let mut rom = vec![0; 0x100000];
+    rom[..0x8000].copy_from_slice(&cartridge_rom(b"MENU"));
+    rom[0x147] = 0x01;
+    rom[0x148] = 0x05;
+    for (i, byte) in rom[0x104..0x134].iter_mut().enumerate() {
+      *byte = i as u8;
+    }
+    rom[0x150..0x153].copy_from_slice(&[0xc3, 0x80, 0xff]);
+    // the second game starts with its own header, in bank 0x10
+    let logo = rom[0x100..0x150].to_vec();
+    rom[0x40100..0x40150].copy_from_slice(&logo);
+    rom[0x40150..0x40154].copy_from_slice(&[0x06, 0x42, 0x18, 0xfe]);
+    rom[0x44000] = 0x11;
+    let header = read_header_from_bytes(&rom).unwrap();
+    let mut core = Core::from_rom_data(rom, header, RomSizePolicy::Header, None).unwrap();
+    assert!(core.memory.is_low_rom_banked());
+
+    // the menu switches games from high RAM, then jumps to the new one
+    let trampoline = [
+      0x3e, 0x01, // LD A, 1
+      0xea, 0x00, 0x60, // LD (0x6000), A
+      0xea, 0x00, 0x40, // LD (0x4000), A
+      0xc3, 0x00, 0x01, // JP 0x0100
+    ];
+    core.memory.high_ram[..trampoline.len()].copy_from_slice(&trampoline);
+    for _ in 0..8 {
+      core.run_code_block();
+    }
+    assert_eq!(core.registers.get_bc() >> 8, 0x42);
+    assert_eq!(core.memory.get_low_rom_bank(), 0x10);
+    assert_eq!(memory_peek_byte(&core.memory, 0x4000), 0x11);
+  }
+
+  #[test]
+  fn hard_reset() {
+    use crate::devices::video::palette::Palette;
+    use crate::system::{read_header_from_bytes, RomSizePolicy};
+
+    let rom = cartridge_rom(b"RESET");
+    let header = read_header_from_bytes(&rom).unwrap();
+    let mut core = Core::from_rom_data(rom.clone(), header, RomSizePolicy::Header, None).unwrap();
+    let pocket = Palette::parse("pocket").unwrap();
+    core.memory.io.video.set_palette(pocket);
+    core.memory.work_ram[0] = 0x42;
+    core.memory.cart_ram[0] = 0x99;
+    core.memory.cart_state.write_rom(0x2000, 3);
+    core.registers.ip = 0x1234;
+    core.run_state = RunState::Locked;
+    core.reset().unwrap();
+    let ip = core.registers.ip;
+    assert_eq!(ip, 0x100);
+    assert_eq!(core.run_state, RunState::Run);
+    assert_eq!(core.memory.work_ram[0], 0);
+    assert_eq!(core.memory.get_rom_bank(), 1);
+    // battery-backed RAM survives, and so do the emulator's settings
+    assert_eq!(core.memory.cart_ram[0], 0x99);
+    assert_eq!(*core.memory.io.video.get_palette(), pocket);
+
+    // a core started from a boot ROM runs it again
+    let header = read_header_from_bytes(&rom).unwrap();
+    let boot_rom = vec![0; 0x100].into_boxed_slice();
+    let mut core = Core::from_rom_data(rom, header, RomSizePolicy::Header, Some(boot_rom)).unwrap();
+    memory_write_byte(&mut core.memory, 0xff50, 1);
+    assert!(!core.memory.is_boot_rom_mapped());
+    core.registers.ip = 0x150;
+    core.reset().unwrap();
+    assert!(core.memory.is_boot_rom_mapped());
+    let ip = core.registers.ip;
+    assert_eq!(ip, 0);
+  }
+
+  #[test]
+  fn replace_game() {
+    use crate::input::Turbo;
+
+    let mut core = Core::from_rom_bytes(cartridge_rom(b"FIRST")).unwrap();
+    core.input.set_turbo(Button::A, Some(Turbo::default()));
+    core.rewind = Some(RewindBuffer::default());
+    core.run_frame();
+    let next = Core::from_rom_bytes(cartridge_rom(b"SECOND")).unwrap();
+    core.replace_game(next).unwrap();
+    assert_eq!(&core.memory.rom[0x134..0x13a], b"SECOND");
+    assert_eq!(core.cycles_elapsed(), 0);
+    assert!(core.input.get_turbo(Button::A).is_some());
+    assert!(core.rewind.is_some());
+  }
+
+  #[test]
+  fn cheat_codes() {
+    let mut rom = cartridge_rom(b"CHEATS");
+    rom[0x150..0x157].copy_from_slice(&[
+      0x3e, 0x11, // LD A, 0x11
+      0xea, 0x00, 0xc0, // LD (0xc000), A
+      0x18, 0xf9, // JR -7
+    ]);
+    let mut core = Core::from_rom_bytes(rom).unwrap();
+    core.run_frame();
+    assert_eq!(core.memory.work_ram[0], 0x11);
+    // code already compiled from the patched byte is thrown away
+    core.add_cheat("221-51F").unwrap();
+    core.add_cheat("013301c0").unwrap();
+    core.run_frame();
+    assert_eq!(core.memory.work_ram[0], 0x22);
+    assert_eq!(core.memory.work_ram[1], 0x33);
+    assert_eq!(core.cheat_codes(), vec!["221-51F", "013301C0"]);
+    assert!(core.remove_cheat("221-51f"));
+    assert!(!core.remove_cheat("221-51f"));
+    core.run_frame();
+    assert_eq!(core.memory.work_ram[0], 0x11);
+  }
+  }
 }
@@ -0,0 +1,135 @@
+//! Errors returned when loading a ROM or setting up the core, so that
+//! embedders can tell failures apart instead of matching on messages. Each
+//! error displays the same message the emulator prints for it, and converts
+//! into a `String` for code that only needs the message.
+
+use alloc::string::{String, ToString};
+use core::fmt;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+  Rom(RomError),
+  Mem(MemError),
+  Jit(JitError),
+}
+
+/// A ROM couldn't be opened, read, or used
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RomError {
+  /// The file couldn't be opened or read, with a description of which step
+  /// failed
+  Unreadable(String),
+  /// The file is too short to hold a header
+  TooShort,
+  InvalidChecksum,
+  /// The file's length doesn't match the size in its header, and the size
+  /// policy doesn't allow fixing it
+  SizeMismatch { file_size: usize, declared_size: usize },
+  /// The file couldn't be mapped into memory
+  MapFailed,
+  /// An archive or patch holding the ROM couldn't be used, with the reason
+  Invalid(String),
+  /// The header names a memory bank controller that isn't emulated
+  UnsupportedCartType(u8),
+}
+
+/// The CPU tried to fetch code from memory that can't provide it
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MemError {
+  NotExecutable(u16),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum JitError {
+  /// There is no backend for this architecture
+  Unavailable,
+  /// A block couldn't be compiled from this address
+  Untranslatable(u16),
+  /// Memory for compiled code couldn't be allocated, or its protection
+  /// couldn't be changed
+  NoExecutableMemory,
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::Rom(error) => error.fmt(f),
+      Error::Mem(error) => error.fmt(f),
+      Error::Jit(error) => error.fmt(f),
+    }
+  }
+}
+
+impl fmt::Display for RomError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RomError::Unreadable(message) | RomError::Invalid(message) => write!(f, "{}", message),
+      RomError::TooShort => write!(f, "File too short. Are you sure this is a ROM file?"),
+      RomError::InvalidChecksum => write!(f, "ROM file is corrupt: invalid header checksum"),
+      RomError::SizeMismatch { file_size, declared_size } => {
+        write!(f, "ROM file is {} bytes, but its header declares {} bytes", file_size, declared_size)
+      },
+      RomError::MapFailed => write!(f, "Unable to map ROM file into memory"),
+      RomError::UnsupportedCartType(cart_type) => write!(f, "Unsupported cartridge type {:02X}", cart_type),
+    }
+  }
+}
+
+impl fmt::Display for MemError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      MemError::NotExecutable(address) => write!(f, "Code can't be fetched from {:04X}", address),
+    }
+  }
+}
+
+impl fmt::Display for JitError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      JitError::Unavailable => write!(f, "The JIT is not supported on this architecture"),
+      JitError::Untranslatable(address) => write!(f, "Unable to compile the block at {:04X}", address),
+      JitError::NoExecutableMemory => write!(f, "Unable to set up executable memory for the JIT"),
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+impl From<RomError> for Error {
+  fn from(error: RomError) -> Self {
+    Error::Rom(error)
+  }
+}
+
+impl From<MemError> for Error {
+  fn from(error: MemError) -> Self {
+    Error::Mem(error)
+  }
+}
+
+impl From<JitError> for Error {
+  fn from(error: JitError) -> Self {
+    Error::Jit(error)
+  }
+}
+
+impl From<Error> for String {
+  fn from(error: Error) -> Self {
+    error.to_string()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Error, JitError, RomError};
+  use alloc::string::String;
+
+  #[test]
+  fn messages() {
+    let error = Error::from(RomError::SizeMismatch { file_size: 0x7000, declared_size: 0x8000 });
+    assert_eq!(String::from(error), "ROM file is 28672 bytes, but its header declares 32768 bytes");
+    let error = Error::from(JitError::Unavailable);
+    assert!(matches!(error, Error::Jit(JitError::Unavailable)));
+  }
+}
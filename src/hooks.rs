@@ -0,0 +1,166 @@
+//! Callbacks for tools built on top of the core, like cheat engines,
+//! auto-splitters, and research scripts. Hooks run either at the end of every
+//! frame, or after an instruction reads or writes a chosen range of memory.
+//! Each one is given the core itself, so it can inspect or change anything.
+//!
+//! Memory hooks are checked by the memory bus the same way as watchpoints,
+//! so blocks are interpreted one instruction at a time while any are set.
+
+use crate::debug::watchpoint::{WatchHit, Watchpoint};
+use crate::emulator::Core;
+
+pub type FrameHook = Box<dyn FnMut(&mut Core) + Send>;
+pub type MemoryHook = Box<dyn FnMut(&mut Core, WatchHit) + Send>;
+
+/// Identifies a registered hook, so that it can be removed later
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct HookId(u32);
+
+#[derive(Default)]
+pub struct Hooks {
+  next_id: u32,
+  frame: Vec<(HookId, FrameHook)>,
+  memory: Vec<(HookId, Watchpoint, MemoryHook)>,
+  /// Hooks that have been taken out to be run
+  running: Vec<HookId>,
+  /// Running hooks that were removed, which are dropped once they finish
+  removed: Vec<HookId>,
+}
+
+impl Hooks {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  fn allocate_id(&mut self) -> HookId {
+    let id = HookId(self.next_id);
+    self.next_id += 1;
+    id
+  }
+
+  pub fn add_frame_hook(&mut self, hook: FrameHook) -> HookId {
+    let id = self.allocate_id();
+    self.frame.push((id, hook));
+    id
+  }
+
+  pub fn add_memory_hook(&mut self, range: Watchpoint, hook: MemoryHook) -> HookId {
+    let id = self.allocate_id();
+    self.memory.push((id, range, hook));
+    id
+  }
+
+  /// Returns true if the hook was registered
+  pub fn remove(&mut self, id: HookId) -> bool {
+    let previous_length = self.frame.len() + self.memory.len();
+    self.frame.retain(|(hook_id, _)| *hook_id != id);
+    self.memory.retain(|(hook_id, _, _)| *hook_id != id);
+    if self.frame.len() + self.memory.len() != previous_length {
+      return true;
+    }
+    if self.running.contains(&id) && !self.removed.contains(&id) {
+      self.removed.push(id);
+      return true;
+    }
+    false
+  }
+
+  pub fn has_frame_hooks(&self) -> bool {
+    !self.frame.is_empty()
+  }
+
+  /// The ranges the memory bus needs to log accesses to
+  pub fn memory_ranges(&self) -> Vec<Watchpoint> {
+    self.memory.iter().map(|(_, range, _)| *range).collect()
+  }
+
+  pub fn frame_hooks_mut(&mut self) -> impl Iterator<Item = &mut FrameHook> {
+    self.frame.iter_mut().map(|(_, hook)| hook)
+  }
+
+  /// Memory hooks whose range includes an access
+  pub fn memory_hooks_for(&mut self, hit: WatchHit) -> impl Iterator<Item = &mut MemoryHook> {
+    self.memory
+      .iter_mut()
+      .filter(move |(_, range, _)| range.matches(hit.address, hit.access))
+      .map(|(_, _, hook)| hook)
+  }
+
+  /// Take out every hook so that they can be given the core, leaving behind
+  /// an empty set that records hooks added or removed in the meantime
+  pub fn take_running(&mut self) -> Hooks {
+    let running = std::mem::take(self);
+    self.next_id = running.next_id;
+    self.running = running.frame.iter().map(|(id, _)| *id)
+      .chain(running.memory.iter().map(|(id, _, _)| *id))
+      .collect();
+    running
+  }
+
+  /// Put back hooks taken out by `take_running`
+  pub fn restore(&mut self, mut running: Hooks) {
+    running.frame.append(&mut self.frame);
+    running.memory.append(&mut self.memory);
+    running.next_id = self.next_id;
+    let removed = std::mem::take(&mut self.removed);
+    running.frame.retain(|(id, _)| !removed.contains(id));
+    running.memory.retain(|(id, _, _)| !removed.contains(id));
+    *self = running;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::debug::watchpoint::{Access, WatchKind, Watchpoint};
+  use crate::emulator::Core;
+  use std::sync::{Arc, Mutex};
+
+  #[test]
+  fn frame_hooks() {
+    // JR -2
+    let mut core = Core::with_code_block(vec![0x18, 0xfe].into_boxed_slice());
+    let frames = Arc::new(Mutex::new(0));
+    let counter = frames.clone();
+    let id = core.on_frame(move |_| *counter.lock().unwrap() += 1);
+    core.run_frame();
+    core.run_frame();
+    assert_eq!(*frames.lock().unwrap(), 2);
+    assert!(core.remove_hook(id));
+    assert!(!core.remove_hook(id));
+    core.run_frame();
+    assert_eq!(*frames.lock().unwrap(), 2);
+  }
+
+  #[test]
+  fn memory_hooks() {
+    let code = vec![
+      0x3e, 0x05, // LD A, 0x05
+      0xea, 0x00, 0xc0, // LD (0xc000), A
+      0xfa, 0x00, 0xc0, // LD A, (0xc000)
+      0x3c, // INC A
+      0xea, 0x01, 0xc0, // LD (0xc001), A
+      0x18, 0xfe, // JR -2
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    let accesses = Arc::new(Mutex::new(Vec::new()));
+    let log = accesses.clone();
+    core.on_memory_access(Watchpoint::new(0xc000, WatchKind::ReadWrite), move |_, hit| {
+      log.lock().unwrap().push((hit.access, hit.value));
+    });
+    // a hook can change memory before the game reads it again, and remove
+    // itself
+    let id = Arc::new(Mutex::new(None));
+    let own_id = id.clone();
+    let hook = core.on_memory_access(Watchpoint::new(0xc000, WatchKind::Write), move |core, _| {
+      core.memory.work_ram[0] = 0x40;
+      core.remove_hook(own_id.lock().unwrap().unwrap());
+    });
+    *id.lock().unwrap() = Some(hook);
+    for _ in 0..5 {
+      assert!(core.update().is_none());
+    }
+    assert_eq!(*accesses.lock().unwrap(), vec![(Access::Write, 0x05), (Access::Read, 0x40)]);
+    assert_eq!(core.memory.work_ram[1], 0x41);
+    assert!(!core.remove_hook(hook));
+  }
+}
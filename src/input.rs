@@ -0,0 +1,371 @@
+//! Input dispatch, between the host and the joypad. Key events from the shell
+//! only update the set of buttons held on the host. Once per frame, those are
+//! combined with turbo and macro playback into the buttons the game sees, and
+//! only that result is committed to the joypad. Anything recorded at the
+//! commit point sees turbo and macro input exactly as the game did.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::devices::joypad::{Button, Joypad};
+
+/// Number of macros that can be recorded at once
+pub const MACRO_SLOTS: usize = 4;
+
+fn button_index(button: Button) -> usize {
+  match button {
+    Button::A => 0,
+    Button::B => 1,
+    Button::Select => 2,
+    Button::Start => 3,
+    Button::Right => 4,
+    Button::Left => 5,
+    Button::Up => 6,
+    Button::Down => 7,
+  }
+}
+
+/// A set of pressed buttons, one bit per button
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ButtonSet(u8);
+
+impl ButtonSet {
+  pub fn empty() -> Self {
+    Self(0)
+  }
+
+  pub fn contains(&self, button: Button) -> bool {
+    self.0 & (1 << button_index(button)) != 0
+  }
+
+  pub fn insert(&mut self, button: Button) {
+    self.0 |= 1 << button_index(button);
+  }
+
+  pub fn remove(&mut self, button: Button) {
+    self.0 &= !(1 << button_index(button));
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0 == 0
+  }
+
+  /// One bit per button, in the order A, B, Select, Start, Right, Left, Up,
+  /// Down from the lowest bit
+  pub fn bits(&self) -> u8 {
+    self.0
+  }
+
+  pub fn from_bits(bits: u8) -> Self {
+    Self(bits)
+  }
+}
+
+impl core::ops::BitOr for ButtonSet {
+  type Output = Self;
+
+  fn bitor(self, rhs: Self) -> Self::Output {
+    ButtonSet(self.0 | rhs.0)
+  }
+}
+
+/// While a turbo button is held, it is repeatedly pressed and released. Each
+/// cycle lasts `period` frames, and the button is down for the first
+/// `pressed` frames of it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Turbo {
+  pressed: u8,
+  period: u8,
+}
+
+impl Turbo {
+  pub fn new(pressed: u8, period: u8) -> Result<Self, String> {
+    if pressed == 0 || pressed >= period {
+      return Err(format!("Invalid turbo duty cycle {}/{}", pressed, period));
+    }
+    Ok(Self { pressed, period })
+  }
+
+  /// Parse a duty cycle written as `<pressed>/<period>`, like `2/4`
+  pub fn parse(text: &str) -> Result<Self, String> {
+    let invalid = || format!("Invalid turbo duty cycle \"{}\"", text);
+    let (pressed, period) = text.split_once('/').ok_or_else(invalid)?;
+    let pressed = pressed.trim().parse::<u8>().map_err(|_| invalid())?;
+    let period = period.trim().parse::<u8>().map_err(|_| invalid())?;
+    Self::new(pressed, period)
+  }
+
+  fn is_pressed(&self, frames_held: usize) -> bool {
+    frames_held % (self.period as usize) < self.pressed as usize
+  }
+}
+
+impl Default for Turbo {
+  /// Two frames down, two frames up. Games that only poll the joypad every
+  /// other frame can miss a single-frame press.
+  fn default() -> Self {
+    Self { pressed: 2, period: 4 }
+  }
+}
+
+/// A recorded sequence of inputs, one entry per frame
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct InputMacro {
+  pub frames: Vec<ButtonSet>,
+}
+
+pub struct InputDispatcher {
+  /// Buttons currently held on the host
+  held: ButtonSet,
+  /// Turbo settings for each button, indexed by `button_index`
+  turbo: [Option<Turbo>; 8],
+  /// Number of frames each button has been held, to keep turbo in phase
+  frames_held: [usize; 8],
+  macros: [Option<InputMacro>; MACRO_SLOTS],
+  /// Slot being recorded into, and the frames recorded so far
+  recording: Option<(usize, Vec<ButtonSet>)>,
+  /// Macro being played back, and the next frame to play
+  playback: Option<(InputMacro, usize)>,
+  /// Buttons last committed to the joypad
+  committed: ButtonSet,
+}
+
+impl InputDispatcher {
+  pub fn new() -> Self {
+    Self {
+      held: ButtonSet::empty(),
+      turbo: [None; 8],
+      frames_held: [0; 8],
+      macros: Default::default(),
+      recording: None,
+      playback: None,
+      committed: ButtonSet::empty(),
+    }
+  }
+
+  pub fn press(&mut self, button: Button) {
+    self.held.insert(button);
+  }
+
+  pub fn release(&mut self, button: Button) {
+    self.held.remove(button);
+  }
+
+  pub fn get_turbo(&self, button: Button) -> Option<Turbo> {
+    self.turbo[button_index(button)]
+  }
+
+  /// Make a button fire repeatedly while held, or remove its turbo with None
+  pub fn set_turbo(&mut self, button: Button, turbo: Option<Turbo>) {
+    self.turbo[button_index(button)] = turbo;
+  }
+
+  pub fn is_recording(&self) -> bool {
+    self.recording.is_some()
+  }
+
+  pub fn is_playing(&self) -> bool {
+    self.playback.is_some()
+  }
+
+  /// Start recording into a slot. If that slot is already being recorded,
+  /// recording stops instead, and the number of frames recorded is returned.
+  /// Starting a recording in another slot discards the current one.
+  pub fn toggle_recording(&mut self, slot: usize) -> Option<usize> {
+    assert!(slot < MACRO_SLOTS, "Invalid macro slot {}", slot);
+    match self.recording.take() {
+      Some((current, frames)) if current == slot => {
+        let length = frames.len();
+        self.macros[slot] = Some(InputMacro { frames });
+        Some(length)
+      },
+      _ => {
+        self.recording = Some((slot, Vec::new()));
+        None
+      },
+    }
+  }
+
+  pub fn get_macro(&self, slot: usize) -> Option<&InputMacro> {
+    self.macros.get(slot)?.as_ref()
+  }
+
+  pub fn set_macro(&mut self, slot: usize, input_macro: InputMacro) {
+    self.macros[slot] = Some(input_macro);
+  }
+
+  /// Begin playing a recorded macro from the next frame. Its inputs are
+  /// combined with whatever is held on the host. Returns false if nothing
+  /// has been recorded in the slot.
+  pub fn play_macro(&mut self, slot: usize) -> bool {
+    match self.get_macro(slot) {
+      Some(input_macro) if !input_macro.frames.is_empty() => {
+        self.playback = Some((input_macro.clone(), 0));
+        true
+      },
+      _ => false,
+    }
+  }
+
+  /// Advance one frame, and return the buttons pressed during it
+  pub fn next_frame(&mut self) -> ButtonSet {
+    let mut buttons = ButtonSet::empty();
+    for button in Button::ALL {
+      let index = button_index(button);
+      if !self.held.contains(button) {
+        self.frames_held[index] = 0;
+        continue;
+      }
+      let pressed = match self.turbo[index] {
+        Some(turbo) => turbo.is_pressed(self.frames_held[index]),
+        None => true,
+      };
+      self.frames_held[index] += 1;
+      if pressed {
+        buttons.insert(button);
+      }
+    }
+
+    let finished = match self.playback.as_mut() {
+      Some((input_macro, position)) => {
+        buttons = buttons | input_macro.frames[*position];
+        *position += 1;
+        *position >= input_macro.frames.len()
+      },
+      None => false,
+    };
+    if finished {
+      self.playback = None;
+    }
+
+    if let Some((_, frames)) = self.recording.as_mut() {
+      frames.push(buttons);
+    }
+    buttons
+  }
+
+  /// Advance one frame, and apply any changed buttons to the joypad. Input
+  /// still advances when the built-in joypad was replaced.
+  pub fn commit_frame(&mut self, joypad: Option<&mut Joypad>) {
+    let buttons = self.next_frame();
+    self.commit_buttons(joypad, buttons);
+  }
+
+  /// Apply buttons decided elsewhere, like a remote player's input, to the
+  /// joypad without advancing the host's input
+  pub fn commit_buttons(&mut self, joypad: Option<&mut Joypad>, buttons: ButtonSet) {
+    if let Some(joypad) = joypad {
+      for button in Button::ALL {
+        match (self.committed.contains(button), buttons.contains(button)) {
+          (false, true) => joypad.press_button(button),
+          (true, false) => joypad.release_button(button),
+          _ => (),
+        }
+      }
+    }
+    self.committed = buttons;
+  }
+}
+
+impl Default for InputDispatcher {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{ButtonSet, InputDispatcher, InputMacro, Turbo};
+  use crate::devices::joypad::{Button, Joypad};
+  use alloc::vec;
+  use alloc::vec::Vec;
+
+  fn set(buttons: &[Button]) -> ButtonSet {
+    let mut set = ButtonSet::empty();
+    for button in buttons {
+      set.insert(*button);
+    }
+    set
+  }
+
+  #[test]
+  fn held_buttons() {
+    let mut input = InputDispatcher::new();
+    input.press(Button::A);
+    input.press(Button::Up);
+    assert_eq!(input.next_frame(), set(&[Button::A, Button::Up]));
+    input.release(Button::A);
+    assert_eq!(input.next_frame(), set(&[Button::Up]));
+  }
+
+  #[test]
+  fn parse_turbo() {
+    assert_eq!(Turbo::parse("1/3"), Turbo::new(1, 3));
+    assert!(Turbo::parse("3/3").is_err());
+    assert!(Turbo::parse("0/2").is_err());
+    assert!(Turbo::parse("2").is_err());
+  }
+
+  #[test]
+  fn turbo_duty_cycle() {
+    let mut input = InputDispatcher::new();
+    input.set_turbo(Button::B, Some(Turbo::new(1, 3).unwrap()));
+    input.press(Button::B);
+    input.press(Button::Right);
+    let frames: Vec<bool> = (0..6).map(|_| input.next_frame().contains(Button::B)).collect();
+    assert_eq!(frames, vec![true, false, false, true, false, false]);
+    assert!(input.next_frame().contains(Button::Right));
+
+    // releasing the button restarts the cycle
+    input.release(Button::B);
+    assert!(!input.next_frame().contains(Button::B));
+    input.press(Button::B);
+    assert!(input.next_frame().contains(Button::B));
+  }
+
+  #[test]
+  fn record_and_play_macro() {
+    let mut input = InputDispatcher::new();
+    assert!(!input.play_macro(0));
+    assert_eq!(input.toggle_recording(0), None);
+    input.press(Button::Down);
+    input.next_frame();
+    input.release(Button::Down);
+    input.press(Button::A);
+    input.next_frame();
+    input.release(Button::A);
+    input.next_frame();
+    assert_eq!(input.toggle_recording(0), Some(3));
+    assert_eq!(
+      input.get_macro(0),
+      Some(&InputMacro { frames: vec![set(&[Button::Down]), set(&[Button::A]), ButtonSet::empty()] }),
+    );
+
+    assert!(input.play_macro(0));
+    input.press(Button::Left);
+    assert_eq!(input.next_frame(), set(&[Button::Down, Button::Left]));
+    assert_eq!(input.next_frame(), set(&[Button::A, Button::Left]));
+    assert_eq!(input.next_frame(), set(&[Button::Left]));
+    assert!(!input.is_playing());
+  }
+
+  #[test]
+  fn commits_to_joypad() {
+    let mut input = InputDispatcher::new();
+    let mut joypad = Joypad::new();
+    // select the action buttons
+    joypad.set_value(0x10);
+    input.set_turbo(Button::A, Some(Turbo::new(1, 2).unwrap()));
+    input.press(Button::A);
+    input.press(Button::Start);
+    // pressing a key has no effect until the next frame is committed
+    assert_eq!(joypad.get_value() & 0x0f, 0x0f);
+    input.commit_frame(Some(&mut joypad));
+    assert_eq!(joypad.get_value() & 0x0f, 0x06);
+    input.commit_frame(Some(&mut joypad));
+    assert_eq!(joypad.get_value() & 0x0f, 0x07);
+    input.release(Button::Start);
+    input.commit_frame(Some(&mut joypad));
+    assert_eq!(joypad.get_value() & 0x0f, 0x0e);
+  }
+}
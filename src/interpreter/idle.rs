@@ -0,0 +1,128 @@
+use crate::decoder::decode;
+use crate::decoder::ops::{JumpCondition, Op, Register8};
+use crate::mem::{get_executable_memory_slice, MemoryAreas};
+
+/// The longest polling loop body that will be recognized, in instructions
+pub const MAX_LOOP_OPS: usize = 4;
+
+/// A loop that makes no observable progress until some external event occurs
+#[derive(Debug, Eq, PartialEq)]
+pub enum IdleLoop {
+  /// A jump to itself. Only an interrupt can break out of it.
+  Spin {
+    cycles: usize,
+  },
+  /// Repeatedly loads a single memory location into A, tests it, and loops
+  /// back. Since nothing is written, each iteration behaves identically until
+  /// the polled value changes or an interrupt fires.
+  Poll {
+    address: u16,
+    cycles: usize,
+  },
+}
+
+impl IdleLoop {
+  /// Machine cycles taken by a single iteration of the loop
+  pub fn get_cycles(&self) -> usize {
+    match self {
+      IdleLoop::Spin { cycles } => *cycles,
+      IdleLoop::Poll { cycles, .. } => *cycles,
+    }
+  }
+}
+
+/// Determine whether the code at `ip` is the start of an idle loop.
+pub fn detect_idle_loop(ip: u16, mem: *const MemoryAreas) -> Option<IdleLoop> {
+  let start = ip as usize;
+  let mut offset = 0;
+  let mut cycles = 0;
+  let mut polled_address = None;
+
+  for index in 0..MAX_LOOP_OPS {
+    let code = get_idle_candidate_slice(start + offset, mem)?;
+    if code.len() < 3 {
+      return None;
+    }
+    let (op, length, op_cycles) = decode(code);
+    offset += length;
+    cycles += op_cycles / 4;
+
+    match op {
+      Op::JumpRelative(cond, relative) => {
+        if start as isize != (start + offset) as isize + relative as isize {
+          return None;
+        }
+        // The branch is taken on every iteration that stays in the loop
+        cycles += 1;
+        return match (cond, polled_address) {
+          (JumpCondition::Always, None) if index == 0 => Some(IdleLoop::Spin { cycles }),
+          (JumpCondition::Always, _) => None,
+          (_, Some(address)) => Some(IdleLoop::Poll { address, cycles }),
+          (_, None) => None,
+        };
+      },
+      Op::LoadAFromMemory(address, _) if index == 0 => {
+        polled_address = Some(address);
+      },
+      // Tests that only modify A and the flags
+      Op::AndAbsolute8(_)
+        | Op::OrAbsolute8(_)
+        | Op::XorAbsolute8(_)
+        | Op::CompareAbsolute8(_)
+        | Op::BitTest(Register8::A, _) if polled_address.is_some() => (),
+      _ => return None,
+    }
+  }
+  None
+}
+
+/// Idle loops are only detected in memory regions the interpreter can execute
+fn get_idle_candidate_slice<'s>(start: usize, mem: *const MemoryAreas) -> Option<&'s [u8]> {
+  match start {
+    0x0000..=0x7fff | 0xc000..=0xfdff | 0xff80..=0xfffe => get_executable_memory_slice(start, mem).ok(),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{detect_idle_loop, IdleLoop};
+  use crate::mem::MemoryAreas;
+  use alloc::vec;
+  use alloc::vec::Vec;
+
+  fn detect(code: Vec<u8>) -> Option<IdleLoop> {
+    let mem = MemoryAreas::with_rom(code.into_boxed_slice());
+    detect_idle_loop(0, &mem as *const MemoryAreas)
+  }
+
+  #[test]
+  fn spin_loop() {
+    assert_eq!(detect(vec![0x18, 0xfe]), Some(IdleLoop::Spin { cycles: 3 }));
+  }
+
+  #[test]
+  fn ly_poll() {
+    let code = vec![
+      0xf0, 0x44, // LDH A, (0x44)
+      0xfe, 0x90, // CP 0x90
+      0x20, 0xfa, // JR NZ, -6
+    ];
+    assert_eq!(detect(code), Some(IdleLoop::Poll { address: 0xff44, cycles: 8 }));
+  }
+
+  #[test]
+  fn loops_with_side_effects() {
+    let code = vec![
+      0xf0, 0x44, // LDH A, (0x44)
+      0x3c, // INC A
+      0x20, 0xfb, // JR NZ, -5
+    ];
+    assert_eq!(detect(code), None);
+    let code = vec![
+      0x00, // NOP
+      0x18, 0xfd, // JR -3
+    ];
+    assert_eq!(detect(code), None);
+  }
+}
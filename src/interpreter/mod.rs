@@ -1,7 +1,9 @@
+pub mod idle;
+
 use crate::decoder::decode;
 use crate::decoder::ops::{Op, Register8, Register16, IndirectLocation, JumpCondition};
 use crate::cpu::{Registers, self};
-use crate::mem::{get_executable_memory_slice, memory_read_byte, memory_write_byte, memory_write_word, MemoryAreas};
+use crate::mem::{get_executable_memory_slice, memory_read_byte, memory_write_byte, memory_write_word, read_straddling_instruction, MemoryAreas};
 
 pub fn run_code_block(registers: &mut Registers, mem: *mut MemoryAreas) -> u8 {
   let mut status = cpu::STATUS_NORMAL;
@@ -38,12 +40,23 @@ pub fn run_code_block(registers: &mut Registers, mem: *mut MemoryAreas) -> u8 {
 
 pub fn run_next_op(registers: &mut Registers, mem: *mut MemoryAreas) -> Option<(u8, bool)> {
   let index = registers.ip as usize;
-  let code_slice = get_executable_memory_slice(index, mem);
-  if code_slice.len() < 1 {
-    return None;
-  }
+  // Anywhere without code to slice, like VRAM or IO registers, the CPU
+  // still fetches whatever the bus returns
+  let straddling;
+  let code_slice = match get_executable_memory_slice(index, mem) {
+    Ok(code_slice) if code_slice.len() >= 3 => code_slice,
+    Ok([]) => return None,
+    _ => {
+      straddling = read_straddling_instruction(index, mem);
+      &straddling[..]
+    },
+  };
   let (next_op, length, cycles) = decode(code_slice);
   let should_break = next_op.is_block_end();
+  #[cfg(feature = "strict_io")]
+  unsafe { (*mem).strict_io.set_ip(index as u16) };
+  #[cfg(feature = "debug_freeze")]
+  unsafe { (*mem).freeze.set_ip(index as u16) };
   let status = run_op(next_op, registers, mem, length as u32);
   registers.cycles += (cycles / 4) as u32;
 
@@ -143,7 +156,8 @@ pub fn run_op(op: Op, registers: &mut Registers, mem: *mut MemoryAreas, length:
     Op::ReturnFromInterrupt => interp_reti(registers, mem),
 
     Op::Stop => {
-      registers.ip += 1;
+      // STOP is followed by a padding byte, which is skipped
+      registers.ip += 2;
       cpu::STATUS_STOP
     },
     Op::Halt => {
@@ -159,7 +173,8 @@ pub fn run_op(op: Op, registers: &mut Registers, mem: *mut MemoryAreas, length:
       cpu::STATUS_INTERRUPT_DISABLE
     },
 
-    Op::Invalid(code) => panic!("Invalid OP: {:#04x}", code),
+    // the CPU hangs on the invalid opcode, without advancing past it
+    Op::Invalid(_) => cpu::STATUS_LOCKED,
   }
 }
 
@@ -401,8 +416,8 @@ fn interp_load_to_indirect(location: IndirectLocation, reg: Register8, registers
   let address = get_register_16(registers, address_register);
   memory_write_byte(mem, address, value);
   match location {
-    IndirectLocation::HLIncrement => registers.hl = registers.hl.wrapping_add(1),
-    IndirectLocation::HLDecrement => registers.hl = registers.hl.wrapping_sub(1),
+    IndirectLocation::HLIncrement => registers.hl = (registers.hl as u16).wrapping_add(1) as u32,
+    IndirectLocation::HLDecrement => registers.hl = (registers.hl as u16).wrapping_sub(1) as u32,
     _ => (),
   }
   registers.ip += length;
@@ -428,8 +443,8 @@ fn interp_load_from_indirect(reg: Register8, location: IndirectLocation, registe
   let value = memory_read_byte(mem, address);
   set_register(registers, reg, value);
   match location {
-    IndirectLocation::HLIncrement => registers.hl = registers.hl.wrapping_add(1),
-    IndirectLocation::HLDecrement => registers.hl = registers.hl.wrapping_sub(1),
+    IndirectLocation::HLIncrement => registers.hl = (registers.hl as u16).wrapping_add(1) as u32,
+    IndirectLocation::HLDecrement => registers.hl = (registers.hl as u16).wrapping_sub(1) as u32,
     _ => (),
   }
   registers.ip += length;
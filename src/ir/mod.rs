@@ -0,0 +1,148 @@
+//! Intermediate representation of a block, between the decoder and the
+//! emitter. Decoded ops are gathered into a `Block`, which can be rewritten by
+//! optimization passes before any machine code is emitted. Each op also
+//! records which flags are still needed after it, so the emitter can skip
+//! materializing the rest.
+
+#[cfg(feature = "optimizer")]
+pub mod optimize;
+
+use crate::decoder::ops::{Op, Register16};
+use crate::emitter::flags;
+
+pub enum Instruction {
+  /// A decoded op, emitted exactly as the naive path would
+  Single(Op),
+  /// Consecutive BIT n,(HL) ops that share a single memory read. Each entry
+  /// is the mask and length of one op.
+  BitTestSequence(Vec<(u8, usize)>),
+  /// Two 8-bit loads that fill both halves of a register pair
+  LoadPair(Register16, PairSource),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PairSource {
+  Immediate(u16),
+  Register(Register16),
+}
+
+pub struct IrOp {
+  pub instruction: Instruction,
+  /// Number of bytes of GB code this op replaces, which is added to the IP
+  pub length: usize,
+  /// Flags that may be read before they are next overwritten
+  pub live_flags: u8,
+}
+
+pub struct Block {
+  pub ops: Vec<IrOp>,
+  /// When set, ops before the last one leave the IP unchanged, and their
+  /// combined length is added just before the last op runs
+  pub collapse_ip: bool,
+}
+
+impl Instruction {
+  fn flags_read(&self) -> u8 {
+    match self {
+      Instruction::Single(op) => flags::flags_read(op),
+      Instruction::BitTestSequence(_) => 0,
+      Instruction::LoadPair(_, _) => 0,
+    }
+  }
+
+  fn flags_written(&self) -> u8 {
+    match self {
+      Instruction::Single(op) => flags::flags_written(op),
+      Instruction::BitTestSequence(_) => flags::FLAG_ZERO | flags::FLAG_NEGATIVE | flags::FLAG_HALF_CARRY,
+      Instruction::LoadPair(_, _) => 0,
+    }
+  }
+}
+
+impl Block {
+  /// Build a block from decoded ops and their lengths, starting at `ip`. Every
+  /// flag is initially considered live.
+  pub fn from_ops(ops: Vec<(Op, usize)>, ip: usize) -> Self {
+    let mut ir_ops: Vec<IrOp> = Vec::with_capacity(ops.len());
+    let mut address = ip;
+    for (op, length) in ops {
+      address += length;
+      // Gather any BIT n,(HL) ops immediately following another one
+      if let Op::BitTestIndirect(mask) = op {
+        if let Some(previous) = ir_ops.last_mut() {
+          if let Instruction::BitTestSequence(tests) = &mut previous.instruction {
+            if crate::mem::can_dynarec(address - length) {
+              tests.push((mask, length));
+              previous.length += length;
+              continue;
+            }
+          }
+        }
+        ir_ops.push(IrOp {
+          instruction: Instruction::BitTestSequence(vec![(mask, length)]),
+          length,
+          live_flags: flags::ALL_FLAGS,
+        });
+        continue;
+      }
+      ir_ops.push(IrOp {
+        instruction: Instruction::Single(op),
+        length,
+        live_flags: flags::ALL_FLAGS,
+      });
+    }
+    Self {
+      ops: ir_ops,
+      collapse_ip: false,
+    }
+  }
+
+  /// Determine which flags each op must compute. Everything is live at the end
+  /// of the block, since the next block or the interpreter may read any flag.
+  pub fn compute_live_flags(&mut self) {
+    let mut live_after = flags::ALL_FLAGS;
+    for ir_op in self.ops.iter_mut().rev() {
+      ir_op.live_flags = live_after;
+      let instruction = &ir_op.instruction;
+      live_after = (live_after & !instruction.flags_written()) | instruction.flags_read();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Block, Instruction};
+  use crate::decoder::ops::{JumpCondition, Op, Register8};
+  use crate::emitter::flags::{ALL_FLAGS, FLAG_CARRY};
+
+  #[test]
+  fn gathers_bit_tests() {
+    let ops = vec![
+      (Op::BitTestIndirect(1), 2),
+      (Op::BitTestIndirect(2), 2),
+      (Op::Increment8(Register8::A), 1),
+      (Op::BitTestIndirect(4), 2),
+      (Op::JumpRelative(JumpCondition::Zero, -9), 2),
+    ];
+    let block = Block::from_ops(ops, 0x100);
+    assert_eq!(block.ops.len(), 4);
+    assert!(matches!(&block.ops[0].instruction, Instruction::BitTestSequence(tests) if tests.len() == 2));
+    assert_eq!(block.ops[0].length, 4);
+    assert!(matches!(&block.ops[2].instruction, Instruction::BitTestSequence(tests) if tests.len() == 1));
+  }
+
+  #[test]
+  fn live_flags() {
+    let ops = vec![
+      (Op::Add8(Register8::A, Register8::B), 1),
+      (Op::Increment8(Register8::C), 1),
+      (Op::AddWithCarry8(Register8::A, Register8::D), 1),
+      (Op::Xor8(Register8::A, Register8::A), 1),
+      (Op::JumpRelative(JumpCondition::NonZero, -6), 2),
+    ];
+    let mut block = Block::from_ops(ops, 0);
+    block.compute_live_flags();
+    let live: Vec<u8> = block.ops.iter().map(|op| op.live_flags).collect();
+    assert_eq!(live, vec![FLAG_CARRY, FLAG_CARRY, 0, ALL_FLAGS, ALL_FLAGS]);
+  }
+}
@@ -0,0 +1,133 @@
+//! Peephole passes over a block. These only change how a block is emitted,
+//! never its result, so the output of an optimized block can be compared
+//! against the naive path by building without the `optimizer` feature.
+
+use super::{Block, Instruction, IrOp, PairSource};
+use crate::decoder::ops::{Op, Register8, Register16};
+
+pub fn optimize(block: &mut Block) {
+  fuse_loads(block);
+  block.collapse_ip = true;
+}
+
+/// Determine which register pair an 8-bit register belongs to, and whether it
+/// is the high half. A is excluded, since its pair also holds the flags.
+fn get_pair(reg: Register8) -> Option<(Register16, bool)> {
+  match reg {
+    Register8::B => Some((Register16::BC, true)),
+    Register8::C => Some((Register16::BC, false)),
+    Register8::D => Some((Register16::DE, true)),
+    Register8::E => Some((Register16::DE, false)),
+    Register8::H => Some((Register16::HL, true)),
+    Register8::L => Some((Register16::HL, false)),
+    Register8::A => None,
+  }
+}
+
+/// If two ops load both halves of the same register pair, return the
+/// equivalent 16-bit load
+fn fuse_pair(first: &Op, second: &Op) -> Option<(Register16, PairSource)> {
+  match (first, second) {
+    (Op::Load8Immediate(dest_a, value_a), Op::Load8Immediate(dest_b, value_b)) => {
+      let (pair_a, high_a) = get_pair(*dest_a)?;
+      let (pair_b, high_b) = get_pair(*dest_b)?;
+      if pair_a != pair_b || high_a == high_b {
+        return None;
+      }
+      let (high, low) = if high_a { (value_a, value_b) } else { (value_b, value_a) };
+      Some((pair_a, PairSource::Immediate(((*high as u16) << 8) | *low as u16)))
+    },
+    (Op::Load8(dest_a, src_a), Op::Load8(dest_b, src_b)) => {
+      let (dest_pair_a, dest_high_a) = get_pair(*dest_a)?;
+      let (dest_pair_b, dest_high_b) = get_pair(*dest_b)?;
+      let (src_pair_a, src_high_a) = get_pair(*src_a)?;
+      let (src_pair_b, src_high_b) = get_pair(*src_b)?;
+      // Both halves must be copied to the matching half of a different pair,
+      // so that the second load doesn't read what the first one wrote
+      let same_pairs = dest_pair_a == dest_pair_b && src_pair_a == src_pair_b;
+      let aligned = dest_high_a == src_high_a && dest_high_b == src_high_b;
+      if !same_pairs || !aligned || dest_high_a == dest_high_b || dest_pair_a == src_pair_a {
+        return None;
+      }
+      Some((dest_pair_a, PairSource::Register(src_pair_a)))
+    },
+    _ => None,
+  }
+}
+
+fn fuse_loads(block: &mut Block) {
+  let mut fused: Vec<IrOp> = Vec::with_capacity(block.ops.len());
+  for ir_op in block.ops.drain(..) {
+    let pair = match (fused.last(), &ir_op.instruction) {
+      (Some(IrOp { instruction: Instruction::Single(previous), .. }), Instruction::Single(op)) => {
+        fuse_pair(previous, op)
+      },
+      _ => None,
+    };
+    match pair {
+      Some((dest, source)) => {
+        let previous = fused.last_mut().unwrap();
+        previous.instruction = Instruction::LoadPair(dest, source);
+        previous.length += ir_op.length;
+      },
+      None => fused.push(ir_op),
+    }
+  }
+  block.ops = fused;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::optimize;
+  use crate::decoder::ops::{JumpCondition, Op, Register8, Register16};
+  use crate::ir::{Block, Instruction, PairSource};
+
+  fn get_pair(block: &Block, index: usize) -> Option<(Register16, PairSource)> {
+    match block.ops[index].instruction {
+      Instruction::LoadPair(dest, source) => Some((dest, source)),
+      _ => None,
+    }
+  }
+
+  #[test]
+  fn fuses_immediate_loads() {
+    let ops = vec![
+      (Op::Load8Immediate(Register8::C, 0x34), 2),
+      (Op::Load8Immediate(Register8::B, 0x12), 2),
+      (Op::Load8Immediate(Register8::D, 0x56), 2),
+      (Op::Load8Immediate(Register8::A, 0x78), 2),
+      (Op::Load8Immediate(Register8::E, 0x9a), 2),
+      (Op::Halt, 1),
+    ];
+    let mut block = Block::from_ops(ops, 0);
+    optimize(&mut block);
+    assert_eq!(block.ops.len(), 5);
+    assert_eq!(get_pair(&block, 0), Some((Register16::BC, PairSource::Immediate(0x1234))));
+    assert_eq!(block.ops[0].length, 4);
+    // loads separated by another op are left alone
+    assert_eq!(get_pair(&block, 1), None);
+    assert!(block.collapse_ip);
+  }
+
+  #[test]
+  fn fuses_register_copies() {
+    let ops = vec![
+      (Op::Load8(Register8::H, Register8::D), 1),
+      (Op::Load8(Register8::L, Register8::E), 1),
+      // swapped halves can't be copied as a pair
+      (Op::Load8(Register8::B, Register8::L), 1),
+      (Op::Load8(Register8::C, Register8::H), 1),
+      // copying within a pair depends on the order of the loads
+      (Op::Load8(Register8::D, Register8::E), 1),
+      (Op::Load8(Register8::E, Register8::D), 1),
+      (Op::JumpRelative(JumpCondition::Always, 0), 2),
+    ];
+    let mut block = Block::from_ops(ops, 0);
+    optimize(&mut block);
+    assert_eq!(block.ops.len(), 6);
+    assert_eq!(get_pair(&block, 0), Some((Register16::HL, PairSource::Register(Register16::DE))));
+    for index in 1..6 {
+      assert_eq!(get_pair(&block, index), None);
+    }
+  }
+}
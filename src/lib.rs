@@ -0,0 +1,66 @@
+//! A Game Boy emulator with a dynamic recompiler. Most front ends only need
+//! the `Emulator` facade, which loads a ROM, runs it a frame at a time, and
+//! exposes the screen and buttons. Everything underneath it is public as
+//! well, for tools that need more control over the core.
+//!
+//! The emulation core (cpu, decoder, interpreter, mem, devices) is written
+//! against `core` and `alloc`, so that it can be built for targets without
+//! std. The JIT, the facade, and everything that touches the host (files,
+//! networking, the shell) require the std feature. On wasm32, the `wasm`
+//! module wraps the facade for JavaScript.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod api;
+#[cfg(windows)]
+pub mod bindings;
+#[cfg(all(jit_backend, feature = "std"))]
+pub mod cache;
+pub mod cpu;
+pub mod cart;
+#[cfg(feature = "std")]
+pub mod cheats;
+#[cfg(feature = "std")]
+pub mod config;
+pub mod debug;
+pub mod decoder;
+pub mod devices;
+#[cfg(feature = "std")]
+pub mod hooks;
+#[cfg(all(jit_backend, feature = "std"))]
+pub mod emitter;
+#[cfg(feature = "std")]
+pub mod emulator;
+pub mod error;
+pub mod input;
+pub mod interpreter;
+#[cfg(all(jit_backend, feature = "std"))]
+pub mod ir;
+#[cfg(feature = "libretro")]
+pub mod libretro;
+pub mod mem;
+#[cfg(feature = "std")]
+pub mod netplay;
+pub mod patch;
+#[cfg(feature = "std")]
+pub mod recording;
+#[cfg(feature = "std")]
+pub mod rewind;
+pub mod savestate;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "std")]
+pub mod shell;
+#[cfg(feature = "std")]
+pub mod system;
+pub mod timing;
+#[cfg(all(target_arch = "wasm32", feature = "std"))]
+pub mod wasm;
+pub mod zip;
+
+#[cfg(feature = "std")]
+pub use api::Emulator;
+pub use devices::joypad::Button;
+pub use error::Error;
@@ -0,0 +1,476 @@
+//! A libretro core, so that the emulator can be loaded into RetroArch and
+//! other libretro front ends. Build the library as a cdylib with the
+//! `libretro` feature:
+//!
+//!   cargo rustc --lib --release --features libretro --crate-type cdylib
+//!
+//! and load the resulting libgb_dynarec.so (or gb_dynarec.dll) as a core.
+//!
+//! The front end calls every entry point from the same thread, so the running
+//! game and the callbacks it has registered are kept in thread-locals.
+
+use crate::api::Emulator;
+use crate::devices::joypad::Button;
+use crate::timing::{CYCLES_PER_SECOND, FRAME_CYCLES};
+use std::cell::{Cell, RefCell};
+use std::ffi::{c_void, CString};
+use std::os::raw::{c_char, c_uint};
+
+const RETRO_API_VERSION: c_uint = 1;
+const RETRO_REGION_NTSC: c_uint = 0;
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+const RETRO_MEMORY_SAVE_RAM: c_uint = 0;
+const RETRO_MEMORY_SYSTEM_RAM: c_uint = 2;
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const RETRO_ENVIRONMENT_GET_LOG_INTERFACE: c_uint = 27;
+const RETRO_LOG_INFO: c_uint = 1;
+const RETRO_LOG_ERROR: c_uint = 3;
+const RETRO_PIXEL_FORMAT_XRGB8888: c_uint = 1;
+
+/// Silence is sent at this rate, since there is no APU yet. Front ends that
+/// sync to audio still need samples to pace the game.
+const SAMPLE_RATE: u64 = 32768;
+
+/// Libretro joypad IDs, in the order the front end numbers them
+const JOYPAD_BUTTONS: [(c_uint, Button); 8] = [
+  (0, Button::B),
+  (2, Button::Select),
+  (3, Button::Start),
+  (4, Button::Up),
+  (5, Button::Down),
+  (6, Button::Left),
+  (7, Button::Right),
+  (8, Button::A),
+];
+
+type EnvironmentFn = extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+type VideoRefreshFn = extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type AudioSampleFn = extern "C" fn(left: i16, right: i16);
+type AudioSampleBatchFn = extern "C" fn(data: *const i16, frames: usize) -> usize;
+type InputPollFn = extern "C" fn();
+type InputStateFn = extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+type LogPrintfFn = unsafe extern "C" fn(level: c_uint, fmt: *const c_char, ...);
+
+#[repr(C)]
+struct RetroLogCallback {
+  log: Option<LogPrintfFn>,
+}
+
+#[repr(C)]
+pub struct RetroSystemInfo {
+  library_name: *const c_char,
+  library_version: *const c_char,
+  valid_extensions: *const c_char,
+  need_fullpath: bool,
+  block_extract: bool,
+}
+
+#[repr(C)]
+pub struct RetroGameGeometry {
+  base_width: c_uint,
+  base_height: c_uint,
+  max_width: c_uint,
+  max_height: c_uint,
+  aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct RetroSystemTiming {
+  fps: f64,
+  sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct RetroSystemAvInfo {
+  geometry: RetroGameGeometry,
+  timing: RetroSystemTiming,
+}
+
+#[repr(C)]
+pub struct RetroGameInfo {
+  path: *const c_char,
+  data: *const c_void,
+  size: usize,
+  meta: *const c_char,
+}
+
+#[derive(Default)]
+struct Callbacks {
+  environment: Cell<Option<EnvironmentFn>>,
+  video_refresh: Cell<Option<VideoRefreshFn>>,
+  audio_sample_batch: Cell<Option<AudioSampleBatchFn>>,
+  input_poll: Cell<Option<InputPollFn>>,
+  input_state: Cell<Option<InputStateFn>>,
+  /// Provided by the front end on request. Without it, nothing is logged,
+  /// since front ends don't show stdout.
+  log: Cell<Option<LogPrintfFn>>,
+}
+
+/// The game that is currently loaded
+struct Game {
+  emulator: Emulator,
+  /// Kept for resetting, which reloads the cartridge from scratch
+  rom: Vec<u8>,
+  /// The last frame as XRGB8888
+  frame: Vec<u32>,
+  /// Fraction of a sample left over from the previous frame, in cycles
+  /// multiplied by the sample rate
+  sample_remainder: u64,
+  silence: Vec<i16>,
+}
+
+thread_local! {
+  static CALLBACKS: Callbacks = Callbacks::default();
+  static GAME: RefCell<Option<Game>> = const { RefCell::new(None) };
+}
+
+/// Pass a message on to the front end's log, if it has one
+fn log(level: c_uint, message: &str) {
+  let log = match CALLBACKS.with(|callbacks| callbacks.log.get()) {
+    Some(log) => log,
+    None => return,
+  };
+  let message = match CString::new(message.replace('\0', "")) {
+    Ok(message) => message,
+    Err(_) => return,
+  };
+  unsafe { log(level, b"%s\n\0".as_ptr() as *const c_char, message.as_ptr()) };
+}
+
+/// Log everything the core has raised since the last call
+fn log_messages(emulator: &mut Emulator) {
+  for message in emulator.take_messages() {
+    log(RETRO_LOG_INFO, &message);
+  }
+}
+
+fn with_game<T>(default: T, f: impl FnOnce(&mut Game) -> T) -> T {
+  GAME.with(|game| match game.borrow_mut().as_mut() {
+    Some(game) => f(game),
+    None => default,
+  })
+}
+
+impl Game {
+  fn run_frame(&mut self) {
+    if let Some(poll) = CALLBACKS.with(|callbacks| callbacks.input_poll.get()) {
+      poll();
+    }
+    if let Some(input_state) = CALLBACKS.with(|callbacks| callbacks.input_state.get()) {
+      for (id, button) in JOYPAD_BUTTONS.iter() {
+        let pressed = input_state(0, RETRO_DEVICE_JOYPAD, 0, *id) != 0;
+        self.emulator.set_button(*button, pressed);
+      }
+    }
+
+    self.emulator.step_frame();
+    log_messages(&mut self.emulator);
+
+    let palette = self.emulator.core().memory.io.video.get_palette();
+    for (pixel, shade) in self.frame.iter_mut().zip(self.emulator.framebuffer().iter()) {
+      let [r, g, b] = palette.to_rgb(*shade);
+      *pixel = ((r as u32) << 16) | ((g as u32) << 8) | b as u32;
+    }
+    let (width, height) = self.emulator.framebuffer_size();
+    if let Some(video_refresh) = CALLBACKS.with(|callbacks| callbacks.video_refresh.get()) {
+      video_refresh(self.frame.as_ptr() as *const c_void, width as c_uint, height as c_uint, width * 4);
+    }
+
+    let samples = FRAME_CYCLES * SAMPLE_RATE + self.sample_remainder;
+    let frames = (samples / CYCLES_PER_SECOND) as usize;
+    self.sample_remainder = samples % CYCLES_PER_SECOND;
+    self.silence.resize(frames * 2, 0);
+    if let Some(audio_sample_batch) = CALLBACKS.with(|callbacks| callbacks.audio_sample_batch.get()) {
+      audio_sample_batch(self.silence.as_ptr(), frames);
+    }
+  }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+  RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(callback: EnvironmentFn) {
+  CALLBACKS.with(|callbacks| callbacks.environment.set(Some(callback)));
+  let mut log = RetroLogCallback { log: None };
+  let log = if callback(RETRO_ENVIRONMENT_GET_LOG_INTERFACE, &mut log as *mut RetroLogCallback as *mut c_void) {
+    log.log
+  } else {
+    None
+  };
+  CALLBACKS.with(|callbacks| callbacks.log.set(log));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(callback: VideoRefreshFn) {
+  CALLBACKS.with(|callbacks| callbacks.video_refresh.set(Some(callback)));
+}
+
+/// Only the batched audio callback is used
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(callback: AudioSampleBatchFn) {
+  CALLBACKS.with(|callbacks| callbacks.audio_sample_batch.set(Some(callback)));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(callback: InputPollFn) {
+  CALLBACKS.with(|callbacks| callbacks.input_poll.set(Some(callback)));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(callback: InputStateFn) {
+  CALLBACKS.with(|callbacks| callbacks.input_state.set(Some(callback)));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+  retro_unload_game();
+}
+
+/// # Safety
+///
+/// `info` must point to a `retro_system_info` that can be written
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo) {
+  *info = RetroSystemInfo {
+    library_name: b"GB Dynarec\0".as_ptr() as *const c_char,
+    library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+    valid_extensions: b"gb|dmg\0".as_ptr() as *const c_char,
+    need_fullpath: false,
+    block_extract: false,
+  };
+}
+
+/// # Safety
+///
+/// `info` must point to a `retro_system_av_info` that can be written
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo) {
+  *info = RetroSystemAvInfo {
+    geometry: RetroGameGeometry {
+      base_width: 160,
+      base_height: 144,
+      max_width: 160,
+      max_height: 144,
+      aspect_ratio: 160.0 / 144.0,
+    },
+    timing: RetroSystemTiming {
+      fps: CYCLES_PER_SECOND as f64 / FRAME_CYCLES as f64,
+      sample_rate: SAMPLE_RATE as f64,
+    },
+  };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+/// Reload the cartridge, keeping the contents of its RAM like a power cycle
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+  with_game((), |game| {
+    let cart_ram = game.emulator.core().memory.cart_ram.clone();
+    if game.emulator.load_rom(&game.rom).is_ok() {
+      let memory = &mut game.emulator.core_mut().memory;
+      if memory.cart_ram.len() == cart_ram.len() {
+        memory.cart_ram = cart_ram;
+      }
+    }
+  });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+  with_game((), |game| game.run_frame());
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+  with_game(0, |game| game.emulator.save_state().len())
+}
+
+/// # Safety
+///
+/// `data` must point to at least `size` writable bytes
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+  with_game(false, |game| {
+    let state = game.emulator.save_state();
+    if state.len() > size {
+      return false;
+    }
+    std::ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+    true
+  })
+}
+
+/// # Safety
+///
+/// `data` must point to at least `size` readable bytes
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+  let state = std::slice::from_raw_parts(data as *const u8, size);
+  with_game(false, |game| game.emulator.load_state(state).is_ok())
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+/// # Safety
+///
+/// `info` must be null, or point to a `retro_game_info` whose data is
+/// readable for `size` bytes
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(info: *const RetroGameInfo) -> bool {
+  if info.is_null() || (*info).data.is_null() {
+    return false;
+  }
+  let rom = std::slice::from_raw_parts((*info).data as *const u8, (*info).size).to_vec();
+
+  if let Some(environment) = CALLBACKS.with(|callbacks| callbacks.environment.get()) {
+    let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+    if !environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut c_uint as *mut c_void) {
+      return false;
+    }
+  }
+
+  let mut emulator = Emulator::new();
+  if let Err(msg) = emulator.load_rom(&rom) {
+    log(RETRO_LOG_ERROR, &format!("Unable to load the game: {}", msg));
+    return false;
+  }
+  let (width, height) = emulator.framebuffer_size();
+  let game = Game {
+    emulator,
+    rom,
+    frame: vec![0; width * height],
+    sample_remainder: 0,
+    silence: Vec::new(),
+  };
+  GAME.with(|current| *current.borrow_mut() = Some(game));
+  true
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_game_type: c_uint, _info: *const RetroGameInfo, _num_info: usize) -> bool {
+  false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+  GAME.with(|game| {
+    if let Some(mut game) = game.borrow_mut().take() {
+      game.emulator.core_mut().shutdown();
+      log_messages(&mut game.emulator);
+    }
+  });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+  RETRO_REGION_NTSC
+}
+
+/// Cartridge RAM is exposed so that the front end can keep battery saves
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+  with_game(std::ptr::null_mut(), |game| {
+    let memory = &mut game.emulator.core_mut().memory;
+    match id {
+      RETRO_MEMORY_SAVE_RAM => memory.cart_ram.as_mut_ptr() as *mut c_void,
+      RETRO_MEMORY_SYSTEM_RAM => memory.work_ram.as_mut_ptr() as *mut c_void,
+      _ => std::ptr::null_mut(),
+    }
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+  with_game(0, |game| {
+    let memory = &game.emulator.core().memory;
+    match id {
+      RETRO_MEMORY_SAVE_RAM => memory.cart_ram.len(),
+      RETRO_MEMORY_SYSTEM_RAM => memory.work_ram.len(),
+      _ => 0,
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  static FRAMES_DRAWN: AtomicUsize = AtomicUsize::new(0);
+  static SAMPLES_SENT: AtomicUsize = AtomicUsize::new(0);
+  static LOG_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+  /// Accepts every request, but leaves the log interface unset, so that
+  /// logging falls back to silence
+  extern "C" fn environment(cmd: c_uint, _data: *mut c_void) -> bool {
+    if cmd == RETRO_ENVIRONMENT_GET_LOG_INTERFACE {
+      LOG_REQUESTS.fetch_add(1, Ordering::SeqCst);
+    }
+    true
+  }
+
+  extern "C" fn video_refresh(_data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+    assert_eq!((width, height, pitch), (160, 144, 640));
+    FRAMES_DRAWN.fetch_add(1, Ordering::SeqCst);
+  }
+
+  extern "C" fn audio_sample_batch(_data: *const i16, frames: usize) -> usize {
+    SAMPLES_SENT.fetch_add(frames, Ordering::SeqCst);
+    frames
+  }
+
+  #[test]
+  fn running_a_game() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]);
+    rom[0x150..0x152].copy_from_slice(&[0x18, 0xfe]);
+    let mut checksum: u8 = 0;
+    for byte in rom[0x134..0x14d].iter() {
+      checksum = checksum.wrapping_sub(*byte).wrapping_sub(1);
+    }
+    rom[0x14d] = checksum;
+
+    retro_set_environment(environment);
+    assert_eq!(LOG_REQUESTS.load(Ordering::SeqCst), 1);
+    retro_set_video_refresh(video_refresh);
+    retro_set_audio_sample_batch(audio_sample_batch);
+    let info = RetroGameInfo {
+      path: std::ptr::null(),
+      data: rom.as_ptr() as *const c_void,
+      size: rom.len(),
+      meta: std::ptr::null(),
+    };
+    let bad_info = RetroGameInfo { size: 0x100, ..info };
+    assert!(!unsafe { retro_load_game(&bad_info) });
+    assert!(unsafe { retro_load_game(&info) });
+    for _ in 0..60 {
+      retro_run();
+    }
+    assert_eq!(FRAMES_DRAWN.load(Ordering::SeqCst), 60);
+    // fractions of a sample are carried over to the next frame
+    let samples = SAMPLES_SENT.load(Ordering::SeqCst) as u64;
+    assert_eq!(samples, 60 * FRAME_CYCLES * SAMPLE_RATE / CYCLES_PER_SECOND);
+
+    let mut state = vec![0u8; retro_serialize_size()];
+    assert!(unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, state.len()) });
+    assert!(unsafe { retro_unserialize(state.as_ptr() as *const c_void, state.len()) });
+    assert!(retro_get_memory_size(RETRO_MEMORY_SYSTEM_RAM) > 0);
+    retro_unload_game();
+    assert_eq!(retro_get_memory_size(RETRO_MEMORY_SYSTEM_RAM), 0);
+  }
+}
@@ -1,68 +1,625 @@
-#[cfg(windows)]
-pub mod bindings;
-pub mod cache;
-pub mod cpu;
-pub mod cart;
-pub mod debug;
-pub mod decoder;
-pub mod devices;
-pub mod emitter;
-pub mod emulator;
-pub mod interpreter;
-pub mod mem;
-pub mod shell;
-pub mod system;
-pub mod timing;
-
+// The emulator itself is the gb_dynarec library. The binary only parses the
+// command line, and hands the configured core to a shell.
+use gb_dynarec::{debug, devices, emulator, input, netplay, recording, rewind, shell, stats, system};
+use gb_dynarec::config::{self, Options};
 use std::env;
-use shell::Shell;
+use std::path::PathBuf;
 
 fn main() {
-  // Initialize UI/Audio/Input
-  let mut emu_shell = shell::create_shell();
+  // Crash reports include the blocks that ran leading up to the panic
+  debug::history::install_panic_hook();
+
+  let mut options = match Options::parse_args(env::args().skip(1)) {
+    Ok(options) => options,
+    Err(msg) => {
+      println!("{}", msg);
+      print_usage();
+      std::process::exit(2);
+    },
+  };
+  if options.has_flag("--help") {
+    print_usage();
+    return;
+  }
+  read_config_file(&mut options);
+
+  // A rough performance baseline, to include with bug reports
+  if options.has_flag("--self-test") {
+    print!("{}", debug::perf::run_self_test());
+  }
+
+  if options.has_flag("--stats") {
+    stats::print_stats();
+    return;
+  }
+
+  if options.has_flag("--info") {
+    match options.rom.as_deref() {
+      Some(name) => print_rom_info(&options, name),
+      None => {
+        println!("--info needs a ROM file");
+        std::process::exit(2);
+      },
+    }
+  }
 
   // Build the Dynarec Core
-  let mut core = match get_file_arg().and_then(load_rom) {
+  let rom = options.rom.as_deref().and_then(|name| load_rom(&options, name));
+  let loaded_rom = rom.is_some();
+  let mut core = match rom {
     Some(core) => core,
     None => fallback_core(),
   };
 
+  if options.has_flag("--interpreter") || options.has_flag("--interp") {
+    // cannot fail, disabling the JIT is always allowed
+    let _ = core.set_jit_enabled(false);
+  } else if options.has_flag("--jit") {
+    if let Err(e) = core.set_jit_enabled(true) {
+      println!("{}, falling back to the interpreter", e);
+    }
+  }
+
+  if options.has_flag("--jit-ram") {
+    if core.is_jit_enabled() {
+      core.set_ram_compilation(true);
+    } else {
+      println!("--jit-ram has no effect when the JIT is disabled");
+    }
+  }
+
+  if options.has_flag("--jit-background") {
+    if core.is_jit_enabled() {
+      #[cfg(jit_backend)]
+      {
+        core.cache.set_background_compilation(true);
+      }
+    } else {
+      println!("--jit-background has no effect when the JIT is disabled");
+    }
+  }
+
+  if let Some(value) = options.get_value("--jit-threshold") {
+    configure_compile_threshold(&value, &mut core);
+  }
+
+  if options.has_flag("--verify-jit") {
+    if core.is_jit_enabled() {
+      println!("Verifying every compiled block against the interpreter");
+      core.verify_jit = true;
+      // The interpreter runs one block at a time, so compiled blocks must
+      // also return after each one for their results to be comparable
+      #[cfg(jit_backend)]
+      {
+        core.cache.link_blocks = false;
+      }
+    } else {
+      println!("--verify-jit has no effect when the JIT is disabled");
+    }
+  }
+
+  // The hottest blocks are listed when the emulator exits
+  if options.has_flag("--profile-blocks") {
+    if core.is_jit_enabled() {
+      #[cfg(jit_backend)]
+      {
+        core.cache.set_profiling(true);
+      }
+    } else {
+      println!("--profile-blocks has no effect when the JIT is disabled");
+    }
+  }
+
+  if options.has_flag("--test-rom") {
+    if !loaded_rom {
+      println!("--test-rom needs a ROM file");
+      std::process::exit(2);
+    }
+    run_test_rom(&options, &mut core);
+  }
+
+  // Initialize UI/Audio/Input
+  let limits = get_run_limits(&options);
+  // scripted runs with limits aren't counted as play time
+  if loaded_rom && !limits.is_limited() && !options.has_flag("--no-stats") {
+    core.play_session = Some(stats::PlaySession::start(&core.memory.rom, core.cycles_elapsed()));
+  }
+  // hashes are only repeatable without a window, where nothing from the host
+  // changes how a run goes
+  let hashing = options.get_value("--hash-frames").is_some() || options.get_value("--check-hashes").is_some();
+  let headless = !cfg!(feature = "shell-window") || options.has_flag("--headless") || hashing;
+  let mut emu_shell = shell::create_shell(shell::Settings {
+    limits,
+    fast_forward: get_fast_forward(&options),
+    filter: get_filter(&options),
+    scale: get_scale(&options),
+    save_dir: options.get_value("--save-dir").map(PathBuf::from),
+    headless,
+    size_policy: get_rom_size_policy(&options),
+    hud: options.has_flag("--hud"),
+    color: get_color_adjustment(&options),
+    config_file: options.config_file(),
+  });
+
+  connect_link_cable(&options, &mut core);
+  configure_turbo(&options, &mut core);
+  configure_palette(&options, &mut core);
+  configure_cheats(&options, &mut core);
+  configure_breaks(&options, &mut core);
+  configure_trace(&options, &mut core);
+  if let Some(name) = options.get_value("--record") {
+    match recording::VideoRecorder::start(&name) {
+      Ok(recorder) => core.recorder = Some(recorder),
+      Err(msg) => println!("{}", msg),
+    }
+  }
+  if hashing {
+    configure_frame_hashing(&options, &mut core);
+  }
+  if options.has_flag("--stack-check") {
+    // warnings are printed as they happen
+    core.stack_monitor = Some(debug::stack::StackMonitor::new(core.registers.sp as u16));
+  }
+  if options.get_value("--netplay-host").is_some() || options.get_value("--netplay-join").is_some() {
+    if !loaded_rom {
+      println!("Netplay needs a ROM file");
+      return;
+    }
+    start_netplay(&options, &mut core);
+  }
+  // only the window has a key for rewinding, and netplay can't be rewound
+  if !headless && core.netplay.is_none() && !options.has_flag("--no-rewind") {
+    core.rewind = Some(rewind::RewindBuffer::default());
+  }
+
+  if options.has_flag("--debug") {
+    // The debugger takes over the terminal, and pauses before the first
+    // instruction
+    #[cfg(feature = "debugger")]
+    {
+      debug::tui::run(&mut core);
+      return;
+    }
+    #[cfg(not(feature = "debugger"))]
+    {
+      println!("--debug has no effect without the debugger feature");
+    }
+  }
+
   emu_shell.run(core);
 }
 
-fn get_file_arg() -> Option<String> {
-  let mut iter = env::args();
-  let _ = iter.next();
-  iter.next()
+/// Fill in settings from the config file. The default file is optional, but
+/// one passed with `--config` has to exist.
+fn read_config_file(options: &mut Options) {
+  if !options.has_flag("--no-config") {
+    if let Some(msg) = config::migrate_display_config() {
+      println!("{}", msg);
+    }
+  }
+  let path = match options.config_file() {
+    Some(path) => path,
+    None => return,
+  };
+  let text = match std::fs::read_to_string(&path) {
+    Ok(text) => text,
+    Err(_) => {
+      if options.get_value("--config").is_some() {
+        println!("Unable to read config file \"{}\"", path.display());
+      }
+      return;
+    },
+  };
+  for warning in options.merge_config(&text) {
+    println!("{}: {}", path.display(), warning);
+  }
+}
+
+/// Colors are adjusted by `--brightness`, `--contrast`, and `--gamma`
+fn get_color_adjustment(options: &Options) -> shell::color::ColorAdjustment {
+  let mut color = shell::color::ColorAdjustment::new();
+  let settings = [
+    ("--brightness", &mut color.brightness),
+    ("--contrast", &mut color.contrast),
+    ("--gamma", &mut color.gamma),
+  ];
+  for (flag, setting) in settings {
+    if let Some(value) = options.get_value(flag) {
+      match value.parse::<f32>() {
+        Ok(parsed) => *setting = parsed,
+        Err(_) => println!("Invalid {} \"{}\", using the default", &flag[2..], value),
+      }
+    }
+  }
+  color.clamped()
+}
+
+/// The window starts at `--scale <n>` times the size of the LCD
+fn get_scale(options: &Options) -> Option<usize> {
+  let value = options.get_value("--scale")?;
+  match value.parse::<usize>() {
+    Ok(scale) if scale > 0 => Some(scale),
+    _ => {
+      println!("Invalid scale \"{}\", using the default", value);
+      None
+    },
+  }
+}
+
+/// Blocks are interpreted `--jit-threshold` times before they are compiled
+fn configure_compile_threshold(value: &str, core: &mut emulator::Core) {
+  if !core.is_jit_enabled() {
+    println!("--jit-threshold has no effect when the JIT is disabled");
+    return;
+  }
+  match value.parse::<u32>() {
+    #[cfg(jit_backend)]
+    Ok(threshold) => core.cache.compile_threshold = threshold,
+    #[cfg(not(jit_backend))]
+    Ok(_) => (),
+    Err(_) => println!("Invalid JIT threshold \"{}\", using the default", value),
+  }
+}
+
+fn load_boot_rom(options: &Options) -> Option<Box<[u8]>> {
+  let boot_rom_name = options.get_value("--boot-rom")?;
+  match system::load_boot_rom(boot_rom_name) {
+    Ok(boot_rom) => Some(boot_rom),
+    Err(msg) => {
+      println!("{}, skipping boot sequence", msg);
+      None
+    },
+  }
+}
+
+/// Run a blargg or mooneye-gb test ROM headlessly, print its result, and exit
+/// with 0 if it passed, 1 if it failed, or 2 if it timed out. The time limit
+/// can be set with `--test-timeout <seconds>`, in emulated time. In a
+/// `strict_io` build, a test that touched an unmodeled IO register fails,
+/// and its accesses are listed with the result.
+fn run_test_rom(options: &Options, core: &mut emulator::Core) -> ! {
+  use debug::testrom::{TestResult, DEFAULT_TIMEOUT_SECONDS};
+
+  let timeout = match options.get_value("--test-timeout").map(|seconds| seconds.parse::<u64>()) {
+    Some(Ok(seconds)) => seconds,
+    Some(Err(_)) => {
+      println!("Invalid test timeout, using {} seconds", DEFAULT_TIMEOUT_SECONDS);
+      DEFAULT_TIMEOUT_SECONDS
+    },
+    None => DEFAULT_TIMEOUT_SECONDS,
+  };
+  let result = core.run_test_rom(timeout);
+  println!("{}", result);
+  let code = match result {
+    TestResult::Passed => 0,
+    TestResult::Failed(_) => 1,
+    TestResult::TimedOut(_) => 2,
+  };
+  std::process::exit(code);
 }
 
-fn load_rom(rom_file_name: String) -> Option<emulator::Core> {
-  // Load ROM, parse MMC type
-  let mut rom_file = {
-    match system::open_rom_file(rom_file_name) {
-      Ok(fd) => fd,
-      Err(msg) => {
-        println!("{}", msg);
-        return None;
+/// Attach a replayed session to the serial port, or record every transfer made
+/// during this session
+fn connect_link_cable(options: &Options, core: &mut emulator::Core) {
+  use devices::link::{LinkPeer, LinkRecording, RecordingPeer, ReplayPeer, Unplugged};
+
+  let peer: Box<dyn LinkPeer> = if let Some(name) = options.get_value("--replay-link") {
+    match LinkRecording::load(&name) {
+      Ok(recording) => {
+        println!("Replaying {} link transfers", recording.transfers.len());
+        Box::new(ReplayPeer::new(recording))
+      },
+      Err(msg) => return println!("{}", msg),
+    }
+  } else if let Some(name) = options.get_value("--record-link") {
+    match RecordingPeer::with_log_file(Box::new(Unplugged), &name) {
+      Ok(recorder) => Box::new(recorder),
+      Err(msg) => return println!("{}", msg),
+    }
+  } else {
+    return;
+  };
+  match core.memory.io.serial_port() {
+    Some(serial) => serial.connect(peer),
+    None => println!("Serial port is not available in this build"),
+  }
+}
+
+/// Without a window, the emulator can stop after `--frames <count>` or
+/// `--cycles <count>` machine cycles, save the final frame with
+/// `--dump-frame <file.png|file.pgm>`, and compare it to a reference image
+/// with `--compare-frame <file.png|file.pgm>`. A frame that doesn't match
+/// has its differences saved to `<reference name>-diff.png`.
+fn get_run_limits(options: &Options) -> shell::RunLimits {
+  let parse_count = |flag: &str| {
+    let value = options.get_value(flag)?;
+    match value.parse::<u64>() {
+      Ok(count) => Some(count),
+      Err(_) => {
+        println!("Invalid count \"{}\" for {}, ignoring it", value, flag);
+        None
       },
     }
   };
-  let header = match system::read_header(&mut rom_file) {
-    Ok(head) => head,
+  shell::RunLimits {
+    frames: parse_count("--frames"),
+    cycles: parse_count("--cycles"),
+    dump_frame: options.get_value("--dump-frame"),
+    reference_frame: options.get_value("--compare-frame"),
+  }
+}
+
+/// Holding Tab in the window runs at `--fast-forward <speed>`, like `2x`,
+/// `4x` (the default), or `unlimited`. Frames in between the ones shown
+/// aren't drawn, unless `--no-frame-skip` is set.
+fn get_fast_forward(options: &Options) -> shell::speed::FastForward {
+  use shell::speed::{FastForward, Speed};
+
+  let mut fast_forward = FastForward::new();
+  if let Some(speed) = options.get_value("--fast-forward") {
+    match Speed::parse(&speed) {
+      Ok(speed) => fast_forward.speed = speed,
+      Err(msg) => println!("{}, using {}", msg, fast_forward.speed.name()),
+    }
+  }
+  fast_forward.skip_rendering = !options.has_flag("--no-frame-skip");
+  fast_forward
+}
+
+/// The window is drawn through `--filter <name>`, one of `none` (the
+/// default), `grid`, `scanlines`, or `green`. F11 switches between them.
+fn get_filter(options: &Options) -> shell::filter::Filter {
+  use shell::filter::Filter;
+
+  match options.get_value("--filter").map(|name| Filter::parse(&name)) {
+    Some(Ok(filter)) => filter,
+    Some(Err(msg)) => {
+      println!("{}, using {}", msg, Filter::None.name());
+      Filter::None
+    },
+    None => Filter::None,
+  }
+}
+
+/// Play a link cable game with another instance, either by waiting for it to
+/// connect with `--netplay-host <port>`, or connecting to it with
+/// `--netplay-join <address>:<port>`. Input takes effect after
+/// `--netplay-delay <frames>`, which should be the same on both sides.
+fn start_netplay(options: &Options, core: &mut emulator::Core) {
+  use netplay::{NetplaySession, DEFAULT_INPUT_DELAY};
+
+  let delay = match options.get_value("--netplay-delay").map(|frames| frames.parse::<u32>()) {
+    Some(Ok(frames)) => frames,
+    Some(Err(_)) => {
+      println!("Invalid netplay delay, using {} frames", DEFAULT_INPUT_DELAY);
+      DEFAULT_INPUT_DELAY
+    },
+    None => DEFAULT_INPUT_DELAY,
+  };
+  let connection = match options.get_value("--netplay-host") {
+    Some(port) => match port.parse::<u16>() {
+      Ok(port) => netplay::host(port).map(|stream| (stream, 0)),
+      Err(_) => Err(format!("Invalid netplay port \"{}\"", port)),
+    },
+    None => match options.get_value("--netplay-join") {
+      Some(address) => netplay::join(&address).map(|stream| (stream, 1)),
+      None => return,
+    },
+  };
+  let (stream, local_player) = match connection {
+    Ok(connection) => connection,
     Err(msg) => {
       println!("{}", msg);
-      return None;
+      return;
+    },
+  };
+  // the other player's Game Boy runs here too, from a second copy of the ROM
+  let remote = match options.rom.as_deref().and_then(|name| load_rom(options, name)) {
+    Some(remote) => remote,
+    None => return,
+  };
+  match NetplaySession::new(core, remote, stream, local_player, delay) {
+    Ok(session) => {
+      println!("Netplay started as player {}", local_player + 1);
+      core.netplay = Some(Box::new(session));
+    },
+    Err(msg) => println!("{}", msg),
+  }
+}
+
+/// Assign turbo to a comma-separated list of buttons, like `--turbo a,b`. The
+/// duty cycle can be set with `--turbo-duty <pressed>/<period>`, in frames.
+fn configure_turbo(options: &Options, core: &mut emulator::Core) {
+  use devices::joypad::Button;
+  use input::Turbo;
+
+  let buttons = match options.get_value("--turbo") {
+    Some(buttons) => buttons,
+    None => return,
+  };
+  let turbo = match options.get_value("--turbo-duty").map(|duty| Turbo::parse(&duty)) {
+    Some(Ok(turbo)) => turbo,
+    Some(Err(msg)) => {
+      println!("{}, using the default", msg);
+      Turbo::default()
+    },
+    None => Turbo::default(),
+  };
+  for name in buttons.split(',') {
+    match Button::from_name(&name.trim().to_lowercase()) {
+      Some(button) => core.input.set_turbo(button, Some(turbo)),
+      None => println!("Unknown button \"{}\"", name),
+    }
+  }
+}
+
+/// Show the screen with `--palette <name>`, one of the presets (`grayscale`,
+/// `green`, `pocket`, or `pastel`), or with four hex colors from lightest to
+/// darkest, like `--palette e0f8d0,88c070,346856,081820`. This also colors
+/// screenshots and recordings.
+fn configure_palette(options: &Options, core: &mut emulator::Core) {
+  use devices::video::palette::Palette;
+
+  if let Some(value) = options.get_value("--palette") {
+    match Palette::parse(&value) {
+      Ok(palette) => core.memory.io.video.set_palette(palette),
+      Err(msg) => println!("{}, using grayscale", msg),
+    }
+  }
+}
+
+/// Apply a comma-separated list of Game Genie and GameShark codes, like
+/// `--cheat 00A-17B-C49,010238CD`
+fn configure_cheats(options: &Options, core: &mut emulator::Core) {
+  let codes = match options.get_value("--cheat") {
+    Some(codes) => codes,
+    None => return,
+  };
+  for code in codes.split(',') {
+    match core.add_cheat(code) {
+      Ok(cheat) => println!("Cheat {}", cheat),
+      Err(msg) => println!("{}", msg),
+    }
+  }
+}
+
+/// Stop at a comma-separated list of breakpoints, like `--break 0x150,03:4f20`,
+/// and on writes to addresses or ranges, like `--watch 0xc000-0xc0ff`
+fn configure_breaks(options: &Options, core: &mut emulator::Core) {
+  use debug::command::{parse_breakpoint, parse_watchpoint};
+  use debug::watchpoint::WatchKind;
+
+  if let Some(locations) = options.get_value("--break") {
+    for location in locations.split(',') {
+      match parse_breakpoint(location) {
+        Some(breakpoint) => core.add_breakpoint(breakpoint),
+        None => println!("Invalid breakpoint \"{}\"", location),
+      }
+    }
+  }
+  if let Some(locations) = options.get_value("--watch") {
+    for location in locations.split(',') {
+      match parse_watchpoint(location, WatchKind::Write) {
+        Some(watchpoint) => core.add_watchpoint(watchpoint),
+        None => println!("Invalid watchpoint \"{}\"", location),
+      }
     }
+  }
+}
+
+/// Log a hash of every frame to `--hash-frames <file>`, or compare each one
+/// to a log from an earlier run with `--check-hashes <file>`. Both run
+/// without a window, and a run that differs from the log stops at the first
+/// frame that doesn't match, with exit status 3.
+fn configure_frame_hashing(options: &Options, core: &mut emulator::Core) {
+  let mut hasher = debug::framehash::FrameHasher::new();
+  if let Some(name) = options.get_value("--hash-frames") {
+    if let Err(msg) = hasher.log_to(&name) {
+      return println!("{}", msg);
+    }
+  }
+  if let Some(name) = options.get_value("--check-hashes") {
+    if let Err(msg) = hasher.compare_with(&name) {
+      return println!("{}", msg);
+    }
+  }
+  core.frame_hasher = Some(hasher);
+}
+
+/// Log executed instructions to `--trace <file>`, optionally limited to
+/// `--trace-range <start>-<end>`. With `--trace-blocks`, compiled code keeps
+/// running and only the start of each block is logged. `--trace-format doctor`
+/// writes lines that can be diffed against Gameboy Doctor logs.
+fn configure_trace(options: &Options, core: &mut emulator::Core) {
+  let name = match options.get_value("--trace") {
+    Some(name) => name,
+    None => return,
   };
+  let mut tracer = match debug::trace::Tracer::to_file(&name) {
+    Ok(tracer) => tracer,
+    Err(msg) => {
+      println!("{}", msg);
+      return;
+    },
+  };
+  if let Some(range) = options.get_value("--trace-range") {
+    match debug::command::parse_range(&range) {
+      Some((start, end)) => tracer.set_range(start, end),
+      None => println!("Invalid trace range \"{}\", tracing everything", range),
+    }
+  }
+  tracer.blocks_only = options.has_flag("--trace-blocks");
+  if let Some(format) = options.get_value("--trace-format") {
+    match debug::trace::TraceFormat::from_name(&format) {
+      Some(format) => tracer.format = format,
+      None => println!("Unknown trace format \"{}\", using the default", format),
+    }
+  }
+  core.tracer = Some(tracer);
+}
 
-  if !header.valid_checksum() {
-    println!("ROM file is corrupt: invalid header checksum");
-    return None;
+fn load_rom(options: &Options, rom_file_name: &str) -> Option<emulator::Core> {
+  // A patch passed with --patch, or sitting next to the ROM, is applied
+  // before anything else reads the ROM
+  let patch_file_name = options.get_value("--patch");
+  let size_policy = get_rom_size_policy(options);
+  match emulator::Core::open_rom_file(rom_file_name, size_policy, patch_file_name.as_deref(), load_boot_rom(options)) {
+    Ok(core) => {
+      for warning in core.load_warnings.iter() {
+        println!("{}", warning);
+      }
+      if let Ok(header) = system::read_header_from_bytes(&core.memory.rom) {
+        println!("Loading \"{}\"", header.get_title());
+      }
+      Some(core)
+    },
+    Err(msg) => {
+      println!("{}", msg);
+      None
+    },
   }
+}
 
-  println!("Loading \"{}\"", header.get_title());
+/// Print everything in a ROM's header, and whether its checksums and size
+/// check out, without running it. A patch is applied first if one is given
+/// with --patch.
+fn print_rom_info(options: &Options, rom_file_name: &str) -> ! {
+  let data = match options.get_value("--patch") {
+    Some(patch_file_name) => system::load_patched_rom(rom_file_name, &patch_file_name),
+    None => system::read_rom_data(rom_file_name),
+  };
+  let report = data.and_then(|data| {
+    let header = system::read_header_from_bytes(&data)?;
+    Ok(header.report(&data))
+  });
+  match report {
+    Ok(report) => {
+      print!("{}", report);
+      std::process::exit(0);
+    },
+    Err(msg) => {
+      println!("{}", msg);
+      std::process::exit(1);
+    },
+  }
+}
 
-  Some(emulator::Core::from_rom_file(&mut rom_file, header))
+/// Determines how ROM files that don't match their declared size are loaded:
+/// `--rom-size header` (the default) pads or truncates them to the declared
+/// size, `--rom-size file` keeps the whole file, and `--rom-size strict`
+/// refuses to load them
+fn get_rom_size_policy(options: &Options) -> system::RomSizePolicy {
+  let name = match options.get_value("--rom-size") {
+    Some(name) => name,
+    None => return system::RomSizePolicy::Header,
+  };
+  match system::RomSizePolicy::from_name(&name) {
+    Some(policy) => policy,
+    None => {
+      println!("Unknown ROM size policy \"{}\", using the header size", name);
+      system::RomSizePolicy::Header
+    },
+  }
 }
 
 fn fallback_core() -> emulator::Core {
@@ -91,5 +648,11 @@ fn fallback_core() -> emulator::Core {
 }
 
 fn print_usage() {
-  println!("No ROM file specified");
+  println!("Usage: gb-dynarec [options] [rom file]
+
+Options can also be set in config.toml, in the config directory, using their
+names without dashes: `scale = 3`, `palette = \"pocket\"`, `headless = true`.
+Options on the command line take precedence.
+");
+  print!("{}", config::usage());
 }
@@ -1,8 +1,43 @@
-use crate::cart::{CartState, Header, NullCartState};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::vec;
+use crate::cart::{CartState, MbcState, NullCartState};
+use crate::devices::hdma::{HDMA, HDMARequest};
+use crate::debug::watchpoint::{Access, AccessLog, WatchpointSet};
 use crate::devices::io::IO;
-use crate::timing::ClockCycles;
+use crate::error::MemError;
+use crate::savestate::{StateReader, StateWriter};
+use crate::timing::{self, ClockCycles, MachineCycles};
+
+// Loading ROMs from the filesystem is only available with std
+#[cfg(feature = "std")]
+use crate::cart::{CgbSupport, Header};
+#[cfg(feature = "std")]
+use crate::devices::sgb::Sgb;
+#[cfg(feature = "std")]
+use crate::error::Error;
+#[cfg(feature = "std")]
+use crate::system::RomSizePolicy;
+#[cfg(feature = "std")]
 use std::fs::File;
 
+/// Value read from addresses where nothing drives the data bus. On the DMG,
+/// the bus has pull-up resistors, so unconnected lines read high:
+///  - Cart RAM that doesn't exist, or is disabled by the MBC, reads as
+///    OPEN_BUS, and writes to it are ignored.
+///  - I/O registers that don't exist read as OPEN_BUS, and unused bits of
+///    the ones that do read as 1.
+///  - While OAM DMA is running, the CPU reads OPEN_BUS from everything but
+///    the IO registers and high RAM.
+///  - While the PPU is drawing a line, the CPU reads OPEN_BUS from VRAM, and
+///    from OAM during the sprite search as well. Writes are ignored.
+///
+/// The prohibited area at 0xfea0-0xfeff is the exception: it is decoded by
+/// the PPU rather than left floating, and reads as 0 on the DMG. Like OAM,
+/// it reads as OPEN_BUS while the PPU has OAM locked.
+pub const OPEN_BUS: u8 = 0xff;
+
 pub struct MemoryAreas {
   pub rom: Box<[u8]>,
   pub cart_state: Box<dyn CartState>,
@@ -18,10 +53,139 @@ pub struct MemoryAreas {
   pub io: IO,
 
   pub oam_dma: Option<DMAState>,
+  /// Last value written to the DMA register at 0xff46
+  oam_dma_register: u8,
+  pub hdma: HDMA,
+  /// Set when the cartridge header asks for Game Boy Color features. VRAM
+  /// DMA only exists in this mode; on a DMG, its registers read as OPEN_BUS
+  /// and writes to them are ignored.
+  pub cgb_mode: bool,
+  /// Cycles during which the CPU was halted by a VRAM DMA, which have not yet
+  /// been accounted for by the core
+  stalled_cycles: MachineCycles,
+
+  /// When present, the boot ROM is overlaid on top of the cartridge ROM
+  /// until it is unmapped by a write to 0xff50
+  boot_rom: Option<Box<[u8]>>,
+
+  /// Regions of OAM and VRAM that the debugger has made read-only
+  #[cfg(feature = "debug_freeze")]
+  pub freeze: crate::debug::freeze::FreezeTable,
 
+  /// Log of accesses to IO registers that no device implements yet
+  #[cfg(feature = "strict_io")]
+  pub strict_io: crate::devices::strict::StrictIo,
+
+  /// Addresses that stop execution when the CPU accesses them
+  pub watchpoints: WatchpointSet,
+  /// Addresses with callbacks registered on them, which are run once the
+  /// instruction accessing them completes
+  pub access_log: AccessLog,
+  /// Work RAM and high RAM written since compiled code last checked
+  pub dirty_ram: DirtyRam,
+  /// Memory that compiled code can access without calling out to Rust
+  #[cfg(jit_backend)]
+  pub pages: PageTable,
+
+  /// Set when the ROM is mapped directly from the file, and must be unmapped
+  /// when dropped
+  #[cfg(feature = "std")]
   rom_mapped: bool,
 }
 
+/// Bitmaps of the RAM written since they were last cleared, with one bit per
+/// byte, laid out like the coverage maps in the code cache. High RAM is small
+/// and almost every game runs code from it, so writes to it are always
+/// recorded. Writes to cartridge RAM and work RAM are only recorded while
+/// enabled, which is whenever code in those is being compiled.
+pub struct DirtyRam {
+  pub enabled: bool,
+  /// 0xa000-0xbfff, in whichever bank was mapped
+  pub cart_ram: [u64; 128],
+  /// 0xc000-0xdfff, as currently mapped
+  pub work_ram: [u64; 128],
+  /// 0xff80-0xffff
+  pub high_ram: [u64; 2],
+}
+
+impl DirtyRam {
+  pub fn new() -> Self {
+    Self {
+      enabled: false,
+      cart_ram: [0; 128],
+      work_ram: [0; 128],
+      high_ram: [0; 2],
+    }
+  }
+
+  /// Record a write. Writes to echo RAM mark the work RAM they mirror.
+  #[inline(always)]
+  pub fn mark(&mut self, addr: u16) {
+    let (bitmap, index): (&mut [u64], usize) = match addr {
+      0xa000..=0xbfff => (&mut self.cart_ram, addr as usize - 0xa000),
+      0xc000..=0xdfff => (&mut self.work_ram, addr as usize - 0xc000),
+      0xe000..=0xfdff => (&mut self.work_ram, addr as usize - 0xe000),
+      0xff80..=0xfffe => (&mut self.high_ram, addr as usize - 0xff80),
+      _ => return,
+    };
+    bitmap[index / 64] |= 1 << (index % 64);
+  }
+
+  /// Mark all of RAM, for when it is replaced wholesale, like by loading a
+  /// save state
+  pub fn mark_all(&mut self) {
+    self.cart_ram = [!0; 128];
+    self.work_ram = [!0; 128];
+    self.high_ram = [!0; 2];
+  }
+
+  pub fn clear(&mut self) {
+    self.cart_ram = [0; 128];
+    self.work_ram = [0; 128];
+    self.high_ram = [0; 2];
+  }
+}
+
+impl Default for DirtyRam {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Host addresses of the memory that compiled code reads and writes directly,
+/// instead of calling `memory_read_byte` and `memory_write_byte`. Each entry
+/// covers a 4KB page of the address space. A page is only filled in while
+/// accessing it has no side effects, so IO, the cartridge, and anything being
+/// watched or tracked stay zero, which sends compiled code down the slow path.
+/// The table is rebuilt before compiled code runs, and after any write that
+/// could change what is mapped or whether it is accessible.
+#[cfg(jit_backend)]
+pub struct PageTable {
+  pub read: [usize; 16],
+  pub write: [usize; 16],
+  /// High RAM shares its page with IO, so it gets its own entry. Compiled
+  /// code that writes to it marks the write in `DirtyRam` itself.
+  pub high_ram: usize,
+}
+
+#[cfg(jit_backend)]
+impl PageTable {
+  pub fn new() -> Self {
+    Self {
+      read: [0; 16],
+      write: [0; 16],
+      high_ram: 0,
+    }
+  }
+}
+
+#[cfg(jit_backend)]
+impl Default for PageTable {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 /// Stores the state of an active DMA procedure
 #[derive(Copy, Clone)]
 pub struct DMAState {
@@ -33,8 +197,9 @@ pub struct DMAState {
 
 impl MemoryAreas {
   pub fn with_rom(rom_code: Box<[u8]>) -> Self {
-    let mut rom = Vec::<u8>::with_capacity(0x4000);
-    for i in 0..0x4000 {
+    // The smallest cartridge ROM is two banks
+    let mut rom = Vec::<u8>::with_capacity(0x8000);
+    for i in 0..0x8000 {
       if i < rom_code.len() {
         rom.push(rom_code[i]);
       } else if i == rom_code.len() {
@@ -43,8 +208,9 @@ impl MemoryAreas {
         rom.push(0xff);
       }
     }
-    let mut work_ram = Vec::<u8>::with_capacity(0x1000);
-    for _ in 0..0x1000 {
+    // both banks of DMG work RAM, so that all of 0xc000-0xdfff is usable
+    let mut work_ram = Vec::<u8>::with_capacity(0x2000);
+    for _ in 0..0x2000 {
       work_ram.push(0);
     }
     let mut video_ram = Vec::<u8>::with_capacity(0x2000);
@@ -67,27 +233,69 @@ impl MemoryAreas {
       io: IO::new(),
 
       oam_dma: None,
+      oam_dma_register: 0xff,
+      hdma: HDMA::new(),
+      cgb_mode: false,
+      stalled_cycles: MachineCycles(0),
+      #[cfg(feature = "debug_freeze")]
+      freeze: crate::debug::freeze::FreezeTable::new(),
+      #[cfg(feature = "strict_io")]
+      strict_io: crate::devices::strict::StrictIo::new(),
+      watchpoints: WatchpointSet::new(),
+      access_log: AccessLog::new(),
+      dirty_ram: DirtyRam::new(),
+      #[cfg(jit_backend)]
+      pages: PageTable::new(),
+
+      boot_rom: None,
 
+      #[cfg(feature = "std")]
       rom_mapped: false,
     }
   }
 
-  pub fn with_rom_file(rom_file: &mut File, header: &Header) -> Self {
-    let cart_state = header.create_cart_state();
-    let rom_size = header.get_rom_size_bytes();
+  /// Create memory for a ROM file. Along with it comes a warning if the file
+  /// had to be resized to fit its header.
+  #[cfg(feature = "std")]
+  pub fn with_rom_file(rom_file: &mut File, header: &Header, size_policy: RomSizePolicy) -> Result<(Self, Option<String>), Error> {
+    let rom = crate::system::get_rom_buffer(rom_file, header, size_policy)?;
+    Self::with_rom_buffer(rom, header)
+  }
+
+  /// Create memory for a ROM that has already been read, such as one that
+  /// was patched at load time. It is resized according to `size_policy`, the
+  /// same as a file would be.
+  #[cfg(feature = "std")]
+  pub fn with_rom_data(data: Vec<u8>, header: &Header, size_policy: RomSizePolicy) -> Result<(Self, Option<String>), Error> {
+    let size_policy = crate::system::size_policy_for(header, size_policy);
+    let (data, warning) = crate::system::fit_rom_size(data, header.get_rom_size_bytes(), size_policy)?;
+    let rom = crate::system::RomBuffer {
+      data: data.into_boxed_slice(),
+      mapped: false,
+      warning,
+    };
+    Self::with_rom_buffer(rom, header)
+  }
+
+  #[cfg(feature = "std")]
+  fn with_rom_buffer(rom: crate::system::RomBuffer, header: &Header) -> Result<(Self, Option<String>), Error> {
+    let cart_state = header.create_cart_state(&rom.data)?;
     let video_ram_size = 8 * 1024; // 8KB for DMB, 16KB for CGB
     let cart_ram_size = header.get_ram_size_bytes();
     let work_ram_size = 8 * 1024; // 8KB for DMG, 32KB for CGB
 
-    let rom = crate::system::get_rom_buffer(rom_file, rom_size);
     let video_ram = create_buffer(video_ram_size);
     let cart_ram = create_buffer(cart_ram_size);
     let work_ram = create_buffer(work_ram_size);
     let oam_ram = create_buffer(0xa0);
     let high_ram = create_buffer(127);
+    let mut io = IO::new();
+    if header.supports_sgb() {
+      io.sgb = Some(Box::new(Sgb::new()));
+    }
 
-    Self {
-      rom,
+    let memory = Self {
+      rom: rom.data,
       cart_state,
       video_ram,
       cart_ram,
@@ -97,26 +305,180 @@ impl MemoryAreas {
       vram_bank: 0,
       wram_bank: 1,
 
-      io: IO::new(),
+      io,
       oam_dma: None,
+      oam_dma_register: 0xff,
+      hdma: HDMA::new(),
+      cgb_mode: header.get_cgb_support() != CgbSupport::None,
+      stalled_cycles: MachineCycles(0),
+      #[cfg(feature = "debug_freeze")]
+      freeze: crate::debug::freeze::FreezeTable::new(),
+      #[cfg(feature = "strict_io")]
+      strict_io: crate::devices::strict::StrictIo::new(),
+      watchpoints: WatchpointSet::new(),
+      access_log: AccessLog::new(),
+      dirty_ram: DirtyRam::new(),
+      #[cfg(jit_backend)]
+      pages: PageTable::new(),
+
+      boot_rom: None,
+
+      rom_mapped: rom.mapped,
+    };
+    Ok((memory, rom.warning))
+  }
+
+  /// Clear everything a hard reset clears, and map `boot_rom` if there is
+  /// one. The ROM stays loaded, and cartridge RAM keeps its contents, since
+  /// battery-backed saves survive a reset on hardware.
+  pub fn reset(&mut self, boot_rom: Option<Box<[u8]>>) {
+    self.cart_state.set_mbc_state(&MbcState {
+      rom_bank: 1,
+      ram_bank: 0,
+      ram_enabled: false,
+      mode: 0,
+    });
+    for area in [&mut self.video_ram, &mut self.work_ram, &mut self.oam_ram, &mut self.high_ram] {
+      area.fill(0);
+    }
+    self.vram_bank = 0;
+    self.wram_bank = 1;
+    self.io.reset();
+    self.oam_dma = None;
+    self.oam_dma_register = 0xff;
+    self.hdma = HDMA::new();
+    self.stalled_cycles = MachineCycles(0);
+    self.boot_rom = boot_rom;
+    self.dirty_ram.mark_all();
+  }
 
-      rom_mapped: true,
+  /// The ROM, for changing it in place. A ROM mapped from its file is copied
+  /// into memory first, so that the file is never written to.
+  #[cfg(feature = "std")]
+  pub fn writable_rom(&mut self) -> &mut [u8] {
+    if self.rom_mapped {
+      let copy = self.rom.to_vec().into_boxed_slice();
+      let mapped = core::mem::replace(&mut self.rom, copy);
+      crate::system::drop_rom_buffer(mapped);
+      self.rom_mapped = false;
     }
+    &mut self.rom
   }
 
   pub fn as_ptr(&self) -> *const Self {
     self as *const Self
   }
 
+  /// Fill in the page table with every area compiled code can currently
+  /// access directly
+  #[cfg(jit_backend)]
+  pub fn update_page_table(&mut self) {
+    let mut pages = PageTable::new();
+    if !self.watchpoints.is_empty() || !self.access_log.is_empty() {
+      self.pages = pages;
+      return;
+    }
+    pages.high_ram = self.high_ram.as_mut_ptr() as usize;
+    // DMA leaves the CPU nothing but IO and high RAM, and IO is never
+    // accessed directly
+    if self.oam_dma.is_none() {
+      // VRAM is left out while the PPU is drawing. The table is rebuilt every
+      // time compiled code is entered, and compiled code stops at each PPU
+      // mode change, so a chain never carries VRAM into mode 3.
+      let vram_start = 0x2000 * self.vram_bank;
+      let vram_unlocked = !self.io.video.is_vram_locked();
+      if let Some(vram) = self.video_ram.get_mut(vram_start..(vram_start + 0x2000)).filter(|_| vram_unlocked) {
+        let vram = vram.as_mut_ptr() as usize;
+        pages.read[0x8] = vram;
+        pages.read[0x9] = vram + 0x1000;
+        #[cfg(feature = "debug_freeze")]
+        let vram_writable = !self.freeze.has_tiles();
+        #[cfg(not(feature = "debug_freeze"))]
+        let vram_writable = true;
+        if vram_writable {
+          pages.write[0x8] = pages.read[0x8];
+          pages.write[0x9] = pages.read[0x9];
+        }
+      }
+      let bank_start = 0x1000 * self.wram_bank;
+      if self.work_ram.len() >= bank_start + 0x1000 {
+        let wram = self.work_ram.as_mut_ptr() as usize;
+        pages.read[0xc] = wram;
+        pages.read[0xd] = wram + bank_start;
+        // echo RAM, only as far as the page that's entirely a mirror
+        pages.read[0xe] = wram;
+        // writes have to be tracked while code in work RAM is compiled
+        if !self.dirty_ram.enabled {
+          pages.write[0xc] = pages.read[0xc];
+          pages.write[0xd] = pages.read[0xd];
+          pages.write[0xe] = pages.read[0xe];
+        }
+      }
+    }
+    self.pages = pages;
+  }
+
+  /// Overlay a boot ROM on the start of the address space. A DMG boot ROM
+  /// covers 0x0000-0x00ff; a CGB boot ROM also covers 0x0200-0x08ff, leaving
+  /// the cartridge header visible in between.
+  pub fn map_boot_rom(&mut self, boot_rom: Box<[u8]>) {
+    self.boot_rom = Some(boot_rom);
+  }
+
+  pub fn is_boot_rom_mapped(&self) -> bool {
+    self.boot_rom.is_some()
+  }
+
+  fn get_boot_rom_slice(&self, addr: usize) -> Option<&[u8]> {
+    let boot_rom = self.boot_rom.as_ref()?;
+    let end = match addr {
+      0x0000..=0x00ff => 0x100,
+      0x0200..=0x08ff => 0x900,
+      _ => return None,
+    };
+    if end > boot_rom.len() {
+      return None;
+    }
+    Some(&boot_rom[addr..end])
+  }
+
+  /// The ROM bank mapped to 0x4000-0x7fff. Selecting a bank beyond the end of
+  /// the ROM wraps around, as the unused high bits of the bank number are not
+  /// connected on real cartridges.
   pub fn get_rom_bank(&self) -> usize {
-    self.cart_state.get_rom_bank()
+    mirror_bank(self.cart_state.get_rom_bank(), self.get_rom_bank_count())
+  }
+
+  /// The ROM bank mapped to 0x0000-0x3fff, which wraps around the same way
+  pub fn get_low_rom_bank(&self) -> usize {
+    mirror_bank(self.cart_state.get_low_rom_bank(), self.get_rom_bank_count())
+  }
+
+  /// Whether anything but bank 0 can ever be mapped to 0x0000-0x3fff, so
+  /// code there can't be assumed to stay the same
+  pub fn is_low_rom_banked(&self) -> bool {
+    self.cart_state.can_switch_low_rom_bank(self.get_rom_bank_count())
+  }
+
+  fn get_rom_bank_count(&self) -> usize {
+    (self.rom.len() / 0x4000).max(1)
+  }
+
+  pub fn get_mbc_state(&self) -> MbcState {
+    self.cart_state.get_mbc_state()
+  }
+
+  pub fn set_mbc_state(&mut self, state: &MbcState) {
+    self.cart_state.set_mbc_state(state)
   }
 
   pub fn run_clock_cycles(&mut self, cycles: ClockCycles) {
     // If a DMA is currently active, it updates with the rest of the memory bus
     // One byte is copied on each machine cycle. This will copy at most that
     // many bytes (or fewer, if the DMA completes before then).
-    if let Some(dma) = self.oam_dma {
+    // The DMA is taken out of its slot while copying, so that the copy itself
+    // is not subject to the bus restrictions placed on the CPU.
+    if let Some(dma) = self.oam_dma.take() {
       let source = dma.source;
       let mut current_offset = dma.current_offset as usize;
 
@@ -129,8 +491,7 @@ impl MemoryAreas {
         let source = source + current_offset;
 
         let value = memory_read_byte(self as *mut MemoryAreas, source as u16);
-        let dest = 0xfe00 + current_offset as u16;
-        memory_write_byte(self as *mut MemoryAreas, dest, value);
+        self.write_oam(0xfe00 + current_offset as u16, value);
 
         bytes_to_copy -= 1;
         current_offset += 1;
@@ -142,46 +503,216 @@ impl MemoryAreas {
             current_offset: current_offset as u8,
           }
         );
-      } else {
-        self.oam_dma = None;
       }
     }
 
     self.io.run_clock_cycles(cycles, &self.video_ram, &self.oam_ram);
+
+    let (entries, hblank_line) = self.io.video.take_hblank_entries();
+    let blocks = self.hdma.hblank_blocks_entered(entries, hblank_line);
+    if blocks > 0 {
+      self.copy_hdma_blocks(blocks);
+    }
+    // a transfer started partway through an H-blank copies its first block
+    // right away
+    let mode = self.io.video.get_current_mode();
+    let line = self.io.video.get_ly();
+    if self.hdma.should_copy_hblank_block(mode, line) {
+      self.copy_hdma_blocks(1);
+    }
+  }
+
+  /// Write to VRAM directly, as DMA does. Unlike the CPU, it isn't locked
+  /// out while the PPU is drawing.
+  fn write_vram(&mut self, addr: u16, value: u8) {
+    #[cfg(feature = "debug_freeze")]
+    if self.freeze.blocks_vram_write(self.vram_bank, addr, value) {
+      return;
+    }
+    let offset = addr as usize & 0x1fff;
+    self.video_ram[0x2000 * self.vram_bank + offset] = value;
+  }
+
+  fn write_oam(&mut self, addr: u16, value: u8) {
+    #[cfg(feature = "debug_freeze")]
+    if self.freeze.blocks_oam_write(addr, value) {
+      return;
+    }
+    self.oam_ram[addr as usize & 0xff] = value;
+  }
+
+  /// Copy 16-byte blocks from the HDMA source to VRAM. The CPU does not run
+  /// while the copy is in progress.
+  pub fn copy_hdma_blocks(&mut self, blocks: u8) {
+    for _ in 0..blocks {
+      let source = self.hdma.get_source();
+      let dest = self.hdma.get_dest();
+      for offset in 0..0x10 {
+        let value = memory_read_byte(self as *mut MemoryAreas, source.wrapping_add(offset));
+        self.write_vram(dest + offset, value);
+      }
+      self.hdma.complete_block();
+    }
+    self.stalled_cycles.0 += timing::HDMA_BLOCK_CYCLES.as_usize() * blocks as usize;
+  }
+
+  /// Returns the number of cycles the CPU has been stalled since the last call
+  pub fn take_stalled_cycles(&mut self) -> MachineCycles {
+    core::mem::replace(&mut self.stalled_cycles, MachineCycles(0))
+  }
+
+  /// Everything but the ROM itself, which the state must be loaded back
+  /// alongside. The boot ROM is only recorded as mapped or not.
+  pub fn save_state(&self, state: &mut StateWriter) {
+    state.bytes(&self.video_ram);
+    state.bytes(&self.cart_ram);
+    state.bytes(&self.work_ram);
+    state.bytes(&self.oam_ram);
+    state.bytes(&self.high_ram);
+    state.u8(self.vram_bank as u8);
+    state.u8(self.wram_bank as u8);
+
+    let mbc = self.get_mbc_state();
+    state.u16(mbc.rom_bank as u16);
+    state.u8(mbc.ram_bank as u8);
+    state.bool(mbc.ram_enabled);
+    state.u8(mbc.mode);
+
+    self.io.save_state(state);
+    state.bool(self.oam_dma.is_some());
+    let dma = self.oam_dma.unwrap_or(DMAState { source: 0, current_offset: 0 });
+    state.u16(dma.source as u16);
+    state.u8(dma.current_offset);
+    state.u8(self.oam_dma_register);
+    self.hdma.save_state(state);
+    state.u32(self.stalled_cycles.as_usize() as u32);
+    state.bool(self.boot_rom.is_some());
+  }
+
+  pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    state.bytes_into(&mut self.video_ram)?;
+    state.bytes_into(&mut self.cart_ram)?;
+    state.bytes_into(&mut self.work_ram)?;
+    state.bytes_into(&mut self.oam_ram)?;
+    state.bytes_into(&mut self.high_ram)?;
+    self.vram_bank = state.u8()? as usize;
+    self.wram_bank = state.u8()? as usize;
+
+    let mbc = MbcState {
+      rom_bank: state.u16()? as usize,
+      ram_bank: state.u8()? as usize,
+      ram_enabled: state.bool()?,
+      mode: state.u8()?,
+    };
+    self.set_mbc_state(&mbc);
+
+    self.io.load_state(state)?;
+    let dma_active = state.bool()?;
+    let dma = DMAState {
+      source: state.u16()? as usize,
+      current_offset: state.u8()?.min(0xa0),
+    };
+    self.oam_dma = if dma_active { Some(dma) } else { None };
+    self.oam_dma_register = state.u8()?;
+    self.hdma.load_state(state)?;
+    self.stalled_cycles = MachineCycles(state.u32()? as usize);
+    if !state.bool()? {
+      self.boot_rom = None;
+    } else if self.boot_rom.is_none() {
+      return Err(String::from("Save state needs the boot ROM, which is not loaded"));
+    }
+    self.dirty_ram.mark_all();
+    Ok(())
   }
 }
 
+#[cfg(feature = "std")]
 impl Drop for MemoryAreas {
   fn drop(&mut self) {
     if !self.rom_mapped {
       return;
     }
     let reset = vec![0xc3, 0x00, 0x00]; // JP 0x0000, infinite loop
-    let old_rom = std::mem::replace(&mut self.rom, reset.into_boxed_slice());
+    let old_rom = core::mem::replace(&mut self.rom, reset.into_boxed_slice());
     crate::system::drop_rom_buffer(old_rom);
   }
 }
 
-pub fn get_executable_memory_slice<'s>(start: usize, mem_ptr: *const MemoryAreas) -> &'s [u8] {
+pub fn get_executable_memory_slice<'s>(start: usize, mem_ptr: *const MemoryAreas) -> Result<&'s [u8], MemError> {
   let mem = unsafe { &*mem_ptr };
-  match start {
-    0x0000..=0x3fff => &mem.rom[start..0x4000],
+  if let Some(boot_rom) = mem.get_boot_rom_slice(start) {
+    return Ok(boot_rom);
+  }
+  let code = match start {
+    0x0000..=0x3fff => {
+      let bank_start = mem.get_low_rom_bank() * 0x4000;
+      &mem.rom[(bank_start + start)..(bank_start + 0x4000)]
+    },
     0x4000..=0x7fff => {
-      let bank_start = mem.cart_state.get_rom_bank() * 0x4000;
+      let bank_start = mem.get_rom_bank() * 0x4000;
       let bank_end = bank_start + 0x4000;
       let offset = (start & 0x3fff) + bank_start;
       &mem.rom[offset..bank_end]
     },
+    0xa000..=0xbfff => get_cart_ram_code(start, mem),
     0xc000..=0xcfff | 0xe000..=0xefff => &mem.work_ram[(start & 0xfff)..0x1000],
-    0xd000..=0xdfff | 0xf000..=0xfe9f => {
+    0xd000..=0xdfff | 0xf000..=0xfdff => {
       let bank_start = mem.wram_bank * 0x1000;
       let bank_end = bank_start + 0x1000;
       let offset = (start & 0xfff) + bank_start;
       &mem.work_ram[offset..bank_end]
     },
     0xff80..=0xfffe => &mem.high_ram[(start & 0x7f)..],
-    _ => panic!("TRIED TO EXECUTE {:X}", start),
+    _ => return Err(MemError::NotExecutable(start as u16)),
+  };
+  Ok(code)
+}
+
+/// Code in cartridge RAM, up to the end of the mapped bank. While the RAM is
+/// disabled or missing, the CPU fetches open bus.
+pub fn get_cart_ram_code(start: usize, mem: &MemoryAreas) -> &[u8] {
+  static OPEN_BUS_CODE: [u8; 3] = [OPEN_BUS; 3];
+  if mem.cart_state.get_ram_override(start as u16).is_some() {
+    return &OPEN_BUS_CODE;
   }
+  let bank_start = mem.cart_state.get_ram_bank() * 0x2000;
+  let bank_end = (bank_start + 0x2000).min(mem.cart_ram.len());
+  let offset = (start & 0x1fff) + bank_start;
+  mem.cart_ram.get(offset..bank_end).filter(|code| !code.is_empty()).unwrap_or(&OPEN_BUS_CODE)
+}
+
+/// Cartridge RAM that code can be compiled from, when writes to it are
+/// being tracked and it is enabled
+pub fn can_dynarec_cart_ram(addr: usize, mem: &MemoryAreas) -> bool {
+  (0xa000..0xc000).contains(&addr)
+    && mem.cart_state.get_ram_override(addr as u16).is_none()
+    && !mem.cart_ram.is_empty()
+}
+
+/// Copy an instruction that runs past the end of the slice holding its
+/// first byte, like one at 0xcfff that continues into the next bank of work
+/// RAM. Bytes are read the way the CPU would see them.
+pub fn read_straddling_instruction(start: usize, mem: *const MemoryAreas) -> [u8; 3] {
+  let mem = unsafe { &*mem };
+  let mut bytes = [0; 3];
+  for (offset, byte) in bytes.iter_mut().enumerate() {
+    *byte = memory_peek_byte(mem, (start + offset) as u16);
+  }
+  bytes
+}
+
+/// Map a selected bank onto a ROM with `count` banks. Only as many bank bits
+/// as the ROM needs are connected, so the rest are masked off. A ROM whose
+/// size isn't a power of two is built like two chips, a larger one followed
+/// by a smaller one, and the smaller one is mirrored to fill out the space.
+fn mirror_bank(bank: usize, count: usize) -> usize {
+  let size = count.next_power_of_two();
+  let bank = bank & (size - 1);
+  if bank < count {
+    return bank;
+  }
+  let half = size / 2;
+  half + mirror_bank(bank - half, count - half)
 }
 
 fn create_buffer(size: usize) -> Box<[u8]> {
@@ -192,23 +723,88 @@ fn create_buffer(size: usize) -> Box<[u8]> {
   buffer.into_boxed_slice()
 }
 
+/// While an OAM DMA is running, it occupies the external and VRAM buses, so
+/// the CPU can only access the IO registers, High RAM, and the interrupt mask.
+/// That includes the DMA register itself, so a transfer can be restarted.
+fn is_accessible_during_dma(addr: u16) -> bool {
+  addr >= 0xff00
+}
+
+/// Memory access functions are called directly from compiled code. On x86_64
+/// the emitter always uses the System V ABI, even on Windows; elsewhere it
+/// uses the platform's standard C ABI. Callers have to zero-extend address and
+/// value arguments to 32 bits, which optimized builds rely on.
+macro_rules! jit_callable {
+  ($(#[$attr:meta])* pub fn $name:ident($($arg:ident: $ty:ty),*) $(-> $ret:ty)? $body:block) => {
+    $(#[$attr])*
+    #[cfg(target_arch = "x86_64")]
+    pub extern "sysv64" fn $name($($arg: $ty),*) $(-> $ret)? $body
+
+    $(#[$attr])*
+    #[cfg(not(target_arch = "x86_64"))]
+    pub extern "C" fn $name($($arg: $ty),*) $(-> $ret)? $body
+  };
+}
+
+jit_callable! {
 #[inline(never)]
-pub extern "sysv64" fn memory_read_byte(areas: *const MemoryAreas, addr: u16) -> u8 {
+pub fn memory_read_byte(areas: *const MemoryAreas, addr: u16) -> u8 {
   let memory_areas: &MemoryAreas = unsafe { &*areas };
+  let value = if is_locked_by_ppu(memory_areas, addr) {
+    OPEN_BUS
+  } else {
+    memory_peek_byte(memory_areas, addr)
+  };
+  if !memory_areas.watchpoints.is_empty() {
+    memory_areas.watchpoints.check(addr, value, Access::Read);
+  }
+  if !memory_areas.access_log.is_empty() {
+    memory_areas.access_log.check(addr, value, Access::Read);
+  }
+  value
+}
+}
+
+/// Whether the PPU currently has exclusive access to an address
+#[inline(always)]
+fn is_locked_by_ppu(memory_areas: &MemoryAreas, addr: u16) -> bool {
+  match addr {
+    0x8000..=0x9fff => memory_areas.io.video.is_vram_locked(),
+    0xfe00..=0xfeff => memory_areas.io.video.is_oam_locked(),
+    _ => false,
+  }
+}
+
+/// Read a byte the same way the CPU would, but without triggering any
+/// watchpoints, so that debug tools can inspect memory. VRAM and OAM can
+/// be inspected even while the PPU has locked the CPU out of them.
+#[inline(always)]
+pub fn memory_peek_byte(memory_areas: &MemoryAreas, addr: u16) -> u8 {
+  if memory_areas.oam_dma.is_some() && !is_accessible_during_dma(addr) {
+    // The DMA occupies the bus, so the CPU doesn't see the actual value
+    return OPEN_BUS;
+  }
+  if let Some(boot_rom) = memory_areas.get_boot_rom_slice(addr as usize) {
+    return boot_rom[0];
+  }
   if addr < 0x4000 { // ROM Bank 0
-    return memory_areas.rom[addr as usize];
+    return memory_areas.rom[0x4000 * memory_areas.get_low_rom_bank() + addr as usize];
   }
   if addr < 0x8000 { // ROM Bank NN
     let offset = addr as usize & 0x3fff;
-    return memory_areas.rom[0x4000 * memory_areas.cart_state.get_rom_bank() + offset];
+    return memory_areas.rom[0x4000 * memory_areas.get_rom_bank() + offset];
   }
   if addr < 0xa000 { // VRAM
     let offset = addr as usize & 0x1fff;
     return memory_areas.video_ram[offset];
   }
   if addr < 0xc000 { // Cart RAM
+    if let Some(value) = memory_areas.cart_state.get_ram_override(addr) {
+      return value;
+    }
     let offset = addr as usize & 0x1fff;
-    return memory_areas.cart_ram[0x2000 * memory_areas.cart_state.get_ram_bank() + offset];
+    let index = 0x2000 * memory_areas.cart_state.get_ram_bank() + offset;
+    return memory_areas.cart_ram.get(index).copied().unwrap_or(OPEN_BUS);
   }
   if addr < 0xd000 { // Work RAM Bank 0
     let offset = addr as usize & 0xfff;
@@ -218,19 +814,27 @@ pub extern "sysv64" fn memory_read_byte(areas: *const MemoryAreas, addr: u16) ->
     let offset = addr as usize & 0xfff;
     return memory_areas.work_ram[0x1000 * memory_areas.wram_bank + offset];
   }
-  if addr < 0xfe00 { // Mirror
-    return 0;
+  if addr < 0xfe00 { // Echo RAM, a mirror of 0xc000-0xddff
+    let offset = addr as usize & 0xfff;
+    if addr < 0xf000 {
+      return memory_areas.work_ram[offset];
+    }
+    return memory_areas.work_ram[0x1000 * memory_areas.wram_bank + offset];
   }
   if addr < 0xfea0 { // OAM
     let offset = addr as usize & 0xff;
     return memory_areas.oam_ram[offset];
   }
-  if addr < 0xff00 { // unused
+  if addr < 0xff00 { // prohibited area
     return 0;
   }
   if addr < 0xff80 { // I/O
+    #[cfg(feature = "strict_io")]
+    memory_areas.strict_io.check_access(addr, false);
     if addr == 0xff46 {
-      // TODO: OAM should return last written value
+      return memory_areas.oam_dma_register;
+    } else if addr == 0xff55 && memory_areas.cgb_mode {
+      return memory_areas.hdma.get_control();
     } else {
       return memory_areas.io.get_byte(addr);
     }
@@ -242,21 +846,42 @@ pub extern "sysv64" fn memory_read_byte(areas: *const MemoryAreas, addr: u16) ->
   memory_areas.high_ram[addr as usize & 0x7f]
 }
 
+jit_callable! {
 #[inline(never)]
-pub extern "sysv64" fn memory_write_byte(areas: *mut MemoryAreas, addr: u16, value: u8) {
+pub fn memory_write_byte(areas: *mut MemoryAreas, addr: u16, value: u8) {
   let memory_areas: &mut MemoryAreas = unsafe { &mut *areas };
+  if memory_areas.oam_dma.is_some() && !is_accessible_during_dma(addr) {
+    return;
+  }
+  if !memory_areas.watchpoints.is_empty() {
+    memory_areas.watchpoints.check(addr, value, Access::Write);
+  }
+  if !memory_areas.access_log.is_empty() {
+    memory_areas.access_log.check(addr, value, Access::Write);
+  }
+  if is_locked_by_ppu(memory_areas, addr) {
+    return;
+  }
   if addr < 0x8000 { // ROM Banks
     memory_areas.cart_state.write_rom(addr, value);
     return;
   }
   if addr < 0xa000 { // VRAM
-    let offset = addr as usize & 0x1fff;
-    memory_areas.video_ram[0x2000 * memory_areas.vram_bank + offset] = value;
+    memory_areas.write_vram(addr, value);
     return;
   }
+  if addr < 0xfe00 && memory_areas.dirty_ram.enabled {
+    memory_areas.dirty_ram.mark(addr);
+  }
   if addr < 0xc000 { // Cart RAM
+    if memory_areas.cart_state.get_ram_override(addr).is_some() {
+      return;
+    }
     let offset = addr as usize & 0x1fff;
-    memory_areas.cart_ram[0x2000 * memory_areas.cart_state.get_ram_bank() + offset] = value;
+    let index = 0x2000 * memory_areas.cart_state.get_ram_bank() + offset;
+    if let Some(byte) = memory_areas.cart_ram.get_mut(index) {
+      *byte = value;
+    }
     return;
   }
   if addr < 0xd000 { // Work RAM Bank 0
@@ -270,19 +895,29 @@ pub extern "sysv64" fn memory_write_byte(areas: *mut MemoryAreas, addr: u16, val
     memory_areas.work_ram[index] = value;
     return;
   }
-  if addr < 0xfe00 { // Mirror
+  if addr < 0xfe00 { // Echo RAM, a mirror of 0xc000-0xddff
+    let offset = addr as usize & 0xfff;
+    let index = if addr < 0xf000 {
+      offset
+    } else {
+      0x1000 * memory_areas.wram_bank + offset
+    };
+    memory_areas.work_ram[index] = value;
     return;
   }
   if addr < 0xfea0 { // OAM
-    let offset = addr as usize & 0xff;
-    memory_areas.oam_ram[offset] = value;
+    memory_areas.write_oam(addr, value);
     return;
   }
   if addr < 0xff00 { // unused
     return;
   }
   if addr < 0xff80 { // I/O
+    #[cfg(feature = "strict_io")]
+    memory_areas.strict_io.check_access(addr, true);
     if addr == 0xff46 {
+      // a write during a transfer restarts it from the new source
+      memory_areas.oam_dma_register = value;
       let source = (value as usize) << 8;
       memory_areas.oam_dma = Some(
         DMAState {
@@ -290,9 +925,22 @@ pub extern "sysv64" fn memory_write_byte(areas: *mut MemoryAreas, addr: u16, val
           current_offset: 0,
         }
       );
+    } else if addr == 0xff50 {
+      // Any non-zero write permanently unmaps the boot ROM
+      if value != 0 {
+        memory_areas.boot_rom = None;
+      }
+    } else if (0xff51..=0xff55).contains(&addr) {
+      if memory_areas.cgb_mode {
+        write_hdma_register(memory_areas, addr, value);
+      }
     } else {
       memory_areas.io.set_byte(addr, value);
     }
+    // starting a DMA or turning off the LCD changes what compiled code can
+    // access directly
+    #[cfg(jit_backend)]
+    memory_areas.update_page_table();
     return;
   }
   if addr == 0xffff { // Interrupt Mask
@@ -303,25 +951,267 @@ pub extern "sysv64" fn memory_write_byte(areas: *mut MemoryAreas, addr: u16, val
     // High RAM
     let index = addr as usize & 0x7f;
     memory_areas.high_ram[index] = value;
+    memory_areas.dirty_ram.mark(addr);
   }
 }
+}
 
+fn write_hdma_register(memory_areas: &mut MemoryAreas, addr: u16, value: u8) {
+  match addr {
+    0xff51 => memory_areas.hdma.set_source_high(value),
+    0xff52 => memory_areas.hdma.set_source_low(value),
+    0xff53 => memory_areas.hdma.set_dest_high(value),
+    0xff54 => memory_areas.hdma.set_dest_low(value),
+    _ => {
+      if let HDMARequest::GeneralPurpose(blocks) = memory_areas.hdma.set_control(value) {
+        memory_areas.copy_hdma_blocks(blocks);
+      }
+    },
+  }
+}
+
+jit_callable! {
 #[inline(never)]
-pub extern "sysv64" fn memory_write_word(areas: *mut MemoryAreas, addr: u16, value: u16) {
+pub fn memory_write_word(areas: *mut MemoryAreas, addr: u16, value: u16) {
   let low = (value & 0xff) as u8;
   let high = (value >> 8) as u8;
   memory_write_byte(areas, addr, low);
-  memory_write_byte(areas, addr + 1, high);
+  memory_write_byte(areas, addr.wrapping_add(1), high);
+}
 }
 
+jit_callable! {
+/// Write a word pushed onto the stack at `addr`. The CPU writes the high
+/// byte first, which matters when the stack runs into IO registers.
 #[inline(never)]
-pub extern "sysv64" fn memory_read_word(areas: *mut MemoryAreas, addr: u16) -> u16 {
+pub fn memory_push_word(areas: *mut MemoryAreas, addr: u16, value: u16) {
+  memory_write_byte(areas, addr.wrapping_add(1), (value >> 8) as u8);
+  memory_write_byte(areas, addr, (value & 0xff) as u8);
+}
+}
+
+jit_callable! {
+#[inline(never)]
+pub fn memory_read_word(areas: *mut MemoryAreas, addr: u16) -> u16 {
   let low = memory_read_byte(areas, addr) as u16;
-  let high = memory_read_byte(areas, addr + 1) as u16;
+  let high = memory_read_byte(areas, addr.wrapping_add(1)) as u16;
   (high << 8) | low
 }
+}
 
 pub fn can_dynarec(addr: usize) -> bool {
   addr < 0x8000
 }
 
+/// Work RAM that code can be compiled from, when writes to it are being
+/// tracked. Code running from echo RAM is always interpreted.
+pub fn can_dynarec_wram(addr: usize) -> bool {
+  (0xc000..0xe000).contains(&addr)
+}
+
+/// High RAM, which code can always be compiled from
+pub fn can_dynarec_hram(addr: usize) -> bool {
+  (0xff80..0xffff).contains(&addr)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::{
+    get_executable_memory_slice, mirror_bank, memory_peek_byte, memory_read_byte, memory_write_byte, read_straddling_instruction,
+    MemoryAreas, OPEN_BUS,
+  };
+  use crate::cart::MBC1CartState;
+  use crate::timing::ClockCycles;
+  use alloc::boxed::Box;
+  use alloc::vec;
+
+  #[test]
+  fn open_bus_regions() {
+    let mut memory = MemoryAreas::with_rom(vec![0x00].into_boxed_slice());
+    let mem_ptr = &mut memory as *mut MemoryAreas;
+    // without any cart RAM, reads float and writes go nowhere
+    memory_write_byte(mem_ptr, 0xa000, 0x12);
+    assert_eq!(memory_read_byte(mem_ptr, 0xa000), OPEN_BUS);
+    assert_eq!(memory_read_byte(mem_ptr, 0xfea0), 0x00);
+    assert_eq!(memory_read_byte(mem_ptr, 0xff03), OPEN_BUS);
+    // unused register bits read high
+    memory_write_byte(mem_ptr, 0xff07, 0x05);
+    assert_eq!(memory_read_byte(mem_ptr, 0xff07), 0xfd);
+    assert_eq!(memory_read_byte(mem_ptr, 0xff41) & 0x80, 0x80);
+  }
+
+  #[test]
+  fn disabled_cart_ram() {
+    let mut memory = MemoryAreas::with_rom(vec![0x00].into_boxed_slice());
+    memory.cart_state = Box::new(MBC1CartState::new());
+    memory.cart_ram = vec![0; 0x2000].into_boxed_slice();
+    let mem_ptr = &mut memory as *mut MemoryAreas;
+    memory_write_byte(mem_ptr, 0xa000, 0x12);
+    assert_eq!(memory_read_byte(mem_ptr, 0xa000), OPEN_BUS);
+    memory_write_byte(mem_ptr, 0x0000, 0x0a);
+    assert_eq!(memory_read_byte(mem_ptr, 0xa000), 0x00);
+    memory_write_byte(mem_ptr, 0xa000, 0x12);
+    assert_eq!(memory_read_byte(mem_ptr, 0xa000), 0x12);
+    // banks past the end of RAM are also open bus
+    memory_write_byte(mem_ptr, 0x4000, 0x03);
+    memory_write_byte(mem_ptr, 0x6000, 0x01);
+    assert_eq!(memory_read_byte(mem_ptr, 0xa000), OPEN_BUS);
+  }
+
+  #[test]
+  fn bank_mirroring() {
+    // powers of two only mask
+    assert_eq!(mirror_bank(7, 4), 3);
+    assert_eq!(mirror_bank(0x21, 32), 1);
+    // six banks are four, then two mirrored over banks 6 and 7
+    assert_eq!((0..8).map(|bank| mirror_bank(bank, 6)).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4, 5, 4, 5]);
+    assert_eq!(mirror_bank(14, 6), 4);
+    assert_eq!(mirror_bank(6, 3), 2);
+    assert_eq!(mirror_bank(5, 1), 0);
+  }
+
+  #[test]
+  fn no_vram_dma_on_dmg() {
+    let mut memory = MemoryAreas::with_rom(vec![0x00].into_boxed_slice());
+    let mem_ptr = &mut memory as *mut MemoryAreas;
+    memory.work_ram[0] = 0x12;
+    memory_write_byte(mem_ptr, 0xff51, 0xc0);
+    memory_write_byte(mem_ptr, 0xff52, 0x00);
+    memory_write_byte(mem_ptr, 0xff53, 0x00);
+    memory_write_byte(mem_ptr, 0xff54, 0x00);
+    memory_write_byte(mem_ptr, 0xff55, 0x00);
+    assert_eq!(memory.video_ram[0], 0x00);
+    assert_eq!(memory_read_byte(mem_ptr, 0xff55), OPEN_BUS);
+    assert_eq!(memory.take_stalled_cycles().0, 0);
+
+    memory.cgb_mode = true;
+    memory_write_byte(mem_ptr, 0xff51, 0xc0);
+    memory_write_byte(mem_ptr, 0xff55, 0x00);
+    assert_eq!(memory.video_ram[0], 0x12);
+    assert_eq!(memory_read_byte(mem_ptr, 0xff55), 0xff);
+  }
+
+  #[test]
+  fn ppu_locks() {
+    let mut memory = MemoryAreas::with_rom(vec![0x00].into_boxed_slice());
+    let mem_ptr = &mut memory as *mut MemoryAreas;
+    memory_write_byte(mem_ptr, 0x8000, 0x12);
+    memory_write_byte(mem_ptr, 0xfe00, 0x34);
+    // turn on the LCD, and run to the sprite search at the start of a line
+    memory_write_byte(mem_ptr, 0xff40, 0x91);
+    while memory.io.video.get_current_mode() != 2 {
+      memory.run_clock_cycles(ClockCycles(4));
+    }
+    assert_eq!(memory_read_byte(mem_ptr, 0x8000), 0x12);
+    assert_eq!(memory_read_byte(mem_ptr, 0xfe00), OPEN_BUS);
+    assert_eq!(memory_read_byte(mem_ptr, 0xfea0), OPEN_BUS);
+    memory_write_byte(mem_ptr, 0xfe00, 0x56);
+    assert_eq!(memory_peek_byte(&memory, 0xfe00), 0x34);
+
+    while memory.io.video.get_current_mode() != 3 {
+      memory.run_clock_cycles(ClockCycles(4));
+    }
+    assert_eq!(memory_read_byte(mem_ptr, 0x8000), OPEN_BUS);
+    memory_write_byte(mem_ptr, 0x8000, 0x56);
+    assert_eq!(memory_peek_byte(&memory, 0x8000), 0x12);
+    // work RAM is never locked, and DMA can still write to OAM
+    memory_write_byte(mem_ptr, 0xc000, 0x78);
+    assert_eq!(memory_read_byte(mem_ptr, 0xc000), 0x78);
+    memory_write_byte(mem_ptr, 0xff46, 0xc0);
+    memory.run_clock_cycles(ClockCycles(8));
+    assert_eq!(memory.oam_ram[0], 0x78);
+
+    // wait for the DMA to finish, since it blocks the CPU as well
+    memory.run_clock_cycles(ClockCycles(0xa0 * 4));
+    while memory.io.video.get_current_mode() != 0 {
+      memory.run_clock_cycles(ClockCycles(4));
+    }
+    memory_write_byte(mem_ptr, 0x8000, 0x56);
+    assert_eq!(memory_read_byte(mem_ptr, 0x8000), 0x56);
+    assert_eq!(memory_read_byte(mem_ptr, 0xfea0), 0x00);
+  }
+
+  #[test]
+  fn hblank_hdma_across_lines() {
+    let mut memory = MemoryAreas::with_rom(vec![0x00].into_boxed_slice());
+    memory.cgb_mode = true;
+    let mem_ptr = &mut memory as *mut MemoryAreas;
+    for i in 0..0x40 {
+      memory_write_byte(mem_ptr, 0xc000 + i, i as u8 + 1);
+    }
+    memory_write_byte(mem_ptr, 0xff40, 0x91);
+    while memory.io.video.get_current_mode() != 2 {
+      memory.run_clock_cycles(ClockCycles(4));
+    }
+    memory_write_byte(mem_ptr, 0xff51, 0xc0);
+    memory_write_byte(mem_ptr, 0xff52, 0x00);
+    memory_write_byte(mem_ptr, 0xff53, 0x01);
+    memory_write_byte(mem_ptr, 0xff54, 0x00);
+    memory_write_byte(mem_ptr, 0xff55, 0x83);
+    // a single catch-up covering three whole lines still copies a block in
+    // each of their H-blanks
+    memory.run_clock_cycles(ClockCycles(456 * 3));
+    assert_eq!(memory_read_byte(mem_ptr, 0xff55), 0x00);
+    assert_eq!(&memory.video_ram[0x100..0x130], &memory.work_ram[0..0x30]);
+    assert_eq!(memory.video_ram[0x130], 0);
+  }
+
+  #[test]
+  fn echo_ram() {
+    let mut memory = MemoryAreas::with_rom(vec![0x00].into_boxed_slice());
+    let mem_ptr = &mut memory as *mut MemoryAreas;
+    memory_write_byte(mem_ptr, 0xc123, 0x12);
+    assert_eq!(memory_read_byte(mem_ptr, 0xe123), 0x12);
+    memory_write_byte(mem_ptr, 0xfdff, 0x34);
+    assert_eq!(memory_read_byte(mem_ptr, 0xddff), 0x34);
+    // the upper half mirrors whichever bank is switched in
+    memory_write_byte(mem_ptr, 0xf000, 0x56);
+    assert_eq!(memory.work_ram[0x1000 * memory.wram_bank], 0x56);
+    // nothing past the end of the mirror is written
+    memory_write_byte(mem_ptr, 0xfea0, 0x78);
+    assert_eq!(memory_read_byte(mem_ptr, 0xfea0), 0x00);
+    assert_eq!(memory.oam_ram[0], 0x00);
+  }
+
+  #[test]
+  fn straddling_instructions() {
+    let mut memory = MemoryAreas::with_rom(vec![0x00].into_boxed_slice());
+    let mem_ptr = &mut memory as *mut MemoryAreas;
+    // LD BC, 0x5678, split across both banks of work RAM
+    memory_write_byte(mem_ptr, 0xcfff, 0x01);
+    memory_write_byte(mem_ptr, 0xd000, 0x78);
+    memory_write_byte(mem_ptr, 0xd001, 0x56);
+    assert_eq!(get_executable_memory_slice(0xcfff, mem_ptr).unwrap().len(), 1);
+    assert_eq!(read_straddling_instruction(0xcfff, mem_ptr), [0x01, 0x78, 0x56]);
+    // writes to high RAM are always recorded, and work RAM only when enabled
+    assert_eq!(memory.dirty_ram.work_ram[63], 0);
+    memory_write_byte(mem_ptr, 0xff81, 0x00);
+    assert_eq!(memory.dirty_ram.high_ram[0], 0b10);
+    memory.dirty_ram.enabled = true;
+    memory_write_byte(mem_ptr, 0xefff, 0x00);
+    assert_eq!(memory.dirty_ram.work_ram[63], 1 << 63);
+  }
+
+  #[cfg(jit_backend)]
+  #[test]
+  fn page_table() {
+    let mut memory = MemoryAreas::with_rom(vec![0x00].into_boxed_slice());
+    memory.update_page_table();
+    let wram = memory.work_ram.as_ptr() as usize;
+    assert_eq!(memory.pages.read[0xc], wram);
+    assert_eq!(memory.pages.write[0xe], wram);
+    assert_eq!(memory.pages.read[0xd], wram + 0x1000 * memory.wram_bank);
+    // ROM, cart RAM, and the top page always go through the slow path
+    assert_eq!(memory.pages.read[0x0], 0);
+    assert_eq!(memory.pages.read[0xa], 0);
+    assert_eq!(memory.pages.read[0xf], 0);
+    assert_eq!(memory.pages.high_ram, memory.high_ram.as_ptr() as usize);
+
+    // tracked writes have to go through the slow path
+    memory.dirty_ram.enabled = true;
+    memory.update_page_table();
+    assert_eq!(memory.pages.read[0xc], wram);
+    assert_eq!(memory.pages.write[0xc], 0);
+  }
+}
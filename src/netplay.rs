@@ -0,0 +1,323 @@
+//! Lockstep netplay for link cable games. Both instances emulate both Game
+//! Boys, connected by a cable in memory, and only exchange joypad input. At
+//! the start of every frame, each side sends its input and stalls until it
+//! has the other side's input for that frame. Since the cores are
+//! deterministic, both sides then run the same frame and stay in sync.
+//!
+//! Input is sent a few frames ahead of when it is used, so that latency only
+//! causes a stall when it exceeds that delay.
+
+use crate::devices::link::link_cable;
+use crate::emulator::Core;
+use crate::input::ButtonSet;
+use crate::patch::crc32;
+use crate::timing::{self, FRAME_CYCLES};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// Frames of input delay, unless configured
+pub const DEFAULT_INPUT_DELAY: u32 = 2;
+
+const HANDSHAKE_MAGIC: [u8; 4] = *b"GBNP";
+
+/// One frame of a player's input
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct InputPacket {
+  pub frame: u32,
+  pub buttons: ButtonSet,
+}
+
+impl InputPacket {
+  pub const LENGTH: usize = 5;
+
+  pub fn to_bytes(&self) -> [u8; Self::LENGTH] {
+    let frame = self.frame.to_be_bytes();
+    [frame[0], frame[1], frame[2], frame[3], self.buttons.bits()]
+  }
+
+  pub fn from_bytes(bytes: [u8; Self::LENGTH]) -> Self {
+    Self {
+      frame: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+      buttons: ButtonSet::from_bits(bytes[4]),
+    }
+  }
+}
+
+/// Exchanges input with the other instance, one frame at a time
+pub struct Lockstep<S: Read + Write> {
+  stream: S,
+  delay: u32,
+  /// Next frame to be run
+  frame: u32,
+  /// Input for upcoming frames, starting with the next one
+  local_inputs: VecDeque<ButtonSet>,
+  remote_inputs: VecDeque<ButtonSet>,
+  /// Frame number expected in the next packet received
+  next_remote_frame: u32,
+}
+
+impl<S: Read + Write> Lockstep<S> {
+  pub fn new(stream: S, delay: u32) -> Self {
+    // nobody presses anything during the first few frames
+    let empty: VecDeque<ButtonSet> = (0..delay).map(|_| ButtonSet::empty()).collect();
+    Self {
+      stream,
+      delay,
+      frame: 0,
+      local_inputs: empty.clone(),
+      remote_inputs: empty,
+      next_remote_frame: delay,
+    }
+  }
+
+  pub fn get_frame(&self) -> u32 {
+    self.frame
+  }
+
+  /// Send this frame's local input, which takes effect after the input
+  /// delay, and wait for the remote input needed to run the next frame.
+  /// Returns the local and remote input for that frame.
+  pub fn exchange(&mut self, local: ButtonSet) -> Result<(ButtonSet, ButtonSet), String> {
+    let packet = InputPacket { frame: self.frame + self.delay, buttons: local };
+    self.stream.write_all(&packet.to_bytes())
+      .and_then(|_| self.stream.flush())
+      .map_err(|_| String::from("Netplay connection lost"))?;
+    self.local_inputs.push_back(local);
+
+    while self.remote_inputs.is_empty() {
+      let mut bytes = [0; InputPacket::LENGTH];
+      self.stream.read_exact(&mut bytes).map_err(|_| String::from("Netplay connection lost"))?;
+      let packet = InputPacket::from_bytes(bytes);
+      if packet.frame != self.next_remote_frame {
+        return Err(format!("Netplay desync: expected input for frame {}, received {}", self.next_remote_frame, packet.frame));
+      }
+      self.next_remote_frame += 1;
+      self.remote_inputs.push_back(packet.buttons);
+    }
+
+    self.frame += 1;
+    // both queues are non-empty at this point
+    Ok((self.local_inputs.pop_front().unwrap(), self.remote_inputs.pop_front().unwrap()))
+  }
+}
+
+/// Check that both sides are running the same ROM on the same engine, before
+/// any input is sent. Compiled blocks time interrupts differently from the
+/// interpreter, so mixing them would desync.
+fn handshake<S: Read + Write>(stream: &mut S, rom_checksum: u32, use_jit: bool) -> Result<(), String> {
+  let mut message = [0; 9];
+  message[..4].copy_from_slice(&HANDSHAKE_MAGIC);
+  message[4..8].copy_from_slice(&rom_checksum.to_be_bytes());
+  message[8] = use_jit as u8;
+  stream.write_all(&message).map_err(|_| String::from("Netplay handshake failed"))?;
+  let mut reply = [0; 9];
+  stream.read_exact(&mut reply).map_err(|_| String::from("Netplay handshake failed"))?;
+  if reply[..4] != HANDSHAKE_MAGIC {
+    return Err(String::from("The other side is not a netplay session"));
+  }
+  if reply[4..8] != message[4..8] {
+    return Err(String::from("The other side is running a different ROM"));
+  }
+  if reply[8] != message[8] {
+    return Err(String::from("Both sides must use the JIT, or both use the interpreter"));
+  }
+  Ok(())
+}
+
+/// Wait for another instance to connect on `port`. The host is player 1.
+pub fn host(port: u16) -> Result<TcpStream, String> {
+  let listener = TcpListener::bind(("0.0.0.0", port)).map_err(|_| format!("Unable to listen on port {}", port))?;
+  println!("Waiting for netplay connection on port {}", port);
+  let (stream, address) = listener.accept().map_err(|_| String::from("Netplay connection failed"))?;
+  println!("Netplay connected to {}", address);
+  Ok(stream)
+}
+
+/// Connect to a host at `address`, like `192.168.0.2:7777`. The guest is
+/// player 2.
+pub fn join(address: &str) -> Result<TcpStream, String> {
+  let stream = TcpStream::connect(address).map_err(|_| format!("Unable to connect to \"{}\"", address))?;
+  println!("Netplay connected to {}", address);
+  Ok(stream)
+}
+
+/// Runs the remote player's core alongside the local one. The session is
+/// attached to the local core, which drives it in place of its own frames.
+pub struct NetplaySession<S: Read + Write = TcpStream> {
+  lockstep: Lockstep<S>,
+  remote: Core,
+  /// 0 for player 1, 1 for player 2. Player 1 always runs first, so that
+  /// both sides interleave the cores identically.
+  local_player: usize,
+}
+
+impl<S: Read + Write> NetplaySession<S> {
+  /// Connect the two cores with a link cable, and confirm the other side is
+  /// running the same ROM. Both cores must have just been created from it.
+  pub fn new(local: &mut Core, mut remote: Core, mut stream: S, local_player: usize, delay: u32) -> Result<Self, String> {
+    handshake(&mut stream, crc32(&local.memory.rom), local.is_jit_enabled())?;
+    remote.set_jit_enabled(local.is_jit_enabled())?;
+    let (first, second) = link_cable();
+    let (local_end, remote_end) = if local_player == 0 { (first, second) } else { (second, first) };
+    for (core, end) in [(&mut *local, local_end), (&mut remote, remote_end)] {
+      match core.memory.io.serial_port() {
+        Some(serial) => serial.connect(Box::new(end)),
+        None => return Err(String::from("Netplay requires the serial port")),
+      }
+    }
+    Ok(Self {
+      lockstep: Lockstep::new(stream, delay),
+      remote,
+      local_player,
+    })
+  }
+
+  pub fn get_remote_core(&self) -> &Core {
+    &self.remote
+  }
+
+  /// Exchange input, then run both cores for one frame in alternating
+  /// scanline-length slices. Breakpoints would stop only one side, so they
+  /// are skipped over during netplay.
+  pub fn run_frame(&mut self, local: &mut Core) -> Result<(), String> {
+    let local_buttons = local.input.next_frame();
+    let (local_buttons, remote_buttons) = self.lockstep.exchange(local_buttons)?;
+    local.input.commit_buttons(local.memory.io.joypad_port(), local_buttons);
+    self.remote.input.commit_buttons(self.remote.memory.io.joypad_port(), remote_buttons);
+
+    // Frames are counted in cycles rather than by vblank, so that they line
+    // up even while the LCD is off
+    let frame_end = self.lockstep.get_frame() as u64 * FRAME_CYCLES;
+    let slice = timing::SCANLINE_CYCLES.as_usize() as u64;
+    let mut slice_end = (frame_end - FRAME_CYCLES) + slice;
+    while slice_end <= frame_end {
+      let (first, second) = if self.local_player == 0 {
+        (&mut *local, &mut self.remote)
+      } else {
+        (&mut self.remote, &mut *local)
+      };
+      first.run_until_cycle(slice_end);
+      second.run_until_cycle(slice_end);
+      slice_end += slice;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{InputPacket, Lockstep, NetplaySession};
+  use crate::devices::joypad::Button;
+  use crate::emulator::Core;
+  use crate::input::ButtonSet;
+  use std::net::{TcpListener, TcpStream};
+  use std::sync::{Arc, Barrier};
+  use std::thread;
+
+  fn buttons(bits: u8) -> ButtonSet {
+    ButtonSet::from_bits(bits)
+  }
+
+  fn connected_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let guest = TcpStream::connect(address).unwrap();
+    let (host, _) = listener.accept().unwrap();
+    (host, guest)
+  }
+
+  #[test]
+  fn packet_round_trip() {
+    let packet = InputPacket { frame: 0x01020304, buttons: buttons(0x81) };
+    assert_eq!(packet.to_bytes(), [1, 2, 3, 4, 0x81]);
+    assert_eq!(InputPacket::from_bytes(packet.to_bytes()), packet);
+  }
+
+  #[test]
+  fn lockstep_input_delay() {
+    let (host, guest) = connected_pair();
+    // each side returns its stream, since closing one with unread input
+    // resets the connection before the other side can read everything
+    let remote = thread::spawn(move || {
+      let mut lockstep = Lockstep::new(guest, 2);
+      let inputs: Vec<_> = (0..4).map(|frame| lockstep.exchange(buttons(0x10 + frame)).unwrap()).collect();
+      (inputs, lockstep)
+    });
+    let mut lockstep = Lockstep::new(host, 2);
+    let local: Vec<_> = (0..4).map(|frame| lockstep.exchange(buttons(frame)).unwrap()).collect();
+    let (remote, _) = remote.join().unwrap();
+
+    // input sent on frame N is used on frame N + 2, on both sides
+    let expected = vec![
+      (buttons(0), buttons(0)),
+      (buttons(0), buttons(0)),
+      (buttons(0), buttons(0x10)),
+      (buttons(1), buttons(0x11)),
+    ];
+    assert_eq!(local, expected);
+    let mirrored: Vec<_> = expected.iter().map(|(a, b)| (*b, *a)).collect();
+    assert_eq!(remote, mirrored);
+  }
+
+  /// Each side sends its joypad state to the other over the link cable, with
+  /// player 1 providing the clock, and stores what it receives at 0xc000
+  fn link_program() -> Box<[u8]> {
+    vec![
+      0x3e, 0x20, // LD A, 0x20
+      0xe0, 0x00, // LDH (0x00), A
+      0xf0, 0x00, // LDH A, (0x00)
+      0xe0, 0x01, // LDH (0x01), A
+      0x78, // LD A, B
+      0xe0, 0x02, // LDH (0x02), A
+      0xf0, 0x02, // LDH A, (0x02)
+      0xe6, 0x80, // AND 0x80
+      0x20, 0xfa, // JR NZ, -6
+      0xf0, 0x01, // LDH A, (0x01)
+      0xea, 0x00, 0xc0, // LD (0xc000), A
+      0x18, 0xe8, // JR -24
+    ].into_boxed_slice()
+  }
+
+  fn create_player(player: usize) -> Core {
+    let mut core = Core::with_code_block(link_program());
+    core.skip_idle_loops = false;
+    // B holds the serial control value: player 1 uses the internal clock
+    core.registers.set_b(if player == 0 { 0x81 } else { 0x80 });
+    core
+  }
+
+  fn run_side(stream: TcpStream, player: usize, press: Button) -> ((u8, u8), NetplaySession) {
+    let mut local = create_player(player);
+    let remote = create_player(1 - player);
+    let mut session = NetplaySession::new(&mut local, remote, stream, player, 2).unwrap();
+    local.input.press(press);
+    for _ in 0..10 {
+      session.run_frame(&mut local).unwrap();
+    }
+    let received = |core: &Core| core.memory.work_ram[0];
+    let cores = if player == 0 { (&local, session.get_remote_core()) } else { (session.get_remote_core(), &local) };
+    let result = (received(cores.0), received(cores.1));
+    (result, session)
+  }
+
+  #[cfg(feature = "serial")]
+  #[test]
+  fn sessions_stay_in_sync() {
+    let (host, guest) = connected_pair();
+    // sessions can't be sent between threads, so the remote one is kept open
+    // until both sides are done
+    let done = Arc::new(Barrier::new(2));
+    let remote_done = done.clone();
+    let remote = thread::spawn(move || {
+      let (result, _session) = run_side(guest, 1, Button::Up);
+      remote_done.wait();
+      result
+    });
+    let (local, _session) = run_side(host, 0, Button::Right);
+    done.wait();
+    assert_eq!(remote.join().unwrap(), local);
+    // each player received the other's direction, active low
+    assert_eq!(local, (0x2b, 0x2e));
+  }
+}
@@ -0,0 +1,355 @@
+//! Soft-patching of ROM images. IPS and BPS patches are applied to a copy of
+//! the ROM in memory when it is loaded, so the original dump is never
+//! modified. The patch format is detected from its magic number.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+/// Source, target, and patch CRC32s at the end of a BPS patch
+const BPS_FOOTER_LENGTH: usize = 12;
+
+/// Apply an IPS or BPS patch to a ROM, returning the patched copy
+pub fn apply_patch(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+  if patch.starts_with(IPS_MAGIC) {
+    apply_ips(rom, patch)
+  } else if patch.starts_with(BPS_MAGIC) {
+    apply_bps(rom, patch)
+  } else {
+    Err(String::from("Unrecognized patch format"))
+  }
+}
+
+/// Reads big-endian fields from an IPS patch
+struct IpsReader<'a> {
+  patch: &'a [u8],
+  position: usize,
+}
+
+impl<'a> IpsReader<'a> {
+  fn read(&mut self, length: usize) -> Result<&'a [u8], String> {
+    let end = self.position + length;
+    if end > self.patch.len() {
+      return Err(String::from("IPS patch is truncated"));
+    }
+    let bytes = &self.patch[self.position..end];
+    self.position = end;
+    Ok(bytes)
+  }
+
+  fn read_number(&mut self, length: usize) -> Result<usize, String> {
+    let bytes = self.read(length)?;
+    Ok(bytes.iter().fold(0, |value, byte| (value << 8) | *byte as usize))
+  }
+}
+
+/// An IPS patch is a list of records, each of which overwrites a run of
+/// bytes, or fills it with a single repeated byte. Records past the end of
+/// the ROM extend it. The end marker can be followed by a length to truncate
+/// the ROM to.
+pub fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+  if !patch.starts_with(IPS_MAGIC) {
+    return Err(String::from("Not an IPS patch"));
+  }
+  let mut output = Vec::from(rom);
+  let mut reader = IpsReader { patch, position: IPS_MAGIC.len() };
+  loop {
+    if reader.read(3)? == IPS_EOF {
+      break;
+    }
+    reader.position -= 3;
+    let offset = reader.read_number(3)?;
+    let length = reader.read_number(2)?;
+    if length == 0 {
+      let run_length = reader.read_number(2)?;
+      let value = reader.read(1)?[0];
+      write_run(&mut output, offset, &alloc::vec![value; run_length]);
+    } else {
+      let data = reader.read(length)?;
+      write_run(&mut output, offset, data);
+    }
+  }
+  if reader.position + 3 <= patch.len() {
+    let truncate = reader.read_number(3)?;
+    output.truncate(truncate);
+  }
+  Ok(output)
+}
+
+fn write_run(output: &mut Vec<u8>, offset: usize, data: &[u8]) {
+  let end = offset + data.len();
+  if end > output.len() {
+    output.resize(end, 0);
+  }
+  output[offset..end].copy_from_slice(data);
+}
+
+/// Reads variable-length numbers and raw bytes from a BPS patch
+struct BpsReader<'a> {
+  patch: &'a [u8],
+  position: usize,
+  /// Actions end where the footer begins
+  end: usize,
+}
+
+impl<'a> BpsReader<'a> {
+  fn read_byte(&mut self) -> Result<u8, String> {
+    if self.position >= self.end {
+      return Err(String::from("BPS patch is truncated"));
+    }
+    let byte = self.patch[self.position];
+    self.position += 1;
+    Ok(byte)
+  }
+
+  /// Numbers are stored 7 bits at a time, with the high bit marking the last
+  /// byte. Each continuation also adds one, so that every number has exactly
+  /// one encoding.
+  fn read_number(&mut self) -> Result<usize, String> {
+    let mut value: usize = 0;
+    let mut shift: usize = 1;
+    loop {
+      let byte = self.read_byte()?;
+      value = value
+        .checked_add((byte & 0x7f) as usize * shift)
+        .ok_or_else(|| String::from("BPS patch contains an invalid number"))?;
+      if byte & 0x80 != 0 {
+        return Ok(value);
+      }
+      shift = shift
+        .checked_shl(7)
+        .filter(|s| *s < (1 << 56))
+        .ok_or_else(|| String::from("BPS patch contains an invalid number"))?;
+      value += shift;
+    }
+  }
+
+  /// Read a signed offset, stored as a magnitude with the sign in bit 0
+  fn read_offset(&mut self) -> Result<isize, String> {
+    let data = self.read_number()?;
+    let magnitude = (data >> 1) as isize;
+    if data & 1 != 0 {
+      Ok(-magnitude)
+    } else {
+      Ok(magnitude)
+    }
+  }
+}
+
+fn read_u32_le(bytes: &[u8]) -> u32 {
+  u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Move a relative offset used by BPS copy actions, checking that it stays
+/// inside of the buffer being copied from
+fn seek(position: usize, delta: isize, length: usize) -> Result<usize, String> {
+  match position.checked_add_signed(delta) {
+    Some(next) if next <= length => Ok(next),
+    _ => Err(String::from("BPS patch copies from outside of the ROM")),
+  }
+}
+
+/// A BPS patch builds the target ROM from a sequence of actions that copy
+/// from the source ROM, the patch, or earlier parts of the target. Checksums
+/// of the source, target, and patch are all verified, so a patch made for a
+/// different dump is rejected.
+pub fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+  if !patch.starts_with(BPS_MAGIC) {
+    return Err(String::from("Not a BPS patch"));
+  }
+  if patch.len() < BPS_MAGIC.len() + BPS_FOOTER_LENGTH {
+    return Err(String::from("BPS patch is truncated"));
+  }
+  let footer = &patch[(patch.len() - BPS_FOOTER_LENGTH)..];
+  let source_crc = read_u32_le(&footer[0..4]);
+  let target_crc = read_u32_le(&footer[4..8]);
+  let patch_crc = read_u32_le(&footer[8..12]);
+  if crc32(&patch[..(patch.len() - 4)]) != patch_crc {
+    return Err(String::from("BPS patch is corrupt"));
+  }
+  if crc32(rom) != source_crc {
+    return Err(String::from("BPS patch was made for a different ROM"));
+  }
+
+  let mut reader = BpsReader {
+    patch,
+    position: BPS_MAGIC.len(),
+    end: patch.len() - BPS_FOOTER_LENGTH,
+  };
+  let source_size = reader.read_number()?;
+  let target_size = reader.read_number()?;
+  let metadata_size = reader.read_number()?;
+  if source_size != rom.len() {
+    return Err(format!("BPS patch expects a {} byte ROM, but it is {} bytes", source_size, rom.len()));
+  }
+  reader.position = reader
+    .position
+    .checked_add(metadata_size)
+    .filter(|position| *position <= reader.end)
+    .ok_or_else(|| String::from("BPS patch is truncated"))?;
+
+  let mut output: Vec<u8> = Vec::with_capacity(target_size);
+  let mut source_offset: usize = 0;
+  let mut target_offset: usize = 0;
+  while reader.position < reader.end {
+    let action = reader.read_number()?;
+    let length = (action >> 2) + 1;
+    if output.len() + length > target_size {
+      return Err(String::from("BPS patch writes past the end of the ROM"));
+    }
+    match action & 3 {
+      // SourceRead: copy from the same offset in the source
+      0 => {
+        let start = output.len();
+        let bytes = rom
+          .get(start..(start + length))
+          .ok_or_else(|| String::from("BPS patch copies from outside of the ROM"))?;
+        output.extend_from_slice(bytes);
+      },
+      // TargetRead: copy bytes stored in the patch
+      1 => {
+        for _ in 0..length {
+          let byte = reader.read_byte()?;
+          output.push(byte);
+        }
+      },
+      // SourceCopy: copy from anywhere in the source
+      2 => {
+        source_offset = seek(source_offset, reader.read_offset()?, rom.len())?;
+        let bytes = rom
+          .get(source_offset..(source_offset + length))
+          .ok_or_else(|| String::from("BPS patch copies from outside of the ROM"))?;
+        output.extend_from_slice(bytes);
+        source_offset += length;
+      },
+      // TargetCopy: copy from earlier in the target. The ranges can overlap,
+      // which repeats a pattern, so this is done one byte at a time.
+      _ => {
+        target_offset = seek(target_offset, reader.read_offset()?, output.len())?;
+        for _ in 0..length {
+          let byte = *output
+            .get(target_offset)
+            .ok_or_else(|| String::from("BPS patch copies from outside of the ROM"))?;
+          output.push(byte);
+          target_offset += 1;
+        }
+      },
+    }
+  }
+
+  if output.len() != target_size {
+    return Err(String::from("BPS patch is truncated"));
+  }
+  if crc32(&output) != target_crc {
+    return Err(String::from("BPS patch produced an unexpected result"));
+  }
+  Ok(output)
+}
+
+/// The CRC-32 used by zip and PNG, computed a bit at a time
+pub fn crc32(data: &[u8]) -> u32 {
+  let mut crc = 0xffffffff_u32;
+  for byte in data {
+    crc ^= *byte as u32;
+    for _ in 0..8 {
+      let mask = (crc & 1).wrapping_neg();
+      crc = (crc >> 1) ^ (0xedb88320 & mask);
+    }
+  }
+  !crc
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{apply_bps, apply_ips, apply_patch, crc32};
+  use alloc::vec;
+  use alloc::vec::Vec;
+
+  #[test]
+  fn crc32_check_value() {
+    assert_eq!(crc32(b"123456789"), 0xcbf43926);
+  }
+
+  #[test]
+  fn ips_records() {
+    let rom = vec![0; 8];
+    let mut patch = b"PATCH".to_vec();
+    // write 2 bytes at 0x0001
+    patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xaa, 0xbb]);
+    // fill 3 bytes at 0x0006 with 0xcc, extending the ROM
+    patch.extend_from_slice(&[0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x03, 0xcc]);
+    patch.extend_from_slice(b"EOF");
+    assert_eq!(apply_ips(&rom, &patch).unwrap(), vec![0, 0xaa, 0xbb, 0, 0, 0, 0xcc, 0xcc, 0xcc]);
+
+    // the end marker can be followed by a length to truncate to
+    patch.extend_from_slice(&[0x00, 0x00, 0x04]);
+    assert_eq!(apply_patch(&rom, &patch).unwrap(), vec![0, 0xaa, 0xbb, 0]);
+
+    assert!(apply_ips(&rom, b"PATCH\x00\x00\x01\x00\x04\xaa").is_err());
+  }
+
+  fn encode_number(mut value: usize, out: &mut Vec<u8>) {
+    loop {
+      let low = (value & 0x7f) as u8;
+      value >>= 7;
+      if value == 0 {
+        out.push(low | 0x80);
+        return;
+      }
+      out.push(low);
+      value -= 1;
+    }
+  }
+
+  fn create_bps(source: &[u8], target: &[u8], actions: &[u8]) -> Vec<u8> {
+    let mut patch = b"BPS1".to_vec();
+    encode_number(source.len(), &mut patch);
+    encode_number(target.len(), &mut patch);
+    encode_number(0, &mut patch);
+    patch.extend_from_slice(actions);
+    patch.extend_from_slice(&crc32(source).to_le_bytes());
+    patch.extend_from_slice(&crc32(target).to_le_bytes());
+    let patch_crc = crc32(&patch);
+    patch.extend_from_slice(&patch_crc.to_le_bytes());
+    patch
+  }
+
+  /// Encode the kind and length of an action
+  fn action(kind: usize, length: usize, out: &mut Vec<u8>) {
+    encode_number(((length - 1) << 2) | kind, out);
+  }
+
+  #[test]
+  fn bps_actions() {
+    let source = b"ABCDEFGH".to_vec();
+    let target = b"ABxyFGHGHGHC".to_vec();
+    let mut actions = Vec::new();
+    // SourceRead 2
+    action(0, 2, &mut actions);
+    // TargetRead 2
+    action(1, 2, &mut actions);
+    actions.extend_from_slice(b"xy");
+    // SourceCopy 3 from offset 5
+    action(2, 3, &mut actions);
+    encode_number(5 << 1, &mut actions);
+    // TargetCopy 4 from offset 5, overlapping what it writes
+    action(3, 4, &mut actions);
+    encode_number(5 << 1, &mut actions);
+    // SourceCopy 1 from offset 2, seeking backwards from 8
+    action(2, 1, &mut actions);
+    encode_number((6 << 1) | 1, &mut actions);
+    let patch = create_bps(&source, &target, &actions);
+    assert_eq!(apply_bps(&source, &patch).unwrap(), target);
+    assert_eq!(apply_patch(&source, &patch).unwrap(), target);
+
+    // a different source ROM is rejected
+    assert!(apply_bps(b"ABCDEFGX", &patch).is_err());
+    // and so is a corrupted patch
+    let mut corrupt = patch.clone();
+    corrupt[8] ^= 1;
+    assert!(apply_bps(&source, &corrupt).is_err());
+  }
+}
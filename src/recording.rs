@@ -0,0 +1,289 @@
+//! Records every frame the emulator presents, for gameplay captures or bug
+//! reports. The output format is picked from the file name:
+//!  - `.rgba` or `.raw` files are a plain stream of 160x144 RGBA8 frames
+//!  - `.avi` files hold uncompressed video, and need no other tools
+//!  - anything else is encoded by piping the frames into `ffmpeg`
+//!
+//! There is no audio device yet, so recordings are silent.
+
+use crate::devices::video::lcd::{LCD_HEIGHT, LCD_WIDTH};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::process::{Child, Command, Stdio};
+
+/// The LCD refreshes every 70224 clock cycles, at 4194304 cycles per second
+pub const FRAME_RATE_NUMERATOR: u32 = 4194304;
+pub const FRAME_RATE_DENOMINATOR: u32 = 70224;
+
+const RGBA_FRAME_SIZE: usize = LCD_WIDTH * LCD_HEIGHT * 4;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RecordingFormat {
+  Raw,
+  Avi,
+  Ffmpeg,
+}
+
+impl RecordingFormat {
+  pub fn for_file_name(name: &str) -> Self {
+    let name = name.to_lowercase();
+    if name.ends_with(".rgba") || name.ends_with(".raw") {
+      RecordingFormat::Raw
+    } else if name.ends_with(".avi") {
+      RecordingFormat::Avi
+    } else {
+      RecordingFormat::Ffmpeg
+    }
+  }
+}
+
+enum RecordingOutput {
+  Raw(BufWriter<File>),
+  Avi(AviWriter<BufWriter<File>>),
+  Ffmpeg(Child),
+}
+
+pub struct VideoRecorder {
+  name: String,
+  output: RecordingOutput,
+  frames: u32,
+}
+
+impl VideoRecorder {
+  pub fn start(name: &str) -> Result<Self, String> {
+    let create = || File::create(name).map(BufWriter::new).map_err(|_| format!("Unable to create recording \"{}\"", name));
+    let output = match RecordingFormat::for_file_name(name) {
+      RecordingFormat::Raw => RecordingOutput::Raw(create()?),
+      RecordingFormat::Avi => {
+        let writer = AviWriter::new(create()?).map_err(|_| format!("Unable to write recording \"{}\"", name))?;
+        RecordingOutput::Avi(writer)
+      },
+      RecordingFormat::Ffmpeg => {
+        let size = format!("{}x{}", LCD_WIDTH, LCD_HEIGHT);
+        let rate = format!("{}/{}", FRAME_RATE_NUMERATOR, FRAME_RATE_DENOMINATOR);
+        let child = Command::new("ffmpeg")
+          .args(["-loglevel", "error", "-y", "-f", "rawvideo", "-pix_fmt", "rgba"])
+          .args(["-s", &size, "-r", &rate, "-i", "-", name])
+          .stdin(Stdio::piped())
+          .spawn()
+          .map_err(|_| String::from("Unable to start ffmpeg, is it installed?"))?;
+        RecordingOutput::Ffmpeg(child)
+      },
+    };
+    Ok(Self {
+      name: String::from(name),
+      output,
+      frames: 0,
+    })
+  }
+
+  /// Add a frame of RGBA8 pixels, like those returned by `Core::screenshot`
+  pub fn record(&mut self, rgba: &[u8]) -> Result<(), String> {
+    let frame = &rgba[..RGBA_FRAME_SIZE];
+    let result = match &mut self.output {
+      RecordingOutput::Raw(writer) => writer.write_all(frame),
+      RecordingOutput::Avi(writer) => writer.write_frame(frame),
+      RecordingOutput::Ffmpeg(child) => match child.stdin.as_mut() {
+        Some(stdin) => stdin.write_all(frame),
+        None => Ok(()),
+      },
+    };
+    result.map_err(|_| format!("Unable to write recording \"{}\"", self.name))?;
+    self.frames += 1;
+    Ok(())
+  }
+
+  /// Complete the file, returning a summary of what was recorded
+  pub fn finish(self) -> Result<String, String> {
+    let error = format!("Unable to finish recording \"{}\"", self.name);
+    match self.output {
+      RecordingOutput::Raw(mut writer) => writer.flush().map_err(|_| error)?,
+      RecordingOutput::Avi(writer) => {
+        writer.finish().map_err(|_| error)?;
+      },
+      RecordingOutput::Ffmpeg(mut child) => {
+        // closing stdin lets ffmpeg know the stream has ended
+        drop(child.stdin.take());
+        match child.wait() {
+          Ok(status) if status.success() => (),
+          _ => return Err(error),
+        }
+      },
+    }
+    Ok(format!("Recorded {} frames to {}", self.frames, self.name))
+  }
+}
+
+/// Writes frames as uncompressed 24-bit video in an AVI container. The
+/// headers need the total frame count, so they are filled in once recording
+/// finishes.
+pub struct AviWriter<W: Write + Seek> {
+  output: W,
+  /// Offset of each frame's chunk, for the index at the end of the file
+  frame_offsets: Vec<u32>,
+  /// Reused to convert each frame to bottom-up BGR rows
+  frame_buffer: Vec<u8>,
+}
+
+/// Where the headers that depend on the frame count are written
+const AVI_RIFF_SIZE: u64 = 4;
+const AVI_TOTAL_FRAMES: u64 = 48;
+const AVI_STREAM_LENGTH: u64 = 140;
+const AVI_MOVI_SIZE: u64 = 216;
+/// Chunk offsets in the index are relative to the "movi" tag
+const AVI_MOVI_START: u32 = 220;
+const AVI_FRAME_SIZE: usize = LCD_WIDTH * LCD_HEIGHT * 3;
+
+impl<W: Write + Seek> AviWriter<W> {
+  pub fn new(mut output: W) -> std::io::Result<Self> {
+    let width = LCD_WIDTH as u32;
+    let height = LCD_HEIGHT as u32;
+    let frame_size = AVI_FRAME_SIZE as u32;
+    let micros_per_frame = (FRAME_RATE_DENOMINATOR as u64 * 1_000_000 / FRAME_RATE_NUMERATOR as u64) as u32;
+
+    let mut header = Vec::with_capacity(AVI_MOVI_START as usize + 4);
+    header.extend_from_slice(b"RIFF");
+    push_u32(&mut header, 0);
+    header.extend_from_slice(b"AVI LIST");
+    push_u32(&mut header, 192);
+    header.extend_from_slice(b"hdrlavih");
+    push_u32(&mut header, 56);
+    for value in [micros_per_frame, frame_size * 60, 0, 0x10, 0, 0, 1, frame_size, width, height, 0, 0, 0, 0] {
+      push_u32(&mut header, value);
+    }
+    header.extend_from_slice(b"LIST");
+    push_u32(&mut header, 116);
+    header.extend_from_slice(b"strlstrh");
+    push_u32(&mut header, 56);
+    header.extend_from_slice(b"vidsDIB ");
+    // flags, priority and language, initial frames
+    for value in [0, 0, 0, FRAME_RATE_DENOMINATOR, FRAME_RATE_NUMERATOR, 0, 0, frame_size, 0xffffffff, 0] {
+      push_u32(&mut header, value);
+    }
+    // frame rectangle, as 16-bit left, top, right, bottom
+    push_u32(&mut header, 0);
+    push_u32(&mut header, width | (height << 16));
+    header.extend_from_slice(b"strf");
+    push_u32(&mut header, 40);
+    // a bitmap header for bottom-up, 24-bit rows
+    for value in [40, width, height, 1 | (24 << 16), 0, frame_size, 0, 0, 0, 0] {
+      push_u32(&mut header, value);
+    }
+    header.extend_from_slice(b"LIST");
+    push_u32(&mut header, 0);
+    header.extend_from_slice(b"movi");
+    output.write_all(&header)?;
+
+    Ok(Self {
+      output,
+      frame_offsets: Vec::new(),
+      frame_buffer: vec![0; AVI_FRAME_SIZE],
+    })
+  }
+
+  pub fn write_frame(&mut self, rgba: &[u8]) -> std::io::Result<()> {
+    let offset = self.output.stream_position()? as u32 - AVI_MOVI_START;
+    let row_size = LCD_WIDTH * 3;
+    for (y, row) in rgba.chunks(LCD_WIDTH * 4).take(LCD_HEIGHT).enumerate() {
+      let start = (LCD_HEIGHT - 1 - y) * row_size;
+      let output_row = &mut self.frame_buffer[start..start + row_size];
+      for (bgr, pixel) in output_row.chunks_mut(3).zip(row.chunks(4)) {
+        bgr.copy_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+      }
+    }
+    self.output.write_all(b"00db")?;
+    self.output.write_all(&(AVI_FRAME_SIZE as u32).to_le_bytes())?;
+    self.output.write_all(&self.frame_buffer)?;
+    self.frame_offsets.push(offset);
+    Ok(())
+  }
+
+  /// Write the index, and fill in every size and count in the headers.
+  /// Returns the underlying output.
+  pub fn finish(mut self) -> std::io::Result<W> {
+    let movi_end = self.output.stream_position()? as u32;
+    let mut index = Vec::with_capacity(8 + self.frame_offsets.len() * 16);
+    index.extend_from_slice(b"idx1");
+    push_u32(&mut index, self.frame_offsets.len() as u32 * 16);
+    for offset in self.frame_offsets.iter() {
+      index.extend_from_slice(b"00db");
+      // every frame is a keyframe
+      push_u32(&mut index, 0x10);
+      push_u32(&mut index, *offset);
+      push_u32(&mut index, AVI_FRAME_SIZE as u32);
+    }
+    self.output.write_all(&index)?;
+    let file_end = self.output.stream_position()? as u32;
+
+    let frame_count = self.frame_offsets.len() as u32;
+    let patches = [
+      (AVI_RIFF_SIZE, file_end - 8),
+      (AVI_TOTAL_FRAMES, frame_count),
+      (AVI_STREAM_LENGTH, frame_count),
+      (AVI_MOVI_SIZE, movi_end - AVI_MOVI_START),
+    ];
+    for (position, value) in patches {
+      self.output.seek(SeekFrom::Start(position))?;
+      self.output.write_all(&value.to_le_bytes())?;
+    }
+    self.output.seek(SeekFrom::End(0))?;
+    self.output.flush()?;
+    Ok(self.output)
+  }
+}
+
+fn push_u32(data: &mut Vec<u8>, value: u32) {
+  data.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{AviWriter, RecordingFormat, AVI_FRAME_SIZE, AVI_MOVI_START, RGBA_FRAME_SIZE};
+  use std::io::Cursor;
+
+  fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+  }
+
+  #[test]
+  fn format_from_name() {
+    assert_eq!(RecordingFormat::for_file_name("capture.AVI"), RecordingFormat::Avi);
+    assert_eq!(RecordingFormat::for_file_name("capture.rgba"), RecordingFormat::Raw);
+    assert_eq!(RecordingFormat::for_file_name("capture.mp4"), RecordingFormat::Ffmpeg);
+  }
+
+  #[test]
+  fn avi_layout() {
+    let mut writer = AviWriter::new(Cursor::new(Vec::new())).unwrap();
+    let mut frame = vec![0; RGBA_FRAME_SIZE];
+    // the top left pixel is red
+    frame[0] = 0xff;
+    writer.write_frame(&frame).unwrap();
+    writer.write_frame(&frame).unwrap();
+    let data = writer.finish().unwrap().into_inner();
+
+    let movi = AVI_MOVI_START as usize;
+    assert_eq!(&data[movi..movi + 8], b"movi00db");
+    assert_eq!(&data[12..16], b"LIST");
+    assert_eq!(&data[20..28], b"hdrlavih");
+    assert_eq!(read_u32(&data, 16) as usize + 20, movi - 8);
+    assert_eq!(&data[88..112], b"LIST\x74\x00\x00\x00strlstrh\x38\x00\x00\x00vids");
+    // sizes and counts are filled in at the end
+    assert_eq!(read_u32(&data, 4) as usize, data.len() - 8);
+    assert_eq!(read_u32(&data, 48), 2);
+    assert_eq!(read_u32(&data, 140), 2);
+    let frames_size = 2 * (8 + AVI_FRAME_SIZE);
+    assert_eq!(read_u32(&data, movi - 4) as usize, 4 + frames_size);
+
+    // rows are stored bottom-up, as BGR
+    let first_frame = movi + 12;
+    let top_row = first_frame + AVI_FRAME_SIZE - 160 * 3;
+    assert_eq!(&data[top_row..top_row + 3], &[0, 0, 0xff]);
+
+    let index = movi + 4 + frames_size;
+    assert_eq!(&data[index..index + 4], b"idx1");
+    assert_eq!(read_u32(&data, index + 4), 32);
+    assert_eq!(read_u32(&data, index + 16), 4);
+    assert_eq!(read_u32(&data, index + 32), 4 + 8 + AVI_FRAME_SIZE as u32);
+  }
+}
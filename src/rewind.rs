@@ -0,0 +1,236 @@
+//! Rewinding, by keeping save states from the last few minutes of play. Only
+//! the newest state is stored whole. Every older state is stored as its
+//! difference from the state after it, XORed together and run-length
+//! encoded. From one capture to the next, most of memory is unchanged, so a
+//! difference is usually a few hundred bytes instead of the ~100KB of a full
+//! state. Stepping back applies one difference at a time to the newest
+//! state, and the oldest differences are dropped once the buffer reaches its
+//! memory limit.
+
+use std::collections::VecDeque;
+
+/// Frames between each captured state
+pub const DEFAULT_INTERVAL: u32 = 10;
+/// Memory used by stored differences before the oldest ones are dropped
+pub const DEFAULT_MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+
+/// A run of equal bytes must be at least this long to end a run of changed
+/// ones, since encoding each run has a small overhead
+const MIN_UNCHANGED_RUN: usize = 4;
+
+pub struct RewindBuffer {
+  interval: u32,
+  frames_until_capture: u32,
+  memory_limit: usize,
+  newest: Option<Vec<u8>>,
+  /// Each entry turns a state into the one captured before it, oldest first
+  deltas: VecDeque<Vec<u8>>,
+  delta_bytes: usize,
+}
+
+impl RewindBuffer {
+  pub fn new(interval: u32, memory_limit: usize) -> Self {
+    let interval = interval.max(1);
+    Self {
+      interval,
+      frames_until_capture: interval,
+      memory_limit,
+      newest: None,
+      deltas: VecDeque::new(),
+      delta_bytes: 0,
+    }
+  }
+
+  /// Count a completed frame, returning true when a state should be captured
+  pub fn frame_completed(&mut self) -> bool {
+    self.frames_until_capture -= 1;
+    if self.frames_until_capture == 0 {
+      self.frames_until_capture = self.interval;
+      return true;
+    }
+    false
+  }
+
+  pub fn push(&mut self, state: Vec<u8>) {
+    match self.newest.take() {
+      Some(previous) if previous.len() == state.len() => {
+        let delta = encode_delta(&previous, &state);
+        self.delta_bytes += delta.len();
+        self.deltas.push_back(delta);
+      },
+      // states of different sizes can't be diffed, so history starts over
+      _ => self.clear(),
+    }
+    self.newest = Some(state);
+
+    while self.delta_bytes > self.memory_limit {
+      match self.deltas.pop_front() {
+        Some(oldest) => self.delta_bytes -= oldest.len(),
+        None => break,
+      }
+    }
+  }
+
+  /// Go back at least `frames` frames, or as far as the buffer reaches.
+  /// Returns the state to load, along with the number of frames since it was
+  /// captured, counted from the newest capture.
+  pub fn step_back(&mut self, frames: u32) -> Option<(&[u8], u32)> {
+    let newest = self.newest.as_mut()?;
+    let steps = frames.div_ceil(self.interval);
+    let mut taken = 0;
+    while taken < steps {
+      match self.deltas.pop_back() {
+        Some(delta) => {
+          apply_delta(newest, &delta);
+          self.delta_bytes -= delta.len();
+          taken += 1;
+        },
+        None => break,
+      }
+    }
+    self.frames_until_capture = self.interval;
+    Some((newest.as_slice(), taken * self.interval))
+  }
+
+  pub fn clear(&mut self) {
+    self.newest = None;
+    self.deltas.clear();
+    self.delta_bytes = 0;
+  }
+
+  /// Number of frames that can be rewound, as of the newest capture
+  pub fn frames_available(&self) -> u32 {
+    self.deltas.len() as u32 * self.interval
+  }
+
+  /// Bytes used by the stored states
+  pub fn memory_used(&self) -> usize {
+    self.delta_bytes + self.newest.as_ref().map_or(0, |state| state.len())
+  }
+}
+
+impl Default for RewindBuffer {
+  fn default() -> Self {
+    Self::new(DEFAULT_INTERVAL, DEFAULT_MEMORY_LIMIT)
+  }
+}
+
+fn write_varint(output: &mut Vec<u8>, mut value: usize) {
+  while value >= 0x80 {
+    output.push(value as u8 | 0x80);
+    value >>= 7;
+  }
+  output.push(value as u8);
+}
+
+fn read_varint(input: &[u8], position: &mut usize) -> usize {
+  let mut value = 0;
+  let mut shift = 0;
+  loop {
+    let byte = input[*position];
+    *position += 1;
+    value |= ((byte & 0x7f) as usize) << shift;
+    if byte & 0x80 == 0 {
+      return value;
+    }
+    shift += 7;
+  }
+}
+
+/// Encode the bytes that differ between two states of the same size. Each
+/// run of changes is written as the number of unchanged bytes before it, its
+/// length, and the changed bytes XORed together.
+fn encode_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+  let mut delta = Vec::new();
+  let length = newer.len();
+  let mut position = 0;
+  while let Some(start) = (position..length).find(|&i| older[i] != newer[i]) {
+    let mut end = start + 1;
+    let mut unchanged = 0;
+    while end + unchanged < length && unchanged < MIN_UNCHANGED_RUN {
+      if older[end + unchanged] != newer[end + unchanged] {
+        end += unchanged + 1;
+        unchanged = 0;
+      } else {
+        unchanged += 1;
+      }
+    }
+    write_varint(&mut delta, start - position);
+    write_varint(&mut delta, end - start);
+    delta.extend(older[start..end].iter().zip(newer[start..end].iter()).map(|(a, b)| a ^ b));
+    position = end;
+  }
+  delta
+}
+
+/// XORing is its own inverse, so a delta turns either state into the other
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+  let mut cursor = 0;
+  let mut position = 0;
+  while cursor < delta.len() {
+    position += read_varint(delta, &mut cursor);
+    let count = read_varint(delta, &mut cursor);
+    let changes = &delta[cursor..cursor + count];
+    for (byte, change) in state[position..position + count].iter_mut().zip(changes.iter()) {
+      *byte ^= change;
+    }
+    cursor += count;
+    position += count;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{apply_delta, encode_delta, RewindBuffer};
+
+  #[test]
+  fn delta_round_trip() {
+    let older = vec![0u8; 300];
+    let mut newer = older.clone();
+    newer[0] = 1;
+    newer[3] = 2;
+    newer[200] = 3;
+    let delta = encode_delta(&older, &newer);
+    // the first two changes are close enough to share a run, and the skip
+    // to the third needs a two-byte varint
+    assert_eq!(delta, vec![0, 4, 1, 0, 0, 2, 0xc4, 0x01, 1, 3]);
+
+    let mut state = newer.clone();
+    apply_delta(&mut state, &delta);
+    assert_eq!(state, older);
+    apply_delta(&mut state, &delta);
+    assert_eq!(state, newer);
+    assert!(encode_delta(&older, &older).is_empty());
+  }
+
+  #[test]
+  fn stepping_back() {
+    let mut buffer = RewindBuffer::new(2, 1024);
+    assert!(buffer.step_back(10).is_none());
+    for frame in 0..10u8 {
+      if buffer.frame_completed() {
+        buffer.push(vec![frame; 16]);
+      }
+    }
+    assert_eq!(buffer.frames_available(), 8);
+    let (state, frames) = buffer.step_back(3).unwrap();
+    assert_eq!((state, frames), (&[5u8; 16][..], 4));
+    // going further than the buffer reaches stops at the oldest state
+    let (state, frames) = buffer.step_back(100).unwrap();
+    assert_eq!((state, frames), (&[1u8; 16][..], 4));
+    assert_eq!(buffer.frames_available(), 0);
+  }
+
+  #[test]
+  fn memory_limit() {
+    let mut buffer = RewindBuffer::new(1, 40);
+    for value in 0..10u8 {
+      buffer.push(vec![value; 16]);
+    }
+    // each delta is 18 bytes, so only two fit
+    assert_eq!(buffer.frames_available(), 2);
+    assert_eq!(buffer.memory_used(), 36 + 16);
+    buffer.push(vec![0; 8]);
+    assert_eq!(buffer.frames_available(), 0);
+  }
+}
@@ -0,0 +1,167 @@
+//! Binary encoding for save states. Each component writes its own fields in a
+//! fixed order with `save_state`, and reads them back in the same order with
+//! `load_state`. Values are little-endian, and there are no field names or
+//! padding, so a state is only readable by the build that wrote it.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Identifies the start of a save state
+pub const STATE_MAGIC: [u8; 4] = *b"GBDS";
+/// Incremented whenever the layout of a save state changes
+pub const STATE_VERSION: u8 = 6;
+
+pub struct StateWriter {
+  data: Vec<u8>,
+}
+
+impl StateWriter {
+  pub fn new() -> Self {
+    Self {
+      data: Vec::new(),
+    }
+  }
+
+  pub fn u8(&mut self, value: u8) {
+    self.data.push(value);
+  }
+
+  pub fn bool(&mut self, value: bool) {
+    self.data.push(value as u8);
+  }
+
+  pub fn u16(&mut self, value: u16) {
+    self.data.extend_from_slice(&value.to_le_bytes());
+  }
+
+  pub fn u32(&mut self, value: u32) {
+    self.data.extend_from_slice(&value.to_le_bytes());
+  }
+
+  pub fn u64(&mut self, value: u64) {
+    self.data.extend_from_slice(&value.to_le_bytes());
+  }
+
+  /// A block of memory, preceded by its length
+  pub fn bytes(&mut self, value: &[u8]) {
+    self.u32(value.len() as u32);
+    self.data.extend_from_slice(value);
+  }
+
+  pub fn into_bytes(self) -> Vec<u8> {
+    self.data
+  }
+}
+
+impl Default for StateWriter {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+pub struct StateReader<'a> {
+  data: &'a [u8],
+  position: usize,
+}
+
+impl<'a> StateReader<'a> {
+  pub fn new(data: &'a [u8]) -> Self {
+    Self {
+      data,
+      position: 0,
+    }
+  }
+
+  fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+    let end = self.position + length;
+    if end > self.data.len() {
+      return Err(String::from("Save state is truncated"));
+    }
+    let slice = &self.data[self.position..end];
+    self.position = end;
+    Ok(slice)
+  }
+
+  pub fn u8(&mut self) -> Result<u8, String> {
+    Ok(self.take(1)?[0])
+  }
+
+  pub fn bool(&mut self) -> Result<bool, String> {
+    Ok(self.u8()? != 0)
+  }
+
+  pub fn u16(&mut self) -> Result<u16, String> {
+    let mut bytes = [0; 2];
+    bytes.copy_from_slice(self.take(2)?);
+    Ok(u16::from_le_bytes(bytes))
+  }
+
+  pub fn u32(&mut self) -> Result<u32, String> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(self.take(4)?);
+    Ok(u32::from_le_bytes(bytes))
+  }
+
+  pub fn u64(&mut self) -> Result<u64, String> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(self.take(8)?);
+    Ok(u64::from_le_bytes(bytes))
+  }
+
+  /// Read a block of memory written by `StateWriter::bytes`
+  pub fn bytes(&mut self) -> Result<&'a [u8], String> {
+    let length = self.u32()? as usize;
+    self.take(length)
+  }
+
+  /// Read a block of memory into a buffer, which must be the same size as
+  /// the one that was saved
+  pub fn bytes_into(&mut self, buffer: &mut [u8]) -> Result<(), String> {
+    let value = self.bytes()?;
+    if value.len() != buffer.len() {
+      return Err(String::from("Save state has a different memory size"));
+    }
+    buffer.copy_from_slice(value);
+    Ok(())
+  }
+
+  pub fn is_finished(&self) -> bool {
+    self.position == self.data.len()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{StateReader, StateWriter};
+  use alloc::string::String;
+
+  #[test]
+  fn round_trip() {
+    let mut writer = StateWriter::new();
+    writer.u8(0x12);
+    writer.bool(true);
+    writer.u16(0x3456);
+    writer.u32(0x789abcde);
+    writer.u64(0x0102030405060708);
+    writer.bytes(&[1, 2, 3]);
+    let data = writer.into_bytes();
+    assert_eq!(&data[..5], &[0x12, 1, 0x56, 0x34, 0xde]);
+
+    let mut reader = StateReader::new(&data);
+    assert_eq!(reader.u8(), Ok(0x12));
+    assert_eq!(reader.bool(), Ok(true));
+    assert_eq!(reader.u16(), Ok(0x3456));
+    assert_eq!(reader.u32(), Ok(0x789abcde));
+    assert_eq!(reader.u64(), Ok(0x0102030405060708));
+    let mut buffer = [0; 2];
+    assert!(reader.bytes_into(&mut buffer).is_err());
+    assert!(reader.is_finished());
+  }
+
+  #[test]
+  fn truncated() {
+    let mut reader = StateReader::new(&[1, 2, 3]);
+    assert_eq!(reader.u16(), Ok(0x0201));
+    assert_eq!(reader.u32(), Err(String::from("Save state is truncated")));
+  }
+}
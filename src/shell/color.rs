@@ -0,0 +1,120 @@
+const BRIGHTNESS_STEP: f32 = 0.05;
+const CONTRAST_STEP: f32 = 0.1;
+const GAMMA_STEP: f32 = 0.1;
+
+/// Adjustments applied when converting LCD shades to screen colors. Many DMG
+/// games were tuned for the original unlit screen and look too dark on a
+/// modern display without some correction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorAdjustment {
+  /// Added to every shade, from -1.0 to 1.0
+  pub brightness: f32,
+  /// Scales shades away from (or towards) mid-gray
+  pub contrast: f32,
+  /// Values above 1.0 lighten the mid-tones
+  pub gamma: f32,
+}
+
+pub enum AdjustmentKey {
+  BrightnessDown,
+  BrightnessUp,
+  ContrastDown,
+  ContrastUp,
+  GammaDown,
+  GammaUp,
+  Reset,
+}
+
+impl ColorAdjustment {
+  pub fn new() -> Self {
+    Self {
+      brightness: 0.0,
+      contrast: 1.0,
+      gamma: 1.0,
+    }
+  }
+
+  pub fn apply_key(&mut self, key: AdjustmentKey) {
+    match key {
+      AdjustmentKey::BrightnessDown => self.brightness -= BRIGHTNESS_STEP,
+      AdjustmentKey::BrightnessUp => self.brightness += BRIGHTNESS_STEP,
+      AdjustmentKey::ContrastDown => self.contrast -= CONTRAST_STEP,
+      AdjustmentKey::ContrastUp => self.contrast += CONTRAST_STEP,
+      AdjustmentKey::GammaDown => self.gamma -= GAMMA_STEP,
+      AdjustmentKey::GammaUp => self.gamma += GAMMA_STEP,
+      AdjustmentKey::Reset => *self = Self::new(),
+    }
+    *self = self.clamped();
+  }
+
+  /// Build a lookup table mapping each LCD shade to an adjusted value
+  pub fn build_table(&self) -> [u8; 256] {
+    let mut table = [0; 256];
+    for (shade, entry) in table.iter_mut().enumerate() {
+      let mut value = shade as f32 / 255.0;
+      value = value.powf(1.0 / self.gamma);
+      value = (value - 0.5) * self.contrast + 0.5;
+      value += self.brightness;
+      *entry = (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+    }
+    table
+  }
+
+  /// Keep each value within the range the hotkeys allow, such as after
+  /// reading it from the config file
+  pub fn clamped(mut self) -> Self {
+    self.brightness = self.brightness.clamp(-1.0, 1.0);
+    self.contrast = self.contrast.clamp(0.1, 4.0);
+    self.gamma = self.gamma.clamp(0.2, 5.0);
+    self
+  }
+
+  /// The config file keys these are saved under, with their values
+  pub fn config_values(&self) -> [(&'static str, String); 3] {
+    [
+      ("brightness", format!("{:.2}", self.brightness)),
+      ("contrast", format!("{:.1}", self.contrast)),
+      ("gamma", format!("{:.1}", self.gamma)),
+    ]
+  }
+}
+
+impl Default for ColorAdjustment {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{AdjustmentKey, ColorAdjustment};
+
+  #[test]
+  fn default_is_identity() {
+    let table = ColorAdjustment::new().build_table();
+    for (shade, value) in table.iter().enumerate() {
+      assert_eq!(*value as usize, shade);
+    }
+  }
+
+  #[test]
+  fn gamma_lightens_midtones() {
+    let mut adjustment = ColorAdjustment::new();
+    adjustment.apply_key(AdjustmentKey::GammaUp);
+    let table = adjustment.build_table();
+    assert_eq!(table[0], 0);
+    assert!(table[85] > 85);
+    assert_eq!(table[255], 255);
+  }
+
+  #[test]
+  fn config_values() {
+    let mut adjustment = ColorAdjustment::new();
+    adjustment.apply_key(AdjustmentKey::BrightnessUp);
+    adjustment.apply_key(AdjustmentKey::ContrastDown);
+    let values = adjustment.config_values();
+    assert_eq!(values[0], ("brightness", String::from("0.05")));
+    assert_eq!(values[1], ("contrast", String::from("0.9")));
+    assert_eq!(values[2], ("gamma", String::from("1.0")));
+  }
+}
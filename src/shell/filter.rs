@@ -0,0 +1,122 @@
+//! Post-processing applied while the screen is scaled up to fill a window.
+//! Each filter picks the colors that shades are drawn with, and can dim some
+//! of the window pixels that make up each Game Boy pixel, depending on where
+//! they fall within it.
+
+use crate::devices::video::palette::Palette;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Filter {
+  #[default]
+  None,
+  /// Darkens the edges of every pixel, like the gaps between them on an LCD
+  LcdGrid,
+  /// Darkens the bottom row of every pixel, like a CRT
+  Scanlines,
+  /// Tints shades to the greens of the original screen, whatever the
+  /// palette is
+  DmgGreen,
+}
+
+impl Filter {
+  pub fn parse(name: &str) -> Result<Self, String> {
+    match name.to_ascii_lowercase().as_str() {
+      "none" => Ok(Filter::None),
+      "grid" | "lcd" => Ok(Filter::LcdGrid),
+      "scanlines" => Ok(Filter::Scanlines),
+      "green" | "dmg" => Ok(Filter::DmgGreen),
+      _ => Err(format!("Unknown filter \"{}\"", name)),
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Filter::None => "NONE",
+      Filter::LcdGrid => "LCD GRID",
+      Filter::Scanlines => "SCANLINES",
+      Filter::DmgGreen => "DMG GREEN",
+    }
+  }
+
+  /// The filter after this one, for cycling through all of them
+  pub fn next(self) -> Self {
+    match self {
+      Filter::None => Filter::LcdGrid,
+      Filter::LcdGrid => Filter::Scanlines,
+      Filter::Scanlines => Filter::DmgGreen,
+      Filter::DmgGreen => Filter::None,
+    }
+  }
+
+  /// Build a table of the 0RGB color each shade is drawn with
+  pub fn colors(&self, palette: &Palette) -> [u32; 256] {
+    let palette = match self {
+      Filter::DmgGreen => Palette::green(),
+      _ => *palette,
+    };
+    let mut colors = [0; 256];
+    for (shade, color) in colors.iter_mut().enumerate() {
+      let [r, g, b] = palette.interpolate(shade as u8);
+      *color = ((r as u32) << 16) | ((g as u32) << 8) | b as u32;
+    }
+    colors
+  }
+
+  /// Whether a window pixel is dimmed, given its position within a Game Boy
+  /// pixel drawn `scale` window pixels wide
+  pub fn is_dimmed(&self, sub_x: usize, sub_y: usize, scale: usize) -> bool {
+    match self {
+      // at smaller scales, the grid would cover most of the screen
+      Filter::LcdGrid => scale >= 3 && (sub_x == scale - 1 || sub_y == scale - 1),
+      Filter::Scanlines => scale >= 2 && sub_y == scale - 1,
+      _ => false,
+    }
+  }
+
+  /// Dim a color from the table built by `colors`
+  pub fn dim(&self, color: u32) -> u32 {
+    match self {
+      Filter::Scanlines => (color >> 1) & 0x7f7f7f,
+      // three quarters of each channel
+      _ => color - ((color >> 2) & 0x3f3f3f),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Filter;
+  use crate::devices::video::palette::Palette;
+
+  #[test]
+  fn colors() {
+    let grayscale = Palette::grayscale();
+    let gray = Filter::None.colors(&grayscale);
+    assert_eq!((gray[0], gray[0x80], gray[255]), (0, 0x808080, 0xffffff));
+    let custom = Filter::Scanlines.colors(&Palette::parse("ffffff,aabbcc,123456,000000").unwrap());
+    assert_eq!(custom[170], 0xaabbcc);
+    let green = Filter::DmgGreen.colors(&grayscale);
+    assert_eq!((green[0], green[170], green[255]), (0x0f380f, 0x8bac0f, 0x9bbc0f));
+    assert_eq!(Filter::Scanlines.dim(0xff8001), 0x7f4000);
+    assert_eq!(Filter::LcdGrid.dim(0xff8001), 0xc06001);
+  }
+
+  #[test]
+  fn dimmed_pixels() {
+    assert!(Filter::LcdGrid.is_dimmed(3, 0, 4));
+    assert!(Filter::LcdGrid.is_dimmed(0, 3, 4));
+    assert!(!Filter::LcdGrid.is_dimmed(2, 2, 4));
+    assert!(!Filter::LcdGrid.is_dimmed(1, 1, 2));
+    assert!(Filter::Scanlines.is_dimmed(0, 1, 2));
+    assert!(!Filter::Scanlines.is_dimmed(0, 0, 1));
+    assert!(!Filter::DmgGreen.is_dimmed(3, 3, 4));
+  }
+
+  #[test]
+  fn parsing() {
+    assert_eq!(Filter::parse("Scanlines"), Ok(Filter::Scanlines));
+    assert_eq!(Filter::parse("grid"), Ok(Filter::LcdGrid));
+    assert!(Filter::parse("crt").is_err());
+    assert_eq!(Filter::DmgGreen.next(), Filter::None);
+  }
+}
@@ -1,22 +1,169 @@
-use crate::emulator::{self, Core};
-use super::Shell;
+use crate::debug::hud::PerfHud;
+use crate::devices::video::lcd::{LCD_HEIGHT, LCD_WIDTH};
+use crate::emulator::Core;
+use super::{image, print_messages, reference, RunLimits, Shell};
+use std::path::Path;
 
-pub struct HeadlessShell {
+/// Exit status when a frame or cycle limit is reached
+pub const EXIT_LIMIT_REACHED: i32 = 0;
+/// Exit status when a breakpoint or watchpoint stops a limited run early
+pub const EXIT_BREAK: i32 = 1;
+/// Exit status when a frame hashes differently from the baseline, or the
+/// last frame doesn't match the reference image
+pub const EXIT_MISMATCH: i32 = 3;
 
+pub struct HeadlessShell {
+  limits: RunLimits,
+  /// When set, performance is printed once a second
+  hud: bool,
 }
 
 impl HeadlessShell {
-  pub fn new() -> Self {
+  pub fn new(limits: RunLimits, hud: bool) -> Self {
     Self {
-      
+      limits,
+      hud,
+    }
+  }
+
+  fn limit_reached(&self, core: &Core, frames: u64) -> bool {
+    let frames_done = matches!(self.limits.frames, Some(limit) if frames >= limit);
+    let cycles_done = matches!(self.limits.cycles, Some(limit) if core.cycles_elapsed() >= limit);
+    frames_done || cycles_done
+  }
+
+  /// Run until a limit is reached or execution breaks, and return the exit
+  /// status
+  fn run_limited(&self, core: &mut Core) -> i32 {
+    let mut frames = 0;
+    let mut hud = self.hud.then(|| PerfHud::start(core));
+    loop {
+      if self.limit_reached(core, frames) {
+        return EXIT_LIMIT_REACHED;
+      }
+      // recordings, hashes, and performance are measured a frame at a time
+      let by_frame = core.recorder.is_some() || core.frame_hasher.is_some() || hud.is_some();
+      let event = if self.limits.frames.is_some() || by_frame {
+        let event = core.run_frame();
+        if event.is_none() {
+          frames += 1;
+        }
+        print_messages(core);
+        print_performance(hud.as_mut(), core);
+        // later frames almost always differ too, so there is no point going on
+        if core.frame_hasher.as_ref().and_then(|hasher| hasher.first_mismatch()).is_some() {
+          return EXIT_MISMATCH;
+        }
+        event
+      } else {
+        let event = core.update();
+        print_messages(core);
+        event
+      };
+      if let Some(event) = event {
+        println!("{}", event);
+        return EXIT_BREAK;
+      }
     }
   }
 }
 
 impl Shell for HeadlessShell {
   fn run(&mut self, mut core: Core) {
-    loop {
-      core.update();
+    if !self.limits.is_limited() {
+      if core.recorder.take().is_some() {
+        println!("Recording without a window needs --frames or --cycles");
+      }
+      if core.frame_hasher.take().is_some() {
+        println!("Hashing frames needs --frames or --cycles");
+      }
+      if self.limits.reference_frame.is_some() {
+        println!("Comparing the last frame needs --frames or --cycles");
+      }
+      // Netplay runs in lockstep one frame at a time, until the connection
+      // drops
+      while core.netplay.is_some() {
+        core.run_frame();
+        print_messages(&mut core);
+      }
+      if self.hud {
+        let mut hud = PerfHud::start(&mut core);
+        let event = loop {
+          if let Some(event) = core.run_frame() {
+            break event;
+          }
+          print_messages(&mut core);
+          print_performance(Some(&mut hud), &core);
+        };
+        print_messages(&mut core);
+        println!("{}", event);
+        core.shutdown();
+        print_messages(&mut core);
+        return;
+      }
+      // Without breakpoints or watchpoints, this runs forever. Messages are
+      // printed as they come, between frames.
+      let event = loop {
+        if let Some(event) = core.run_frame() {
+          break event;
+        }
+        print_messages(&mut core);
+      };
+      print_messages(&mut core);
+      println!("{}", event);
+      core.shutdown();
+      print_messages(&mut core);
+      return;
     }
+
+    // a missing reference should be found before the run, not after it
+    let reference = match self.limits.reference_frame.as_deref().map(reference::load_reference) {
+      Some(Ok(reference)) => Some(reference),
+      Some(Err(msg)) => {
+        println!("{}", msg);
+        core.shutdown();
+        print_messages(&mut core);
+        std::process::exit(2);
+      },
+      None => None,
+    };
+    let mut status = self.run_limited(&mut core);
+    println!("Stopped after {} cycles", core.cycles_elapsed());
+    if let Some(name) = self.limits.dump_frame.as_ref() {
+      if let Err(msg) = image::save_image(name, core.get_screen_buffer(), LCD_WIDTH, LCD_HEIGHT) {
+        println!("{}", msg);
+      }
+    }
+    if let Some(reference) = reference {
+      let diff = reference::compare_frame(core.get_screen_buffer(), &reference);
+      println!("{}", diff);
+      if !diff.matches() {
+        save_diff_image(&diff, self.limits.reference_frame.as_deref().unwrap_or_default());
+        if status == EXIT_LIMIT_REACHED {
+          status = EXIT_MISMATCH;
+        }
+      }
+    }
+    core.shutdown();
+    print_messages(&mut core);
+    std::process::exit(status);
+  }
+}
+
+/// Save the diff to the working directory, named after the reference it was
+/// compared to
+fn save_diff_image(diff: &reference::FrameDiff, reference_name: &str) {
+  let stem = Path::new(reference_name).file_stem().and_then(|stem| stem.to_str()).unwrap_or("frame");
+  let name = format!("{}-diff.png", stem);
+  match diff.save_image(&name) {
+    Ok(()) => println!("Saved the differences to {}", name),
+    Err(msg) => println!("{}", msg),
+  }
+}
+
+/// Print a line each time the HUD measures another second
+fn print_performance(hud: Option<&mut PerfHud>, core: &Core) {
+  if let Some(report) = hud.and_then(|hud| hud.frame_shown(core)) {
+    println!("{}", report);
   }
 }
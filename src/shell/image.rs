@@ -0,0 +1,384 @@
+//! Encoders for saving the LCD as an image file. The LCD buffer holds one
+//! 8-bit shade per pixel, so it can be written as grayscale, while
+//! screenshots are converted to RGBA first. PNG data is stored without
+//! compression, which keeps the encoder tiny; a 160x144 grayscale screen is
+//! only about 23KB either way.
+//!
+//! Images can also be read back as shades, for comparing frames to reference
+//! images. Those usually come from other tools, so the decoder handles
+//! compressed and filtered PNGs in most formats.
+
+use crate::emulator::Core;
+use crate::devices::video::lcd::{LCD_HEIGHT, LCD_WIDTH};
+use crate::patch::crc32;
+use crate::zip::inflate;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Largest block of data a stored deflate block can hold
+const MAX_STORED_BLOCK: usize = 0xffff;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+/// Binary PGM, with a short text header followed by the raw shades
+pub fn encode_pgm(pixels: &[u8], width: usize, height: usize) -> Vec<u8> {
+  let mut data = format!("P5\n{} {}\n255\n", width, height).into_bytes();
+  data.extend_from_slice(&pixels[..width * height]);
+  data
+}
+
+/// PNG color types, along with the bytes used by each pixel
+const GRAYSCALE: (u8, usize) = (0, 1);
+const RGBA: (u8, usize) = (6, 4);
+
+/// 8-bit grayscale PNG
+pub fn encode_png(pixels: &[u8], width: usize, height: usize) -> Vec<u8> {
+  encode_png_as(GRAYSCALE, pixels, width, height)
+}
+
+/// 8-bit RGBA PNG
+pub fn encode_png_rgba(pixels: &[u8], width: usize, height: usize) -> Vec<u8> {
+  encode_png_as(RGBA, pixels, width, height)
+}
+
+fn encode_png_as(format: (u8, usize), pixels: &[u8], width: usize, height: usize) -> Vec<u8> {
+  let (color_type, pixel_size) = format;
+  let mut data = PNG_SIGNATURE.to_vec();
+
+  let mut header = Vec::with_capacity(13);
+  header.extend_from_slice(&(width as u32).to_be_bytes());
+  header.extend_from_slice(&(height as u32).to_be_bytes());
+  // bit depth 8, default compression, filtering, no interlacing
+  header.extend_from_slice(&[8, color_type, 0, 0, 0]);
+  write_chunk(&mut data, b"IHDR", &header);
+
+  // every row starts with its filter type, which is always "none"
+  let row_size = width * pixel_size;
+  let mut rows = Vec::with_capacity((row_size + 1) * height);
+  for row in pixels[..row_size * height].chunks(row_size) {
+    rows.push(0);
+    rows.extend_from_slice(row);
+  }
+  write_chunk(&mut data, b"IDAT", &zlib_stored(&rows));
+  write_chunk(&mut data, b"IEND", &[]);
+  data
+}
+
+fn write_chunk(data: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+  data.extend_from_slice(&(body.len() as u32).to_be_bytes());
+  let start = data.len();
+  data.extend_from_slice(kind);
+  data.extend_from_slice(body);
+  let crc = crc32(&data[start..]);
+  data.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wrap data in a zlib stream made of uncompressed deflate blocks
+fn zlib_stored(input: &[u8]) -> Vec<u8> {
+  let mut output = vec![0x78, 0x01];
+  let mut blocks = input.chunks(MAX_STORED_BLOCK).peekable();
+  if blocks.peek().is_none() {
+    // an empty stream still needs one final block
+    output.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+  }
+  while let Some(block) = blocks.next() {
+    let is_final = blocks.peek().is_none();
+    output.push(is_final as u8);
+    let length = block.len() as u16;
+    output.extend_from_slice(&length.to_le_bytes());
+    output.extend_from_slice(&(!length).to_le_bytes());
+    output.extend_from_slice(block);
+  }
+  output.extend_from_slice(&adler32(input).to_be_bytes());
+  output
+}
+
+fn adler32(data: &[u8]) -> u32 {
+  let mut a = 1_u32;
+  let mut b = 0_u32;
+  for byte in data {
+    a = (a + *byte as u32) % 65521;
+    b = (b + a) % 65521;
+  }
+  (b << 16) | a
+}
+
+/// An image read back as one 8-bit gray level per pixel, like the LCD
+pub struct GrayImage {
+  pub width: usize,
+  pub height: usize,
+  pub pixels: Vec<u8>,
+}
+
+/// Read a binary PGM with 8-bit samples, like the ones `encode_pgm` writes
+pub fn decode_pgm(data: &[u8]) -> Result<GrayImage, String> {
+  let invalid = || String::from("Invalid PGM image");
+  // the header is four whitespace-separated fields, then a single space
+  let mut fields = Vec::new();
+  let mut offset = 0;
+  while fields.len() < 4 {
+    while data.get(offset).is_some_and(u8::is_ascii_whitespace) {
+      offset += 1;
+    }
+    let start = offset;
+    while data.get(offset).is_some_and(|byte| !byte.is_ascii_whitespace()) {
+      offset += 1;
+    }
+    if start == offset {
+      return Err(invalid());
+    }
+    fields.push(std::str::from_utf8(&data[start..offset]).map_err(|_| invalid())?);
+  }
+  let number = |field: &str| field.parse::<usize>().map_err(|_| invalid());
+  let (width, height) = (number(fields[1])?, number(fields[2])?);
+  if fields[0] != "P5" || fields[3] != "255" {
+    return Err(String::from("Only binary PGM images with 8-bit samples are supported"));
+  }
+  let pixels = data.get(offset + 1..offset + 1 + width * height).ok_or_else(invalid)?;
+  Ok(GrayImage { width, height, pixels: pixels.to_vec() })
+}
+
+/// Read a PNG as gray levels. Colors are converted by their luma, and alpha
+/// is ignored. 16-bit samples and interlacing aren't supported, since
+/// nothing needs them for images this small.
+pub fn decode_png(data: &[u8]) -> Result<GrayImage, String> {
+  let truncated = || String::from("PNG image is truncated");
+  if !data.starts_with(&PNG_SIGNATURE) {
+    return Err(String::from("Not a PNG image"));
+  }
+  let mut header = None;
+  let mut palette = Vec::new();
+  let mut compressed = Vec::new();
+  let mut offset = PNG_SIGNATURE.len();
+  while offset < data.len() {
+    let length = data.get(offset..offset + 4).ok_or_else(truncated)?;
+    let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+    let kind = data.get(offset + 4..offset + 8).ok_or_else(truncated)?;
+    let body = data.get(offset + 8..offset + 8 + length).ok_or_else(truncated)?;
+    match kind {
+      b"IHDR" => header = Some(body),
+      b"PLTE" => palette = body.chunks_exact(3).map(|color| luma(color[0], color[1], color[2])).collect(),
+      b"IDAT" => compressed.extend_from_slice(body),
+      b"IEND" => break,
+      _ => (),
+    }
+    // skip the CRC as well
+    offset += 12 + length;
+  }
+  let header = header.filter(|header| header.len() == 13).ok_or_else(|| String::from("PNG image has no header"))?;
+  let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+  let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+  let (depth, color_type, interlaced) = (header[8] as usize, header[9], header[12] != 0);
+  let channels = match color_type {
+    0 | 3 => 1,
+    2 => 3,
+    4 => 2,
+    6 => 4,
+    _ => return Err(String::from("Invalid PNG color type")),
+  };
+  let supported_depth = depth == 8 || (channels == 1 && matches!(depth, 1 | 2 | 4));
+  if !supported_depth || interlaced {
+    return Err(String::from("Only 8-bit, non-interlaced PNG images are supported"));
+  }
+
+  // every row starts with the filter it was encoded with
+  let row_size = (width * channels * depth).div_ceil(8);
+  let pixel_size = (channels * depth / 8).max(1);
+  // skip the zlib header, and leave the checksum at the end unread
+  let stream = compressed.get(2..).ok_or_else(truncated)?;
+  let filtered = inflate(stream, (row_size + 1) * height)?;
+  if filtered.len() != (row_size + 1) * height {
+    return Err(truncated());
+  }
+  let mut rows = vec![0; row_size * height];
+  for y in 0..height {
+    let line = &filtered[y * (row_size + 1)..(y + 1) * (row_size + 1)];
+    let (done, rest) = rows.split_at_mut(y * row_size);
+    let previous = if y > 0 { &done[(y - 1) * row_size..] } else { &[][..] };
+    let row = &mut rest[..row_size];
+    for x in 0..row_size {
+      let left = if x >= pixel_size { row[x - pixel_size] } else { 0 };
+      let up = previous.get(x).copied().unwrap_or(0);
+      let up_left = if x >= pixel_size { previous.get(x - pixel_size).copied().unwrap_or(0) } else { 0 };
+      let prediction = match line[0] {
+        0 => 0,
+        1 => left,
+        2 => up,
+        3 => ((left as u16 + up as u16) / 2) as u8,
+        4 => paeth(left, up, up_left),
+        _ => return Err(String::from("Invalid PNG filter")),
+      };
+      row[x] = line[x + 1].wrapping_add(prediction);
+    }
+  }
+
+  let mut pixels = Vec::with_capacity(width * height);
+  for row in rows.chunks_exact(row_size) {
+    for x in 0..width {
+      let gray = if depth < 8 {
+        let bit = x * depth;
+        let max = (1 << depth) - 1;
+        let sample = (row[bit / 8] >> (8 - depth - bit % 8)) as usize & max;
+        match color_type {
+          3 => *palette.get(sample).ok_or_else(|| String::from("Invalid PNG palette index"))?,
+          _ => (sample * 255 / max) as u8,
+        }
+      } else {
+        let sample = &row[x * channels..(x + 1) * channels];
+        match color_type {
+          2 | 6 => luma(sample[0], sample[1], sample[2]),
+          3 => *palette.get(sample[0] as usize).ok_or_else(|| String::from("Invalid PNG palette index"))?,
+          _ => sample[0],
+        }
+      };
+      pixels.push(gray);
+    }
+  }
+  Ok(GrayImage { width, height, pixels })
+}
+
+fn luma(r: u8, g: u8, b: u8) -> u8 {
+  ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000) as u8
+}
+
+/// Predict a byte from whichever of its neighbors is closest to their
+/// gradient, as PNG filter 4 does
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+  let estimate = left as i16 + up as i16 - up_left as i16;
+  let distance = |value: u8| (estimate - value as i16).abs();
+  if distance(left) <= distance(up) && distance(left) <= distance(up_left) {
+    left
+  } else if distance(up) <= distance(up_left) {
+    up
+  } else {
+    up_left
+  }
+}
+
+/// Read a PNG or PGM, picked by its contents rather than the file name
+pub fn load_image(name: &str) -> Result<GrayImage, String> {
+  let data = std::fs::read(name).map_err(|_| format!("Unable to read image \"{}\"", name))?;
+  let image = if data.starts_with(&PNG_SIGNATURE) {
+    decode_png(&data)
+  } else {
+    decode_pgm(&data)
+  };
+  image.map_err(|msg| format!("{} in \"{}\"", msg, name))
+}
+
+/// Save pixels as a PNG if the file name ends in `.png`, and as a PGM
+/// otherwise
+pub fn save_image(name: &str, pixels: &[u8], width: usize, height: usize) -> Result<(), String> {
+  let data = if name.to_lowercase().ends_with(".png") {
+    encode_png(pixels, width, height)
+  } else {
+    encode_pgm(pixels, width, height)
+  };
+  std::fs::write(name, data).map_err(|_| format!("Unable to write image \"{}\"", name))
+}
+
+/// Save the current frame to a PNG named after the time it was taken, in
+/// `dir` if one is given or the working directory otherwise. Returns the
+/// path of the new file.
+pub fn save_screenshot(core: &Core, dir: Option<&Path>) -> Result<String, String> {
+  let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+  let mut path = PathBuf::new();
+  if let Some(dir) = dir {
+    std::fs::create_dir_all(dir)
+      .map_err(|_| format!("Unable to create directory \"{}\"", dir.display()))?;
+    path.push(dir);
+  }
+  path.push(format!("screenshot-{}{:03}.png", time.as_secs(), time.subsec_millis()));
+  let name = path.display().to_string();
+  let data = encode_png_rgba(&core.screenshot(), LCD_WIDTH, LCD_HEIGHT);
+  std::fs::write(&path, data).map_err(|_| format!("Unable to write image \"{}\"", name))?;
+  Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{
+    adler32, decode_pgm, decode_png, encode_pgm, encode_png, encode_png_rgba, write_chunk, zlib_stored, PNG_SIGNATURE,
+  };
+
+  #[test]
+  fn pgm_header() {
+    let data = encode_pgm(&[0, 85, 170, 255], 2, 2);
+    assert_eq!(data, b"P5\n2 2\n255\n\x00\x55\xaa\xff".to_vec());
+  }
+
+  #[test]
+  fn stored_blocks() {
+    assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+    let input = vec![7; 70000];
+    let stream = zlib_stored(&input);
+    // two blocks, only the second marked final
+    assert_eq!(&stream[2..7], &[0, 0xff, 0xff, 0, 0]);
+    let second = 7 + 0xffff;
+    assert_eq!(&stream[second..second + 5], &[1, 0x71, 0x11, 0x8e, 0xee]);
+    assert_eq!(stream.len(), 2 + 5 + 0xffff + 5 + 4465 + 4);
+  }
+
+  #[test]
+  fn png_structure() {
+    let data = encode_png(&[0, 255, 255, 0], 2, 2);
+    assert_eq!(&data[..8], &[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a]);
+    assert_eq!(&data[12..16], b"IHDR");
+    assert_eq!(&data[16..29], &[0, 0, 0, 2, 0, 0, 0, 2, 8, 0, 0, 0, 0]);
+    assert_eq!(&data[data.len() - 8..data.len() - 4], b"IEND");
+    // IEND has no body, so its CRC is the same for every file
+    assert_eq!(&data[data.len() - 4..], &[0xae, 0x42, 0x60, 0x82]);
+  }
+
+  #[test]
+  fn png_rgba_rows() {
+    let pixels = [1, 2, 3, 4, 5, 6, 7, 8];
+    let data = encode_png_rgba(&pixels, 1, 2);
+    assert_eq!(&data[16..29], &[0, 0, 0, 1, 0, 0, 0, 2, 8, 6, 0, 0, 0]);
+    // IDAT body: zlib header, stored block header, then filtered rows
+    assert_eq!(&data[37..41], b"IDAT");
+    assert_eq!(&data[41..48], &[0x78, 0x01, 1, 10, 0, 0xf5, 0xff]);
+    assert_eq!(&data[48..58], &[0, 1, 2, 3, 4, 0, 5, 6, 7, 8]);
+  }
+
+  #[test]
+  fn decoding_what_was_encoded() {
+    let shades = [0, 85, 170, 255, 255, 170];
+    let image = decode_png(&encode_png(&shades, 3, 2)).unwrap();
+    assert_eq!((image.width, image.height, image.pixels), (3, 2, shades.to_vec()));
+    let image = decode_pgm(&encode_pgm(&shades, 2, 3)).unwrap();
+    assert_eq!((image.width, image.height, image.pixels), (2, 3, shades.to_vec()));
+    // colors are read by their luma
+    let rgba = [255, 255, 255, 255, 255, 0, 0, 128];
+    assert_eq!(decode_png(&encode_png_rgba(&rgba, 2, 1)).unwrap().pixels, vec![255, 76]);
+    assert!(decode_png(&encode_pgm(&shades, 3, 2)).is_err());
+    assert!(decode_pgm(b"P5\n2 2\n255\n\x00").is_err());
+  }
+
+  #[test]
+  fn decoding_filters_and_palettes() {
+    let png = |header: [u8; 13], palette: &[u8], rows: &[u8]| {
+      let mut data = PNG_SIGNATURE.to_vec();
+      write_chunk(&mut data, b"IHDR", &header);
+      if !palette.is_empty() {
+        write_chunk(&mut data, b"PLTE", palette);
+      }
+      write_chunk(&mut data, b"IDAT", &zlib_stored(rows));
+      write_chunk(&mut data, b"IEND", &[]);
+      data
+    };
+    // 2x4 grayscale, one row for each of the sub, up, average, and paeth
+    // filters
+    let header = [0, 0, 0, 2, 0, 0, 0, 4, 8, 0, 0, 0, 0];
+    let rows = [1, 10, 5, 2, 1, 1, 3, 10, 0, 4, 0, 1];
+    let image = decode_png(&png(header, &[], &rows)).unwrap();
+    assert_eq!(image.pixels, vec![10, 15, 11, 16, 15, 15, 15, 16]);
+
+    // 2-bit indexed colors, with three pixels in one byte
+    let header = [0, 0, 0, 3, 0, 0, 0, 1, 2, 3, 0, 0, 0];
+    let palette = [0, 0, 0, 255, 255, 255, 170, 170, 170];
+    let image = decode_png(&png(header, &palette, &[0, 0b10_01_00_00])).unwrap();
+    assert_eq!(image.pixels, vec![170, 255, 0]);
+    assert!(decode_png(&png(header, &palette, &[0, 0b11_00_00_00])).is_err());
+  }
+}
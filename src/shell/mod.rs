@@ -1,19 +1,91 @@
-#[cfg(not(feature="graphics"))]
+pub mod color;
+pub mod filter;
 mod headless;
-#[cfg(feature="graphics")]
+pub mod image;
+pub mod reference;
+pub mod speed;
+pub mod sprites;
+pub mod text;
+pub mod thread;
+pub mod viewer;
+#[cfg(feature="shell-window")]
 mod window;
 
-#[cfg(not(feature="graphics"))]
-use headless::HeadlessShell as ShellImpl;
-#[cfg(feature="graphics")]
-use window::WindowShell as ShellImpl;
-
 use crate::emulator::Core;
+use crate::system::RomSizePolicy;
+use color::ColorAdjustment;
+use filter::Filter;
+use headless::HeadlessShell;
+use speed::FastForward;
+use std::path::PathBuf;
 
 pub trait Shell {
   fn run(&mut self, core: Core);
 }
 
-pub fn create_shell() -> ShellImpl {
-  ShellImpl::new()
+/// Conditions for ending a run without a window, so that the emulator can be
+/// scripted. Shells with a window ignore them.
+#[derive(Default)]
+pub struct RunLimits {
+  /// Stop after this many frames
+  pub frames: Option<u64>,
+  /// Stop once this many machine cycles have run. When combined with a frame
+  /// limit or a recording, this is only checked between frames.
+  pub cycles: Option<u64>,
+  /// Save the last frame to this file when stopping, as a PNG or PGM
+  pub dump_frame: Option<String>,
+  /// Compare the last frame to this reference image when stopping
+  pub reference_frame: Option<String>,
+}
+
+impl RunLimits {
+  pub fn is_limited(&self) -> bool {
+    self.frames.is_some() || self.cycles.is_some()
+  }
+}
+
+/// Everything the shells read from the command line or config file
+pub struct Settings {
+  pub limits: RunLimits,
+  pub fast_forward: FastForward,
+  pub filter: Filter,
+  /// Initial window scale, in multiples of the LCD size
+  pub scale: Option<usize>,
+  /// Directory for screenshots, instead of the working directory
+  pub save_dir: Option<PathBuf>,
+  /// Run without a window, even when one is available
+  pub headless: bool,
+  /// How ROMs opened while running are resized, like the first one
+  pub size_policy: RomSizePolicy,
+  /// Show the performance HUD from the start, or print it once a second
+  /// without a window
+  pub hud: bool,
+  /// Brightness, contrast, and gamma applied by the window
+  pub color: ColorAdjustment,
+  /// The config file that changes made while running, like color
+  /// adjustments, are saved to. Nothing is saved without one.
+  pub config_file: Option<PathBuf>,
+}
+
+/// Print everything the core has raised since the last call. The core
+/// doesn't print anything itself, so shells call this after running frames
+/// and after shutting it down.
+pub fn print_messages(core: &mut Core) {
+  for message in core.take_messages() {
+    println!("{}", message);
+  }
+}
+
+#[cfg(not(feature="shell-window"))]
+pub fn create_shell(settings: Settings) -> Box<dyn Shell> {
+  Box::new(HeadlessShell::new(settings.limits, settings.hud))
+}
+
+#[cfg(feature="shell-window")]
+pub fn create_shell(settings: Settings) -> Box<dyn Shell> {
+  if settings.headless {
+    Box::new(HeadlessShell::new(settings.limits, settings.hud))
+  } else {
+    Box::new(window::WindowShell::new(settings))
+  }
 }
@@ -0,0 +1,168 @@
+//! Compares frames to reference images, for PPU test ROMs like dmg-acid2
+//! that draw their result instead of reporting it. A ROM runs for a number
+//! of frames, then its last frame is checked pixel by pixel. On a mismatch,
+//! a diff image shows which pixels are wrong.
+//!
+//! Setting `GB_SCREENSHOT_DIR` runs every ROM in that directory which has a
+//! reference image beside it, like `dmg-acid2.gb` with `dmg-acid2.png`, as
+//! part of the tests.
+
+use crate::devices::video::lcd::{LCD_HEIGHT, LCD_WIDTH};
+use crate::emulator::Core;
+use super::image;
+use std::fmt;
+
+/// Frames that test ROMs get to draw their result, unless told otherwise.
+/// Most of them are done within a few frames.
+pub const DEFAULT_TEST_FRAMES: u64 = 120;
+
+/// Matching pixels are shown faded in the diff image, and mismatched pixels
+/// in this color
+const MISMATCH_COLOR: [u8; 4] = [255, 0, 0, 255];
+
+/// Load a reference image as LCD shades. It has to be the size of the LCD.
+/// Other tools save references in different grays, so each pixel is moved to
+/// the nearest of the four shades.
+pub fn load_reference(name: &str) -> Result<Vec<u8>, String> {
+  let reference = image::load_image(name)?;
+  if (reference.width, reference.height) != (LCD_WIDTH, LCD_HEIGHT) {
+    return Err(format!(
+      "Reference image \"{}\" is {}x{}, instead of {}x{}",
+      name, reference.width, reference.height, LCD_WIDTH, LCD_HEIGHT,
+    ));
+  }
+  Ok(reference.pixels.iter().map(|gray| ((*gray as u16 + 42) / 85 * 85) as u8).collect())
+}
+
+/// The result of comparing a frame to its reference
+pub struct FrameDiff {
+  pub mismatched: usize,
+  /// Top-left and bottom-right corners of the area that differs
+  pub bounds: Option<((usize, usize), (usize, usize))>,
+  /// RGBA8 pixels of the diff image
+  pub image: Vec<u8>,
+}
+
+impl FrameDiff {
+  pub fn matches(&self) -> bool {
+    self.mismatched == 0
+  }
+
+  /// Save the diff image as a PNG
+  pub fn save_image(&self, name: &str) -> Result<(), String> {
+    let data = image::encode_png_rgba(&self.image, LCD_WIDTH, LCD_HEIGHT);
+    std::fs::write(name, data).map_err(|_| format!("Unable to write image \"{}\"", name))
+  }
+}
+
+impl fmt::Display for FrameDiff {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.bounds {
+      Some(((left, top), (right, bottom))) => write!(
+        f,
+        "{} pixels differ from the reference, between ({}, {}) and ({}, {})",
+        self.mismatched, left, top, right, bottom,
+      ),
+      None => write!(f, "Frame matches the reference"),
+    }
+  }
+}
+
+/// Compare a frame of LCD shades to a reference of the same size
+pub fn compare_frame(lcd: &[u8], reference: &[u8]) -> FrameDiff {
+  let mut diff = FrameDiff {
+    mismatched: 0,
+    bounds: None,
+    image: Vec::with_capacity(LCD_WIDTH * LCD_HEIGHT * 4),
+  };
+  for (index, (actual, expected)) in lcd.iter().zip(reference.iter()).enumerate() {
+    if actual == expected {
+      let faded = 192 + actual / 4;
+      diff.image.extend_from_slice(&[faded, faded, faded, 255]);
+      continue;
+    }
+    diff.image.extend_from_slice(&MISMATCH_COLOR);
+    diff.mismatched += 1;
+    let (x, y) = (index % LCD_WIDTH, index / LCD_WIDTH);
+    // pixels are compared from the top down, so the latest is the lowest
+    diff.bounds = Some(match diff.bounds {
+      Some(((left, top), (right, _))) => ((left.min(x), top), (right.max(x), y)),
+      None => ((x, y), (x, y)),
+    });
+  }
+  diff
+}
+
+/// Run `frames` frames, then compare the last one to the reference. Breaks
+/// along the way are ignored, so that a test ROM which signals the end with
+/// a breakpoint still gets to finish the frame.
+pub fn run_screenshot_test(core: &mut Core, frames: u64, reference: &[u8]) -> FrameDiff {
+  let mut completed = 0;
+  while completed < frames {
+    if core.run_frame().is_none() {
+      completed += 1;
+    }
+  }
+  compare_frame(core.get_screen_buffer(), reference)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{compare_frame, load_reference, run_screenshot_test, DEFAULT_TEST_FRAMES, MISMATCH_COLOR};
+  use crate::devices::video::lcd::{LCD_HEIGHT, LCD_WIDTH};
+  use crate::emulator::Core;
+
+  #[test]
+  fn comparing_frames() {
+    let reference = vec![255; LCD_WIDTH * LCD_HEIGHT];
+    let mut lcd = reference.clone();
+    let diff = compare_frame(&lcd, &reference);
+    assert!(diff.matches());
+    assert_eq!(diff.to_string(), "Frame matches the reference");
+    assert_eq!(diff.image.len(), LCD_WIDTH * LCD_HEIGHT * 4);
+
+    lcd[LCD_WIDTH + 20] = 0;
+    lcd[LCD_WIDTH * 3 + 5] = 85;
+    let diff = compare_frame(&lcd, &reference);
+    assert!(!diff.matches());
+    assert_eq!(diff.to_string(), "2 pixels differ from the reference, between (5, 1) and (20, 3)");
+    let pixel = (LCD_WIDTH + 20) * 4;
+    assert_eq!(diff.image[pixel..pixel + 4], MISMATCH_COLOR);
+    assert_eq!(diff.image[..4], [255, 255, 255, 255]);
+
+    // JR -2, with every shade of the palette left black
+    let mut core = Core::with_code_block(vec![0x18, 0xfe].into_boxed_slice());
+    assert_eq!(run_screenshot_test(&mut core, 2, &reference).mismatched, LCD_WIDTH * LCD_HEIGHT);
+    assert!(run_screenshot_test(&mut core, 1, &vec![0; LCD_WIDTH * LCD_HEIGHT]).matches());
+  }
+
+  /// Runs the ROMs in `GB_SCREENSHOT_DIR`, if it is set, saving a diff image
+  /// beside each one that fails
+  #[test]
+  fn screenshot_roms() {
+    let dir = match std::env::var("GB_SCREENSHOT_DIR") {
+      Ok(dir) => dir,
+      Err(_) => return,
+    };
+    let mut failures = Vec::new();
+    let entries = std::fs::read_dir(&dir).unwrap_or_else(|_| panic!("Unable to read {}", dir));
+    for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+      if path.extension().is_none_or(|extension| extension != "gb") {
+        continue;
+      }
+      let reference_path = path.with_extension("png");
+      if !reference_path.exists() {
+        continue;
+      }
+      let reference = load_reference(&reference_path.display().to_string()).unwrap();
+      let data = std::fs::read(&path).unwrap_or_else(|_| panic!("Unable to read {}", path.display()));
+      let mut core = Core::from_rom_bytes(data).unwrap();
+      let diff = run_screenshot_test(&mut core, DEFAULT_TEST_FRAMES, &reference);
+      if !diff.matches() {
+        diff.save_image(&path.with_extension("diff.png").display().to_string()).unwrap();
+        failures.push(format!("{}: {}", path.display(), diff));
+      }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+  }
+}
@@ -0,0 +1,127 @@
+//! Fast-forward, which runs several frames for each one shown. Frames that
+//! won't be shown can skip drawing, which leaves more time for emulation.
+
+use crate::emulator::{BreakEvent, Core};
+use std::time::{Duration, Instant};
+
+/// How long the window shows each frame at normal speed
+pub const FRAME_TIME: Duration = Duration::from_millis(16);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Speed {
+  /// A fixed number of frames in the time of one
+  Multiplier(u32),
+  /// As many frames as can run in the time of one
+  Unlimited,
+}
+
+impl Speed {
+  /// Parse a speed like "2x", "4", or "unlimited"
+  pub fn parse(value: &str) -> Result<Self, String> {
+    let value = value.trim().to_lowercase();
+    if value == "unlimited" || value == "max" {
+      return Ok(Speed::Unlimited);
+    }
+    match value.trim_end_matches('x').parse::<u32>() {
+      Ok(multiplier) if multiplier >= 1 => Ok(Speed::Multiplier(multiplier)),
+      _ => Err(format!("Invalid fast-forward speed \"{}\"", value)),
+    }
+  }
+
+  pub fn name(&self) -> String {
+    match self {
+      Speed::Multiplier(multiplier) => format!("{}X", multiplier),
+      Speed::Unlimited => String::from("MAX"),
+    }
+  }
+}
+
+pub struct FastForward {
+  pub speed: Speed,
+  /// When set, only the last frame of each batch is drawn
+  pub skip_rendering: bool,
+}
+
+impl FastForward {
+  pub fn new() -> Self {
+    Self {
+      speed: Speed::Multiplier(4),
+      skip_rendering: true,
+    }
+  }
+
+  /// Run the frames that replace a single frame at normal speed, stopping
+  /// early if execution breaks
+  pub fn run_frames(&self, core: &mut Core) -> Option<BreakEvent> {
+    let deadline = Instant::now() + FRAME_TIME;
+    let mut frames = 1;
+    loop {
+      let last = match self.speed {
+        Speed::Multiplier(multiplier) => frames >= multiplier,
+        Speed::Unlimited => Instant::now() >= deadline,
+      };
+      let event = if last || !self.skip_rendering {
+        core.run_frame()
+      } else {
+        core.skip_frame()
+      };
+      if last || event.is_some() {
+        return event;
+      }
+      frames += 1;
+    }
+  }
+}
+
+impl Default for FastForward {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{FastForward, Speed};
+  use crate::emulator::Core;
+  use crate::timing::FRAME_CYCLES;
+
+  #[test]
+  fn parse_speeds() {
+    assert_eq!(Speed::parse("2x"), Ok(Speed::Multiplier(2)));
+    assert_eq!(Speed::parse("8"), Ok(Speed::Multiplier(8)));
+    assert_eq!(Speed::parse("Unlimited"), Ok(Speed::Unlimited));
+    assert!(Speed::parse("0x").is_err());
+    assert!(Speed::parse("fast").is_err());
+    assert_eq!(Speed::Multiplier(4).name(), "4X");
+  }
+
+  #[test]
+  #[cfg(feature = "video")]
+  fn skipped_frames_are_not_drawn() {
+    let code = vec![
+      0x3e, 0x91, // LD A, 0x91
+      0xe0, 0x40, // LDH (0x40), A
+      0x3e, 0xe4, // LD A, 0xe4
+      0xe0, 0x47, // LDH (0x47), A
+      0x18, 0xfe, // JR -2
+    ];
+    let mut core = Core::with_code_block(code.into_boxed_slice());
+    core.run_frame();
+    core.run_frame();
+    // the LCD is blank
+    assert!(core.get_screen_buffer().iter().all(|shade| *shade == 255));
+    core.memory.video_ram[0] = 0xff;
+    core.memory.video_ram[1] = 0xff;
+
+    let mut fast_forward = FastForward::new();
+    fast_forward.speed = Speed::Multiplier(3);
+    let start = core.cycles_elapsed();
+    core.skip_frame();
+    assert_eq!(core.get_screen_buffer()[0], 255);
+    assert!(fast_forward.run_frames(&mut core).is_none());
+    // the last frame is drawn, showing the dark tile in the corner
+    assert_eq!(core.get_screen_buffer()[0], 0);
+    let frames = (core.cycles_elapsed() - start) as f64 / FRAME_CYCLES as f64;
+    assert!(frames > 3.5 && frames < 4.5);
+  }
+}
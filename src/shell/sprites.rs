@@ -0,0 +1,84 @@
+//! Debug view of every OAM entry, laid out in a grid that fills a 160x144
+//! buffer so that it can be displayed like a second LCD. Each cell is labeled
+//! with the entry's index, and the object is drawn inside a frame that shows
+//! its full size, including transparent pixels. Labels of objects that are
+//! positioned offscreen are drawn in a lighter shade.
+
+use crate::devices::video::VideoState;
+use crate::devices::video::lcd::{LCD_HEIGHT, LCD_WIDTH};
+use crate::devices::video::sprite::{OAM_ENTRY_COUNT, SpriteImage};
+use super::text;
+
+const COLUMNS: usize = 8;
+const CELL_WIDTH: usize = LCD_WIDTH / COLUMNS;
+const CELL_HEIGHT: usize = 28;
+
+const BACKGROUND: u8 = 255;
+const FRAME: u8 = 170;
+
+pub fn draw_sprite_grid(buffer: &mut [u8], video: &VideoState, video_ram: &[u8], oam: &[u8]) {
+  for pixel in buffer.iter_mut() {
+    *pixel = BACKGROUND;
+  }
+  for index in 0..OAM_ENTRY_COUNT {
+    let sprite = video.render_sprite(video_ram, oam, index);
+    let cell_x = (index % COLUMNS) * CELL_WIDTH;
+    let cell_y = (index / COLUMNS) * CELL_HEIGHT;
+    let label_shade = if sprite.is_offscreen() { FRAME } else { 0 };
+    text::draw_text(buffer, cell_x + 6, cell_y + 1, &format!("{:02}", index), label_shade);
+    draw_sprite(buffer, &sprite, cell_x + 6, cell_y + 9);
+  }
+}
+
+fn draw_sprite(buffer: &mut [u8], sprite: &SpriteImage, x: usize, y: usize) {
+  // frame the sprite with a one-pixel border
+  for frame_y in (y - 1)..(y + sprite.height + 1) {
+    for frame_x in (x - 1)..(x + 9) {
+      set_pixel(buffer, frame_x, frame_y, FRAME);
+    }
+  }
+  for (i, pixel) in sprite.pixels.iter().enumerate() {
+    let shade = pixel.unwrap_or(BACKGROUND);
+    set_pixel(buffer, x + (i % 8), y + (i / 8), shade);
+  }
+}
+
+fn set_pixel(buffer: &mut [u8], x: usize, y: usize, shade: u8) {
+  if x < LCD_WIDTH && y < LCD_HEIGHT {
+    buffer[y * LCD_WIDTH + x] = shade;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{draw_sprite_grid, CELL_HEIGHT, CELL_WIDTH, FRAME};
+  use crate::devices::video::VideoState;
+  use crate::devices::video::lcd::{LCD_HEIGHT, LCD_WIDTH};
+
+  #[test]
+  fn grid_layout() {
+    let mut vram = vec![0; 0x2000];
+    // tile 1 is solid color 3
+    for byte in vram[0x10..0x20].iter_mut() {
+      *byte = 0xff;
+    }
+    let mut oam = vec![0; 0xa0];
+    // entry 9 is in the second row, second column
+    oam[36] = 16;
+    oam[37] = 8;
+    oam[38] = 1;
+    let mut video = VideoState::new();
+    video.set_obj_palette(0, 0b11100100);
+    let mut buffer = vec![0; LCD_WIDTH * LCD_HEIGHT];
+    draw_sprite_grid(&mut buffer, &video, &vram, &oam);
+
+    let left = CELL_WIDTH + 6;
+    let top = CELL_HEIGHT + 9;
+    assert_eq!(buffer[top * LCD_WIDTH + left], 0);
+    assert_eq!(buffer[(top + 7) * LCD_WIDTH + left + 7], 0);
+    assert_eq!(buffer[(top - 1) * LCD_WIDTH + left], FRAME);
+    // entry 0 uses the transparent tile 0
+    assert_eq!(buffer[9 * LCD_WIDTH + 6], 255);
+    assert_eq!(buffer[8 * LCD_WIDTH + 6], FRAME);
+  }
+}
@@ -0,0 +1,168 @@
+//! Debug text drawn directly into the 160x144 LCD buffer, using a built-in
+//! 3x5 pixel font. Each character occupies a 4x6 cell, leaving a column and a
+//! row of spacing. Because text is drawn before any scaling or color
+//! conversion, every shell can display it, including headless screen dumps.
+
+use crate::devices::video::lcd::{LCD_HEIGHT, LCD_WIDTH};
+
+pub const GLYPH_WIDTH: usize = 4;
+pub const GLYPH_HEIGHT: usize = 6;
+
+/// Each glyph is 5 rows of 3 pixels, with the leftmost pixel in bit 2.
+/// Lowercase letters are drawn as uppercase, and any character without a
+/// glyph is drawn as a question mark.
+fn get_glyph(c: char) -> [u8; 5] {
+  match c.to_ascii_uppercase() {
+    ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+    '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+    '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+    '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+    '3' => [0b111, 0b001, 0b011, 0b001, 0b111],
+    '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+    '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+    '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+    '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+    '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+    '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+    'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+    'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+    'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+    'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+    'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+    'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+    'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+    'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+    'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+    'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+    'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+    'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+    'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+    'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+    'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+    'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+    'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+    'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+    'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+    'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+    'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+    'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+    'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+    'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+    'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+    'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+    '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+    ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+    ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+    ';' => [0b000, 0b010, 0b000, 0b010, 0b100],
+    '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+    '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+    '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+    '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+    '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+    '\'' => [0b010, 0b010, 0b000, 0b000, 0b000],
+    '"' => [0b101, 0b101, 0b000, 0b000, 0b000],
+    '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+    ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+    '[' => [0b011, 0b010, 0b010, 0b010, 0b011],
+    ']' => [0b110, 0b010, 0b010, 0b010, 0b110],
+    '<' => [0b001, 0b010, 0b100, 0b010, 0b001],
+    '>' => [0b100, 0b010, 0b001, 0b010, 0b100],
+    '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+    '*' => [0b000, 0b101, 0b010, 0b101, 0b000],
+    '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+    '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+    '$' => [0b011, 0b110, 0b010, 0b011, 0b110],
+    '&' => [0b010, 0b101, 0b010, 0b101, 0b011],
+    '@' => [0b010, 0b101, 0b111, 0b100, 0b011],
+    _ => [0b111, 0b001, 0b011, 0b000, 0b010],
+  }
+}
+
+/// Size in pixels of a block of text, as (width, height). Lines are separated
+/// by newlines.
+pub fn measure_text(text: &str) -> (usize, usize) {
+  let columns = text.lines().map(|line| line.chars().count()).max().unwrap_or(0);
+  let rows = text.lines().count();
+  (columns * GLYPH_WIDTH, rows * GLYPH_HEIGHT)
+}
+
+/// Draw text with its top-left corner at (x, y), using a single shade for the
+/// foreground. Only the glyph pixels are written, so the image behind shows
+/// through. Anything beyond the edges of the screen is clipped.
+pub fn draw_text(buffer: &mut [u8], x: usize, y: usize, text: &str, shade: u8) {
+  for (row, line) in text.lines().enumerate() {
+    let top = y + row * GLYPH_HEIGHT;
+    for (column, c) in line.chars().enumerate() {
+      let left = x + column * GLYPH_WIDTH;
+      let glyph = get_glyph(c);
+      for (glyph_y, bits) in glyph.iter().enumerate() {
+        for glyph_x in 0..3 {
+          if bits & (4 >> glyph_x) != 0 {
+            set_pixel(buffer, left + glyph_x, top + glyph_y, shade);
+          }
+        }
+      }
+    }
+  }
+}
+
+/// Draw text on top of a solid background, so that it stays readable
+/// regardless of what is on screen. The background extends one pixel beyond
+/// the text on every side.
+pub fn draw_text_box(buffer: &mut [u8], x: usize, y: usize, text: &str, shade: u8, background: u8) {
+  let (width, height) = measure_text(text);
+  let left = x.saturating_sub(1);
+  let top = y.saturating_sub(1);
+  for box_y in top..(y + height) {
+    for box_x in left..(x + width) {
+      set_pixel(buffer, box_x, box_y, background);
+    }
+  }
+  draw_text(buffer, x, y, text, shade);
+}
+
+fn set_pixel(buffer: &mut [u8], x: usize, y: usize, shade: u8) {
+  if x < LCD_WIDTH && y < LCD_HEIGHT {
+    buffer[y * LCD_WIDTH + x] = shade;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{draw_text, draw_text_box, measure_text};
+  use crate::devices::video::lcd::{LCD_HEIGHT, LCD_WIDTH};
+
+  fn row(buffer: &[u8], x: usize, y: usize, width: usize) -> Vec<u8> {
+    let start = y * LCD_WIDTH + x;
+    buffer[start..(start + width)].to_vec()
+  }
+
+  #[test]
+  fn draws_glyphs() {
+    let mut buffer = vec![255; LCD_WIDTH * LCD_HEIGHT];
+    draw_text(&mut buffer, 10, 20, "a1", 0);
+    assert_eq!(row(&buffer, 10, 20, 8), vec![255, 0, 255, 255, 255, 0, 255, 255]);
+    assert_eq!(row(&buffer, 10, 22, 8), vec![0, 0, 0, 255, 255, 0, 255, 255]);
+    assert_eq!(row(&buffer, 10, 24, 8), vec![0, 255, 0, 255, 0, 0, 0, 255]);
+    // the spacing row below each line is untouched
+    assert_eq!(row(&buffer, 10, 25, 8), vec![255; 8]);
+  }
+
+  #[test]
+  fn background_box() {
+    let mut buffer = vec![255; LCD_WIDTH * LCD_HEIGHT];
+    draw_text_box(&mut buffer, 1, 1, "-", 255, 0);
+    assert_eq!(row(&buffer, 0, 0, 6), vec![0, 0, 0, 0, 0, 255]);
+    assert_eq!(row(&buffer, 0, 3, 6), vec![0, 255, 255, 255, 0, 255]);
+    assert_eq!(row(&buffer, 0, 7, 6), vec![255; 6]);
+  }
+
+  #[test]
+  fn clips_at_edges() {
+    let mut buffer = vec![255; LCD_WIDTH * LCD_HEIGHT];
+    draw_text_box(&mut buffer, 155, 140, "CLIPPED\nTEXT", 0, 128);
+    assert_eq!(buffer[139 * LCD_WIDTH + 154], 128);
+    assert_eq!(buffer[143 * LCD_WIDTH + 159], 0);
+    assert_eq!(measure_text("CLIPPED\nTEXT"), (28, 12));
+  }
+}
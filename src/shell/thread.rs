@@ -0,0 +1,240 @@
+//! Runs the core on a thread of its own, so that the window stays responsive
+//! however long a frame takes to emulate. The window sends commands in, like
+//! button presses or a game to load, and the thread hands back each finished
+//! frame along with everything needed to draw it. The thread also keeps
+//! emulated time in step with real time, waiting out the rest of each frame
+//! while it listens for commands.
+
+use crate::debug::hud::PerfHud;
+use crate::devices::sgb::SgbScreen;
+use crate::devices::video::views::DebugImage;
+use crate::devices::video::palette::Palette;
+use crate::emulator::Core;
+use crate::timing::{CYCLES_PER_SECOND, FRAME_CYCLES};
+use super::print_messages;
+use super::speed::FastForward;
+use super::viewer::VramView;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Time a frame takes on hardware, a little longer than 1/60th of a second
+pub const FRAME_DURATION: Duration = Duration::from_nanos(FRAME_CYCLES * 1_000_000_000 / CYCLES_PER_SECOND);
+
+/// Text shown on top of the screen, and the number of frames it stays up
+pub type OnScreenMessage = (String, usize);
+
+/// Work done on the core between frames. It can return a message to show.
+pub type CoreTask = Box<dyn FnOnce(&mut Core) -> Option<OnScreenMessage> + Send>;
+
+pub enum Command {
+  Run(CoreTask),
+  /// Run several frames in the time of one, while fast-forward is held
+  FastForward(bool),
+  /// Start or stop measuring performance for the HUD
+  ToggleHud,
+  /// Start or stop drawing a view of VRAM along with each frame
+  ShowView(Option<VramView>),
+}
+
+/// A finished frame, and everything else the window draws along with it
+pub struct Frame {
+  /// Shade of each pixel on the LCD
+  pub lcd: Vec<u8>,
+  pub palette: Palette,
+  /// Colors and border to draw the LCD with, for Super Game Boy games
+  pub sgb: Option<SgbScreen>,
+  /// The VRAM view being shown, if any
+  pub view: Option<DebugImage>,
+  /// Latest performance report, while the HUD is shown
+  pub hud: Option<String>,
+  /// Messages from the tasks run since the previous frame
+  pub messages: Vec<OnScreenMessage>,
+}
+
+pub struct CoreThread {
+  commands: Option<Sender<Command>>,
+  handle: Option<JoinHandle<Core>>,
+}
+
+impl CoreThread {
+  /// Start running frames on `core`, passing each one to `show`. The thread
+  /// stops once `show` returns false, or when it is stopped from here.
+  pub fn start<F>(core: Core, fast_forward: FastForward, hud: bool, show: F) -> Self
+  where
+    F: FnMut(Frame) -> bool + Send + 'static,
+  {
+    let (commands, receiver) = mpsc::channel();
+    let handle = thread::Builder::new()
+      .name(String::from("core"))
+      .spawn(move || {
+        let mut emulation = Emulation::new(core, fast_forward, hud);
+        emulation.run(receiver, show);
+        emulation.core
+      })
+      .expect("Failed to start the core thread");
+    Self {
+      commands: Some(commands),
+      handle: Some(handle),
+    }
+  }
+
+  /// Commands sent after the thread has stopped are ignored
+  pub fn send(&self, command: Command) {
+    if let Some(commands) = self.commands.as_ref() {
+      let _ = commands.send(command);
+    }
+  }
+
+  /// Run `task` on the core before the next frame starts
+  pub fn run<T: FnOnce(&mut Core) -> Option<OnScreenMessage> + Send + 'static>(&self, task: T) {
+    self.send(Command::Run(Box::new(task)));
+  }
+
+  /// Stop the thread once its current frame is done, and take back the
+  /// core. Returns nothing if it has already been stopped.
+  pub fn stop(&mut self) -> Option<Core> {
+    self.commands = None;
+    let handle = self.handle.take()?;
+    Some(handle.join().expect("The core thread panicked"))
+  }
+}
+
+/// State that lives on the core thread
+struct Emulation {
+  core: Core,
+  fast_forward: FastForward,
+  fast_forward_held: bool,
+  hud: Option<PerfHud>,
+  view: Option<VramView>,
+  messages: Vec<OnScreenMessage>,
+}
+
+impl Emulation {
+  fn new(mut core: Core, fast_forward: FastForward, hud: bool) -> Self {
+    let hud = hud.then(|| PerfHud::start(&mut core));
+    Self {
+      core,
+      fast_forward,
+      fast_forward_held: false,
+      hud,
+      view: None,
+      messages: Vec::new(),
+    }
+  }
+
+  /// Run frames until the window stops listening or the commands end
+  fn run<F: FnMut(Frame) -> bool>(&mut self, commands: Receiver<Command>, mut show: F) {
+    let mut next_frame = Instant::now();
+    loop {
+      loop {
+        match commands.try_recv() {
+          Ok(command) => self.handle(command),
+          Err(TryRecvError::Empty) => break,
+          Err(TryRecvError::Disconnected) => return,
+        }
+      }
+
+      // a paused core doesn't run its frames, so there is nothing to hurry
+      let event = if self.fast_forward_held && !self.core.is_paused() {
+        self.fast_forward.run_frames(&mut self.core)
+      } else {
+        self.core.run_frame()
+      };
+      print_messages(&mut self.core);
+      if let Some(event) = event {
+        println!("{}", event);
+      }
+      if !show(self.frame()) {
+        return;
+      }
+
+      // After falling behind, pick up from now instead of rushing through
+      // frames to catch up
+      next_frame += FRAME_DURATION;
+      next_frame = next_frame.max(Instant::now());
+      while let Some(remaining) = next_frame.checked_duration_since(Instant::now()) {
+        match commands.recv_timeout(remaining) {
+          Ok(command) => self.handle(command),
+          Err(RecvTimeoutError::Timeout) => break,
+          Err(RecvTimeoutError::Disconnected) => return,
+        }
+      }
+    }
+  }
+
+  fn handle(&mut self, command: Command) {
+    match command {
+      Command::Run(task) => {
+        if let Some(message) = task(&mut self.core) {
+          self.messages.push(message);
+        }
+      },
+      Command::FastForward(held) => self.fast_forward_held = held,
+      Command::ToggleHud => {
+        self.hud = match self.hud.take() {
+          Some(hud) => {
+            hud.stop(&mut self.core);
+            None
+          },
+          None => Some(PerfHud::start(&mut self.core)),
+        };
+      },
+      Command::ShowView(view) => self.view = view,
+    }
+  }
+
+  fn frame(&mut self) -> Frame {
+    let core = &self.core;
+    let memory = &core.memory;
+    let view = self.view.map(|view| view.render(core));
+    let hud = self.hud.as_mut().and_then(|hud| {
+      hud.frame_shown(core);
+      hud.report().map(|report| report.hud_text())
+    });
+    Frame {
+      lcd: core.get_screen_buffer().to_vec(),
+      palette: *memory.io.video.get_palette(),
+      sgb: memory.io.sgb.as_ref().map(|sgb| sgb.get_screen().clone()),
+      view,
+      hud,
+      messages: std::mem::take(&mut self.messages),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{CoreThread, FRAME_DURATION};
+  use crate::emulator::Core;
+  use crate::shell::speed::FastForward;
+  use std::sync::mpsc;
+  use std::time::Instant;
+
+  #[test]
+  fn frames_and_tasks() {
+    // JR -2
+    let core = Core::with_code_block(vec![0x18, 0xfe].into_boxed_slice());
+    let (frames, received) = mpsc::channel();
+    let started = Instant::now();
+    let mut emulation = CoreThread::start(core, FastForward::new(), false, move |frame| frames.send(frame).is_ok());
+    emulation.run(|core| {
+      core.registers.bc = 0x1234;
+      Some((String::from("DONE"), 60))
+    });
+    let mut messages = Vec::new();
+    for _ in 0..3 {
+      let frame = received.recv().unwrap();
+      assert!(frame.view.is_none());
+      assert!(frame.sgb.is_none());
+      messages.extend(frame.messages);
+    }
+    // frames are paced like the hardware
+    assert!(started.elapsed() >= FRAME_DURATION * 2);
+    assert_eq!(messages, vec![(String::from("DONE"), 60)]);
+
+    let core = emulation.stop().unwrap();
+    assert_eq!(core.registers.get_bc(), 0x1234);
+    assert!(emulation.stop().is_none());
+  }
+}
@@ -0,0 +1,127 @@
+//! Debug views of VRAM for a secondary window, which cycles through them one
+//! at a time, and for saving all of them at once as PNGs. Along with the
+//! images, a save writes the OAM entries as a text listing.
+
+use crate::devices::video::lcd::{LCD_HEIGHT, LCD_WIDTH};
+use crate::devices::video::sprite::OAM_ENTRY_COUNT;
+use crate::devices::video::views::DebugImage;
+use crate::emulator::Core;
+use super::image;
+use super::sprites;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VramView {
+  /// Every OAM entry, in a grid
+  Sprites,
+  /// The whole background map, with the visible area outlined
+  BgMap,
+  Tiles,
+  Palettes,
+}
+
+impl VramView {
+  pub const ALL: [VramView; 4] = [VramView::Sprites, VramView::BgMap, VramView::Tiles, VramView::Palettes];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      VramView::Sprites => "OAM",
+      VramView::BgMap => "BG MAP",
+      VramView::Tiles => "TILES",
+      VramView::Palettes => "PALETTES",
+    }
+  }
+
+  /// The view after this one, for cycling through all of them
+  pub fn next(self) -> Self {
+    match self {
+      VramView::Sprites => VramView::BgMap,
+      VramView::BgMap => VramView::Tiles,
+      VramView::Tiles => VramView::Palettes,
+      VramView::Palettes => VramView::Sprites,
+    }
+  }
+
+  pub fn render(&self, core: &Core) -> DebugImage {
+    let memory = &core.memory;
+    let video = &memory.io.video;
+    match self {
+      VramView::Sprites => {
+        let mut image = DebugImage::new(LCD_WIDTH, LCD_HEIGHT);
+        sprites::draw_sprite_grid(&mut image.pixels, video, &memory.video_ram, &memory.oam_ram);
+        image
+      },
+      VramView::BgMap => video.render_bg_map(&memory.video_ram),
+      VramView::Tiles => video.render_tile_data(&memory.video_ram),
+      VramView::Palettes => video.render_palettes(),
+    }
+  }
+
+  fn file_name(&self) -> &'static str {
+    match self {
+      VramView::Sprites => "oam",
+      VramView::BgMap => "bgmap",
+      VramView::Tiles => "tiles",
+      VramView::Palettes => "palettes",
+    }
+  }
+}
+
+/// Every OAM entry, one per line
+pub fn oam_listing(core: &Core) -> String {
+  let memory = &core.memory;
+  let mut listing = String::new();
+  for index in 0..OAM_ENTRY_COUNT {
+    let sprite = memory.io.video.render_sprite(&memory.video_ram, &memory.oam_ram, index);
+    listing.push_str(&format!("{:02}: {}\n", index, sprite));
+  }
+  listing
+}
+
+/// Save every view as a PNG, and the OAM listing as text, named after the
+/// time they were taken, in `dir` if one is given or the working directory
+/// otherwise. Returns the paths of the new files.
+pub fn save_views(core: &Core, dir: Option<&Path>) -> Result<Vec<String>, String> {
+  let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+  let stamp = format!("{}{:03}", time.as_secs(), time.subsec_millis());
+  let mut base = PathBuf::new();
+  if let Some(dir) = dir {
+    std::fs::create_dir_all(dir)
+      .map_err(|_| format!("Unable to create directory \"{}\"", dir.display()))?;
+    base.push(dir);
+  }
+  let mut names = Vec::new();
+  for view in VramView::ALL {
+    let name = base.join(format!("vram-{}-{}.png", view.file_name(), stamp)).display().to_string();
+    let image = view.render(core);
+    image::save_image(&name, &image.pixels, image.width, image.height)?;
+    names.push(name);
+  }
+  let name = base.join(format!("vram-oam-{}.txt", stamp)).display().to_string();
+  std::fs::write(&name, oam_listing(core)).map_err(|_| format!("Unable to write \"{}\"", name))?;
+  names.push(name);
+  Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{oam_listing, VramView};
+  use crate::emulator::Core;
+
+  #[test]
+  fn views_and_listing() {
+    let mut core = Core::with_code_block(vec![0x18, 0xfe].into_boxed_slice());
+    core.memory.oam_ram[..4].copy_from_slice(&[16, 8, 1, 0]);
+    let mut view = VramView::Sprites;
+    for _ in 0..VramView::ALL.len() {
+      let image = view.render(&core);
+      assert_eq!(image.pixels.len(), image.width * image.height);
+      view = view.next();
+    }
+    assert_eq!(view, VramView::Sprites);
+    let listing = oam_listing(&core);
+    assert_eq!(listing.lines().count(), 40);
+    assert_eq!(listing.lines().next(), Some("00: x   8 y  16 tile 01 attr 00"));
+  }
+}
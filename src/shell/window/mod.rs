@@ -1,70 +1,124 @@
+use crate::config;
 use crate::emulator::Core;
 use crate::devices::joypad::Button;
+use crate::devices::video::lcd::{LCD_HEIGHT, LCD_WIDTH};
+use crate::input::Turbo;
+use crate::recording::VideoRecorder;
+use crate::system::RomSizePolicy;
+use super::{image, text};
+use super::color::{AdjustmentKey, ColorAdjustment};
+use super::filter::Filter;
+use super::speed::FastForward;
+use super::thread::{Command, CoreThread, Frame};
+use super::viewer::{self, VramView};
+use super::Settings;
+use viewport::{Screen, Viewport};
+#[cfg(not(feature = "softbuffer-video"))]
 use raw_window_handle::{
   HasRawDisplayHandle,
   HasRawWindowHandle,
   RawDisplayHandle,
   RawWindowHandle,
 };
-use std::time::{Duration, SystemTime};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 use winit::{
   dpi::PhysicalSize,
   event::{ElementState, Event, VirtualKeyCode, WindowEvent},
-  event_loop::{ControlFlow, EventLoop},
-  window::WindowBuilder,
+  event_loop::{ControlFlow, EventLoopBuilder},
+  window::{Fullscreen, Window, WindowBuilder},
 };
 
-#[cfg(windows)]
+#[cfg(feature = "softbuffer-video")]
+pub mod soft;
+#[cfg(all(windows, not(feature = "softbuffer-video")))]
 pub mod windows;
-#[cfg(unix)]
+#[cfg(all(unix, not(feature = "softbuffer-video")))]
+pub mod wayland;
+pub mod viewport;
+#[cfg(all(unix, not(feature = "softbuffer-video")))]
 pub mod x11;
 
 pub static WINDOW_TITLE: &str = "GB DYNAREC";
 pub const INITIAL_SCALE: usize = 4;
+/// Frames rewound each time the rewind key is pressed
+pub const REWIND_FRAMES: u32 = 180;
 
-pub struct WindowShell {}
+pub struct WindowShell {
+  fast_forward: FastForward,
+  filter: Filter,
+  scale: usize,
+  save_dir: Option<PathBuf>,
+  size_policy: RomSizePolicy,
+  hud: bool,
+  color: ColorAdjustment,
+  config_file: Option<PathBuf>,
+}
 
 impl WindowShell {
-  pub fn new() -> Self {
-    Self{}
+  pub fn new(settings: Settings) -> Self {
+    let scale = settings.scale
+      .unwrap_or(INITIAL_SCALE)
+      .clamp(viewport::MIN_SCALE, viewport::MAX_SCALE);
+    Self {
+      fast_forward: settings.fast_forward,
+      filter: settings.filter,
+      scale,
+      save_dir: settings.save_dir,
+      size_policy: settings.size_policy,
+      hud: settings.hud,
+      color: settings.color,
+      config_file: settings.config_file,
+    }
   }
 }
 
 impl super::Shell for WindowShell {
-  fn run(&mut self, mut core: Core) {
-    let initial_width = 160 * INITIAL_SCALE as u32;
-    let initial_height = 144 * INITIAL_SCALE as u32;
-    let event_loop = EventLoop::new();
+  fn run(&mut self, core: Core) {
+    let initial_size = viewport::size_for_scale(self.scale);
+    let event_loop = EventLoopBuilder::<Frame>::with_user_event().build();
     let window = WindowBuilder::new()
       .with_title(WINDOW_TITLE)
-      .with_inner_size(PhysicalSize::new(initial_width, initial_height))
+      .with_inner_size(initial_size)
+      .with_min_inner_size(viewport::size_for_scale(1))
       .build(&event_loop)
       .expect("Failed to initialize window");
 
-    let mut video_impl: Box<dyn VideoImpl> = match window.raw_window_handle() {
-      #[cfg(windows)]
-      RawWindowHandle::Win32(handle) => {
-        Box::new(windows::Video::new(handle))
-      },
-      #[cfg(unix)]
-      RawWindowHandle::Xlib(window_handle) => {
-        let display_handle = match window.raw_display_handle() {
-          RawDisplayHandle::Xlib(raw_handle) => raw_handle,
-          _ => panic!("Display type does not match window type"),
-        };
-        Box::new(x11::Video::new(window_handle, display_handle))
-      },
-      #[cfg(unix)]
-      RawWindowHandle::Wayland(handle) => {
-        panic!("Wayland is not supported yet");
-      },
-      _ => panic!("Unsupported platform"),
-    };
-
-    let mut last_frame_time = SystemTime::now();
-
-    event_loop.run(move |event, _, control_flow| {
-      *control_flow = ControlFlow::Poll;
+    let mut video_impl = create_video_impl(&window);
+
+    let mut color_adjustment = self.color;
+    let config_file = self.config_file.take();
+    let mut color_table = color_adjustment.build_table();
+    let mut adjusted_lcd = vec![0u8; LCD_WIDTH * LCD_HEIGHT];
+    // Super Game Boy games are colored by the core, and may add a border
+    // around the screen that the window fits in along with it
+    let mut sgb_pixels = Vec::new();
+    // Message shown on top of the screen, and the number of frames remaining
+    // before it disappears
+    let mut osd_message: Option<(String, usize)> = None;
+    // Secondary window showing a live view of VRAM. F8 opens it on the OAM
+    // entries, Shift + F8 moves on to the next view, and Ctrl + F8 saves
+    // all of them.
+    let mut viewer_window: Option<(Window, Box<dyn VideoImpl>)> = None;
+    let mut vram_view = VramView::Sprites;
+    let mut viewer_pixels = Vec::new();
+    // Fast-forward runs while Tab is held
+    let fast_forward = std::mem::take(&mut self.fast_forward);
+    let fast_forward_name = fast_forward.speed.name();
+    let mut fast_forward_held = false;
+    // F11 cycles through display filters, and Shift + F11 through palettes
+    let mut filter = self.filter;
+    let save_dir = self.save_dir.take();
+    let size_policy = self.size_policy;
+
+    // The core runs on its own thread, and wakes the event loop with each
+    // frame it finishes. Ctrl + P shows or hides the performance HUD, and
+    // Ctrl + B starts profiling compiled blocks, then prints the report.
+    let proxy = event_loop.create_proxy();
+    let mut emulation = CoreThread::start(core, fast_forward, self.hud, move |frame| proxy.send_event(frame).is_ok());
+
+    event_loop.run(move |event, window_target, control_flow| {
+      *control_flow = ControlFlow::Wait;
 
       match event {
         Event::WindowEvent {
@@ -74,33 +128,331 @@ impl super::Shell for WindowShell {
           if window_id == window.id() {
             match e {
               WindowEvent::CloseRequested => {
-                // clean up?
+                // the event loop exits the process without dropping the core
+                if let Some(mut core) = emulation.stop() {
+                  core.shutdown();
+                  super::print_messages(&mut core);
+                }
 
                 *control_flow = ControlFlow::Exit;
               },
+              WindowEvent::Resized(size) => {
+                video_impl.resize(size);
+              },
+              WindowEvent::DroppedFile(path) => {
+                // a ROM dropped onto the window replaces the running game
+                let name = path.to_string_lossy().into_owned();
+                emulation.run(move |core| {
+                  let boot_rom = core.boot_rom().map(Box::from);
+                  let loaded = Core::open_rom_file(&name, size_policy, None, boot_rom)
+                    .map_err(String::from)
+                    .and_then(|next| {
+                      for warning in next.load_warnings.iter() {
+                        println!("{}", warning);
+                      }
+                      core.replace_game(next)
+                    });
+                  let message = match loaded {
+                    Ok(()) => "GAME LOADED",
+                    Err(msg) => {
+                      println!("{}", msg);
+                      "LOAD FAILED"
+                    },
+                  };
+                  Some((String::from(message), 120))
+                });
+              },
               WindowEvent::KeyboardInput { input, .. } => {
                 let pressed = input.state == ElementState::Pressed;
                 let is_ctrl = input.modifiers.ctrl();
+                let is_shift = input.modifiers.shift();
+                // a fullscreen window can't change size
+                let is_windowed = window.fullscreen().is_none();
                 match input.virtual_keycode {
                   Some(VirtualKeyCode::Equals) => {
-                    if is_ctrl && pressed {
+                    if is_ctrl && pressed && is_windowed {
                       let new_size = video_impl.increase_scale();
                       window.set_inner_size(new_size);
                     }
                   },
                   Some(VirtualKeyCode::Minus) => {
-                    if is_ctrl && pressed {
+                    if is_ctrl && pressed && is_windowed {
                       let new_size = video_impl.decrease_scale();
                       window.set_inner_size(new_size);
                     }
                   },
-                  Some(code) => {
-                    if let KeyboardInput::Joypad(b) = KeyboardInput::from_raw_input(code) {
-                      if pressed {
-                        core.memory.io.joypad.press_button(b);
+                  Some(VirtualKeyCode::Return) if input.modifiers.alt() => {
+                    if pressed {
+                      // the backend is resized by the event that follows
+                      let fullscreen = if is_windowed {
+                        Some(Fullscreen::Borderless(None))
                       } else {
-                        core.memory.io.joypad.release_button(b);
+                        None
+                      };
+                      window.set_fullscreen(fullscreen);
+                    }
+                  },
+                  Some(VirtualKeyCode::R) if is_ctrl => {
+                    if pressed {
+                      emulation.run(|core| {
+                        let message = match core.reset() {
+                          Ok(()) => "RESET",
+                          Err(msg) => {
+                            println!("{}", msg);
+                            "RESET FAILED"
+                          },
+                        };
+                        Some((String::from(message), 60))
+                      });
+                    }
+                  },
+                  Some(VirtualKeyCode::P) if is_ctrl => {
+                    if pressed {
+                      emulation.send(Command::ToggleHud);
+                    }
+                  },
+                  Some(VirtualKeyCode::B) if is_ctrl => {
+                    if pressed {
+                      emulation.run(|core| Some((String::from(toggle_block_profile(core)), 60)));
+                    }
+                  },
+                  Some(VirtualKeyCode::P) => {
+                    if pressed {
+                      emulation.run(|core| {
+                        let paused = !core.is_paused();
+                        let message = match core.set_paused(paused) {
+                          Ok(()) if paused => "PAUSED",
+                          Ok(()) => "RESUMED",
+                          Err(msg) => {
+                            println!("{}", msg);
+                            "PAUSE FAILED"
+                          },
+                        };
+                        Some((String::from(message), 60))
+                      });
+                    }
+                  },
+                  Some(VirtualKeyCode::N) => {
+                    // advancing a running game pauses it first
+                    if pressed {
+                      emulation.run(|core| {
+                        if let Err(msg) = core.set_paused(true) {
+                          println!("{}", msg);
+                          return Some((String::from("PAUSE FAILED"), 60));
+                        }
+                        if let Some(event) = core.advance_frame() {
+                          println!("{}", event);
+                        }
+                        None
+                      });
+                    }
+                  },
+                  Some(VirtualKeyCode::Tab) => {
+                    // held keys repeat, but the core only needs to hear about
+                    // the first press
+                    if pressed != fast_forward_held {
+                      if pressed {
+                        osd_message = Some((format!("FAST FORWARD {}", fast_forward_name), 60));
                       }
+                      emulation.send(Command::FastForward(pressed));
+                    }
+                    fast_forward_held = pressed;
+                  },
+                  Some(VirtualKeyCode::Back) => {
+                    if pressed {
+                      emulation.run(|core| {
+                        let message = match core.rewind(REWIND_FRAMES) {
+                          Ok(frames) => format!("REWIND {:.1}S", frames as f32 / 60.0),
+                          Err(msg) => {
+                            println!("{}", msg);
+                            String::from("NOTHING TO REWIND")
+                          },
+                        };
+                        Some((message, 60))
+                      });
+                    }
+                  },
+                  Some(VirtualKeyCode::F8) if is_ctrl => {
+                    if pressed {
+                      let save_dir = save_dir.clone();
+                      emulation.run(move |core| {
+                        let message = match viewer::save_views(core, save_dir.as_deref()) {
+                          Ok(names) => {
+                            for name in names {
+                              println!("Saved {}", name);
+                            }
+                            "VRAM SAVED"
+                          },
+                          Err(msg) => {
+                            println!("{}", msg);
+                            "VRAM SAVE FAILED"
+                          },
+                        };
+                        Some((String::from(message), 120))
+                      });
+                    }
+                  },
+                  Some(VirtualKeyCode::F8) if is_shift => {
+                    if let (true, Some((preview, _))) = (pressed, viewer_window.as_ref()) {
+                      vram_view = vram_view.next();
+                      preview.set_title(&viewer_title(vram_view));
+                      emulation.send(Command::ShowView(Some(vram_view)));
+                    }
+                  },
+                  Some(VirtualKeyCode::F8) => {
+                    if pressed {
+                      viewer_window = match viewer_window.take() {
+                        Some(_) => None,
+                        None => {
+                          vram_view = VramView::Sprites;
+                          let preview = WindowBuilder::new()
+                            .with_title(viewer_title(vram_view))
+                            .with_inner_size(initial_size)
+                            .with_min_inner_size(viewport::size_for_scale(1))
+                            .build(window_target)
+                            .expect("Failed to initialize VRAM window");
+                          let preview_impl = create_video_impl(&preview);
+                          Some((preview, preview_impl))
+                        },
+                      };
+                      let view = viewer_window.as_ref().map(|_| vram_view);
+                      emulation.send(Command::ShowView(view));
+                    }
+                  },
+                  Some(VirtualKeyCode::F9) => {
+                    if pressed {
+                      emulation.run(|core| {
+                        let message = if core.recorder.is_some() {
+                          core.stop_recording();
+                          String::from("RECORDING STOPPED")
+                        } else {
+                          match start_recording() {
+                            Ok(recorder) => {
+                              core.recorder = Some(recorder);
+                              String::from("RECORDING")
+                            },
+                            Err(msg) => {
+                              println!("{}", msg);
+                              String::from("RECORDING FAILED")
+                            },
+                          }
+                        };
+                        Some((message, 120))
+                      });
+                    }
+                  },
+                  Some(VirtualKeyCode::F10) => {
+                    if pressed {
+                      emulation.run(|core| {
+                        let message = match core.play_session.as_ref() {
+                          Some(session) => {
+                            let stats = session.current(core.cycles_elapsed());
+                            format!("PLAYED {}, {} SESSIONS", stats.play_time(), stats.sessions)
+                          },
+                          None => String::from("NOT TRACKING PLAY TIME"),
+                        };
+                        Some((message, 180))
+                      });
+                    }
+                  },
+                  Some(VirtualKeyCode::F11) => {
+                    if pressed && is_shift {
+                      emulation.run(|core| {
+                        let video = &mut core.memory.io.video;
+                        let palette = video.get_palette().next_preset();
+                        video.set_palette(palette);
+                        let name = palette.name().unwrap_or_default().to_ascii_uppercase();
+                        Some((format!("PALETTE {}", name), 120))
+                      });
+                    } else if pressed {
+                      filter = filter.next();
+                      osd_message = Some((format!("FILTER {}", filter.name()), 120));
+                    }
+                  },
+                  Some(VirtualKeyCode::F12) => {
+                    if pressed {
+                      let save_dir = save_dir.clone();
+                      emulation.run(move |core| {
+                        let message = match image::save_screenshot(core, save_dir.as_deref()) {
+                          Ok(name) => {
+                            println!("Saved {}", name);
+                            "SCREENSHOT SAVED"
+                          },
+                          Err(msg) => {
+                            println!("{}", msg);
+                            "SCREENSHOT FAILED"
+                          },
+                        };
+                        Some((String::from(message), 120))
+                      });
+                    }
+                  },
+                  Some(code) => {
+                    match KeyboardInput::from_raw_input(code) {
+                      KeyboardInput::Joypad(b) => {
+                        if is_ctrl {
+                          // Ctrl + button toggles turbo for that button
+                          if pressed {
+                            emulation.run(move |core| {
+                              let turbo = match core.input.get_turbo(b) {
+                                Some(_) => None,
+                                None => Some(Turbo::default()),
+                              };
+                              let state = if turbo.is_some() { "ON" } else { "OFF" };
+                              core.input.set_turbo(b, turbo);
+                              Some((format!("TURBO {:?} {}", b, state), 120))
+                            });
+                          }
+                        } else if pressed {
+                          emulation.run(move |core| {
+                            core.input.press(b);
+                            None
+                          });
+                        } else {
+                          emulation.run(move |core| {
+                            core.input.release(b);
+                            None
+                          });
+                        }
+                      },
+                      KeyboardInput::Macro(slot) => {
+                        // Ctrl + number starts or stops recording a macro,
+                        // the number alone plays it back
+                        if pressed {
+                          emulation.run(move |core| {
+                            let message = if is_ctrl {
+                              match core.input.toggle_recording(slot) {
+                                Some(frames) => format!("MACRO {} SAVED ({} FRAMES)", slot + 1, frames),
+                                None => format!("RECORDING MACRO {}", slot + 1),
+                              }
+                            } else if core.input.play_macro(slot) {
+                              format!("PLAYING MACRO {}", slot + 1)
+                            } else {
+                              format!("MACRO {} IS EMPTY", slot + 1)
+                            };
+                            Some((message, 120))
+                          });
+                        }
+                      },
+                      KeyboardInput::Color(key) => {
+                        if pressed {
+                          color_adjustment.apply_key(key);
+                          color_table = color_adjustment.build_table();
+                          if let Some(path) = config_file.as_ref() {
+                            if let Err(msg) = config::save_config_values(path, &color_adjustment.config_values()) {
+                              println!("{}", msg);
+                            }
+                          }
+                          let message = format!(
+                            "BRI {:+.2} CON {:.1} GAM {:.1}",
+                            color_adjustment.brightness,
+                            color_adjustment.contrast,
+                            color_adjustment.gamma,
+                          );
+                          osd_message = Some((message, 120));
+                        }
+                      },
+                      KeyboardInput::Unknown => (),
                     }
                   },
                   _ => (),
@@ -108,29 +460,60 @@ impl super::Shell for WindowShell {
               },
               _ => (),
             }
+          } else if let Some((preview, preview_impl)) = viewer_window.as_mut() {
+            if preview.id() == window_id {
+              match e {
+                WindowEvent::CloseRequested => {
+                  viewer_window = None;
+                  emulation.send(Command::ShowView(None));
+                },
+                WindowEvent::Resized(size) => preview_impl.resize(size),
+                _ => (),
+              }
+            }
           }
         },
-        Event::MainEventsCleared => {
-          let now = SystemTime::now();
-          let mut elapsed = match now.duration_since(last_frame_time) {
-            Ok(n) => n.as_millis(),
-            Err(_) => 1,
-          };
-          last_frame_time = now;
-
-          if elapsed < 16 {
-            let diff = 16 - elapsed;
-            let sleep_time = Duration::from_millis(diff as u64);
-            std::thread::sleep(sleep_time);
-            elapsed += diff;
+        Event::UserEvent(frame) => {
+          if let Some(message) = frame.messages.into_iter().last() {
+            osd_message = Some(message);
           }
 
-          core.run_frame();
-
-          // get latest lcd data
-          let lcd_data = core.get_screen_buffer();
+          // apply any color adjustments to the latest lcd data
+          for (adjusted, shade) in adjusted_lcd.iter_mut().zip(frame.lcd.iter()) {
+            *adjusted = color_table[*shade as usize];
+          }
+          let osd_expired = match osd_message.as_mut() {
+            Some((message, frames)) => {
+              text::draw_text_box(&mut adjusted_lcd, 2, 2, message, 255, 0);
+              *frames -= 1;
+              *frames == 0
+            },
+            None => false,
+          };
+          if osd_expired {
+            osd_message = None;
+          }
+          if let Some(report) = frame.hud {
+            let (_, height) = text::measure_text(&report);
+            text::draw_text_box(&mut adjusted_lcd, 2, LCD_HEIGHT - height - 1, &report, 255, 0);
+          }
           // draw lcd data to screen
-          video_impl.draw_lcd(lcd_data);
+          let screen = match frame.sgb {
+            Some(sgb) => {
+              sgb.render(&adjusted_lcd, &mut sgb_pixels);
+              Screen::Rgb(&sgb_pixels, sgb.size().0)
+            },
+            None => Screen::Lcd(&adjusted_lcd, &frame.palette),
+          };
+          video_impl.draw_screen(screen, filter);
+
+          if let (Some((_, preview_impl)), Some(view)) = (viewer_window.as_mut(), frame.view) {
+            // views come in all sizes, so they are colored here
+            let colors = Filter::None.colors(&frame.palette);
+            viewer_pixels.clear();
+            viewer_pixels.extend(view.pixels.iter().map(|shade| colors[color_table[*shade as usize] as usize]));
+            preview_impl.draw_screen(Screen::Rgb(&viewer_pixels, view.width), Filter::None);
+          }
         },
         _ => (),
       }
@@ -139,14 +522,97 @@ impl super::Shell for WindowShell {
   }
 }
 
+/// Start profiling compiled blocks, or print what has been profiled so far
+/// and stop. Returns the message to show.
+fn toggle_block_profile(core: &mut Core) -> &'static str {
+  if !core.is_jit_enabled() {
+    return "PROFILING NEEDS THE JIT";
+  }
+  #[cfg(jit_backend)]
+  {
+    if !core.cache.is_profiling() {
+      core.cache.set_profiling(true);
+      return "PROFILING BLOCKS";
+    }
+    print!("{}", crate::debug::profile::profile_report(core, crate::debug::profile::DEFAULT_REPORT_BLOCKS));
+    core.cache.set_profiling(false);
+  }
+  "BLOCK PROFILE PRINTED"
+}
+
+fn viewer_title(view: VramView) -> String {
+  format!("{} - {}", WINDOW_TITLE, view.name())
+}
+
+/// Recordings started from the keyboard are saved as AVI files, which don't
+/// depend on any other programs, named after the time they were started
+fn start_recording() -> Result<VideoRecorder, String> {
+  let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+  let name = format!("recording-{}.avi", time.as_secs());
+  println!("Recording to {}", name);
+  VideoRecorder::start(&name)
+}
+
+#[cfg(feature = "softbuffer-video")]
+fn create_video_impl(window: &Window) -> Box<dyn VideoImpl> {
+  Box::new(soft::Video::new(window))
+}
+
+#[cfg(not(feature = "softbuffer-video"))]
+fn create_video_impl(window: &Window) -> Box<dyn VideoImpl> {
+  match window.raw_window_handle() {
+    #[cfg(windows)]
+    RawWindowHandle::Win32(handle) => {
+      Box::new(windows::Video::new(handle, window.inner_size()))
+    },
+    #[cfg(unix)]
+    RawWindowHandle::Xlib(window_handle) => {
+      let display_handle = match window.raw_display_handle() {
+        RawDisplayHandle::Xlib(raw_handle) => raw_handle,
+        _ => panic!("Display type does not match window type"),
+      };
+      Box::new(x11::Video::new(window_handle, display_handle, window.inner_size()))
+    },
+    #[cfg(unix)]
+    RawWindowHandle::Wayland(window_handle) => {
+      let display_handle = match window.raw_display_handle() {
+        RawDisplayHandle::Wayland(raw_handle) => raw_handle,
+        _ => panic!("Display type does not match window type"),
+      };
+      Box::new(wayland::Video::new(window_handle, display_handle, window.inner_size()))
+    },
+    _ => panic!("Unsupported platform"),
+  }
+}
+
 pub trait VideoImpl {
-  fn draw_lcd(&mut self, lcd_data: &[u8]);
-  fn increase_scale(&mut self) -> PhysicalSize<u32>;
-  fn decrease_scale(&mut self) -> PhysicalSize<u32>;
+  fn draw_screen(&mut self, screen: Screen, filter: Filter);
+  fn viewport(&self) -> Viewport;
+  /// Called whenever the window changes size, including when it enters or
+  /// leaves fullscreen
+  fn resize(&mut self, size: PhysicalSize<u32>);
+
+  /// Double the scale, returning the window size that fits it
+  fn increase_scale(&mut self) -> PhysicalSize<u32> {
+    let scale = (self.viewport().scale * 2).min(viewport::MAX_SCALE);
+    let size = viewport::size_for_scale(scale);
+    self.resize(size);
+    size
+  }
+
+  /// Halve the scale, returning the window size that fits it
+  fn decrease_scale(&mut self) -> PhysicalSize<u32> {
+    let scale = (self.viewport().scale / 2).max(viewport::MIN_SCALE);
+    let size = viewport::size_for_scale(scale);
+    self.resize(size);
+    size
+  }
 }
 
 pub enum KeyboardInput {
   Joypad(Button),
+  Macro(usize),
+  Color(AdjustmentKey),
   Unknown,
 }
 
@@ -162,6 +628,19 @@ impl KeyboardInput {
       VirtualKeyCode::Right => KeyboardInput::Joypad(Button::Right),
       VirtualKeyCode::Down => KeyboardInput::Joypad(Button::Down),
 
+      VirtualKeyCode::Key1 => KeyboardInput::Macro(0),
+      VirtualKeyCode::Key2 => KeyboardInput::Macro(1),
+      VirtualKeyCode::Key3 => KeyboardInput::Macro(2),
+      VirtualKeyCode::Key4 => KeyboardInput::Macro(3),
+
+      VirtualKeyCode::F1 => KeyboardInput::Color(AdjustmentKey::BrightnessDown),
+      VirtualKeyCode::F2 => KeyboardInput::Color(AdjustmentKey::BrightnessUp),
+      VirtualKeyCode::F3 => KeyboardInput::Color(AdjustmentKey::ContrastDown),
+      VirtualKeyCode::F4 => KeyboardInput::Color(AdjustmentKey::ContrastUp),
+      VirtualKeyCode::F5 => KeyboardInput::Color(AdjustmentKey::GammaDown),
+      VirtualKeyCode::F6 => KeyboardInput::Color(AdjustmentKey::GammaUp),
+      VirtualKeyCode::F7 => KeyboardInput::Color(AdjustmentKey::Reset),
+
       _ => KeyboardInput::Unknown,
     }
   }
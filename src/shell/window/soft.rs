@@ -0,0 +1,51 @@
+use crate::shell::filter::Filter;
+use softbuffer::GraphicsContext;
+use super::VideoImpl;
+use super::viewport::{Screen, Viewport};
+use winit::{dpi::PhysicalSize, window::Window};
+
+/// Portable video output through softbuffer, which picks the right way to
+/// blit pixels for whichever platform winit is running on
+pub struct Video {
+  viewport: Viewport,
+  context: GraphicsContext,
+  /// Contents of the whole window, with one 0RGB pixel per u32
+  pixels: Vec<u32>,
+}
+
+fn buffer_for_viewport(viewport: &Viewport) -> Vec<u32> {
+  vec![0; viewport.window_width * viewport.window_height]
+}
+
+impl Video {
+  pub fn new(window: &Window) -> Self {
+    let viewport = Viewport::new(window.inner_size());
+    // the window and this context are both owned by the event loop, which
+    // runs until the process exits
+    let context = unsafe { GraphicsContext::new(window, window) }
+      .expect("Failed to initialize softbuffer");
+    Self {
+      pixels: buffer_for_viewport(&viewport),
+      viewport,
+      context,
+    }
+  }
+}
+
+impl VideoImpl for Video {
+  fn draw_screen(&mut self, screen: Screen, filter: Filter) {
+    self.viewport.draw_scaled(screen, filter, &mut self.pixels);
+    let width = self.viewport.window_width as u16;
+    let height = self.viewport.window_height as u16;
+    self.context.set_buffer(&self.pixels, width, height);
+  }
+
+  fn viewport(&self) -> Viewport {
+    self.viewport
+  }
+
+  fn resize(&mut self, size: PhysicalSize<u32>) {
+    self.viewport = Viewport::new(size);
+    self.pixels = buffer_for_viewport(&self.viewport);
+  }
+}
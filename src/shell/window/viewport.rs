@@ -0,0 +1,160 @@
+use crate::devices::video::lcd::{LCD_HEIGHT, LCD_WIDTH};
+use crate::devices::video::palette::Palette;
+use crate::shell::filter::Filter;
+use std::borrow::Cow;
+use winit::dpi::PhysicalSize;
+
+pub const MIN_SCALE: usize = 1;
+pub const MAX_SCALE: usize = 8;
+
+/// The area of a window that the screen is drawn into. The screen is only
+/// ever scaled by a whole number, so every Game Boy pixel is the same size,
+/// and the rest of the window is filled with black bars.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Viewport {
+  pub window_width: usize,
+  pub window_height: usize,
+  pub scale: usize,
+  /// Offset of the top-left corner of the screen within the window
+  pub x: usize,
+  pub y: usize,
+}
+
+/// A frame to draw into the viewport
+#[derive(Clone, Copy)]
+pub enum Screen<'a> {
+  /// Shades of the LCD, colored with a palette
+  Lcd(&'a [u8], &'a Palette),
+  /// 0RGB pixels, with the width of each row, for a picture that is already
+  /// colored, and may be bigger than the LCD, like a Super Game Boy border
+  Rgb(&'a [u32], usize),
+}
+
+impl Screen<'_> {
+  pub fn size(&self) -> (usize, usize) {
+    match self {
+      Screen::Lcd(..) => (LCD_WIDTH, LCD_HEIGHT),
+      Screen::Rgb(pixels, width) => (*width, pixels.len() / width),
+    }
+  }
+}
+
+impl Viewport {
+  /// Fit the largest whole-number scale into a window, centered
+  pub fn new(size: PhysicalSize<u32>) -> Self {
+    Self::for_screen(size, LCD_WIDTH, LCD_HEIGHT)
+  }
+
+  /// Fit a screen of any size, for pictures bigger than the LCD
+  pub fn for_screen(size: PhysicalSize<u32>, screen_width: usize, screen_height: usize) -> Self {
+    // minimized windows can report a size of zero
+    let window_width = (size.width as usize).max(1);
+    let window_height = (size.height as usize).max(1);
+    let scale = (window_width / screen_width)
+      .min(window_height / screen_height)
+      .max(MIN_SCALE);
+    Self {
+      window_width,
+      window_height,
+      scale,
+      x: window_width.saturating_sub(screen_width * scale) / 2,
+      y: window_height.saturating_sub(screen_height * scale) / 2,
+    }
+  }
+
+  /// Scale the screen into a buffer the size of the window through a filter,
+  /// with one 0RGB pixel per u32. Anything outside of the screen is drawn
+  /// black, and a window smaller than the screen cuts off its right and
+  /// bottom edges. Screens bigger than the LCD get a scale of their own.
+  pub fn draw_scaled(&self, screen: Screen, filter: Filter, output: &mut [u32]) {
+    let (screen_width, screen_height) = screen.size();
+    let window_size = PhysicalSize::new(self.window_width as u32, self.window_height as u32);
+    let layout = Self::for_screen(window_size, screen_width, screen_height);
+    let pixels: Cow<[u32]> = match screen {
+      Screen::Lcd(shades, palette) => {
+        let colors = filter.colors(palette);
+        Cow::Owned(shades.iter().map(|shade| colors[*shade as usize]).collect())
+      },
+      Screen::Rgb(pixels, _) => Cow::Borrowed(pixels),
+    };
+    let width = self.window_width;
+    let scale = layout.scale;
+    for (y, row) in output.chunks_exact_mut(width).take(self.window_height).enumerate() {
+      let offset_y = match y.checked_sub(layout.y) {
+        Some(offset) if offset < screen_height * scale => offset,
+        _ => {
+          row.fill(0);
+          continue;
+        },
+      };
+      let src_y = offset_y / scale;
+      let sub_y = offset_y % scale;
+      let src_row = &pixels[src_y * screen_width..(src_y + 1) * screen_width];
+      let screen_end = (layout.x + screen_width * scale).min(width);
+      row[..layout.x].fill(0);
+      for (x, pixel) in row[layout.x..screen_end].iter_mut().enumerate() {
+        let color = src_row[x / scale];
+        *pixel = if filter.is_dimmed(x % scale, sub_y, scale) {
+          filter.dim(color)
+        } else {
+          color
+        };
+      }
+      row[screen_end..].fill(0);
+    }
+  }
+}
+
+pub fn size_for_scale(scale: usize) -> PhysicalSize<u32> {
+  PhysicalSize::new((LCD_WIDTH * scale) as u32, (LCD_HEIGHT * scale) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{size_for_scale, Filter, Palette, Screen, Viewport};
+  use crate::devices::video::lcd::{LCD_HEIGHT, LCD_WIDTH};
+  use winit::dpi::PhysicalSize;
+
+  #[test]
+  fn letterboxing() {
+    assert_eq!(Viewport::new(size_for_scale(3)), Viewport {
+      window_width: 480,
+      window_height: 432,
+      scale: 3,
+      x: 0,
+      y: 0,
+    });
+    // a 1920x1080 fullscreen window fits a 7x screen, with bars on all sides
+    let fullscreen = Viewport::new(PhysicalSize::new(1920, 1080));
+    assert_eq!((fullscreen.scale, fullscreen.x, fullscreen.y), (7, 400, 36));
+    // windows smaller than the screen still draw at 1x
+    let tiny = Viewport::new(PhysicalSize::new(100, 200));
+    assert_eq!((tiny.scale, tiny.x, tiny.y), (1, 0, 28));
+    assert_eq!(size_for_scale(2), PhysicalSize::new(320, 288));
+  }
+
+  #[test]
+  fn drawing() {
+    let mut lcd = vec![0u8; LCD_WIDTH * LCD_HEIGHT];
+    lcd[0] = 0x12;
+    lcd[LCD_WIDTH * LCD_HEIGHT - 1] = 0x34;
+    let viewport = Viewport::new(PhysicalSize::new(324, 290));
+    let mut output = vec![0xffffffff; 324 * 290];
+    viewport.draw_scaled(Screen::Lcd(&lcd, &Palette::grayscale()), Filter::None, &mut output);
+    // the screen is offset by two pixels horizontally and one vertically
+    assert_eq!(&output[..2], &[0, 0]);
+    assert_eq!(&output[324..328], &[0, 0, 0x121212, 0x121212]);
+    assert_eq!(&output[324 * 2 + 2..324 * 2 + 4], &[0x121212, 0x121212]);
+    assert_eq!(&output[324 * 288 + 320..324 * 288 + 324], &[0x343434, 0x343434, 0, 0]);
+    assert!(output[324 * 289..].iter().all(|pixel| *pixel == 0));
+
+    // a bordered picture is fit into the same window at a scale of its own
+    let mut pixels = vec![0x123456; 256 * 224];
+    pixels[256 * 224 - 1] = 0xabcdef;
+    viewport.draw_scaled(Screen::Rgb(&pixels, 256), Filter::Scanlines, &mut output);
+    assert!(output[..324 * 33 + 34].iter().all(|pixel| *pixel == 0));
+    assert_eq!(output[324 * 33 + 34], 0x123456);
+    assert_eq!(output[324 * 256 + 289], 0xabcdef);
+    assert_eq!(output[324 * 257], 0);
+  }
+}
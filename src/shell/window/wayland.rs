@@ -0,0 +1,221 @@
+use crate::shell::filter::Filter;
+use raw_window_handle::{WaylandDisplayHandle, WaylandWindowHandle};
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::rc::Rc;
+use super::VideoImpl;
+use super::viewport::{Screen, Viewport};
+use wayland_client::{
+  protocol::{wl_buffer, wl_shm, wl_shm_pool, wl_surface},
+  sys::client::{wl_display, wl_proxy},
+  Display,
+  EventQueue,
+  GlobalManager,
+  Main,
+  Proxy,
+};
+use winit::dpi::PhysicalSize;
+
+/// Frames are drawn into one buffer while the compositor may still be
+/// reading the other
+const BUFFER_COUNT: usize = 2;
+
+/// A region of the shared memory pool that the compositor can display
+struct ShmBuffer {
+  buffer: Main<wl_buffer::WlBuffer>,
+  offset: usize,
+  /// Set from the time the buffer is attached until the compositor releases
+  /// it, during which it must not be written
+  busy: Rc<Cell<bool>>,
+}
+
+/// Draws into a Wayland surface through shared memory buffers (wl_shm). The
+/// surface and connection belong to winit, which also reads events from the
+/// compositor; this only sends requests, on an event queue of its own.
+pub struct Video {
+  viewport: Viewport,
+
+  display: Display,
+  event_queue: EventQueue,
+  shm: Main<wl_shm::WlShm>,
+  surface: wl_surface::WlSurface,
+
+  pool: Main<wl_shm_pool::WlShmPool>,
+  buffers: Vec<ShmBuffer>,
+  /// Backs the pool. The file itself has already been unlinked.
+  _file: File,
+  memory: *mut u8,
+  memory_size: usize,
+}
+
+fn buffer_size_for_viewport(viewport: &Viewport) -> usize {
+  let bytes_per_pixel = 4;
+  viewport.window_width * viewport.window_height * bytes_per_pixel
+}
+
+/// Create an anonymous file for sharing memory with the compositor, in the
+/// runtime directory when there is one
+fn create_shm_file(size: usize) -> std::io::Result<File> {
+  let dir = std::env::var_os("XDG_RUNTIME_DIR")
+    .map(PathBuf::from)
+    .unwrap_or_else(std::env::temp_dir);
+  let path = dir.join(format!("gb-dynarec-shm-{}", std::process::id()));
+  let file = OpenOptions::new()
+    .read(true)
+    .write(true)
+    .create(true)
+    .truncate(true)
+    .open(&path)?;
+  std::fs::remove_file(&path)?;
+  file.set_len(size as u64)?;
+  Ok(file)
+}
+
+impl Video {
+  pub fn new(window_handle: WaylandWindowHandle, display_handle: WaylandDisplayHandle, size: PhysicalSize<u32>) -> Self {
+    let viewport = Viewport::new(size);
+
+    unsafe {
+      let display = Display::from_external_display(display_handle.display as *mut wl_display);
+      let mut event_queue = display.create_event_queue();
+      let attached_display = display.attach(event_queue.token());
+      let globals = GlobalManager::new(&attached_display);
+      event_queue
+        .sync_roundtrip(&mut (), |_, _, _| {})
+        .expect("Failed to read Wayland globals");
+      let shm = globals
+        .instantiate_exact::<wl_shm::WlShm>(1)
+        .expect("Wayland compositor does not support wl_shm");
+      let surface: wl_surface::WlSurface = Proxy::<wl_surface::WlSurface>::from_c_ptr(window_handle.surface as *mut wl_proxy).into();
+
+      let (file, memory, memory_size, pool, buffers) = Self::create_buffers(&shm, &viewport);
+
+      Self {
+        viewport,
+
+        display,
+        event_queue,
+        shm,
+        surface,
+
+        pool,
+        buffers,
+        _file: file,
+        memory,
+        memory_size,
+      }
+    }
+  }
+
+  fn create_buffers(shm: &Main<wl_shm::WlShm>, viewport: &Viewport) -> (File, *mut u8, usize, Main<wl_shm_pool::WlShmPool>, Vec<ShmBuffer>) {
+    let width = viewport.window_width as i32;
+    let height = viewport.window_height as i32;
+    let buffer_size = buffer_size_for_viewport(viewport);
+    let memory_size = buffer_size * BUFFER_COUNT;
+
+    let file = create_shm_file(memory_size).expect("Failed to create shared memory for Wayland");
+    let memory = unsafe {
+      libc::mmap(
+        std::ptr::null_mut(),
+        memory_size,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_SHARED,
+        file.as_raw_fd(),
+        0,
+      )
+    };
+    if memory == libc::MAP_FAILED {
+      panic!("Failed to map shared memory for Wayland");
+    }
+
+    let pool = shm.create_pool(file.as_raw_fd(), memory_size as i32);
+    let mut buffers = Vec::with_capacity(BUFFER_COUNT);
+    for index in 0..BUFFER_COUNT {
+      let offset = index * buffer_size;
+      let buffer = pool.create_buffer(offset as i32, width, height, width * 4, wl_shm::Format::Xrgb8888);
+      let busy = Rc::new(Cell::new(false));
+      let released = busy.clone();
+      buffer.quick_assign(move |_, event, _| {
+        if let wl_buffer::Event::Release = event {
+          released.set(false);
+        }
+      });
+      buffers.push(ShmBuffer {
+        buffer,
+        offset,
+        busy,
+      });
+    }
+    (file, memory as *mut u8, memory_size, pool, buffers)
+  }
+
+  fn destroy_buffers(&mut self) {
+    for buffer in self.buffers.drain(..) {
+      buffer.buffer.destroy();
+    }
+    self.pool.destroy();
+    unsafe {
+      libc::munmap(self.memory as *mut libc::c_void, self.memory_size);
+    }
+  }
+
+  fn set_viewport(&mut self, viewport: Viewport) {
+    if self.viewport == viewport {
+      return;
+    }
+    self.destroy_buffers();
+    let (file, memory, memory_size, pool, buffers) = Self::create_buffers(&self.shm, &viewport);
+    self._file = file;
+    self.memory = memory;
+    self.memory_size = memory_size;
+    self.pool = pool;
+    self.buffers = buffers;
+    self.viewport = viewport;
+  }
+}
+
+impl Drop for Video {
+  fn drop(&mut self) {
+    self.destroy_buffers();
+  }
+}
+
+impl VideoImpl for Video {
+  fn draw_screen(&mut self, screen: Screen, filter: Filter) {
+    // pick up release events, which winit has already read from the socket
+    if self.event_queue.dispatch_pending(&mut (), |_, _, _| {}).is_err() {
+      return;
+    }
+    let buffer = match self.buffers.iter().find(|buffer| !buffer.busy.get()) {
+      Some(buffer) => buffer,
+      // the compositor is behind, so this frame is dropped
+      None => return,
+    };
+
+    let width = self.viewport.window_width;
+    let height = self.viewport.window_height;
+    // XRGB8888 is a little-endian u32, which matches the 0RGB pixels of the
+    // viewport. Buffers start at multiples of their size within a mapping
+    // that is page-aligned, so they are always aligned for u32.
+    let bitmap_data = unsafe {
+      std::slice::from_raw_parts_mut(self.memory.add(buffer.offset) as *mut u32, width * height)
+    };
+    self.viewport.draw_scaled(screen, filter, bitmap_data);
+
+    buffer.busy.set(true);
+    self.surface.attach(Some(&buffer.buffer), 0, 0);
+    self.surface.damage(0, 0, width as i32, height as i32);
+    self.surface.commit();
+    let _ = self.display.flush();
+  }
+
+  fn viewport(&self) -> Viewport {
+    self.viewport
+  }
+
+  fn resize(&mut self, size: PhysicalSize<u32>) {
+    self.set_viewport(Viewport::new(size));
+  }
+}
@@ -10,116 +10,113 @@ use crate::bindings::{
     BitBlt,
     CreateCompatibleDC,
     CreateDIBSection,
+    DeleteObject,
     GetDC,
     ReleaseDC,
     SelectObject,
   },
 };
+use crate::shell::filter::Filter;
 use raw_window_handle::Win32WindowHandle;
 use super::VideoImpl;
+use super::viewport::{Screen, Viewport};
 use winit::{
   dpi::PhysicalSize,
 };
 
 pub struct Video {
-  scale: usize,
+  viewport: Viewport,
   hwnd: HWND,
   bitmap: HBITMAP,
-  bitmap_raw_ptr: *mut u8,
-  bitmap_length: usize,
+  bitmap_raw_ptr: *mut u32,
 }
 
-impl Video {
-  pub fn new(window_handle: Win32WindowHandle) -> Self {
-    let scale = super::INITIAL_SCALE;
-
-    let row_size = ((160 * scale * 24 + 31) / 32) * 4;
-    let image_size = row_size * 144 * scale;
+/// Create a bitmap covering the whole window. Its 32-bit pixels are stored
+/// as B, G, R, unused, matching the 0RGB pixels of the viewport.
+fn create_bitmap(viewport: &Viewport) -> (HBITMAP, *mut u32) {
+  unsafe {
+    use std::ffi::c_void;
 
-    let hwnd = HWND(window_handle.hwnd as isize);
-    let (bitmap, bitmap_raw_ptr) = unsafe {
-      use std::ffi::c_void;
+    let mut info: BITMAPINFO = std::mem::zeroed();
+    info.bmiHeader.biSize = std::mem::size_of::<BITMAPINFOHEADER>() as u32;
+    info.bmiHeader.biWidth = viewport.window_width as i32;
+    info.bmiHeader.biHeight = -(viewport.window_height as i32);
+    info.bmiHeader.biPlanes = 1;
+    info.bmiHeader.biBitCount = 32;
+    info.bmiHeader.biCompression = 0;
 
-      let mut info: BITMAPINFO = std::mem::zeroed();
-      info.bmiHeader.biSize = std::mem::size_of::<BITMAPINFOHEADER>() as u32;
-      info.bmiHeader.biWidth = 160 * scale as i32;
-      info.bmiHeader.biHeight = -144 * scale as i32;
-      info.bmiHeader.biPlanes = 1;
-      info.bmiHeader.biBitCount = 24;
-      info.bmiHeader.biCompression = 0;
+    let mut bits_ptr: *mut c_void = std::ptr::null_mut();
 
-      let mut bits_ptr: *mut c_void = std::ptr::null_mut();
+    let bitmap: HBITMAP = CreateDIBSection(
+      HDC::NULL,
+      &info as *const BITMAPINFO,
+      DIB_RGB_COLORS,
+      &mut bits_ptr as *mut *mut c_void,
+      HANDLE::NULL,
+      0,
+    );
 
-      let bitmap: HBITMAP = CreateDIBSection(
-        HDC::NULL,
-        &info as *const BITMAPINFO,
-        DIB_RGB_COLORS,
-        &mut bits_ptr as *mut *mut c_void,
-        HANDLE::NULL,
-        0,
-      );
+    (bitmap, bits_ptr as *mut u32)
+  }
+}
 
-      (bitmap, bits_ptr as *mut u8)
-    };
+impl Video {
+  pub fn new(window_handle: Win32WindowHandle, size: PhysicalSize<u32>) -> Self {
+    let viewport = Viewport::new(size);
+    let hwnd = HWND(window_handle.hwnd as isize);
+    let (bitmap, bitmap_raw_ptr) = create_bitmap(&viewport);
 
     Self {
-      scale,
+      viewport,
       hwnd,
       bitmap,
       bitmap_raw_ptr,
-      bitmap_length: image_size,
+    }
+  }
+}
+
+impl Drop for Video {
+  fn drop(&mut self) {
+    unsafe {
+      DeleteObject(self.bitmap);
     }
   }
 }
 
 impl VideoImpl for Video {
-  fn draw_lcd(&mut self, lcd_data: &[u8]) {
-    let scale = self.scale;
+  fn draw_screen(&mut self, screen: Screen, filter: Filter) {
+    let width = self.viewport.window_width;
+    let height = self.viewport.window_height;
 
     unsafe {
-      let bitmap_memory: &mut [u8] = std::slice::from_raw_parts_mut(self.bitmap_raw_ptr, self.bitmap_length);
-      // Copy LCD data to bitmap
-      let width = 160 * scale;
-      let row_size = ((width * 24 + 31) / 32) * 4;
-
-      for x in 0..(160 * scale) {
-        for y in 0..(144 * scale) {
-          let src_x = x / scale;
-          let src_y = y / scale;
-          let src_index = src_y * 160 + src_x;
-          let pixel = lcd_data[src_index];
-          let offset = y * row_size + x * 3;
-          bitmap_memory[offset + 0] = pixel; // B
-          bitmap_memory[offset + 1] = pixel; // R
-          bitmap_memory[offset + 2] = pixel; // G
-        }
-      }
+      let bitmap_memory: &mut [u32] = std::slice::from_raw_parts_mut(self.bitmap_raw_ptr, width * height);
+      self.viewport.draw_scaled(screen, filter, bitmap_memory);
 
       // draw bitmap to screen
       let hdc = GetDC(self.hwnd);
       let dc_mem = CreateCompatibleDC(hdc);
       let old_bitmap = SelectObject(dc_mem, self.bitmap);
-      BitBlt(hdc, 0, 0, 160 * scale as i32, 144 * scale as i32, dc_mem, 0, 0, SRCCOPY);
+      BitBlt(hdc, 0, 0, width as i32, height as i32, dc_mem, 0, 0, SRCCOPY);
       SelectObject(dc_mem, old_bitmap);
       ReleaseDC(self.hwnd, hdc);
     }
   }
 
-  fn increase_scale(&mut self) -> PhysicalSize<u32> {
-    if self.scale >= 8 {
-      return PhysicalSize::new(160 * self.scale as u32, 144 * self.scale as u32);
-    }
-    let new_scale = self.scale * 2;
-    //self.set_scale(new_scale);
-    PhysicalSize::new(160 * new_scale as u32, 144 * new_scale as u32)
+  fn viewport(&self) -> Viewport {
+    self.viewport
   }
 
-  fn decrease_scale(&mut self) -> PhysicalSize<u32> {
-    if self.scale <= 1 {
-      return PhysicalSize::new(160 * self.scale as u32, 144 * self.scale as u32);
+  fn resize(&mut self, size: PhysicalSize<u32>) {
+    let viewport = Viewport::new(size);
+    if viewport == self.viewport {
+      return;
+    }
+    let (bitmap, bitmap_raw_ptr) = create_bitmap(&viewport);
+    unsafe {
+      DeleteObject(self.bitmap);
     }
-    let new_scale = self.scale / 2;
-    //self.set_scale(new_scale);
-    PhysicalSize::new(160 * new_scale as u32, 144 * new_scale as u32)
+    self.viewport = viewport;
+    self.bitmap = bitmap;
+    self.bitmap_raw_ptr = bitmap_raw_ptr;
   }
 }
@@ -1,10 +1,13 @@
+use crate::shell::filter::Filter;
 use raw_window_handle::{XlibDisplayHandle, XlibWindowHandle};
 use super::VideoImpl;
+use super::viewport::{Screen, Viewport};
 use winit::dpi::PhysicalSize;
 
 pub struct Video {
-  scale: usize,
-  video_buffer: Box<[u8]>,
+  viewport: Viewport,
+  /// Contents of the whole window, as 32-bit pixels
+  video_buffer: Vec<u32>,
 
   xlib: x11_dl::xlib::Xlib,
   display: *mut x11_dl::xlib::Display,
@@ -14,20 +17,14 @@ pub struct Video {
   graphics_context: x11_dl::xlib::GC,
 }
 
-fn buffer_for_scale(scale: usize) -> Box<[u8]> {
-  let bytes_per_pixel = 4;
-  let size = (160 * scale) * (144 * scale) * bytes_per_pixel;
-  let mut buffer = Vec::<u8>::with_capacity(size);
-  for _ in 0..size {
-    buffer.push(0);
-  }
-  buffer.into_boxed_slice()
+fn buffer_for_viewport(viewport: &Viewport) -> Vec<u32> {
+  vec![0; viewport.window_width * viewport.window_height]
 }
 
 impl Video {
-  pub fn new(window_handle: XlibWindowHandle, display_handle: XlibDisplayHandle) -> Self {
-    let scale = super::INITIAL_SCALE;
-    let video_buffer = buffer_for_scale(scale);
+  pub fn new(window_handle: XlibWindowHandle, display_handle: XlibDisplayHandle, size: PhysicalSize<u32>) -> Self {
+    let viewport = Viewport::new(size);
+    let video_buffer = buffer_for_viewport(&viewport);
 
     unsafe {
       let xlib = x11_dl::xlib::Xlib::open().expect("Failed to open xlib");
@@ -38,7 +35,7 @@ impl Video {
       let graphics_context = (xlib.XDefaultGC)(display, screen);
 
       Self {
-        scale,
+        viewport,
         video_buffer,
 
         xlib,
@@ -50,59 +47,15 @@ impl Video {
       }
     }
   }
-
-  pub fn get_width(&self) -> f64 {
-    self.scale as f64 * 160.0
-  }
-
-  pub fn get_height(&self) -> f64 {
-    self.scale as f64 * 144.0
-  }
-
-  pub fn set_scale(&mut self, scale: usize) {
-    if self.scale != scale {
-      unsafe {
-        //(self.xlib.XClearWindow)(self.display, self.drawable);
-        (self.xlib.XFillRectangle)(
-          self.display,
-          self.drawable,
-          self.graphics_context,
-          0, 0,
-          160 * self.scale as u32,
-          144 * self.scale as u32,
-        );
-      }
-    }
-    self.scale = scale;
-    let new_buffer = buffer_for_scale(scale);
-    let old_buffer = std::mem::replace(&mut self.video_buffer, new_buffer);
-    std::mem::forget(old_buffer);
-  }
 }
 
 impl VideoImpl for Video {
-  fn draw_lcd(&mut self, lcd_data: &[u8]) {
-    // convert lcd_data into RGBA values for the local image buffer
-    let scale = self.scale;
-    let width = 160 * scale;
-    let height = 144 * scale;
-    let row_size = width * 4;
-
-    let bitmap_data = &mut self.video_buffer;
-
-    for x in 0..width {
-      for y in 0..height {
-        let src_x = x / scale;
-        let src_y = y / scale;
-        let src_index = src_y * 160 + src_x;
-        let pixel = lcd_data[src_index];
-        let offset = y * row_size + x * 4;
-        bitmap_data[offset + 0] = pixel; // R
-        bitmap_data[offset + 1] = pixel; // G
-        bitmap_data[offset + 2] = pixel; // B
-        bitmap_data[offset + 3] = 255;   // A
-      }
-    }
+  fn draw_screen(&mut self, screen: Screen, filter: Filter) {
+    // pixels are 0RGB, which a 24-bit ZPixmap on a little-endian machine
+    // stores as B, G, R, unused
+    self.viewport.draw_scaled(screen, filter, &mut self.video_buffer);
+    let width = self.viewport.window_width;
+    let height = self.viewport.window_height;
 
     unsafe {
       let image = (self.xlib.XCreateImage)(
@@ -139,22 +92,13 @@ impl VideoImpl for Video {
     }
   }
 
-  fn increase_scale(&mut self) -> PhysicalSize<u32> {
-    if self.scale >= 8 {
-      return PhysicalSize::new(160 * self.scale as u32, 144 * self.scale as u32);
-    }
-    let new_scale = self.scale * 2;
-    self.set_scale(new_scale);
-    PhysicalSize::new(160 * new_scale as u32, 144 * new_scale as u32)
+  fn viewport(&self) -> Viewport {
+    self.viewport
   }
 
-  fn decrease_scale(&mut self) -> PhysicalSize<u32> {
-    if self.scale <= 1 {
-      return PhysicalSize::new(160 * self.scale as u32, 144 * self.scale as u32);
-    }
-    let new_scale = self.scale / 2;
-    self.set_scale(new_scale);
-    PhysicalSize::new(160 * new_scale as u32, 144 * new_scale as u32)
+  fn resize(&mut self, size: PhysicalSize<u32>) {
+    self.viewport = Viewport::new(size);
+    self.video_buffer = buffer_for_viewport(&self.viewport);
   }
 }
 
@@ -204,7 +148,10 @@ impl Shell for WindowShell {
           }
         },
         Event::MainEventsCleared => {
-          core.run_frame();
+          if let Some(event) = core.run_frame() {
+            println!("{}", event);
+          }
+          crate::shell::print_messages(&mut core);
 
           let lcd_data = core.get_screen_buffer();
           // draw lcd data
@@ -0,0 +1,258 @@
+//! Play time and other statistics for each game, kept in a small text
+//! database in the config directory. Games are identified by the CRC32 of
+//! their ROM, so a patched copy is tracked separately from the original.
+
+use crate::patch::crc32;
+use crate::system::{self, config_path, save_config_file};
+use crate::timing::FRAME_CYCLES;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const STATS_FILE: &str = "stats.db";
+const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GameStats {
+  pub title: String,
+  pub sessions: u64,
+  /// Frames of emulated time, which differs from play time while running
+  /// faster or slower than real hardware
+  pub frames: u64,
+  pub play_seconds: u64,
+  /// Seconds since the Unix epoch
+  pub last_played: u64,
+}
+
+impl GameStats {
+  /// Play time as hours and minutes, like "12H 05M"
+  pub fn play_time(&self) -> String {
+    let minutes = self.play_seconds / 60;
+    format!("{}H {:02}M", minutes / 60, minutes % 60)
+  }
+
+  /// The day this game was last played, as YYYY-MM-DD
+  pub fn last_played_date(&self) -> String {
+    // converts days since the epoch to a proleptic Gregorian date
+    let days = (self.last_played / SECONDS_PER_DAY) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+  }
+
+  fn add(&mut self, session: &GameStats) {
+    self.title = session.title.clone();
+    self.sessions += session.sessions;
+    self.frames += session.frames;
+    self.play_seconds += session.play_seconds;
+    self.last_played = self.last_played.max(session.last_played);
+  }
+}
+
+pub struct StatsDatabase {
+  games: BTreeMap<u32, GameStats>,
+}
+
+impl StatsDatabase {
+  pub fn new() -> Self {
+    Self {
+      games: BTreeMap::new(),
+    }
+  }
+
+  /// Read the database from the config directory, or start an empty one
+  pub fn load() -> Self {
+    config_path(STATS_FILE)
+      .and_then(|path| std::fs::read_to_string(path).ok())
+      .map(|text| Self::deserialize(&text))
+      .unwrap_or_default()
+  }
+
+  pub fn save(&self) -> Result<(), String> {
+    save_config_file(STATS_FILE, &self.serialize())
+  }
+
+  /// One game per line: the ROM hash, sessions, frames, seconds played, the
+  /// time it was last played, and its title
+  pub fn serialize(&self) -> String {
+    let mut text = String::new();
+    for (hash, game) in self.games.iter() {
+      text.push_str(&format!(
+        "{:08x}\t{}\t{}\t{}\t{}\t{}\n",
+        hash,
+        game.sessions,
+        game.frames,
+        game.play_seconds,
+        game.last_played,
+        game.title,
+      ));
+    }
+    text
+  }
+
+  /// Parse a database written by serialize, skipping malformed lines
+  pub fn deserialize(text: &str) -> Self {
+    let mut database = Self::new();
+    for line in text.lines() {
+      let fields: Vec<&str> = line.splitn(6, '\t').collect();
+      if fields.len() != 6 {
+        continue;
+      }
+      let hash = u32::from_str_radix(fields[0], 16).ok();
+      let numbers: Vec<u64> = fields[1..5].iter().filter_map(|field| field.parse().ok()).collect();
+      if let (Some(hash), [sessions, frames, play_seconds, last_played]) = (hash, numbers.as_slice()) {
+        database.games.insert(hash, GameStats {
+          title: String::from(fields[5]),
+          sessions: *sessions,
+          frames: *frames,
+          play_seconds: *play_seconds,
+          last_played: *last_played,
+        });
+      }
+    }
+    database
+  }
+
+  pub fn get(&self, hash: u32) -> Option<&GameStats> {
+    self.games.get(&hash)
+  }
+
+  /// Every game, most recently played first
+  pub fn games_by_recent(&self) -> Vec<&GameStats> {
+    let mut games: Vec<&GameStats> = self.games.values().collect();
+    games.sort_by_key(|game| Reverse(game.last_played));
+    games
+  }
+
+  fn add_session(&mut self, hash: u32, session: &GameStats) {
+    self.games.entry(hash).or_default().add(session);
+  }
+}
+
+impl Default for StatsDatabase {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Tracks the game that is currently running, until it is saved to the
+/// database when the emulator exits
+pub struct PlaySession {
+  hash: u32,
+  title: String,
+  started: Instant,
+  start_cycles: u64,
+  /// Totals from earlier sessions, for showing while this one is running
+  previous: GameStats,
+}
+
+impl PlaySession {
+  pub fn start(rom: &[u8], cycles_elapsed: u64) -> Self {
+    let hash = crc32(rom);
+    let title = system::read_header_from_bytes(rom)
+      .map(|header| header.get_title())
+      .unwrap_or_default();
+    let previous = StatsDatabase::load().get(hash).cloned().unwrap_or_default();
+    Self {
+      hash,
+      title,
+      started: Instant::now(),
+      start_cycles: cycles_elapsed,
+      previous,
+    }
+  }
+
+  /// Just this session
+  fn session_stats(&self, cycles_elapsed: u64) -> GameStats {
+    GameStats {
+      title: self.title.clone(),
+      sessions: 1,
+      frames: cycles_elapsed.saturating_sub(self.start_cycles) / FRAME_CYCLES,
+      play_seconds: self.started.elapsed().as_secs(),
+      last_played: SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0),
+    }
+  }
+
+  /// Totals for this game, including the session so far
+  pub fn current(&self, cycles_elapsed: u64) -> GameStats {
+    let mut totals = self.previous.clone();
+    totals.add(&self.session_stats(cycles_elapsed));
+    totals
+  }
+
+  /// Add this session to the database. It's reloaded first, in case another
+  /// instance has saved to it in the meantime.
+  pub fn finish(self, cycles_elapsed: u64) -> Result<(), String> {
+    let mut database = StatsDatabase::load();
+    database.add_session(self.hash, &self.session_stats(cycles_elapsed));
+    database.save()
+  }
+}
+
+/// Print every game in the database, for `--stats`
+pub fn print_stats() {
+  let database = StatsDatabase::load();
+  let games = database.games_by_recent();
+  if games.is_empty() {
+    println!("No games played yet");
+    return;
+  }
+  println!("{:<16} {:>10} {:>9} {:>10}  Last played", "Title", "Play time", "Sessions", "Frames");
+  for game in games {
+    println!(
+      "{:<16} {:>10} {:>9} {:>10}  {}",
+      game.title,
+      game.play_time(),
+      game.sessions,
+      game.frames,
+      game.last_played_date(),
+    );
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{GameStats, StatsDatabase};
+
+  fn game(title: &str, play_seconds: u64, last_played: u64) -> GameStats {
+    GameStats {
+      title: String::from(title),
+      sessions: 1,
+      frames: play_seconds * 60,
+      play_seconds,
+      last_played,
+    }
+  }
+
+  #[test]
+  fn database_round_trip() {
+    let mut database = StatsDatabase::new();
+    database.add_session(0x1234abcd, &game("TETRIS", 90, 1_700_000_000));
+    database.add_session(0x1234abcd, &game("TETRIS", 30, 1_700_001_000));
+    database.add_session(0x42, &game("POKEMON RED", 3600, 1_600_000_000));
+    let text = database.serialize();
+    assert!(text.starts_with("00000042\t1\t216000\t3600\t1600000000\tPOKEMON RED\n"));
+
+    let restored = StatsDatabase::deserialize(&format!("{}garbage\n", text));
+    let tetris = restored.get(0x1234abcd).unwrap();
+    assert_eq!(tetris.sessions, 2);
+    assert_eq!(tetris.play_seconds, 120);
+    assert_eq!(tetris.last_played, 1_700_001_000);
+    let recent: Vec<&str> = restored.games_by_recent().iter().map(|game| game.title.as_str()).collect();
+    assert_eq!(recent, vec!["TETRIS", "POKEMON RED"]);
+  }
+
+  #[test]
+  fn formatting() {
+    let stats = game("TETRIS", 3 * 3600 + 5 * 60 + 59, 1_700_000_000);
+    assert_eq!(stats.play_time(), "3H 05M");
+    assert_eq!(stats.last_played_date(), "2023-11-14");
+    assert_eq!(game("", 0, 951_782_400).last_played_date(), "2000-02-29");
+  }
+}
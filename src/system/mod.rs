@@ -1,29 +1,80 @@
 #[cfg(unix)]
 pub mod linux;
+#[cfg(not(any(unix, windows)))]
+pub mod portable;
 #[cfg(windows)]
 pub mod windows;
 
 #[cfg(unix)]
-use linux::{map_rom_file, unmap_rom_file};
+type PlatformMapper = linux::Mmap;
+#[cfg(not(any(unix, windows)))]
+type PlatformMapper = portable::BufferedRead;
 #[cfg(windows)]
-use self::windows::{map_rom_file, unmap_rom_file};
+type PlatformMapper = self::windows::FileMapping;
 
 use crate::cart::Header;
+use crate::error::{Error, RomError};
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
 use std::mem;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::string::String;
 
-pub fn open_rom_file(name: String) -> Result<File, String> {
+/// Location of a file in the emulator's config directory, if the user has
+/// one
+#[cfg(unix)]
+pub fn config_path(file_name: &str) -> Option<PathBuf> {
+  let base = match std::env::var_os("XDG_CONFIG_HOME") {
+    Some(dir) => PathBuf::from(dir),
+    None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+  };
+  Some(base.join("gb-dynarec").join(file_name))
+}
+
+#[cfg(windows)]
+pub fn config_path(file_name: &str) -> Option<PathBuf> {
+  let base = PathBuf::from(std::env::var_os("APPDATA")?);
+  Some(base.join("gb-dynarec").join(file_name))
+}
+
+/// Targets like wasm32 have nowhere to keep settings between runs
+#[cfg(not(any(unix, windows)))]
+pub fn config_path(_file_name: &str) -> Option<PathBuf> {
+  None
+}
+
+/// Write a file to the config directory, creating the directory if needed
+pub fn save_config_file(file_name: &str, contents: &str) -> Result<(), String> {
+  let path = config_path(file_name).ok_or_else(|| String::from("No config directory available"))?;
+  if let Some(dir) = path.parent() {
+    let _ = std::fs::create_dir_all(dir);
+  }
+  std::fs::write(&path, contents).map_err(|_| format!("Unable to write \"{}\"", path.display()))
+}
+
+pub fn open_rom_file(name: String) -> Result<File, Error> {
+  let path = Path::new(&name);
+  File::open(path).map_err(|_| unreadable("Unable to open file"))
+}
+
+fn unreadable(message: &str) -> Error {
+  Error::Rom(RomError::Unreadable(String::from(message)))
+}
+
+/// Read a boot ROM image: 256 bytes for DMG, or 2304 bytes for CGB
+pub fn load_boot_rom(name: String) -> Result<Box<[u8]>, String> {
   let path = Path::new(&name);
-  File::open(path).map_err(|_| String::from("Unable to open file"))
+  let data = std::fs::read(path).map_err(|_| String::from("Unable to open boot ROM"))?;
+  match data.len() {
+    0x100 | 0x900 => Ok(data.into_boxed_slice()),
+    _ => Err(format!("Boot ROM has unexpected size of {} bytes", data.len())),
+  }
 }
 
-pub fn read_header(rom_file: &mut File) -> Result<Header, String> {
-  let pos = rom_file.seek(SeekFrom::Start(0x100)).map_err(|_| String::from("Unable to read ROM file"))?;
+pub fn read_header(rom_file: &mut File) -> Result<Header, Error> {
+  let pos = rom_file.seek(SeekFrom::Start(0x100)).map_err(|_| unreadable("Unable to read ROM file"))?;
   if pos != 0x100 {
-    return Err(String::from("File too short. Are you sure this is a ROM file?"));
+    return Err(Error::Rom(RomError::TooShort));
   }
   let mut header = unsafe { mem::zeroed::<Header>() };
   let read_length = mem::size_of::<Header>();
@@ -32,16 +83,214 @@ pub fn read_header(rom_file: &mut File) -> Result<Header, String> {
       &mut header as *mut Header as *mut u8,
       read_length,
     );
-    rom_file.read_exact(buffer).map_err(|_| String::from("Unable to read ROM header"))?;
+    rom_file.read_exact(buffer).map_err(|_| unreadable("Unable to read ROM header"))?;
   }
 
   Ok(header)
 }
 
-pub fn get_rom_buffer(rom_file: &mut File, rom_size: usize) -> Box<[u8]> {
-  map_rom_file(rom_file, rom_size)
+/// Read the header of a ROM that has already been loaded into memory
+pub fn read_header_from_bytes(data: &[u8]) -> Result<Header, Error> {
+  let read_length = mem::size_of::<Header>();
+  if data.len() < 0x100 + read_length {
+    return Err(Error::Rom(RomError::TooShort));
+  }
+  let mut header = unsafe { mem::zeroed::<Header>() };
+  unsafe {
+    std::ptr::copy_nonoverlapping(
+      data[0x100..].as_ptr(),
+      &mut header as *mut Header as *mut u8,
+      read_length,
+    );
+  }
+  Ok(header)
+}
+
+/// Look for a patch next to a ROM: a .ips or .bps file with the same name
+pub fn find_patch_file(rom_file_name: &str) -> Option<String> {
+  let path = Path::new(rom_file_name);
+  ["ips", "bps"]
+    .iter()
+    .map(|extension| path.with_extension(extension))
+    .find(|patch_path| patch_path.is_file())
+    .map(|patch_path| patch_path.to_string_lossy().into_owned())
+}
+
+/// Whether a ROM file name points to a zip archive, rather than the ROM
+/// itself
+pub fn is_zip_file(rom_file_name: &str) -> bool {
+  Path::new(rom_file_name)
+    .extension()
+    .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+}
+
+/// Read an entire ROM file into memory. If it is a zip archive, the first
+/// .gb or .gbc file inside it is extracted.
+pub fn read_rom_data(rom_file_name: &str) -> Result<Vec<u8>, Error> {
+  let data = std::fs::read(rom_file_name).map_err(|_| unreadable("Unable to open file"))?;
+  Ok(crate::zip::unpack_rom(data).map_err(RomError::Invalid)?)
+}
+
+/// Read an entire ROM file, and apply an IPS or BPS patch to it. The file
+/// itself is left unmodified.
+pub fn load_patched_rom(rom_file_name: &str, patch_file_name: &str) -> Result<Vec<u8>, Error> {
+  let rom = read_rom_data(rom_file_name)?;
+  let patch = std::fs::read(patch_file_name).map_err(|_| unreadable("Unable to open patch file"))?;
+  Ok(crate::patch::apply_patch(&rom, &patch).map_err(RomError::Invalid)?)
+}
+
+/// How to handle a ROM file whose length doesn't match the size declared by
+/// the ROM size code in its header
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RomSizePolicy {
+  /// Pad the file with 0xff, or truncate it, to the declared size
+  Header,
+  /// Keep the entire file, padding it with 0xff to a whole number of banks
+  File,
+  /// Refuse to load the ROM
+  Strict,
+}
+
+impl RomSizePolicy {
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "header" => Some(RomSizePolicy::Header),
+      "file" => Some(RomSizePolicy::File),
+      "strict" => Some(RomSizePolicy::Strict),
+      _ => None,
+    }
+  }
+}
+
+/// Each platform's way of loading a ROM file without copying it. The buffer
+/// has to be writable, since cheats patch the ROM in place, but writes must
+/// never reach the file.
+pub trait RomMapper {
+  fn map_rom_file(file: &mut File, size: usize) -> Result<Box<[u8]>, RomError>;
+
+  /// Release a buffer returned by `map_rom_file`
+  fn unmap_rom_file(buffer: Box<[u8]>);
+}
+
+pub struct RomBuffer {
+  pub data: Box<[u8]>,
+  /// Mapped buffers must be released with `drop_rom_buffer`
+  pub mapped: bool,
+  /// Describes how the file was resized, if it didn't match its header
+  pub warning: Option<String>,
+}
+
+/// Load the ROM into memory. When the file matches its declared size, it is
+/// mapped directly; otherwise, or if it can't be mapped, it is copied into a
+/// buffer that has been resized according to `policy`.
+pub fn get_rom_buffer(rom_file: &mut File, header: &Header, policy: RomSizePolicy) -> Result<RomBuffer, Error> {
+  let declared_size = header.get_rom_size_bytes();
+  let file_size = rom_file
+    .metadata()
+    .map_err(|_| unreadable("Unable to read ROM file"))?
+    .len() as usize;
+  if file_size == declared_size {
+    if let Ok(data) = PlatformMapper::map_rom_file(rom_file, declared_size) {
+      return Ok(RomBuffer {
+        data,
+        mapped: true,
+        warning: None,
+      });
+    }
+  }
+
+  let mut data = Vec::with_capacity(file_size);
+  rom_file.seek(SeekFrom::Start(0)).map_err(|_| unreadable("Unable to read ROM file"))?;
+  rom_file.read_to_end(&mut data).map_err(|_| unreadable("Unable to read ROM file"))?;
+  let (data, warning) = fit_rom_size(data, declared_size, size_policy_for(header, policy))?;
+  Ok(RomBuffer {
+    data: data.into_boxed_slice(),
+    mapped: false,
+    warning,
+  })
+}
+
+/// A header with a ROM size code no cartridge uses doesn't declare a size at
+/// all, so the file is kept whole
+pub fn size_policy_for(header: &Header, policy: RomSizePolicy) -> RomSizePolicy {
+  if header.is_rom_size_known() {
+    policy
+  } else {
+    RomSizePolicy::File
+  }
+}
+
+/// Resize ROM data that doesn't match the size declared in its header. The
+/// result always contains at least the two banks visible at startup. A ROM
+/// whose size is a power of two is mirrored to fill the space, the same way
+/// a small ROM chip repeats on the cartridge bus; anything else is padded.
+/// Any change is described in the returned warning, for the caller to report.
+pub fn fit_rom_size(mut data: Vec<u8>, declared_size: usize, policy: RomSizePolicy) -> Result<(Vec<u8>, Option<String>), Error> {
+  let file_size = data.len();
+  if file_size == declared_size {
+    return Ok((data, None));
+  }
+  let size = match policy {
+    RomSizePolicy::Strict => {
+      return Err(Error::Rom(RomError::SizeMismatch { file_size, declared_size }));
+    },
+    RomSizePolicy::Header => declared_size,
+    RomSizePolicy::File => file_size.div_ceil(0x4000) * 0x4000,
+  };
+  let size = size.max(0x8000);
+  let change = if file_size < size && file_size.is_power_of_two() {
+    data = data.iter().copied().cycle().take(size).collect();
+    "mirroring"
+  } else if file_size < size {
+    "padding"
+  } else if file_size > size {
+    "truncating"
+  } else {
+    return Ok((data, None));
+  };
+  data.resize(size, 0xff);
+  Ok((data, Some(format!("ROM file is {} bytes, {} to {} bytes", file_size, change, size))))
 }
 
 pub fn drop_rom_buffer(buffer: Box<[u8]>) {
-  unmap_rom_file(buffer)
+  PlatformMapper::unmap_rom_file(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{fit_rom_size, RomSizePolicy};
+
+  #[test]
+  fn fit_to_header() {
+    let rom = fit_rom_size(vec![0; 0x5000], 0x10000, RomSizePolicy::Header).unwrap().0;
+    assert_eq!(rom.len(), 0x10000);
+    assert_eq!(rom[0x4fff], 0);
+    assert_eq!(rom[0x5000], 0xff);
+
+    let rom = fit_rom_size(vec![0; 0x20000], 0x8000, RomSizePolicy::Header).unwrap().0;
+    assert_eq!(rom.len(), 0x8000);
+  }
+
+  #[test]
+  fn fit_to_file() {
+    let rom = fit_rom_size(vec![0; 0x14001], 0x8000, RomSizePolicy::File).unwrap().0;
+    assert_eq!(rom.len(), 0x18000);
+    let rom = fit_rom_size(vec![0; 0x100], 0x8000, RomSizePolicy::File).unwrap().0;
+    assert_eq!(rom.len(), 0x8000);
+  }
+
+  #[test]
+  fn mirror_small_roms() {
+    let rom: Vec<u8> = (0..0x2000).map(|i| i as u8).collect();
+    let rom = fit_rom_size(rom, 0x8000, RomSizePolicy::Header).unwrap().0;
+    assert_eq!(rom.len(), 0x8000);
+    assert_eq!(rom[0x2001], 1);
+    assert_eq!(rom[0x7fff], 0xff);
+  }
+
+  #[test]
+  fn strict_rejects_mismatch() {
+    assert_eq!(fit_rom_size(vec![0; 0x8000], 0x8000, RomSizePolicy::Strict).unwrap().1, None);
+    assert!(fit_rom_size(vec![0; 0x7000], 0x8000, RomSizePolicy::Strict).is_err());
+  }
 }
@@ -0,0 +1,26 @@
+//! Targets without memory-mapped files, like wasm32, read the ROM into an
+//! ordinary buffer instead
+
+use crate::error::RomError;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use super::RomMapper;
+
+pub struct BufferedRead;
+
+impl RomMapper for BufferedRead {
+  fn map_rom_file(file: &mut File, size: usize) -> Result<Box<[u8]>, RomError> {
+    let mut data = Vec::with_capacity(size);
+    let read = file
+      .seek(SeekFrom::Start(0))
+      .and_then(|_| file.take(size as u64).read_to_end(&mut data));
+    if read.is_err() || data.len() != size {
+      return Err(RomError::Unreadable(String::from("Unable to read ROM file")));
+    }
+    Ok(data.into_boxed_slice())
+  }
+
+  fn unmap_rom_file(buffer: Box<[u8]>) {
+    drop(buffer);
+  }
+}
@@ -1,56 +1,61 @@
+use crate::error::RomError;
 use std::ffi::c_void;
 use std::fs::File;
 use std::os::windows::io::AsRawHandle;
+use super::RomMapper;
 
-mod bindings {
-  windows::include_bindings!();
-}
-
-use bindings::{
+use crate::bindings::{
   Windows::Win32::System::Memory::{
     CreateFileMappingA,
     MapViewOfFile,
     UnmapViewOfFile,
-    FILE_MAP_READ,
-    PAGE_READONLY,
+    FILE_MAP_COPY,
+    PAGE_WRITECOPY,
   },
   Windows::Win32::Foundation::{
+    CloseHandle,
     HANDLE,
     PSTR,
   },
 };
 
-pub fn map_rom_file(file: &mut File, size: usize) -> Box<[u8]> {
-  unsafe {
-    let handle: HANDLE = CreateFileMappingA(
-      HANDLE(file.as_raw_handle() as isize),
-      std::ptr::null_mut(),
-      PAGE_READONLY,
-      0,
-      0,
-      PSTR::NULL,
-    );
-    //if handle == std::ptr::null() {
-    //  panic!("Unable to create file mapping for ROM file");
-    //}
-    let pointer: *mut c_void = MapViewOfFile(
-      handle,
-      FILE_MAP_READ,
-      0,
-      0,
-      size,
-    );
-    if pointer == std::ptr::null_mut() {
-      panic!("Unable to create file mapping for ROM file");
+/// Maps a copy-on-write view of the file, the equivalent of a private mmap
+pub struct FileMapping;
+
+impl RomMapper for FileMapping {
+  fn map_rom_file(file: &mut File, size: usize) -> Result<Box<[u8]>, RomError> {
+    unsafe {
+      let mapping: HANDLE = CreateFileMappingA(
+        HANDLE(file.as_raw_handle() as isize),
+        std::ptr::null_mut(),
+        PAGE_WRITECOPY,
+        0,
+        0,
+        PSTR::NULL,
+      );
+      if mapping.0 == 0 {
+        return Err(RomError::MapFailed);
+      }
+      let pointer: *mut c_void = MapViewOfFile(
+        mapping,
+        FILE_MAP_COPY,
+        0,
+        0,
+        size,
+      );
+      // the view keeps the mapping alive until it is unmapped
+      CloseHandle(mapping);
+      if pointer.is_null() {
+        return Err(RomError::MapFailed);
+      }
+      Ok(Vec::from_raw_parts(pointer as *mut u8, size, size).into_boxed_slice())
     }
-    Vec::from_raw_parts(pointer as *mut u8, size, size).into_boxed_slice()
   }
-}
 
-pub fn unmap_rom_file(buffer: Box<[u8]>) {
-  let size = buffer.len();
-  let address = Box::into_raw(buffer) as *mut () as *mut std::ffi::c_void;
-  unsafe {
-    UnmapViewOfFile(address);
+  fn unmap_rom_file(buffer: Box<[u8]>) {
+    let address = Box::into_raw(buffer) as *mut () as *mut c_void;
+    unsafe {
+      UnmapViewOfFile(address);
+    }
   }
-}
\ No newline at end of file
+}
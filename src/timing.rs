@@ -1,6 +1,6 @@
 /// Represents a number of raw clock cycles, the smallest unit of time for all
 /// GB hardware.
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 #[repr(transparent)]
 pub struct ClockCycles(pub usize);
 
@@ -18,6 +18,19 @@ impl ClockCycles {
   }
 }
 
+/// A VRAM DMA halts the CPU for 8 machine cycles per 16-byte block copied
+/// (in single-speed mode)
+pub const HDMA_BLOCK_CYCLES: MachineCycles = MachineCycles(8);
+
+/// Time taken to draw a single scanline, including H-blank
+pub const SCANLINE_CYCLES: MachineCycles = MachineCycles(114);
+
+/// Machine cycles in a full frame of 154 scanlines
+pub const FRAME_CYCLES: u64 = 17556;
+
+/// Machine cycles in one second, from the 4.194304MHz clock
+pub const CYCLES_PER_SECOND: u64 = 1048576;
+
 /// Represents a number of CPU "Machine" cycles. Each Machine cycle is 4 clock
 /// cycles, and the fastest CPU instructions run in a single Machine cycle.
 #[derive(Copy, Clone, Eq, PartialEq)]
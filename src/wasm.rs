@@ -0,0 +1,73 @@
+//! Bindings for running the emulator in a browser. wasm32 has no JIT
+//! backend, so the core always runs on the interpreter. Build the library as
+//! a cdylib, then generate the JavaScript glue with wasm-bindgen:
+//!
+//!   cargo rustc --lib --release --target wasm32-unknown-unknown --crate-type cdylib
+//!   wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/release/gb_dynarec.wasm
+//!
+//! A front end loads a ROM, then calls `step_frame` and draws `framebuffer`
+//! into an ImageData on every animation frame.
+
+use crate::api::Emulator;
+use crate::devices::joypad::Button;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+pub struct WebEmulator {
+  emulator: Emulator,
+}
+
+#[wasm_bindgen]
+impl WebEmulator {
+  #[wasm_bindgen(constructor)]
+  pub fn new() -> Self {
+    Self {
+      emulator: Emulator::new(),
+    }
+  }
+
+  pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), JsValue> {
+    self.emulator.load_rom(rom).map_err(|msg| JsValue::from_str(&msg.to_string()))
+  }
+
+  /// Run until the end of the next frame
+  pub fn step_frame(&mut self) {
+    self.emulator.step_frame();
+  }
+
+  /// The last completed frame as RGBA8 pixels, ready for an ImageData
+  pub fn framebuffer(&self) -> Vec<u8> {
+    self.emulator.framebuffer_rgba()
+  }
+
+  pub fn width(&self) -> usize {
+    self.emulator.framebuffer_size().0
+  }
+
+  pub fn height(&self) -> usize {
+    self.emulator.framebuffer_size().1
+  }
+
+  /// Press or release a button by name: a, b, select, start, up, down, left,
+  /// or right
+  pub fn set_button(&mut self, name: &str, pressed: bool) -> Result<(), JsValue> {
+    let button = Button::from_name(&name.to_ascii_lowercase())
+      .ok_or_else(|| JsValue::from_str(&format!("Unknown button \"{}\"", name)))?;
+    self.emulator.set_button(button, pressed);
+    Ok(())
+  }
+
+  pub fn save_state(&self) -> Vec<u8> {
+    self.emulator.save_state()
+  }
+
+  pub fn load_state(&mut self, data: &[u8]) -> Result<(), JsValue> {
+    self.emulator.load_state(data).map_err(|msg| JsValue::from_str(&msg))
+  }
+}
+
+impl Default for WebEmulator {
+  fn default() -> Self {
+    Self::new()
+  }
+}
@@ -0,0 +1,473 @@
+//! Reading ROMs out of zip archives, which is how most ROM sets are
+//! distributed. Only what that needs is supported: the first .gb or .gbc
+//! entry is extracted, and it must either be stored or compressed with
+//! deflate. Archives usually come from outside of the emulator, so nothing
+//! here trusts the sizes and offsets it reads.
+
+use crate::patch::crc32;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const LOCAL_HEADER_MAGIC: u32 = 0x04034b50;
+const CENTRAL_HEADER_MAGIC: u32 = 0x02014b50;
+const END_OF_DIRECTORY_MAGIC: u32 = 0x06054b50;
+/// Length of the end of central directory record, without its comment
+const END_OF_DIRECTORY_LENGTH: usize = 22;
+const CENTRAL_HEADER_LENGTH: usize = 46;
+const LOCAL_HEADER_LENGTH: usize = 30;
+
+/// The largest ROM any cart header can declare. Entries that claim to be
+/// larger aren't ROMs, and are rejected before anything is allocated for them.
+const MAX_ROM_SIZE: usize = 8 * 1024 * 1024;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+/// Whether a buffer starts like a zip archive
+pub fn is_zip(data: &[u8]) -> bool {
+  read_u32(data, 0) == Some(LOCAL_HEADER_MAGIC)
+}
+
+/// If a buffer is a zip archive, extract the ROM inside it. Anything else is
+/// assumed to be a ROM already, and is returned unchanged.
+pub fn unpack_rom(data: Vec<u8>) -> Result<Vec<u8>, String> {
+  if is_zip(&data) {
+    extract_rom(&data)
+  } else {
+    Ok(data)
+  }
+}
+
+/// Extract the first entry in an archive with a .gb or .gbc extension
+pub fn extract_rom(archive: &[u8]) -> Result<Vec<u8>, String> {
+  let entry = find_rom_entry(archive)?;
+  if entry.size > MAX_ROM_SIZE {
+    return Err(format!("\"{}\" in zip archive is too large to be a ROM", entry.name));
+  }
+  let local_header = read_u32(archive, entry.local_offset);
+  if local_header != Some(LOCAL_HEADER_MAGIC) {
+    return Err(String::from("Zip archive is corrupt"));
+  }
+  // the magic number was found, so the rest of the header offsets can't
+  // overflow
+  let name_length = read_u16(archive, entry.local_offset + 26).ok_or_else(truncated)? as usize;
+  let extra_length = read_u16(archive, entry.local_offset + 28).ok_or_else(truncated)? as usize;
+  let start = entry.local_offset + LOCAL_HEADER_LENGTH + name_length + extra_length;
+  let compressed = archive
+    .get(start..start.saturating_add(entry.compressed_size))
+    .ok_or_else(truncated)?;
+
+  let data = match entry.method {
+    METHOD_STORED => compressed.to_vec(),
+    METHOD_DEFLATE => inflate(compressed, entry.size)?,
+    method => return Err(format!("Unsupported zip compression method {}", method)),
+  };
+  if data.len() != entry.size || crc32(&data) != entry.crc {
+    return Err(format!("\"{}\" in zip archive is corrupt", entry.name));
+  }
+  Ok(data)
+}
+
+struct Entry {
+  name: String,
+  method: u16,
+  crc: u32,
+  compressed_size: usize,
+  size: usize,
+  local_offset: usize,
+}
+
+/// Walk the central directory at the end of the archive, looking for a ROM
+fn find_rom_entry(archive: &[u8]) -> Result<Entry, String> {
+  let end = find_end_of_directory(archive).ok_or_else(|| String::from("Not a zip archive"))?;
+  let entry_count = read_u16(archive, end + 10).ok_or_else(truncated)?;
+  let mut offset = read_u32(archive, end + 16).ok_or_else(truncated)? as usize;
+  for _ in 0..entry_count {
+    if read_u32(archive, offset) != Some(CENTRAL_HEADER_MAGIC) {
+      return Err(String::from("Zip archive is corrupt"));
+    }
+    let field = |at: usize| read_u16(archive, offset + at).ok_or_else(truncated);
+    let long_field = |at: usize| read_u32(archive, offset + at).ok_or_else(truncated);
+    let flags = field(8)?;
+    let name_length = field(28)? as usize;
+    let name_start = offset + CENTRAL_HEADER_LENGTH;
+    let name = archive.get(name_start..name_start + name_length).ok_or_else(truncated)?;
+    let name = String::from_utf8_lossy(name).into_owned();
+
+    let lower = name.to_ascii_lowercase();
+    if lower.ends_with(".gb") || lower.ends_with(".gbc") {
+      if flags & 1 != 0 {
+        return Err(format!("\"{}\" in zip archive is encrypted", name));
+      }
+      return Ok(Entry {
+        name,
+        method: field(10)?,
+        crc: long_field(16)?,
+        compressed_size: long_field(20)? as usize,
+        size: long_field(24)? as usize,
+        local_offset: long_field(42)? as usize,
+      });
+    }
+    offset = name_start + name_length + field(30)? as usize + field(32)? as usize;
+  }
+  Err(String::from("No .gb or .gbc file found in zip archive"))
+}
+
+/// The end of central directory record is at the very end of the archive,
+/// unless it is followed by a comment of up to 64KiB
+fn find_end_of_directory(archive: &[u8]) -> Option<usize> {
+  let last = archive.len().checked_sub(END_OF_DIRECTORY_LENGTH)?;
+  let first = last.saturating_sub(0xffff);
+  (first..=last).rev().find(|offset| read_u32(archive, *offset) == Some(END_OF_DIRECTORY_MAGIC))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+  let bytes = data.get(offset..offset.checked_add(2)?)?;
+  Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+  let bytes = data.get(offset..offset.checked_add(4)?)?;
+  Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn truncated() -> String {
+  String::from("Zip archive is truncated")
+}
+
+const LENGTH_BASE: [u16; 29] = [
+  3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+  163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+  0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+  1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049,
+  3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+  0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+/// The order that the lengths of the code length code are stored in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Reads a deflate stream, least significant bit first
+struct BitReader<'a> {
+  data: &'a [u8],
+  position: usize,
+  buffer: u32,
+  count: u32,
+}
+
+impl<'a> BitReader<'a> {
+  fn new(data: &'a [u8]) -> Self {
+    Self {
+      data,
+      position: 0,
+      buffer: 0,
+      count: 0,
+    }
+  }
+
+  fn bits(&mut self, count: u32) -> Result<u32, String> {
+    while self.count < count {
+      let byte = *self.data.get(self.position).ok_or_else(truncated)?;
+      self.position += 1;
+      self.buffer |= (byte as u32) << self.count;
+      self.count += 8;
+    }
+    let value = self.buffer & ((1 << count) - 1);
+    self.buffer >>= count;
+    self.count -= count;
+    Ok(value)
+  }
+
+  /// Skip to the next byte boundary. Bytes are only read as they're needed,
+  /// so fewer than 8 bits are ever left over.
+  fn align(&mut self) {
+    self.buffer = 0;
+    self.count = 0;
+  }
+
+  fn bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+    let bytes = self
+      .data
+      .get(self.position..self.position + length)
+      .ok_or_else(truncated)?;
+    self.position += length;
+    Ok(bytes)
+  }
+}
+
+/// A canonical Huffman code, decoded one bit at a time
+struct Huffman {
+  /// Number of codes of each length
+  counts: [u16; 16],
+  /// Symbols ordered by code
+  symbols: Vec<u16>,
+}
+
+impl Huffman {
+  fn new(lengths: &[u8]) -> Self {
+    let mut counts = [0; 16];
+    for length in lengths {
+      counts[*length as usize] += 1;
+    }
+    counts[0] = 0;
+    let mut symbols = Vec::with_capacity(lengths.len());
+    for length in 1..16 {
+      for (symbol, _) in lengths.iter().enumerate().filter(|(_, l)| **l == length) {
+        symbols.push(symbol as u16);
+      }
+    }
+    Self { counts, symbols }
+  }
+
+  fn decode(&self, reader: &mut BitReader) -> Result<u16, String> {
+    // codes of each length follow on from the last code of the length
+    // before, so a code is found once it falls within its length's range
+    let mut code = 0;
+    let mut first = 0;
+    let mut index = 0;
+    for count in self.counts[1..].iter() {
+      code |= reader.bits(1)? as usize;
+      let count = *count as usize;
+      if code < first + count {
+        return Ok(self.symbols[index + code - first]);
+      }
+      index += count;
+      first = (first + count) << 1;
+      code <<= 1;
+    }
+    Err(String::from("Invalid code in deflate stream"))
+  }
+}
+
+/// Decompress a raw deflate stream, which is expected to produce `size`
+/// bytes. Anything that would produce more is rejected, rather than
+/// allocating whatever an archive asks for.
+pub fn inflate(data: &[u8], size: usize) -> Result<Vec<u8>, String> {
+  if size > MAX_ROM_SIZE {
+    return Err(String::from("Deflate stream is larger than any ROM"));
+  }
+  // the declared size is only a guess until the stream has been decoded, so
+  // the output grows as it goes past what is reserved up front
+  let mut output = Vec::with_capacity(size.min(MAX_ROM_SIZE));
+  let mut reader = BitReader::new(data);
+  loop {
+    let last = reader.bits(1)? == 1;
+    match reader.bits(2)? {
+      0 => {
+        reader.align();
+        let header = reader.bytes(4)?;
+        let length = u16::from_le_bytes([header[0], header[1]]);
+        if length != !u16::from_le_bytes([header[2], header[3]]) {
+          return Err(String::from("Invalid stored block in deflate stream"));
+        }
+        output.extend_from_slice(reader.bytes(length as usize)?);
+      },
+      1 => {
+        let (literals, distances) = fixed_codes();
+        inflate_block(&mut reader, &literals, &distances, &mut output, size)?;
+      },
+      2 => {
+        let (literals, distances) = dynamic_codes(&mut reader)?;
+        inflate_block(&mut reader, &literals, &distances, &mut output, size)?;
+      },
+      _ => return Err(String::from("Invalid block type in deflate stream")),
+    }
+    if output.len() > size {
+      return Err(String::from("Deflate stream is larger than expected"));
+    }
+    if last {
+      return Ok(output);
+    }
+  }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+  let mut lengths = [0; 288];
+  lengths[..144].fill(8);
+  lengths[144..256].fill(9);
+  lengths[256..280].fill(7);
+  lengths[280..].fill(8);
+  (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+  let literal_count = reader.bits(5)? as usize + 257;
+  let distance_count = reader.bits(5)? as usize + 1;
+  let code_length_count = reader.bits(4)? as usize + 4;
+  let mut code_lengths = [0; 19];
+  for index in CODE_LENGTH_ORDER.iter().take(code_length_count) {
+    code_lengths[*index] = reader.bits(3)? as u8;
+  }
+  let code_length_code = Huffman::new(&code_lengths);
+
+  let total = literal_count + distance_count;
+  let mut lengths = Vec::with_capacity(total);
+  while lengths.len() < total {
+    let (length, repeat) = match code_length_code.decode(reader)? {
+      symbol @ 0..=15 => (symbol as u8, 1),
+      16 => {
+        let previous = *lengths
+          .last()
+          .ok_or_else(|| String::from("Invalid code lengths in deflate stream"))?;
+        (previous, 3 + reader.bits(2)? as usize)
+      },
+      17 => (0, 3 + reader.bits(3)? as usize),
+      _ => (0, 11 + reader.bits(7)? as usize),
+    };
+    if lengths.len() + repeat > total {
+      return Err(String::from("Invalid code lengths in deflate stream"));
+    }
+    lengths.resize(lengths.len() + repeat, length);
+  }
+  let (literal_lengths, distance_lengths) = lengths.split_at(literal_count);
+  Ok((Huffman::new(literal_lengths), Huffman::new(distance_lengths)))
+}
+
+fn inflate_block(
+  reader: &mut BitReader,
+  literals: &Huffman,
+  distances: &Huffman,
+  output: &mut Vec<u8>,
+  size: usize,
+) -> Result<(), String> {
+  loop {
+    let symbol = literals.decode(reader)? as usize;
+    if symbol < 256 {
+      output.push(symbol as u8);
+      continue;
+    }
+    if symbol == 256 {
+      return Ok(());
+    }
+    let index = symbol - 257;
+    if index >= LENGTH_BASE.len() {
+      return Err(String::from("Invalid length in deflate stream"));
+    }
+    let length = LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index] as u32)? as usize;
+    let index = distances.decode(reader)? as usize;
+    if index >= DISTANCE_BASE.len() {
+      return Err(String::from("Invalid distance in deflate stream"));
+    }
+    let distance = DISTANCE_BASE[index] as usize + reader.bits(DISTANCE_EXTRA[index] as u32)? as usize;
+    if distance > output.len() {
+      return Err(String::from("Invalid distance in deflate stream"));
+    }
+    if output.len() + length > size {
+      return Err(String::from("Deflate stream is larger than expected"));
+    }
+    // the copy can overlap the bytes it produces, for runs
+    let start = output.len() - distance;
+    for offset in 0..length {
+      output.push(output[start + offset]);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{extract_rom, inflate, is_zip, unpack_rom, METHOD_DEFLATE, METHOD_STORED};
+  use crate::patch::crc32;
+  use alloc::vec;
+  use alloc::vec::Vec;
+
+  /// Build an archive with a directory, a readme, and then a single file
+  fn build_zip(name: &str, method: u16, data: &[u8], compressed: &[u8]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    let mut directory = Vec::new();
+    let files: [(&str, u16, &[u8], &[u8]); 3] = [
+      ("roms/", METHOD_STORED, b"", b""),
+      ("README.txt", METHOD_STORED, b"hi", b"hi"),
+      (name, method, data, compressed),
+    ];
+    for (name, method, data, compressed) in files.iter() {
+      let offset = archive.len() as u32;
+      let mut fields = Vec::new();
+      fields.extend_from_slice(&20u16.to_le_bytes());
+      fields.extend_from_slice(&0u16.to_le_bytes());
+      fields.extend_from_slice(&method.to_le_bytes());
+      fields.extend_from_slice(&[0; 4]);
+      fields.extend_from_slice(&crc32(data).to_le_bytes());
+      fields.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+      fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+      fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+      fields.extend_from_slice(&0u16.to_le_bytes());
+
+      archive.extend_from_slice(&super::LOCAL_HEADER_MAGIC.to_le_bytes());
+      archive.extend_from_slice(&fields);
+      archive.extend_from_slice(name.as_bytes());
+      archive.extend_from_slice(compressed);
+
+      directory.extend_from_slice(&super::CENTRAL_HEADER_MAGIC.to_le_bytes());
+      directory.extend_from_slice(&20u16.to_le_bytes());
+      directory.extend_from_slice(&fields);
+      // comment length, disk, attributes
+      directory.extend_from_slice(&[0; 10]);
+      directory.extend_from_slice(&offset.to_le_bytes());
+      directory.extend_from_slice(name.as_bytes());
+    }
+    let directory_offset = archive.len() as u32;
+    archive.extend_from_slice(&directory);
+    archive.extend_from_slice(&super::END_OF_DIRECTORY_MAGIC.to_le_bytes());
+    archive.extend_from_slice(&[0; 4]);
+    archive.extend_from_slice(&3u16.to_le_bytes());
+    archive.extend_from_slice(&3u16.to_le_bytes());
+    archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    archive.extend_from_slice(&directory_offset.to_le_bytes());
+    archive.extend_from_slice(&0u16.to_le_bytes());
+    archive
+  }
+
+  #[test]
+  fn inflating() {
+    // a stored block
+    assert_eq!(inflate(&[0x01, 0x03, 0x00, 0xfc, 0xff, 1, 2, 3], 3).unwrap(), vec![1, 2, 3]);
+    // a fixed block, made almost entirely of one overlapping copy
+    let fixed = [0x4b, 0x4c, 0x4a, 0x4e, 0xc4, 0x40, 0x00];
+    assert_eq!(inflate(&fixed, 21).unwrap(), b"abcabcabcabcabcabcabc".to_vec());
+    assert!(inflate(&fixed, 20).is_err());
+    // a dynamic block
+    let dynamic = [
+      0x1d, 0x89, 0x81, 0x09, 0x00, 0x00, 0x08, 0x83, 0x6e, 0xd5, 0xf5, 0xff, 0x0d, 0xad, 0x40,
+      0x26, 0x43, 0x14, 0x46, 0x2a, 0x99, 0xd0, 0x2d, 0xe6, 0xf4, 0x2f, 0x17, 0x71, 0x01,
+    ];
+    let text = b"abbaadbabbabadcaabaababcbaabcaabacdbabab";
+    assert_eq!(inflate(&dynamic, text.len()).unwrap(), text.to_vec());
+    assert!(inflate(&dynamic[..20], text.len()).is_err());
+    // a size no ROM could have is rejected before anything is decoded
+    assert!(inflate(&fixed, usize::MAX).is_err());
+  }
+
+  #[test]
+  fn extracting_roms() {
+    let rom: Vec<u8> = (0..=255).collect();
+    let stored = build_zip("roms/Game.GB", METHOD_STORED, &rom, &rom);
+    assert!(is_zip(&stored));
+    assert_eq!(extract_rom(&stored).unwrap(), rom);
+    assert_eq!(unpack_rom(rom.clone()).unwrap(), rom);
+
+    let text = b"abcabcabcabcabcabcabc";
+    let deflated = build_zip("game.gbc", METHOD_DEFLATE, text, &[0x4b, 0x4c, 0x4a, 0x4e, 0xc4, 0x40, 0x00]);
+    assert_eq!(unpack_rom(deflated).unwrap(), text.to_vec());
+
+    // a mismatched checksum, a missing ROM, and a truncated archive
+    let mut corrupt = stored.clone();
+    corrupt[150] ^= 1;
+    assert!(extract_rom(&corrupt).is_err());
+    assert!(extract_rom(&build_zip("game.txt", METHOD_STORED, &rom, &rom)).is_err());
+    assert!(extract_rom(&stored[..stored.len() - 1]).is_err());
+    assert!(extract_rom(&stored[..200]).is_err());
+
+    // an entry claiming to be 4GiB is refused instead of allocated
+    let mut huge = build_zip("game.gb", METHOD_DEFLATE, text, &[0x4b, 0x4c, 0x4a, 0x4e, 0xc4, 0x40, 0x00]);
+    let magic = super::CENTRAL_HEADER_MAGIC.to_le_bytes();
+    let header = huge.windows(4).rposition(|bytes| bytes == magic).unwrap();
+    huge[header + 24..header + 28].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(extract_rom(&huge).unwrap_err().contains("too large"));
+  }
+}
@@ -0,0 +1,114 @@
+//! Runs blargg's cpu_instrs and mooneye-gb's acceptance tests against the
+//! emulator binary, once with the interpreter and once with the JIT. The ROMs
+//! aren't part of the repository; place them under `tests/roms`, or point
+//! `GB_TEST_ROMS` at a directory laid out like:
+//!
+//!   cpu_instrs/individual/01-special.gb ...
+//!   acceptance/**/*.gb
+//!
+//! Every cpu_instrs ROM is expected to pass. Not every acceptance test passes
+//! yet, so those only need to give the same result with both engines.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const TIMEOUT_SECONDS: &str = "120";
+
+#[derive(Debug, Eq, PartialEq)]
+enum Outcome {
+  Passed,
+  Failed,
+  TimedOut,
+}
+
+fn rom_directory() -> Option<PathBuf> {
+  let directory = match std::env::var("GB_TEST_ROMS") {
+    Ok(directory) => PathBuf::from(directory),
+    Err(_) => Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms"),
+  };
+  if directory.is_dir() {
+    Some(directory)
+  } else {
+    println!("No test ROMs found at {}, skipping", directory.display());
+    None
+  }
+}
+
+/// Recursively collect every .gb file, in a stable order
+fn find_roms(directory: &Path, roms: &mut Vec<PathBuf>) {
+  let mut entries: Vec<PathBuf> = match std::fs::read_dir(directory) {
+    Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect(),
+    Err(_) => return,
+  };
+  entries.sort();
+  for path in entries {
+    if path.is_dir() {
+      find_roms(&path, roms);
+    } else if path.extension() == Some("gb".as_ref()) {
+      roms.push(path);
+    }
+  }
+}
+
+fn run_rom(rom: &Path, engine: &str) -> Outcome {
+  let status = Command::new(env!("CARGO_BIN_EXE_gb-dynarec"))
+    .arg(rom)
+    .arg("--test-rom")
+    .arg(engine)
+    .args(["--test-timeout", TIMEOUT_SECONDS])
+    .output()
+    .expect("Failed to start the emulator")
+    .status;
+  match status.code() {
+    Some(0) => Outcome::Passed,
+    Some(2) => Outcome::TimedOut,
+    _ => Outcome::Failed,
+  }
+}
+
+/// Runs each ROM with both engines, returning the interpreter's results and
+/// a list of every ROM the engines disagreed on
+fn run_suite(directory: &Path) -> (Vec<(PathBuf, Outcome)>, Vec<String>) {
+  let mut roms = Vec::new();
+  find_roms(directory, &mut roms);
+  let mut results = Vec::new();
+  let mut mismatches = Vec::new();
+  for rom in roms {
+    let interpreted = run_rom(&rom, "--interpreter");
+    let compiled = run_rom(&rom, "--jit");
+    println!("{}: {:?} (interpreter), {:?} (jit)", rom.display(), interpreted, compiled);
+    if interpreted != compiled {
+      mismatches.push(rom.display().to_string());
+    }
+    results.push((rom, interpreted));
+  }
+  (results, mismatches)
+}
+
+#[test]
+fn blargg_cpu_instrs() {
+  let directory = match rom_directory() {
+    Some(directory) => directory.join("cpu_instrs"),
+    None => return,
+  };
+  let (results, mismatches) = run_suite(&directory);
+  let failures: Vec<String> = results
+    .iter()
+    .filter(|(_, outcome)| *outcome != Outcome::Passed)
+    .map(|(rom, _)| rom.display().to_string())
+    .collect();
+  assert!(failures.is_empty(), "Failed: {:?}", failures);
+  assert!(mismatches.is_empty(), "Engines disagree on: {:?}", mismatches);
+}
+
+#[test]
+fn mooneye_acceptance() {
+  let directory = match rom_directory() {
+    Some(directory) => directory.join("acceptance"),
+    None => return,
+  };
+  let (results, mismatches) = run_suite(&directory);
+  let passed = results.iter().filter(|(_, outcome)| *outcome == Outcome::Passed).count();
+  println!("{} of {} acceptance tests passed", passed, results.len());
+  assert!(mismatches.is_empty(), "Engines disagree on: {:?}", mismatches);
+}